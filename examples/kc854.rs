@@ -0,0 +1,754 @@
+//
+// A KC85/4 emulator.
+//
+// The KC85/4 is the last and most advanced model of the East German
+// KC85 home computer line: a 1.77 MHz Z80 CPU, a PIO and a CTC, 64 KByte
+// RAM (RAM0 at 0x0000, RAM4 at 0x4000, and 2 switchable RAM8 banks
+// at 0x8000), 64 KByte video RAM (the IRM, two 320x256 images with
+// separate pixel and color banks which are switched into 0x8000), the
+// CAOS operating system ROMs and a BASIC ROM at 0xC000, and 2 module
+// slots in the base device.
+//
+// The ROM dumps are not included in the repository, copy them from
+// your KC85/4 (or another emulator) into the examples/dumps directory:
+//
+//  caos42c.854     4 KByte CAOS 4.2 ROM C (0xC000)
+//  caos42e.854     8 KByte CAOS 4.2 ROM E (0xE000)
+//  basic_c0.853    8 KByte BASIC ROM (0xC000)
+//
+// The hardware wiring which is emulated here:
+//
+// - PIO channel A switches the CAOS ROM E, RAM0, the IRM and the
+//   BASIC ROM on and off, PIO channel B controls the sound volume,
+//   RAM8 and the blinking of the color attributes
+// - the output latches at port 0x84 and 0x86 select the displayed and
+//   the CPU-visible IRM image and bank, the RAM8 bank, RAM4 and the
+//   CAOS ROM C
+// - CTC channel 0 and 1 zero counts toggle the two sound oscillators,
+//   channel 2 zero counts toggle the blink flip-flop, the vertical
+//   retrace triggers the CLK/TRG inputs of channel 2 and 3
+// - the PIO, the CTC and the PIO of the module in slot 08h are
+//   registered in this order on the interrupt daisychain (the
+//   DeviceMap forwards the chip interrupts to the right controller)
+//
+// The serial keyboard protocol (pulse width measurements with the CTC)
+// is not emulated, instead the key code is written directly into the
+// keyboard buffer of the CAOS interrupt service routine once per frame.
+//
+// The module slots: slot 08h holds an M001 Digital IN/OUT module, which
+// is a second PIO at port 30h..33h whose outputs are printed to the
+// console. A ROM module dump (8 KByte M026 FORTH-type, or 16 KByte
+// M006 BASIC-type) can be put into slot 0Ch from the command line, and
+// switched on with the CAOS SWITCH command:
+//
+// > cargo run --release --example kc854 -- forth.853
+// %SWITCH 0C C1
+//
+// The sound of the two oscillators is mixed into one channel. Since
+// minifb has no audio output, the sound can be captured into a WAV file:
+//
+// F4:  start capturing sound, press F4 again to stop capturing and
+//      write the sound to 'kc854_sound.wav'
+
+extern crate rz80;
+extern crate minifb;
+
+use rz80::{CPU, Memory, PIO, CTC, Daisychain, DeviceMap, DeviceKind, Bus, NullBus, IoMap, RegT,
+           PIO_A, PIO_B, CTC_0, CTC_1, CTC_2, CTC_3, Clock, Beeper, Framebuffer, FrameTimer,
+           Scheduler, PROFILE_KC85_4};
+use minifb::{Key, KeyRepeat, Window, Scale, WindowOptions};
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::fs;
+use std::io;
+
+// directory with the ROM dumps
+const ROM_DIR: &str = "examples/dumps";
+
+// framebuffer dimensions
+const WIDTH: usize = 320;
+const HEIGHT: usize = 256;
+// CPU clock
+const CLOCK: Clock = PROFILE_KC85_4.clock;
+// the vertical retrace starts after the 256 visible lines
+const VBLANK_LINE: i64 = 256;
+// audio sample rate
+const SAMPLE_RATE: u32 = 44100;
+
+// heap layout: RAM0, RAM4, RAM8 bank 0 and 1, IRM image 0 pixels
+// and colors, IRM image 1 pixels and colors, 16 KBytes each
+const RAM0: usize = 0x00000;
+const RAM4: usize = 0x04000;
+const RAM8: usize = 0x08000;
+const IRM: usize = 0x10000;
+const BANK_SIZE: usize = 0x4000;
+
+// PIO channel A output bits
+const PIO_A_CAOS_E: u8 = 1 << 0;
+const PIO_A_RAM0: u8 = 1 << 1;
+const PIO_A_IRM: u8 = 1 << 2;
+const PIO_A_RAM0_WE: u8 = 1 << 3;
+const PIO_A_BASIC: u8 = 1 << 7;
+
+// PIO channel B output bits (bits 0..4 are the inverted sound volume)
+const PIO_B_VOLUME: u8 = 0x1F;
+const PIO_B_RAM8: u8 = 1 << 5;
+const PIO_B_RAM8_WE: u8 = 1 << 6;
+const PIO_B_BLINK: u8 = 1 << 7;
+
+// output latch at port 0x84
+const IO84_DISPLAY_IMAGE: u8 = 1 << 0;
+const IO84_RAM8_BANK: u8 = 1 << 4;
+
+// output latch at port 0x86
+const IO86_RAM4: u8 = 1 << 0;
+const IO86_RAM4_WE: u8 = 1 << 1;
+const IO86_CAOS_C: u8 = 1 << 7;
+
+// the PIO ids (the M001 module contains the second PIO)
+const PIO_MAIN: usize = 0;
+const PIO_M001: usize = 1;
+// first I/O port of the M001 PIO
+const M001_PORT: RegT = 0x30;
+
+// the module slots in the base device
+const SLOTS: [RegT; 2] = [0x08, 0x0C];
+
+// foreground colors (color attribute bits 3..6)
+static FG_COLORS: [u32; 16] = [
+    0xFF000000, 0xFF0000FF, 0xFFFF0000, 0xFFFF00FF, 0xFF00FF00, 0xFF00FFFF, 0xFFFFFF00, 0xFFFFFFFF,
+    0xFF000000, 0xFFA000FF, 0xFFFFA000, 0xFFFF00A0, 0xFF00FFA0, 0xFF00A0FF, 0xFFA0FF00, 0xFFFFFFFF,
+];
+// background colors (color attribute bits 0..2)
+static BG_COLORS: [u32; 8] = [
+    0xFF000000, 0xFF0000A0, 0xFFA00000, 0xFFA000A0, 0xFF00A000, 0xFF00A0A0, 0xFFA0A000, 0xFFA0A0A0,
+];
+
+// minifb key codes and their KC85 key codes without and with shift key,
+// the KC85 keyboard produces upper case letters without shift key
+static KEYS: &[(Key,u8,u8)] = &[
+    (Key::Key0,b'0',b')'), (Key::Key1,b'1',b'!'), (Key::Key2,b'2',b'@'), (Key::Key3,b'3',b'#'),
+    (Key::Key4,b'4',b'$'), (Key::Key5,b'5',b'%'), (Key::Key6,b'6',b'^'), (Key::Key7,b'7',b'&'),
+    (Key::Key8,b'8',b'*'), (Key::Key9,b'9',b'('), (Key::Minus,b'-',b'_'), (Key::Equal,b'=',b'+'),
+    (Key::A,b'A',b'a'), (Key::B,b'B',b'b'), (Key::C,b'C',b'c'), (Key::D,b'D',b'd'),
+    (Key::E,b'E',b'e'), (Key::F,b'F',b'f'), (Key::G,b'G',b'g'), (Key::H,b'H',b'h'),
+    (Key::I,b'I',b'i'), (Key::J,b'J',b'j'), (Key::K,b'K',b'k'), (Key::L,b'L',b'l'),
+    (Key::M,b'M',b'm'), (Key::N,b'N',b'n'), (Key::O,b'O',b'o'), (Key::P,b'P',b'p'),
+    (Key::Q,b'Q',b'q'), (Key::R,b'R',b'r'), (Key::S,b'S',b's'), (Key::T,b'T',b't'),
+    (Key::U,b'U',b'u'), (Key::V,b'V',b'v'), (Key::W,b'W',b'w'), (Key::X,b'X',b'x'),
+    (Key::Y,b'Y',b'y'), (Key::Z,b'Z',b'z'),
+    (Key::Comma,b',',b'<'), (Key::Period,b'.',b'>'), (Key::Slash,b'/',b'?'),
+    (Key::LeftBracket,b'[',b'{'), (Key::RightBracket,b']',b'}'),
+    (Key::Semicolon,b';',b':'), (Key::Apostrophe,b'\'',b'"'), (Key::Backslash,b'\\',b'|'),
+    (Key::Space,0x20,0x20), (Key::Left,0x08,0x08), (Key::Right,0x09,0x09), (Key::Down,0x0A,0x0A),
+    (Key::Up,0x0B,0x0B), (Key::Enter,0x0D,0x0D), (Key::Escape,0x03,0x03), (Key::Backspace,0x01,0x01),
+    (Key::F1,0xF1,0xF1), (Key::F2,0xF2,0xF2), (Key::F3,0xF3,0xF3),
+];
+
+// the ROM dumps, mapped without copying
+struct Roms {
+    caos_c: &'static [u8],
+    caos_e: &'static [u8],
+    basic: &'static [u8],
+}
+
+// load a ROM dump, and turn it into a static slice for Memory::map_slice()
+fn load_rom(path: &str, size: usize) -> Result<&'static [u8], String> {
+    let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    if data.len() != size {
+        return Err(format!("{}: expected {} bytes, got {}", path, size, data.len()));
+    }
+    Ok(Box::leak(data.into_boxed_slice()))
+}
+
+impl Roms {
+    pub fn load() -> Result<Roms, String> {
+        Ok(Roms {
+            caos_c: load_rom(&format!("{}/caos42c.854", ROM_DIR), 0x1000)?,
+            caos_e: load_rom(&format!("{}/caos42e.854", ROM_DIR), 0x2000)?,
+            basic: load_rom(&format!("{}/basic_c0.853", ROM_DIR), 0x2000)?,
+        })
+    }
+}
+
+// A module in one of the module slots. Each module has a type id which
+// the CPU reads from port 0x80 (with the slot number in the upper 8 bits
+// of the port address), and a control byte which the CPU writes to the
+// same port: bit 0 switches the module on, the upper bits (masked with
+// the module's address mask) are the upper bits of the address where
+// the module's memory is mapped.
+struct Module {
+    id: u8,
+    addr_mask: u8,
+    rom: Option<&'static [u8]>,
+    ctrl: u8,
+}
+
+impl Module {
+    // the M001 Digital IN/OUT module, an I/O module without memory
+    pub fn m001() -> Module {
+        Module {
+            id: 0xEF,
+            addr_mask: 0,
+            rom: None,
+            ctrl: 0,
+        }
+    }
+
+    // a ROM module, the type is derived from the size of the dump
+    pub fn rom(path: &str) -> Result<Module, String> {
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        let (id, addr_mask) = match data.len() {
+            0x2000 => (0xFB, 0xE0),
+            0x4000 => (0xFC, 0xC0),
+            len => return Err(format!("{}: not an 8 or 16 KByte ROM module ({} bytes)", path, len)),
+        };
+        Ok(Module {
+            id,
+            addr_mask,
+            rom: Some(Box::leak(data.into_boxed_slice())),
+            ctrl: 0,
+        })
+    }
+}
+
+// events in the system scheduler
+#[derive(Clone,Copy,PartialEq)]
+enum Event {
+    CtcTimer,
+}
+
+// The System struct owns all the hardware components and implements the
+// Bus trait. The PIO output bits and the output latches are copies of
+// the chip state which define the memory mapping, since the memory
+// mapping can't be updated while the CPU is executing an OUT instruction,
+// the remap flag defers the update to the end of the instruction.
+//
+// The CTC timers and the sound oscillators aren't updated after each
+// instruction, instead the scheduler raises an event when the next CTC
+// timer reaches zero, and the vertical retrace with its frame timing.
+struct System {
+    pub cpu: RefCell<CPU>,
+    pub pio: RefCell<PIO>,
+    pub ctc: RefCell<CTC>,
+    pub m001: RefCell<PIO>,
+    pub devices: DeviceMap,
+    pub daisy: RefCell<Daisychain>,
+    pub io: IoMap<System>,
+    pub beepers: RefCell<[Beeper; 2]>,
+    pub sched: RefCell<Scheduler<Event>>,
+    pub slots: RefCell<[Option<Module>; 2]>,
+    roms: Roms,
+    pio_a: Cell<u8>,
+    pio_b: Cell<u8>,
+    io84: Cell<u8>,
+    io86: Cell<u8>,
+    remap: Cell<bool>,
+    irq: Cell<bool>,
+    blink: Cell<bool>,
+    key_code: Cell<u8>,
+    ctc_time: Cell<i64>,
+    beeper_time: Cell<i64>,
+}
+
+impl System {
+    // The KC85/4 decodes the lower 8 bits of the port address:
+    //
+    // 0x30..0x33:  M001 PIO A data, B data, A control, B control
+    // 0x80:        module slots (slot number in the upper 8 bits)
+    // 0x84:        IRM and RAM8 bank latch
+    // 0x86:        RAM4 and CAOS ROM C latch
+    // 0x88..0x8B:  PIO A data, B data, A control, B control
+    // 0x8C..0x8F:  CTC channel 0..3
+    pub fn new(roms: Roms, module: Option<Module>) -> System {
+        let mut io = IoMap::new();
+        io.map(0x00FC, M001_PORT, System::m001_read, System::m001_write);
+        io.map(0x00FF, 0x80, System::module_read, System::module_write);
+        io.map_outp(0x00FF, 0x84, System::io84_write);
+        io.map_outp(0x00FF, 0x86, System::io86_write);
+        io.map(0x00FC, 0x88, System::pio_read, System::pio_write);
+        io.map(0x00FC, 0x8C, System::ctc_read, System::ctc_write);
+
+        // the interrupt daisychain, in priority order
+        let mut devices = DeviceMap::new();
+        devices.add(DeviceKind::Pio, PIO_MAIN, "PIO");
+        devices.add(DeviceKind::Ctc, 0, "CTC");
+        devices.add(DeviceKind::Pio, PIO_M001, "M001");
+
+        let mut sched = Scheduler::new();
+        sched.set_frame_timing(Some(PROFILE_KC85_4.frame_timing().with_vblank_line(VBLANK_LINE)));
+
+        System {
+            cpu: RefCell::new(CPU::new()),
+            pio: RefCell::new(PIO::new(PIO_MAIN)),
+            ctc: RefCell::new(CTC::new(0)),
+            m001: RefCell::new(PIO::new(PIO_M001)),
+            daisy: RefCell::new(Daisychain::new(devices.num_controllers())),
+            devices,
+            io,
+            beepers: RefCell::new([
+                Beeper::new(CLOCK, SAMPLE_RATE as i64, 8192),
+                Beeper::new(CLOCK, SAMPLE_RATE as i64, 8192),
+            ]),
+            sched: RefCell::new(sched),
+            slots: RefCell::new([Some(Module::m001()), module]),
+            roms,
+            pio_a: Cell::new(0),
+            pio_b: Cell::new(0),
+            io84: Cell::new(0),
+            io86: Cell::new(0),
+            remap: Cell::new(false),
+            irq: Cell::new(false),
+            blink: Cell::new(false),
+            key_code: Cell::new(0),
+            ctc_time: Cell::new(0),
+            beeper_time: Cell::new(0),
+        }
+    }
+
+    // first-time init of the emulator
+    pub fn poweron(&self) {
+        // all memory is switched on after reset, the CAOS ROM start
+        // code then sets up the initial memory configuration
+        self.pio_a.set(PIO_A_CAOS_E | PIO_A_RAM0 | PIO_A_IRM | PIO_A_RAM0_WE);
+        self.pio_b.set(PIO_B_RAM8 | PIO_B_RAM8_WE);
+        self.io84.set(0);
+        self.io86.set(IO86_RAM4 | IO86_RAM4_WE | IO86_CAOS_C);
+        let mut cpu = self.cpu.borrow_mut();
+        self.update_memory_map(&mut cpu.mem);
+        cpu.reg.set_pc(0xF000);
+    }
+
+    // map the memory banks selected by the PIO output bits and the output
+    // latches on layer 0, and the memory of active modules on layer 1
+    fn update_memory_map(&self, mem: &mut Memory) {
+        mem.unmap_layer(0);
+        mem.unmap_layer(1);
+        let (pio_a, pio_b) = (self.pio_a.get(), self.pio_b.get());
+        let (io84, io86) = (self.io84.get(), self.io86.get());
+        if (pio_a & PIO_A_RAM0) != 0 {
+            mem.map(0, RAM0, 0x0000, (pio_a & PIO_A_RAM0_WE) != 0, BANK_SIZE);
+        }
+        if (io86 & IO86_RAM4) != 0 {
+            mem.map(0, RAM4, 0x4000, (io86 & IO86_RAM4_WE) != 0, BANK_SIZE);
+        }
+        if (pio_a & PIO_A_IRM) != 0 {
+            // bit 1 of port 0x84 selects pixels or colors, bit 2 the image,
+            // 0xA800..0xBFFF always shows the pixels of image 0
+            let bank = IRM + ((io84 as usize >> 1) & 3) * BANK_SIZE;
+            mem.map(0, bank, 0x8000, true, 0x2800);
+            mem.map(0, IRM + 0x2800, 0xA800, true, 0x1800);
+        } else if (pio_b & PIO_B_RAM8) != 0 {
+            let bank = if (io84 & IO84_RAM8_BANK) != 0 { 1 } else { 0 };
+            mem.map(0, RAM8 + bank * BANK_SIZE, 0x8000, (pio_b & PIO_B_RAM8_WE) != 0, BANK_SIZE);
+        }
+        if (io86 & IO86_CAOS_C) != 0 {
+            mem.map_slice(0, 0xC000, self.roms.caos_c);
+        } else if (pio_a & PIO_A_BASIC) != 0 {
+            mem.map_slice(0, 0xC000, self.roms.basic);
+        }
+        if (pio_a & PIO_A_CAOS_E) != 0 {
+            mem.map_slice(0, 0xE000, self.roms.caos_e);
+        }
+        for module in self.slots.borrow().iter().flatten() {
+            if let Some(rom) = module.rom {
+                if (module.ctrl & 1) != 0 {
+                    let addr = ((module.ctrl & module.addr_mask) as usize) << 8;
+                    mem.map_slice(1, addr, rom);
+                }
+            }
+        }
+    }
+
+    // bit 0 of the port selects the PIO channel, bit 1 data or control
+    fn pio_port_write(&self, pio: &RefCell<PIO>, port: RegT, val: RegT) {
+        let chn = if (port & 1) == 0 {PIO_A} else {PIO_B};
+        if (port & 2) == 0 {
+            pio.borrow_mut().write_data(self, chn, val);
+        } else {
+            // invalid control words are ignored by the PIO
+            let _ = pio.borrow_mut().write_control(chn, val);
+        }
+    }
+    fn pio_port_read(&self, pio: &RefCell<PIO>, port: RegT) -> RegT {
+        let chn = if (port & 1) == 0 {PIO_A} else {PIO_B};
+        if (port & 2) == 0 {
+            pio.borrow_mut().read_data(self, chn)
+        } else {
+            pio.borrow().read_control()
+        }
+    }
+    fn pio_write(&self, port: RegT, val: RegT) {
+        self.pio_port_write(&self.pio, port, val);
+    }
+    fn pio_read(&self, port: RegT) -> RegT {
+        self.pio_port_read(&self.pio, port)
+    }
+    fn m001_write(&self, port: RegT, val: RegT) {
+        self.pio_port_write(&self.m001, port, val);
+    }
+    fn m001_read(&self, port: RegT) -> RegT {
+        self.pio_port_read(&self.m001, port)
+    }
+
+    // the CTC timers are only updated when the CPU accesses the CTC,
+    // when the next CTC timer event in the scheduler is due, and before
+    // the vertical retrace triggers channel 2 and 3
+    fn sync_ctc(&self) {
+        let now = self.sched.borrow().now();
+        let elapsed = now - self.ctc_time.replace(now);
+        if elapsed > 0 {
+            self.ctc.borrow_mut().update_timers(self, elapsed);
+        }
+    }
+    fn schedule_ctc(&self) {
+        let next = self.ctc.borrow().next_event();
+        let mut sched = self.sched.borrow_mut();
+        sched.cancel(|e| *e == Event::CtcTimer);
+        if let Some(next) = next {
+            sched.schedule(next, Event::CtcTimer);
+        }
+    }
+
+    // bring the sound oscillators up to the current time, before their
+    // output changes and at the end of each frame
+    fn sync_beepers(&self) {
+        let now = self.sched.borrow().now();
+        let elapsed = now - self.beeper_time.replace(now);
+        for beeper in self.beepers.borrow_mut().iter_mut() {
+            beeper.update(elapsed);
+        }
+    }
+
+    fn ctc_write(&self, port: RegT, val: RegT) {
+        self.sync_ctc();
+        self.ctc.borrow_mut().write(self, (port & 3) as usize, val);
+        self.schedule_ctc();
+    }
+    fn ctc_read(&self, port: RegT) -> RegT {
+        self.sync_ctc();
+        self.ctc.borrow().read((port & 3) as usize)
+    }
+
+    fn io84_write(&self, _: RegT, val: RegT) {
+        self.io84.set(val as u8);
+        self.remap.set(true);
+    }
+    fn io86_write(&self, _: RegT, val: RegT) {
+        self.io86.set(val as u8);
+        self.remap.set(true);
+    }
+
+    // the upper 8 bits of the port address select the module slot,
+    // empty slots read as 0xFF
+    fn module_read(&self, port: RegT) -> RegT {
+        let slot = SLOTS.iter().position(|&s| s == (port >> 8) & 0xFF);
+        match slot.and_then(|i| self.slots.borrow()[i].as_ref().map(|m| m.id)) {
+            Some(id) => id as RegT,
+            None => 0xFF,
+        }
+    }
+    fn module_write(&self, port: RegT, val: RegT) {
+        if let Some(i) = SLOTS.iter().position(|&s| s == (port >> 8) & 0xFF) {
+            if let Some(ref mut module) = self.slots.borrow_mut()[i] {
+                module.ctrl = val as u8;
+                self.remap.set(true);
+            }
+        }
+    }
+
+    // run the emulator for one frame
+    pub fn step_frame(&self, micro_seconds: i64) {
+        let num_cycles = CLOCK.cycles_from_micros(micro_seconds);
+        let frame_end = self.sched.borrow().now() + num_cycles;
+        while self.sched.borrow().now() < frame_end {
+            let cycles = self.cpu.borrow_mut().step(self);
+            if self.remap.replace(false) {
+                self.update_memory_map(&mut self.cpu.borrow_mut().mem);
+            }
+            self.sched.borrow_mut().advance(cycles);
+
+            // handle due events, the scheduler can't be borrowed while
+            // the events call back into the System
+            loop {
+                let event = self.sched.borrow_mut().pop();
+                match event {
+                    Some((_, Event::CtcTimer)) => {
+                        self.sync_ctc();
+                        self.schedule_ctc();
+                    }
+                    None => break,
+                }
+            }
+            if self.sched.borrow_mut().update_vblank(&NullBus) {
+                self.vblank();
+            }
+            // the daisychain requests interrupts while the CPU is
+            // busy, so the request is forwarded after the instruction
+            if self.irq.replace(false) {
+                self.cpu.borrow_mut().irq();
+            }
+        }
+        self.sync_beepers();
+    }
+
+    // the vertical retrace pulse triggers CTC channel 2 and 3,
+    // and the keyboard handling
+    fn vblank(&self) {
+        self.sync_ctc();
+        {
+            let mut ctc = self.ctc.borrow_mut();
+            for &chn in &[CTC_2, CTC_3] {
+                ctc.trigger_edge(self, chn, true);
+                ctc.trigger_edge(self, chn, false);
+            }
+        }
+        self.schedule_ctc();
+        self.update_keyboard();
+    }
+
+    // A simplified version of the CAOS keyboard interrupt service routine,
+    // which normally decodes the pulses of the serial keyboard: the key
+    // code goes to IX+0x0D, bit 0 of IX+0x08 signals a new key, and
+    // IX+0x0A counts the frames a key is held down for the key repeat.
+    fn update_keyboard(&self) {
+        let mut cpu = self.cpu.borrow_mut();
+        let ix = cpu.reg.ix();
+        let key_code = self.key_code.get() as RegT;
+        let mem = &mut cpu.mem;
+        let status = mem.r8(ix + 0x08);
+        if key_code == 0 {
+            mem.w8(ix + 0x08, status & !1);
+        } else if key_code != mem.r8(ix + 0x0D) {
+            mem.w8(ix + 0x0D, key_code);
+            mem.w8(ix + 0x08, status | 1);
+            mem.w8(ix + 0x0A, 0);
+        } else {
+            // the same key is still pressed, repeat it every 8 frames
+            let repeat = mem.r8(ix + 0x0A);
+            if repeat < 8 {
+                mem.w8(ix + 0x0A, repeat + 1);
+            } else {
+                mem.w8(ix + 0x0A, 0);
+                mem.w8(ix + 0x08, status | 1);
+            }
+        }
+    }
+
+    // Decode the displayed IRM image into the frame buffer. Pixels and
+    // colors are organized in 40 columns of 256 bytes, one byte is 8
+    // pixels of one line. The color attribute byte of the 8 pixels has the
+    // foreground color in bits 3..6, the background color in bits 0..2,
+    // and bit 7 makes the foreground blink (if enabled in PIO channel B).
+    pub fn decode_framebuffer(&self, fb: &mut Framebuffer) {
+        let cpu = self.cpu.borrow();
+        let image = IRM + (self.io84.get() & IO84_DISPLAY_IMAGE) as usize * 2 * BANK_SIZE;
+        let pixels = &cpu.mem.heap[image..image + 0x2800];
+        let colors = &cpu.mem.heap[image + BANK_SIZE..image + BANK_SIZE + 0x2800];
+        let blink_off = (self.pio_b.get() & PIO_B_BLINK) != 0 && !self.blink.get();
+        let dst = fb.pixels_mut();
+        for x in 0..WIDTH / 8 {
+            for y in 0..HEIGHT {
+                let p = pixels[x * 256 + y];
+                let c = colors[x * 256 + y];
+                let bg = BG_COLORS[(c & 7) as usize];
+                let fg = if blink_off && (c & 0x80) != 0 {
+                    bg
+                } else {
+                    FG_COLORS[((c >> 3) & 15) as usize]
+                };
+                for bit in 0..8 {
+                    dst[y * WIDTH + x * 8 + bit] = if (p & (0x80 >> bit)) != 0 { fg } else { bg };
+                }
+            }
+        }
+    }
+
+    // forward a new host key code to the emulator
+    pub fn put_key(&self, key_code: u8) {
+        self.key_code.set(key_code);
+    }
+
+    // fetch the sound samples of both oscillators, mixed into one channel
+    pub fn fill_samples(&self, dst: &mut [f32]) -> usize {
+        let mut beepers = self.beepers.borrow_mut();
+        let num = beepers[0].len().min(beepers[1].len()).min(dst.len());
+        let mut tmp = vec![0.0f32; num];
+        beepers[0].fill(&mut dst[..num]);
+        beepers[1].fill(&mut tmp);
+        for (d, s) in dst.iter_mut().zip(tmp) {
+            *d += s;
+        }
+        num
+    }
+}
+
+impl Bus for System {
+    fn cpu_outp(&self, port: RegT, val: RegT) {
+        self.io.outp(self, port, val);
+    }
+    fn cpu_inp(&self, port: RegT) -> RegT {
+        self.io.inp(self, port)
+    }
+
+    // with the device map, the default pio_irq() and ctc_irq()
+    // forward the chip interrupts to irq() and the daisychain
+    fn devices(&self) -> Option<&DeviceMap> {
+        Some(&self.devices)
+    }
+    fn irq(&self, ctrl_id: usize, vec: u8) {
+        self.daisy.borrow_mut().irq(self, ctrl_id, vec);
+    }
+    fn irq_cpu(&self) {
+        self.irq.set(true);
+    }
+    fn irq_ack(&self) -> RegT {
+        self.daisy.borrow_mut().irq_ack()
+    }
+    fn irq_reti(&self) {
+        self.daisy.borrow_mut().irq_reti(self);
+    }
+
+    // the PIO outputs switch memory banks and control the sound volume
+    // and blinking, the M001 outputs are printed to the console
+    fn pio_outp(&self, pio: usize, chn: usize, data: RegT) {
+        if pio == PIO_MAIN {
+            if chn == PIO_A {
+                self.pio_a.set(data as u8);
+            } else {
+                self.pio_b.set(data as u8);
+                let volume = (!data as u8 & PIO_B_VOLUME) as f32 / PIO_B_VOLUME as f32;
+                self.sync_beepers();
+                for beeper in self.beepers.borrow_mut().iter_mut() {
+                    beeper.set_volume(0.25 * volume);
+                }
+            }
+            self.remap.set(true);
+        } else {
+            println!("M001: port {} = {:02X}h", if chn == PIO_A {'A'} else {'B'}, data);
+        }
+    }
+    fn pio_inp(&self, _: usize, _: usize) -> RegT {
+        0xFF
+    }
+
+    // zero counts of CTC channel 0 and 1 toggle the sound oscillators,
+    // channel 2 the blink flip-flop
+    fn ctc_zero(&self, chn: usize, _: &CTC) {
+        match chn {
+            CTC_0 | CTC_1 => {
+                self.sync_beepers();
+                self.beepers.borrow_mut()[chn].toggle();
+            }
+            CTC_2 => self.blink.set(!self.blink.get()),
+            _ => (),
+        }
+    }
+}
+
+// write mono 16-bit PCM samples to a WAV file
+fn write_wav(path: &str, samples: &[f32]) -> io::Result<()> {
+    let data_size = (samples.len() * 2) as u32;
+    let mut bytes = Vec::with_capacity(44 + samples.len() * 2);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_size).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());              // PCM
+    bytes.extend_from_slice(&1u16.to_le_bytes());              // mono
+    bytes.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    bytes.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // bytes per second
+    bytes.extend_from_slice(&2u16.to_le_bytes());              // bytes per frame
+    bytes.extend_from_slice(&16u16.to_le_bytes());             // bits per sample
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_size.to_le_bytes());
+    for &sample in samples {
+        bytes.extend_from_slice(&((sample * 32767.0) as i16).to_le_bytes());
+    }
+    fs::write(path, bytes)
+}
+
+//--- the main loop
+fn main() {
+    let roms = match Roms::load() {
+        Ok(roms) => roms,
+        Err(err) => panic!("Unable to load ROM dumps (see examples/kc854.rs): {}", err)
+    };
+    // optional ROM module from the command line
+    let module = env::args().nth(1).map(|path| match Module::rom(&path) {
+        Ok(module) => module,
+        Err(err) => panic!("Unable to load module: {}", err)
+    });
+
+    // create a window via minifb
+    let mut window = match Window::new("rz80 KC85/4 Example",
+           WIDTH, HEIGHT,
+           WindowOptions {
+               resize: false,
+               scale: Scale::X2,
+               ..WindowOptions::default()
+           }) {
+        Ok(win) => win,
+        Err(err) => panic!("Unable to create minifb window: {}", err)
+    };
+
+    // the pixel frame buffer, written by System::decode_framebuffer()
+    // and transfered to the minifb window
+    let mut frame_buffer = Framebuffer::new(WIDTH, HEIGHT);
+
+    // spin up the emulator and run the main loop
+    let system = System::new(roms, module);
+    system.poweron();
+
+    // captured sound samples while sound capture is active
+    let mut sound: Option<Vec<f32>> = None;
+    let mut samples = vec![0.0f32; 8192];
+    let mut timer = FrameTimer::new(50);
+    while window.is_open() {
+        let micro_seconds = timer.tick();
+
+        // get keyboard input from minifb
+        let mut key_code: u8 = 0;
+        let shift = window.is_key_down(Key::LeftShift)|window.is_key_down(Key::RightShift);
+        for key in KEYS {
+            if window.is_key_down(key.0) {
+                key_code = if shift {key.2} else {key.1}
+            }
+        }
+        system.put_key(key_code);
+
+        if window.is_key_pressed(Key::F4, KeyRepeat::No) {
+            sound = match sound.take() {
+                None => {
+                    println!("capturing sound, press F4 to stop");
+                    Some(Vec::new())
+                },
+                Some(captured) => {
+                    match write_wav("kc854_sound.wav", &captured) {
+                        Ok(_) => println!("sound written to kc854_sound.wav"),
+                        Err(err) => println!("ERROR: kc854_sound.wav: {}", err),
+                    }
+                    None
+                }
+            };
+        }
+
+        // run the emulator for the current frame
+        system.step_frame(micro_seconds);
+
+        // fetch the generated sound samples
+        let num_samples = system.fill_samples(&mut samples);
+        if let Some(ref mut captured) = sound {
+            captured.extend_from_slice(&samples[..num_samples]);
+        }
+
+        // update the window content, if the host is too slow the
+        // rendering is skipped, but the window still polls the input
+        if timer.skip_frame() {
+            window.update();
+        } else {
+            system.decode_framebuffer(&mut frame_buffer);
+            window.update_with_buffer(frame_buffer.pixels());
+        }
+    }
+}
@@ -1,213 +1,278 @@
 #![allow(unused)]
 extern crate rz80;
-extern crate time;
 extern crate minifb;
 extern crate rand;
 
-use rz80::{CPU,PIO,CTC,Daisychain,Bus,RegT,PIO_A,PIO_B,CTC_0,CTC_1,CTC_2,CTC_3};
+use rz80::{CPU,Memory,PIO,CTC,Daisychain,IoBus,RecordingBus,BusEvent,Scheduler,RegT,PIO_A,PIO_B,Clock,Framebuffer,
+           Z9001Video,Z9001Config,Z9001Model,Z9001_WIDTH,Z9001_HEIGHT,FrameTimer,PROFILE_Z9001};
 use minifb::{Key, Window, Scale, WindowOptions};
-use time::PreciseTime;
-use std::cell::RefCell;
 
 // binary dumps for OS, font and BASIC interpreter
 static OS: &'static [u8] = include_bytes!("dumps/kc87_os_2.bin");
 static FONT: &'static [u8] = include_bytes!("dumps/kc87_font_2.bin");
 static BASIC: &'static [u8] = include_bytes!("dumps/z9001_basic.bin");
 
-// framebuffer dimensions (40x24 characters at 8x8 pixels)
-const WIDTH: usize = 320;
-const HEIGHT: usize = 192;
+// framebuffer dimensions (40x24 characters at 8x8 pixels, and the border)
+const WIDTH: usize = Z9001_WIDTH;
+const HEIGHT: usize = Z9001_HEIGHT;
 // number of keys in key mapping tables
 const MAX_KEYS: usize = 128;
-// CPU frequency in kHZ
-const FREQ_KHZ: i64 = 2458;
+// CPU clock
+const CLOCK: Clock = PROFILE_Z9001.clock;
 
 struct KC87 {
     key_mask: u64,
     kbd_column_mask: u8,
     kbd_line_mask: u8,
     key_map: [u64; MAX_KEYS],
-    blink_flip_flop: bool,
 }
 
-struct System {
-    pub cpu: RefCell<CPU>,
-    pub pio1: RefCell<PIO>,
-    pub pio2: RefCell<PIO>,
-    pub ctc: RefCell<CTC>,
-    pub daisy: RefCell<Daisychain>,
+// events in the system scheduler
+#[derive(Clone,Copy,PartialEq)]
+enum Event {
+    CtcTimer,
 }
 
-impl System {
-    pub fn new() -> System {
-        System {
-            cpu: RefCell::new(CPU::new()),
-            pio1: RefCell::new(PIO::new(0)),
-            pio2: RefCell::new(PIO::new(1)),
-            ctc: RefCell::new(CTC::new(0)),
-            daisy: RefCell::new(Daisychain::new(8))
+// The Board owns all chips except the CPU and implements the IoBus
+// trait, the CPU is stepped with 'cpu.step_io(&mut system.board)' so
+// that the port I/O callbacks get mutable access to the Board without
+// RefCells. The chips are called with the Board's RecordingBus, and
+// the recorded callbacks are applied after the chip call has returned
+// (this is what allows the CTC2 output to trigger CTC3 on the same
+// CTC, which isn't possible from inside Bus::ctc_zero()).
+struct Board {
+    pub pio1: PIO,
+    pub pio2: PIO,
+    pub ctc: CTC,
+    pub daisy: Daisychain,
+    pub sched: Scheduler<Event>,
+    pub video: Z9001Video,
+    ctc_time: i64,
+    callbacks: RecordingBus,
+}
+
+impl Board {
+    pub fn new(config: Z9001Config) -> Board {
+        Board {
+            pio1: PIO::new(0),
+            pio2: PIO::new(1),
+            ctc: CTC::new(0),
+            daisy: Daisychain::new(8),
+            sched: Scheduler::new(),
+            video: Z9001Video::new(config, FONT),
+            ctc_time: 0,
+            callbacks: RecordingBus::new(),
         }
     }
 
-    pub fn poweron(&mut self) {
-        let mut cpu = self.cpu.borrow_mut();
-        
-        // map 48 KByte RAM
-        cpu.mem.map(0, 0x00000, 0x0000, true, 0xC000);
-        // 2 KByte video RAM (1 KByte colors, 1 KByte ASCII)
-        cpu.mem.map(0, 0x0E800, 0xE800, true, 0x0800);
-
-        // BASIC and OS ROMs
-        cpu.mem.map_bytes(1, 0x10000, 0xC000, false, &BASIC);
-        cpu.mem.map_bytes(1, 0x12000, 0xE000, false, &OS);
-
-        // fill video and color RAM with randomness
-        for b in &mut cpu.mem.heap[0x0E800..0xF000] {
-            *b = rand::random();
+    // apply the callbacks recorded during chip calls, this may call
+    // into the chips again, which records new callbacks
+    fn apply_callbacks(&mut self) {
+        loop {
+            let events = self.callbacks.take_events();
+            if events.is_empty() {
+                break;
+            }
+            for (_, event) in events {
+                match event {
+                    // PIO1 channel A bits 3..5 are the border color
+                    BusEvent::PioOutp { pio: 0, chn: PIO_A, data } => {
+                        self.video.pio1_a(data);
+                    }
+                    // the CTC2 output pulse is the CTC3 trigger input
+                    BusEvent::CtcZero { chn: 2 } => {
+                        self.ctc.trigger_edge(&self.callbacks, 3, true);
+                        self.ctc.trigger_edge(&self.callbacks, 3, false);
+                    }
+                    _ => {}
+                }
+            }
         }
+    }
 
-        // set PC to ROM start
-        cpu.reg.set_pc(0xF000);
+    // the CTC timers are only updated when the CPU accesses the CTC,
+    // or when the next CTC timer event in the scheduler is due
+    fn sync_ctc(&mut self) {
+        let now = self.sched.now();
+        let elapsed = now - self.ctc_time;
+        self.ctc_time = now;
+        if elapsed > 0 {
+            self.ctc.update_timers(&self.callbacks, elapsed);
+            self.apply_callbacks();
+        }
     }
-    
-    // run the emulator for one frame
-    pub fn step_frame(&self, micro_seconds: i64) {
-        let num_cycles = (FREQ_KHZ * micro_seconds) / 1000;
-        let mut cur_cycles = 0;
-        while cur_cycles < num_cycles {
-            let op_cycles = self.cpu.borrow_mut().step(self);
-            self.ctc.borrow_mut().update_timers(self, op_cycles);
-            cur_cycles += op_cycles;
+    fn schedule_ctc(&mut self) {
+        self.sched.cancel(|e| *e == Event::CtcTimer);
+        if let Some(next) = self.ctc.next_event() {
+            self.sched.schedule(next, Event::CtcTimer);
         }
     }
 
-    #[inline(always)]
-    fn rgba8(color: u8) -> u32 {
-        match color {
-            0 => 0xFF000000,        // black
-            1 => 0xFFFF0000,        // red
-            2 => 0xFF00FF00,        // green
-            3 => 0xFFFFFF00,        // yellow
-            4 => 0xFF0000FF,        // blue
-            5 => 0xFFFF00FF,        // purple
-            6 => 0xFF00FFFF,        // cyan
-            _ => 0xFFFFFFFF,        // white
-        }
+    fn ctc_write(&mut self, port: RegT, val: RegT) {
+        self.sync_ctc();
+        self.ctc.write(&self.callbacks, (port & 3) as usize, val);
+        self.apply_callbacks();
+        self.schedule_ctc();
+    }
+    fn ctc_read(&mut self, port: RegT) -> RegT {
+        self.sync_ctc();
+        self.ctc.read((port & 3) as usize)
     }
 
-    pub fn decode_framebuffer(&self, fb: &mut [u32]) {
-        let mut fb_iter = fb.iter_mut();
-        let cpu = self.cpu.borrow();
-        let blinking = true;   // FIXME
-        let video_mem = &cpu.mem.heap[0xEC00..0xF000];
-        let color_mem = &cpu.mem.heap[0xE800..0xEC00];
-        let mut off = 0;
-        for y in 0..24 {
-            for py in 0..8 {
-                for x in 0..40 {
-                    let chr = video_mem[off+x] as usize;
-                    let bits = FONT[(chr<<3)|py];
-                    let color = color_mem[off+x];
-                    let b = (color & 0x80) != 0 && blinking;
-                    let fg_bits = if b {color & 7} else {(color>>4) & 7};
-                    let bg_bits = if b {(color>>4) & 7} else {color & 7};
-                    let fg = System::rgba8(fg_bits);
-                    let bg = System::rgba8(bg_bits);
-                    for px in 0..8 {
-                        let pixel = if (bits & (0x80>>px)) != 0 {fg} else {bg};
-                        *fb_iter.next().unwrap() = pixel;
-                    }
-                }
-            }
-            off += 40;
+    // bit 0 of the port selects the PIO channel, bit 1 data or control,
+    // the PIO inputs aren't emulated yet and read as 0
+    fn pio_write(&mut self, pio: usize, port: RegT, val: RegT) {
+        let chn = if (port & 1) == 0 {PIO_A} else {PIO_B};
+        let pio = if pio == 0 { &mut self.pio1 } else { &mut self.pio2 };
+        if (port & 2) == 0 {
+            pio.write_data(&self.callbacks, chn, val);
+        } else {
+            // invalid control words are ignored by the PIO
+            let _ = pio.write_control(chn, val);
         }
+        self.apply_callbacks();
+    }
+    fn pio_read(&mut self, pio: usize, port: RegT) -> RegT {
+        let chn = if (port & 1) == 0 {PIO_A} else {PIO_B};
+        let pio = if pio == 0 { &mut self.pio1 } else { &mut self.pio2 };
+        let val = if (port & 2) == 0 {
+            self.callbacks.set_inp_value(0);
+            pio.read_data(&self.callbacks, chn)
+        } else {
+            pio.read_control()
+        };
+        self.apply_callbacks();
+        val
     }
 }
 
-impl Bus for System {
-
-    fn cpu_outp(&self, port: RegT, val: RegT) {
+// the KC87 only decodes the lower 8 bits of the port address,
+// and each device shows up twice in the I/O map:
+//
+// 0x80..0x87:  CTC channel 0..3
+// 0x88..0x8F:  PIO1 A data, B data, A control, B control
+// 0x90..0x97:  PIO2 A data, B data, A control, B control
+impl IoBus for Board {
+    fn outp(&mut self, port: RegT, val: RegT) {
         println!("cpu_outp: port={:x} val={:x}", port & 0xFF, val);
-        match port & 0xFF {
-            0x80|0x84 => self.ctc.borrow_mut().write(self, CTC_0, val),
-            0x81|0x85 => self.ctc.borrow_mut().write(self, CTC_1, val),
-            0x82|0x86 => self.ctc.borrow_mut().write(self, CTC_2, val),
-            0x83|0x87 => self.ctc.borrow_mut().write(self, CTC_3, val),
-            0x88|0x8C => self.pio1.borrow_mut().write_data(self, PIO_A, val),
-            0x89|0x8D => self.pio1.borrow_mut().write_data(self, PIO_B, val),
-            0x8A|0x8E => self.pio1.borrow_mut().write_control(PIO_A, val),
-            0x8B|0x8F => self.pio1.borrow_mut().write_control(PIO_B, val),
-            0x90|0x94 => self.pio2.borrow_mut().write_data(self, PIO_A, val),
-            0x91|0x95 => self.pio2.borrow_mut().write_data(self, PIO_B, val),
-            0x92|0x96 => self.pio2.borrow_mut().write_control(PIO_A, val),
-            0x93|0x97 => self.pio2.borrow_mut().write_control(PIO_B, val),
-            _ => (),
-            
+        match port & 0xF8 {
+            0x80 => self.ctc_write(port, val),
+            0x88 => self.pio_write(0, port, val),
+            0x90 => self.pio_write(1, port, val),
+            _ => {}
         }
     }
 
-    fn cpu_inp(&self, port: RegT) -> RegT {
+    fn inp(&mut self, port: RegT) -> RegT {
         println!("cpu_inp: port={:x}", port & 0xFF);
-        match port & 0xFF {
-            0x80|0x84 => self.ctc.borrow().read(CTC_0),
-            0x81|0x85 => self.ctc.borrow().read(CTC_1),
-            0x82|0x86 => self.ctc.borrow().read(CTC_2),
-            0x83|0x87 => self.ctc.borrow().read(CTC_3),
-            0x88|0x8C => self.pio1.borrow_mut().read_data(self, PIO_A),
-            0x89|0x8D => self.pio1.borrow_mut().read_data(self, PIO_B),
-            0x8A|0x8E|0x8B|0x8F => self.pio1.borrow().read_control(),
-            0x90|0x94 => self.pio2.borrow_mut().read_data(self, PIO_A),
-            0x91|0x95 => self.pio2.borrow_mut().read_data(self, PIO_B),
-            0x92|0x96|0x93|0x97 => self.pio2.borrow().read_control(),
+        match port & 0xF8 {
+            0x80 => self.ctc_read(port),
+            0x88 => self.pio_read(0, port),
+            0x90 => self.pio_read(1, port),
             _ => 0xFF,
         }
     }
+}
 
-    fn irq(&self, ctrl_id: usize, vec: u8) {
-        println!("irq: ctrl_id={:x} vec={:x}", ctrl_id, vec);
-    }
-    fn irq_cpu(&self) {
-        println!("irq_cpu")
-    }
-    fn irq_ack(&self) -> RegT {
-        println!("irq_ack");
-        0
-    }
-    fn irq_reti(&self) {
-        println!("irq_reti");
-    }
+struct System {
+    pub cpu: CPU,
+    pub board: Board,
+}
 
-    fn pio_outp(&self, pio: usize, chn: usize, data: RegT) {
-        println!("pio_outp: pio={:x} chn={:x} data={:x}", pio, chn, data);
-    }
-    fn pio_inp(&self, pio: usize, chn: usize) -> RegT {
-        println!("pio_in: pio={:x} chn={:x}", pio, chn);
-        0
-    }
-    fn pio_rdy(&self, pio: usize, chn: usize, rdy: bool) {
-        println!("pio_rdy: pio={:x} chn={:x} rdy={:}", pio, chn, rdy);
-    }
-    fn pio_irq(&self, pio: usize, chn: usize, int_vector: RegT) {
-        println!("pio_irq: pio={:x} chn={:x} int_vector{:x}", pio, chn, int_vector);
+impl System {
+    pub fn new(config: Z9001Config) -> System {
+        System {
+            cpu: CPU::new(),
+            board: Board::new(config),
+        }
     }
 
-    fn ctc_write(&self, chn: usize, ctc: &CTC) {
-        println!("ctc_write: chn={:x}", chn);
+    pub fn poweron(&mut self) {
+        let cpu = &mut self.cpu;
+        
+        // map 48 KByte RAM
+        cpu.mem.map(0, 0x00000, 0x0000, true, 0xC000);
+        // 1 KByte video RAM (ASCII), and 1 KByte color RAM of the color module
+        let config = self.board.video.config();
+        cpu.mem.map(0, 0x0EC00, 0xEC00, true, 0x0400);
+        if config.color {
+            cpu.mem.map(0, 0x0E800, 0xE800, true, 0x0400);
+        }
+
+        // BASIC ROM (KC87 only) and OS ROM
+        if config.has_basic_rom() {
+            cpu.mem.map_bytes(1, 0x10000, 0xC000, false, &BASIC);
+        }
+        cpu.mem.map_bytes(1, 0x12000, 0xE000, false, &OS);
+
+        // fill video and color RAM with randomness
+        let noise: Vec<u8> = (0..0x0800).map(|_| rand::random()).collect();
+        cpu.mem.write(0xE800, &noise);
+
+        // set PC to ROM start
+        cpu.reg.set_pc(0xF000);
     }
-    fn ctc_zero(&self, chn: usize, ctc: &CTC) {
-        // blargh, and here we are stuck... CTC2 output trigger is connected
-        // CTC3 input trigger, and here the snake baits its tail...
-        // ...back to the drawing board...
-        println!("ctc_zero: chn={:x}", chn);
+    
+    // run the emulator for one frame
+    pub fn step_frame(&mut self, micro_seconds: i64) {
+        let num_cycles = CLOCK.cycles_from_micros(micro_seconds);
+        let frame_end = self.board.sched.now() + num_cycles;
+        loop {
+            let (now, next) = (self.board.sched.now(), self.board.sched.next_time());
+            if now >= frame_end {
+                break;
+            }
+            // if the CPU is halted, fast-forward to the next
+            // scheduled event or the end of the frame
+            let max_skip = match next {
+                Some(next) if next < frame_end => next - now,
+                _ => frame_end - now,
+            };
+            let mut op_cycles = self.cpu.skip_halt(max_skip);
+            if op_cycles == 0 {
+                op_cycles = self.cpu.step_io(&mut self.board);
+            }
+            self.board.sched.advance(op_cycles);
+            self.board.video.update(op_cycles);
+
+            // handle due events
+            while let Some((_, event)) = self.board.sched.pop() {
+                match event {
+                    Event::CtcTimer => {
+                        self.board.sync_ctc();
+                        self.board.schedule_ctc();
+                    }
+                }
+            }
+        }
     }
-    fn ctc_irq(&self, ctc: usize, chn: usize, int_vector: RegT) {
-        println!("ctc_irq: ctc={:x}, chn={:x}, int_vector={:x}", ctc, chn, int_vector);
+
+    // decode the video and color RAM into the frame buffer
+    pub fn decode_framebuffer(&self, fb: &mut Framebuffer) {
+        self.board.video.decode_mem(&self.cpu.mem, fb);
     }
 }
 
 fn main() {
+    // the Z9001 family model from the command line: --z9001, --kc85-1 or
+    // --kc87 (the default), with or without color module (--color, --mono),
+    // only the KC87 OS ROM is included, which is also used for the
+    // Z9001 and KC85/1 configurations
+    let mut config = Z9001Config::new(Z9001Model::KC87);
+    for arg in std::env::args().skip(1) {
+        config = match arg.as_str() {
+            "--z9001" => Z9001Config::new(Z9001Model::Z9001),
+            "--kc85-1" => Z9001Config::new(Z9001Model::KC85_1),
+            "--kc87" => Z9001Config::new(Z9001Model::KC87),
+            "--color" => config.with_color(true),
+            "--mono" => config.with_color(false),
+            _ => panic!("Unknown option: {}", arg),
+        };
+    }
+
     // create a window via minifb
-    let mut window = match Window::new("rz80 KC87 example (WIP)",
+    let title = format!("rz80 {} example (WIP)", config.name());
+    let mut window = match Window::new(&title,
            WIDTH, HEIGHT,
            WindowOptions {
                resize: false,
@@ -220,24 +285,25 @@ fn main() {
 
     // the pixel frame buffer, written by System::decode_framebuffer()
     // and transfered to the minifb window
-    let mut frame_buffer = vec![0u32; WIDTH*HEIGHT];
+    let mut frame_buffer = Framebuffer::new(WIDTH, HEIGHT);
 
-    let mut system = System::new();
+    let mut system = System::new(config);
     system.poweron();
-    let mut micro_seconds_per_frame: i64 = 0;
+    let mut timer = FrameTimer::new(50);
     while window.is_open() {
-        let start = PreciseTime::now();
+        let micro_seconds = timer.tick();
 
         // run the emulator for the current frame
-        system.step_frame(micro_seconds_per_frame);
+        system.step_frame(micro_seconds);
 
-        // update the window content
-        system.decode_framebuffer(&mut frame_buffer);
-        window.update_with_buffer(&frame_buffer); 
-
-        // measure the elapsed time to run emulator at the correct speed
-        let frame_time = start.to(PreciseTime::now());
-        micro_seconds_per_frame = frame_time.num_microseconds().unwrap();
+        // update the window content, if the host is too slow the
+        // rendering is skipped, but the window still polls the input
+        if timer.skip_frame() {
+            window.update();
+        } else {
+            system.decode_framebuffer(&mut frame_buffer);
+            window.update_with_buffer(frame_buffer.pixels());
+        }
     }
 }
 
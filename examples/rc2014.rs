@@ -0,0 +1,388 @@
+extern crate rz80;
+
+use rz80::{CPU, CTC, SIO, Daisychain, Bus, IoMap, RegT, SerialTransport, Clock, IdeDrive, Scheduler,
+           SIO_A, CTC_0};
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A minimal RC2014-style single board computer without graphics:
+//
+// - 7.3728 MHz Z80 CPU
+// - 32 KByte ROM at 0x0000, 32 KByte RAM at 0x8000
+// - SIO at ports 0x80..0x83 (A control, A data, B control, B data),
+//   channel A is the console, connected to stdin/stdout
+// - CTC at ports 0x88..0x8B, channel 0 is the baud rate generator,
+//   each zero count is one character time on the serial line
+// - optional CF card module at ports 0x10..0x17, backed by the disk
+//   image file passed on the command line:
+//   cargo run --example rc2014 -- cpm.img
+//
+// The SIO and CTC are connected to the interrupt daisychain (the SIO
+// has the highest priority), and the CPU runs in interrupt mode 2.
+//
+// There's no ROM dump included, instead the ROM contains a tiny
+// hand-assembled echo program which prints a banner, and then echoes
+// each received character in upper case from the SIO receive interrupt
+// handler:
+//
+//  0000    DI
+//          LD SP,0000h
+//          JP 0100h
+//  0100    LD A,27h        ; CTC 0: timer, prescaler 256, constant follows
+//          OUT (88h),A
+//          LD A,30         ; 7680 cycles per character (9600 baud)
+//          OUT (88h),A
+//          LD HL,0300h     ; SIO init tables
+//          LD B,9
+//          LD C,80h
+//          OTIR
+//          LD B,5
+//          LD C,82h
+//          OTIR
+//          LD A,02h        ; interrupt vector table at 0200h
+//          LD I,A
+//          IM 2
+//          LD HL,0310h     ; print banner
+//  0120    LD A,(HL)
+//          OR A
+//          JR Z,012Ah
+//          CALL 0130h
+//          INC HL
+//          JR 0120h
+//  012A    EI
+//  012B    HALT
+//          JR 012Bh
+//  0130    PUSH AF         ; PUTC: wait until transmit buffer is empty
+//  0131    IN A,(80h)
+//          BIT 2,A
+//          JR Z,0131h
+//          POP AF
+//          OUT (81h),A
+//          RET
+//  0140    PUSH AF         ; SIO channel A receive interrupt
+//          IN A,(81h)
+//          CP 'a'
+//          JR C,014Dh
+//          CP 'z'+1
+//          JR NC,014Dh
+//          SUB 20h
+//  014D    CALL 0130h
+//          CP 0Dh
+//          JR NZ,0159h
+//          LD A,0Ah
+//          CALL 0130h
+//  0159    POP AF
+//          EI
+//          RETI
+//  020C    DW 0140h        ; vector for channel A receive (status affects vector)
+//  0300    DB 18h,04h,44h,03h,C1h,05h,68h,01h,18h   ; SIO A: reset, x16 1 stop bit,
+//                                                   ; rx 8 bits, tx 8 bits, rx int
+//  0309    DB 18h,02h,00h,01h,04h                   ; SIO B: reset, vector 0,
+//                                                   ; status affects vector
+//  0310    DB "...",0                               ; banner
+//
+const ROM_PROGRAM: &[(usize, &[u8])] = &[
+    (0x0000, &[0xF3, 0x31, 0x00, 0x00, 0xC3, 0x00, 0x01]),
+    (0x0100, &[0x3E, 0x27, 0xD3, 0x88, 0x3E, 0x1E, 0xD3, 0x88,
+               0x21, 0x00, 0x03, 0x06, 0x09, 0x0E, 0x80, 0xED, 0xB3,
+               0x06, 0x05, 0x0E, 0x82, 0xED, 0xB3,
+               0x3E, 0x02, 0xED, 0x47, 0xED, 0x5E,
+               0x21, 0x10, 0x03,
+               0x7E, 0xB7, 0x28, 0x06, 0xCD, 0x30, 0x01, 0x23, 0x18, 0xF6,
+               0xFB, 0x76, 0x18, 0xFD]),
+    (0x0130, &[0xF5, 0xDB, 0x80, 0xCB, 0x57, 0x28, 0xFA, 0xF1, 0xD3, 0x81, 0xC9]),
+    (0x0140, &[0xF5, 0xDB, 0x81, 0xFE, 0x61, 0x38, 0x06, 0xFE, 0x7B, 0x30, 0x02, 0xD6, 0x20,
+               0xCD, 0x30, 0x01, 0xFE, 0x0D, 0x20, 0x05, 0x3E, 0x0A, 0xCD, 0x30, 0x01,
+               0xF1, 0xFB, 0xED, 0x4D]),
+    (0x020C, &[0x40, 0x01]),
+    (0x0300, &[0x18, 0x04, 0x44, 0x03, 0xC1, 0x05, 0x68, 0x01, 0x18,
+               0x18, 0x02, 0x00, 0x01, 0x04]),
+    (0x0310, b"rz80 RC2014 example, type something (Ctrl-D to quit)\r\n\0"),
+];
+
+const CLOCK: Clock = Clock::new(7_372_800);
+
+// interrupt daisychain priorities
+const DAISY_SIO: usize = 0;
+const DAISY_CTC: usize = 1;
+
+// events in the system scheduler
+#[derive(Clone,Copy,PartialEq)]
+enum Event {
+    CtcTimer,
+}
+
+// the console: stdin is read on a separate thread so the emulation never blocks
+struct StdioSerial {
+    rx: Receiver<u8>,
+    eof: bool,
+}
+
+impl StdioSerial {
+    fn new() -> StdioSerial {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for byte in stdin.lock().bytes() {
+                match byte {
+                    // terminals send LF, the ROM expects CR
+                    Ok(b'\n') => tx.send(b'\r'),
+                    Ok(b) => tx.send(b),
+                    Err(_) => break,
+                }.unwrap_or(());
+            }
+        });
+        StdioSerial { rx, eof: false }
+    }
+}
+
+impl SerialTransport for StdioSerial {
+    fn send(&mut self, byte: u8) {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let _ = out.write_all(&[byte]);
+        let _ = out.flush();
+    }
+    fn recv(&mut self) -> Option<u8> {
+        match self.rx.try_recv() {
+            Ok(b) => Some(b),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.eof = true;
+                None
+            }
+        }
+    }
+}
+
+struct System {
+    pub cpu: RefCell<CPU>,
+    pub sio: RefCell<SIO>,
+    pub ctc: RefCell<CTC>,
+    pub daisy: RefCell<Daisychain>,
+    pub serial: RefCell<StdioSerial>,
+    pub cf: RefCell<Option<IdeDrive>>,
+    pub io: IoMap<System>,
+    pub sched: RefCell<Scheduler<Event>>,
+    ctc_time: Cell<i64>,
+    irq: Cell<bool>,
+}
+
+impl System {
+    pub fn new() -> System {
+        let mut io = IoMap::new();
+        io.map(0x00FC, 0x80, System::sio_read, System::sio_write);
+        io.map(0x00FC, 0x88, System::ctc_read, System::ctc_write);
+        io.map(0x00F8, 0x10, System::cf_read, System::cf_write);
+        System {
+            cpu: RefCell::new(CPU::new()),
+            sio: RefCell::new(SIO::new(0)),
+            ctc: RefCell::new(CTC::new(0)),
+            daisy: RefCell::new(Daisychain::new(2)),
+            serial: RefCell::new(StdioSerial::new()),
+            cf: RefCell::new(None),
+            io,
+            sched: RefCell::new(Scheduler::new()),
+            ctc_time: Cell::new(0),
+            irq: Cell::new(false),
+        }
+    }
+
+    pub fn poweron(&mut self) {
+        let mut cpu = self.cpu.borrow_mut();
+        let mut rom = vec![0u8; 0x8000];
+        for &(addr, bytes) in ROM_PROGRAM {
+            rom[addr..addr + bytes.len()].copy_from_slice(bytes);
+        }
+        cpu.mem.map_bytes(0, 0x00000, 0x0000, false, &rom);
+        cpu.mem.map(0, 0x08000, 0x8000, true, 0x8000);
+        cpu.reg.set_pc(0x0000);
+    }
+
+    // bit 0 of the port selects data or control, bit 1 the channel
+    fn sio_write(&self, port: RegT, val: RegT) {
+        let chn = ((port >> 1) & 1) as usize;
+        let mut sio = self.sio.borrow_mut();
+        if (port & 1) == 0 {
+            sio.write_control(chn, val);
+        } else {
+            sio.write_data(self, chn, val);
+        }
+    }
+    fn sio_read(&self, port: RegT) -> RegT {
+        let chn = ((port >> 1) & 1) as usize;
+        let mut sio = self.sio.borrow_mut();
+        if (port & 1) == 0 {
+            sio.read_control(chn)
+        } else {
+            sio.read_data(chn)
+        }
+    }
+
+    // the CTC timers are only updated when the CPU accesses the CTC,
+    // or when the next CTC timer event in the scheduler is due
+    fn sync_ctc(&self) {
+        let now = self.sched.borrow().now();
+        let elapsed = now - self.ctc_time.replace(now);
+        if elapsed > 0 {
+            self.ctc.borrow_mut().update_timers(self, elapsed);
+        }
+    }
+    fn schedule_ctc(&self) {
+        let next = self.ctc.borrow().next_event();
+        let mut sched = self.sched.borrow_mut();
+        sched.cancel(|e| *e == Event::CtcTimer);
+        if let Some(next) = next {
+            sched.schedule(next, Event::CtcTimer);
+        }
+    }
+
+    fn ctc_write(&self, port: RegT, val: RegT) {
+        self.sync_ctc();
+        self.ctc.borrow_mut().write(self, (port & 3) as usize, val);
+        self.schedule_ctc();
+    }
+    fn ctc_read(&self, port: RegT) -> RegT {
+        self.sync_ctc();
+        self.ctc.borrow().read((port & 3) as usize)
+    }
+
+    // the lower 3 port bits select the IDE register, without a
+    // CF card the port reads as 0xFF (floating bus)
+    fn cf_write(&self, port: RegT, val: RegT) {
+        if let Some(ref mut cf) = *self.cf.borrow_mut() {
+            cf.write((port & 7) as usize, val);
+        }
+    }
+    fn cf_read(&self, port: RegT) -> RegT {
+        match *self.cf.borrow_mut() {
+            Some(ref mut cf) => cf.read((port & 7) as usize),
+            None => 0xFF,
+        }
+    }
+
+    // run the emulator for a number of CPU cycles
+    pub fn step(&self, num_cycles: i64) {
+        let end = self.sched.borrow().now() + num_cycles;
+        loop {
+            let (now, next) = {
+                let sched = self.sched.borrow();
+                (sched.now(), sched.next_time())
+            };
+            if now >= end {
+                break;
+            }
+            // the ROM waits for interrupts in HALT, fast-forward to
+            // the next scheduled event or the end of the slice
+            let max_skip = match next {
+                Some(next) if next < end => next - now,
+                _ => end - now,
+            };
+            let mut cycles = self.cpu.borrow_mut().skip_halt(max_skip);
+            if cycles == 0 {
+                cycles = self.cpu.borrow_mut().step(self);
+            }
+            self.sched.borrow_mut().advance(cycles);
+
+            // handle due events, the scheduler can't be borrowed while
+            // the events call back into the System
+            loop {
+                let event = self.sched.borrow_mut().pop();
+                match event {
+                    Some((_, Event::CtcTimer)) => {
+                        self.sync_ctc();
+                        self.schedule_ctc();
+                    }
+                    None => break,
+                }
+            }
+            if self.irq.replace(false) {
+                self.cpu.borrow_mut().irq();
+            }
+        }
+    }
+
+    // stdin was closed and all input has been consumed
+    pub fn done(&self) -> bool {
+        self.serial.borrow().eof
+    }
+}
+
+impl Bus for System {
+    fn cpu_outp(&self, port: RegT, val: RegT) {
+        self.io.outp(self, port, val);
+    }
+    fn cpu_inp(&self, port: RegT) -> RegT {
+        self.io.inp(self, port)
+    }
+
+    // the daisychain forwards interrupt requests to the CPU, the
+    // CPU may be busy executing an instruction at this point, so
+    // the request is stored and forwarded after the instruction
+    fn irq(&self, ctrl_id: usize, vec: u8) {
+        self.daisy.borrow_mut().irq(self, ctrl_id, vec);
+    }
+    fn irq_cpu(&self) {
+        self.irq.set(true);
+    }
+    fn irq_ack(&self) -> RegT {
+        self.daisy.borrow_mut().irq_ack()
+    }
+    fn irq_reti(&self) {
+        self.daisy.borrow_mut().irq_reti(self);
+    }
+
+    fn sio_outp(&self, _sio: usize, chn: usize, data: RegT) {
+        if chn == SIO_A {
+            self.serial.borrow_mut().send(data as u8);
+        }
+    }
+    fn sio_irq(&self, _sio: usize, _chn: usize, int_vector: RegT) {
+        self.irq(DAISY_SIO, int_vector as u8);
+    }
+    fn ctc_irq(&self, _ctc: usize, _chn: usize, int_vector: RegT) {
+        self.irq(DAISY_CTC, int_vector as u8);
+    }
+
+    // CTC channel 0 is the baud rate generator, one character
+    // can be received per zero count
+    fn ctc_zero(&self, chn: usize, _ctc: &CTC) {
+        if chn == CTC_0 && self.sio.borrow().rx_ready(SIO_A) {
+            if let Some(byte) = self.serial.borrow_mut().recv() {
+                self.sio.borrow_mut().receive(self, SIO_A, byte);
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut system = System::new();
+    system.poweron();
+    if let Some(path) = env::args().nth(1) {
+        match IdeDrive::open(&path) {
+            Ok(cf) => *system.cf.borrow_mut() = Some(cf),
+            Err(err) => {
+                eprintln!("failed to open CF card image '{}': {}", path, err);
+                return;
+            }
+        }
+    }
+
+    // run in 10 millisecond slices, and sleep for the rest of each slice
+    let slice = Duration::from_millis(10);
+    let slice_cycles = CLOCK.cycles_from_micros(10_000);
+    while !system.done() {
+        let start = Instant::now();
+        system.step(slice_cycles);
+        let elapsed = start.elapsed();
+        if elapsed < slice {
+            thread::sleep(slice - elapsed);
+        }
+    }
+    // give the ROM some time to echo the last characters
+    system.step(slice_cycles);
+}
@@ -4,11 +4,8 @@
 // The Z1013 is a very simple Z80-based home computer, just a CPU,
 // a PIO, some RAM, ROM and a keyboard matrix: 
 //
-// Since this is just a minimal sample, some Z1013 features
-// are not implemented, most notably cassette tape in/out and 
-// sound output. Also, since the Z1013 doesn't require
-// interrupts to run, all interrupt handling and
-// the interrupt controller daisychain have been left out.
+// Since the Z1013 doesn't require interrupts to run, all interrupt
+// handling and the interrupt controller daisychain have been left out.
 //
 // For convenience, a BASIC interpreter has been preloaded (this would
 // normally happen by loading from cassette tape). To start the
@@ -25,15 +22,39 @@
 // >RUN[Enter]
 // 
 // To leave the BASIC interpreter, type 'BYE[Enter]'
+//
+// Cassette tape files can be passed on the command line, either
+// as memory image (.z80 with header, KC85 .kcc and .tap files, or a
+// headerless .com file at 0x0100) or as recorded tape signal (.tap):
+//
+// > cargo run --release --example z1013 -- game.z80
+//
+// F1:  instant-load the tape file directly into memory
+// F2:  play the tape, and type 'L aaaa eeee[Enter]' to load it
+//      through the monitor's cassette routine (the load and end
+//      address of a .z80 file is printed on the console)
+// F3:  start recording, type 'S aaaa eeee[Enter]' to save a memory
+//      range, and press F3 again to stop recording, this writes
+//      the tape signal to 'z1013_save.tap' and the decoded data
+//      to 'z1013_save.z80'
+//
+// The cassette tape output bit also drives the speaker. Since minifb
+// has no audio output, the sound can be captured into a WAV file:
+//
+// F4:  start capturing sound, press F4 again to stop capturing and
+//      write the sound to 'z1013_sound.wav'
 
 extern crate rz80;
-extern crate time;
 extern crate minifb;
 
-use rz80::{CPU, PIO, Bus, RegT, PIO_A, PIO_B};
-use minifb::{Key, Window, Scale, WindowOptions};
-use time::PreciseTime;
-use std::cell::RefCell;
+use rz80::{CPU, Memory, PIO, IoBus, RecordingBus, BusEvent, RegT, RomRegistry, PIO_A, PIO_B, Clock, Tape,
+           TapeFile, Beeper, TextMode, Framebuffer, read_tap, write_tap, z1013_encode,
+           z1013_decode, FrameTimer, InputQueue, InputEvent, KeyMatrix, ProgramFormat,
+           load_program, Scheduler, PROFILE_Z1013};
+use minifb::{Key, KeyRepeat, Window, Scale, WindowOptions};
+use std::env;
+use std::fs;
+use std::io;
 
 // import binary dumps of the operating system, font data and BASIC interpreter
 static OS:      &'static [u8] = include_bytes!("dumps/z1013_mon_a2.bin");
@@ -43,10 +64,12 @@ static BASIC:   &'static [u8] = include_bytes!("dumps/kc_basic.z80");
 // framebuffer dimensions (32x32 characters @ 8x8 pixels)
 const WIDTH: usize=256;
 const HEIGHT: usize=256;
-// number of entries in key-mapping tables
-const MAX_KEYS: usize=128;
-// CPU frequency in KHz
-const FREQ_KHZ: i64=2000;
+// minimal time a host key is held down or released (one 50Hz frame)
+const KEY_HOLD_CYCLES: i64=PROFILE_Z1013.cycles_per_frame();
+// CPU clock
+const CLOCK: Clock=PROFILE_Z1013.clock;
+// audio sample rate
+const SAMPLE_RATE: u32=44100;
 
 // a mapping of all required minifb key codes to their ASCII values, the
 // first ASCII value is with shift-key released, the second with shift-key pressed
@@ -92,12 +115,16 @@ static KEY_MATRIX: &'static [u8] =
 // To select the 'upper' or 'lower' 4 lines of the keyboard matrix,
 // the CPU does a write to PIO-B with bit 4 on or off.
 //
-// The key_map array member holds the complete 64-bit keyboard matrix 
-// state for each possible ASCII code. Whenever a key is in pressed state
-// on the host machine, the next_kbd_matrix_bits will be set
-// to the corresponding keyboard matrix state. Whenever 
-// a new keyboard polling sequence starts in the emulator, this
-// next_kbd_matrix_bits mask is copied into the kbd_matrix_bits
+// The KeyMatrix maps each ASCII code to its 64-bit keyboard matrix
+// state. Host key presses and releases go through the System's
+// InputQueue, which forwards them to the KeyMatrix at the right
+// emulated cycle, and holds each key long enough for the Z1013 OS
+// to see it, even when several keys are typed within one frame.
+// Each key is pressed and released independently, so several keys
+// can be held down at the same time (e.g. cursor keys and space in
+// games which read the keyboard matrix directly).
+// Whenever a new keyboard polling sequence starts in the emulator,
+// the KeyMatrix state is copied into the kbd_matrix_bits
 // which remains valid until the keyboard polling sequence 
 // is finished. The kbd_matrix_bits member is essentially the
 // current state of the keyboard matrix, which remains valid
@@ -118,9 +145,9 @@ static KEY_MATRIX: &'static [u8] =
 struct Z1013 {
     kbd_column_nr_requested: usize,     // kbd matrix column 'lit up' by CPU
     kbd_high_lines_requested: bool,     // get upper or lower 4 kbd matrix lines
-    next_kbd_matrix_bits: u64,          // kbd matrix state of 'next' key
     kbd_matrix_bits: u64,               // kbd matrix state of current key
-    key_map: [u64; MAX_KEYS],           // kbd matrix state table for all keys
+    key_matrix: KeyMatrix,              // kbd matrix state of host keys
+    tape_out: bool,                     // last cassette tape output level
 }
 
 impl Z1013 {
@@ -128,136 +155,233 @@ impl Z1013 {
         Z1013 {
             kbd_column_nr_requested: 0,
             kbd_high_lines_requested: false,
-            next_kbd_matrix_bits: 0,
             kbd_matrix_bits: 0,
-            key_map: Z1013::key_map(),
+            key_matrix: Z1013::key_matrix(),
+            tape_out: false,
         }
     }
 
-    // get the keyboard matrix state bit for a given column and line
-    fn key_bit(col: usize, line: usize) -> u64 {
-        (1u64<<line)<<(col*8)
-    }
-    
     // get the matrix state bits for a column/line with shift key status
     fn key_mask(col: usize, line: usize, shift:bool) -> u64 {
-        Z1013::key_bit(col, line) | if shift {Z1013::key_bit(7, 6)} else {0}
+        KeyMatrix::bit(col, line) | if shift {KeyMatrix::bit(7, 6)} else {0}
     }
 
-    // get the entire keyboard matrix state map
-    fn key_map() -> [u64; MAX_KEYS] {
-        let mut map = [0u64; MAX_KEYS];
+    // get the keyboard matrix with the state bits of all keys
+    fn key_matrix() -> KeyMatrix {
+        let mut kbd = KeyMatrix::new();
         for shift in 0..2 {
             for line in 0..8 {
                 for col in 0..8 {
-                    let c = KEY_MATRIX[shift*64 + line*8 + col] as usize;
+                    let c = KEY_MATRIX[shift*64 + line*8 + col];
                     if 0x20 != c {
-                        map[c] = Z1013::key_mask(col, line, shift != 0);
+                        kbd.map(c, Z1013::key_mask(col, line, shift != 0));
                     }
                 }
             }
         }
 
         // special keys
-        map[0x20] = Z1013::key_bit(6, 4);    // space
-        map[0x08] = Z1013::key_bit(6, 2);    // cursor left
-        map[0x09] = Z1013::key_bit(6, 3);    // cursor right
-        map[0x0A] = Z1013::key_bit(6, 7);    // cursor down
-        map[0x0B] = Z1013::key_bit(6, 6);    // cursor up
-        map[0x0D] = Z1013::key_bit(6, 1);    // enter
+        kbd.map(0x20, KeyMatrix::bit(6, 4));    // space
+        kbd.map(0x08, KeyMatrix::bit(6, 2));    // cursor left
+        kbd.map(0x09, KeyMatrix::bit(6, 3));    // cursor right
+        kbd.map(0x0A, KeyMatrix::bit(6, 7));    // cursor down
+        kbd.map(0x0B, KeyMatrix::bit(6, 6));    // cursor up
+        kbd.map(0x0D, KeyMatrix::bit(6, 1));    // enter
 
         // Ctrl+C (== STOP/BREAK)
-        map[0x03] = Z1013::key_bit(6, 5) | Z1013::key_bit(1, 3);
+        kbd.map(0x03, KeyMatrix::bit(6, 5) | KeyMatrix::bit(1, 3));
 
-        map
+        kbd
     }
+}
+
+// A tape file from the command line, either a memory image with
+// header (.z80), or a recorded tape signal (.tap)
+enum TapeImage {
+    File(TapeFile),
+    Pulses(Vec<i64>),
+}
 
-    // update next keyboard matrix state when a host machine key is pressed
-    pub fn put_key(&mut self, ascii: u8) {
-        self.next_kbd_matrix_bits = match ascii {
-            0 => 0,
-            _ => self.key_map[(ascii as usize) & (MAX_KEYS-1)]
-        };
+impl TapeImage {
+    pub fn open(path: &str) -> Result<TapeImage, String> {
+        let bytes = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        let path_lower = path.to_lowercase();
+        if path_lower.ends_with(".tap") && !bytes.starts_with(b"\xC3KC-TAPE") {
+            read_tap(&bytes, CLOCK).map(TapeImage::Pulses)
+        }
+        else {
+            let format = if path_lower.ends_with(".tap") {
+                ProgramFormat::KcTap
+            } else if path_lower.ends_with(".kcc") {
+                ProgramFormat::Kcc
+            } else if path_lower.ends_with(".com") {
+                ProgramFormat::Com(0x0100)
+            } else {
+                ProgramFormat::Z80
+            };
+            TapeFile::parse(format, &bytes).map(TapeImage::File)
+        }.map_err(|err| format!("{}: {}", path, err))
     }
 }
 
-// The System struct owns all the hardware components and implements the 
-// Bus trait, which implements the emulator-specific 'wiring'.
-// The use of RefCell here is a bit smelly :/
-struct System {
-    pub cpu: RefCell<CPU>,
-    pub pio: RefCell<PIO>,
-    pub z1013: RefCell<Z1013>,
+// The Board struct owns the chips and devices which the CPU talks to
+// through port I/O, and implements the IoBus trait. The CPU lives
+// next to it in the System struct, and is stepped with
+// 'cpu.step_io(&mut system.board)', so that the Board gets mutable
+// access to itself in the port I/O callbacks, and no RefCell is needed
+// (the borrow checker accepts this because the cpu and board fields are
+// borrowed separately).
+//
+// The PIO reports its output and asks for its input through Bus
+// callbacks. Instead of implementing the Bus trait on the Board (which
+// would only get a shared reference), the PIO is handed the Board's
+// RecordingBus: the PIO input value is set before a read, and the
+// recorded output callbacks are applied after the PIO call has returned,
+// when the Board can be mutably borrowed again.
+//
+// The tape and the speaker aren't updated after each instruction, they
+// are brought up to the scheduler's current time when the CPU reads or
+// writes the PIO. The only scheduled event is the delivery of the next
+// host key event.
+struct Board {
+    pio: PIO,
+    z1013: Z1013,
+    tape: Tape,
+    beeper: Beeper,
+    sched: Scheduler<Event>,
+    tape_time: i64,
+    beeper_time: i64,
+    callbacks: RecordingBus,
 }
 
-// The Bus trait, implemented for the Z1013. This defines how the
-// various hardware components in an emulated system talk to each other.
-impl Bus for System {
-
-    // cpu_outp() is called when the CPU executes an OUT instruction, on the
-    // Z1013 there are 5 important output ports:
-    //
-    // 0x00:    PIO-A data (unused)
-    // 0x01:    PIO-A control (unused)
-    // 0x02:    PIO-B data (keyboard input)
-    // 0x03:    PIO-B control (keyboard input)
-    // 0x08:    light up keyboard matrix columns
-    //
-    // For the output ports 0x00 to 0x03, the method will simply forward
-    // the output value to the respective PIO write function. For
-    // port 0x08, the requested keyboard column is stored for later
-    // when the CPU reads back the keyboard matrix line state.
-    fn cpu_outp(&self, port: RegT, val: RegT) {
+// events in the system scheduler
+#[derive(Clone,Copy,PartialEq)]
+enum Event {
+    Input,
+}
+
+// The IoBus trait, implemented for the Z1013. The Z1013 only decodes
+// the lower 8 bits of the port address, there are 5 important I/O ports:
+//
+// 0x00:    PIO-A data (unused)
+// 0x01:    PIO-A control (unused)
+// 0x02:    PIO-B data (keyboard input)
+// 0x03:    PIO-B control (keyboard input)
+// 0x08:    light up keyboard matrix columns
+impl IoBus for Board {
+    fn outp(&mut self, port: RegT, val: RegT) {
         match port & 0xFF {
-            0x00 => self.pio.borrow_mut().write_data(self, PIO_A, val),
-            0x01 => self.pio.borrow_mut().write_control(PIO_A, val),
-            0x02 => self.pio.borrow_mut().write_data(self, PIO_B, val),
-            0x03 => self.pio.borrow_mut().write_control(PIO_B, val),
-            0x08 => {
-                let mut z1013 = self.z1013.borrow_mut();
-                if val == 0 {
-                    // OS starts reading out a new key
-                    z1013.kbd_matrix_bits = z1013.next_kbd_matrix_bits;
-                }
-                z1013.kbd_column_nr_requested = val as usize;
-            },
-            _ => ()
+            0x00..=0x03 => self.pio_write(port, val),
+            0x08 => self.kbd_column_write(val),
+            _ => {}
         }
     }
-    
-    // cpu_inp() is called when the CPU executes an IN instruction,
-    // it simply reads the PIO data and control registers back
-    fn cpu_inp(&self, port: RegT) -> RegT {
+    fn inp(&mut self, port: RegT) -> RegT {
         match port & 0xFF {
-            0x00 => self.pio.borrow_mut().read_data(self, PIO_A),
-            0x01 => self.pio.borrow_mut().read_control(),
-            0x02 => self.pio.borrow_mut().read_data(self, PIO_B),
-            0x03 => self.pio.borrow_mut().read_control(),
-            _ => 0xFF
+            0x00..=0x03 => self.pio_read(port),
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Board {
+    pub fn new() -> Board {
+        Board {
+            pio: PIO::new(0),
+            z1013: Z1013::new(),
+            tape: Tape::new(),
+            beeper: Beeper::new(CLOCK, SAMPLE_RATE as i64, 8192),
+            sched: Scheduler::new(),
+            tape_time: 0,
+            beeper_time: 0,
+            callbacks: RecordingBus::new(),
+        }
+    }
+
+    // bring the tape up to the current time, before its level is
+    // read or a recorded edge is written
+    fn sync_tape(&mut self) {
+        let now = self.sched.now();
+        self.tape.update(now - self.tape_time);
+        self.tape_time = now;
+    }
+
+    // bring the speaker up to the current time, before its level
+    // changes and at the end of each frame
+    fn sync_beeper(&mut self) {
+        let now = self.sched.now();
+        self.beeper.update(now - self.beeper_time);
+        self.beeper_time = now;
+    }
+
+    // For the ports 0x00 to 0x03, the output value is simply forwarded
+    // to the respective PIO data or control register, bit 1 of the port
+    // selects the PIO channel, bit 0 selects data or control
+    fn pio_write(&mut self, port: RegT, val: RegT) {
+        let chn = if (port & 2) == 0 {PIO_A} else {PIO_B};
+        if (port & 1) == 0 {
+            self.pio.write_data(&self.callbacks, chn, val);
+            self.apply_callbacks();
+        }
+        else {
+            // invalid control words are ignored by the PIO
+            let _ = self.pio.write_control(chn, val);
         }
     }
 
-    // pio_outp() is called when a PIO data register is written,
-    // the second '_' parameter is an ID for the PIO, this is
-    // only important for emulated systems with multiple PIOs.
-    // The only thing that's happening here is checking whether
-    // bit 4 is set when writing to PIO-B, this tells us whether
-    // the lower or upper 4 keyboard matrix lines are requested
-    // in the next read of PIO-B
-    fn pio_outp(&self, _: usize, chn: usize, data: RegT) {
+    // ...reading simply reads the PIO data and control registers back
+    fn pio_read(&mut self, port: RegT) -> RegT {
+        let chn = if (port & 2) == 0 {PIO_A} else {PIO_B};
+        if (port & 1) == 0 {
+            self.sync_tape();
+            let input = self.pio_input(chn);
+            self.callbacks.set_inp_value(input);
+            let val = self.pio.read_data(&self.callbacks, chn);
+            self.apply_callbacks();
+            val
+        }
+        else {
+            self.pio.read_control()
+        }
+    }
+
+    // apply the PIO output callbacks recorded during a PIO call
+    fn apply_callbacks(&mut self) {
+        for (_, event) in self.callbacks.take_events() {
+            if let BusEvent::PioOutp { chn, data, .. } = event {
+                self.pio_outp(chn, data);
+            }
+        }
+    }
+
+    // Called when a PIO data register is written. The only thing
+    // that's happening here is checking whether bit 4 is set when
+    // writing to PIO-B, this tells us whether the lower or upper 4
+    // keyboard matrix lines are requested in the next read of PIO-B.
+    // Bit 7 of PIO-B is the cassette tape output, each level change
+    // is a tape signal edge, and also moves the speaker membrane
+    fn pio_outp(&mut self, chn: usize, data: RegT) {
         if chn == PIO_B {
-            let mut z1013 = self.z1013.borrow_mut();
+            let z1013 = &mut self.z1013;
             z1013.kbd_high_lines_requested = 0 != (data & (1<<4));
+            let tape_out = 0 != (data & (1<<7));
+            if tape_out != z1013.tape_out {
+                z1013.tape_out = tape_out;
+                self.sync_tape();
+                self.sync_beeper();
+                self.tape.toggle();
+                self.beeper.set(tape_out);
+            }
         }
     }
 
-    // pio_inp() is called when a PIO data register is read, and this
+    // The PIO input value when a PIO data register is read, and this
     // is the final piece in the keyboard emulation puzzle
     // where the upper or lower 4 lines of the keyboard matrix
-    // are returned
-    fn pio_inp(&self, _: usize, chn: usize) -> RegT {
+    // are returned, bit 6 is the cassette tape input
+    fn pio_input(&self, chn: usize) -> RegT {
         if chn == PIO_B {
-            let z1013 = self.z1013.borrow();
+            let z1013 = &self.z1013;
             let col = z1013.kbd_column_nr_requested & 7;
             let mut val = z1013.kbd_matrix_bits >> (col*8);
             if z1013.kbd_high_lines_requested {
@@ -268,6 +392,9 @@ impl Bus for System {
             // the keyboard matrix logic is 'active low', so 
             // invert all the relevant bits
             val = 0xF & !(val & 0xF);
+            if self.tape.level() {
+                val |= 1<<6;
+            }
             val as RegT
         }
         else {
@@ -275,20 +402,43 @@ impl Bus for System {
             0xFF
         }
     }
+
+    // For port 0x08, the requested keyboard column is stored for later
+    // when the CPU reads back the keyboard matrix line state.
+    fn kbd_column_write(&mut self, val: RegT) {
+        let z1013 = &mut self.z1013;
+        if val == 0 {
+            // OS starts reading out a new key
+            z1013.kbd_matrix_bits = z1013.key_matrix.state();
+        }
+        z1013.kbd_column_nr_requested = val as usize;
+    }
+}
+
+// The System struct owns the CPU, the Board with the I/O devices,
+// and the host-side state (keyboard input queue and text decoder).
+struct System {
+    pub cpu: CPU,
+    pub board: Board,
+    pub input: InputQueue,
+    pub text: TextMode,
+    input_time: i64,
 }
- 
+
 impl System {
     pub fn new() -> System {
         System {
-            cpu: RefCell::new(CPU::new()),
-            pio: RefCell::new(PIO::new(0)),
-            z1013: RefCell::new(Z1013::new()),
+            cpu: CPU::new(),
+            board: Board::new(),
+            input: InputQueue::new(KEY_HOLD_CYCLES),
+            text: TextMode::new(32, 32, FONT),
+            input_time: 0,
         }
     }
 
     // first-time init of the emulator 
-    pub fn poweron(&self) {
-        let mut cpu = self.cpu.borrow_mut();
+    pub fn poweron(&mut self) {
+        let cpu = &mut self.cpu;
         
         // map 64 KByte RAM at memory layer 1
         cpu.mem.map(1, 0x00000, 0x0000, true, 0x10000);
@@ -296,57 +446,198 @@ impl System {
         // map the 2 KByte OS ROM at higher prio memory layer 0
         cpu.mem.map_bytes(0, 0x10000, 0xF000, false, &OS);
 
-        // copy BASIC interpreter dump into RAM at address 0x100, 
-        // skip the first 0x20 bytes, these are used as header
-        // of the '.z80' file format
-        cpu.mem.write(0x0100, &BASIC[0x20..]);
+        // check the ROM dumps against their known checksums, a bad
+        // dump is the most likely reason for a garbled screen
+        let mut roms = RomRegistry::new();
+        roms.register("z1013_mon_a2", 0x0800, 0x98B19B10);
+        roms.register("z1013_font", 0x0800, 0x7023088F);
+        if let Err(err) = roms.verify_mapped("z1013_mon_a2", &cpu.mem, 0xF000) {
+            println!("WARNING: {}", err);
+        }
+        if let Err(err) = roms.verify("z1013_font", FONT) {
+            println!("WARNING: {}", err);
+        }
+
+        // load the BASIC interpreter '.z80' file into RAM at
+        // the address in its header (0x0100)
+        if let Err(err) = load_program(&mut cpu.mem, ProgramFormat::Z80, BASIC) {
+            println!("WARNING: kc_basic.z80: {}", err);
+        }
 
         // start execution at address 0xF000
         cpu.reg.set_pc(0xF000);
     }
 
     // run the emulator for one frame
-    pub fn step_frame(&self, micro_seconds: i64) {
-        let num_cycles = (FREQ_KHZ * micro_seconds) / 1000;
-        let mut cur_cycles = 0;
-        let mut cpu = self.cpu.borrow_mut();
-        while cur_cycles < num_cycles {
-            cur_cycles += cpu.step(self);
+    pub fn step_frame(&mut self, micro_seconds: i64) {
+        let num_cycles = CLOCK.cycles_from_micros(micro_seconds);
+        let frame_end = self.board.sched.now() + num_cycles;
+        loop {
+            let (now, next) = (self.board.sched.now(), self.board.sched.next_time());
+            if now >= frame_end {
+                break;
+            }
+            // a halted CPU can skip ahead to the next scheduled
+            // event or the end of the frame
+            let max_skip = match next {
+                Some(next) if next < frame_end => next - now,
+                _ => frame_end - now,
+            };
+            let mut cycles = self.cpu.skip_halt(max_skip);
+            if cycles == 0 {
+                cycles = self.cpu.step_io(&mut self.board);
+            }
+            self.board.sched.advance(cycles);
+
+            // handle due events
+            while let Some((_, event)) = self.board.sched.pop() {
+                match event {
+                    Event::Input => self.update_input(),
+                }
+            }
+        }
+        self.board.sync_beeper();
+    }
+
+    // the input queue is brought up to the scheduler's time before
+    // new key events are queued or due key events are delivered
+    fn sync_input(&mut self) {
+        let now = self.board.sched.now();
+        self.input.advance(now - self.input_time);
+        self.input_time = now;
+    }
+    fn schedule_input(&mut self) {
+        let sched = &mut self.board.sched;
+        sched.cancel(|e| *e == Event::Input);
+        if let Some(next) = self.input.cycles_to_next() {
+            sched.schedule(next, Event::Input);
+        }
+    }
+
+    // deliver due host key events to the keyboard matrix
+    fn update_input(&mut self) {
+        self.sync_input();
+        while let Some(event) = self.input.pop() {
+            self.board.z1013.key_matrix.apply(event);
+        }
+        self.schedule_input();
+    }
+
+    // instant-load: copy the tape content directly into memory
+    pub fn load_tape(&mut self, image: &TapeImage) {
+        let cpu = &mut self.cpu;
+        match *image {
+            TapeImage::File(ref file) => {
+                file.write_to(&mut cpu.mem);
+                println!("loaded '{}' at {:04X}-{:04X}, type 'J {:04X}' to start",
+                    file.name, file.load, file.end, file.exec);
+            },
+            // tapes saved with the monitor's S command don't contain
+            // block addresses, these can only be loaded with the L command
+            TapeImage::Pulses(ref pulses) => match z1013_decode(pulses, CLOCK) {
+                Ok(ref blocks) if !blocks.is_empty() && blocks[0].0 != 0 => {
+                    for &(addr, ref data) in blocks {
+                        cpu.mem.write(addr, data);
+                    }
+                    println!("loaded tape at {:04X}-{:04X}",
+                        blocks[0].0, blocks[blocks.len()-1].0 + 31);
+                },
+                Ok(_) => println!("tape has no block addresses, load it with F2 and the L command"),
+                Err(err) => println!("ERROR: {}", err),
+            }
+        }
+    }
+
+    // cycle-accurate load: play the tape signal into the cassette input
+    pub fn play_tape(&mut self, image: &TapeImage) {
+        self.board.sync_tape();
+        let tape = &mut self.board.tape;
+        match *image {
+            TapeImage::File(ref file) => {
+                tape.insert(z1013_encode(file, CLOCK));
+                println!("playing '{}', type 'L {:04X} {:04X}' to load", file.name, file.load, file.end);
+            },
+            TapeImage::Pulses(ref pulses) => {
+                tape.insert(pulses.clone());
+                println!("playing tape, type 'L aaaa eeee' to load");
+            }
+        }
+        tape.play();
+    }
+
+    // start recording, or stop recording and write the tape files
+    pub fn record_tape(&mut self) {
+        self.board.sync_tape();
+        let tape = &mut self.board.tape;
+        if !tape.is_recording() {
+            tape.record();
+            println!("recording, type 'S aaaa eeee' to save, and press F3 when done");
+            return;
+        }
+        tape.stop();
+        let pulses = tape.take_recording();
+        if let Err(err) = fs::write("z1013_save.tap", write_tap(&pulses, CLOCK)) {
+            println!("ERROR: z1013_save.tap: {}", err);
+        }
+        // the monitor's S command keeps the start and end address at 0x1B and 0x1D
+        let cpu = &self.cpu;
+        let (load, end) = (cpu.mem.r16(0x001B), cpu.mem.r16(0x001D));
+        match z1013_decode(&pulses, CLOCK) {
+            Ok(ref blocks) if !blocks.is_empty() && end >= load => {
+                let mut data: Vec<u8> = blocks.iter().flat_map(|b| b.1.clone()).collect();
+                data.truncate((end - load + 1) as usize);
+                let file = TapeFile::new("Z1013SAVE", b'C', load, load, data);
+                if let Err(err) = fs::write("z1013_save.z80", file.to_z80()) {
+                    println!("ERROR: z1013_save.z80: {}", err);
+                }
+                println!("saved {:04X}-{:04X} to z1013_save.tap and z1013_save.z80", load, file.end);
+            },
+            Ok(_) => println!("nothing recorded"),
+            Err(err) => println!("ERROR: {}", err),
         }
     }
 
     // Decode the 32x32 video memory (at address 0xEC00 to 0xEFFF) into a 
     // linear RGBA8 frame buffer, each byte stores an 'extended ASCII code'. 
     // The 'system font' pixel data lives in a hidden ROM not accessible 
-    // by the CPU.
-    pub fn decode_framebuffer(&self, fb: &mut [u32]) {
-        let mut fb_iter = fb.iter_mut();
-        let cpu = self.cpu.borrow();
-        let vid_mem = &cpu.mem.heap[0xEC00..0xF000];
-        for y in 0..32 {
-            for py in 0..8 {
-                for x in 0..32 {
-                    let chr = vid_mem[(y<<5)+x] as usize;
-                    let bits = FONT[(chr<<3)|py];
-                    for px in 0..8 {
-                        let pixel = if (bits & (0x80>>px)) != 0 {
-                            0xFFFFFFFF
-                        } 
-                        else {
-                            0xFF000000
-                        };
-                        *fb_iter.next().unwrap() = pixel;
-                    }
-                }
-            }
+    // by the CPU. Decoding is skipped if the video memory hasn't been
+    // written since the last call.
+    pub fn decode_framebuffer(&mut self, fb: &mut Framebuffer) {
+        let cpu = &mut self.cpu;
+        if cpu.mem.take_dirty_pages() & Memory::page_mask(0xEC00, 0x0400) == 0 {
+            return;
         }
+        self.text.decode(&cpu.mem.heap[0xEC00..0xF000], fb);
     }
 
-    // forward a new host ASCII key code to the emulator
-    pub fn put_key(&mut self, ascii: u8) {
-        let mut z1013 = self.z1013.borrow_mut();
-        z1013.put_key(ascii);
+    // forward a host key press or release (as ASCII code) to the emulator
+    pub fn put_key(&mut self, event: InputEvent) {
+        self.sync_input();
+        self.input.push(event);
+        self.schedule_input();
+    }
+}
+
+// write mono 16-bit PCM samples to a WAV file
+fn write_wav(path: &str, samples: &[f32]) -> io::Result<()> {
+    let data_size = (samples.len() * 2) as u32;
+    let mut bytes = Vec::with_capacity(44 + samples.len() * 2);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_size).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());              // PCM
+    bytes.extend_from_slice(&1u16.to_le_bytes());              // mono
+    bytes.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    bytes.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // bytes per second
+    bytes.extend_from_slice(&2u16.to_le_bytes());              // bytes per frame
+    bytes.extend_from_slice(&16u16.to_le_bytes());             // bits per sample
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_size.to_le_bytes());
+    for &sample in samples {
+        bytes.extend_from_slice(&((sample * 32767.0) as i16).to_le_bytes());
     }
+    fs::write(path, bytes)
 }
 
 //--- the main loop
@@ -365,35 +656,91 @@ fn main() {
 
     // the pixel frame buffer, written by System::decode_framebuffer()
     // and transfered to the minifb window
-    let mut frame_buffer = vec![0u32; WIDTH*HEIGHT];
+    let mut frame_buffer = Framebuffer::new(WIDTH, HEIGHT);
     
     // spin up the emulator and run the main loop
     let mut system = System::new();
     system.poweron();
-    let mut micro_seconds_per_frame: i64 = 0;
-    while window.is_open() {
-        let start = PreciseTime::now();
 
-        // get keyboard input from minifb, this is currently a bit crude...
-        let mut ascii: u8 = 0;
+    // optional tape file from the command line
+    let tape_image = env::args().nth(1).map(|path| match TapeImage::open(&path) {
+        Ok(image) => image,
+        Err(err) => panic!("Unable to load tape file: {}", err)
+    });
+
+    // captured sound samples while sound capture is active
+    let mut sound: Option<Vec<f32>> = None;
+    let mut samples = vec![0.0f32; 8192];
+    // the host keys currently held down, and their ASCII codes
+    let mut keys_down: Vec<(Key, u8)> = Vec::new();
+    let mut timer = FrameTimer::new(50);
+    while window.is_open() {
+        let micro_seconds = timer.tick();
+
+        // get keyboard input from minifb: keys released since the last
+        // frame first, then all newly pressed keys in order
+        keys_down.retain(|&(key, ascii)| {
+            let down = window.is_key_down(key);
+            if !down {
+                system.put_key(InputEvent::KeyUp(ascii));
+            }
+            down
+        });
         let shift = window.is_key_down(Key::LeftShift)|window.is_key_down(Key::RightShift);
-        for key in KEYS {
-            if window.is_key_down(key.0) {
-                ascii = if shift {key.2} else {key.1}
+        for pressed in window.get_keys_pressed(KeyRepeat::No).unwrap_or_default() {
+            if let Some(key) = KEYS.iter().find(|key| key.0 == pressed) {
+                let ascii = if shift {key.2} else {key.1};
+                system.put_key(InputEvent::KeyDown(ascii));
+                keys_down.push((pressed, ascii));
             }
         }
-        system.put_key(ascii);
+
+        // tape controls
+        if let Some(ref image) = tape_image {
+            if window.is_key_pressed(Key::F1, KeyRepeat::No) {
+                system.load_tape(image);
+            }
+            if window.is_key_pressed(Key::F2, KeyRepeat::No) {
+                system.play_tape(image);
+            }
+        }
+        if window.is_key_pressed(Key::F3, KeyRepeat::No) {
+            system.record_tape();
+        }
+        if window.is_key_pressed(Key::F4, KeyRepeat::No) {
+            sound = match sound.take() {
+                None => {
+                    println!("capturing sound, press F4 to stop");
+                    Some(Vec::new())
+                },
+                Some(captured) => {
+                    match write_wav("z1013_sound.wav", &captured) {
+                        Ok(_) => println!("sound written to z1013_sound.wav"),
+                        Err(err) => println!("ERROR: z1013_sound.wav: {}", err),
+                    }
+                    None
+                }
+            };
+        }
 
         // run the emulator for the current frame
-        system.step_frame(micro_seconds_per_frame);
+        system.step_frame(micro_seconds);
 
-        // update the window content
-        system.decode_framebuffer(&mut frame_buffer);
-        window.update_with_buffer(&frame_buffer); 
+        // fetch the generated sound samples
+        let num_samples = system.board.beeper.len();
+        let num_samples = system.board.beeper.fill(&mut samples[..num_samples]);
+        if let Some(ref mut captured) = sound {
+            captured.extend_from_slice(&samples[..num_samples]);
+        }
 
-        // measure the elapsed time to run emulator at the correct speed
-        let frame_time = start.to(PreciseTime::now());
-        micro_seconds_per_frame = frame_time.num_microseconds().unwrap();
+        // update the window content, if the host is too slow the
+        // rendering is skipped, but the window still polls the input
+        if timer.skip_frame() {
+            window.update();
+        } else {
+            system.decode_framebuffer(&mut frame_buffer);
+            window.update_with_buffer(frame_buffer.pixels());
+        }
     }
 }
 
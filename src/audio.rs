@@ -0,0 +1,295 @@
+use std::collections::VecDeque;
+use machine::Clock;
+
+/// resampling audio ring buffer between the emulation and the host audio callback
+///
+/// Sound chips and beepers report output level changes with a CPU cycle
+/// time stamp through **sample()** (typically forwarded from
+/// **Bus::audio_sample()**), or push samples which are already generated
+/// at the output sample rate (e.g. from **SN76489::take_samples()**)
+/// with **push_samples()**. The level changes are resampled to the
+/// output sample rate by averaging the level over each sample period.
+///
+/// The host audio callback pulls the samples with **fill()**, so the
+/// emulation doesn't need to run in lockstep with the audio callback.
+/// If the emulation runs ahead, the oldest samples are dropped when the
+/// buffer is full, if it falls behind, **fill()** repeats the last
+/// sample instead of producing a click.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{AudioBuffer, Clock};
+///
+/// // 1 MHz CPU, 10 kHz sample rate: 100 cycles per sample
+/// let mut audio = AudioBuffer::new(Clock::new(1_000_000), 10_000, 1024);
+/// // beeper: high at cycle 0, low at cycle 150
+/// audio.sample(0, 1.0);
+/// audio.sample(150, -1.0);
+/// audio.advance_to(300);
+///
+/// let mut out = [0.0; 4];
+/// assert_eq!(audio.fill(&mut out), 3);
+/// assert_eq!(out, [1.0, 0.0, -1.0, -1.0]);
+/// ```
+#[derive(Clone,Debug)]
+pub struct AudioBuffer {
+    clock_hz: i64,
+    sample_rate: i64,
+    capacity: usize,
+    samples: VecDeque<f32>,
+    cycle: u64,
+    level: f32,
+    pos: i64,
+    sum: f32,
+    last: f32,
+}
+
+impl AudioBuffer {
+    /// create an audio buffer for a CPU clock, output sample rate in Hz and capacity in samples
+    pub fn new(clock: Clock, sample_rate: i64, capacity: usize) -> AudioBuffer {
+        assert!(sample_rate > 0 && sample_rate <= clock.hz() && capacity > 0);
+        AudioBuffer {
+            clock_hz: clock.hz(),
+            sample_rate,
+            capacity,
+            samples: VecDeque::with_capacity(capacity),
+            cycle: 0,
+            level: 0.0,
+            pos: 0,
+            sum: 0.0,
+            last: 0.0,
+        }
+    }
+
+    /// output sample rate in Hz
+    pub fn sample_rate(&self) -> i64 {
+        self.sample_rate
+    }
+
+    /// number of samples waiting to be fetched with fill()
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// true if no samples are waiting
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// the output level changes at a CPU cycle (e.g. CPU::cycles)
+    ///
+    /// Cycle time stamps must not go backward, earlier time stamps
+    /// change the level at the current time.
+    pub fn sample(&mut self, cycle: u64, level: f32) {
+        self.advance_to(cycle);
+        self.level = level;
+    }
+
+    /// generate samples with the current level up to a CPU cycle
+    ///
+    /// Call this at the end of each frame so that a constant level
+    /// (or silence) also produces samples.
+    pub fn advance_to(&mut self, cycle: u64) {
+        if cycle <= self.cycle {
+            return;
+        }
+        // one CPU cycle is sample_rate units, one output sample clock_hz units
+        let mut units = (cycle - self.cycle) as i64 * self.sample_rate;
+        self.cycle = cycle;
+        while units > 0 {
+            let n = units.min(self.clock_hz - self.pos);
+            self.sum += self.level * n as f32;
+            self.pos += n;
+            units -= n;
+            if self.pos == self.clock_hz {
+                let sample = self.sum / self.clock_hz as f32;
+                self.push(sample);
+                self.pos = 0;
+                self.sum = 0.0;
+            }
+        }
+    }
+
+    /// append samples which have been generated at the output sample rate
+    pub fn push_samples(&mut self, samples: &[f32]) {
+        for &sample in samples {
+            self.push(sample);
+        }
+    }
+
+    /// fill dst with buffered samples, return the number of buffered samples
+    ///
+    /// On buffer underrun, the rest of dst is filled with the last sample.
+    pub fn fill(&mut self, dst: &mut [f32]) -> usize {
+        let mut num = 0;
+        for d in dst.iter_mut() {
+            if let Some(sample) = self.samples.pop_front() {
+                self.last = sample;
+                num += 1;
+            }
+            *d = self.last;
+        }
+        num
+    }
+
+    /// drop all buffered samples
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    fn push(&mut self, sample: f32) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+}
+
+/// 1-bit beeper which converts the level of a speaker output bit into audio samples
+///
+/// The emulator calls **update()** with the executed CPU cycles, and
+/// **set()** or **toggle()** when the emulated system changes the
+/// speaker bit. The samples are buffered in an AudioBuffer and fetched
+/// with **fill()**.
+///
+/// ```
+/// use rz80::{Beeper, Clock};
+///
+/// // 1 MHz CPU, 10 kHz sample rate: 100 cycles per sample
+/// let mut beeper = Beeper::new(Clock::new(1_000_000), 10_000, 1024);
+/// beeper.set(true);
+/// beeper.update(150);
+/// beeper.toggle();
+/// beeper.update(150);
+///
+/// let mut out = [0.0; 3];
+/// assert_eq!(beeper.fill(&mut out), 3);
+/// assert_eq!(out, [0.5, 0.25, 0.0]);
+/// ```
+#[derive(Clone,Debug)]
+pub struct Beeper {
+    audio: AudioBuffer,
+    cycle: u64,
+    state: bool,
+    volume: f32,
+}
+
+impl Beeper {
+    /// create a beeper for a CPU clock, output sample rate in Hz and buffer capacity in samples
+    pub fn new(clock: Clock, sample_rate: i64, capacity: usize) -> Beeper {
+        Beeper {
+            audio: AudioBuffer::new(clock, sample_rate, capacity),
+            cycle: 0,
+            state: false,
+            volume: 0.5,
+        }
+    }
+
+    /// set the output level of the speaker when switched on (default is 0.5)
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+        if self.state {
+            self.audio.sample(self.cycle, volume);
+        }
+    }
+
+    /// current state of the speaker bit
+    pub fn state(&self) -> bool {
+        self.state
+    }
+
+    /// set the speaker bit
+    pub fn set(&mut self, on: bool) {
+        if on != self.state {
+            self.state = on;
+            self.audio.sample(self.cycle, if on { self.volume } else { 0.0 });
+        }
+    }
+
+    /// flip the speaker bit
+    pub fn toggle(&mut self) {
+        let on = !self.state;
+        self.set(on);
+    }
+
+    /// advance the beeper by a number of CPU cycles
+    pub fn update(&mut self, cycles: i64) {
+        self.cycle += cycles as u64;
+        self.audio.advance_to(self.cycle);
+    }
+
+    /// number of samples waiting to be fetched with fill()
+    pub fn len(&self) -> usize {
+        self.audio.len()
+    }
+
+    /// true if no samples are waiting
+    pub fn is_empty(&self) -> bool {
+        self.audio.is_empty()
+    }
+
+    /// fill dst with buffered samples, see AudioBuffer::fill()
+    pub fn fill(&mut self, dst: &mut [f32]) -> usize {
+        self.audio.fill(dst)
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resample() {
+        // 10 cycles per sample
+        let mut audio = AudioBuffer::new(Clock::new(1000), 100, 16);
+        audio.sample(0, 1.0);
+        audio.sample(5, 0.0);
+        audio.sample(12, 1.0);
+        audio.sample(14, 0.0);
+        audio.advance_to(20);
+        assert_eq!(audio.len(), 2);
+        let mut out = [0.0; 2];
+        audio.fill(&mut out);
+        assert_eq!(out, [0.5, 0.2]);
+        // cycle time stamps in the past don't generate samples
+        audio.sample(10, 1.0);
+        assert!(audio.is_empty());
+        audio.advance_to(30);
+        assert_eq!(audio.fill(&mut out), 1);
+        assert_eq!(out, [1.0, 1.0]);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut audio = AudioBuffer::new(Clock::new(1000), 1000, 4);
+        audio.push_samples(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6]);
+        assert_eq!(audio.len(), 4);
+        let mut out = [0.0; 3];
+        assert_eq!(audio.fill(&mut out), 3);
+        assert_eq!(out, [0.3, 0.4, 0.5]);
+        audio.clear();
+        assert_eq!(audio.fill(&mut out), 0);
+        assert_eq!(out, [0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn beeper() {
+        // 10 cycles per sample
+        let mut beeper = Beeper::new(Clock::new(1000), 100, 16);
+        beeper.set(true);
+        beeper.set(true);
+        beeper.update(20);
+        assert!(beeper.state());
+        beeper.set_volume(1.0);
+        beeper.update(5);
+        beeper.toggle();
+        beeper.update(5);
+        assert!(!beeper.state());
+        let mut out = [0.0; 3];
+        assert_eq!(beeper.fill(&mut out), 3);
+        assert_eq!(out, [0.5, 0.5, 0.5]);
+        assert!(beeper.is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+use RegT;
+
+/// the KC-BASIC keywords, the token of a keyword is 0x80 plus its index
+///
+/// Tokens 0x80..0xD4 are the keywords of the KC-BASIC core (identical
+/// in the KC87 ROM and the Z1013/KC85 RAM versions), tokens 0xD5..0xE5
+/// the keywords of the extensions of the RAM version.
+pub const BASIC_KEYWORDS: [&str; 102] = [
+    "END", "FOR", "NEXT", "DATA", "INPUT", "DIM", "READ", "LET",
+    "GOTO", "RUN", "IF", "RESTORE", "GOSUB", "RETURN", "REM", "STOP",
+    "OUT", "ON", "NULL", "WAIT", "DEF", "POKE", "DOKE", "AUTO",
+    "LINES", "CLS", "WIDTH", "BYE", "!", "CALL", "PRINT", "CONT",
+    "LIST", "CLEAR", "CLOAD", "CSAVE", "NEW", "TAB(", "TO", "FN",
+    "SPC(", "THEN", "NOT", "STEP", "+", "-", "*", "/",
+    "^", "AND", "OR", ">", "=", "<", "SGN", "INT",
+    "ABS", "USR", "FRE", "INP", "POS", "SQR", "RND", "LN",
+    "EXP", "COS", "SIN", "TAN", "ATN", "PEEK", "DEEK", "PI",
+    "LEN", "STR$", "VAL", "ASC", "CHR$", "LEFT$", "RIGHT$", "MID$",
+    "LOAD", "TRON", "TROFF", "EDIT", "ELSE", "INKEY$", "JOYST", "STRING$",
+    "INSTR", "RENUMBER", "DELETE", "PAUSE", "BEEP", "WINDOW", "BORDER", "INK",
+    "PAPER", "AT", "HSAVE", "HLOAD", "PSET", "PRES",
+];
+
+const TOKEN_DATA: u8 = 0x83;
+const TOKEN_REM: u8 = 0x8E;
+const TOKEN_PRINT: u8 = 0x9E;
+const MAX_LINE_NR: u32 = 65529;
+const MAX_LINE_LEN: usize = 255;
+
+/// reasons why a BASIC program couldn't be converted
+#[derive(Clone,Debug,PartialEq)]
+pub enum BasicError {
+    /// a listing line doesn't start with a line number (listing line)
+    NoLineNumber(usize),
+    /// a line number is too big or not ascending (line number)
+    BadLineNumber(u32),
+    /// a tokenized line is longer than 255 bytes (line number)
+    LineTooLong(u32),
+    /// the program doesn't fit into the address space
+    TooBig,
+    /// a line link points outside of the program data (address of the link)
+    BadLink(RegT),
+}
+
+impl fmt::Display for BasicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BasicError::NoLineNumber(line) => write!(f, "missing line number in line {}", line),
+            BasicError::BadLineNumber(nr) => write!(f, "bad line number {}", nr),
+            BasicError::LineTooLong(nr) => write!(f, "line {} is too long", nr),
+            BasicError::TooBig => write!(f, "program is too big"),
+            BasicError::BadLink(addr) => write!(f, "bad line link at {:04X}", addr),
+        }
+    }
+}
+
+/// convert a BASIC listing into the tokenized in-memory format
+///
+/// The program is placed at **addr**, which is the start of the
+/// BASIC program area (the interpreter expects a zero byte right
+/// before it). Each line is stored as the address of the next line,
+/// the line number, the tokenized text and a zero byte, a zero link
+/// terminates the program.
+///
+/// Like the interpreter, keywords are recognized anywhere outside of
+/// string literals, REM comments and DATA statements (keywords must be
+/// upper case), and '?' is an abbreviation for PRINT. Lines must
+/// have ascending line numbers, empty lines are ignored.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{basic_tokenize, basic_detokenize};
+///
+/// let prog = basic_tokenize("10 PRINT \"HI\"\n20 GOTO 10\n", 0x2C01).unwrap();
+/// assert_eq!(prog, [0x0C, 0x2C, 10, 0, 0x9E, b' ', b'"', b'H', b'I', b'"', 0,
+///                   0x15, 0x2C, 20, 0, 0x88, b' ', b'1', b'0', 0,
+///                   0, 0]);
+/// assert_eq!(basic_detokenize(&prog, 0x2C01).unwrap(), "10 PRINT \"HI\"\n20 GOTO 10\n");
+/// ```
+pub fn basic_tokenize(listing: &str, addr: RegT) -> Result<Vec<u8>, BasicError> {
+    let mut prog = Vec::new();
+    let mut last_nr = None;
+    for (i, line) in listing.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() {
+            continue;
+        }
+        let digits = line.bytes().take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 {
+            return Err(BasicError::NoLineNumber(i + 1));
+        }
+        let nr = line[..digits].parse::<u32>().unwrap_or(u32::MAX);
+        if nr > MAX_LINE_NR || last_nr.is_some_and(|last| nr <= last) {
+            return Err(BasicError::BadLineNumber(nr));
+        }
+        last_nr = Some(nr);
+        let text = tokenize_line(line[digits..].trim_start());
+        if text.len() > MAX_LINE_LEN {
+            return Err(BasicError::LineTooLong(nr));
+        }
+        let next = addr as usize + prog.len() + 4 + text.len() + 1;
+        if next > 0xFFFF {
+            return Err(BasicError::TooBig);
+        }
+        prog.extend_from_slice(&[next as u8, (next >> 8) as u8, nr as u8, (nr >> 8) as u8]);
+        prog.extend_from_slice(&text);
+        prog.push(0);
+    }
+    prog.extend_from_slice(&[0, 0]);
+    Ok(prog)
+}
+
+/// convert a tokenized BASIC program at addr into a listing
+///
+/// The data must start with the first program line, and the
+/// program ends at the first zero link.
+pub fn basic_detokenize(prog: &[u8], addr: RegT) -> Result<String, BasicError> {
+    let mut listing = String::new();
+    let mut pos = 0;
+    loop {
+        if pos + 2 > prog.len() {
+            return Err(BasicError::BadLink(addr + pos as RegT));
+        }
+        let next = (prog[pos] as usize) | ((prog[pos + 1] as usize) << 8);
+        if next == 0 {
+            return Ok(listing);
+        }
+        let next_pos = next.wrapping_sub(addr as usize);
+        if next_pos <= pos + 4 || next_pos > prog.len() {
+            return Err(BasicError::BadLink(addr + pos as RegT));
+        }
+        let nr = (prog[pos + 2] as u32) | ((prog[pos + 3] as u32) << 8);
+        listing.push_str(&format!("{} ", nr));
+        for &c in prog[pos + 4..next_pos].iter().take_while(|&&c| c != 0) {
+            match c {
+                0x80..=0xFF => {
+                    match BASIC_KEYWORDS.get((c - 0x80) as usize) {
+                        Some(keyword) => listing.push_str(keyword),
+                        None => listing.push(c as char),
+                    }
+                }
+                _ => listing.push(c as char),
+            }
+        }
+        listing.push('\n');
+        pos = next_pos;
+    }
+}
+
+// tokenize the text of a line after the line number
+fn tokenize_line(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut quoted = false;
+    let mut data = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b'"' {
+            quoted = !quoted;
+        }
+        if quoted || (data && c != b':') || c == b' ' || c.is_ascii_digit() || c >= 0x80 {
+            out.push(c);
+            i += 1;
+            continue;
+        }
+        data = false;
+        if c == b'?' {
+            out.push(TOKEN_PRINT);
+            i += 1;
+            continue;
+        }
+        match BASIC_KEYWORDS.iter().position(|k| bytes[i..].starts_with(k.as_bytes())) {
+            Some(index) => {
+                let token = 0x80 + index as u8;
+                out.push(token);
+                i += BASIC_KEYWORDS[index].len();
+                if token == TOKEN_REM {
+                    out.extend_from_slice(&bytes[i..]);
+                    break;
+                }
+                data = token == TOKEN_DATA;
+            }
+            None => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        assert_eq!(tokenize_line("FORI=1TO9:?I:NEXT"),
+                   [0x81, b'I', 0xB4, b'1', 0xA6, b'9', b':', 0x9E, b'I', b':', 0x82]);
+        assert_eq!(tokenize_line("DATA TO,\"A:B\":REM IF ?"),
+                   b"\x83 TO,\"A:B\":\x8E IF ?".to_vec());
+        assert_eq!(tokenize_line("A$=INKEY$:PRES 1,2"),
+                   [b'A', b'$', 0xB4, 0xD5, b':', 0xE5, b' ', b'1', b',', b'2']);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let listing = "10 REM \"TEST\"\n 20 FOR I=0 TO 10\n\n30 PRINT I*2;\n40 NEXT\n";
+        let prog = basic_tokenize(listing, 0x0401).unwrap();
+        assert_eq!(basic_detokenize(&prog, 0x0401).unwrap(),
+                   "10 REM \"TEST\"\n20 FOR I=0 TO 10\n30 PRINT I*2;\n40 NEXT\n");
+        assert_eq!(basic_detokenize(&prog[..prog.len() - 2], 0x0401),
+                   Err(BasicError::BadLink(0x0401 + prog.len() as RegT - 2)));
+        let mut bad = prog.clone();
+        bad[0] = 0x01;
+        assert_eq!(basic_detokenize(&bad, 0x0401), Err(BasicError::BadLink(0x0401)));
+        assert_eq!(basic_tokenize("", 0x0401).unwrap(), [0, 0]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(basic_tokenize("10 END\nPRINT", 0x0401), Err(BasicError::NoLineNumber(2)));
+        assert_eq!(basic_tokenize("20 END\n10 END", 0x0401), Err(BasicError::BadLineNumber(10)));
+        assert_eq!(basic_tokenize("65530 END", 0x0401), Err(BasicError::BadLineNumber(65530)));
+        let long = format!("10 REM {}", "X".repeat(300));
+        assert_eq!(basic_tokenize(&long, 0x0401), Err(BasicError::LineTooLong(10)));
+        assert_eq!(basic_tokenize("10 END", 0xFFFC), Err(BasicError::TooBig));
+    }
+}
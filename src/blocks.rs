@@ -0,0 +1,332 @@
+use RegT;
+use bus::Bus;
+use cpu::CPU;
+use memory::Memory;
+use decoder::{self, Instruction, Operand};
+use registers::Reg8;
+
+// max number of instructions in a block
+pub const MAX_OPS: usize = 64;
+
+/// a decoded instruction
+pub struct Inst {
+    /// address of the opcode byte
+    pub addr: RegT,
+    /// the opcode byte
+    pub op: RegT,
+    /// instruction length in bytes
+    pub len: RegT,
+    /// the instruction decoded by decoder::decode()
+    pub inst: Instruction,
+    /// true if the instruction ends the block (jumps, calls and returns)
+    pub end: bool,
+    /// true if the instruction writes to memory
+    pub writes: bool,
+}
+
+/// return the 'ends block' flag of instructions which can be in a block
+///
+/// These are the unprefixed instructions except the ones that need
+/// to talk to the Bus (IN, OUT), change the interrupt state (DI, EI,
+/// HALT), or swap in the shadow registers (EX AF,AF', EXX), and
+/// DAA, SCF, CCF and EX (SP),HL.
+pub fn inst_info(inst: &Instruction) -> Option<bool> {
+    if inst.is_indexed() {
+        return None;
+    }
+    match *inst {
+        // LD I,A; LD R,A; LD A,I; LD A,R are ED-prefixed
+        Instruction::Ld(Operand::Reg(Reg8::I), _) | Instruction::Ld(Operand::Reg(Reg8::R), _) |
+        Instruction::Ld(_, Operand::Reg(Reg8::I)) | Instruction::Ld(_, Operand::Reg(Reg8::R)) => None,
+        Instruction::Nop | Instruction::Ld(..) | Instruction::Ld16(..) |
+        Instruction::LdToMem16(..) | Instruction::LdFromMem16(..) | Instruction::LdSP(_) |
+        Instruction::Push(_) | Instruction::Pop(_) | Instruction::ExDEHL |
+        Instruction::Alu(..) | Instruction::Inc(_) | Instruction::Dec(_) |
+        Instruction::Inc16(_) | Instruction::Dec16(_) | Instruction::Add16(..) |
+        Instruction::Rlca | Instruction::Rrca | Instruction::Rla | Instruction::Rra |
+        Instruction::Cpl => Some(false),
+        // jumps, calls and returns end the block
+        Instruction::Jp(..) | Instruction::JpInd(_) | Instruction::Jr(..) |
+        Instruction::Djnz(_) | Instruction::Call(..) | Instruction::Ret(_) |
+        Instruction::Rst(_) => Some(true),
+        _ => None,
+    }
+}
+
+/// decode a block of instructions starting at pc
+///
+/// The block ends after a jump, call or return, before an instruction
+/// which can't be in a block, or after MAX_OPS instructions.
+pub fn decode_block(mem: &Memory, pc: RegT) -> Vec<Inst> {
+    let mut insts = Vec::new();
+    let mut addr = pc;
+    while insts.len() < MAX_OPS {
+        let bytes = mem.peek_inst(addr);
+        let (inst, len) = decoder::decode(&bytes);
+        match inst_info(&inst) {
+            Some(end) => {
+                let writes = match inst {
+                    Instruction::Ld(Operand::Reg(_), _) => false,
+                    Instruction::Ld(..) | Instruction::LdToMem16(..) | Instruction::Push(_) => true,
+                    Instruction::Inc(o) | Instruction::Dec(o) => !matches!(o, Operand::Reg(_)),
+                    _ => false,
+                };
+                insts.push(Inst { addr, op: bytes[0] as RegT, len, inst, end, writes });
+                addr = (addr + len) & 0xFFFF;
+                if end {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    insts
+}
+
+struct Block {
+    insts: Vec<Inst>,
+    bytes: Vec<u8>,
+    pages: [RegT; 2],
+    gens: [u32; 2],
+}
+
+impl Block {
+    /// check if the block's memory pages are unchanged (fast)
+    fn valid(&self, mem: &Memory) -> bool {
+        mem.page_generation(self.pages[0]) == self.gens[0] &&
+        mem.page_generation(self.pages[1]) == self.gens[1]
+    }
+
+    /// check if the block's instruction bytes are unchanged (slow)
+    fn unchanged(&self, mem: &Memory) -> bool {
+        let addr = self.pages[0];
+        self.bytes.iter().enumerate().all(|(i, b)| mem.peek8(addr + i as RegT) == *b as RegT)
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    hits: u32,
+    rebuilds: u32,
+    block: Option<Box<Block>>,
+}
+
+/// cache of pre-decoded instruction blocks
+pub struct BlockCache {
+    entries: Vec<Entry>,
+}
+
+impl BlockCache {
+    /// create an empty block cache
+    pub fn new() -> BlockCache {
+        BlockCache { entries: (0..(1 << 16)).map(|_| Entry::default()).collect() }
+    }
+
+    fn build(mem: &Memory, pc: RegT) -> Block {
+        let insts = decode_block(mem, pc);
+        let last = match insts.last() {
+            Some(inst) => (inst.addr + inst.len - 1) & 0xFFFF,
+            None => pc,
+        };
+        let len = insts.iter().map(|inst| inst.len).sum::<RegT>();
+        Block {
+            insts,
+            bytes: (0..len).map(|i| mem.peek8(pc + i) as u8).collect(),
+            pages: [pc, last],
+            gens: [mem.page_generation(pc), mem.page_generation(last)],
+        }
+    }
+
+    /// run the block at the CPU's PC, or return None if there is none
+    pub fn run(&mut self, cpu: &mut CPU, bus: &dyn Bus) -> Option<i64> {
+        let pc = cpu.reg.pc();
+        let entry = &mut self.entries[pc as usize];
+        let valid = match entry.block {
+            Some(ref mut block) => {
+                // a write to the same memory page doesn't necessarily
+                // change the block (e.g. if code and data share a page)
+                if !block.valid(&cpu.mem) && block.unchanged(&cpu.mem) {
+                    block.gens = [cpu.mem.page_generation(block.pages[0]),
+                                  cpu.mem.page_generation(block.pages[1])];
+                }
+                block.valid(&cpu.mem)
+            }
+            None => false,
+        };
+        if !valid {
+            if entry.block.is_some() {
+                entry.block = None;
+                entry.hits = 0;
+                entry.rebuilds += 1;
+            }
+            // blocks which are overwritten often (self-modifying code)
+            // are left to the interpreter for a while
+            entry.hits += 1;
+            if entry.hits < (1 << entry.rebuilds.min(16)) {
+                return None;
+            }
+            entry.block = Some(Box::new(BlockCache::build(&cpu.mem, pc)));
+        }
+        let block = entry.block.as_ref().unwrap();
+        if block.insts.is_empty() {
+            return None;
+        }
+        let mut cycles = 0;
+        for inst in &block.insts {
+            cpu.mem.check_exec(inst.addr);
+            bus.m1(inst.addr, inst.op);
+            cpu.reg.r = (cpu.reg.r & 0x80) | ((cpu.reg.r + 1) & 0x7F);
+            cpu.reg.set_pc((inst.addr + inst.len) & 0xFFFF);
+            cycles += cpu.execute(bus, inst.inst);
+            // a jump, call or return has set the PC
+            if inst.end {
+                break;
+            }
+            // stop if the instruction has overwritten the block
+            if inst.writes && !block.valid(&cpu.mem) && !block.unchanged(&cpu.mem) {
+                break;
+            }
+        }
+        Some(cycles)
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use registers::Im;
+
+    struct TestBus;
+    impl Bus for TestBus {}
+
+    // run until PC has been at 0x0100 num times, return cycles
+    fn run(cpu: &mut CPU, num: usize) -> i64 {
+        let bus = TestBus;
+        let mut cycles = 0;
+        let mut visits = 0;
+        while visits < num {
+            cycles += cpu.run_block(&bus);
+            if cpu.reg.pc() == 0x0100 {
+                visits += 1;
+            }
+        }
+        cycles
+    }
+
+    fn check_same(a: &CPU, b: &CPU) {
+        assert_eq!(a.reg.af(), b.reg.af());
+        assert_eq!(a.reg.bc(), b.reg.bc());
+        assert_eq!(a.reg.de(), b.reg.de());
+        assert_eq!(a.reg.hl(), b.reg.hl());
+        assert_eq!(a.reg.sp(), b.reg.sp());
+        assert_eq!(a.reg.wz(), b.reg.wz());
+        assert_eq!(a.reg.pc(), b.reg.pc());
+        assert_eq!(a.reg.r, b.reg.r);
+        assert!(a.mem.diff(&b.mem).is_empty());
+    }
+
+    #[test]
+    fn random_blocks() {
+        // random blocks in ROM, run by the interpreter and
+        // from the block cache, must produce the same results
+        let mut seed: u32 = 0x87654321;
+        let mut rnd = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for _ in 0..64 {
+            let mut rom = vec![0u8; 0x400];
+            let mut addr = 0x100;
+            while addr < 0x1F0 {
+                let bytes: Vec<u8> = (0..4).map(|_| rnd() as u8).collect();
+                let (inst, len) = decoder::decode(&bytes);
+                if let Some(false) = inst_info(&inst) {
+                    let len = len as usize;
+                    rom[addr..addr + len].copy_from_slice(&bytes[..len]);
+                    addr += len;
+                }
+            }
+            // JP 0x0100
+            rom[addr..addr + 3].copy_from_slice(&[0xC3, 0x00, 0x01]);
+
+            let mut cpus = [CPU::new(), CPU::new()];
+            let regs: Vec<RegT> = (0..5).map(|_| (rnd() & 0xFFFF) as RegT).collect();
+            for cpu in cpus.iter_mut() {
+                cpu.mem.map_bytes(0, 0x00000, 0x0000, false, &rom);
+                cpu.mem.map(0, 0x00400, 0x0400, true, 0xFC00);
+                cpu.reg.set_af(regs[0]);
+                cpu.reg.set_bc(regs[1]);
+                cpu.reg.set_de(regs[2]);
+                cpu.reg.set_hl(regs[3]);
+                cpu.reg.set_sp(regs[4]);
+                cpu.reg.set_pc(0x0100);
+            }
+            cpus[1].set_block_cache(true);
+            let cycles0 = run(&mut cpus[0], 40);
+            let cycles1 = run(&mut cpus[1], 40);
+            assert_eq!(cycles0, cycles1);
+            check_same(&cpus[0], &cpus[1]);
+        }
+    }
+
+    #[test]
+    fn branches() {
+        // LD B,10; loop: INC A; DJNZ loop; LD C,A; CALL sub; JR NZ,+0; JP 0x0100
+        // sub: DEC C; RET NZ; INC D; RET
+        let prog = [0x06, 0x0A, 0x3C, 0x10, 0xFD, 0x4F, 0xCD, 0x10, 0x01, 0x20, 0x00,
+                    0xC3, 0x00, 0x01, 0x00, 0x00, 0x0D, 0xC0, 0x14, 0xC9];
+        let mut cpus = [CPU::new_64k(), CPU::new_64k()];
+        for cpu in cpus.iter_mut() {
+            cpu.mem.write(0x0100, &prog);
+            cpu.reg.set_sp(0x8000);
+            cpu.reg.set_pc(0x0100);
+        }
+        cpus[1].set_block_cache(true);
+        let cycles0 = run(&mut cpus[0], 100);
+        let cycles1 = run(&mut cpus[1], 100);
+        assert_eq!(cycles0, cycles1);
+        check_same(&cpus[0], &cpus[1]);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // LD HL,0x010A; INC (HL); NOP; NOP; NOP; NOP; NOP; ADD A,n; JP 0x0100
+        // (INC (HL) increments the immediate operand of ADD A,n)
+        let prog = [0x21, 0x0A, 0x01, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0xC6, 0x00,
+                    0xC3, 0x00, 0x01];
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0100, &prog);
+        cpu.reg.set_pc(0x0100);
+        cpu.set_block_cache(true);
+        run(&mut cpu, 100);
+        assert_eq!(cpu.reg.a(), 5050 & 0xFF);
+    }
+
+    #[test]
+    fn step_granularity() {
+        // LD A,1; LD B,2; LD C,3; JP 0x0100
+        let prog = [0x3E, 0x01, 0x06, 0x02, 0x0E, 0x03, 0xC3, 0x00, 0x01];
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0100, &prog);
+        cpu.reg.set_pc(0x0100);
+        cpu.set_block_cache(true);
+        // step() executes a single instruction, run_block() the whole block
+        assert_eq!(cpu.step(&TestBus), 7);
+        assert_eq!(cpu.reg.pc(), 0x0102);
+        cpu.reg.set_pc(0x0100);
+        assert_eq!(cpu.run_block(&TestBus), 3 * 7 + 10);
+        assert_eq!(cpu.reg.pc(), 0x0100);
+
+        // with an interrupt pending, run_block() only executes one instruction
+        cpu.set_iff(true, true);
+        cpu.set_interrupt_mode(Im::One);
+        cpu.reg.set_sp(0x8000);
+        cpu.irq();
+        assert_eq!(cpu.run_block(&TestBus), 7 + 13);
+        assert_eq!(cpu.reg.pc(), 0x0038);
+        assert_eq!(cpu.mem.r16(0x7FFE), 0x0102);
+    }
+}
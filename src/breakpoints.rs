@@ -0,0 +1,604 @@
+use std::fmt;
+use cpu::CPU;
+use symbols::{SymbolTable, parse_addr};
+use RegT;
+
+/// errors when parsing a breakpoint condition
+#[derive(Clone,Debug,PartialEq)]
+pub enum ConditionError {
+    /// unexpected character or token at a byte position
+    Syntax(usize),
+    /// a name is neither a register nor a known symbol
+    UnknownName(String),
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConditionError::Syntax(pos) => write!(f, "syntax error in condition at {}", pos),
+            ConditionError::UnknownName(ref name) => write!(f, "unknown name '{}'", name),
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Op {
+    Or, And, Eq, Ne, Lt, Le, Gt, Ge, BitOr, BitXor, BitAnd, Shl, Shr, Add, Sub, Mul, Div, Mod,
+}
+
+impl Op {
+    /// operator precedence, higher binds tighter
+    fn prec(self) -> usize {
+        match self {
+            Op::Or => 1,
+            Op::And => 2,
+            Op::Eq | Op::Ne => 3,
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => 4,
+            Op::BitOr => 5,
+            Op::BitXor => 6,
+            Op::BitAnd => 7,
+            Op::Shl | Op::Shr => 8,
+            Op::Add | Op::Sub => 9,
+            Op::Mul | Op::Div | Op::Mod => 10,
+        }
+    }
+
+    fn apply(self, l: RegT, r: RegT) -> RegT {
+        match self {
+            Op::Or => ((l != 0) || (r != 0)) as RegT,
+            Op::And => ((l != 0) && (r != 0)) as RegT,
+            Op::Eq => (l == r) as RegT,
+            Op::Ne => (l != r) as RegT,
+            Op::Lt => (l < r) as RegT,
+            Op::Le => (l <= r) as RegT,
+            Op::Gt => (l > r) as RegT,
+            Op::Ge => (l >= r) as RegT,
+            Op::BitOr => l | r,
+            Op::BitXor => l ^ r,
+            Op::BitAnd => l & r,
+            Op::Shl => l.wrapping_shl(r as u32),
+            Op::Shr => l.wrapping_shr(r as u32),
+            Op::Add => l.wrapping_add(r),
+            Op::Sub => l.wrapping_sub(r),
+            Op::Mul => l.wrapping_mul(r),
+            Op::Div => if r == 0 { 0 } else { l.wrapping_div(r) },
+            Op::Mod => if r == 0 { 0 } else { l.wrapping_rem(r) },
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Unary {
+    Not, Inv, Neg,
+}
+
+#[derive(Clone,Debug)]
+enum Expr {
+    Num(RegT),
+    Var(fn(&CPU) -> RegT),
+    Mem(Box<Expr>),
+    Unary(Unary, Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, cpu: &CPU) -> RegT {
+        match *self {
+            Expr::Num(val) => val,
+            Expr::Var(get) => get(cpu),
+            Expr::Mem(ref addr) => cpu.mem.peek8(addr.eval(cpu) & 0xFFFF),
+            Expr::Unary(Unary::Not, ref e) => (e.eval(cpu) == 0) as RegT,
+            Expr::Unary(Unary::Inv, ref e) => !e.eval(cpu),
+            Expr::Unary(Unary::Neg, ref e) => e.eval(cpu).wrapping_neg(),
+            Expr::Binary(op, ref l, ref r) => {
+                // && and || short-circuit like in Rust
+                let lv = l.eval(cpu);
+                match op {
+                    Op::And if lv == 0 => 0,
+                    Op::Or if lv != 0 => 1,
+                    _ => op.apply(lv, r.eval(cpu)),
+                }
+            }
+        }
+    }
+}
+
+/// lookup a register (or CPU state) name
+fn register(name: &str) -> Option<fn(&CPU) -> RegT> {
+    let get: fn(&CPU) -> RegT = match name.to_ascii_uppercase().as_str() {
+        "A" => |cpu| cpu.reg.a(),
+        "F" => |cpu| cpu.reg.f(),
+        "B" => |cpu| cpu.reg.b(),
+        "C" => |cpu| cpu.reg.c(),
+        "D" => |cpu| cpu.reg.d(),
+        "E" => |cpu| cpu.reg.e(),
+        "H" => |cpu| cpu.reg.h(),
+        "L" => |cpu| cpu.reg.l(),
+        "I" => |cpu| cpu.reg.i,
+        "R" => |cpu| cpu.reg.r,
+        "AF" => |cpu| cpu.reg.af(),
+        "BC" => |cpu| cpu.reg.bc(),
+        "DE" => |cpu| cpu.reg.de(),
+        "HL" => |cpu| cpu.reg.hl(),
+        "IX" => |cpu| cpu.reg.ix(),
+        "IY" => |cpu| cpu.reg.iy(),
+        "SP" => |cpu| cpu.reg.sp(),
+        "PC" => |cpu| cpu.reg.pc(),
+        "WZ" => |cpu| cpu.reg.wz(),
+        "AF'" => |cpu| cpu.reg.af_(),
+        "BC'" => |cpu| cpu.reg.bc_(),
+        "DE'" => |cpu| cpu.reg.de_(),
+        "HL'" => |cpu| cpu.reg.hl_(),
+        "IM" => |cpu| cpu.interrupt_mode() as RegT,
+        "IFF1" => |cpu| cpu.iff1() as RegT,
+        "IFF2" => |cpu| cpu.iff2() as RegT,
+        "HALT" => |cpu| cpu.is_halted() as RegT,
+        _ => return None,
+    };
+    Some(get)
+}
+
+#[derive(Clone,Debug,PartialEq)]
+enum Token {
+    Num(RegT),
+    Name(String),
+    Op(Op),
+    Not,
+    Inv,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    End,
+}
+
+struct Parser<'a> {
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+    symbols: Option<&'a SymbolTable>,
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, ConditionError> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let c = bytes[pos] as char;
+        let start = pos;
+        if c.is_whitespace() {
+            pos += 1;
+            continue;
+        }
+        let is_word = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'.';
+        let token = if c.is_ascii_digit() || c == '$' || c == '#' {
+            pos += 1;
+            while pos < bytes.len() && bytes[pos].is_ascii_alphanumeric() {
+                pos += 1;
+            }
+            Token::Num(parse_addr(&text[start..pos]).ok_or(ConditionError::Syntax(start))?)
+        } else if is_word(bytes[pos]) {
+            while pos < bytes.len() && is_word(bytes[pos]) {
+                pos += 1;
+            }
+            if pos < bytes.len() && bytes[pos] == b'\'' {
+                pos += 1;
+            }
+            Token::Name(text[start..pos].to_string())
+        } else {
+            let two = text.get(pos..pos + 2).unwrap_or("");
+            let (token, len) = match two {
+                "||" => (Token::Op(Op::Or), 2),
+                "&&" => (Token::Op(Op::And), 2),
+                "==" => (Token::Op(Op::Eq), 2),
+                "!=" => (Token::Op(Op::Ne), 2),
+                "<=" => (Token::Op(Op::Le), 2),
+                ">=" => (Token::Op(Op::Ge), 2),
+                "<<" => (Token::Op(Op::Shl), 2),
+                ">>" => (Token::Op(Op::Shr), 2),
+                _ => {
+                    let token = match c {
+                        '<' => Token::Op(Op::Lt),
+                        '>' => Token::Op(Op::Gt),
+                        '|' => Token::Op(Op::BitOr),
+                        '^' => Token::Op(Op::BitXor),
+                        '&' => Token::Op(Op::BitAnd),
+                        '+' => Token::Op(Op::Add),
+                        '-' => Token::Op(Op::Sub),
+                        '*' => Token::Op(Op::Mul),
+                        '/' => Token::Op(Op::Div),
+                        '%' => Token::Op(Op::Mod),
+                        '!' => Token::Not,
+                        '~' => Token::Inv,
+                        '(' => Token::LParen,
+                        ')' => Token::RParen,
+                        '[' => Token::LBracket,
+                        ']' => Token::RBracket,
+                        _ => return Err(ConditionError::Syntax(pos)),
+                    };
+                    (token, 1)
+                }
+            };
+            pos += len;
+            token
+        };
+        tokens.push((start, token));
+    }
+    tokens.push((text.len(), Token::End));
+    Ok(tokens)
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.index].1
+    }
+
+    fn next(&mut self) -> (usize, Token) {
+        let token = self.tokens[self.index].clone();
+        if self.index + 1 < self.tokens.len() {
+            self.index += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), ConditionError> {
+        let (pos, next) = self.next();
+        if next == token {
+            Ok(())
+        } else {
+            Err(ConditionError::Syntax(pos))
+        }
+    }
+
+    /// parse binary operators with at least the given precedence
+    fn binary(&mut self, min_prec: usize) -> Result<Expr, ConditionError> {
+        let mut left = self.unary()?;
+        loop {
+            let op = match *self.peek() {
+                Token::Op(op) if op.prec() >= min_prec => op,
+                _ => return Ok(left),
+            };
+            self.next();
+            let right = self.binary(op.prec() + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ConditionError> {
+        let (pos, token) = self.next();
+        match token {
+            Token::Num(val) => Ok(Expr::Num(val)),
+            Token::Name(name) => {
+                if let Some(get) = register(&name) {
+                    Ok(Expr::Var(get))
+                } else if let Some(addr) = self.symbols.and_then(|s| s.addr(&name)) {
+                    Ok(Expr::Num(addr))
+                } else {
+                    Err(ConditionError::UnknownName(name))
+                }
+            }
+            Token::Not => Ok(Expr::Unary(Unary::Not, Box::new(self.unary()?))),
+            Token::Inv => Ok(Expr::Unary(Unary::Inv, Box::new(self.unary()?))),
+            Token::Op(Op::Sub) => Ok(Expr::Unary(Unary::Neg, Box::new(self.unary()?))),
+            Token::LParen => {
+                // a memory address can't be the result of a comparison
+                let addr = self.binary(Op::BitOr.prec())?;
+                self.expect(Token::RParen)?;
+                Ok(Expr::Mem(Box::new(addr)))
+            }
+            Token::LBracket => {
+                let expr = self.binary(0)?;
+                self.expect(Token::RBracket)?;
+                Ok(expr)
+            }
+            _ => Err(ConditionError::Syntax(pos)),
+        }
+    }
+}
+
+/// a breakpoint condition evaluated against the CPU state
+///
+/// Conditions are written in a C-like syntax with Z80 assembler flavour:
+///
+/// - register names (case insensitive): A, F, B, C, D, E, H, L, I, R, AF, BC,
+///   DE, HL, IX, IY, SP, PC, WZ, AF', BC', DE', HL', and the CPU state IM,
+///   IFF1, IFF2, HALT
+/// - numbers: decimal, `0x3F`, `$3F`, `#3F` or `3Fh` (must start with a digit)
+/// - `(addr)` reads a byte from memory like in Z80 assembler, so `[...]`
+///   is used for grouping
+/// - operators by increasing precedence: `||`, `&&`, `== !=`,
+///   `< <= > >=`, `|`, `^`, `&`, `<< >>`, `+ -`, `* / %`, and the unary
+///   operators `! ~ -`
+///
+/// When parsed with a SymbolTable, labels can be used as numbers.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, Condition};
+///
+/// let mut cpu = CPU::new_64k();
+/// let cond = Condition::parse("A==0x3F && (HL)>=0x40").unwrap();
+/// cpu.reg.set_a(0x3F);
+/// cpu.reg.set_hl(0x4000);
+/// assert!(!cond.is_true(&cpu));
+/// cpu.mem.w8(0x4000, 0x80);
+/// assert!(cond.is_true(&cpu));
+/// ```
+#[derive(Clone,Debug)]
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+impl Condition {
+    /// parse a condition
+    pub fn parse(text: &str) -> Result<Condition, ConditionError> {
+        Condition::parse_expr(text, None)
+    }
+
+    /// parse a condition which can use the labels of a symbol table
+    pub fn parse_with_symbols(text: &str, symbols: &SymbolTable) -> Result<Condition, ConditionError> {
+        Condition::parse_expr(text, Some(symbols))
+    }
+
+    fn parse_expr(text: &str, symbols: Option<&SymbolTable>) -> Result<Condition, ConditionError> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            index: 0,
+            symbols,
+        };
+        let expr = parser.binary(0)?;
+        parser.expect(Token::End)?;
+        Ok(Condition {
+            text: text.to_string(),
+            expr,
+        })
+    }
+
+    /// the condition source text
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// evaluate the condition, comparisons evaluate to 0 or 1
+    pub fn eval(&self, cpu: &CPU) -> RegT {
+        self.expr.eval(cpu)
+    }
+
+    /// true if the condition evaluates to non-zero
+    pub fn is_true(&self, cpu: &CPU) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+/// a breakpoint on an address and/or condition
+#[derive(Clone,Debug)]
+pub struct Breakpoint {
+    /// break only when PC is at this address (any address if None)
+    pub addr: Option<RegT>,
+    /// break only if this condition is true
+    pub cond: Option<Condition>,
+    /// disabled breakpoints are not checked
+    pub enabled: bool,
+    /// number of hits which don't stop the CPU
+    pub ignore_count: u32,
+    /// number of times the address and condition matched
+    pub hits: u32,
+}
+
+impl Breakpoint {
+    /// check a breakpoint and update the hit count, true if the CPU should stop
+    pub fn check(&mut self, cpu: &CPU) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if let Some(addr) = self.addr {
+            if cpu.reg.pc() != (addr & 0xFFFF) {
+                return false;
+            }
+        }
+        if let Some(ref cond) = self.cond {
+            if !cond.is_true(cpu) {
+                return false;
+            }
+        }
+        self.hits += 1;
+        self.hits > self.ignore_count
+    }
+}
+
+/// a list of breakpoints which can be checked in CPU::step_until()
+///
+/// Breakpoints are checked before an instruction is executed, to
+/// continue after a breakpoint has stopped the CPU, execute the
+/// instruction at the breakpoint with CPU::step() first.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, NullBus, Breakpoints, Condition, StopReason};
+///
+/// let mut cpu = CPU::new_64k();
+/// let prog = [
+///     0x3E, 0x00,     // LD A,0x00
+///     0x3C,           // INC A
+///     0x18, 0xFD,     // JR -3
+/// ];
+/// cpu.mem.write(0x0000, &prog);
+///
+/// let mut bps = Breakpoints::new();
+/// let id = bps.add(Some(0x0003), Some(Condition::parse("A>=3").unwrap()));
+/// bps.get_mut(id).unwrap().ignore_count = 1;
+/// let (_, reason) = cpu.step_until(&NullBus, 1000, |cpu| bps.check(cpu).is_some());
+/// assert_eq!(reason, StopReason::Predicate);
+/// assert_eq!(cpu.reg.a(), 4);
+/// assert_eq!(bps.get(id).unwrap().hits, 2);
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct Breakpoints {
+    bps: Vec<Option<Breakpoint>>,
+}
+
+impl Breakpoints {
+    /// create an empty breakpoint list
+    pub fn new() -> Breakpoints {
+        Breakpoints { bps: Vec::new() }
+    }
+
+    /// add a breakpoint on an address and/or condition, return its id
+    pub fn add(&mut self, addr: Option<RegT>, cond: Option<Condition>) -> usize {
+        self.bps.push(Some(Breakpoint {
+            addr,
+            cond,
+            enabled: true,
+            ignore_count: 0,
+            hits: 0,
+        }));
+        self.bps.len() - 1
+    }
+
+    /// remove a breakpoint, the ids of other breakpoints are unchanged
+    pub fn remove(&mut self, id: usize) -> Option<Breakpoint> {
+        self.bps.get_mut(id).and_then(|bp| bp.take())
+    }
+
+    /// remove all breakpoints
+    pub fn clear(&mut self) {
+        self.bps.clear();
+    }
+
+    /// get a breakpoint by id
+    pub fn get(&self, id: usize) -> Option<&Breakpoint> {
+        self.bps.get(id).and_then(|bp| bp.as_ref())
+    }
+
+    /// get a mutable breakpoint by id
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.bps.get_mut(id).and_then(|bp| bp.as_mut())
+    }
+
+    /// iterate over the breakpoints and their ids
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.bps.iter().enumerate().filter_map(|(id, bp)| bp.as_ref().map(|bp| (id, bp)))
+    }
+
+    /// check all breakpoints, return the id of the first one which stops the CPU
+    ///
+    /// All matching breakpoints have their hit counts updated.
+    pub fn check(&mut self, cpu: &CPU) -> Option<usize> {
+        let mut hit = None;
+        for (id, bp) in self.bps.iter_mut().enumerate() {
+            if let Some(ref mut bp) = *bp {
+                if bp.check(cpu) && hit.is_none() {
+                    hit = Some(id);
+                }
+            }
+        }
+        hit
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use memory::Memory;
+
+    fn eval(text: &str, cpu: &CPU) -> RegT {
+        Condition::parse(text).unwrap().eval(cpu)
+    }
+
+    #[test]
+    fn expressions() {
+        let mut cpu = CPU::new_64k();
+        cpu.reg.set_a(0x3F);
+        cpu.reg.set_hl(0x1234);
+        cpu.reg.set_sp(0xFFF0);
+        cpu.mem.w8(0x1234, 0x56);
+        cpu.mem.w8(0x1235, 0x78);
+        assert_eq!(eval("a", &cpu), 0x3F);
+        assert_eq!(eval("1+2*3", &cpu), 7);
+        assert_eq!(eval("[1+2]*3", &cpu), 9);
+        assert_eq!(eval("HL & 0xFF00 | $0C", &cpu), 0x120C);
+        assert_eq!(eval("(HL)", &cpu), 0x56);
+        assert_eq!(eval("(HL+1)<<8 | (HL)", &cpu), 0x7856);
+        assert_eq!(eval("((0x1234)+0x11DF)", &cpu), 0x78);
+        assert_eq!(eval("A==3Fh && (hl)==#56", &cpu), 1);
+        assert_eq!(eval("A!=63 || SP<0xFF00", &cpu), 0);
+        assert_eq!(eval("!A", &cpu), 0);
+        assert_eq!(eval("~0 & 0xFF", &cpu), 0xFF);
+        assert_eq!(eval("-1", &cpu), -1);
+        assert_eq!(eval("10/0 + 10%4", &cpu), 2);
+        assert_eq!(eval("1 < 2 == 1", &cpu), 1);
+        assert_eq!(eval("IFF1 || HALT || IM", &cpu), 0);
+        cpu.reg.set_af_(0x1122);
+        assert_eq!(eval("AF'", &cpu), 0x1122);
+    }
+
+    #[test]
+    fn symbols() {
+        let mut cpu = CPU::new_64k();
+        cpu.reg.set_pc(0x0110);
+        let syms = SymbolTable::parse("main.loop=0x0110").unwrap();
+        let cond = Condition::parse_with_symbols("PC==main.loop", &syms).unwrap();
+        assert_eq!(cond.text(), "PC==main.loop");
+        assert!(cond.is_true(&cpu));
+        assert_eq!(Condition::parse("PC==main.loop").unwrap_err(),
+                   ConditionError::UnknownName("main.loop".to_string()));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Condition::parse("A==").unwrap_err(), ConditionError::Syntax(3));
+        assert_eq!(Condition::parse("A @ 1").unwrap_err(), ConditionError::Syntax(2));
+        assert_eq!(Condition::parse("A==ä").unwrap_err(), ConditionError::Syntax(3));
+        assert_eq!(Condition::parse("(A==1)").unwrap_err(), ConditionError::Syntax(2));
+        assert_eq!(Condition::parse("[A==1").unwrap_err(), ConditionError::Syntax(5));
+        assert_eq!(Condition::parse("1 2").unwrap_err(), ConditionError::Syntax(2));
+        assert_eq!(Condition::parse("0xZZ").unwrap_err(), ConditionError::Syntax(0));
+        assert_eq!(format!("{}", ConditionError::UnknownName("X".to_string())),
+                   "unknown name 'X'");
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut cpu = CPU::new_64k();
+        let mut bps = Breakpoints::new();
+        let a = bps.add(Some(0x0000), None);
+        let b = bps.add(None, Some(Condition::parse("A==1").unwrap()));
+        assert_eq!(bps.check(&cpu), Some(a));
+        bps.get_mut(a).unwrap().enabled = false;
+        assert_eq!(bps.check(&cpu), None);
+        cpu.reg.set_a(1);
+        assert_eq!(bps.check(&cpu), Some(b));
+        assert_eq!(bps.get(a).unwrap().hits, 1);
+        assert_eq!(bps.get(b).unwrap().hits, 1);
+        assert!(bps.remove(a).is_some());
+        assert!(bps.get(a).is_none());
+        assert_eq!(bps.iter().map(|(id, _)| id).collect::<Vec<_>>(), [b]);
+        assert_eq!(bps.check(&cpu), Some(b));
+    }
+
+    #[test]
+    fn host_reads_dont_fault() {
+        // checksums, diffs and breakpoint conditions inspect memory
+        // without calling the guest fault handler
+        let mut cpu = CPU::new();
+        cpu.mem.map(0, 0x0000, 0x0000, true, 0x4000);
+        cpu.mem.set_permissions(0, 0x0000, 0x4000, 0);
+        let faults = Rc::new(Cell::new(0));
+        let count = faults.clone();
+        cpu.mem.set_fault_handler(move |_| count.set(count.get() + 1));
+        cpu.mem.crc32(0x0000, 0x10000);
+        assert!(!cpu.mem.diff(&Memory::new()).is_empty());
+        assert_eq!(eval("(0x0000)", &cpu), 0x00);
+        assert_eq!(eval("(0x8000)", &cpu), 0xFF);
+        assert_eq!(faults.get(), 0);
+
+        // guest reads still fault
+        cpu.mem.r8(0x0000);
+        assert_eq!(faults.get(), 1);
+    }
+}
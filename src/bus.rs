@@ -1,5 +1,12 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use RegT;
+use CPU;
+use StackFault;
 use CTC;
+use GateArray;
+use DeviceMap;
+use DeviceKind;
 
 /// system bus trait
 ///
@@ -9,6 +16,11 @@ use CTC;
 /// need to be communicated to other chips or the higher-level
 /// parts of the emulator (such as port I/O), one of the
 /// trait functions will be called.
+///
+/// All trait functions have default implementations which do nothing
+/// (or return 0), so a system only implements the ones it needs. Larger
+/// systems can be split into several device structs which each implement
+/// Bus, and are combined into the system bus with a CompositeBus.
 #[allow(unused_variables)]
 pub trait Bus {
     /// CPU reads from I/O port
@@ -17,15 +29,42 @@ pub trait Bus {
     }
     /// CPU writes to I/O port
     fn cpu_outp(&self, port: RegT, val: RegT) {}
+    /// extra wait states for an I/O cycle (write is false for IN), on top
+    /// of the one the Z80 inserts into each I/O cycle, for slow peripherals
+    /// which hold /WAIT low, this is called before cpu_inp() or cpu_outp()
+    fn io_wait(&self, port: RegT, write: bool) -> i64 {
+        0
+    }
+    /// CPU has fetched an opcode byte in an M1 cycle (also for prefix bytes)
+    fn m1(&self, pc: RegT, op: RegT) {}
+    /// CPU has hit an undefined ED instruction at pc (see InvalidOpPolicy)
+    fn invalid_op(&self, pc: RegT, op: RegT) {}
+    /// a push or pop of the instruction at pc violated the CPU's stack range
+    fn stack_fault(&self, pc: RegT, sp: RegT, fault: StackFault) {}
+    /// CPU has entered HALT at pc with interrupts disabled (see CPU::set_halt_check())
+    fn dead_halt(&self, pc: RegT) {}
+    /// the CPU's watchdog has detected a loop in the PC range lo..=hi (see CPU::set_watchdog())
+    fn runaway(&self, lo: RegT, hi: RegT) {}
 
+    /// the system's device registry, used by the default pio_irq(),
+    /// ctc_irq() and sio_irq() to route interrupt requests to irq()
+    fn devices(&self) -> Option<&DeviceMap> {
+        None
+    }
     /// request an interrupt, called by a device to generate interrupt
     fn irq(&self, ctrl_id: usize, vec: u8) {}
     /// forward an interrupt-request to CPU, called by daisychain
     fn irq_cpu(&self) {}
     /// interrupt request acknowledge (called by CPU), return interrupt vector
+    /// (in IM2), or the opcode byte to execute (in IM0)
     fn irq_ack(&self) -> RegT {
         0
     }
+    /// IM0 interrupt acknowledge, return the following instruction bytes (index 1..3)
+    /// for multi-byte instructions like CALL nn
+    fn irq_ack_im0(&self, index: usize) -> RegT {
+        0
+    }
     /// notify interrupt daisy chain that CPU executed a RETI
     fn irq_reti(&self) {}
 
@@ -38,12 +77,651 @@ pub trait Bus {
     /// PIO channel rdy line has changed
     fn pio_rdy(&self, pio: usize, chn: usize, rdy: bool) {}
     /// interrupt request from PIO
-    fn pio_irq(&self, pio: usize, chn: usize, int_vector: RegT) {}
+    fn pio_irq(&self, pio: usize, chn: usize, int_vector: RegT) {
+        route_irq(self, DeviceKind::Pio, pio, chn, int_vector);
+    }
 
     /// CTC write callback
     fn ctc_write(&self, chn: usize, ctc: &CTC) {}
     /// CTC counter/timer reached zero
     fn ctc_zero(&self, chn: usize, ctc: &CTC) {}
     /// interrupt request from CTC
-    fn ctc_irq(&self, ctc: usize, chn: usize, int_vector: RegT) {}
+    fn ctc_irq(&self, ctc: usize, chn: usize, int_vector: RegT) {
+        route_irq(self, DeviceKind::Ctc, ctc, chn, int_vector);
+    }
+
+    /// SIO has transmitted a character
+    fn sio_outp(&self, sio: usize, chn: usize, data: RegT) {}
+    /// interrupt request from SIO
+    fn sio_irq(&self, sio: usize, chn: usize, int_vector: RegT) {
+        route_irq(self, DeviceKind::Sio, sio, chn, int_vector);
+    }
+
+    /// CRTC HSYNC output has changed
+    fn crtc_hsync(&self, crtc: usize, active: bool) {}
+    /// CRTC VSYNC output has changed
+    fn crtc_vsync(&self, crtc: usize, active: bool) {}
+
+    /// CPC gate array ROM or RAM configuration has changed
+    fn ga_memory_config(&self, ga: &GateArray) {}
+    /// interrupt request from CPC gate array
+    fn ga_irq(&self) {}
+
+    /// VDP interrupt output has changed
+    fn vdp_irq(&self, active: bool) {}
+    /// TMS9918 interrupt output has changed
+    fn tms9918_irq(&self, active: bool) {}
+
+    /// the vertical blank of a frame has started (see Scheduler::set_frame_timing())
+    fn vblank(&self) {}
+
+    /// a glue logic Latch has been written
+    fn latch_outp(&self, latch: usize, val: RegT) {}
+    /// the output of a glue logic FlipFlop has changed
+    fn flipflop_changed(&self, ff: usize, q: bool) {}
+    /// a glue logic ShiftRegister has shifted, with the new parallel output
+    fn shiftreg_outp(&self, sr: usize, val: u32) {}
+
+    /// analog input value (0..255) of a paddle or analog joystick channel
+    fn analog_inp(&self, chn: usize) -> RegT {
+        0
+    }
+    /// light pen or light gun position (x, y) while it is triggered and
+    /// sees the beam, in the video chip's coordinates (CRTC: character
+    /// column and scanline, VDP: pixel and line), polled once per scanline
+    fn lightpen(&self) -> Option<(RegT, RegT)> {
+        None
+    }
+
+    /// sound output level (-1.0..1.0) of a sound chip or beeper has
+    /// changed at a CPU cycle, usually forwarded to AudioBuffer::sample()
+    fn audio_sample(&self, cycle: u64, value: f32) {}
+
+    /// system reset (called by CPU::system_reset()), reset all devices
+    /// and user state owned by the bus (but not the CPU)
+    fn reset(&self, kind: ResetKind) {}
+}
+
+// forward a chip interrupt request to Bus::irq() via the device map
+fn route_irq<B: Bus + ?Sized>(bus: &B, kind: DeviceKind, id: usize, chn: usize, int_vector: RegT) {
+    if let Some(ctrl_id) = bus.devices().and_then(|d| d.irq_ctrl(kind, id, chn)) {
+        bus.irq(ctrl_id, int_vector as u8);
+    }
+}
+
+/// warm or cold system reset
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ResetKind {
+    /// reset button (RESET line), RAM and chip registers which aren't
+    /// affected by the RESET line keep their content
+    Warm,
+    /// power-on reset, all state is initialized
+    Cold,
+}
+
+/// a chip which can be reset as part of a system reset
+pub trait Reset {
+    /// reset the chip, with a cold reset also clearing the state
+    /// which survives a warm reset
+    fn system_reset(&mut self, kind: ResetKind);
+}
+
+/// reset a group of chips, typically called from Bus::reset()
+///
+/// ```
+/// use std::cell::RefCell;
+/// use rz80::{CPU, PIO, CTC, Daisychain, Bus, ResetKind, reset_all};
+///
+/// struct System {
+///     pio: RefCell<PIO>,
+///     ctc: RefCell<CTC>,
+///     daisy: RefCell<Daisychain>,
+/// }
+///
+/// impl Bus for System {
+///     fn reset(&self, kind: ResetKind) {
+///         reset_all(kind, &mut [&mut *self.pio.borrow_mut(),
+///                               &mut *self.ctc.borrow_mut(),
+///                               &mut *self.daisy.borrow_mut()]);
+///     }
+/// }
+///
+/// let mut cpu = CPU::new_64k();
+/// let sys = System {
+///     pio: RefCell::new(PIO::new(0)),
+///     ctc: RefCell::new(CTC::new(0)),
+///     daisy: RefCell::new(Daisychain::new(2)),
+/// };
+/// cpu.reg.set_pc(0x1234);
+/// cpu.system_reset(&sys, ResetKind::Warm);
+/// assert_eq!(cpu.reg.pc(), 0x0000);
+/// ```
+pub fn reset_all(kind: ResetKind, chips: &mut [&mut dyn Reset]) {
+    for chip in chips.iter_mut() {
+        chip.system_reset(kind);
+    }
+}
+
+/// a Bus implementation which ignores everything
+///
+/// Useful for running code which doesn't do any I/O:
+///
+/// ```
+/// use rz80::{CPU, NullBus};
+///
+/// let mut cpu = CPU::new_64k();
+/// // LD A,0x11
+/// cpu.mem.write(0x0000, &[0x3E, 0x11]);
+/// cpu.step(&NullBus);
+/// assert_eq!(cpu.reg.a(), 0x11);
+/// ```
+#[derive(Clone,Copy,Debug,Default)]
+pub struct NullBus;
+
+impl Bus for NullBus {}
+
+/// a Bus interaction recorded by the RecordingBus
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum BusEvent {
+    CpuInp { port: RegT, val: RegT },
+    CpuOutp { port: RegT, val: RegT },
+    Irq { ctrl_id: usize, vec: u8 },
+    IrqCpu,
+    IrqAck,
+    IrqReti,
+    PioOutp { pio: usize, chn: usize, data: RegT },
+    PioInp { pio: usize, chn: usize },
+    PioRdy { pio: usize, chn: usize, rdy: bool },
+    PioIrq { pio: usize, chn: usize, int_vector: RegT },
+    CtcWrite { chn: usize },
+    CtcZero { chn: usize },
+    CtcIrq { ctc: usize, chn: usize, int_vector: RegT },
+    SioOutp { sio: usize, chn: usize, data: RegT },
+    SioIrq { sio: usize, chn: usize, int_vector: RegT },
+}
+
+/// a Bus implementation which records all port, interrupt, PIO, CTC and SIO interactions
+///
+/// Each event is stamped with the current time in cycles, which is
+/// set with **set_cycles()**, or automatically when the CPU is
+/// stepped through **RecordingBus::step()**. Port reads return the
+/// value set with **set_inp_value()** (default 0xFF).
+///
+/// Besides testing, the RecordingBus can be used as callback buffer for
+/// systems without RefCells: a chip is called with the RecordingBus,
+/// and the system applies the recorded events with take_events()
+/// after the chip call, when the chips can be mutably borrowed again.
+///
+/// ```
+/// use rz80::{CPU, RecordingBus, BusEvent};
+///
+/// let mut cpu = CPU::new_64k();
+/// let bus = RecordingBus::new();
+/// // LD A,0x11; OUT (0x22),A
+/// cpu.mem.write(0x0000, &[0x3E, 0x11, 0xD3, 0x22]);
+/// bus.step(&mut cpu);
+/// bus.step(&mut cpu);
+/// assert_eq!(bus.events(), vec![(7, BusEvent::CpuOutp { port: 0x1122, val: 0x11 })]);
+/// ```
+#[derive(Debug,Default)]
+pub struct RecordingBus {
+    cycles: Cell<u64>,
+    inp_value: Cell<RegT>,
+    events: RefCell<Vec<(u64, BusEvent)>>,
+}
+
+impl RecordingBus {
+    /// create a new RecordingBus with an empty event log
+    pub fn new() -> RecordingBus {
+        RecordingBus {
+            cycles: Cell::new(0),
+            inp_value: Cell::new(0xFF),
+            events: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// set the current time for event timestamps
+    pub fn set_cycles(&self, cycles: u64) {
+        self.cycles.set(cycles);
+    }
+
+    /// set the value returned by port reads
+    pub fn set_inp_value(&self, val: RegT) {
+        self.inp_value.set(val);
+    }
+
+    /// step the CPU with the event timestamp set to the CPU's cycle counter
+    pub fn step(&self, cpu: &mut CPU) -> i64 {
+        self.set_cycles(cpu.cycles);
+        cpu.step(self)
+    }
+
+    /// get a copy of the recorded events
+    pub fn events(&self) -> Vec<(u64, BusEvent)> {
+        self.events.borrow().clone()
+    }
+
+    /// remove and return the recorded events
+    pub fn take_events(&self) -> Vec<(u64, BusEvent)> {
+        self.events.replace(Vec::new())
+    }
+
+    fn record(&self, event: BusEvent) {
+        self.events.borrow_mut().push((self.cycles.get(), event));
+    }
+}
+
+impl Bus for RecordingBus {
+    fn cpu_inp(&self, port: RegT) -> RegT {
+        let val = self.inp_value.get();
+        self.record(BusEvent::CpuInp { port, val });
+        val
+    }
+    fn cpu_outp(&self, port: RegT, val: RegT) {
+        self.record(BusEvent::CpuOutp { port, val });
+    }
+    fn irq(&self, ctrl_id: usize, vec: u8) {
+        self.record(BusEvent::Irq { ctrl_id, vec });
+    }
+    fn irq_cpu(&self) {
+        self.record(BusEvent::IrqCpu);
+    }
+    fn irq_ack(&self) -> RegT {
+        self.record(BusEvent::IrqAck);
+        0
+    }
+    fn irq_reti(&self) {
+        self.record(BusEvent::IrqReti);
+    }
+    fn pio_outp(&self, pio: usize, chn: usize, data: RegT) {
+        self.record(BusEvent::PioOutp { pio, chn, data });
+    }
+    fn pio_inp(&self, pio: usize, chn: usize) -> RegT {
+        self.record(BusEvent::PioInp { pio, chn });
+        self.inp_value.get()
+    }
+    fn pio_rdy(&self, pio: usize, chn: usize, rdy: bool) {
+        self.record(BusEvent::PioRdy { pio, chn, rdy });
+    }
+    fn pio_irq(&self, pio: usize, chn: usize, int_vector: RegT) {
+        self.record(BusEvent::PioIrq { pio, chn, int_vector });
+    }
+    fn ctc_write(&self, chn: usize, _ctc: &CTC) {
+        self.record(BusEvent::CtcWrite { chn });
+    }
+    fn ctc_zero(&self, chn: usize, _ctc: &CTC) {
+        self.record(BusEvent::CtcZero { chn });
+    }
+    fn ctc_irq(&self, ctc: usize, chn: usize, int_vector: RegT) {
+        self.record(BusEvent::CtcIrq { ctc, chn, int_vector });
+    }
+    fn sio_outp(&self, sio: usize, chn: usize, data: RegT) {
+        self.record(BusEvent::SioOutp { sio, chn, data });
+    }
+    fn sio_irq(&self, sio: usize, chn: usize, int_vector: RegT) {
+        self.record(BusEvent::SioIrq { sio, chn, int_vector });
+    }
+}
+
+/// a Bus which forwards to a list of device handlers
+///
+/// Notifications (like cpu_outp() or reset()) are forwarded to all
+/// handlers in the order they were added. The values returned by
+/// cpu_inp(), pio_inp(), analog_inp(), irq_ack() and irq_ack_im0()
+/// are ORed together, so handlers return 0 for ports and channels they
+/// don't decode (which is what the default implementations do).
+/// devices() and lightpen() return the first handler's result which
+/// isn't None. Note that a handler's default pio_irq(), ctc_irq() and
+/// sio_irq() route through the handler's own devices() and irq().
+///
+/// The handlers are shared through Rc, so that the system can keep
+/// a reference to access their state.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::Cell;
+/// use std::rc::Rc;
+/// use rz80::{CPU, Bus, CompositeBus, RegT};
+///
+/// // a latch at port 0x10
+/// struct Latch { val: Cell<RegT> }
+/// impl Bus for Latch {
+///     fn cpu_outp(&self, port: RegT, val: RegT) {
+///         if port & 0xFF == 0x10 { self.val.set(val); }
+///     }
+/// }
+/// // a keyboard at port 0x20
+/// struct Keyboard;
+/// impl Bus for Keyboard {
+///     fn cpu_inp(&self, port: RegT) -> RegT {
+///         if port & 0xFF == 0x20 { 0x41 } else { 0 }
+///     }
+/// }
+///
+/// let latch = Rc::new(Latch { val: Cell::new(0) });
+/// let mut bus = CompositeBus::new();
+/// bus.add(latch.clone());
+/// bus.add(Rc::new(Keyboard));
+///
+/// let mut cpu = CPU::new_64k();
+/// // IN A,(0x20); OUT (0x10),A
+/// cpu.mem.write(0x0000, &[0xDB, 0x20, 0xD3, 0x10]);
+/// cpu.step(&bus);
+/// cpu.step(&bus);
+/// assert_eq!(latch.val.get(), 0x41);
+/// ```
+#[derive(Default)]
+pub struct CompositeBus {
+    handlers: Vec<Rc<dyn Bus>>,
+}
+
+impl CompositeBus {
+    /// create an empty CompositeBus
+    pub fn new() -> CompositeBus {
+        CompositeBus { handlers: Vec::new() }
+    }
+
+    /// add a device handler, returns its index
+    pub fn add(&mut self, handler: Rc<dyn Bus>) -> usize {
+        self.handlers.push(handler);
+        self.handlers.len() - 1
+    }
+
+    /// the number of handlers
+    pub fn len(&self) -> usize {
+        self.handlers.len()
+    }
+
+    /// true if there are no handlers
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// get a handler by index
+    pub fn handler(&self, index: usize) -> &dyn Bus {
+        &*self.handlers[index]
+    }
+}
+
+impl Bus for CompositeBus {
+    fn cpu_inp(&self, port: RegT) -> RegT {
+        self.handlers.iter().fold(0, |val, h| val | h.cpu_inp(port))
+    }
+    fn irq_ack(&self) -> RegT {
+        self.handlers.iter().fold(0, |val, h| val | h.irq_ack())
+    }
+    fn irq_ack_im0(&self, index: usize) -> RegT {
+        self.handlers.iter().fold(0, |val, h| val | h.irq_ack_im0(index))
+    }
+    fn pio_inp(&self, pio: usize, chn: usize) -> RegT {
+        self.handlers.iter().fold(0, |val, h| val | h.pio_inp(pio, chn))
+    }
+    fn analog_inp(&self, chn: usize) -> RegT {
+        self.handlers.iter().fold(0, |val, h| val | h.analog_inp(chn))
+    }
+    fn devices(&self) -> Option<&DeviceMap> {
+        self.handlers.iter().filter_map(|h| h.devices()).next()
+    }
+    fn lightpen(&self) -> Option<(RegT, RegT)> {
+        self.handlers.iter().filter_map(|h| h.lightpen()).next()
+    }
+    fn io_wait(&self, port: RegT, write: bool) -> i64 {
+        // /WAIT is wired-or, the slowest device counts
+        self.handlers.iter().map(|h| h.io_wait(port, write)).max().unwrap_or(0)
+    }
+    fn cpu_outp(&self, port: RegT, val: RegT) {
+        for h in &self.handlers {
+            h.cpu_outp(port, val);
+        }
+    }
+    fn m1(&self, pc: RegT, op: RegT) {
+        for h in &self.handlers {
+            h.m1(pc, op);
+        }
+    }
+    fn invalid_op(&self, pc: RegT, op: RegT) {
+        for h in &self.handlers {
+            h.invalid_op(pc, op);
+        }
+    }
+    fn stack_fault(&self, pc: RegT, sp: RegT, fault: StackFault) {
+        for h in &self.handlers {
+            h.stack_fault(pc, sp, fault);
+        }
+    }
+    fn dead_halt(&self, pc: RegT) {
+        for h in &self.handlers {
+            h.dead_halt(pc);
+        }
+    }
+    fn runaway(&self, lo: RegT, hi: RegT) {
+        for h in &self.handlers {
+            h.runaway(lo, hi);
+        }
+    }
+    fn irq(&self, ctrl_id: usize, vec: u8) {
+        for h in &self.handlers {
+            h.irq(ctrl_id, vec);
+        }
+    }
+    fn irq_cpu(&self) {
+        for h in &self.handlers {
+            h.irq_cpu();
+        }
+    }
+    fn vblank(&self) {
+        for h in &self.handlers {
+            h.vblank();
+        }
+    }
+    fn irq_reti(&self) {
+        for h in &self.handlers {
+            h.irq_reti();
+        }
+    }
+    fn pio_outp(&self, pio: usize, chn: usize, data: RegT) {
+        for h in &self.handlers {
+            h.pio_outp(pio, chn, data);
+        }
+    }
+    fn pio_rdy(&self, pio: usize, chn: usize, rdy: bool) {
+        for h in &self.handlers {
+            h.pio_rdy(pio, chn, rdy);
+        }
+    }
+    fn pio_irq(&self, pio: usize, chn: usize, int_vector: RegT) {
+        for h in &self.handlers {
+            h.pio_irq(pio, chn, int_vector);
+        }
+    }
+    fn ctc_write(&self, chn: usize, ctc: &CTC) {
+        for h in &self.handlers {
+            h.ctc_write(chn, ctc);
+        }
+    }
+    fn ctc_zero(&self, chn: usize, ctc: &CTC) {
+        for h in &self.handlers {
+            h.ctc_zero(chn, ctc);
+        }
+    }
+    fn ctc_irq(&self, ctc: usize, chn: usize, int_vector: RegT) {
+        for h in &self.handlers {
+            h.ctc_irq(ctc, chn, int_vector);
+        }
+    }
+    fn sio_outp(&self, sio: usize, chn: usize, data: RegT) {
+        for h in &self.handlers {
+            h.sio_outp(sio, chn, data);
+        }
+    }
+    fn sio_irq(&self, sio: usize, chn: usize, int_vector: RegT) {
+        for h in &self.handlers {
+            h.sio_irq(sio, chn, int_vector);
+        }
+    }
+    fn crtc_hsync(&self, crtc: usize, active: bool) {
+        for h in &self.handlers {
+            h.crtc_hsync(crtc, active);
+        }
+    }
+    fn crtc_vsync(&self, crtc: usize, active: bool) {
+        for h in &self.handlers {
+            h.crtc_vsync(crtc, active);
+        }
+    }
+    fn ga_memory_config(&self, ga: &GateArray) {
+        for h in &self.handlers {
+            h.ga_memory_config(ga);
+        }
+    }
+    fn ga_irq(&self) {
+        for h in &self.handlers {
+            h.ga_irq();
+        }
+    }
+    fn vdp_irq(&self, active: bool) {
+        for h in &self.handlers {
+            h.vdp_irq(active);
+        }
+    }
+    fn tms9918_irq(&self, active: bool) {
+        for h in &self.handlers {
+            h.tms9918_irq(active);
+        }
+    }
+    fn latch_outp(&self, latch: usize, val: RegT) {
+        for h in &self.handlers {
+            h.latch_outp(latch, val);
+        }
+    }
+    fn flipflop_changed(&self, ff: usize, q: bool) {
+        for h in &self.handlers {
+            h.flipflop_changed(ff, q);
+        }
+    }
+    fn shiftreg_outp(&self, sr: usize, val: u32) {
+        for h in &self.handlers {
+            h.shiftreg_outp(sr, val);
+        }
+    }
+    fn audio_sample(&self, cycle: u64, value: f32) {
+        for h in &self.handlers {
+            h.audio_sample(cycle, value);
+        }
+    }
+    fn reset(&self, kind: ResetKind) {
+        for h in &self.handlers {
+            h.reset(kind);
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {PIO, PIO_A, CTC, CTC_0, Daisychain};
+
+    #[test]
+    fn recording_bus() {
+        let mut cpu = CPU::new_64k();
+        let bus = RecordingBus::new();
+        bus.set_inp_value(0x42);
+        // IN A,(0x10); OUT (0x20),A
+        cpu.mem.write(0x0000, &[0xDB, 0x10, 0xD3, 0x20]);
+        bus.step(&mut cpu);
+        bus.step(&mut cpu);
+        assert_eq!(cpu.reg.a(), 0x42);
+        assert_eq!(bus.take_events(),
+                   vec![(0, BusEvent::CpuInp { port: 0x0010, val: 0x42 }),
+                        (11, BusEvent::CpuOutp { port: 0x4220, val: 0x42 })]);
+        assert!(bus.events().is_empty());
+
+        // PIO output in output mode
+        let mut pio = PIO::new(1);
+        bus.set_cycles(100);
+        pio.write_data(&bus, PIO_A, 0x33);
+        assert_eq!(bus.events(),
+                   vec![(100, BusEvent::PioOutp { pio: 1, chn: PIO_A, data: 0x33 }),
+                        (100, BusEvent::PioRdy { pio: 1, chn: PIO_A, rdy: true })]);
+    }
+
+    #[test]
+    fn composite_bus() {
+        let a = Rc::new(RecordingBus::new());
+        let b = Rc::new(RecordingBus::new());
+        a.set_inp_value(0x0F);
+        b.set_inp_value(0x30);
+        let mut bus = CompositeBus::new();
+        assert!(bus.is_empty());
+        assert_eq!(bus.add(a.clone()), 0);
+        assert_eq!(bus.add(b.clone()), 1);
+        assert_eq!(bus.len(), 2);
+        assert_eq!(bus.cpu_inp(0x1234), 0x3F);
+        bus.cpu_outp(0x10, 0x22);
+        bus.irq_reti();
+        let events = vec![(0, BusEvent::CpuInp { port: 0x1234, val: 0x0F }),
+                          (0, BusEvent::CpuOutp { port: 0x10, val: 0x22 }),
+                          (0, BusEvent::IrqReti)];
+        assert_eq!(a.events(), events);
+        assert_eq!(b.events()[1..], events[1..]);
+        assert!(bus.devices().is_none());
+        assert!(bus.lightpen().is_none());
+        bus.handler(1).irq_cpu();
+        assert_eq!(b.events().last(), Some(&(0, BusEvent::IrqCpu)));
+    }
+
+    struct System {
+        pio: RefCell<PIO>,
+        ctc: RefCell<CTC>,
+        daisy: RefCell<Daisychain>,
+        user: Cell<u32>,
+    }
+
+    impl Bus for System {
+        fn reset(&self, kind: ResetKind) {
+            reset_all(kind,
+                      &mut [&mut *self.pio.borrow_mut(),
+                            &mut *self.ctc.borrow_mut(),
+                            &mut *self.daisy.borrow_mut()]);
+            if kind == ResetKind::Cold {
+                self.user.set(0);
+            }
+        }
+    }
+
+    #[test]
+    fn system_reset() {
+        let mut cpu = CPU::new_64k();
+        let sys = System {
+            pio: RefCell::new(PIO::new(0)),
+            ctc: RefCell::new(CTC::new(0)),
+            daisy: RefCell::new(Daisychain::new(2)),
+            user: Cell::new(1),
+        };
+        sys.pio.borrow_mut().write_control(PIO_A, 0xE0).unwrap();
+        sys.ctc.borrow_mut().write(&NullBus, CTC_0, 0xE8);
+        sys.daisy.borrow_mut().irq(&NullBus, 0, 0x10);
+        // NOP
+        cpu.step(&sys);
+        assert_eq!(cpu.cycles, 4);
+
+        // warm reset keeps interrupt vectors, cycle counter and user state
+        cpu.system_reset(&sys, ResetKind::Warm);
+        assert_eq!(cpu.reg.pc(), 0);
+        assert_eq!(cpu.cycles, 4);
+        assert_eq!(sys.pio.borrow().int_vector(PIO_A), 0xE0);
+        assert_eq!(sys.ctc.borrow().int_vector(CTC_0), 0xE8);
+        assert!(!sys.daisy.borrow().ctrl[0].int_requested);
+        assert_eq!(sys.user.get(), 1);
+
+        // cold reset initializes everything
+        cpu.system_reset(&sys, ResetKind::Cold);
+        assert_eq!(cpu.cycles, 0);
+        assert_eq!(cpu.reg.sp(), 0xFFFF);
+        assert_eq!(sys.pio.borrow().int_vector(PIO_A), 0);
+        assert_eq!(sys.ctc.borrow().int_vector(CTC_0), 0);
+        assert_eq!(sys.user.get(), 0);
+    }
 }
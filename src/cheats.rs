@@ -0,0 +1,230 @@
+use RegT;
+use memory::Memory;
+
+/// how a memory search narrows down the candidate addresses
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Search {
+    /// the byte has this value
+    Value(u8),
+    /// the byte has the same value as in the last search
+    Unchanged,
+    /// the byte has a different value than in the last search
+    Changed,
+    /// the byte is bigger than in the last search
+    Increased,
+    /// the byte is smaller than in the last search
+    Decreased,
+    /// the byte has increased by this value since the last search (with wraparound)
+    IncreasedBy(u8),
+    /// the byte has decreased by this value since the last search (with wraparound)
+    DecreasedBy(u8),
+}
+
+impl Search {
+    fn matches(&self, old: u8, new: u8) -> bool {
+        match *self {
+            Search::Value(val) => new == val,
+            Search::Unchanged => new == old,
+            Search::Changed => new != old,
+            Search::Increased => new > old,
+            Search::Decreased => new < old,
+            Search::IncreasedBy(val) => new == old.wrapping_add(val),
+            Search::DecreasedBy(val) => new == old.wrapping_sub(val),
+        }
+    }
+}
+
+/// memory bytes held at fixed values
+#[derive(Clone,Debug)]
+pub struct Cheat {
+    /// a name for displaying the cheat (e.g. 'INFINITE LIVES')
+    pub name: String,
+    /// the first frozen address
+    pub addr: RegT,
+    /// the frozen values, starting at addr
+    pub values: Vec<u8>,
+    /// disabled cheats are not applied
+    pub enabled: bool,
+}
+
+/// cheats and trainer-style memory search
+///
+/// Freezes hold memory bytes at fixed values, the host calls **apply()**
+/// after each frame (or after each instruction for values which are
+/// read right after being written), which writes the frozen values
+/// into memory, ignoring write-protection.
+///
+/// The memory search finds the address of a game variable: **start_search()**
+/// takes a snapshot of the 64 KByte address space and makes all addresses
+/// candidates, each **search()** drops the candidates which don't match
+/// the current memory (e.g. the number of lives, or 'decreased' after
+/// losing a life) and takes a new snapshot, until only a few candidates
+/// are left.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Memory, CheatEngine, Search};
+///
+/// let mut mem = Memory::new_64k();
+/// mem.w8(0x5000, 3);
+/// mem.w8(0x6000, 3);
+///
+/// let mut cheats = CheatEngine::new();
+/// cheats.start_search(&mem);
+/// assert_eq!(cheats.search(&mem, Search::Value(3)), 2);
+/// // a life is lost
+/// mem.w8(0x5000, 2);
+/// assert_eq!(cheats.search(&mem, Search::DecreasedBy(1)), 1);
+/// assert_eq!(cheats.candidates(), [0x5000]);
+///
+/// // infinite lives
+/// cheats.freeze("LIVES", 0x5000, &[9]);
+/// mem.w8(0x5000, 1);
+/// cheats.apply(&mut mem);
+/// assert_eq!(mem.r8(0x5000), 9);
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct CheatEngine {
+    cheats: Vec<Option<Cheat>>,
+    candidates: Vec<RegT>,
+    snapshot: Vec<u8>,
+}
+
+impl CheatEngine {
+    /// create a cheat engine without cheats
+    pub fn new() -> CheatEngine {
+        CheatEngine {
+            cheats: Vec::new(),
+            candidates: Vec::new(),
+            snapshot: Vec::new(),
+        }
+    }
+
+    /// freeze memory bytes at fixed values, return the id of the cheat
+    pub fn freeze(&mut self, name: &str, addr: RegT, values: &[u8]) -> usize {
+        self.cheats.push(Some(Cheat {
+            name: name.to_string(),
+            addr,
+            values: values.to_vec(),
+            enabled: true,
+        }));
+        self.cheats.len() - 1
+    }
+
+    /// remove a cheat, the ids of other cheats are unchanged
+    pub fn remove(&mut self, id: usize) -> Option<Cheat> {
+        self.cheats.get_mut(id).and_then(|c| c.take())
+    }
+
+    /// remove all cheats
+    pub fn clear(&mut self) {
+        self.cheats.clear();
+    }
+
+    /// get a cheat by id
+    pub fn get(&self, id: usize) -> Option<&Cheat> {
+        self.cheats.get(id).and_then(|c| c.as_ref())
+    }
+
+    /// get a mutable cheat by id
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Cheat> {
+        self.cheats.get_mut(id).and_then(|c| c.as_mut())
+    }
+
+    /// iterate over the cheats and their ids
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Cheat)> {
+        self.cheats.iter().enumerate().filter_map(|(id, c)| c.as_ref().map(|c| (id, c)))
+    }
+
+    /// write the values of all enabled cheats into memory, return the number of applied cheats
+    pub fn apply(&self, mem: &mut Memory) -> usize {
+        let mut num = 0;
+        for cheat in self.cheats.iter().flatten().filter(|c| c.enabled) {
+            mem.write(cheat.addr, &cheat.values);
+            num += 1;
+        }
+        num
+    }
+
+    /// start a new memory search with all addresses as candidates
+    pub fn start_search(&mut self, mem: &Memory) {
+        self.candidates = (0..0x10000).collect();
+        self.snapshot = vec![0; 0x10000];
+        mem.read_slice(0, &mut self.snapshot);
+    }
+
+    /// drop the candidates which don't match, return the number of remaining candidates
+    pub fn search(&mut self, mem: &Memory, search: Search) -> usize {
+        let mut current = vec![0; 0x10000];
+        mem.read_slice(0, &mut current);
+        if self.snapshot.len() == current.len() {
+            let old = &self.snapshot;
+            self.candidates.retain(|&addr| {
+                search.matches(old[addr as usize], current[addr as usize])
+            });
+        }
+        self.snapshot = current;
+        self.candidates.len()
+    }
+
+    /// the remaining candidate addresses of the memory search
+    pub fn candidates(&self) -> &[RegT] {
+        &self.candidates
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search() {
+        let mut mem = Memory::new_64k();
+        let mut cheats = CheatEngine::new();
+        // searching without start_search() has no candidates
+        assert_eq!(cheats.search(&mem, Search::Unchanged), 0);
+
+        mem.w8(0x1000, 0xFF);
+        mem.w8(0x2000, 0x10);
+        cheats.start_search(&mem);
+        assert_eq!(cheats.search(&mem, Search::Unchanged), 0x10000);
+        mem.w8(0x1000, 0x01);
+        mem.w8(0x2000, 0x11);
+        mem.w8(0x3000, 0x20);
+        assert_eq!(cheats.search(&mem, Search::Changed), 3);
+        assert_eq!(cheats.search(&mem, Search::Unchanged), 3);
+        mem.w8(0x1000, 0x00);
+        mem.w8(0x2000, 0x12);
+        mem.w8(0x3000, 0x10);
+        assert_eq!(cheats.search(&mem, Search::Decreased), 2);
+        assert_eq!(cheats.candidates(), [0x1000, 0x3000]);
+        mem.w8(0x1000, 0xFF);
+        assert_eq!(cheats.search(&mem, Search::DecreasedBy(1)), 1);
+        assert_eq!(cheats.candidates(), [0x1000]);
+        mem.w8(0x1000, 0x00);
+        assert_eq!(cheats.search(&mem, Search::IncreasedBy(1)), 1);
+        assert_eq!(cheats.search(&mem, Search::Increased), 0);
+    }
+
+    #[test]
+    fn freeze() {
+        let mut mem = Memory::new_64k();
+        let mut cheats = CheatEngine::new();
+        let a = cheats.freeze("A", 0x1000, &[1, 2]);
+        let b = cheats.freeze("B", 0x2000, &[3]);
+        assert_eq!(cheats.apply(&mut mem), 2);
+        assert_eq!((mem.r16(0x1000), mem.r8(0x2000)), (0x0201, 3));
+
+        cheats.get_mut(a).unwrap().enabled = false;
+        mem.w8(0x1000, 0);
+        mem.w8(0x2000, 0);
+        assert_eq!(cheats.apply(&mut mem), 1);
+        assert_eq!((mem.r8(0x1000), mem.r8(0x2000)), (0, 3));
+        assert_eq!(cheats.remove(b).map(|c| c.name), Some("B".to_string()));
+        assert_eq!(cheats.iter().count(), 1);
+        cheats.clear();
+        assert_eq!(cheats.apply(&mut mem), 0);
+    }
+}
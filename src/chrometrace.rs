@@ -0,0 +1,379 @@
+use std::io;
+use std::fmt::Write as FmtWrite;
+use RegT;
+use cpu::CPU;
+use bus::{Bus, BusEvent};
+use iotrace::{IoTrace, IoDir};
+use symbols::SymbolTable;
+
+/// the track of the CPU call frames
+pub const TRACK_CPU: &str = "CPU";
+/// the track of interrupt requests, acknowledges and RETIs
+pub const TRACK_IRQ: &str = "IRQ";
+/// the track of port reads and writes
+pub const TRACK_IO: &str = "I/O";
+
+#[derive(Clone,Debug)]
+enum Phase {
+    Begin,
+    End,
+    Instant,
+    Complete(u64),
+    Counter(i64),
+}
+
+#[derive(Clone,Debug)]
+struct TraceEvent {
+    cycles: u64,
+    track: usize,
+    phase: Phase,
+    name: String,
+}
+
+/// a cycle-stamped event log which can be viewed in Perfetto or chrome://tracing
+///
+/// Events are recorded on named tracks (shown as threads in the trace
+/// viewer) with the time in CPU cycles, which is converted to
+/// microseconds with the CPU clock when the trace is written in the
+/// Chrome trace event JSON format.
+///
+/// Stepping the CPU through **step()** records the called subroutines
+/// and interrupt handlers as nested slices on the CPU track (a call
+/// is detected when the return address is pushed and PC jumps away, it
+/// ends when SP goes back above the return address). The events of a
+/// RecordingBus or an IoTrace are added with **add_bus_events()** and
+/// **add_io_trace()**, systems can add their own device events with
+/// **instant()**, **begin()**/**end()** and **counter()**.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, ChromeTrace, RecordingBus, SymbolTable};
+///
+/// let mut cpu = CPU::new_64k();
+/// let bus = RecordingBus::new();
+/// // CALL 0x0010; HALT; ... 0x0010: OUT (0x20),A; RET
+/// cpu.mem.write(0x0000, &[0xCD, 0x10, 0x00, 0x76]);
+/// cpu.mem.write(0x0010, &[0xD3, 0x20, 0xC9]);
+/// cpu.reg.set_sp(0x8000);
+/// cpu.reg.set_a(0x00);
+///
+/// let mut trace = ChromeTrace::new(4_000_000);
+/// trace.set_symbols(SymbolTable::parse("print=0x0010").unwrap());
+/// for _ in 0..4 {
+///     bus.set_cycles(cpu.cycles);
+///     trace.step(&mut cpu, &bus);
+/// }
+/// trace.add_bus_events(&bus.take_events());
+/// let json = trace.to_json();
+/// assert!(json.contains(r#""name":"print","ph":"B","ts":0.000"#));
+/// assert!(json.contains(r#""name":"OUT 0020h 00h","ph":"i""#));
+/// ```
+pub struct ChromeTrace {
+    clock_hz: i64,
+    tracks: Vec<String>,
+    events: Vec<TraceEvent>,
+    symbols: SymbolTable,
+    /// SP after each active call
+    frames: Vec<RegT>,
+}
+
+impl ChromeTrace {
+    /// create an empty trace for a CPU running at clock_hz
+    pub fn new(clock_hz: i64) -> ChromeTrace {
+        ChromeTrace {
+            clock_hz,
+            tracks: Vec::new(),
+            events: Vec::new(),
+            symbols: SymbolTable::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// use a symbol table to name the call frames
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// number of recorded events
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// true if no events are recorded
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// remove all recorded events (tracks are kept)
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.frames.clear();
+    }
+
+    fn track(&mut self, name: &str) -> usize {
+        match self.tracks.iter().position(|t| t == name) {
+            Some(index) => index,
+            None => {
+                self.tracks.push(name.to_string());
+                self.tracks.len() - 1
+            }
+        }
+    }
+
+    fn add(&mut self, cycles: u64, track: &str, phase: Phase, name: &str) {
+        let track = self.track(track);
+        self.events.push(TraceEvent {
+            cycles,
+            track,
+            phase,
+            name: name.to_string(),
+        });
+    }
+
+    /// record an instant event
+    pub fn instant(&mut self, cycles: u64, track: &str, name: &str) {
+        self.add(cycles, track, Phase::Instant, name);
+    }
+
+    /// begin a slice, slices on a track must be properly nested
+    pub fn begin(&mut self, cycles: u64, track: &str, name: &str) {
+        self.add(cycles, track, Phase::Begin, name);
+    }
+
+    /// end the innermost slice of a track
+    pub fn end(&mut self, cycles: u64, track: &str) {
+        self.add(cycles, track, Phase::End, "");
+    }
+
+    /// record a slice with known start and duration
+    pub fn complete(&mut self, cycles: u64, duration: u64, track: &str, name: &str) {
+        self.add(cycles, track, Phase::Complete(duration), name);
+    }
+
+    /// record the value of a counter (shown as a graph)
+    pub fn counter(&mut self, cycles: u64, name: &str, value: i64) {
+        self.add(cycles, name, Phase::Counter(value), name);
+    }
+
+    /// step the CPU and record calls and returns on the CPU track
+    pub fn step(&mut self, cpu: &mut CPU, bus: &dyn Bus) -> i64 {
+        let pc0 = cpu.reg.pc();
+        let sp0 = cpu.reg.sp();
+        let cycles = cpu.step(bus);
+        let pc = cpu.reg.pc();
+        let sp = cpu.reg.sp();
+        // a return pops the frames which are now above SP
+        while let Some(&frame_sp) = self.frames.last() {
+            let above = (sp - frame_sp) & 0xFFFF;
+            if above == 0 || above >= 0x8000 {
+                break;
+            }
+            self.frames.pop();
+            self.end(cpu.cycles, TRACK_CPU);
+        }
+        // a call or interrupt pushes a return address near the old PC and jumps away
+        if sp == ((sp0 - 2) & 0xFFFF) {
+            let ret = cpu.mem.peek8(sp) | cpu.mem.peek8(sp + 1) << 8;
+            let near = ((ret - pc0) & 0xFFFF) <= 4;
+            if near && ((pc - pc0) & 0xFFFF) > 4 {
+                let name = match self.symbols.name(pc) {
+                    Some(name) => name.to_string(),
+                    None => format!("{:04X}h", pc),
+                };
+                self.frames.push(sp);
+                self.begin(cpu.cycles - cycles as u64, TRACK_CPU, &name);
+            }
+        }
+        cycles
+    }
+
+    /// add the events recorded by a RecordingBus
+    pub fn add_bus_events(&mut self, events: &[(u64, BusEvent)]) {
+        for &(cycles, ref event) in events {
+            let (track, name) = match *event {
+                BusEvent::CpuInp { port, val } => {
+                    (TRACK_IO.to_string(), format!("IN {:04X}h {:02X}h", port, val))
+                }
+                BusEvent::CpuOutp { port, val } => {
+                    (TRACK_IO.to_string(), format!("OUT {:04X}h {:02X}h", port, val))
+                }
+                BusEvent::Irq { ctrl_id, vec } => {
+                    (TRACK_IRQ.to_string(), format!("request {} vector {:02X}h", ctrl_id, vec))
+                }
+                BusEvent::IrqCpu => (TRACK_IRQ.to_string(), "irq".to_string()),
+                BusEvent::IrqAck => (TRACK_IRQ.to_string(), "ack".to_string()),
+                BusEvent::IrqReti => (TRACK_IRQ.to_string(), "reti".to_string()),
+                BusEvent::PioOutp { pio, chn, data } => {
+                    (format!("PIO{}", pio), format!("{} out {:02X}h", pio_chn(chn), data))
+                }
+                BusEvent::PioInp { pio, chn } => {
+                    (format!("PIO{}", pio), format!("{} in", pio_chn(chn)))
+                }
+                BusEvent::PioRdy { pio, chn, rdy } => {
+                    (format!("PIO{}", pio), format!("{} rdy {}", pio_chn(chn), rdy as u8))
+                }
+                BusEvent::PioIrq { pio, chn, int_vector } => {
+                    (format!("PIO{}", pio), format!("{} irq {:02X}h", pio_chn(chn), int_vector))
+                }
+                BusEvent::CtcWrite { chn } => ("CTC".to_string(), format!("CTC{} write", chn)),
+                BusEvent::CtcZero { chn } => ("CTC".to_string(), format!("CTC{} zero", chn)),
+                BusEvent::CtcIrq { ctc, chn, int_vector } => {
+                    (format!("CTC{}", ctc), format!("CTC{} irq {:02X}h", chn, int_vector))
+                }
+                BusEvent::SioOutp { sio, chn, data } => {
+                    (format!("SIO{}", sio), format!("{} out {:02X}h", pio_chn(chn), data))
+                }
+                BusEvent::SioIrq { sio, chn, int_vector } => {
+                    (format!("SIO{}", sio), format!("{} irq {:02X}h", pio_chn(chn), int_vector))
+                }
+            };
+            self.instant(cycles, &track, &name);
+        }
+    }
+
+    /// add the accesses recorded by an IoTrace (with the decoder descriptions)
+    pub fn add_io_trace(&mut self, trace: &IoTrace) {
+        for access in trace.entries() {
+            let dir = match access.dir {
+                IoDir::In => "IN",
+                IoDir::Out => "OUT",
+            };
+            let mut name = format!("{} {:04X}h {:02X}h", dir, access.port, access.val);
+            if let Some(info) = access.info {
+                name = format!("{} {}", name, info);
+            }
+            self.instant(access.cycles, TRACK_IO, &name);
+        }
+    }
+
+    /// the trace in Chrome trace event JSON format
+    pub fn to_json(&self) -> String {
+        let mut s = String::from("{\"traceEvents\":[\n");
+        for (tid, name) in self.tracks.iter().enumerate() {
+            let _ = writeln!(s, "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\
+                                \"args\":{{\"name\":\"{}\"}}}},", tid, escape(name));
+        }
+        for event in &self.events {
+            let ts = self.micros(event.cycles);
+            let _ = write!(s, "{{\"name\":\"{}\",", escape(&event.name));
+            let _ = match event.phase {
+                Phase::Begin => write!(s, "\"ph\":\"B\",\"ts\":{}", ts),
+                Phase::End => write!(s, "\"ph\":\"E\",\"ts\":{}", ts),
+                Phase::Instant => write!(s, "\"ph\":\"i\",\"s\":\"t\",\"ts\":{}", ts),
+                Phase::Complete(duration) => {
+                    write!(s, "\"ph\":\"X\",\"ts\":{},\"dur\":{}", ts, self.micros(duration))
+                }
+                Phase::Counter(value) => {
+                    write!(s, "\"ph\":\"C\",\"ts\":{},\"args\":{{\"value\":{}}}", ts, value)
+                }
+            };
+            let _ = writeln!(s, ",\"pid\":1,\"tid\":{}}},", event.track);
+        }
+        // the trailing comma is allowed in the trace format, but not in JSON
+        if s.ends_with(",\n") {
+            s.truncate(s.len() - 2);
+            s.push('\n');
+        }
+        s.push_str("],\"displayTimeUnit\":\"ns\"}\n");
+        s
+    }
+
+    /// write the trace in Chrome trace event JSON format
+    pub fn write(&self, out: &mut dyn io::Write) -> io::Result<()> {
+        out.write_all(self.to_json().as_bytes())
+    }
+
+    fn micros(&self, cycles: u64) -> String {
+        let nanos = (cycles as u128 * 1_000_000_000 / self.clock_hz as u128) as u64;
+        format!("{}.{:03}", nanos / 1000, nanos % 1000)
+    }
+}
+
+fn pio_chn(chn: usize) -> &'static str {
+    if chn == 0 { "A" } else { "B" }
+}
+
+fn escape(text: &str) -> String {
+    let mut s = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(s, "\\u{:04x}", c as u32);
+            }
+            c => s.push(c),
+        }
+    }
+    s
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::RecordingBus;
+
+    #[test]
+    fn call_frames() {
+        let mut cpu = CPU::new_64k();
+        let bus = RecordingBus::new();
+        // 0000: CALL 0010h; PUSH HL; HALT
+        // 0010: CALL 0020h; RET
+        // 0020: RST 38h; RET
+        // 0038: RET
+        cpu.mem.write(0x0000, &[0xCD, 0x10, 0x00, 0xE5, 0x76]);
+        cpu.mem.write(0x0010, &[0xCD, 0x20, 0x00, 0xC9]);
+        cpu.mem.write(0x0020, &[0xFF, 0xC9]);
+        cpu.mem.write(0x0038, &[0xC9]);
+        cpu.reg.set_sp(0x8000);
+        let mut trace = ChromeTrace::new(1_000_000);
+        let mut syms = SymbolTable::new();
+        syms.add("outer", 0x0010);
+        trace.set_symbols(syms);
+        for _ in 0..10 {
+            trace.step(&mut cpu, &bus);
+        }
+        assert_eq!(cpu.reg.pc(), 0x0004);
+        let phases: Vec<String> = trace.events.iter().map(|e| match e.phase {
+            Phase::Begin => format!("B {} {}", e.cycles, e.name),
+            Phase::End => format!("E {}", e.cycles),
+            _ => String::new(),
+        }).collect();
+        // PUSH HL isn't a call
+        assert_eq!(phases, ["B 0 outer", "B 17 0020h", "B 34 0038h",
+                            "E 55", "E 65", "E 75"]);
+        assert!(trace.frames.is_empty());
+    }
+
+    #[test]
+    fn json() {
+        let mut trace = ChromeTrace::new(3_000_000);
+        trace.begin(3, "main", "frame \"1\"");
+        trace.counter(6, "level", -5);
+        trace.complete(9, 30, "CTC", "CTC0");
+        trace.end(3_000_000, "main");
+        trace.add_bus_events(&[(1, BusEvent::CtcZero { chn: 2 }),
+                               (2, BusEvent::PioOutp { pio: 0, chn: 1, data: 0x55 })]);
+        assert_eq!(trace.len(), 6);
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = json.lines().collect();
+        assert_eq!(lines, [
+            r#"{"traceEvents":["#,
+            r#"{"name":"thread_name","ph":"M","pid":1,"tid":0,"args":{"name":"main"}},"#,
+            r#"{"name":"thread_name","ph":"M","pid":1,"tid":1,"args":{"name":"level"}},"#,
+            r#"{"name":"thread_name","ph":"M","pid":1,"tid":2,"args":{"name":"CTC"}},"#,
+            r#"{"name":"thread_name","ph":"M","pid":1,"tid":3,"args":{"name":"PIO0"}},"#,
+            r#"{"name":"frame \"1\"","ph":"B","ts":1.000,"pid":1,"tid":0},"#,
+            r#"{"name":"level","ph":"C","ts":2.000,"args":{"value":-5},"pid":1,"tid":1},"#,
+            r#"{"name":"CTC0","ph":"X","ts":3.000,"dur":10.000,"pid":1,"tid":2},"#,
+            r#"{"name":"","ph":"E","ts":1000000.000,"pid":1,"tid":0},"#,
+            r#"{"name":"CTC2 zero","ph":"i","s":"t","ts":0.333,"pid":1,"tid":2},"#,
+            r#"{"name":"B out 55h","ph":"i","s":"t","ts":0.666,"pid":1,"tid":3}"#,
+            r#"],"displayTimeUnit":"ns"}"#,
+        ]);
+    }
+}
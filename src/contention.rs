@@ -0,0 +1,283 @@
+use RegT;
+use bus::Bus;
+use cpu::CPU;
+use mcycles::{MCycle, MCycleKind};
+use zx128::ZX128Paging;
+
+/// the delays of the 8-cycle ULA fetch pattern
+const PATTERN: [u8; 8] = [6, 5, 4, 3, 2, 1, 0, 0];
+/// the number of display lines with contention
+const DISPLAY_LINES: i64 = 192;
+/// the number of cycles per line with contention
+const DISPLAY_CYCLES: i64 = 128;
+
+/// the ULA timing of a Spectrum model
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ContentionModel {
+    /// 48K Spectrum: 312 lines with 224 cycles
+    Spectrum48,
+    /// Spectrum 128 and +2: 311 lines with 228 cycles
+    Spectrum128,
+}
+
+impl ContentionModel {
+    /// the number of cycles per line
+    pub fn cycles_per_line(&self) -> i64 {
+        match *self {
+            ContentionModel::Spectrum48 => 224,
+            ContentionModel::Spectrum128 => 228,
+        }
+    }
+
+    /// the number of lines per frame
+    pub fn lines(&self) -> i64 {
+        match *self {
+            ContentionModel::Spectrum48 => 312,
+            ContentionModel::Spectrum128 => 311,
+        }
+    }
+
+    /// the number of cycles per frame
+    pub fn cycles_per_frame(&self) -> i64 {
+        self.cycles_per_line() * self.lines()
+    }
+
+    /// the first contended cycle of a frame (with the longest delay)
+    pub fn first_contended(&self) -> i64 {
+        match *self {
+            ContentionModel::Spectrum48 => 14335,
+            ContentionModel::Spectrum128 => 14361,
+        }
+    }
+}
+
+/// the memory and I/O contention of the Spectrum ULA
+///
+/// While the ULA fetches the screen, CPU accesses to the contended
+/// memory (0x4000..0x7FFF on all models, and the odd RAM banks at
+/// 0xC000 on the 128) are delayed by up to 6 cycles, depending on the
+/// cycle within the frame. The delay of each cycle of a frame is
+/// precomputed in a table.
+///
+/// **step()** executes one instruction with the machine cycle trace
+/// (see CPU::set_mcycle_trace()) and adds the delays of the memory and
+/// I/O cycles, starting at the current cycle within the frame. The
+/// delays are added to the returned cycles and CPU::cycles, the trace
+/// itself keeps the uncontended timing.
+///
+/// The internal cycles which keep a contended address on the bus (like
+/// the extra cycles of INC (HL) or LDIR) are not contended, because the
+/// trace doesn't record their address. The +2A and +3 contention isn't
+/// covered.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, NullBus, ContendedMemory, ContentionModel};
+///
+/// let mut cpu = CPU::new_64k();
+/// let mut ula = ContendedMemory::new(ContentionModel::Spectrum48);
+/// // LD A,(4000h) in uncontended memory
+/// cpu.mem.write(0x8000, &[0x3A, 0x00, 0x40]);
+/// cpu.reg.set_pc(0x8000);
+/// ula.set_tstate(14335);
+///
+/// // the read of 4000h starts at cycle 14345 with a delay of 4 cycles
+/// assert_eq!(ula.delay(14345), 4);
+/// assert_eq!(ula.step(&mut cpu, &NullBus), 13 + 4);
+/// assert_eq!(ula.tstate(), 14335 + 17);
+/// ```
+pub struct ContendedMemory {
+    model: ContentionModel,
+    table: Vec<u8>,
+    contended: [bool; 4],
+    tstate: i64,
+}
+
+impl ContendedMemory {
+    /// create the contention of a model, with 0x4000..0x7FFF contended
+    pub fn new(model: ContentionModel) -> ContendedMemory {
+        let mut table = vec![0; model.cycles_per_frame() as usize];
+        for line in 0..DISPLAY_LINES {
+            let start = model.first_contended() + line * model.cycles_per_line();
+            for x in 0..DISPLAY_CYCLES {
+                table[(start + x) as usize] = PATTERN[(x & 7) as usize];
+            }
+        }
+        ContendedMemory {
+            model,
+            table,
+            contended: [false, true, false, false],
+            tstate: 0,
+        }
+    }
+
+    /// the ULA model
+    pub fn model(&self) -> ContentionModel {
+        self.model
+    }
+
+    /// the contention delay of a cycle within the frame
+    pub fn delay(&self, tstate: i64) -> i64 {
+        self.table[tstate.rem_euclid(self.model.cycles_per_frame()) as usize] as i64
+    }
+
+    /// the current cycle within the frame
+    pub fn tstate(&self) -> i64 {
+        self.tstate
+    }
+
+    /// set the current cycle within the frame (0 is the start of the frame interrupt)
+    pub fn set_tstate(&mut self, tstate: i64) {
+        self.tstate = tstate.rem_euclid(self.model.cycles_per_frame());
+    }
+
+    /// true if an address is in contended memory
+    pub fn is_contended(&self, addr: RegT) -> bool {
+        self.contended[((addr >> 14) & 3) as usize]
+    }
+
+    /// set whether one of the 4 16-KByte slots of the address space is contended
+    pub fn set_contended(&mut self, slot: usize, contended: bool) {
+        self.contended[slot] = contended;
+    }
+
+    /// update the contended slots from the 128K paging (the odd RAM banks are contended)
+    pub fn update_paging(&mut self, paging: &ZX128Paging) {
+        let banks = paging.special_banks().unwrap_or([0, 5, 2, paging.ram_bank()]);
+        let rom = paging.special_banks().is_none();
+        for (slot, &bank) in banks.iter().enumerate() {
+            self.contended[slot] = !(rom && slot == 0) && (bank & 1) != 0;
+        }
+    }
+
+    /// the total delay of machine cycles which start at a cycle within the frame
+    pub fn contend(&self, tstate: i64, cycles: &[MCycle]) -> i64 {
+        let mut t = tstate;
+        let mut extra = 0;
+        for c in cycles {
+            let d = match c.kind {
+                MCycleKind::Fetch | MCycleKind::Read | MCycleKind::Write
+                    if self.is_contended(c.addr) => self.delay(t),
+                MCycleKind::In | MCycleKind::Out => self.io_delay(t, c.addr),
+                _ => 0,
+            };
+            extra += d;
+            t += d + c.tstates;
+        }
+        extra
+    }
+
+    /// private method to compute the delay of an I/O cycle
+    ///
+    /// The ULA is selected by even ports, and the high byte of the
+    /// port is on the address bus like a memory address.
+    fn io_delay(&self, tstate: i64, port: RegT) -> i64 {
+        let high = self.is_contended(port);
+        let ula = (port & 1) == 0;
+        let mut t = tstate;
+        match (high, ula) {
+            // N:1, C:3
+            (false, true) => self.delay(t + 1),
+            // N:4
+            (false, false) => 0,
+            // C:1, C:3
+            (true, true) => {
+                let d1 = self.delay(t);
+                t += d1 + 1;
+                d1 + self.delay(t)
+            }
+            // C:1, C:1, C:1, C:1
+            (true, false) => {
+                let mut extra = 0;
+                for _ in 0..4 {
+                    let d = self.delay(t);
+                    extra += d;
+                    t += d + 1;
+                }
+                extra
+            }
+        }
+    }
+
+    /// execute one instruction with contention, return the number of cycles taken
+    pub fn step(&mut self, cpu: &mut CPU, bus: &dyn Bus) -> i64 {
+        cpu.set_mcycle_trace(true);
+        let cycles = cpu.step(bus);
+        let extra = self.contend(self.tstate, cpu.mcycles());
+        cpu.cycles += extra as u64;
+        let frame = self.model.cycles_per_frame();
+        self.tstate = (self.tstate + cycles + extra) % frame;
+        cycles + extra
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::NullBus;
+    use memory::Memory;
+    use zx128::ZX128Model;
+
+    #[test]
+    fn tables() {
+        let ula = ContendedMemory::new(ContentionModel::Spectrum48);
+        assert_eq!(ula.model().cycles_per_frame(), 69888);
+        assert_eq!(ula.delay(14334), 0);
+        let delays: Vec<i64> = (14335..14343).map(|t| ula.delay(t)).collect();
+        assert_eq!(delays, [6, 5, 4, 3, 2, 1, 0, 0]);
+        // the border part of the line and the next line
+        assert_eq!(ula.delay(14335 + 128), 0);
+        assert_eq!(ula.delay(14335 + 224), 6);
+        // the last display line, and the next frame
+        assert_eq!(ula.delay(14335 + 191 * 224 + 127), 0);
+        assert_eq!(ula.delay(14335 + 191 * 224 + 126), 0);
+        assert_eq!(ula.delay(14335 + 191 * 224 + 125), 1);
+        assert_eq!(ula.delay(14335 + 192 * 224), 0);
+        assert_eq!(ula.delay(69888 + 14336), 5);
+
+        let ula = ContendedMemory::new(ContentionModel::Spectrum128);
+        assert_eq!(ula.model().cycles_per_frame(), 70908);
+        assert_eq!(ula.delay(14361 + 228 + 2), 4);
+    }
+
+    #[test]
+    fn io() {
+        let mut cpu = CPU::new_64k();
+        let mut ula = ContendedMemory::new(ContentionModel::Spectrum48);
+        // OUT (FEh),A; IN A,(FFh) with A=40h
+        cpu.mem.write(0x8000, &[0xD3, 0xFE, 0xDB, 0xFF]);
+        cpu.reg.set_pc(0x8000);
+        cpu.reg.set_a(0x00);
+        // the I/O cycle starts at 14335 + 7: N:1 at 14342, C:3 at 14343 (delay 6)
+        ula.set_tstate(14335);
+        assert_eq!(ula.step(&mut cpu, &NullBus), 11 + 6);
+        // uncontended high byte and odd port: no delay
+        assert_eq!(ula.step(&mut cpu, &NullBus), 11);
+
+        // contended high byte and odd port: C:1 four times, at 14335 (delay 6),
+        // 14342 (no delay), 14343 (delay 6) and 14350 (no delay)
+        cpu.reg.set_pc(0x8002);
+        cpu.reg.set_a(0x40);
+        ula.set_tstate(14335 - 7);
+        assert_eq!(ula.step(&mut cpu, &NullBus), 11 + 12);
+        assert_eq!(cpu.cycles, 17 + 11 + 23);
+    }
+
+    #[test]
+    fn paging() {
+        static ROM: [u8; 0x8000] = [0; 0x8000];
+        let mut mem = Memory::new();
+        let mut paging = ZX128Paging::new(ZX128Model::Spectrum128, &ROM, 0);
+        paging.init(&mut mem);
+        let mut ula = ContendedMemory::new(ContentionModel::Spectrum128);
+        ula.update_paging(&paging);
+        assert!(!ula.is_contended(0x3FFF) && ula.is_contended(0x4000) && !ula.is_contended(0xC000));
+        paging.write_7ffd(&mut mem, 0x03);
+        ula.update_paging(&paging);
+        assert!(ula.is_contended(0xC000) && !ula.is_contended(0x8000));
+        ula.set_contended(2, true);
+        assert!(ula.is_contended(0xBFFF));
+    }
+}
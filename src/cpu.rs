@@ -1,7 +1,17 @@
+use std::fmt;
 use RegT;
 use memory::Memory;
-use registers::Registers;
-use bus::Bus;
+use registers::{Registers, Reg8, Reg16, Im};
+use decoder::{decode, is_index_reg8, is_index_reg16, Instruction, Operand};
+use bus::{Bus, ResetKind};
+use iobus::{IoBus, IoBusAdapter};
+use blocks::BlockCache;
+use mcycles::{self, MCycle, MCycleKind, MCycleTrace};
+use watchdog::Watchdog;
+use opstats::OpcodeStats;
+use savestate::{SaveState, ChunkWriter, ChunkReader, StateError};
+#[cfg(feature = "jit")]
+use jit::Jit;
 
 /// Z80 CPU emulation
 ///
@@ -22,8 +32,7 @@ use bus::Bus;
 ///
 /// What's **not** implemented:
 ///
-/// - interrupt modes 0 and 1
-/// - non-maskable interrupts (including the RETN instruction)
+/// - non-maskable interrupts
 /// - extra memory wait states
 ///
 /// # Examples
@@ -61,19 +70,122 @@ use bus::Bus;
 /// }
 /// assert_eq!(cpu.reg.a(), 0x33);
 /// assert_eq!(cycles, 18);
+///
+/// // the CPU also keeps track of the total number of cycles
+/// assert_eq!(cpu.cycles, 18);
 /// ```
 ///
 pub struct CPU {
     pub reg: Registers,
-    pub halt: bool,
-    pub iff1: bool,
-    pub iff2: bool,
+    /// set by step() if an undefined ED instruction was executed (as NOP)
     pub invalid_op: bool,
+    /// set by step() if a push or pop violated the stack range (see set_stack_range())
+    pub stack_fault: Option<StackFault>,
+    /// set by step() if HALT was entered with interrupts disabled (see set_halt_check())
+    pub dead_halt: bool,
+    /// set by step() if the watchdog has tripped (see set_watchdog())
+    pub runaway: bool,
+    /// total number of cycles executed by step() and skip_halt() (not cleared by reset())
+    pub cycles: u64,
+    halt: bool,
+    iff1: bool,
+    iff2: bool,
     enable_interrupt: bool,
     irq_received: bool,
+    ld_a_ir: bool,
+    im0_active: bool,
+    im0_pos: usize,
+    im0_data: [RegT; 4],
+    variant: CpuVariant,
+    out_c0_value: RegT,
+    invalid_op_policy: InvalidOpPolicy,
+    stack_range: Option<(RegT, RegT)>,
+    halt_check: bool,
+    watchdog: Option<Watchdog>,
+    io_access: bool,
+    io_wait: i64,
+    block_cache: Option<Box<BlockCache>>,
+    #[cfg(feature = "jit")]
+    jit: Option<Box<Jit>>,
+    mcycle_trace: Option<MCycleTrace>,
+    opcode_stats: Option<Box<OpcodeStats>>,
     pub mem: Memory,
 }
 
+/// the Z80 chip variant emulated by a CPU object
+///
+/// The variants differ in some undocumented behaviour: the undocumented
+/// OUT (C),0 instruction (ED 71) outputs 0 on NMOS chips and 0xFF on CMOS
+/// chips, and SCF/CCF copy the undocumented X and Y flags from A or'ed with
+/// the previous flags (NMOS), only from A (CMOS), or leave them unchanged (R800).
+/// On NMOS chips, LD A,I and LD A,R also reset the P/V flag (instead of
+/// copying IFF2) when an interrupt is accepted right after the instruction,
+/// some copy protections check this.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum CpuVariant {
+    /// the original NMOS Z80 (the default)
+    NMOS,
+    /// the CMOS Z80 (Z84C00)
+    CMOS,
+    /// an R800-like CPU
+    R800,
+}
+
+/// what the CPU does when it executes an undefined ED instruction
+///
+/// In all cases the CPU's invalid_op flag is set after step().
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum InvalidOpPolicy {
+    /// execute as 8-cycle NOP like real hardware (the default)
+    Nop,
+    /// execute as NOP and call Bus::invalid_op()
+    Log,
+    /// call Bus::invalid_op() but don't execute, PC stays on the ED prefix
+    /// and CPU::step_until() stops with StopReason::InvalidOp
+    Trap,
+}
+
+/// reason why CPU::step_until() has returned
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum StopReason {
+    /// the predicate function returned true
+    Predicate,
+    /// the CPU is in HALT state
+    Halt,
+    /// the cycle budget has been used up
+    Cycles,
+    /// an undefined instruction was hit with InvalidOpPolicy::Trap
+    InvalidOp,
+    /// a push or pop violated the stack range (see CPU::set_stack_range())
+    StackFault,
+    /// the watchdog has detected a runaway loop (see CPU::set_watchdog())
+    Runaway,
+}
+
+/// registers, bus accesses and cycles of a single step, see CPU::step_captured()
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct StepRecord {
+    /// the registers before the step
+    pub before: Registers,
+    /// the registers after the step
+    pub after: Registers,
+    /// the memory and I/O accesses in execution order (without internal cycles)
+    pub accesses: Vec<MCycle>,
+    /// the number of cycles returned by step()
+    pub cycles: i64,
+}
+
+/// stack pointer violation detected by the stack range guard
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum StackFault {
+    /// a push moved SP below the bottom of the stack range
+    Overflow,
+    /// a pop moved SP above the top of the stack range
+    Underflow,
+    /// a push or pop wrapped SP through 0x0000
+    Wrap,
+}
+
 use registers::CF;
 use registers::NF;
 use registers::VF;
@@ -83,12 +195,13 @@ use registers::HF;
 use registers::YF;
 use registers::ZF;
 use registers::SF;
+use registers::XYF;
 
 #[inline(always)]
 #[cfg_attr(rustfmt, rustfmt_skip)]
 fn flags_add(acc: RegT, add: RegT, res: RegT) -> RegT {
     (if (res & 0xFF) == 0 {ZF} else {res & SF}) |
-    (res & (YF | XF)) | ((res >> 8) & CF) |
+    (res & XYF) | ((res >> 8) & CF) |
     ((acc ^ add ^ res) & HF) | ((((acc ^ add ^ 0x80) & (add ^ res)) >> 5) & VF)
 }
 
@@ -96,7 +209,7 @@ fn flags_add(acc: RegT, add: RegT, res: RegT) -> RegT {
 #[cfg_attr(rustfmt, rustfmt_skip)]
 fn flags_sub(acc: RegT, sub: RegT, res: RegT) -> RegT {
     NF | (if (res & 0xFF) == 0 {ZF} else {res & SF}) |
-    (res & (YF | XF)) | ((res >> 8) & CF) |
+    (res & XYF) | ((res >> 8) & CF) |
     ((acc ^ sub ^ res) & HF) | ((((acc ^ sub) & (res ^ acc)) >> 5) & VF)
 }
 
@@ -107,7 +220,7 @@ fn flags_cp(acc: RegT, sub: RegT, res: RegT) -> RegT {
     // 2 undocumented flag bits X and Y are taken from the
     // sub-value, not the result
     NF | (if (res & 0xFF) == 0 {ZF} else {res & SF}) |
-    (sub & (YF | XF)) | ((res >> 8) & CF) |
+    (sub & XYF) | ((res >> 8) & CF) |
     ((acc ^ sub ^ res) & HF) | ((((acc ^ sub) & (res ^ acc)) >> 5) & VF)
 }
 
@@ -116,14 +229,20 @@ fn flags_cp(acc: RegT, sub: RegT, res: RegT) -> RegT {
 fn flags_szp(val: RegT) -> RegT {
     let v = val & 0xFF;
     (if (v.count_ones() & 1) == 0 {PF} else {0}) |
-    (if v == 0 {ZF} else {v & SF}) | (v & (YF | XF))
+    (if v == 0 {ZF} else {v & SF}) | (v & XYF)
 }
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 #[inline(always)]
 fn flags_sziff2(val: RegT, iff2: bool) -> RegT {
     (if (val & 0xFF) == 0 {ZF} else {val & SF}) |
-    (val & (YF | XF)) | if iff2 {PF} else {0}
+    (val & XYF) | if iff2 {PF} else {0}
+}
+
+#[inline(always)]
+fn prefix_cycles(indexed: bool) -> i64 {
+    // the DD or FD prefix of instructions on IX and IY
+    if indexed { 4 } else { 0 }
 }
 
 use registers::BC;
@@ -146,8 +265,29 @@ impl CPU {
             iff1: false,
             iff2: false,
             invalid_op: false,
+            stack_fault: None,
+            dead_halt: false,
+            runaway: false,
+            cycles: 0,
             enable_interrupt: false,
             irq_received: false,
+            ld_a_ir: false,
+            im0_active: false,
+            im0_pos: 0,
+            im0_data: [0; 4],
+            variant: CpuVariant::NMOS,
+            out_c0_value: 0,
+            invalid_op_policy: InvalidOpPolicy::Nop,
+            stack_range: None,
+            halt_check: false,
+            watchdog: None,
+            io_access: false,
+            io_wait: 0,
+            block_cache: None,
+            #[cfg(feature = "jit")]
+            jit: None,
+            mcycle_trace: None,
+            opcode_stats: None,
             mem: Memory::new(),
         }
     }
@@ -160,12 +300,162 @@ impl CPU {
             iff1: false,
             iff2: false,
             invalid_op: false,
+            stack_fault: None,
+            dead_halt: false,
+            runaway: false,
+            cycles: 0,
             enable_interrupt: false,
             irq_received: false,
+            ld_a_ir: false,
+            im0_active: false,
+            im0_pos: 0,
+            im0_data: [0; 4],
+            variant: CpuVariant::NMOS,
+            out_c0_value: 0,
+            invalid_op_policy: InvalidOpPolicy::Nop,
+            stack_range: None,
+            halt_check: false,
+            watchdog: None,
+            io_access: false,
+            io_wait: 0,
+            block_cache: None,
+            #[cfg(feature = "jit")]
+            jit: None,
+            mcycle_trace: None,
+            opcode_stats: None,
             mem: Memory::new_64k(),
         }
     }
 
+    /// set the emulated chip variant, this also sets the OUT (C),0 value
+    pub fn set_variant(&mut self, variant: CpuVariant) {
+        self.variant = variant;
+        self.out_c0_value = if variant == CpuVariant::NMOS { 0 } else { 0xFF };
+    }
+
+    /// get the emulated chip variant
+    pub fn variant(&self) -> CpuVariant {
+        self.variant
+    }
+
+    /// set the value written by the undocumented OUT (C),0 instruction (ED 71)
+    ///
+    /// NMOS Z80s output 0 (the default), CMOS Z80s output 0xFF. Some
+    /// software uses this to detect the CPU type.
+    pub fn set_out_c0_value(&mut self, val: RegT) {
+        self.out_c0_value = val & 0xFF;
+    }
+
+    /// get the value written by OUT (C),0
+    pub fn out_c0_value(&self) -> RegT {
+        self.out_c0_value
+    }
+
+    /// set what happens on undefined ED instructions
+    pub fn set_invalid_op_policy(&mut self, policy: InvalidOpPolicy) {
+        self.invalid_op_policy = policy;
+    }
+
+    /// get what happens on undefined ED instructions
+    pub fn invalid_op_policy(&self) -> InvalidOpPolicy {
+        self.invalid_op_policy
+    }
+
+    /// set the legal range of SP values for push and pop (both inclusive)
+    ///
+    /// Pushes and pops (including CALL, RET, RST and interrupts) which
+    /// move SP outside the range or wrap it through 0x0000 set the
+    /// stack_fault flag, call Bus::stack_fault() and stop
+    /// CPU::step_until() with StopReason::StackFault. Use 0x10000 as
+    /// top for a stack at the end of memory which starts with SP=0x0000.
+    /// Other SP changes (like LD SP,HL) aren't checked, and JIT compiled
+    /// blocks are not used while a stack range is set.
+    pub fn set_stack_range(&mut self, bottom: RegT, top: RegT) {
+        self.stack_range = Some((bottom, top));
+    }
+
+    /// remove the stack range guard
+    pub fn clear_stack_range(&mut self) {
+        self.stack_range = None;
+    }
+
+    /// get the stack range
+    pub fn stack_range(&self) -> Option<(RegT, RegT)> {
+        self.stack_range
+    }
+
+    /// enable or disable the check for HALT with interrupts disabled
+    ///
+    /// Without an NMI source, a CPU which executes HALT with IFF1 cleared
+    /// never continues (on real hardware, a dead machine). With the check
+    /// enabled, entering such a HALT sets the dead_halt flag and calls
+    /// Bus::dead_halt() with the address of the HALT instruction. Only
+    /// enable it for systems without NMI.
+    pub fn set_halt_check(&mut self, enabled: bool) {
+        self.halt_check = enabled;
+    }
+
+    /// get whether the check for HALT with interrupts disabled is enabled
+    pub fn halt_check(&self) -> bool {
+        self.halt_check
+    }
+
+    /// set a watchdog which detects runaway loops
+    ///
+    /// After each step(), the watchdog checks whether the PC has stayed
+    /// within its range without I/O or memory writes. When it trips,
+    /// step() sets the runaway flag, calls Bus::runaway() with the PC
+    /// range, and CPU::step_until() stops with StopReason::Runaway.
+    pub fn set_watchdog(&mut self, watchdog: Watchdog) {
+        self.watchdog = Some(watchdog);
+        self.restart_watchdog();
+    }
+
+    /// remove the watchdog
+    pub fn clear_watchdog(&mut self) {
+        self.watchdog = None;
+    }
+
+    /// get the watchdog
+    pub fn watchdog(&self) -> Option<&Watchdog> {
+        self.watchdog.as_ref()
+    }
+
+    /// restart the watchdog at the current PC
+    fn restart_watchdog(&mut self) {
+        let (pc, cycles, gen) = (self.reg.pc(), self.cycles, self.mem_generation());
+        if let Some(ref mut watchdog) = self.watchdog {
+            watchdog.restart(pc, cycles, gen);
+        }
+    }
+
+    /// a counter which changes on each memory write
+    fn mem_generation(&self) -> u32 {
+        (0..64).fold(0u32, |gen, page| gen.wrapping_add(self.mem.page_generation(page << 10)))
+    }
+
+    /// check a push (delta -2) or pop (delta 2) from sp against the stack range
+    #[inline(always)]
+    fn check_stack(&mut self, sp: RegT, delta: RegT) {
+        if let Some((bottom, top)) = self.stack_range {
+            let at_end = top == 0x10000;
+            let sp = if at_end && sp == 0 && delta < 0 { 0x10000 } else { sp };
+            let new_sp = sp + delta;
+            let fault = if new_sp < 0 || (new_sp > 0xFFFF && !(at_end && new_sp == 0x10000)) {
+                StackFault::Wrap
+            } else if new_sp < bottom {
+                StackFault::Overflow
+            } else if new_sp > top {
+                StackFault::Underflow
+            } else {
+                return;
+            };
+            if self.stack_fault.is_none() {
+                self.stack_fault = Some(fault);
+            }
+        }
+    }
+
     /// reset the cpu
     pub fn reset(&mut self) {
         self.reg.reset();
@@ -173,61 +463,603 @@ impl CPU {
         self.iff1 = false;
         self.iff2 = false;
         self.invalid_op = false;
+        self.stack_fault = None;
+        self.dead_halt = false;
+        self.runaway = false;
         self.irq_received = false;
         self.enable_interrupt = false;
+        self.im0_active = false;
+        self.restart_watchdog();
+    }
+
+    /// the interrupt mode
+    pub fn interrupt_mode(&self) -> Im {
+        self.reg.im()
+    }
+
+    /// set the interrupt mode (like the IM instruction)
+    pub fn set_interrupt_mode(&mut self, im: Im) {
+        self.reg.set_im(im);
+    }
+
+    /// the interrupt enable flip-flop IFF1 (interrupts are accepted if set)
+    pub fn iff1(&self) -> bool {
+        self.iff1
+    }
+
+    /// the interrupt enable flip-flop IFF2 (the saved IFF1 during an NMI)
+    pub fn iff2(&self) -> bool {
+        self.iff2
+    }
+
+    /// set both interrupt enable flip-flops (like EI or DI, but without delay)
+    pub fn set_iff(&mut self, iff1: bool, iff2: bool) {
+        self.iff1 = iff1;
+        self.iff2 = iff2;
+    }
+
+    /// true if the CPU executes a HALT instruction
+    pub fn is_halted(&self) -> bool {
+        self.halt
+    }
+
+    /// leave the HALT state and continue after the HALT instruction
+    ///
+    /// This does the same as an interrupt, setting the PC of a halted CPU
+    /// instead would execute the HALT instruction again.
+    pub fn leave_halt(&mut self) {
+        if self.halt {
+            self.halt = false;
+            self.reg.inc_pc(1);
+        }
+    }
+
+    /// reset the CPU and call Bus::reset() to reset the rest of the system
+    ///
+    /// A cold reset also sets AF and SP to 0xFFFF (as most Z80s do after
+    /// power-on) and clears the cycle counter. The CPU must not be
+    /// accessed from Bus::reset().
+    pub fn system_reset(&mut self, bus: &dyn Bus, kind: ResetKind) {
+        self.reset();
+        if kind == ResetKind::Cold {
+            self.reg.set_af(0xFFFF);
+            self.reg.set_sp(0xFFFF);
+            self.cycles = 0;
+            self.restart_watchdog();
+        }
+        bus.reset(kind);
+    }
+
+    /// read the next instruction byte from memory and advance PC,
+    /// or from the data bus during an IM0 interrupt acknowledge
+    #[inline(always)]
+    fn next8(&mut self) -> RegT {
+        if self.im0_active {
+            let b = self.im0_data[self.im0_pos & 3];
+            self.im0_pos += 1;
+            b
+        } else {
+            let pc = self.reg.pc();
+            let b = self.mem.peek8(pc);
+            self.reg.inc_pc(1);
+            b
+        }
     }
 
     /// fetch the next instruction byte from memory
     #[inline(always)]
     fn fetch_op(&mut self) -> RegT {
         self.reg.r = (self.reg.r & 0x80) | ((self.reg.r + 1) & 0x7F);
+        self.next8()
+    }
+
+    /// fetch the next instruction byte in an M1 cycle, and notify the bus
+    #[inline(always)]
+    fn fetch_m1(&mut self, bus: &dyn Bus) -> RegT {
         let pc = self.reg.pc();
-        let op = self.mem.r8(pc);
-        self.reg.inc_pc(1);
+        let op = self.fetch_op();
+        if !self.im0_active {
+            self.mem.check_exec(pc);
+            bus.m1(pc, op);
+        }
         op
     }
 
+    /// fetch and decode the instruction at PC and advance PC, or
+    /// from the data bus during an IM0 interrupt acknowledge
+    ///
+    /// The opcode and prefix bytes are fetched in M1 cycles (which
+    /// increment R and notify the bus), except the last byte of the
+    /// DD CB d op and FD CB d op instructions, which only increments R.
+    #[inline(always)]
+    fn fetch_inst(&mut self, bus: &dyn Bus) -> Instruction {
+        let pc = self.reg.pc();
+        let bytes = if self.im0_active {
+            let mut bytes = [0u8; 4];
+            for (i, b) in bytes.iter_mut().enumerate() {
+                *b = self.im0_data[(self.im0_pos + i) & 3] as u8;
+            }
+            bytes
+        } else {
+            self.mem.peek_inst(pc)
+        };
+        let (inst, len) = decode(&bytes);
+        if let Some(ref mut stats) = self.opcode_stats {
+            stats.record(&bytes, len);
+        }
+        let (num_m1, num_r) = match (bytes[0], bytes[1]) {
+            (0xDD, 0xCB) | (0xFD, 0xCB) => (2, 3),
+            (0xCB, _) | (0xED, _) => (2, 2),
+            (0xDD, _) | (0xFD, _) if len > 1 => (2, 2),
+            _ => (1, 1),
+        };
+        self.reg.r = (self.reg.r & 0x80) | ((self.reg.r + num_r) & 0x7F);
+        if self.im0_active {
+            self.im0_pos += len as usize;
+        } else {
+            for (i, &op) in bytes.iter().enumerate().take(num_m1) {
+                let addr = (pc + i as RegT) & 0xFFFF;
+                self.mem.check_exec(addr);
+                bus.m1(addr, op as RegT);
+            }
+            self.reg.inc_pc(len as u16);
+        }
+        inst
+    }
+
     /// decode and execute one instruction, return number of cycles taken
+    ///
+    /// Repeating block instructions (LDIR, LDDR, CPIR, CPDR, INIR, INDR,
+    /// OTIR, OTDR) execute one iteration per step() and rewind PC to the
+    /// instruction while repeating, so that a pending interrupt is accepted
+    /// between iterations like on real hardware: the address of the block
+    /// instruction is pushed as return address, and the instruction
+    /// continues after the interrupt handler returns.
     pub fn step(&mut self, bus: &dyn Bus) -> i64 {
+        self.step_inner(bus, false)
+    }
+
+    /// like step(), but run a whole block from the block cache or JIT if enabled
+    ///
+    /// With the block cache or the JIT enabled (see set_block_cache() and
+    /// set_jit()), this runs a block of up to 64 simple instructions at
+    /// once and returns the cycles of all of them, otherwise it is the
+    /// same as step(). Since interrupts are only accepted between blocks,
+    /// and the run loop's per-step work (e.g. ticking a CTC or updating a
+    /// Scheduler) only happens once per block, an interrupt may be accepted
+    /// up to a block later than with step(). While an interrupt is pending,
+    /// only a single instruction is executed so that it is accepted right
+    /// away. step_until() always uses step().
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rz80::{CPU, NullBus};
+    ///
+    /// let mut cpu = CPU::new_64k();
+    /// // INC A; INC A; INC A; JP 0000h
+    /// cpu.mem.write(0x0000, &[0x3C, 0x3C, 0x3C, 0xC3, 0x00, 0x00]);
+    /// cpu.set_block_cache(true);
+    /// assert_eq!(cpu.run_block(&NullBus), 3 * 4 + 10);
+    /// assert_eq!(cpu.reg.pc(), 0x0000);
+    ///
+    /// // step() always executes a single instruction
+    /// assert_eq!(cpu.step(&NullBus), 4);
+    /// assert_eq!(cpu.reg.pc(), 0x0001);
+    /// ```
+    pub fn run_block(&mut self, bus: &dyn Bus) -> i64 {
+        self.step_inner(bus, true)
+    }
+
+    /// private method for step() and run_block()
+    #[inline(always)]
+    fn step_inner(&mut self, bus: &dyn Bus, blocks: bool) -> i64 {
         self.invalid_op = false;
+        self.stack_fault = None;
+        self.dead_halt = false;
+        self.runaway = false;
+        let pc = self.reg.pc();
         if self.enable_interrupt {
             self.iff1 = true;
             self.iff2 = true;
             self.enable_interrupt = false
         }
-        let mut cyc = self.do_op(bus, false);
+        self.ld_a_ir = false;
+        let mut cyc = if self.mcycle_trace.is_some() {
+            self.exec_traced(bus)
+        } else if self.opcode_stats.is_some() {
+            self.exec_decoded(bus)
+        } else {
+            self.exec(bus, blocks && !self.irq_received)
+        };
         if self.irq_received {
-            cyc += self.handle_irq(bus);
+            if self.ld_a_ir && self.iff1 && self.variant == CpuVariant::NMOS {
+                // NMOS bug: IFF2 is already cleared when P/V is set
+                let f = self.reg.f() & !PF;
+                self.reg.set_f(f);
+            }
+            let (irq_pc, irq_sp) = (self.reg.pc(), self.reg.sp());
+            let irq_cyc = self.handle_irq(bus);
+            if let Some(mut trace) = self.mcycle_trace.take() {
+                mcycles::interrupt(self, &mut trace, irq_pc, irq_sp, irq_cyc);
+                self.mcycle_trace = Some(trace);
+            }
+            cyc += irq_cyc;
             self.irq_received = false;
         }
+        cyc += self.take_io_wait();
+        if let Some(fault) = self.stack_fault {
+            bus.stack_fault(pc, self.reg.sp(), fault);
+        }
+        if self.dead_halt {
+            bus.dead_halt(self.reg.pc());
+        }
+        self.cycles += cyc as u64;
+        if self.watchdog.is_some() {
+            self.update_watchdog(bus);
+        }
         cyc
     }
 
+    /// check the watchdog after a step
+    fn update_watchdog(&mut self, bus: &dyn Bus) {
+        let (pc, cycles, gen) = (self.reg.pc(), self.cycles, self.mem_generation());
+        let io = self.io_access;
+        self.io_access = false;
+        if let Some(ref mut watchdog) = self.watchdog {
+            if watchdog.update(pc, cycles, gen, io) {
+                self.runaway = true;
+                let (lo, hi) = watchdog.range();
+                bus.runaway(lo, hi);
+            }
+        }
+    }
+
+    /// like step(), but with a mutable IoBus instead of a Bus
+    pub fn step_io(&mut self, io: &mut dyn IoBus) -> i64 {
+        let bus = IoBusAdapter::new(io);
+        self.step(&bus)
+    }
+
+    /// like run_block(), but with a mutable IoBus instead of a Bus
+    pub fn run_block_io(&mut self, io: &mut dyn IoBus) -> i64 {
+        let bus = IoBusAdapter::new(io);
+        self.run_block(&bus)
+    }
+
+    /// like step_until(), but with a mutable IoBus instead of a Bus
+    pub fn step_until_io<F>(&mut self, io: &mut dyn IoBus, max_cycles: i64, pred: F) -> (i64, StopReason)
+        where F: FnMut(&CPU) -> bool
+    {
+        let bus = IoBusAdapter::new(io);
+        self.step_until(&bus, max_cycles, pred)
+    }
+
+    /// execute the next instruction (or cached or compiled block)
+    #[inline(always)]
+    fn exec(&mut self, bus: &dyn Bus, blocks: bool) -> i64 {
+        #[cfg(feature = "jit")]
+        {
+            // JIT compiled code doesn't check the stack range
+            if blocks && self.stack_range.is_none() {
+                if let Some(mut jit) = self.jit.take() {
+                    let res = jit.run(self);
+                    self.jit = Some(jit);
+                    if let Some(cycles) = res {
+                        return cycles;
+                    }
+                }
+            }
+        }
+        if blocks {
+            if let Some(mut cache) = self.block_cache.take() {
+                let res = cache.run(self, bus);
+                self.block_cache = Some(cache);
+                if let Some(cycles) = res {
+                    return cycles;
+                }
+            }
+        }
+        self.do_op(bus, false)
+    }
+
+    /// execute the next instruction in the interpreter and record its machine cycles
+    fn exec_traced(&mut self, bus: &dyn Bus) -> i64 {
+        let mut cycles = 0;
+        if let Some(ref mut trace) = self.mcycle_trace {
+            trace.cycles.clear();
+        }
+        loop {
+            let plan = mcycles::plan(self);
+            let inst = self.fetch_inst(bus);
+            let inst_cycles = match inst {
+                Instruction::Prefix(_) => 4,
+                inst => self.execute(bus, inst),
+            };
+            let io_wait = self.take_io_wait();
+            let inst_cycles = inst_cycles + io_wait;
+            if let Some(mut trace) = self.mcycle_trace.take() {
+                mcycles::complete(self, &mut trace, plan, inst_cycles, io_wait);
+                self.mcycle_trace = Some(trace);
+            }
+            cycles += inst_cycles;
+            if let Instruction::Prefix(_) = inst {
+                continue;
+            }
+            return cycles;
+        }
+    }
+
+    /// enable or disable the machine cycle trace
+    ///
+    /// With the trace enabled, step() records the machine cycles of the
+    /// executed instruction (and of an accepted interrupt) with their
+    /// address, data and length in T states, available from mcycles()
+    /// until the next step(). The recorded timing is the emulator's
+    /// (which follows the Zilog documentation), so that it can be
+    /// compared with logic analyzer captures of real hardware. The data
+    /// of write cycles is read back from memory after the instruction,
+    /// writes to ROM show the ROM content. While tracing, step() always
+    /// runs the interpreter, not the block cache or JIT.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rz80::{CPU, NullBus, MCycleKind};
+    ///
+    /// let mut cpu = CPU::new_64k();
+    /// // LD (HL),n
+    /// cpu.mem.write(0x0000, &[0x36, 0x42]);
+    /// cpu.reg.set_hl(0x4000);
+    /// cpu.set_mcycle_trace(true);
+    /// assert_eq!(cpu.step(&NullBus), 10);
+    /// let trace: Vec<String> = cpu.mcycles().iter().map(|c| c.to_string()).collect();
+    /// assert_eq!(trace, ["M1   0000 36 4T", "MR   0001 42 3T", "MW   4000 42 3T"]);
+    /// assert_eq!(cpu.mcycles()[2].kind, MCycleKind::Write);
+    /// ```
+    pub fn set_mcycle_trace(&mut self, enabled: bool) {
+        if enabled {
+            if self.mcycle_trace.is_none() {
+                self.mcycle_trace = Some(MCycleTrace::new());
+            }
+        } else {
+            self.mcycle_trace = None;
+        }
+    }
+
+    /// the machine cycles of the last step(), empty if the trace is disabled
+    pub fn mcycles(&self) -> &[MCycle] {
+        self.mcycle_trace.as_ref().map_or(&[], |trace| &trace.cycles)
+    }
+
+    /// enable or disable the opcode statistics
+    ///
+    /// With the statistics enabled, step() counts each executed
+    /// instruction by opcode (in the main and prefixed opcode tables),
+    /// available from opcode_stats() as counts or a report. Like the
+    /// machine cycle trace, this runs step() in the interpreter, not
+    /// the block cache or JIT. Enabling keeps existing counts.
+    pub fn set_opcode_stats(&mut self, enabled: bool) {
+        if enabled {
+            if self.opcode_stats.is_none() {
+                self.opcode_stats = Some(Box::new(OpcodeStats::new()));
+            }
+        } else {
+            self.opcode_stats = None;
+        }
+    }
+
+    /// the opcode statistics, None if disabled
+    pub fn opcode_stats(&self) -> Option<&OpcodeStats> {
+        self.opcode_stats.as_deref()
+    }
+
+    /// the opcode statistics for clearing the counts, None if disabled
+    pub fn opcode_stats_mut(&mut self) -> Option<&mut OpcodeStats> {
+        self.opcode_stats.as_deref_mut()
+    }
+
+    /// like step(), but return the state before and after the step
+    ///
+    /// The bus accesses are taken from the machine cycle trace, which is
+    /// enabled for the duration of the call if it isn't already (so the
+    /// step always runs in the interpreter, see set_mcycle_trace()).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rz80::{CPU, NullBus, MCycleKind};
+    ///
+    /// let mut cpu = CPU::new_64k();
+    /// // LD (HL),A
+    /// cpu.mem.write(0x0000, &[0x77]);
+    /// cpu.reg.set_hl(0x4000);
+    /// cpu.reg.set_a(0x42);
+    /// let rec = cpu.step_captured(&NullBus);
+    /// assert_eq!(rec.cycles, 7);
+    /// assert_eq!(rec.before.pc(), 0x0000);
+    /// assert_eq!(rec.after.pc(), 0x0001);
+    /// assert_eq!(rec.accesses.len(), 2);
+    /// assert_eq!(rec.accesses[1].kind, MCycleKind::Write);
+    /// assert_eq!((rec.accesses[1].addr, rec.accesses[1].data), (0x4000, 0x42));
+    /// ```
+    pub fn step_captured(&mut self, bus: &dyn Bus) -> StepRecord {
+        let tracing = self.mcycle_trace.is_some();
+        if !tracing {
+            self.set_mcycle_trace(true);
+        }
+        let before = self.reg;
+        let cycles = self.step(bus);
+        let accesses = self.mcycles().iter()
+            .filter(|c| c.kind != MCycleKind::Internal)
+            .cloned()
+            .collect();
+        if !tracing {
+            self.set_mcycle_trace(false);
+        }
+        StepRecord {
+            before,
+            after: self.reg,
+            accesses,
+            cycles,
+        }
+    }
+
+    /// enable or disable the pre-decoded block cache
+    ///
+    /// With the block cache enabled, run_block() decodes blocks of simple
+    /// instructions once into a cache (keyed by the start address and
+    /// invalidated when the block's memory pages are written or remapped),
+    /// and runs a whole block at once, so run_block() may return the cycles
+    /// of many instructions. step() isn't affected and always executes a
+    /// single instruction. Bus::m1() is still called for each instruction,
+    /// blocks never contain I/O, interrupt-related or prefixed instructions,
+    /// these are handled by the interpreter.
+    pub fn set_block_cache(&mut self, enabled: bool) {
+        if enabled {
+            if self.block_cache.is_none() {
+                self.block_cache = Some(Box::new(BlockCache::new()));
+            }
+        } else {
+            self.block_cache = None;
+        }
+    }
+
+    /// enable or disable the JIT compiler (requires the 'jit' feature)
+    ///
+    /// With the JIT enabled, run_block() compiles frequently executed blocks
+    /// of simple instructions into native code and runs a whole block
+    /// at once (so run_block() may return the cycles of many instructions),
+    /// all other instructions are handled by the interpreter. Compiled blocks
+    /// are thrown away when their memory is written or the memory mapping
+    /// changes. step() isn't affected and always executes a single
+    /// instruction in the interpreter.
+    ///
+    /// Unlike the block cache, compiled blocks don't call Bus::m1() and
+    /// don't check the execute permission, so m1-based tracing and
+    /// breakpoints only see the instructions run by the interpreter.
+    /// Like with the block cache, a pending interrupt is accepted after the
+    /// block has finished, and run_block() falls back to a single
+    /// instruction while an interrupt is pending. Compiled blocks never
+    /// contain I/O instructions.
+    #[cfg(feature = "jit")]
+    pub fn set_jit(&mut self, enabled: bool) {
+        if enabled {
+            if self.jit.is_none() {
+                self.jit = Some(Box::new(Jit::new()));
+            }
+        } else {
+            self.jit = None;
+        }
+    }
+
+    /// number of blocks currently compiled by the JIT
+    #[cfg(feature = "jit")]
+    pub fn jit_blocks(&self) -> usize {
+        self.jit.as_ref().map_or(0, |jit| jit.num_blocks())
+    }
+
+    /// fast-forward a halted CPU by up to max_cycles, return cycles skipped
+    ///
+    /// While the CPU is in HALT state waiting for an interrupt it would
+    /// just execute the HALT instruction over and over. Instead of calling
+    /// step() in a loop, the run loop can skip ahead to the next event
+    /// which might trigger an interrupt (e.g. a CTC timer reaching zero,
+    /// or the end of the frame). The skipped time is rounded down to
+    /// full HALT instructions (4 cycles each). Returns 0 if the CPU
+    /// isn't halted, has an interrupt pending, or max_cycles is less
+    /// than 4, in this case the caller should call step() as usual.
+    ///
+    /// NOTE: the Bus::m1() callback isn't called for skipped HALT instructions.
+    pub fn skip_halt(&mut self, max_cycles: i64) -> i64 {
+        if !self.halt || self.irq_received || self.enable_interrupt {
+            return 0;
+        }
+        let num_halts = max_cycles / 4;
+        if num_halts > 0 {
+            // each HALT is an M1 cycle which bumps the R register
+            self.reg.r = (self.reg.r & 0x80) | ((self.reg.r + (num_halts & 0x7F) as RegT) & 0x7F);
+        }
+        self.cycles += (num_halts * 4) as u64;
+        num_halts * 4
+    }
+
+    /// step until predicate returns true, the CPU halts or max_cycles is reached
+    ///
+    /// The predicate is checked before each instruction, returns the
+    /// number of cycles executed and the reason why stepping has stopped.
+    /// With InvalidOpPolicy::Trap, stepping also stops on undefined instructions.
+    ///
+    /// ```
+    /// use rz80::{CPU, Bus, StopReason};
+    ///
+    /// struct DummyBus;
+    /// impl Bus for DummyBus { };
+    ///
+    /// let mut cpu = CPU::new_64k();
+    /// let prog = [
+    ///     0x3E, 0x11,     // LD A,0x11 (7 cycles)
+    ///     0x06, 0x22,     // LD B,0x22 (7 cycles)
+    ///     0x80,           // ADD A,B   (4 cycles)
+    ///     0x76,           // HALT      (4 cycles)
+    /// ];
+    /// cpu.mem.write(0x0100, &prog);
+    /// cpu.reg.set_pc(0x0100);
+    ///
+    /// // run until PC reaches the ADD instruction
+    /// let (cycles, reason) = cpu.step_until(&DummyBus, 1000, |cpu| cpu.reg.pc() == 0x0104);
+    /// assert_eq!((cycles, reason), (14, StopReason::Predicate));
+    ///
+    /// // run until the CPU halts
+    /// let (cycles, reason) = cpu.step_until(&DummyBus, 1000, |_| false);
+    /// assert_eq!((cycles, reason), (8, StopReason::Halt));
+    /// assert_eq!(cpu.reg.a(), 0x33);
+    /// ```
+    pub fn step_until<F>(&mut self, bus: &dyn Bus, max_cycles: i64, mut pred: F) -> (i64, StopReason)
+        where F: FnMut(&CPU) -> bool
+    {
+        let mut cycles = 0;
+        loop {
+            if pred(self) {
+                return (cycles, StopReason::Predicate);
+            }
+            if self.halt {
+                return (cycles, StopReason::Halt);
+            }
+            if cycles >= max_cycles {
+                return (cycles, StopReason::Cycles);
+            }
+            cycles += self.step(bus);
+            if self.invalid_op && self.invalid_op_policy == InvalidOpPolicy::Trap {
+                return (cycles, StopReason::InvalidOp);
+            }
+            if self.stack_fault.is_some() {
+                return (cycles, StopReason::StackFault);
+            }
+            if self.runaway {
+                return (cycles, StopReason::Runaway);
+            }
+        }
+    }
+
     /// load 8-bit unsigned immediate operand and increment PC
     #[inline(always)]
     fn imm8(&mut self) -> RegT {
-        let pc = self.reg.pc();
-        let imm = self.mem.r8(pc);
-        self.reg.inc_pc(1);
-        imm
+        self.next8()
     }
 
     /// load 16-bit immediate operand and bump PC
     #[inline(always)]
     fn imm16(&mut self) -> RegT {
-        let pc = self.reg.pc();
-        let imm = self.mem.r16(pc);
-        self.reg.inc_pc(2);
-        imm
+        let l = self.next8();
+        let h = self.next8();
+        h << 8 | l
     }
 
     /// load d (as in IX+d) from memory and advance PC
     #[inline(always)]
     fn d(&mut self) -> RegT {
-        let pc = self.reg.pc();
-        let d = self.mem.rs8(pc);
-        self.reg.inc_pc(1);
-        d
+        self.next8() as i8 as RegT
     }
 
     /// load effective address HL, IX+d or IY+d with existing d
@@ -258,7 +1090,7 @@ impl CPU {
 
     /// check condition (for conditional jumps etc)
     #[inline(always)]
-    fn cc(&self, y: usize) -> bool {
+    pub(crate) fn cc(&self, y: usize) -> bool {
         let f = self.reg.f();
         match y {
             0 => 0 == f & ZF, // JR NZ
@@ -273,6 +1105,24 @@ impl CPU {
         }
     }
 
+    /// fetch, decode and execute the next instruction through decode() and
+    /// execute(), return number of cycles taken
+    ///
+    /// This is used for the opcode statistics and IM0 interrupts, step()
+    /// otherwise runs the direct-dispatch interpreter in do_op(). A DD or FD
+    /// prefix which doesn't affect the following instruction is executed
+    /// together with that instruction (interrupts are not accepted after a
+    /// prefix).
+    fn exec_decoded(&mut self, bus: &dyn Bus) -> i64 {
+        let mut cycles = 0;
+        loop {
+            match self.fetch_inst(bus) {
+                Instruction::Prefix(_) => cycles += 4,
+                inst => return cycles + self.execute(bus, inst),
+            }
+        }
+    }
+
     /// execute a single 'main-instruction'
     ///
     /// This function may be called recursively for prefixed
@@ -288,7 +1138,7 @@ impl CPU {
         } else {
             (0, 0)
         };
-        let op = self.fetch_op();
+        let op = self.fetch_m1(bus);
 
         // split instruction byte into bit groups
         let x = op >> 6;
@@ -591,12 +1441,14 @@ impl CPU {
                         self.reg.set_pc(nn);
                         10
                     }
-                    1 => self.do_cb_op(ext),
+                    1 => self.do_cb_op(bus, ext),
                     2 => {
                         // OUT (n),A
                         let a = self.reg.a();
-                        let port = (a << 8 | self.imm8()) & 0xFFFF;
+                        let n = self.imm8();
+                        let port = (a << 8 | n) & 0xFFFF;
                         self.outp(bus, port, a);
+                        self.reg.set_wz(a << 8 | ((n + 1) & 0xFF));
                         11
                     }
                     3 => {
@@ -604,6 +1456,7 @@ impl CPU {
                         let port = (self.reg.a() << 8 | self.imm8()) & 0xFFFF;
                         let v = self.inp(bus, port);
                         self.reg.set_a(v);
+                        self.reg.set_wz(port + 1);
                         11
                     }
                     4 => {
@@ -685,14 +1538,17 @@ impl CPU {
                 self.rst((y * 8) as RegT);
                 11
             }
-            // not implemented
-            _ => panic!("Invalid instruction!")
+            // all opcodes are handled above
+            _ => {
+                self.invalid_op = true;
+                4
+            }
         }
     }
 
     /// fetch and execute ED prefix instruction
     fn do_ed_op(&mut self, bus: &dyn Bus) -> i64 {
-        let op = self.fetch_op();
+        let op = self.fetch_m1(bus);
 
         // split instruction byte into bit groups
         let x = op >> 6;
@@ -760,9 +1616,10 @@ impl CPU {
                 12
             }
             (1, 6, 1) => {
-                // OUT (C),F (undocumented special case, always output 0)
+                // OUT (C),0 (undocumented special case, 0 on NMOS, 0xFF on CMOS chips)
                 let bc = self.reg.bc();
-                self.outp(bus, bc, 0);
+                let v = self.out_c0_value;
+                self.outp(bus, bc, v);
                 12
             }
             (1, _, 1) => {
@@ -808,20 +1665,20 @@ impl CPU {
                 8
             }
             (1, 1, 5) => {
-                // RETI (RETN is not implemented)
+                // RETI
                 self.reti(bus)
             }
+            (1, _, 5) => {
+                // RETN (and undocumented mirrors)
+                self.ret();
+                self.iff1 = self.iff2;
+                14
+            }
             (1, _, 6) => {
                 match y {
-                    0 | 1 | 4 | 5 => {
-                        self.reg.im = 0;
-                    }
-                    2 | 6 => {
-                        self.reg.im = 1;
-                    }
-                    3 | 7 => {
-                        self.reg.im = 2;
-                    }
+                    0 | 1 | 4 | 5 => self.reg.set_im(Im::Zero),
+                    2 | 6 => self.reg.set_im(Im::One),
+                    3 | 7 => self.reg.set_im(Im::Two),
                     _ => unreachable!()
                 }
                 8
@@ -840,6 +1697,7 @@ impl CPU {
                 self.reg.set_a(i);
                 let f = flags_sziff2(i, self.iff2) | (self.reg.f() & CF);
                 self.reg.set_f(f);
+                self.ld_a_ir = true;
                 9
             }
             (1, 3, 7) => {
@@ -848,6 +1706,7 @@ impl CPU {
                 self.reg.set_a(r);
                 let f = flags_sziff2(r, self.iff2) | (self.reg.f() & CF);
                 self.reg.set_f(f);
+                self.ld_a_ir = true;
                 9
             }
             (1, 4, 7) => {
@@ -858,19 +1717,34 @@ impl CPU {
                 self.rld();
                 18
             }    // RLD
-            (1, _, 7) => 9,     // NOP (ED)
-            _ => panic!("CB: Invalid instruction!"),
+            (1, _, 7) => 8,     // NOP (ED)
+            // undefined ED instructions are NOPs
+            _ => {
+                self.invalid_op = true;
+                if self.invalid_op_policy != InvalidOpPolicy::Nop {
+                    bus.invalid_op((self.reg.pc() - 2) & 0xFFFF, op);
+                }
+                if self.invalid_op_policy == InvalidOpPolicy::Trap {
+                    self.reg.dec_pc(2);
+                }
+                8
+            }
         }
     }
 
     /// fetch and execute CB prefix instruction
-    fn do_cb_op(&mut self, ext: bool) -> i64 {
+    fn do_cb_op(&mut self, bus: &dyn Bus, ext: bool) -> i64 {
         let d = if ext {
             self.d()
         } else {
             0
         };
-        let op = self.fetch_op();
+        // in DD CB d op and FD CB d op, the op byte isn't read in an M1 cycle
+        let op = if ext {
+            self.fetch_op()
+        } else {
+            self.fetch_m1(bus)
+        };
         let cyc = if ext {
             4
         } else {
@@ -911,8 +1785,9 @@ impl CPU {
             }
             1 => {
                 // BIT n
-                if z == 6 {
+                if z == 6 || ext {
                     // BIT n,(HL); BIT n,(IX+d); BIT n,(IY+d)
+                    // (undocumented: all DD/FD CB BIT ops test (IX+d)/(IY+d))
                     let a = self.addr_d(d, ext);
                     let v = self.mem.r8(a);
                     self.ibit(v, 1 << y);
@@ -947,30 +1822,483 @@ impl CPU {
                     8
                 }
             }
-            3 => {
-                // SET n
-                if z == 6 {
-                    // SET n,(HL); SET n,(IX+d); SET n,(IY+d)
-                    let a = self.addr_d(d, ext);
-                    let v = self.mem.r8(a) | 1 << y;
-                    self.mem.w8(a, v);
-                    15
-                } else if ext {
-                    // SET n,(IX+d),r; SET n,(IY+d),r
-                    // (also stores result in a register)
-                    let a = self.addr_d(d, ext);
-                    let v = self.mem.r8(a) | 1 << y;
-                    self.reg.set_r8i(z, v);
-                    self.mem.w8(a, v);
-                    15
-                } else {
-                    // SET n,r
-                    let v = self.reg.r8i(z) | 1 << y;
-                    self.reg.set_r8i(z, v);
-                    8
+            3 => {
+                // SET n
+                if z == 6 {
+                    // SET n,(HL); SET n,(IX+d); SET n,(IY+d)
+                    let a = self.addr_d(d, ext);
+                    let v = self.mem.r8(a) | 1 << y;
+                    self.mem.w8(a, v);
+                    15
+                } else if ext {
+                    // SET n,(IX+d),r; SET n,(IY+d),r
+                    // (also stores result in a register)
+                    let a = self.addr_d(d, ext);
+                    let v = self.mem.r8(a) | 1 << y;
+                    self.reg.set_r8i(z, v);
+                    self.mem.w8(a, v);
+                    15
+                } else {
+                    // SET n,r
+                    let v = self.reg.r8i(z) | 1 << y;
+                    self.reg.set_r8i(z, v);
+                    8
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+
+    /// execute a decoded instruction, return number of cycles taken
+    ///
+    /// PC must already point to the next instruction, like after
+    /// fetching the instruction bytes (it is the base of relative
+    /// jumps and the return address of calls). This only executes
+    /// the instruction, step() also fetches the instruction, calls
+    /// Bus::m1(), updates the R register and handles interrupts.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rz80::{CPU, NullBus, decode};
+    ///
+    /// let mut cpu = CPU::new_64k();
+    /// // LD A,11h
+    /// let (inst, len) = decode(&[0x3E, 0x11]);
+    /// cpu.reg.set_pc(len);
+    /// assert_eq!(cpu.execute(&NullBus, inst), 7);
+    /// assert_eq!(cpu.reg.a(), 0x11);
+    /// ```
+    pub fn execute(&mut self, bus: &dyn Bus, inst: Instruction) -> i64 {
+        match inst {
+            Instruction::Nop | Instruction::Prefix(_) => 4,
+            Instruction::Halt => {
+                self.halt();
+                4
+            }
+            // LD I,A; LD R,A
+            Instruction::Ld(Operand::Reg(Reg8::I), _) => {
+                self.reg.i = self.reg.a();
+                9
+            }
+            Instruction::Ld(Operand::Reg(Reg8::R), _) => {
+                self.reg.r = self.reg.a();
+                9
+            }
+            // LD A,I; LD A,R
+            Instruction::Ld(_, Operand::Reg(r @ Reg8::I)) |
+            Instruction::Ld(_, Operand::Reg(r @ Reg8::R)) => {
+                let v = self.reg.get8(r);
+                self.reg.set_a(v);
+                let f = flags_sziff2(v, self.iff2) | (self.reg.f() & CF);
+                self.reg.set_f(f);
+                self.ld_a_ir = true;
+                9
+            }
+            Instruction::Ld(dst, src) => {
+                let v = self.load(src);
+                self.store(dst, v);
+                let cycles = match (dst, src) {
+                    (Operand::Abs(_), _) | (_, Operand::Abs(_)) => 13,
+                    (Operand::Idx(..), _) | (_, Operand::Idx(..)) => 15,
+                    (Operand::Ind(_), Operand::Imm(_)) => 10,
+                    (Operand::Ind(_), _) | (_, Operand::Ind(_)) | (_, Operand::Imm(_)) => 7,
+                    _ => 4,
+                };
+                cycles + prefix_cycles(dst.is_indexed() || src.is_indexed())
+            }
+            Instruction::Ld16(r, nn) => {
+                self.reg.set(r, nn);
+                10 + prefix_cycles(is_index_reg16(r))
+            }
+            Instruction::LdToMem16(nn, r) | Instruction::EdLdToMem16(nn, r) => {
+                let v = self.reg.get(r);
+                self.mem.w16(nn, v);
+                self.reg.set_wz(nn + 1);
+                if let Instruction::LdToMem16(..) = inst { 16 + prefix_cycles(is_index_reg16(r)) } else { 20 }
+            }
+            Instruction::LdFromMem16(r, nn) | Instruction::EdLdFromMem16(r, nn) => {
+                let v = self.mem.r16(nn);
+                self.reg.set(r, v);
+                self.reg.set_wz(nn + 1);
+                if let Instruction::LdFromMem16(..) = inst { 16 + prefix_cycles(is_index_reg16(r)) } else { 20 }
+            }
+            Instruction::LdSP(r) => {
+                let v = self.reg.get(r);
+                self.reg.set_sp(v);
+                6 + prefix_cycles(is_index_reg16(r))
+            }
+            Instruction::Push(r) => {
+                let v = self.reg.get(r);
+                self.push(v);
+                11 + prefix_cycles(is_index_reg16(r))
+            }
+            Instruction::Pop(r) => {
+                let v = self.pop();
+                self.reg.set(r, v);
+                10 + prefix_cycles(is_index_reg16(r))
+            }
+            Instruction::ExAF => {
+                self.reg.swap(AF, AF_);
+                4
+            }
+            Instruction::Exx => {
+                self.reg.swap(BC, BC_);
+                self.reg.swap(DE, DE_);
+                self.reg.swap(HL, HL_);
+                self.reg.swap(WZ, WZ_);
+                4
+            }
+            Instruction::ExDEHL => {
+                self.reg.swap(DE, HL);
+                4
+            }
+            Instruction::ExSP(r) => {
+                let sp = self.reg.sp();
+                let v_reg = self.reg.get(r);
+                let v_mem = self.mem.r16(sp);
+                self.mem.w16(sp, v_reg);
+                self.reg.set_wz(v_mem);
+                self.reg.set(r, v_mem);
+                19 + prefix_cycles(is_index_reg16(r))
+            }
+            Instruction::Alu(op, src) => {
+                let v = self.load(src);
+                self.alu8(op as usize, v);
+                let cycles = match src {
+                    Operand::Reg(_) => 4,
+                    Operand::Idx(..) => 15,
+                    _ => 7,
+                };
+                cycles + prefix_cycles(src.is_indexed())
+            }
+            Instruction::Inc(Operand::Reg(r)) | Instruction::Dec(Operand::Reg(r)) => {
+                let v = self.reg.get8(r);
+                let w = if let Instruction::Inc(_) = inst { self.inc8(v) } else { self.dec8(v) };
+                self.reg.set8(r, w);
+                4 + prefix_cycles(is_index_reg8(r))
+            }
+            Instruction::Inc(o) | Instruction::Dec(o) => {
+                let addr = self.operand_addr(o);
+                let v = self.mem.r8(addr);
+                let w = if let Instruction::Inc(_) = inst { self.inc8(v) } else { self.dec8(v) };
+                self.mem.w8(addr, w);
+                if let Operand::Idx(..) = o { 23 } else { 11 }
+            }
+            Instruction::Inc16(r) => {
+                let v = self.reg.get(r) + 1;
+                self.reg.set(r, v);
+                6 + prefix_cycles(is_index_reg16(r))
+            }
+            Instruction::Dec16(r) => {
+                let v = self.reg.get(r) - 1;
+                self.reg.set(r, v);
+                6 + prefix_cycles(is_index_reg16(r))
+            }
+            Instruction::Add16(r, s) => {
+                let acc = self.reg.get(r);
+                let v = self.reg.get(s);
+                let res = self.add16(acc, v);
+                self.reg.set(r, res);
+                11 + prefix_cycles(is_index_reg16(r))
+            }
+            Instruction::Adc16(r) | Instruction::Sbc16(r) => {
+                let acc = self.reg.hl();
+                let v = self.reg.get(r);
+                let res = if let Instruction::Adc16(_) = inst {
+                    self.adc16(acc, v)
+                } else {
+                    self.sbc16(acc, v)
+                };
+                self.reg.set_hl(res);
+                15
+            }
+            Instruction::Rlca => {
+                self.rlca8();
+                4
+            }
+            Instruction::Rrca => {
+                self.rrca8();
+                4
+            }
+            Instruction::Rla => {
+                self.rla8();
+                4
+            }
+            Instruction::Rra => {
+                self.rra8();
+                4
+            }
+            Instruction::Daa => {
+                self.daa();
+                4
+            }
+            Instruction::Cpl => {
+                self.cpl();
+                4
+            }
+            Instruction::Scf => {
+                self.scf();
+                4
+            }
+            Instruction::Ccf => {
+                self.ccf();
+                4
+            }
+            Instruction::Neg => {
+                self.neg8();
+                8
+            }
+            Instruction::Rot(_, Operand::Reg(r), _) |
+            Instruction::Res(_, Operand::Reg(r), _) |
+            Instruction::Set(_, Operand::Reg(r), _) => {
+                let v = self.reg.get8(r);
+                let w = self.bit_op(inst, v);
+                self.reg.set8(r, w);
+                8
+            }
+            // with the undocumented copy of the result in a register
+            Instruction::Rot(_, o, copy) |
+            Instruction::Res(_, o, copy) |
+            Instruction::Set(_, o, copy) => {
+                let addr = self.operand_addr(o);
+                let v = self.mem.r8(addr);
+                let w = self.bit_op(inst, v);
+                if let Some(r) = copy {
+                    self.reg.set8(r, w);
+                }
+                self.mem.w8(addr, w);
+                if let Operand::Idx(..) = o { 23 } else { 15 }
+            }
+            Instruction::Bit(b, Operand::Reg(r)) => {
+                let v = self.reg.get8(r);
+                self.bit(v, 1 << b);
+                8
+            }
+            // BIT b,(HL); BIT b,(IX+d); BIT b,(IY+d) take X and Y from WZ
+            Instruction::Bit(b, o) => {
+                let addr = self.operand_addr(o);
+                let v = self.mem.r8(addr);
+                self.ibit(v, 1 << b);
+                if let Operand::Idx(..) = o { 20 } else { 12 }
+            }
+            Instruction::Rld => {
+                self.rld();
+                18
+            }
+            Instruction::Rrd => {
+                self.rrd();
+                18
+            }
+            Instruction::Jp(cc, nn) => {
+                self.reg.set_wz(nn);
+                if cc.is_none_or(|cc| self.cc(cc as usize)) {
+                    self.reg.set_pc(nn);
+                }
+                10
+            }
+            Instruction::JpInd(r) => {
+                let v = self.reg.get(r);
+                self.reg.set_pc(v);
+                4 + prefix_cycles(is_index_reg16(r))
+            }
+            Instruction::Jr(cc, d) => {
+                if cc.is_none_or(|cc| self.cc(cc as usize)) {
+                    let wz = self.reg.pc() + d;
+                    self.reg.set_pc(wz);
+                    self.reg.set_wz(wz);
+                    12
+                } else {
+                    7
+                }
+            }
+            Instruction::Djnz(d) => self.djnz_d(d),
+            Instruction::Call(cc, nn) => {
+                if cc.is_none_or(|cc| self.cc(cc as usize)) {
+                    self.call_nn(nn)
+                } else {
+                    self.reg.set_wz(nn);
+                    10
+                }
+            }
+            Instruction::Ret(None) => self.ret(),
+            Instruction::Ret(Some(cc)) => self.retcc(cc as usize),
+            Instruction::Reti => self.reti(bus),
+            Instruction::Retn => {
+                // RETN (and undocumented mirrors)
+                self.ret();
+                self.iff1 = self.iff2;
+                14
+            }
+            Instruction::Rst(p) => {
+                self.rst(p);
+                11
+            }
+            Instruction::Di => {
+                self.iff1 = false;
+                self.iff2 = false;
+                4
+            }
+            Instruction::Ei => {
+                self.enable_interrupt = true;
+                4
+            }
+            Instruction::Im(mode) => {
+                self.reg.set_im(mode);
+                8
+            }
+            Instruction::InA(n) => {
+                let port = (self.reg.a() << 8 | n) & 0xFFFF;
+                let v = self.inp(bus, port);
+                self.reg.set_a(v);
+                self.reg.set_wz(port + 1);
+                11
+            }
+            Instruction::OutA(n) => {
+                let a = self.reg.a();
+                let port = (a << 8 | n) & 0xFFFF;
+                self.outp(bus, port, a);
+                self.reg.set_wz(a << 8 | ((n + 1) & 0xFF));
+                11
+            }
+            Instruction::In(r) => {
+                // IN (C) (undocumented) only alters the flags
+                let bc = self.reg.bc();
+                let v = self.inp(bus, bc);
+                if let Some(r) = r {
+                    self.reg.set8(r, v);
+                }
+                let f = flags_szp(v) | (self.reg.f() & CF);
+                self.reg.set_f(f);
+                12
+            }
+            Instruction::Out(r) => {
+                // OUT (C),0 (undocumented) outputs 0 on NMOS, 0xFF on CMOS chips
+                let bc = self.reg.bc();
+                let v = match r {
+                    Some(r) => self.reg.get8(r),
+                    None => self.out_c0_value,
+                };
+                self.outp(bus, bc, v);
+                12
+            }
+            Instruction::Ldi => {
+                self.ldi();
+                16
+            }
+            Instruction::Ldd => {
+                self.ldd();
+                16
+            }
+            Instruction::Ldir => self.ldir(),
+            Instruction::Lddr => self.lddr(),
+            Instruction::Cpi => {
+                self.cpi();
+                16
+            }
+            Instruction::Cpd => {
+                self.cpd();
+                16
+            }
+            Instruction::Cpir => self.cpir(),
+            Instruction::Cpdr => self.cpdr(),
+            Instruction::Ini => {
+                self.ini(bus);
+                16
+            }
+            Instruction::Ind => {
+                self.ind(bus);
+                16
+            }
+            Instruction::Inir => self.inir(bus),
+            Instruction::Indr => self.indr(bus),
+            Instruction::Outi => {
+                self.outi(bus);
+                16
+            }
+            Instruction::Outd => {
+                self.outd(bus);
+                16
+            }
+            Instruction::Otir => self.otir(bus),
+            Instruction::Otdr => self.otdr(bus),
+            Instruction::EdNop(_) => 8,
+            // undefined ED instructions are NOPs
+            Instruction::Invalid(op) => {
+                self.invalid_op = true;
+                if self.invalid_op_policy != InvalidOpPolicy::Nop {
+                    bus.invalid_op((self.reg.pc() - 2) & 0xFFFF, op);
+                }
+                if self.invalid_op_policy == InvalidOpPolicy::Trap {
+                    self.reg.dec_pc(2);
+                }
+                8
+            }
+        }
+    }
+
+    /// the result of a rotate, shift, RES or SET instruction
+    #[inline(always)]
+    fn bit_op(&mut self, inst: Instruction, val: RegT) -> RegT {
+        match inst {
+            Instruction::Rot(op, _, _) => self.rot(op as usize, val),
+            Instruction::Res(b, _, _) => val & !(1 << b),
+            Instruction::Set(b, _, _) => val | 1 << b,
+            _ => unreachable!(),
+        }
+    }
+
+    /// address of a memory operand, (IX+d) and (IY+d) also set WZ
+    #[inline(always)]
+    fn operand_addr(&mut self, o: Operand) -> RegT {
+        match o {
+            Operand::Ind(r) => self.reg.get(r),
+            Operand::Idx(r, d) => {
+                let addr = (self.reg.get(r) + d) & 0xFFFF;
+                self.reg.set_wz(addr);
+                addr
+            }
+            Operand::Abs(nn) => nn,
+            Operand::Reg(_) | Operand::Imm(_) => unreachable!(),
+        }
+    }
+
+    /// read an 8-bit operand
+    #[inline(always)]
+    fn load(&mut self, src: Operand) -> RegT {
+        match src {
+            Operand::Reg(r) => self.reg.get8(r),
+            Operand::Imm(n) => n,
+            _ => {
+                let addr = self.operand_addr(src);
+                // LD A,(BC); LD A,(DE); LD A,(nn)
+                if let Operand::Ind(Reg16::BC) | Operand::Ind(Reg16::DE) | Operand::Abs(_) = src {
+                    self.reg.set_wz(addr + 1);
                 }
+                self.mem.r8(addr)
+            }
+        }
+    }
+
+    /// write an 8-bit operand
+    #[inline(always)]
+    fn store(&mut self, dst: Operand, val: RegT) {
+        match dst {
+            Operand::Reg(r) => self.reg.set8(r, val),
+            _ => {
+                let addr = self.operand_addr(dst);
+                match dst {
+                    // LD (BC),A; LD (DE),A
+                    Operand::Ind(Reg16::BC) | Operand::Ind(Reg16::DE) => {
+                        self.reg.set_wz(val << 8 | ((addr + 1) & 0xFF));
+                    }
+                    // LD (nn),A
+                    Operand::Abs(_) => self.reg.set_wz(addr + 1),
+                    _ => (),
+                }
+                self.mem.w8(addr, val);
             }
-            _ => unreachable!(),
         }
     }
 
@@ -982,52 +2310,85 @@ impl CPU {
     fn reti(&mut self, bus: &dyn Bus) -> i64 {
         self.ret();
         bus.irq_reti();
-        15
+        14
     }
 
     #[inline(always)]
     fn handle_irq(&mut self, bus: &dyn Bus) -> i64 {
-        // NOTE: only interrupt mode 2 is supported at the moment
-        assert_eq!(2, self.reg.im);
-
         let mut cycles = 2;
 
-        // leave HALT state
-        if self.halt {
-            self.halt = false;
-            self.reg.inc_pc(1);
-        }
+        self.leave_halt();
 
         // handle the interrupt
         if self.iff1 {
             self.irq_received = false;
             self.iff1 = false;
             self.iff2 = false;
-            let vec = bus.irq_ack();
-            let addr = (self.reg.i << 8 | vec) & 0xFFFE;
+            if self.reg.im() == Im::Zero {
+                cycles += self.handle_irq_im0(bus);
+            } else if self.reg.im() == Im::One {
+                // IM1 ignores the data bus and always executes a RST 38h
+                let vec = bus.irq_ack();
+                self.trace_data(vec);
+                self.rst(0x38);
+                cycles += 11;
+            } else {
+                let vec = bus.irq_ack();
+                self.trace_data(vec);
+                let addr = (self.reg.i << 8 | vec) & 0xFFFE;
 
-            // store return address on stack, and jump to interrupt handler
-            let sp = (self.reg.sp() - 2) & 0xFFFF;
-            self.mem.w16(sp, self.reg.pc());
-            self.reg.set_sp(sp);
-            let int_handler = self.mem.r16(addr);
-            self.reg.set_pc(int_handler);
-            cycles += 19;
+                // store return address on stack, and jump to interrupt handler
+                let sp = self.reg.sp();
+                self.check_stack(sp, -2);
+                let sp = (sp - 2) & 0xFFFF;
+                self.mem.w16(sp, self.reg.pc());
+                self.reg.set_sp(sp);
+                let int_handler = self.mem.r16(addr);
+                self.reg.set_pc(int_handler);
+                cycles += 19;
+            }
         }
         let pc = self.reg.pc();
         self.reg.set_wz(pc);
         cycles
     }
 
+    /// execute the instruction put on the data bus by an IM0 interrupt acknowledge
+    fn handle_irq_im0(&mut self, bus: &dyn Bus) -> i64 {
+        self.im0_data[0] = bus.irq_ack();
+        self.trace_data(self.im0_data[0]);
+        for i in 1..4 {
+            self.im0_data[i] = bus.irq_ack_im0(i);
+        }
+        self.im0_pos = 0;
+        self.im0_active = true;
+        let cycles = self.exec_decoded(bus);
+        self.im0_active = false;
+        cycles
+    }
+
+    /// record the value on the data bus for the machine cycle trace
+    #[inline(always)]
+    fn trace_data(&mut self, val: RegT) {
+        if let Some(ref mut trace) = self.mcycle_trace {
+            trace.data = val;
+        }
+    }
+
     /// execute a halt instruction
     pub fn halt(&mut self) {
+        if self.halt_check && !self.halt && !self.iff1 {
+            self.dead_halt = true;
+        }
         self.halt = true;
         self.reg.dec_pc(1);
     }
 
     #[inline(always)]
     pub fn push(&mut self, val: RegT) {
-        let addr = (self.reg.sp() - 2) & 0xFFFF;
+        let sp = self.reg.sp();
+        self.check_stack(sp, -2);
+        let addr = (sp - 2) & 0xFFFF;
         self.reg.set_sp(addr);
         self.mem.w16(addr, val);
     }
@@ -1035,6 +2396,7 @@ impl CPU {
     #[inline(always)]
     pub fn pop(&mut self) -> RegT {
         let addr = self.reg.sp();
+        self.check_stack(addr, 2);
         let val = self.mem.r16(addr);
         self.reg.set_sp(addr + 2);
         val
@@ -1135,7 +2497,7 @@ impl CPU {
     pub fn inc8(&mut self, val: RegT) -> RegT {
         let res = (val + 1) & 0xFF;
         let f = (if res == 0 {ZF} else {res & SF}) |
-            (res & (XF | YF)) | ((res ^ val) & HF) |
+            (res & XYF) | ((res ^ val) & HF) |
             (if res == 0x80 {VF} else {0}) |
             (self.reg.f() & CF);
         self.reg.set_f(f);
@@ -1147,7 +2509,7 @@ impl CPU {
     pub fn dec8(&mut self, val: RegT) -> RegT {
         let res = (val - 1) & 0xFF;
         let f = NF | (if res == 0 {ZF} else {res & SF}) |
-            (res & (XF | YF)) | ((res ^ val) & HF) |
+            (res & XYF) | ((res ^ val) & HF) |
             (if res == 0x7F {VF} else {0}) |
             (self.reg.f() & CF);
         self.reg.set_f(f);
@@ -1180,7 +2542,7 @@ impl CPU {
     pub fn rlca8(&mut self) {
         let acc = self.reg.a();
         let res = (acc << 1 | acc >> 7) & 0xFF;
-        let f = ((acc >> 7) & CF) | (res & (XF | YF)) | (self.reg.f() & (SF | ZF | PF));
+        let f = ((acc >> 7) & CF) | (res & XYF) | (self.reg.f() & (SF | ZF | PF));
         self.reg.set_f(f);
         self.reg.set_a(res);
     }
@@ -1196,7 +2558,7 @@ impl CPU {
     pub fn rrca8(&mut self) {
         let acc = self.reg.a();
         let res = (acc >> 1 | acc << 7) & 0xFF;
-        let f = (acc & CF) | (res & (XF | YF)) | (self.reg.f() & (SF | ZF | PF));
+        let f = (acc & CF) | (res & XYF) | (self.reg.f() & (SF | ZF | PF));
         self.reg.set_f(f);
         self.reg.set_a(res);
     }
@@ -1213,7 +2575,7 @@ impl CPU {
         let acc = self.reg.a();
         let f = self.reg.f();
         let res = (acc << 1 | (f & CF)) & 0xFF;
-        self.reg.set_f(((acc >> 7) & CF) | (res & (XF | YF)) | (f & (SF | ZF | PF)));
+        self.reg.set_f(((acc >> 7) & CF) | (res & XYF) | (f & (SF | ZF | PF)));
         self.reg.set_a(res);
     }
 
@@ -1229,7 +2591,7 @@ impl CPU {
         let acc = self.reg.a();
         let f = self.reg.f();
         let res = (acc >> 1 | (f & CF) << 7) & 0xFF;
-        self.reg.set_f((acc & CF) | (res & (XF | YF)) | (f & (SF | ZF | PF)));
+        self.reg.set_f((acc & CF) | (res & XYF) | (f & (SF | ZF | PF)));
         self.reg.set_a(res);
     }
 
@@ -1295,7 +2657,7 @@ impl CPU {
     pub fn bit(&mut self, val: RegT, mask: RegT) {
         let res = val & mask;
         let f = HF | (self.reg.f() & CF) | (if res == 0 {ZF | PF} else {res & SF}) |
-            (val & (XF | YF));
+            (val & XYF);
         self.reg.set_f(f)
     }
 
@@ -1307,7 +2669,7 @@ impl CPU {
     // from high byte of HL+1 or IX/IY+d (expected in WZ)
         let res = val & mask;
         let f = HF | (self.reg.f() & CF) | (if res == 0 {ZF | PF} else {res & SF}) |
-            (self.reg.w() & (XF | YF));
+            (self.reg.w() & XYF);
         self.reg.set_f(f)
     }
 
@@ -1316,7 +2678,7 @@ impl CPU {
         self.reg.set_wz(acc + 1);
         let res = acc + add;
         let f = (self.reg.f() & (SF | ZF | VF)) | (((acc ^ res ^ add) >> 8) & HF) |
-                (res >> 16 & CF) | (res >> 8 & (YF | XF));
+                (res >> 16 & CF) | (res >> 8 & XYF);
         self.reg.set_f(f);
         res & 0xFFFF
     }
@@ -1327,7 +2689,7 @@ impl CPU {
         self.reg.set_wz(acc + 1);
         let res = acc + add + (self.reg.f() & CF);
         self.reg.set_f((((acc ^ res ^ add) >> 8) & HF) | ((res >> 16) & CF) |
-                       ((res >> 8) & (SF | XF | YF)) |
+                       ((res >> 8) & (SF | XYF)) |
                        (if (res & 0xFFFF) == 0 {ZF} else {0}) |
                        (((add ^ acc ^ 0x8000) & (add ^ res) & 0x8000) >> 13));
         res & 0xFFFF
@@ -1339,7 +2701,7 @@ impl CPU {
         self.reg.set_wz(acc + 1);
         let res = acc - sub - (self.reg.f() & CF);
         self.reg.set_f(NF | (((acc ^ res ^ sub) >> 8) & HF) | ((res >> 16) & CF) |
-                       ((res >> 8) & (SF | XF | YF)) |
+                       ((res >> 8) & (SF | XYF)) |
                        (if (res & 0xFFFF) == 0 {ZF} else {0}) |
                        (((sub ^ acc) & (acc ^ res) & 0x8000) >> 13));
         res & 0xFFFF
@@ -1347,18 +2709,23 @@ impl CPU {
 
     #[inline(always)]
     pub fn djnz(&mut self) -> i64 {
+        let pc = self.reg.pc();
+        let d = self.mem.rs8(pc);
+        self.reg.set_pc(pc + 1);
+        self.djnz_d(d)
+    }
+
+    /// DJNZ with the displacement, PC points to the next instruction
+    #[inline(always)]
+    fn djnz_d(&mut self, d: RegT) -> i64 {
         let b = (self.reg.b() - 1) & 0xFF;
         self.reg.set_b(b);
         if b > 0 {
-            let addr = self.reg.pc();
-            let d = self.mem.rs8(addr);
-            let wz = addr + d + 1;
+            let wz = self.reg.pc() + d;
             self.reg.set_wz(wz);
             self.reg.set_pc(wz);
             13  // return num cycles if branch taken
         } else {
-            let pc = self.reg.pc() + 1;
-            self.reg.set_pc(pc);
             8   // return num cycles if loop finished
         }
     }
@@ -1394,28 +2761,40 @@ impl CPU {
     pub fn cpl(&mut self) {
         let f = self.reg.f();
         let a = self.reg.a() ^ 0xFF;
-        self.reg.set_f((f & (SF | ZF | PF | CF)) | (HF | NF) | (a & (YF | XF)));
+        self.reg.set_f((f & (SF | ZF | PF | CF)) | (HF | NF) | (a & XYF));
         self.reg.set_a(a);
     }
 
+    /// X and Y flags after SCF/CCF, depending on the chip variant
+    #[inline(always)]
+    fn scf_ccf_xy(&self) -> RegT {
+        let f = self.reg.f() & XYF;
+        let a = self.reg.a() & XYF;
+        match self.variant {
+            CpuVariant::NMOS => f | a,
+            CpuVariant::CMOS => a,
+            CpuVariant::R800 => f,
+        }
+    }
+
     #[inline(always)]
     pub fn scf(&mut self) {
         let f = self.reg.f();
-        let a = self.reg.a();
-        self.reg.set_f((f & (SF | ZF | YF | XF | PF)) | CF | (a & (YF | XF)));
+        let xy = self.scf_ccf_xy();
+        self.reg.set_f((f & (SF | ZF | PF)) | CF | xy);
     }
 
     #[inline(always)]
     pub fn ccf(&mut self) {
         let f = self.reg.f();
-        let a = self.reg.a();
-        self.reg
-            .set_f(((f & (SF | ZF | YF | XF | PF | CF)) | ((f & CF) << 4) | (a & (YF | XF))) ^ CF);
+        let xy = self.scf_ccf_xy();
+        self.reg.set_f(((f & (SF | ZF | PF | CF)) | ((f & CF) << 4) | xy) ^ CF);
     }
 
     #[inline(always)]
     pub fn ret(&mut self) -> i64 {
         let sp = self.reg.sp();
+        self.check_stack(sp, 2);
         let wz = self.mem.r16(sp);
         self.reg.set_wz(wz);
         self.reg.set_pc(wz);
@@ -1426,7 +2805,15 @@ impl CPU {
     #[inline(always)]
     pub fn call(&mut self) -> i64 {
         let wz = self.imm16();
-        let sp = (self.reg.sp() - 2) & 0xFFFF;
+        self.call_nn(wz)
+    }
+
+    /// CALL nn, PC points to the next instruction
+    #[inline(always)]
+    fn call_nn(&mut self, wz: RegT) -> i64 {
+        let sp = self.reg.sp();
+        self.check_stack(sp, -2);
+        let sp = (sp - 2) & 0xFFFF;
         self.mem.w16(sp, self.reg.pc());
         self.reg.set_sp(sp);
         self.reg.set_wz(wz);
@@ -1467,8 +2854,8 @@ impl CPU {
         self.reg.set_bc(bc);
         let n = (val + self.reg.a()) & 0xFF;
         let f = (self.reg.f() & (SF | ZF | CF)) |
-                (if (n & 0x02) != 0 {YF} else {0}) |
-                (if (n & 0x08) != 0 {XF} else {0}) |
+                (((if (n & 0x02) != 0 {YF} else {0}) |
+                  (if (n & 0x08) != 0 {XF} else {0})) & XYF) |
                 (if bc > 0 {VF} else {0});
         self.reg.set_f(f);
     }
@@ -1486,8 +2873,8 @@ impl CPU {
         self.reg.set_bc(bc);
         let n = (val + self.reg.a()) & 0xFF;
         let f = (self.reg.f() & (SF | ZF | CF)) |
-                (if (n & 0x02) != 0 {YF} else {0}) |
-                (if (n & 0x08) != 0 {XF} else {0}) |
+                (((if (n & 0x02) != 0 {YF} else {0}) |
+                  (if (n & 0x08) != 0 {XF} else {0})) & XYF) |
                 (if bc > 0 {VF} else {0});
         self.reg.set_f(f);
     }
@@ -1496,8 +2883,9 @@ impl CPU {
     pub fn ldir(&mut self) -> i64 {
         self.ldi();
         if (self.reg.f() & VF) != 0 {
-            let pc = self.reg.pc();
+            // WZ is the instruction address + 1
             self.reg.dec_pc(2);
+            let pc = self.reg.pc();
             self.reg.set_wz(pc + 1);
             21
         } else {
@@ -1509,8 +2897,9 @@ impl CPU {
     pub fn lddr(&mut self) -> i64 {
         self.ldd();
         if (self.reg.f() & VF) != 0 {
-            let pc = self.reg.pc();
+            // WZ is the instruction address + 1
             self.reg.dec_pc(2);
+            let pc = self.reg.pc();
             self.reg.set_wz(pc + 1);
             21
         } else {
@@ -1537,10 +2926,10 @@ impl CPU {
             v -= 1;
         }
         if (v & 0x02) != 0 {
-            f |= YF
+            f |= YF & XYF
         };
         if (v & 0x08) != 0 {
-            f |= XF
+            f |= XF & XYF
         };
         self.reg.set_f(f);
     }
@@ -1564,10 +2953,10 @@ impl CPU {
             v -= 1;
         }
         if (v & 0x02) != 0 {
-            f |= YF
+            f |= YF & XYF
         };
         if (v & 0x08) != 0 {
-            f |= XF
+            f |= XF & XYF
         };
         self.reg.set_f(f);
     }
@@ -1576,8 +2965,9 @@ impl CPU {
     pub fn cpir(&mut self) -> i64 {
         self.cpi();
         if (self.reg.f() & (VF | ZF)) == VF {
-            let pc = self.reg.pc();
+            // WZ is the instruction address + 1
             self.reg.dec_pc(2);
+            let pc = self.reg.pc();
             self.reg.set_wz(pc + 1);
             21
         } else {
@@ -1589,8 +2979,9 @@ impl CPU {
     pub fn cpdr(&mut self) -> i64 {
         self.cpd();
         if (self.reg.f() & (VF | ZF)) == VF {
-            let pc = self.reg.pc();
+            // WZ is the instruction address + 1
             self.reg.dec_pc(2);
+            let pc = self.reg.pc();
             self.reg.set_wz(pc + 1);
             21
         } else {
@@ -1598,16 +2989,37 @@ impl CPU {
         }
     }
 
+    /// read from an I/O port
+    ///
+    /// The 4 T states of an I/O cycle include the wait state which the
+    /// Z80 inserts automatically, extra wait states from Bus::io_wait()
+    /// are added to the cycles returned by step().
     #[inline(always)]
     pub fn inp(&mut self, bus: &dyn Bus, port: RegT) -> RegT {
-        bus.cpu_inp(port) & 0xFF
+        self.io_wait += bus.io_wait(port, false);
+        let val = bus.cpu_inp(port) & 0xFF;
+        self.io_access = true;
+        self.trace_data(val);
+        val
     }
 
+    /// write to an I/O port, see inp() for the timing
     #[inline(always)]
     pub fn outp(&mut self, bus: &dyn Bus, port: RegT, val: RegT) {
+        self.io_wait += bus.io_wait(port, true);
+        self.io_access = true;
+        self.trace_data(val);
         bus.cpu_outp(port, val);
     }
 
+    /// private method to return and clear the extra I/O wait states
+    #[inline(always)]
+    fn take_io_wait(&mut self) -> i64 {
+        let io_wait = self.io_wait;
+        self.io_wait = 0;
+        io_wait
+    }
+
     #[inline(always)]
     #[cfg_attr(rustfmt, rustfmt_skip)]
     fn ini_ind_flags(&self, val: RegT, add: RegT) -> RegT {
@@ -1733,13 +3145,90 @@ impl CPU {
     }
 }
 
+impl fmt::Display for CPU {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}\nIFF1={} IFF2={} HALT={}",
+               self.reg,
+               self.iff1 as u8,
+               self.iff2 as u8,
+               self.halt as u8)
+    }
+}
+
+/// the 16-bit registers in a savestate (followed by PC)
+const STATE_REGS: [Reg16; 13] = [Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL, Reg16::IX,
+                                 Reg16::IY, Reg16::SP, Reg16::WZ, Reg16::AF_, Reg16::BC_,
+                                 Reg16::DE_, Reg16::HL_, Reg16::WZ_];
+
+impl SaveState for CPU {
+    const STATE_VERSION: u16 = 2;
+
+    /// the registers and interrupt state, the memory is saved separately
+    fn save_state(&self, w: &mut ChunkWriter) {
+        for &r in STATE_REGS.iter() {
+            w.u16(self.reg.get(r) as u16);
+        }
+        w.u16(self.reg.pc() as u16);
+        w.u8(self.reg.i as u8);
+        w.u8(self.reg.r as u8);
+        w.u8(self.reg.im() as u8);
+        w.bool(self.halt);
+        w.bool(self.iff1);
+        w.bool(self.iff2);
+        w.bool(self.enable_interrupt);
+        w.bool(self.irq_received);
+        w.u8(self.variant as u8);
+        w.u64(self.cycles);
+        w.u8(self.out_c0_value as u8);
+    }
+
+    fn load_state(&mut self, r: &mut ChunkReader) -> Result<(), StateError> {
+        let mut reg = Registers::new();
+        for &r16 in STATE_REGS.iter() {
+            reg.set(r16, r.u16()? as RegT);
+        }
+        reg.set_pc(r.u16()? as RegT);
+        reg.i = r.u8()? as RegT;
+        reg.r = r.u8()? as RegT;
+        let im = r.u8()? as RegT;
+        reg.set_im(Im::from_bits(im).ok_or_else(|| r.invalid())?);
+        let (halt, iff1, iff2) = (r.bool()?, r.bool()?, r.bool()?);
+        let (enable_interrupt, irq_received) = (r.bool()?, r.bool()?);
+        let variant = match r.u8()? {
+            0 => CpuVariant::NMOS,
+            1 => CpuVariant::CMOS,
+            2 => CpuVariant::R800,
+            _ => return Err(r.invalid()),
+        };
+        let cycles = r.u64()?;
+        // version 2 added the OUT (C),0 value
+        let out_c0_value = if r.version() >= 2 { Some(r.u8()? as RegT) } else { None };
+        self.cycles = cycles;
+        self.reg = reg;
+        self.halt = halt;
+        self.iff1 = iff1;
+        self.iff2 = iff2;
+        self.enable_interrupt = enable_interrupt;
+        self.irq_received = irq_received;
+        self.im0_active = false;
+        self.set_variant(variant);
+        if let Some(val) = out_c0_value {
+            self.out_c0_value = val;
+        }
+        Ok(())
+    }
+}
+
 // ------------------------------------------------------------------------------
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use std::cell::RefCell;
     use RegT;
     use Bus;
+    use bus::NullBus;
     use registers::CF;
     use registers::NF;
     use registers::VF;
@@ -1755,19 +3244,18 @@ mod tests {
         let mut cpu = CPU::new_64k();
         cpu.reg.set_pc(0x1234);
         cpu.reg.set_wz(1234);
-        cpu.reg.im = 45;
-        cpu.halt = true;
-        cpu.iff1 = true;
-        cpu.iff2 = true;
+        cpu.set_interrupt_mode(Im::Two);
+        cpu.halt();
+        cpu.set_iff(true, true);
         cpu.reg.i = 2;
         cpu.reg.r = 3;
         cpu.reset();
         assert_eq!(0, cpu.reg.pc());
         assert_eq!(0, cpu.reg.wz());
-        assert_eq!(0, cpu.reg.im);
-        assert!(!cpu.halt);
-        assert!(!cpu.iff1);
-        assert!(!cpu.iff2);
+        assert_eq!(Im::Zero, cpu.interrupt_mode());
+        assert!(!cpu.is_halted());
+        assert!(!cpu.iff1());
+        assert!(!cpu.iff2());
         assert_eq!(0, cpu.reg.i);
         assert_eq!(0, cpu.reg.r);
     }
@@ -1797,11 +3285,475 @@ mod tests {
         let mut cpu = CPU::new_64k();
         cpu.reg.set_pc(0x1234);
         cpu.halt();
-        assert!(cpu.halt);
+        assert!(cpu.is_halted());
         assert_eq!(0x1233, cpu.reg.pc());
+        cpu.leave_halt();
+        assert!(!cpu.is_halted());
+        assert_eq!(0x1234, cpu.reg.pc());
+        cpu.leave_halt();
+        assert_eq!(0x1234, cpu.reg.pc());
+    }
+
+    #[test]
+    fn step_until() {
+        let mut cpu = CPU::new_64k();
+        let bus = TestBus {};
+        // INC A; JR -3 (endless loop)
+        cpu.mem.write(0x0000, &[0x3C, 0x18, 0xFD]);
+        let (cycles, reason) = cpu.step_until(&bus, 100, |_| false);
+        assert_eq!(reason, StopReason::Cycles);
+        assert_eq!(cycles, 100);
+        assert_eq!(cpu.reg.a(), 7);
+
+        // stop when A changes
+        let a = cpu.reg.a();
+        let (cycles, reason) = cpu.step_until(&bus, 100, |cpu| cpu.reg.a() != a);
+        assert_eq!(reason, StopReason::Predicate);
+        assert_eq!(cycles, 16);
+        assert_eq!(cpu.reg.a(), 8);
+
+        // predicate is checked first
+        let (cycles, reason) = cpu.step_until(&bus, 0, |_| true);
+        assert_eq!((cycles, reason), (0, StopReason::Predicate));
+        let (cycles, reason) = cpu.step_until(&bus, 0, |_| false);
+        assert_eq!((cycles, reason), (0, StopReason::Cycles));
+    }
+
+    struct OutBus {
+        out: RefCell<Vec<(RegT, RegT)>>,
+    }
+    impl Bus for OutBus {
+        fn cpu_outp(&self, port: RegT, val: RegT) {
+            self.out.borrow_mut().push((port, val));
+        }
+    }
+
+    #[test]
+    fn variant() {
+        let mut cpu = CPU::new_64k();
+        assert_eq!(cpu.variant(), CpuVariant::NMOS);
+        let bus = OutBus { out: RefCell::new(Vec::new()) };
+        // OUT (C),0
+        cpu.mem.write(0x0000, &[0xED, 0x71, 0xED, 0x71]);
+        cpu.reg.set_bc(0x1234);
+        cpu.step(&bus);
+        cpu.set_variant(CpuVariant::CMOS);
+        cpu.step(&bus);
+        assert_eq!(*bus.out.borrow(), vec![(0x1234, 0x00), (0x1234, 0xFF)]);
+
+        // SCF/CCF X and Y flags
+        if cfg!(feature = "fast") {
+            return;
+        }
+        for &(variant, scf_xy, ccf_xy) in &[(CpuVariant::NMOS, XF | YF, XF | YF),
+                                          (CpuVariant::CMOS, XF, XF),
+                                          (CpuVariant::R800, YF, YF)] {
+            cpu.set_variant(variant);
+            cpu.reg.set_a(XF);
+            cpu.reg.set_f(YF);
+            cpu.scf();
+            assert_eq!(cpu.reg.f(), CF | scf_xy);
+            cpu.reg.set_f(YF);
+            cpu.ccf();
+            assert_eq!(cpu.reg.f(), CF | ccf_xy);
+        }
+    }
+
+    #[test]
+    fn out_c0_value() {
+        let mut cpu = CPU::new_64k();
+        let bus = OutBus { out: RefCell::new(Vec::new()) };
+        assert_eq!(cpu.out_c0_value(), 0);
+        cpu.set_variant(CpuVariant::CMOS);
+        assert_eq!(cpu.out_c0_value(), 0xFF);
+        cpu.set_variant(CpuVariant::NMOS);
+        assert_eq!(cpu.out_c0_value(), 0);
+        // OUT (C),0; OUT (C),0
+        cpu.mem.write(0x0000, &[0xED, 0x71, 0xED, 0x71]);
+        cpu.reg.set_bc(0x00FE);
+        cpu.set_out_c0_value(0x1AB);
+        assert_eq!(cpu.out_c0_value(), 0xAB);
+        cpu.step(&bus);
+        // the variant stays unchanged
+        assert_eq!(cpu.variant(), CpuVariant::NMOS);
+        cpu.set_out_c0_value(0x00);
+        cpu.step(&bus);
+        assert_eq!(*bus.out.borrow(), vec![(0x00FE, 0xAB), (0x00FE, 0x00)]);
+    }
+
+    struct M1Bus {
+        fetches: RefCell<Vec<(RegT, RegT)>>,
+    }
+    impl Bus for M1Bus {
+        fn m1(&self, pc: RegT, op: RegT) {
+            self.fetches.borrow_mut().push((pc, op));
+        }
+    }
+
+    #[test]
+    fn m1() {
+        let mut cpu = CPU::new_64k();
+        let bus = M1Bus { fetches: RefCell::new(Vec::new()) };
+        let prog = [
+            0x3E, 0x11,             // LD A,0x11
+            0xCB, 0x07,             // RLC A
+            0xED, 0x44,             // NEG
+            0xDD, 0xCB, 0x01, 0x06, // RLC (IX+1)
+        ];
+        cpu.mem.write(0x0000, &prog);
+        for _ in 0..4 {
+            cpu.step(&bus);
+        }
+        assert_eq!(*bus.fetches.borrow(),
+                   [(0x0000, 0x3E), (0x0002, 0xCB), (0x0003, 0x07), (0x0004, 0xED),
+                    (0x0005, 0x44), (0x0006, 0xDD), (0x0007, 0xCB)]);
+    }
+
+    struct Im0Bus {
+        data: [RegT; 4],
+    }
+    impl Bus for Im0Bus {
+        fn irq_ack(&self) -> RegT {
+            self.data[0]
+        }
+        fn irq_ack_im0(&self, index: usize) -> RegT {
+            self.data[index]
+        }
+    }
+
+    #[test]
+    fn irq_im0() {
+        let mut cpu = CPU::new_64k();
+        // IM 0; EI; NOP; NOP
+        cpu.mem.write(0x0100, &[0xED, 0x46, 0xFB, 0x00, 0x00]);
+        cpu.reg.set_pc(0x0100);
+        cpu.reg.set_sp(0x8000);
+
+        // RST 38h on the data bus
+        let bus = Im0Bus { data: [0xFF, 0x00, 0x00, 0x00] };
+        cpu.step(&bus);
+        cpu.step(&bus);
+        cpu.irq();
+        assert_eq!(cpu.step(&bus), 4 + 2 + 11);
+        assert_eq!(cpu.reg.pc(), 0x0038);
+        assert_eq!(cpu.reg.sp(), 0x7FFE);
+        assert_eq!(cpu.mem.r16(0x7FFE), 0x0104);
+        assert!(!cpu.iff1());
+
+        // CALL 0x1234 on the data bus
+        let bus = Im0Bus { data: [0xCD, 0x34, 0x12, 0x00] };
+        cpu.reg.set_pc(0x0102);
+        cpu.step(&bus);
+        cpu.irq();
+        assert_eq!(cpu.step(&bus), 4 + 2 + 17);
+        assert_eq!(cpu.reg.pc(), 0x1234);
+        assert_eq!(cpu.reg.sp(), 0x7FFC);
+        assert_eq!(cpu.mem.r16(0x7FFC), 0x0104);
+    }
+
+    #[test]
+    fn undefined_ed() {
+        let mut cpu = CPU::new_64k();
+        let bus = TestBus {};
+        // ED 00 (undefined); ED FF (undefined); NOP
+        cpu.mem.write(0x0000, &[0xED, 0x00, 0xED, 0xFF, 0x00]);
+        assert_eq!(cpu.step(&bus), 8);
+        assert!(cpu.invalid_op);
+        assert_eq!(cpu.step(&bus), 8);
+        assert_eq!(cpu.reg.pc(), 0x0004);
+        assert_eq!(cpu.step(&bus), 4);
+        assert!(!cpu.invalid_op);
+    }
+
+    struct InvalidOpBus {
+        ops: RefCell<Vec<(RegT, RegT)>>,
+    }
+    impl Bus for InvalidOpBus {
+        fn invalid_op(&self, pc: RegT, op: RegT) {
+            self.ops.borrow_mut().push((pc, op));
+        }
+    }
+
+    #[test]
+    fn invalid_op_policy() {
+        let mut cpu = CPU::new_64k();
+        let bus = InvalidOpBus { ops: RefCell::new(Vec::new()) };
+        assert_eq!(cpu.invalid_op_policy(), InvalidOpPolicy::Nop);
+        // ED 00 (undefined); NOP; ED 01 (undefined); NOP
+        cpu.mem.write(0x0000, &[0xED, 0x00, 0x00, 0xED, 0x01, 0x00]);
+        cpu.step(&bus);
+        assert!(bus.ops.borrow().is_empty());
+        cpu.set_invalid_op_policy(InvalidOpPolicy::Log);
+        cpu.reg.set_pc(0x0000);
+        assert_eq!(cpu.step(&bus), 8);
+        assert_eq!(cpu.reg.pc(), 0x0002);
+        assert_eq!(*bus.ops.borrow(), vec![(0x0000, 0x00)]);
+        cpu.set_invalid_op_policy(InvalidOpPolicy::Trap);
+        let (cycles, reason) = cpu.step_until(&bus, 1000, |_| false);
+        assert_eq!((cycles, reason), (4 + 8, StopReason::InvalidOp));
+        assert!(cpu.invalid_op);
+        assert_eq!(cpu.reg.pc(), 0x0003);
+        assert_eq!(bus.ops.borrow()[1], (0x0003, 0x01));
+    }
+
+    struct StackFaultBus {
+        faults: RefCell<Vec<(RegT, RegT, StackFault)>>,
+    }
+    impl Bus for StackFaultBus {
+        fn stack_fault(&self, pc: RegT, sp: RegT, fault: StackFault) {
+            self.faults.borrow_mut().push((pc, sp, fault));
+        }
+    }
+
+    #[test]
+    fn stack_range() {
+        let mut cpu = CPU::new_64k();
+        let bus = StackFaultBus { faults: RefCell::new(Vec::new()) };
+        // PUSH BC; PUSH BC; POP BC; POP BC; POP BC
+        cpu.mem.write(0x0000, &[0xC5, 0xC5, 0xC1, 0xC1, 0xC1]);
+        cpu.reg.set_sp(0x8000);
+        cpu.set_stack_range(0x7FFE, 0x8000);
+        assert_eq!(cpu.stack_range(), Some((0x7FFE, 0x8000)));
+        cpu.step(&bus);
+        assert_eq!(cpu.stack_fault, None);
+        let (_, reason) = cpu.step_until(&bus, 1000, |_| false);
+        assert_eq!(reason, StopReason::StackFault);
+        assert_eq!(cpu.stack_fault, Some(StackFault::Overflow));
+        assert_eq!(cpu.reg.pc(), 0x0002);
+        cpu.step(&bus);
+        cpu.step(&bus);
+        assert_eq!(cpu.stack_fault, None);
+        cpu.step(&bus);
+        assert_eq!(cpu.stack_fault, Some(StackFault::Underflow));
+        assert_eq!(*bus.faults.borrow(),
+                   vec![(0x0001, 0x7FFC, StackFault::Overflow),
+                        (0x0004, 0x8002, StackFault::Underflow)]);
+
+        // CALL 0x0010; RET with a stack at the end of memory
+        cpu.mem.write(0x0000, &[0xCD, 0x10, 0x00]);
+        cpu.mem.w8(0x0010, 0xC9);
+        cpu.reg.set_pc(0x0000);
+        cpu.reg.set_sp(0x0000);
+        cpu.set_stack_range(0xFF00, 0x10000);
+        cpu.step(&bus);
+        cpu.step(&bus);
+        assert_eq!(cpu.stack_fault, None);
+        assert_eq!((cpu.reg.pc(), cpu.reg.sp()), (0x0003, 0x0000));
+        cpu.set_stack_range(0x0000, 0xFFFF);
+        cpu.reg.set_pc(0x0000);
+        cpu.step(&bus);
+        assert_eq!(cpu.stack_fault, Some(StackFault::Wrap));
+        cpu.clear_stack_range();
+        cpu.step(&bus);
+        assert_eq!(cpu.stack_fault, None);
+        assert_eq!(bus.faults.borrow().len(), 3);
+    }
+
+    struct DeadHaltBus {
+        halts: RefCell<Vec<RegT>>,
+    }
+    impl Bus for DeadHaltBus {
+        fn dead_halt(&self, pc: RegT) {
+            self.halts.borrow_mut().push(pc);
+        }
+    }
+
+    #[test]
+    fn halt_check() {
+        let mut cpu = CPU::new_64k();
+        let bus = DeadHaltBus { halts: RefCell::new(Vec::new()) };
+        // EI; HALT; DI; HALT
+        cpu.mem.write(0x0000, &[0xFB, 0x76, 0xF3, 0x76]);
+        cpu.set_halt_check(true);
+        assert!(cpu.halt_check());
+        cpu.step(&bus);
+        cpu.step(&bus);
+        assert!(cpu.is_halted() && !cpu.dead_halt);
+        cpu.leave_halt();
+        cpu.step(&bus);
+        cpu.step(&bus);
+        assert!(cpu.is_halted() && cpu.dead_halt);
+        // only reported when entering the HALT state
+        cpu.step(&bus);
+        assert!(cpu.is_halted() && !cpu.dead_halt);
+        assert_eq!(*bus.halts.borrow(), vec![0x0003]);
+
+        cpu.set_halt_check(false);
+        cpu.leave_halt();
+        cpu.reg.set_pc(0x0003);
+        cpu.step(&bus);
+        assert!(cpu.is_halted() && !cpu.dead_halt);
+        assert_eq!(bus.halts.borrow().len(), 1);
+    }
+
+    struct RunawayBus {
+        loops: RefCell<Vec<(RegT, RegT)>>,
+    }
+    impl Bus for RunawayBus {
+        fn runaway(&self, lo: RegT, hi: RegT) {
+            self.loops.borrow_mut().push((lo, hi));
+        }
+    }
+
+    #[test]
+    fn watchdog() {
+        use watchdog::Watchdog;
+        let mut cpu = CPU::new_64k();
+        let bus = RunawayBus { loops: RefCell::new(Vec::new()) };
+        // a loop with OUT (0x10),A isn't a runaway
+        cpu.mem.write(0x0000, &[0xD3, 0x10, 0x18, 0xFC]);
+        cpu.set_watchdog(Watchdog::new(8, 1000));
+        let (_, reason) = cpu.step_until(&bus, 10000, |_| false);
+        assert_eq!(reason, StopReason::Cycles);
+        // neither is a loop which writes memory: INC (HL); DJNZ
+        cpu.mem.write(0x0000, &[0x34, 0x10, 0xFD, 0x18, 0xFB]);
+        cpu.reg.set_pc(0x0000);
+        let (_, reason) = cpu.step_until(&bus, 10000, |_| false);
+        assert_eq!(reason, StopReason::Cycles);
+        // but the inner loop of DEC A; JR NZ,-3; JR 0x0000 is
+        cpu.mem.write(0x0000, &[0x3D, 0x20, 0xFD, 0x18, 0xFB]);
+        cpu.reg.set_pc(0x0000);
+        let (_, reason) = cpu.step_until(&bus, 10000, |_| false);
+        assert_eq!(reason, StopReason::Runaway);
+        assert!(cpu.runaway);
+        assert_eq!(*bus.loops.borrow(), vec![(0x0000, 0x0001)]);
+        assert!(cpu.watchdog().unwrap().is_tripped());
+        cpu.clear_watchdog();
+        assert!(cpu.watchdog().is_none());
+    }
+
+    #[test]
+    fn exec_permissions() {
+        use std::rc::Rc;
+        use memory::{MemAccess, MEM_EXEC};
+        let mut cpu = CPU::new();
+        cpu.mem.map(0, 0x0000, 0x0000, false, 0x0400);
+        cpu.mem.map(0, 0x0400, 0x8000, true, 0x0400);
+        cpu.mem.set_permissions(0, 0x0000, 0x0400, MEM_EXEC);
+        // LD A,(0x0010); LD HL,0x8000; LD (HL),A; JP HL
+        cpu.mem.write(0x0000, &[0x3A, 0x10, 0x00, 0x21, 0x00, 0x80, 0x77, 0xE9]);
+        // JP 0x4000
+        cpu.mem.write(0x8001, &[0xC3, 0x00, 0x40]);
+        let faults = Rc::new(RefCell::new(Vec::new()));
+        let log = faults.clone();
+        cpu.mem.set_fault_handler(move |fault| log.borrow_mut().push((fault.addr, fault.access)));
+        let bus = TestBus {};
+        for _ in 0..7 {
+            cpu.step(&bus);
+        }
+        // unmapped memory reads as 0xFF (RST 38h), which pushes into unmapped memory
+        assert_eq!(cpu.reg.pc(), 0x0038);
+        assert_eq!(*faults.borrow(),
+                   [(0x0010, MemAccess::Read), (0x4000, MemAccess::Exec),
+                    (0xFFFE, MemAccess::Write), (0xFFFF, MemAccess::Write)]);
     }
 
     #[test]
+    fn ld_a_ir_interrupt() {
+        let bus = TestBus {};
+        for &(variant, pf) in &[(CpuVariant::NMOS, 0), (CpuVariant::CMOS, PF)] {
+            let mut cpu = CPU::new_64k();
+            cpu.set_variant(variant);
+            // IM 1; EI; NOP; LD A,I; LD A,R
+            cpu.mem.write(0x0000, &[0xED, 0x56, 0xFB, 0x00, 0xED, 0x57, 0xED, 0x5F]);
+            cpu.reg.set_sp(0x8000);
+            for _ in 0..3 {
+                cpu.step(&bus);
+            }
+            // without interrupt, P/V is a copy of IFF2
+            cpu.step(&bus);
+            assert_eq!(cpu.reg.f() & PF, PF);
+            // an interrupt accepted after LD A,R
+            cpu.irq();
+            cpu.step(&bus);
+            assert_eq!(cpu.reg.pc(), 0x0038);
+            assert_eq!(cpu.reg.f() & PF, pf);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "fast"))]
+    fn block_repeat_interrupt() {
+        let bus = TestBus {};
+        let mut cpu = CPU::new_64k();
+        // IM 1; EI; LDIR; HALT
+        cpu.mem.write(0x0100, &[0xED, 0x56, 0xFB, 0xED, 0xB0, 0x76]);
+        // interrupt handler: EI; RET
+        cpu.mem.write(0x0038, &[0xFB, 0xC9]);
+        cpu.mem.write(0x1000, &[1, 2, 3, 4]);
+        cpu.reg.set_pc(0x0100);
+        cpu.reg.set_sp(0x8000);
+        cpu.reg.set_hl(0x1000);
+        cpu.reg.set_de(0x2000);
+        cpu.reg.set_bc(4);
+        cpu.step(&bus);
+        cpu.step(&bus);
+        assert_eq!(cpu.step(&bus), 21);
+        assert_eq!((cpu.reg.pc(), cpu.reg.wz(), cpu.reg.bc()), (0x0103, 0x0104, 3));
+        // interrupt between the first and second iteration
+        cpu.irq();
+        assert_eq!(cpu.step(&bus), 21 + 13);
+        assert_eq!(cpu.reg.pc(), 0x0038);
+        assert_eq!(cpu.mem.r16(cpu.reg.sp()), 0x0103);
+        assert_eq!(cpu.reg.bc(), 2);
+        cpu.step(&bus);
+        cpu.step(&bus);
+        assert_eq!(cpu.reg.pc(), 0x0103);
+        cpu.step(&bus);
+        assert_eq!(cpu.step(&bus), 16);
+        assert_eq!((cpu.reg.pc(), cpu.reg.bc()), (0x0105, 0));
+        assert_eq!(cpu.mem.r16(0x2000), 0x0201);
+        assert_eq!(cpu.mem.r16(0x2002), 0x0403);
+
+        // CPIR: WZ is also set to the instruction address + 1
+        cpu.mem.write(0x0200, &[0xED, 0xB1]);
+        cpu.reg.set_pc(0x0200);
+        cpu.reg.set_hl(0x1000);
+        cpu.reg.set_bc(4);
+        cpu.reg.set_a(3);
+        cpu.step(&bus);
+        assert_eq!((cpu.reg.pc(), cpu.reg.wz()), (0x0200, 0x0201));
+    }
+
+    #[test]
+    fn skip_halt() {
+        let mut cpu = CPU::new_64k();
+        let bus = TestBus {};
+        // NOP; HALT
+        cpu.mem.write(0x0000, &[0x00, 0x76]);
+        assert_eq!(cpu.skip_halt(100), 0);
+        cpu.step(&bus);
+        cpu.step(&bus);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.reg.r, 2);
+        assert_eq!(cpu.skip_halt(3), 0);
+        assert_eq!(cpu.skip_halt(1001), 1000);
+        assert_eq!(cpu.cycles, 8 + 1000);
+        assert_eq!(cpu.reg.r, (2 + 250) & 0x7F);
+        assert_eq!(cpu.reg.pc(), 0x0001);
+        assert!(cpu.is_halted());
+        cpu.irq();
+        assert_eq!(cpu.skip_halt(1000), 0);
+    }
+
+    #[test]
+    fn irq_im1() {
+        let mut cpu = CPU::new_64k();
+        let bus = TestBus {};
+        // IM 1; EI; NOP
+        cpu.mem.write(0x0100, &[0xED, 0x56, 0xFB, 0x00]);
+        cpu.reg.set_pc(0x0100);
+        cpu.reg.set_sp(0x8000);
+        cpu.step(&bus);
+        cpu.step(&bus);
+        cpu.irq();
+        assert_eq!(cpu.step(&bus), 4 + 2 + 11);
+        assert_eq!(cpu.reg.pc(), 0x0038);
+        assert_eq!(cpu.mem.r16(0x7FFE), 0x0104);
+    }
+
+    #[test]
+    #[cfg(not(feature = "fast"))]
     fn rst() {
         let mut cpu = CPU::new_64k();
         cpu.reg.set_pc(0x123);
@@ -2170,4 +4122,220 @@ mod tests {
         let bus = TestBus {};
         cpu.outp(&bus, 0x1234, 12);
     }
+
+    struct SlowBus;
+    impl Bus for SlowBus {
+        fn io_wait(&self, port: RegT, write: bool) -> i64 {
+            match (port & 0xFF, write) {
+                (0x10, true) => 2,
+                (0x20, false) => 1,
+                _ => 0,
+            }
+        }
+    }
+
+    #[test]
+    fn io_wait() {
+        use std::rc::Rc;
+        use bus::CompositeBus;
+        let mut cpu = CPU::new_64k();
+        // OUT (10h),A; IN A,(10h); IN A,(20h); INIR (with B=2, C=20h)
+        cpu.mem.write(0x0000, &[0xD3, 0x10, 0xDB, 0x10, 0xDB, 0x20, 0xED, 0xB2]);
+        assert_eq!(cpu.step(&SlowBus), 11 + 2);
+        assert_eq!(cpu.step(&SlowBus), 11);
+        assert_eq!(cpu.step(&SlowBus), 11 + 1);
+        cpu.reg.set_bc(0x0220);
+        cpu.set_mcycle_trace(true);
+        assert_eq!(cpu.step(&SlowBus), 21 + 1);
+        let io: Vec<_> = cpu.mcycles().iter().filter(|c| c.kind == MCycleKind::In).collect();
+        assert_eq!((io.len(), io[0].tstates), (1, 4 + 1));
+        assert_eq!(cpu.step(&SlowBus), 16 + 1);
+        assert_eq!(cpu.cycles, 13 + 11 + 12 + 22 + 17);
+
+        // the slowest device of a CompositeBus counts
+        let mut bus = CompositeBus::new();
+        bus.add(Rc::new(NullBus));
+        bus.add(Rc::new(SlowBus));
+        assert_eq!(bus.io_wait(0x0010, true), 2);
+    }
+
+    #[test]
+    fn display() {
+        let mut cpu = CPU::new_64k();
+        cpu.set_iff(true, false);
+        let s = format!("{}", cpu);
+        assert!(s.starts_with("AF=0000 BC=0000"));
+        assert!(s.ends_with("IM=0\nIFF1=1 IFF2=0 HALT=0"));
+    }
+
+    #[test]
+    fn step_captured() {
+        let mut cpu = CPU::new_64k();
+        // PUSH BC, INC A (internal cycles only after the fetch)
+        cpu.mem.write(0x0000, &[0xC5, 0x3C]);
+        cpu.reg.set_sp(0x8000);
+        cpu.reg.set_bc(0x1234);
+        let rec = cpu.step_captured(&NullBus);
+        assert_eq!(rec.cycles, 11);
+        assert_eq!(rec.before.sp(), 0x8000);
+        assert_eq!(rec.after.sp(), 0x7FFE);
+        let acc: Vec<(MCycleKind, RegT, RegT)> = rec.accesses.iter().map(|c| (c.kind, c.addr, c.data)).collect();
+        assert_eq!(acc, [(MCycleKind::Fetch, 0x0000, 0xC5),
+                         (MCycleKind::Write, 0x7FFF, 0x12),
+                         (MCycleKind::Write, 0x7FFE, 0x34)]);
+        // the trace is switched off again
+        assert!(cpu.mcycles().is_empty());
+
+        cpu.set_mcycle_trace(true);
+        let rec = cpu.step_captured(&NullBus);
+        assert_eq!(rec.accesses.len(), 1);
+        assert_eq!(rec.after.a(), 1);
+        assert_eq!(cpu.mcycles().len(), 1);
+    }
+
+    #[test]
+    fn opcode_stats() {
+        use opstats::OpTable;
+        let mut cpu = CPU::new_64k();
+        // LD B,2; LOOP: INC A; RLC (IX+1); DJNZ LOOP; LDIR
+        cpu.mem.write(0x0000, &[0x06, 0x02, 0x3C, 0xDD, 0xCB, 0x01, 0x06, 0x10, 0xF9, 0xED, 0xB0]);
+        cpu.reg.set_bc(0x0000);
+        // the block cache is bypassed while counting
+        cpu.set_block_cache(true);
+        cpu.set_opcode_stats(true);
+        for _ in 0..8 {
+            cpu.step(&NullBus);
+        }
+        {
+            let stats = cpu.opcode_stats().unwrap();
+            assert_eq!(stats.count(OpTable::Main, 0x06), 1);
+            assert_eq!(stats.count(OpTable::Main, 0x3C), 2);
+            assert_eq!(stats.count(OpTable::DDCB, 0x06), 2);
+            assert_eq!(stats.count(OpTable::Main, 0x10), 2);
+            assert_eq!(stats.count(OpTable::ED, 0xB0), 1);
+            assert_eq!(stats.total(), 8);
+        }
+        cpu.opcode_stats_mut().unwrap().clear();
+        assert_eq!(cpu.opcode_stats().unwrap().total(), 0);
+        cpu.set_opcode_stats(false);
+        assert!(cpu.opcode_stats().is_none());
+    }
+
+    #[test]
+    fn interpreter_matches_execute() {
+        // every instruction must give the same result in the direct-dispatch
+        // interpreter and in execute() (which runs while counting opcodes)
+        let mut seed: u32 = 0x2468ACE1;
+        let mut rnd = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        let prefixes: [&[u8]; 7] = [&[], &[0xCB], &[0xED], &[0xDD], &[0xFD], &[0xDD, 0xCB], &[0xFD, 0xCB]];
+        for prefix in &prefixes {
+            for op in 0..256 {
+                let mut bytes = prefix.to_vec();
+                if prefix.len() == 2 {
+                    // the d of (IX+d) and (IY+d)
+                    bytes.push(rnd() as u8);
+                }
+                bytes.push(op as u8);
+                bytes.extend((0..3).map(|_| rnd() as u8));
+                let mut cpus = [CPU::new_64k(), CPU::new_64k()];
+                let regs: Vec<RegT> = (0..8).map(|_| (rnd() & 0xFFFF) as RegT).collect();
+                for cpu in cpus.iter_mut() {
+                    cpu.mem.write(0x8000, &bytes);
+                    cpu.reg.set_af(regs[0]);
+                    cpu.reg.set_bc(regs[1]);
+                    cpu.reg.set_de(regs[2]);
+                    cpu.reg.set_hl(regs[3]);
+                    cpu.reg.set_ix(regs[4]);
+                    cpu.reg.set_iy(regs[5]);
+                    cpu.reg.set_sp(regs[6]);
+                    cpu.reg.set_wz(regs[7]);
+                    cpu.reg.set_pc(0x8000);
+                    cpu.set_iff(true, true);
+                }
+                cpus[1].set_opcode_stats(true);
+                let cycles0 = cpus[0].step(&NullBus);
+                let cycles1 = cpus[1].step(&NullBus);
+                assert_eq!(cycles0, cycles1, "{:02X?}", bytes);
+                assert_eq!(cpus[0].reg, cpus[1].reg, "{:02X?}", bytes);
+                assert_eq!((cpus[0].iff1(), cpus[0].iff2(), cpus[0].is_halted()),
+                           (cpus[1].iff1(), cpus[1].iff2(), cpus[1].is_halted()), "{:02X?}", bytes);
+                assert!(cpus[0].mem.diff(&cpus[1].mem).is_empty(), "{:02X?}", bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn savestate() {
+        use savestate::{StateWriter, StateReader};
+        use rom::crc32;
+        let mut cpu = CPU::new_64k();
+        // EI; IM 2; LD (HL),A; HALT
+        cpu.mem.write(0x0000, &[0xFB, 0xED, 0x5E, 0x77, 0x76]);
+        cpu.reg.set_hl(0x4000);
+        cpu.reg.set_a(0x42);
+        cpu.reg.set_wz_(0x1234);
+        cpu.set_variant(CpuVariant::CMOS);
+        for _ in 0..4 {
+            cpu.step(&NullBus);
+        }
+        let mut w = StateWriter::new();
+        w.add(b"CPU ", &cpu);
+        w.add(b"MEM ", &cpu.mem);
+        let data = w.finish();
+
+        let mut other = CPU::new_64k();
+        other.set_block_cache(true);
+        let r = StateReader::new(&data).unwrap();
+        r.load(b"CPU ", &mut other).unwrap();
+        r.load(b"MEM ", &mut other.mem).unwrap();
+        assert_eq!(other.reg, cpu.reg);
+        assert_eq!((other.is_halted(), other.iff1(), other.iff2(), other.interrupt_mode()),
+                   (true, true, true, Im::Two));
+        assert_eq!(other.variant(), CpuVariant::CMOS);
+        assert_eq!(other.out_c0_value(), 0xFF);
+        assert_eq!(other.cycles, cpu.cycles);
+        assert_eq!(other.mem.r8(0x4000), 0x42);
+        assert_eq!(other.step(&NullBus), cpu.step(&NullBus));
+        assert_eq!(other.reg, cpu.reg);
+
+        // an out of range interrupt mode is rejected and leaves the CPU unchanged
+        // (IM is the byte after the 16-bit registers, PC, I and R)
+        let mut w = StateWriter::new();
+        w.add(b"CPU ", &cpu);
+        let mut data = w.finish();
+        let start = 22;
+        let len = u32::from_le_bytes([data[18], data[19], data[20], data[21]]) as usize;
+        data[start + 30] = 3;
+        let crc = crc32(&data[start..start + len]);
+        data[start + len..start + len + 4].copy_from_slice(&crc.to_le_bytes());
+        let r = StateReader::new(&data).unwrap();
+        assert_eq!(r.load(b"CPU ", &mut other), Err(StateError::InvalidValue(*b"CPU ")));
+        assert_eq!(other.interrupt_mode(), Im::Two);
+
+        // a custom OUT (C),0 value survives a round trip
+        cpu.set_out_c0_value(0x55);
+        let mut w = StateWriter::new();
+        w.add(b"CPU ", &cpu);
+        let data = w.finish();
+        let mut other = CPU::new_64k();
+        StateReader::new(&data).unwrap().load(b"CPU ", &mut other).unwrap();
+        assert_eq!((other.variant(), other.out_c0_value()), (CpuVariant::CMOS, 0x55));
+
+        // version 1 chunks (without the OUT (C),0 value) use the variant's default
+        let mut data = data[..data.len() - 5].to_vec();
+        data[12 + 4] = 1;
+        let start = 22;
+        let len = u32::from_le_bytes([data[18], data[19], data[20], data[21]]) as usize - 1;
+        data[18..22].copy_from_slice(&(len as u32).to_le_bytes());
+        let crc = crc32(&data[start..start + len]);
+        data.extend_from_slice(&crc.to_le_bytes());
+        let mut other = CPU::new_64k();
+        StateReader::new(&data).unwrap().load(b"CPU ", &mut other).unwrap();
+        assert_eq!((other.variant(), other.out_c0_value()), (CpuVariant::CMOS, 0xFF));
+    }
 }
@@ -0,0 +1,294 @@
+use std::fmt::Write;
+use RegT;
+use cpu::CPU;
+use registers::{Reg8, Reg16, Flags, Im};
+
+/// a CPU state value checked by CpuState
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum Field {
+    Reg8(Reg8),
+    Reg16(Reg16),
+    Im,
+    Iff1,
+    Iff2,
+    Halt,
+}
+
+impl Field {
+    fn name(&self) -> String {
+        match *self {
+            Field::Reg8(r) => format!("{:?}", r),
+            Field::Reg16(r) => format!("{:?}", r).replace('_', "'"),
+            Field::Im => "IM".to_string(),
+            Field::Iff1 => "IFF1".to_string(),
+            Field::Iff2 => "IFF2".to_string(),
+            Field::Halt => "HALT".to_string(),
+        }
+    }
+
+    fn get(&self, cpu: &CPU) -> RegT {
+        match *self {
+            Field::Reg8(r) => cpu.reg.get8(r),
+            Field::Reg16(r) => cpu.reg.get(r),
+            Field::Im => cpu.interrupt_mode() as RegT,
+            Field::Iff1 => cpu.iff1() as RegT,
+            Field::Iff2 => cpu.iff2() as RegT,
+            Field::Halt => cpu.is_halted() as RegT,
+        }
+    }
+
+    fn format(&self, val: RegT) -> String {
+        match *self {
+            Field::Reg8(Reg8::F) => format!("{:02X} [{}]", val, Flags::from(val)),
+            Field::Reg16(Reg16::AF) | Field::Reg16(Reg16::AF_) => {
+                format!("{:04X} [{}]", val, Flags::from(val & 0xFF))
+            }
+            Field::Reg8(_) => format!("{:02X}", val),
+            Field::Reg16(_) => format!("{:04X}", val),
+            _ => format!("{}", val),
+        }
+    }
+}
+
+/// expected CPU register and interrupt state for tests
+///
+/// Only the values which have been set are checked, **diff()** returns
+/// one line per mismatch, and **assert()** panics with the mismatches
+/// and a register dump. The assert_cpu_state! macro is a shortcut
+/// for building and asserting a CpuState.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate rz80;
+/// use rz80::{CPU, CpuState, NullBus, ZF, PF};
+///
+/// # fn main() {
+/// let mut cpu = CPU::new_64k();
+/// // LD HL,1234h; XOR A
+/// cpu.mem.write(0x0000, &[0x21, 0x34, 0x12, 0xAF]);
+/// cpu.step(&NullBus);
+/// cpu.step(&NullBus);
+///
+/// assert_cpu_state!(cpu, a: 0x00, f: ZF | PF, hl: 0x1234, pc: 0x0004);
+///
+/// let expected = CpuState::new().hl(0x1234).sp(0xFFFF).f(ZF);
+/// assert_eq!(expected.diff(&cpu), ["SP: expected FFFF, got 0000",
+///                                  "F: expected 40 [-Z------], got 44 [-Z---P--]"]);
+/// # }
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct CpuState {
+    fields: Vec<(Field, RegT)>,
+}
+
+impl CpuState {
+    /// create an expected state without values
+    pub fn new() -> CpuState {
+        CpuState { fields: Vec::new() }
+    }
+
+    fn with(mut self, field: Field, val: RegT) -> CpuState {
+        self.fields.retain(|&(f, _)| f != field);
+        self.fields.push((field, val));
+        self
+    }
+
+    /// expect an 8-bit register value
+    pub fn reg8(self, r: Reg8, val: RegT) -> CpuState {
+        self.with(Field::Reg8(r), val & 0xFF)
+    }
+
+    /// expect a 16-bit register value
+    pub fn reg16(self, r: Reg16, val: RegT) -> CpuState {
+        self.with(Field::Reg16(r), val & 0xFFFF)
+    }
+
+    pub fn a(self, val: RegT) -> CpuState {
+        self.reg8(Reg8::A, val)
+    }
+
+    /// expect the flags register (all flag bits, including XF and YF)
+    pub fn f(self, val: RegT) -> CpuState {
+        self.reg8(Reg8::F, val)
+    }
+
+    pub fn b(self, val: RegT) -> CpuState {
+        self.reg8(Reg8::B, val)
+    }
+
+    pub fn c(self, val: RegT) -> CpuState {
+        self.reg8(Reg8::C, val)
+    }
+
+    pub fn d(self, val: RegT) -> CpuState {
+        self.reg8(Reg8::D, val)
+    }
+
+    pub fn e(self, val: RegT) -> CpuState {
+        self.reg8(Reg8::E, val)
+    }
+
+    pub fn h(self, val: RegT) -> CpuState {
+        self.reg8(Reg8::H, val)
+    }
+
+    pub fn l(self, val: RegT) -> CpuState {
+        self.reg8(Reg8::L, val)
+    }
+
+    pub fn i(self, val: RegT) -> CpuState {
+        self.reg8(Reg8::I, val)
+    }
+
+    pub fn r(self, val: RegT) -> CpuState {
+        self.reg8(Reg8::R, val)
+    }
+
+    pub fn af(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::AF, val)
+    }
+
+    pub fn bc(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::BC, val)
+    }
+
+    pub fn de(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::DE, val)
+    }
+
+    pub fn hl(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::HL, val)
+    }
+
+    pub fn ix(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::IX, val)
+    }
+
+    pub fn iy(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::IY, val)
+    }
+
+    pub fn sp(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::SP, val)
+    }
+
+    pub fn pc(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::PC, val)
+    }
+
+    pub fn wz(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::WZ, val)
+    }
+
+    pub fn af_(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::AF_, val)
+    }
+
+    pub fn bc_(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::BC_, val)
+    }
+
+    pub fn de_(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::DE_, val)
+    }
+
+    pub fn hl_(self, val: RegT) -> CpuState {
+        self.reg16(Reg16::HL_, val)
+    }
+
+    /// expect the interrupt mode
+    pub fn im(self, val: Im) -> CpuState {
+        self.with(Field::Im, val as RegT)
+    }
+
+    pub fn iff1(self, val: bool) -> CpuState {
+        self.with(Field::Iff1, val as RegT)
+    }
+
+    pub fn iff2(self, val: bool) -> CpuState {
+        self.with(Field::Iff2, val as RegT)
+    }
+
+    pub fn halt(self, val: bool) -> CpuState {
+        self.with(Field::Halt, val as RegT)
+    }
+
+    /// the mismatches between the expected values and a CPU, one line per value
+    pub fn diff(&self, cpu: &CPU) -> Vec<String> {
+        self.fields.iter()
+            .filter(|&&(field, val)| field.get(cpu) != val)
+            .map(|&(field, val)| {
+                format!("{}: expected {}, got {}", field.name(), field.format(val),
+                        field.format(field.get(cpu)))
+            })
+            .collect()
+    }
+
+    /// true if all expected values match
+    pub fn matches(&self, cpu: &CPU) -> bool {
+        self.diff(cpu).is_empty()
+    }
+
+    /// panic with the mismatches and a register dump if a value doesn't match
+    pub fn assert(&self, cpu: &CPU) {
+        let diff = self.diff(cpu);
+        if !diff.is_empty() {
+            let mut msg = String::from("CPU state mismatch:\n");
+            for line in &diff {
+                let _ = writeln!(msg, "  {}", line);
+            }
+            let _ = write!(msg, "{}", cpu);
+            panic!("{}", msg);
+        }
+    }
+}
+
+/// assert CPU register and interrupt state, see CpuState
+///
+/// The values are given as 'name: value' with the names of the
+/// CpuState methods, e.g. `assert_cpu_state!(cpu, a: 0x12, hl: 0x1234, halt: true)`.
+#[macro_export]
+macro_rules! assert_cpu_state {
+    ($cpu:expr, $($name:ident : $val:expr),+ $(,)*) => {
+        $crate::CpuState::new()$(.$name($val))+.assert(&$cpu)
+    };
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use registers::{ZF, CF};
+
+    #[test]
+    fn diff() {
+        let mut cpu = CPU::new_64k();
+        cpu.reg.set_af(0x1241);
+        cpu.reg.set_af_(0x0001);
+        cpu.reg.set_ix(0xABCD);
+        cpu.set_iff(true, false);
+        let state = CpuState::new().af(0x1241).ix(0xABCD).iff1(true).iff2(false).im(Im::Zero);
+        assert!(state.matches(&cpu));
+        assert_cpu_state!(cpu, af: 0x1241, ix: 0xABCD, iff1: true, iff2: false,);
+
+        // the last value of a register counts
+        let state = state.a(0x13).f(ZF).af_(0x0040).halt(true).a(0x12).ix(0xABCE);
+        assert_eq!(state.diff(&cpu), [
+            "F: expected 40 [-Z------], got 41 [-Z-----C]",
+            "AF': expected 0040 [-Z------], got 0001 [-------C]",
+            "HALT: expected 1, got 0",
+            "IX: expected ABCE, got ABCD",
+        ]);
+        assert!(!state.matches(&cpu));
+        assert!(CpuState::new().f(ZF | CF).matches(&cpu));
+    }
+
+    #[test]
+    #[should_panic(expected = "CPU state mismatch:\n  HL: expected 1234, got 0000\nAF=")]
+    fn assert_panics() {
+        let cpu = CPU::new();
+        assert_cpu_state!(cpu, hl: 0x1234);
+    }
+}
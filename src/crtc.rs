@@ -0,0 +1,447 @@
+use RegT;
+use bus::Bus;
+
+/// horizontal total (in characters - 1)
+pub const CRTC_H_TOTAL: usize = 0;
+/// horizontal displayed (in characters)
+pub const CRTC_H_DISPLAYED: usize = 1;
+/// horizontal sync position (in characters)
+pub const CRTC_H_SYNC_POS: usize = 2;
+/// sync widths (bits 0..3: hsync width in characters, bits 4..7: vsync width in scanlines)
+pub const CRTC_SYNC_WIDTHS: usize = 3;
+/// vertical total (in character rows - 1)
+pub const CRTC_V_TOTAL: usize = 4;
+/// vertical total adjust (in scanlines)
+pub const CRTC_V_TOTAL_ADJUST: usize = 5;
+/// vertical displayed (in character rows)
+pub const CRTC_V_DISPLAYED: usize = 6;
+/// vertical sync position (in character rows)
+pub const CRTC_V_SYNC_POS: usize = 7;
+/// interlace and skew
+pub const CRTC_INTERLACE_MODE: usize = 8;
+/// max scanline address (scanlines per character row - 1)
+pub const CRTC_MAX_SCANLINE_ADDR: usize = 9;
+/// cursor start scanline
+pub const CRTC_CURSOR_START: usize = 10;
+/// cursor end scanline
+pub const CRTC_CURSOR_END: usize = 11;
+/// display start address (high byte)
+pub const CRTC_START_ADDR_HI: usize = 12;
+/// display start address (low byte)
+pub const CRTC_START_ADDR_LO: usize = 13;
+/// cursor address (high byte)
+pub const CRTC_CURSOR_HI: usize = 14;
+/// cursor address (low byte)
+pub const CRTC_CURSOR_LO: usize = 15;
+/// light pen address (high byte)
+pub const CRTC_LIGHTPEN_HI: usize = 16;
+/// light pen address (low byte)
+pub const CRTC_LIGHTPEN_LO: usize = 17;
+const NUM_REGS: usize = 18;
+
+// writable bits of each register
+const REG_MASK: [u8; NUM_REGS] = [0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0x1F, 0x7F, 0x7F, 0xF3, 0x1F,
+                                  0x7F, 0x1F, 0x3F, 0xFF, 0x3F, 0xFF, 0x3F, 0xFF];
+
+/// MC6845 CRTC emulation
+///
+/// The CRTC generates the video timing (horizontal and vertical sync,
+/// display enable) and the video memory addresses for a raster display.
+/// It doesn't generate any pixels itself, this is the job of the
+/// system-specific video logic (for instance the CPC GateArray), which
+/// reads the current memory address (**ma()**) and scanline within the
+/// character row (**ra()**) after each **tick()**.
+///
+/// The tick() method must be called once per character clock (1 MHz on
+/// the Amstrad CPC). Changes of the HSYNC and VSYNC outputs are
+/// forwarded to the Bus::crtc_hsync() and Bus::crtc_vsync() callbacks.
+///
+/// The CPU side has 2 ports: the register select port (**select()**)
+/// and the register data port (**write()**/**read()**). Only the cursor
+/// and light pen registers can be read back. The light pen position is
+/// polled from Bus::lightpen() at the end of each scanline, a hit latches
+/// the memory address under the pen into the light pen registers.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Bus, CRTC, CRTC_H_TOTAL, CRTC_H_DISPLAYED, CRTC_H_SYNC_POS, CRTC_SYNC_WIDTHS};
+/// use std::cell::Cell;
+///
+/// struct System {
+///     hsyncs: Cell<usize>,
+/// }
+/// impl Bus for System {
+///     fn crtc_hsync(&self, crtc: usize, active: bool) {
+///         if active {
+///             self.hsyncs.set(self.hsyncs.get() + 1);
+///         }
+///     }
+/// }
+///
+/// let sys = System { hsyncs: Cell::new(0) };
+/// let mut crtc = CRTC::new(0);
+/// // 64 characters per line, 40 visible, hsync at character 46, 14 characters wide
+/// for &(reg, val) in &[(CRTC_H_TOTAL, 63), (CRTC_H_DISPLAYED, 40),
+///                      (CRTC_H_SYNC_POS, 46), (CRTC_SYNC_WIDTHS, 0x8E)] {
+///     crtc.select(reg as i32);
+///     crtc.write(val);
+/// }
+/// // run for 10 scanlines
+/// for _ in 0..(64 * 10) {
+///     crtc.tick(&sys);
+/// }
+/// assert_eq!(sys.hsyncs.get(), 10);
+/// ```
+pub struct CRTC {
+    id: usize,
+    sel: usize,
+    reg: [u8; NUM_REGS],
+    h_ctr: RegT,
+    ra: RegT,
+    row_ctr: RegT,
+    adj_ctr: RegT,
+    in_adjust: bool,
+    ma: RegT,
+    ma_row: RegT,
+    scanline: RegT,
+    hs_ctr: RegT,
+    vs_ctr: RegT,
+    hs: bool,
+    vs: bool,
+    h_de: bool,
+    v_de: bool,
+}
+
+impl CRTC {
+    /// initialize a new CRTC object
+    pub fn new(id: usize) -> CRTC {
+        CRTC {
+            id,
+            sel: 0,
+            reg: [0; NUM_REGS],
+            h_ctr: 0,
+            ra: 0,
+            row_ctr: 0,
+            adj_ctr: 0,
+            in_adjust: false,
+            ma: 0,
+            ma_row: 0,
+            scanline: 0,
+            hs_ctr: 0,
+            vs_ctr: 0,
+            hs: false,
+            vs: false,
+            h_de: true,
+            v_de: true,
+        }
+    }
+
+    /// reset the CRTC (this only resets the counters, not the registers)
+    pub fn reset(&mut self) {
+        self.sel = 0;
+        self.h_ctr = 0;
+        self.ra = 0;
+        self.row_ctr = 0;
+        self.adj_ctr = 0;
+        self.in_adjust = false;
+        self.ma = 0;
+        self.ma_row = 0;
+        self.scanline = 0;
+        self.hs_ctr = 0;
+        self.vs_ctr = 0;
+        self.hs = false;
+        self.vs = false;
+        self.h_de = true;
+        self.v_de = true;
+    }
+
+    /// select a register for the next write() or read()
+    pub fn select(&mut self, val: RegT) {
+        self.sel = (val & 0x1F) as usize;
+    }
+
+    /// write the currently selected register
+    pub fn write(&mut self, val: RegT) {
+        if self.sel < NUM_REGS {
+            self.reg[self.sel] = (val as u8) & REG_MASK[self.sel];
+        }
+    }
+
+    /// read the currently selected register (only R14..R17 are readable)
+    pub fn read(&self) -> RegT {
+        match self.sel {
+            CRTC_CURSOR_HI..=CRTC_LIGHTPEN_LO => self.reg[self.sel] as RegT,
+            _ => 0,
+        }
+    }
+
+    /// get a register value
+    pub fn reg(&self, r: usize) -> RegT {
+        self.reg[r] as RegT
+    }
+
+    /// get the current 14-bit memory address
+    #[inline(always)]
+    pub fn ma(&self) -> RegT {
+        self.ma & 0x3FFF
+    }
+
+    /// get the current scanline within the character row
+    #[inline(always)]
+    pub fn ra(&self) -> RegT {
+        self.ra
+    }
+
+    /// get the current scanline within the frame
+    #[inline(always)]
+    pub fn scanline(&self) -> RegT {
+        self.scanline
+    }
+
+    /// get the state of the display enable output
+    #[inline(always)]
+    pub fn de(&self) -> bool {
+        self.h_de && self.v_de
+    }
+
+    /// get the state of the HSYNC output
+    #[inline(always)]
+    pub fn hs(&self) -> bool {
+        self.hs
+    }
+
+    /// get the state of the VSYNC output
+    #[inline(always)]
+    pub fn vs(&self) -> bool {
+        self.vs
+    }
+
+    /// advance the CRTC by one character clock
+    pub fn tick(&mut self, bus: &dyn Bus) {
+        self.h_ctr += 1;
+        self.ma += 1;
+        if self.h_ctr == self.reg[CRTC_H_DISPLAYED] as RegT {
+            self.h_de = false;
+        }
+
+        // horizontal sync (a width of 0 means no hsync)
+        if self.hs {
+            self.hs_ctr += 1;
+            if self.hs_ctr >= self.hsync_width() {
+                self.hs = false;
+                bus.crtc_hsync(self.id, false);
+            }
+        }
+        if self.h_ctr == self.reg[CRTC_H_SYNC_POS] as RegT && self.hsync_width() > 0 {
+            self.hs = true;
+            self.hs_ctr = 0;
+            bus.crtc_hsync(self.id, true);
+        }
+
+        // end of scanline?
+        if self.h_ctr > self.reg[CRTC_H_TOTAL] as RegT {
+            self.h_ctr = 0;
+            self.h_de = true;
+            self.end_of_scanline(bus);
+        }
+    }
+
+    fn hsync_width(&self) -> RegT {
+        (self.reg[CRTC_SYNC_WIDTHS] & 0x0F) as RegT
+    }
+
+    fn vsync_width(&self) -> RegT {
+        // a vsync width of 0 means 16 scanlines
+        match self.reg[CRTC_SYNC_WIDTHS] >> 4 {
+            0 => 16,
+            w => w as RegT,
+        }
+    }
+
+    fn end_of_scanline(&mut self, bus: &dyn Bus) {
+        if let Some((x, y)) = bus.lightpen() {
+            if y == self.scanline {
+                let addr = (self.ma_row + x) & 0x3FFF;
+                self.reg[CRTC_LIGHTPEN_HI] = (addr >> 8) as u8;
+                self.reg[CRTC_LIGHTPEN_LO] = addr as u8;
+            }
+        }
+        self.scanline += 1;
+
+        // vertical sync is counted in scanlines
+        if self.vs {
+            self.vs_ctr += 1;
+            if self.vs_ctr >= self.vsync_width() {
+                self.vs = false;
+                bus.crtc_vsync(self.id, false);
+            }
+        }
+
+        if self.in_adjust {
+            self.adj_ctr += 1;
+            if self.adj_ctr >= self.reg[CRTC_V_TOTAL_ADJUST] as RegT {
+                self.new_frame(bus);
+            }
+        } else if self.ra >= self.reg[CRTC_MAX_SCANLINE_ADDR] as RegT {
+            // end of character row
+            self.ra = 0;
+            self.row_ctr += 1;
+            self.ma_row += self.reg[CRTC_H_DISPLAYED] as RegT;
+            if self.row_ctr > self.reg[CRTC_V_TOTAL] as RegT {
+                if self.reg[CRTC_V_TOTAL_ADJUST] > 0 {
+                    self.in_adjust = true;
+                    self.adj_ctr = 0;
+                } else {
+                    self.new_frame(bus);
+                }
+            } else {
+                self.check_vertical(bus);
+            }
+        } else {
+            self.ra += 1;
+        }
+        self.ma = self.ma_row;
+    }
+
+    fn new_frame(&mut self, bus: &dyn Bus) {
+        self.in_adjust = false;
+        self.scanline = 0;
+        self.ra = 0;
+        self.row_ctr = 0;
+        self.v_de = true;
+        self.ma_row = ((self.reg[CRTC_START_ADDR_HI] as RegT) << 8) |
+                      self.reg[CRTC_START_ADDR_LO] as RegT;
+        self.check_vertical(bus);
+    }
+
+    fn check_vertical(&mut self, bus: &dyn Bus) {
+        if self.row_ctr == self.reg[CRTC_V_DISPLAYED] as RegT {
+            self.v_de = false;
+        }
+        if self.row_ctr == self.reg[CRTC_V_SYNC_POS] as RegT && !self.vs {
+            self.vs = true;
+            self.vs_ctr = 0;
+            bus.crtc_vsync(self.id, true);
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use Bus;
+
+    struct TestBus {
+        hsyncs: Cell<usize>,
+        vsyncs: Cell<usize>,
+    }
+    impl Bus for TestBus {
+        fn crtc_hsync(&self, _: usize, active: bool) {
+            if active {
+                self.hsyncs.set(self.hsyncs.get() + 1);
+            }
+        }
+        fn crtc_vsync(&self, _: usize, active: bool) {
+            if active {
+                self.vsyncs.set(self.vsyncs.get() + 1);
+            }
+        }
+    }
+
+    // the Amstrad CPC default CRTC setup
+    const CPC_REGS: [RegT; 14] = [63, 40, 46, 0x8E, 38, 0, 25, 30, 0, 7, 0, 0, 0x30, 0x00];
+
+    fn cpc_crtc() -> CRTC {
+        let mut crtc = CRTC::new(0);
+        for (i, val) in CPC_REGS.iter().enumerate() {
+            crtc.select(i as RegT);
+            crtc.write(*val);
+        }
+        crtc
+    }
+
+    #[test]
+    fn registers() {
+        let mut crtc = CRTC::new(0);
+        crtc.select(CRTC_V_TOTAL as RegT);
+        crtc.write(0xFF);
+        assert_eq!(crtc.reg(CRTC_V_TOTAL), 0x7F);
+        assert_eq!(crtc.read(), 0);
+        crtc.select(CRTC_CURSOR_HI as RegT);
+        crtc.write(0x12);
+        assert_eq!(crtc.read(), 0x12);
+    }
+
+    #[test]
+    fn frame_timing() {
+        let bus = TestBus {
+            hsyncs: Cell::new(0),
+            vsyncs: Cell::new(0),
+        };
+        let mut crtc = cpc_crtc();
+        crtc.reset();
+
+        // first scanline: 40 characters visible, starting at 0
+        assert!(crtc.de());
+        for i in 1..64 {
+            crtc.tick(&bus);
+            assert_eq!(crtc.de(), i < 40);
+            assert_eq!(crtc.hs(), (46..46 + 14).contains(&i));
+        }
+        crtc.tick(&bus);
+        assert_eq!(crtc.ra(), 1);
+        assert_eq!(crtc.ma(), 0);
+        assert_eq!(bus.hsyncs.get(), 1);
+
+        // run to the end of the first character row
+        for _ in 0..(64 * 7) {
+            crtc.tick(&bus);
+        }
+        assert_eq!(crtc.ra(), 0);
+        assert_eq!(crtc.ma(), 40);
+
+        // a full frame is 39 rows * 8 scanlines * 64 characters
+        for _ in 0..(64 * 8 * 38) {
+            crtc.tick(&bus);
+        }
+        assert_eq!(bus.hsyncs.get(), 39 * 8);
+        assert_eq!(bus.vsyncs.get(), 1);
+        assert_eq!(crtc.ra(), 0);
+        assert_eq!(crtc.ma(), 0x3000);
+        assert!(crtc.de());
+    }
+
+    struct PenBus {
+        pos: (RegT, RegT),
+    }
+    impl Bus for PenBus {
+        fn lightpen(&self) -> Option<(RegT, RegT)> {
+            Some(self.pos)
+        }
+    }
+
+    #[test]
+    fn lightpen() {
+        // pen at character column 5 of scanline 17 (character row 2)
+        let bus = PenBus { pos: (5, 17) };
+        let mut crtc = cpc_crtc();
+        crtc.reset();
+        for _ in 0..(64 * 17) {
+            crtc.tick(&bus);
+        }
+        assert_eq!(crtc.scanline(), 17);
+        assert_eq!(crtc.reg(CRTC_LIGHTPEN_LO), 0);
+        for _ in 0..64 {
+            crtc.tick(&bus);
+        }
+        assert_eq!(crtc.scanline(), 18);
+        // the first frame after reset starts at address 0
+        crtc.select(CRTC_LIGHTPEN_HI as RegT);
+        assert_eq!(crtc.read(), 0);
+        crtc.select(CRTC_LIGHTPEN_LO as RegT);
+        assert_eq!(crtc.read(), 2 * 40 + 5);
+    }
+}
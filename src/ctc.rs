@@ -1,6 +1,9 @@
 #![allow(unused)]
 use RegT;
-use bus::Bus;
+use std::collections::HashSet;
+use bus::{Bus, Reset, ResetKind};
+use iotrace::IoDir;
+use savestate::{SaveState, ChunkWriter, ChunkReader, StateError};
 
 /// CTC channel 0
 pub const CTC_0: usize = 0;
@@ -39,16 +42,20 @@ pub const CTC_CONTROL_BIT: u8 = 1 << 0;
 pub const CTC_CONTROL_WORD: u8 = CTC_CONTROL_BIT;
 pub const CTC_CONTROL_VECTOR: u8 = 0;
 
-#[derive(Clone,Copy)]
+#[derive(Clone,Copy,Debug)]
 struct Channel {
     pub control: u8,
     pub constant: u8,
     pub down_counter: RegT,
     pub waiting_for_trigger: bool,
     pub int_vector: u8,
+    pub int_requested: bool,
+    pub int_under_service: bool,
+    pub trg_level: bool,
 }
 
 /// Z80 CTC emulation
+#[derive(Debug)]
 pub struct CTC {
     id: usize, // a CTC ID for systems with multiple CTCs
     chn: [Channel; NUM_CHANNELS],
@@ -65,6 +72,9 @@ impl CTC {
                 down_counter: 0,
                 waiting_for_trigger: false,
                 int_vector: 0,
+                int_requested: false,
+                int_under_service: false,
+                trg_level: false,
             }; NUM_CHANNELS],
         }
     }
@@ -76,6 +86,8 @@ impl CTC {
             chn.constant = 0;
             chn.down_counter = 0;
             chn.waiting_for_trigger = false;
+            chn.int_requested = false;
+            chn.int_under_service = false;
         }
     }
 
@@ -138,6 +150,30 @@ impl CTC {
         }
     }
 
+    /// externally provided CLK/TRG input level
+    ///
+    /// Only the active edge selected by the CTC_EDGE_BIT in the channel's
+    /// control word has an effect: in counter mode it decrements the
+    /// counter, in timer mode it starts a timer which is waiting for
+    /// a pulse trigger (CTC_TRIGGER_PULSE). Calling this with an unchanged
+    /// level does nothing.
+    pub fn trigger_edge(&mut self, bus: &dyn Bus, chn: usize, level: bool) {
+        let c = &mut self.chn[chn];
+        if c.trg_level == level {
+            return;
+        }
+        c.trg_level = level;
+        let rising = (c.control & CTC_EDGE_BIT) == CTC_EDGE_RISING;
+        if level != rising || (c.control & (CTC_RESET | CTC_CONSTANT_FOLLOWS)) != 0 {
+            return;
+        }
+        if (c.control & CTC_MODE_BIT) == CTC_MODE_COUNTER {
+            self.trigger(bus, chn);
+        } else {
+            c.waiting_for_trigger = false;
+        }
+    }
+
     /// update the CTC channel timers
     #[inline(always)]
     pub fn update_timers(&mut self, bus: &dyn Bus, cycles: i64) {
@@ -156,6 +192,121 @@ impl CTC {
         }
     }
 
+    /// number of CPU cycles between two zero counts of a running timer channel
+    ///
+    /// Returns None if the channel isn't running in timer mode, see
+    /// Clock::frequency() to convert the period to a frequency.
+    pub fn timer_period(&self, chn: usize) -> Option<i64> {
+        let c = &self.chn[chn];
+        if (c.control & (CTC_RESET | CTC_CONSTANT_FOLLOWS)) == 0 &&
+           (c.control & CTC_MODE_BIT) == CTC_MODE_TIMER {
+            Some(CTC::down_counter_initial(c) as i64)
+        } else {
+            None
+        }
+    }
+
+    /// number of cycles until the next timer channel reaches zero
+    ///
+    /// Returns None if no channel is running in timer mode, this can be
+    /// used to schedule a timer event in a Scheduler instead of calling
+    /// update_timers() after each instruction, or to fast-forward a
+    /// halted CPU (see CPU::skip_halt()).
+    pub fn next_event(&self) -> Option<i64> {
+        let mut next: Option<i64> = None;
+        for c in &self.chn {
+            if (c.control & (CTC_RESET | CTC_CONSTANT_FOLLOWS)) == 0 &&
+               (c.control & CTC_MODE_BIT) == CTC_MODE_TIMER && !c.waiting_for_trigger {
+                let cycles = c.down_counter as i64;
+                next = Some(match next {
+                    Some(n) if n < cycles => n,
+                    _ => cycles,
+                });
+            }
+        }
+        next
+    }
+
+    /// control word of a channel (CTC_* bits)
+    pub fn control(&self, chn: usize) -> u8 {
+        self.chn[chn].control
+    }
+
+    /// time constant of a channel
+    pub fn constant(&self, chn: usize) -> u8 {
+        self.chn[chn].constant
+    }
+
+    /// raw down counter value of a channel (in timer mode including the prescaler)
+    pub fn down_counter(&self, chn: usize) -> RegT {
+        self.chn[chn].down_counter
+    }
+
+    /// interrupt vector of a channel
+    pub fn int_vector(&self, chn: usize) -> u8 {
+        self.chn[chn].int_vector
+    }
+
+    /// true if a timer channel waits for the trigger pulse to start
+    pub fn waiting_for_trigger(&self, chn: usize) -> bool {
+        self.chn[chn].waiting_for_trigger
+    }
+
+    /// true if a channel has requested an interrupt which hasn't been acknowledged yet
+    pub fn int_requested(&self, chn: usize) -> bool {
+        self.chn[chn].int_requested
+    }
+
+    /// true if a channel's interrupt has been acknowledged and the CPU hasn't executed RETI yet
+    pub fn int_under_service(&self, chn: usize) -> bool {
+        self.chn[chn].int_under_service
+    }
+
+    /// true if a channel's interrupt request is blocked by the interrupt
+    /// of the same or a higher-priority channel (lower channel number) being serviced
+    fn int_blocked(&self, chn: usize) -> bool {
+        self.chn[..chn + 1].iter().any(|c| c.int_under_service)
+    }
+
+    /// CPU acknowledges the CTC interrupt, returns the interrupt vector
+    ///
+    /// The highest-priority requesting channel goes into 'under service'
+    /// state until the CPU executes a RETI. Returns None if no channel
+    /// is requesting an interrupt.
+    pub fn irq_ack(&mut self) -> Option<RegT> {
+        for chn in 0..NUM_CHANNELS {
+            if self.int_blocked(chn) {
+                break;
+            }
+            let c = &mut self.chn[chn];
+            if c.int_requested {
+                c.int_requested = false;
+                c.int_under_service = true;
+                return Some(c.int_vector as RegT);
+            }
+        }
+        None
+    }
+
+    /// CPU has executed a RETI, ends the interrupt service of the highest-priority channel
+    ///
+    /// Interrupt requests of lower-priority channels which have been
+    /// held back during the interrupt service are now forwarded to
+    /// Bus::ctc_irq(). Returns the channel whose service has ended.
+    pub fn irq_reti(&mut self, bus: &dyn Bus) -> Option<usize> {
+        let chn = self.chn.iter().position(|c| c.int_under_service)?;
+        self.chn[chn].int_under_service = false;
+        for i in chn..NUM_CHANNELS {
+            if self.int_blocked(i) {
+                break;
+            }
+            if self.chn[i].int_requested {
+                bus.ctc_irq(self.id, i, self.chn[i].int_vector as RegT);
+            }
+        }
+        Some(chn)
+    }
+
     /// get prescaler value (256 or 16) based on prescaler bit
     fn prescale(ctrl: u8) -> RegT {
         if (ctrl & CTC_PRESCALER_BIT) == CTC_PRESCALER_256 {
@@ -179,14 +330,105 @@ impl CTC {
     }
 
     /// trigger interrupt and/or callback when downcounter reaches 0
-    fn down_counter_trigger(&self, bus: &dyn Bus, chn: usize) {
+    ///
+    /// While an interrupt of the same or a higher-priority channel
+    /// is being serviced, the interrupt request is held back until RETI.
+    fn down_counter_trigger(&mut self, bus: &dyn Bus, chn: usize) {
         if (self.chn[chn].control & CTC_INTERRUPT_BIT) == CTC_INTERRUPT_ENABLED {
-            bus.ctc_irq(self.id, chn, self.chn[chn].int_vector as RegT);
+            self.chn[chn].int_requested = true;
+            if !self.int_blocked(chn) {
+                bus.ctc_irq(self.id, chn, self.chn[chn].int_vector as RegT);
+            }
         }
         bus.ctc_zero(chn, self);
     }
 }
 
+/// create an IoTrace decoder for CTC channel ports
+///
+/// The decoder tracks the 'time constant follows' state per port, so
+/// it must see all writes to the CTC.
+pub fn ctc_decoder() -> impl FnMut(IoDir, RegT, RegT) -> Option<String> {
+    let mut constant_follows: HashSet<RegT> = HashSet::new();
+    move |dir, port, val| {
+        if dir == IoDir::In {
+            return Some("read counter".to_string());
+        }
+        let v = val as u8;
+        if constant_follows.remove(&port) {
+            Some(format!("time constant {:02X}h", v))
+        } else if (v & CTC_CONTROL_BIT) == 0 {
+            Some(format!("int vector {:02X}h", v))
+        } else {
+            let edge = if (v & CTC_EDGE_BIT) != 0 { "rising edge" } else { "falling edge" };
+            let mut parts = Vec::new();
+            if (v & CTC_MODE_BIT) == CTC_MODE_COUNTER {
+                parts.push("counter");
+                parts.push(edge);
+            } else {
+                parts.push("timer");
+                parts.push(if (v & CTC_PRESCALER_BIT) != 0 { "prescale 256" } else { "prescale 16" });
+                if (v & CTC_TRIGGER_BIT) != 0 {
+                    parts.push("pulse trigger");
+                    parts.push(edge);
+                }
+            }
+            parts.push(if (v & CTC_INTERRUPT_BIT) != 0 { "int on" } else { "int off" });
+            if (v & CTC_CONSTANT_FOLLOWS) != 0 {
+                constant_follows.insert(port);
+                parts.push("constant follows");
+            }
+            if (v & CTC_RESET) != 0 {
+                parts.push("reset");
+            }
+            Some(format!("control {:02X}h = {}", v, parts.join(", ")))
+        }
+    }
+}
+
+impl Reset for CTC {
+    /// a cold reset also clears the interrupt vectors
+    fn system_reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            *self = CTC::new(self.id);
+        }
+        self.reset();
+    }
+}
+
+
+impl SaveState for CTC {
+    const STATE_VERSION: u16 = 1;
+
+    fn save_state(&self, w: &mut ChunkWriter) {
+        for chn in &self.chn {
+            w.u8(chn.control);
+            w.u8(chn.constant);
+            w.u32(chn.down_counter as u32);
+            w.bool(chn.waiting_for_trigger);
+            w.u8(chn.int_vector);
+            w.bool(chn.int_requested);
+            w.bool(chn.int_under_service);
+            w.bool(chn.trg_level);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut ChunkReader) -> Result<(), StateError> {
+        let mut chns = self.chn;
+        for chn in &mut chns {
+            chn.control = r.u8()?;
+            chn.constant = r.u8()?;
+            chn.down_counter = r.u32()? as RegT;
+            chn.waiting_for_trigger = r.bool()?;
+            chn.int_vector = r.u8()?;
+            chn.int_requested = r.bool()?;
+            chn.int_under_service = r.bool()?;
+            chn.trg_level = r.bool()?;
+        }
+        self.chn = chns;
+        Ok(())
+    }
+}
 #[cfg(test)]
 mod test {
     use std::cell::RefCell;
@@ -383,4 +625,167 @@ mod test {
     fn ctc_timer_with_irq() {
         ctc_timer_test(true);
     }
+
+    #[test]
+    fn next_event() {
+        let mut ctc = CTC::new(0);
+        let bus = TestBus::new();
+        assert_eq!(ctc.next_event(), None);
+        let ctrl = (CTC_CONTROL_WORD | CTC_MODE_TIMER | CTC_PRESCALER_16 | CTC_CONSTANT_FOLLOWS) as RegT;
+        ctc.write(&bus, CTC_1, ctrl);
+        assert_eq!(ctc.next_event(), None);
+        ctc.write(&bus, CTC_1, 0x20);
+        assert_eq!(ctc.next_event(), Some(0x200));
+        ctc.write(&bus, CTC_2, ctrl);
+        ctc.write(&bus, CTC_2, 0x10);
+        assert_eq!(ctc.next_event(), Some(0x100));
+        ctc.update_timers(&bus, 0xF0);
+        assert_eq!(ctc.next_event(), Some(0x10));
+        assert!(!bus.state.borrow().ctc_zero_called);
+        ctc.update_timers(&bus, 0x10);
+        assert!(bus.state.borrow().ctc_zero_called);
+        assert_eq!(ctc.next_event(), Some(0x100));
+
+        // counter mode channels don't count
+        ctc.write(&bus, CTC_3, (CTC_CONTROL_WORD | CTC_MODE_COUNTER | CTC_CONSTANT_FOLLOWS) as RegT);
+        ctc.write(&bus, CTC_3, 0x01);
+        assert_eq!(ctc.next_event(), Some(0x100));
+    }
+
+    #[test]
+    fn int_service() {
+        let mut ctc = CTC::new(0);
+        let bus = TestBus::new();
+        let ctrl = (CTC_CONTROL_WORD | CTC_INTERRUPT_ENABLED | CTC_MODE_COUNTER |
+                    CTC_CONSTANT_FOLLOWS) as RegT;
+        ctc.write(&bus, CTC_0, 0xE0);
+        for chn in 0..2 {
+            ctc.write(&bus, chn, ctrl);
+            ctc.write(&bus, chn, 1);
+        }
+        assert_eq!(ctc.irq_ack(), None);
+
+        // channel 1 fires and is acknowledged
+        ctc.trigger(&bus, CTC_1);
+        assert!(ctc.int_requested(CTC_1));
+        assert_eq!(ctc.irq_ack(), Some(0xE2));
+        assert!(!ctc.int_requested(CTC_1));
+        assert!(ctc.int_under_service(CTC_1));
+
+        // higher-priority channel 0 may interrupt the service routine,
+        // channel 1 itself is held back
+        ctc.trigger(&bus, CTC_0);
+        ctc.trigger(&bus, CTC_1);
+        assert_eq!(bus.state.borrow().ctc_irq_counter, 2);
+        assert!(ctc.int_requested(CTC_1));
+        assert_eq!(ctc.irq_ack(), Some(0xE0));
+        assert!(ctc.int_under_service(CTC_0));
+
+        // first RETI ends channel 0, channel 1 is still under service
+        assert_eq!(ctc.irq_reti(&bus), Some(CTC_0));
+        assert_eq!(bus.state.borrow().ctc_irq_counter, 2);
+        assert_eq!(ctc.irq_ack(), None);
+
+        // second RETI ends channel 1 and forwards the held request
+        assert_eq!(ctc.irq_reti(&bus), Some(CTC_1));
+        assert_eq!(bus.state.borrow().ctc_irq_counter, 3);
+        assert_eq!(ctc.irq_ack(), Some(0xE2));
+        assert_eq!(ctc.irq_reti(&bus), Some(CTC_1));
+        assert_eq!(ctc.irq_reti(&bus), None);
+    }
+
+    #[test]
+    fn trigger_edge() {
+        let mut ctc = CTC::new(0);
+        let bus = TestBus::new();
+
+        // counter on falling edge
+        ctc.write(&bus, CTC_0, (CTC_CONTROL_WORD | CTC_MODE_COUNTER | CTC_EDGE_FALLING |
+                                CTC_CONSTANT_FOLLOWS) as RegT);
+        ctc.write(&bus, CTC_0, 0x10);
+        ctc.trigger_edge(&bus, CTC_0, true);
+        assert_eq!(ctc.read(CTC_0), 0x10);
+        ctc.trigger_edge(&bus, CTC_0, false);
+        assert_eq!(ctc.read(CTC_0), 0x0F);
+        ctc.trigger_edge(&bus, CTC_0, false);
+        assert_eq!(ctc.read(CTC_0), 0x0F);
+
+        // counter on rising edge
+        ctc.write(&bus, CTC_1, (CTC_CONTROL_WORD | CTC_MODE_COUNTER | CTC_EDGE_RISING |
+                                CTC_CONSTANT_FOLLOWS) as RegT);
+        ctc.write(&bus, CTC_1, 0x02);
+        for _ in 0..2 {
+            ctc.trigger_edge(&bus, CTC_1, true);
+            ctc.trigger_edge(&bus, CTC_1, false);
+        }
+        assert_eq!(bus.state.borrow().ctc_zero_counter, 1);
+        assert_eq!(ctc.read(CTC_1), 0x02);
+
+        // timer waiting for a rising edge pulse trigger
+        ctc.write(&bus, CTC_2, (CTC_CONTROL_WORD | CTC_MODE_TIMER | CTC_PRESCALER_16 |
+                                CTC_EDGE_RISING | CTC_TRIGGER_PULSE |
+                                CTC_CONSTANT_FOLLOWS) as RegT);
+        ctc.write(&bus, CTC_2, 0x10);
+        assert!(ctc.chn[CTC_2].waiting_for_trigger);
+        ctc.update_timers(&bus, 16);
+        assert_eq!(ctc.read(CTC_2), 0x10);
+        ctc.trigger_edge(&bus, CTC_2, false);
+        assert!(ctc.chn[CTC_2].waiting_for_trigger);
+        ctc.trigger_edge(&bus, CTC_2, true);
+        assert!(!ctc.chn[CTC_2].waiting_for_trigger);
+        ctc.update_timers(&bus, 16);
+        assert_eq!(ctc.read(CTC_2), 0x0F);
+    }
+
+    #[test]
+    fn introspection() {
+        let mut ctc = CTC::new(0);
+        let bus = TestBus::new();
+        let ctrl = CTC_CONTROL_WORD | CTC_MODE_TIMER | CTC_PRESCALER_16 | CTC_TRIGGER_PULSE;
+        ctc.write(&bus, CTC_0, 0xE0);
+        ctc.write(&bus, CTC_1, (ctrl | CTC_CONSTANT_FOLLOWS) as RegT);
+        ctc.write(&bus, CTC_1, 0x20);
+        assert_eq!(ctc.control(CTC_1), ctrl);
+        assert_eq!(ctc.control(CTC_2), CTC_RESET);
+        assert_eq!(ctc.constant(CTC_1), 0x20);
+        assert_eq!(ctc.down_counter(CTC_1), 0x200);
+        assert_eq!(ctc.int_vector(CTC_3), 0xE6);
+        assert!(ctc.waiting_for_trigger(CTC_1));
+        assert!(!ctc.waiting_for_trigger(CTC_0));
+    }
+
+    #[test]
+    fn timer_period() {
+        let mut ctc = CTC::new(0);
+        let bus = TestBus::new();
+        assert_eq!(ctc.timer_period(CTC_0), None);
+        ctc.write(&bus, CTC_0, (CTC_CONTROL_WORD | CTC_MODE_TIMER | CTC_PRESCALER_256 |
+                                CTC_CONSTANT_FOLLOWS) as RegT);
+        assert_eq!(ctc.timer_period(CTC_0), None);
+        ctc.write(&bus, CTC_0, 0x10);
+        assert_eq!(ctc.timer_period(CTC_0), Some(0x1000));
+        // a time constant of 0 means 256
+        ctc.write(&bus, CTC_1, (CTC_CONTROL_WORD | CTC_MODE_TIMER | CTC_PRESCALER_16 |
+                                CTC_CONSTANT_FOLLOWS) as RegT);
+        ctc.write(&bus, CTC_1, 0);
+        assert_eq!(ctc.timer_period(CTC_1), Some(0x1000));
+        ctc.write(&bus, CTC_2, (CTC_CONTROL_WORD | CTC_MODE_COUNTER | CTC_CONSTANT_FOLLOWS) as RegT);
+        ctc.write(&bus, CTC_2, 0x10);
+        assert_eq!(ctc.timer_period(CTC_2), None);
+    }
+
+    #[test]
+    fn decoder() {
+        let mut decode = ctc_decoder();
+        assert_eq!(decode(IoDir::Out, 0x80, 0xA7).unwrap(),
+                   "control A7h = timer, prescale 256, int on, constant follows, reset");
+        assert_eq!(decode(IoDir::Out, 0x81, 0x00).unwrap(), "int vector 00h");
+        assert_eq!(decode(IoDir::Out, 0x80, 0x00).unwrap(), "time constant 00h");
+        assert_eq!(decode(IoDir::Out, 0x82, 0x5D).unwrap(),
+                   "control 5Dh = counter, rising edge, int off, constant follows");
+        assert_eq!(decode(IoDir::Out, 0x83, 0x09).unwrap(),
+                   "control 09h = timer, prescale 16, pulse trigger, falling edge, int off");
+        assert_eq!(decode(IoDir::In, 0x82, 0x12).unwrap(), "read counter");
+        assert_eq!(decode(IoDir::Out, 0x82, 0x12).unwrap(), "time constant 12h");
+    }
 }
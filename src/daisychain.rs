@@ -1,7 +1,7 @@
 #![allow(unused)]
 use std::cell::RefCell;
 use RegT;
-use bus::Bus;
+use bus::{Bus, Reset, ResetKind};
 
 const MAX_CONTROLLERS: usize = 16;
 
@@ -11,6 +11,7 @@ pub struct Controller {
     pub int_enabled: bool,
     pub int_requested: bool,
     pub int_pending: bool,
+    pub int_held: bool,
     pub int_vec: u8,
 }
 
@@ -20,6 +21,7 @@ impl Controller {
             int_enabled: true,
             int_requested: false,
             int_pending: false,
+            int_held: false,
             int_vec: 0,
         }
     }
@@ -27,6 +29,7 @@ impl Controller {
         self.int_enabled = true;
         self.int_requested = false;
         self.int_pending = false;
+        self.int_held = false;
         self.int_vec = 0;
     }
 }
@@ -54,11 +57,14 @@ impl Daisychain {
     }
 
     /// request an interrupt from an interrupt controller, called by bus
+    ///
+    /// If the controller is disabled because a higher-priority interrupt
+    /// is being serviced, the request is held back until a RETI enables
+    /// the controller again.
     pub fn irq(&mut self, bus: &dyn Bus, ctrl_id: usize, vec: u8) {
         if self.ctrl[ctrl_id].int_enabled {
             {
                 let ctrl = &mut self.ctrl[ctrl_id];
-                assert!(!ctrl.int_pending);
                 ctrl.int_enabled = false;
                 ctrl.int_requested = true;
                 ctrl.int_vec = vec;
@@ -69,10 +75,14 @@ impl Daisychain {
             for i in ctrl_id + 1..self.num_ctrl {
                 self.ctrl[i].int_enabled = false;
             }
+        } else if !self.ctrl[ctrl_id].int_requested {
+            let ctrl = &mut self.ctrl[ctrl_id];
+            ctrl.int_held = true;
+            ctrl.int_vec = vec;
         }
     }
 
-    /// CPU acknowledges interrupt request, return the interrupt vector
+    /// CPU acknowledges interrupt request, return the interrupt vector (0xFF if none pending)
     pub fn irq_ack(&mut self) -> RegT {
         // find the interrupt controller which issued the request
         // and return it's interrupt vector.
@@ -85,11 +95,15 @@ impl Daisychain {
                 return ctrl.int_vec as RegT;
             }
         }
-        panic!("irq_ack() called without any interrupt pending!")
+        // no interrupt pending, this is what the CPU reads from the idle data bus
+        0xFF
     }
 
     /// CPU executes a RETI, this enabled interrupts on downstream controllers
-    pub fn irq_reti(&mut self) {
+    ///
+    /// Requests which have been held back on a now enabled controller
+    /// are forwarded to the CPU.
+    pub fn irq_reti(&mut self, bus: &dyn Bus) {
         let mut is_downstream = false;
         for ctrl in self.ctrl.iter_mut() {
             ctrl.int_enabled = true;
@@ -104,6 +118,19 @@ impl Daisychain {
                 is_downstream = true;
             }
         }
+        for i in 0..self.num_ctrl {
+            if self.ctrl[i].int_held && self.ctrl[i].int_enabled {
+                self.ctrl[i].int_held = false;
+                let vec = self.ctrl[i].int_vec;
+                self.irq(bus, i, vec);
+            }
+        }
+    }
+}
+
+impl Reset for Daisychain {
+    fn system_reset(&mut self, _kind: ResetKind) {
+        self.reset();
     }
 }
 
@@ -196,9 +223,11 @@ mod test {
             assert!(!dev0.int_enabled);
             assert!(!dev0.int_requested);
             assert!(!dev0.int_pending);
-            assert_eq!(dev0.int_vec, 0x00);
-            assert!(!state.irq_received);
+            assert!(dev0.int_held);
+            assert_eq!(dev0.int_vec, 0x10);
+            assert!(!state.irq_cpu_called);
         }
+        daisy.ctrl[DEV0].int_held = false;
         // test with interrupt enabled
         daisy.ctrl[DEV0].int_enabled = true;
         daisy.irq(&bus, DEV0, 0x10);
@@ -215,5 +244,32 @@ mod test {
             assert!(!dev1.int_enabled);
             assert!(!dev2.int_enabled);
         }
+        assert_eq!(daisy.irq_ack(), 0x10);
+        // without a pending request the CPU reads 0xFF
+        assert_eq!(daisy.irq_ack(), 0xFF);
+    }
+
+    #[test]
+    fn held_irq() {
+        let bus = TestBus::new();
+        let mut daisy = bus.daisy.borrow_mut();
+        // DEV0 interrupt is being serviced
+        daisy.irq(&bus, DEV0, 0x10);
+        assert_eq!(daisy.irq_ack(), 0x10);
+        bus.state.borrow_mut().irq_cpu_called = false;
+        // DEV1 request is held back until RETI
+        daisy.irq(&bus, DEV1, 0x20);
+        assert!(daisy.ctrl[DEV1].int_held);
+        assert!(!daisy.ctrl[DEV1].int_requested);
+        assert!(!bus.state.borrow().irq_cpu_called);
+        daisy.irq_reti(&bus);
+        assert!(!daisy.ctrl[DEV0].int_pending);
+        assert!(!daisy.ctrl[DEV1].int_held);
+        assert!(daisy.ctrl[DEV1].int_requested);
+        assert!(!daisy.ctrl[DEV2].int_enabled);
+        assert!(bus.state.borrow().irq_cpu_called);
+        assert_eq!(daisy.irq_ack(), 0x20);
+        daisy.irq_reti(&bus);
+        assert!(daisy.ctrl.iter().all(|c| c.int_enabled && !c.int_pending));
     }
 }
@@ -0,0 +1,658 @@
+use std::fmt;
+use RegT;
+use registers::{Reg8, Reg16, Im};
+
+/// condition of conditional jumps, calls and returns
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Cond {
+    NZ, Z, NC, C, PO, PE, P, M,
+}
+
+/// 8-bit ALU operation (ADD A,s; ADC A,s; SUB s; SBC A,s; AND s; XOR s; OR s; CP s)
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum AluOp {
+    Add, Adc, Sub, Sbc, And, Xor, Or, Cp,
+}
+
+/// rotate and shift operation of the CB instruction group
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum RotOp {
+    Rlc, Rrc, Rl, Rr, Sla, Sra, Sll, Srl,
+}
+
+/// 8-bit instruction operand
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Operand {
+    /// an 8-bit register
+    Reg(Reg8),
+    /// an immediate value
+    Imm(RegT),
+    /// memory at the address in a 16-bit register: (HL), (BC), (DE)
+    Ind(Reg16),
+    /// memory at IX or IY plus a signed displacement: (IX+d), (IY+d)
+    Idx(Reg16, RegT),
+    /// memory at an absolute address: (nn)
+    Abs(RegT),
+}
+
+/// a decoded Z80 instruction
+///
+/// Instructions are decoded by **decode()**, and executed by
+/// **CPU::execute()**. The same decoder is used by the opcode statistics,
+/// the machine cycle trace, the block cache and the JIT, while
+/// CPU::step() runs a direct-dispatch interpreter on the opcode bytes
+/// for speed. The DD and FD prefixes are folded into the
+/// operands (IX/IY, IXH/IXL/IYH/IYL and (IX+d)/(IY+d)), a DD or FD
+/// prefix which doesn't change the following instruction is decoded
+/// as a separate **Prefix** instruction. The CB-prefixed rotate, shift,
+/// RES and SET instructions on (IX+d) and (IY+d) have an optional
+/// register for the undocumented copy of the result.
+///
+/// The Display implementation produces Zilog mnemonics with hex
+/// numbers, relative jumps are shown relative to the instruction
+/// address ($).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{decode, Instruction, Operand, AluOp, Reg8, Reg16};
+///
+/// // ADD A,(IX+5)
+/// let (inst, len) = decode(&[0xDD, 0x86, 0x05]);
+/// assert_eq!(inst, Instruction::Alu(AluOp::Add, Operand::Idx(Reg16::IX, 5)));
+/// assert_eq!(len, 3);
+/// assert_eq!(inst.to_string(), "ADD A,(IX+05h)");
+///
+/// // LD A,B
+/// let (inst, len) = decode(&[0x78]);
+/// assert_eq!(inst, Instruction::Ld(Operand::Reg(Reg8::A), Operand::Reg(Reg8::B)));
+/// assert_eq!(len, 1);
+/// ```
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Instruction {
+    /// NOP
+    Nop,
+    /// a DD or FD prefix which has no effect on the following instruction
+    Prefix(RegT),
+    /// HALT
+    Halt,
+    /// 8-bit loads, including LD A,I; LD A,R; LD I,A and LD R,A
+    Ld(Operand, Operand),
+    /// LD rr,nn
+    Ld16(Reg16, RegT),
+    /// LD (nn),HL; LD (nn),IX; LD (nn),IY
+    LdToMem16(RegT, Reg16),
+    /// LD HL,(nn); LD IX,(nn); LD IY,(nn)
+    LdFromMem16(Reg16, RegT),
+    /// LD (nn),rr (ED-prefixed)
+    EdLdToMem16(RegT, Reg16),
+    /// LD rr,(nn) (ED-prefixed)
+    EdLdFromMem16(Reg16, RegT),
+    /// LD SP,HL; LD SP,IX; LD SP,IY
+    LdSP(Reg16),
+    /// PUSH rr
+    Push(Reg16),
+    /// POP rr
+    Pop(Reg16),
+    /// EX AF,AF'
+    ExAF,
+    /// EXX
+    Exx,
+    /// EX DE,HL
+    ExDEHL,
+    /// EX (SP),HL; EX (SP),IX; EX (SP),IY
+    ExSP(Reg16),
+    /// 8-bit arithmetic and logic
+    Alu(AluOp, Operand),
+    /// INC s
+    Inc(Operand),
+    /// DEC s
+    Dec(Operand),
+    /// INC rr
+    Inc16(Reg16),
+    /// DEC rr
+    Dec16(Reg16),
+    /// ADD HL,rr; ADD IX,rr; ADD IY,rr
+    Add16(Reg16, Reg16),
+    /// ADC HL,rr
+    Adc16(Reg16),
+    /// SBC HL,rr
+    Sbc16(Reg16),
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    Neg,
+    /// rotates and shifts (with the undocumented copy register)
+    Rot(RotOp, Operand, Option<Reg8>),
+    /// BIT b,s
+    Bit(RegT, Operand),
+    /// RES b,s (with the undocumented copy register)
+    Res(RegT, Operand, Option<Reg8>),
+    /// SET b,s (with the undocumented copy register)
+    Set(RegT, Operand, Option<Reg8>),
+    Rld,
+    Rrd,
+    /// JP nn; JP cc,nn
+    Jp(Option<Cond>, RegT),
+    /// JP (HL); JP (IX); JP (IY)
+    JpInd(Reg16),
+    /// JR d; JR cc,d (with the signed displacement)
+    Jr(Option<Cond>, RegT),
+    /// DJNZ d (with the signed displacement)
+    Djnz(RegT),
+    /// CALL nn; CALL cc,nn
+    Call(Option<Cond>, RegT),
+    /// RET; RET cc
+    Ret(Option<Cond>),
+    Reti,
+    Retn,
+    /// RST p
+    Rst(RegT),
+    Di,
+    Ei,
+    /// IM 0, IM 1, IM 2
+    Im(Im),
+    /// IN A,(n)
+    InA(RegT),
+    /// OUT (n),A
+    OutA(RegT),
+    /// IN r,(C), or the undocumented IN (C) which only sets the flags
+    In(Option<Reg8>),
+    /// OUT (C),r, or the undocumented OUT (C),0
+    Out(Option<Reg8>),
+    Ldi,
+    Ldd,
+    Ldir,
+    Lddr,
+    Cpi,
+    Cpd,
+    Cpir,
+    Cpdr,
+    Ini,
+    Ind,
+    Inir,
+    Indr,
+    Outi,
+    Outd,
+    Otir,
+    Otdr,
+    /// the ED-prefixed NOPs ED 77 and ED 7F
+    EdNop(RegT),
+    /// an undefined ED-prefixed instruction (executed as NOP)
+    Invalid(RegT),
+}
+
+const CONDS: [Cond; 8] = [Cond::NZ, Cond::Z, Cond::NC, Cond::C, Cond::PO, Cond::PE, Cond::P, Cond::M];
+const ALU_OPS: [AluOp; 8] = [AluOp::Add, AluOp::Adc, AluOp::Sub, AluOp::Sbc,
+                             AluOp::And, AluOp::Xor, AluOp::Or, AluOp::Cp];
+const ROT_OPS: [RotOp; 8] = [RotOp::Rlc, RotOp::Rrc, RotOp::Rl, RotOp::Rr,
+                             RotOp::Sla, RotOp::Sra, RotOp::Sll, RotOp::Srl];
+const RP: [Reg16; 4] = [Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP];
+const RP2: [Reg16; 4] = [Reg16::BC, Reg16::DE, Reg16::HL, Reg16::AF];
+const REGS: [Reg8; 8] = [Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L, Reg8::F, Reg8::A];
+
+#[inline(always)]
+pub(crate) fn is_index_reg8(r: Reg8) -> bool {
+    matches!(r, Reg8::IXH | Reg8::IXL | Reg8::IYH | Reg8::IYL)
+}
+
+#[inline(always)]
+pub(crate) fn is_index_reg16(r: Reg16) -> bool {
+    r == Reg16::IX || r == Reg16::IY
+}
+
+impl Operand {
+    /// true if the operand needs a DD or FD prefix (IXH, IXL, IYH, IYL, (IX+d), (IY+d))
+    #[inline(always)]
+    pub fn is_indexed(&self) -> bool {
+        match *self {
+            Operand::Reg(r) => is_index_reg8(r),
+            Operand::Idx(_, _) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Instruction {
+    /// true if the instruction is DD or FD prefixed
+    #[inline(always)]
+    pub fn is_indexed(&self) -> bool {
+        match *self {
+            Instruction::Ld(dst, src) => dst.is_indexed() || src.is_indexed(),
+            Instruction::Alu(_, o) | Instruction::Inc(o) | Instruction::Dec(o) |
+            Instruction::Rot(_, o, _) | Instruction::Bit(_, o) | Instruction::Res(_, o, _) |
+            Instruction::Set(_, o, _) => o.is_indexed(),
+            Instruction::Ld16(r, _) | Instruction::LdToMem16(_, r) |
+            Instruction::LdFromMem16(r, _) | Instruction::LdSP(r) | Instruction::Push(r) |
+            Instruction::Pop(r) | Instruction::ExSP(r) | Instruction::Inc16(r) |
+            Instruction::Dec16(r) | Instruction::Add16(r, _) |
+            Instruction::JpInd(r) => is_index_reg16(r),
+            _ => false,
+        }
+    }
+}
+
+// reads the instruction bytes, missing bytes are 0
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    #[inline(always)]
+    fn next(&mut self) -> RegT {
+        let b = self.bytes.get(self.pos).map_or(0, |&b| b as RegT);
+        self.pos += 1;
+        b
+    }
+
+    #[inline(always)]
+    fn next_d(&mut self) -> RegT {
+        self.next() as i8 as RegT
+    }
+
+    #[inline(always)]
+    fn next16(&mut self) -> RegT {
+        let l = self.next();
+        l | self.next() << 8
+    }
+}
+
+/// decode the instruction at the start of bytes, return the instruction and its length
+///
+/// Missing bytes at the end of the slice are decoded as zeros.
+#[inline(always)]
+pub fn decode(bytes: &[u8]) -> (Instruction, RegT) {
+    let mut rd = Reader { bytes, pos: 0 };
+    let inst = match rd.next() {
+        0xCB => decode_cb(rd.next(), Operand::Ind(Reg16::HL), false),
+        0xED => decode_ed(&mut rd),
+        prefix @ 0xDD | prefix @ 0xFD => {
+            let index = if prefix == 0xDD { Reg16::IX } else { Reg16::IY };
+            match rd.next() {
+                0xDD | 0xED | 0xFD => Instruction::Prefix(prefix),
+                0xCB => {
+                    let d = rd.next_d();
+                    decode_cb(rd.next(), Operand::Idx(index, d), true)
+                }
+                op => {
+                    let inst = decode_main(op, &mut rd, Some(index));
+                    if inst.is_indexed() {
+                        inst
+                    } else {
+                        Instruction::Prefix(prefix)
+                    }
+                }
+            }
+        }
+        op => decode_main(op, &mut rd, None),
+    };
+    let len = if let Instruction::Prefix(_) = inst { 1 } else { rd.pos };
+    (inst, len as RegT)
+}
+
+#[inline(always)]
+fn decode_main(op: RegT, rd: &mut Reader, index: Option<Reg16>) -> Instruction {
+    let x = op >> 6;
+    let y = ((op >> 3) & 7) as usize;
+    let z = (op & 7) as usize;
+    let p = y >> 1;
+    let q = y & 1;
+    let hl = index.unwrap_or(Reg16::HL);
+    // r table with H and L replaced by IXH/IXL or IYH/IYL
+    let reg = |r: usize| {
+        match (r, index) {
+            (4, Some(Reg16::IX)) => Reg8::IXH,
+            (5, Some(Reg16::IX)) => Reg8::IXL,
+            (4, Some(Reg16::IY)) => Reg8::IYH,
+            (5, Some(Reg16::IY)) => Reg8::IYL,
+            _ => REGS[r],
+        }
+    };
+    // (HL), or (IX+d) and (IY+d), which read the displacement
+    let mem = |rd: &mut Reader| {
+        match index {
+            Some(index) => Operand::Idx(index, rd.next_d()),
+            None => Operand::Ind(Reg16::HL),
+        }
+    };
+    // rp and rp2 tables with HL replaced by IX or IY
+    let rp = |p: usize| if p == 2 { hl } else { RP[p] };
+    let rp2 = |p: usize| if p == 2 { hl } else { RP2[p] };
+    match (x, y, z) {
+        (1, 6, 6) => Instruction::Halt,
+        // LD (HL),r; LD (IX+d),r (never IXH/IXL)
+        (1, 6, _) => Instruction::Ld(mem(rd), Operand::Reg(REGS[z])),
+        // LD r,(HL); LD r,(IX+d) (never IXH/IXL)
+        (1, _, 6) => Instruction::Ld(Operand::Reg(REGS[y]), mem(rd)),
+        (1, _, _) => Instruction::Ld(Operand::Reg(reg(y)), Operand::Reg(reg(z))),
+        (2, _, 6) => Instruction::Alu(ALU_OPS[y], mem(rd)),
+        (2, _, _) => Instruction::Alu(ALU_OPS[y], Operand::Reg(reg(z))),
+        (0, 0, 0) => Instruction::Nop,
+        (0, 1, 0) => Instruction::ExAF,
+        (0, 2, 0) => Instruction::Djnz(rd.next_d()),
+        (0, 3, 0) => Instruction::Jr(None, rd.next_d()),
+        (0, _, 0) => Instruction::Jr(Some(CONDS[y - 4]), rd.next_d()),
+        (0, _, 1) if q == 0 => Instruction::Ld16(rp(p), rd.next16()),
+        (0, _, 1) => Instruction::Add16(hl, rp(p)),
+        (0, _, 2) => {
+            let a = Operand::Reg(Reg8::A);
+            match (q, p) {
+                (0, 2) => Instruction::LdToMem16(rd.next16(), hl),
+                (0, 3) => Instruction::Ld(Operand::Abs(rd.next16()), a),
+                (0, _) => Instruction::Ld(Operand::Ind(rp(p)), a),
+                (1, 2) => Instruction::LdFromMem16(hl, rd.next16()),
+                (1, 3) => Instruction::Ld(a, Operand::Abs(rd.next16())),
+                _ => Instruction::Ld(a, Operand::Ind(rp(p))),
+            }
+        }
+        (0, _, 3) if q == 0 => Instruction::Inc16(rp(p)),
+        (0, _, 3) => Instruction::Dec16(rp(p)),
+        (0, 6, 4) => Instruction::Inc(mem(rd)),
+        (0, _, 4) => Instruction::Inc(Operand::Reg(reg(y))),
+        (0, 6, 5) => Instruction::Dec(mem(rd)),
+        (0, _, 5) => Instruction::Dec(Operand::Reg(reg(y))),
+        (0, 6, 6) => {
+            let dst = mem(rd);
+            Instruction::Ld(dst, Operand::Imm(rd.next()))
+        }
+        (0, _, 6) => Instruction::Ld(Operand::Reg(reg(y)), Operand::Imm(rd.next())),
+        (0, _, 7) => {
+            [Instruction::Rlca, Instruction::Rrca, Instruction::Rla, Instruction::Rra,
+             Instruction::Daa, Instruction::Cpl, Instruction::Scf, Instruction::Ccf][y]
+        }
+        (3, _, 0) => Instruction::Ret(Some(CONDS[y])),
+        (3, _, 1) => {
+            match (q, p) {
+                (0, _) => Instruction::Pop(rp2(p)),
+                (1, 0) => Instruction::Ret(None),
+                (1, 1) => Instruction::Exx,
+                (1, 2) => Instruction::JpInd(hl),
+                _ => Instruction::LdSP(hl),
+            }
+        }
+        (3, _, 2) => Instruction::Jp(Some(CONDS[y]), rd.next16()),
+        (3, 0, 3) => Instruction::Jp(None, rd.next16()),
+        (3, 2, 3) => Instruction::OutA(rd.next()),
+        (3, 3, 3) => Instruction::InA(rd.next()),
+        (3, 4, 3) => Instruction::ExSP(hl),
+        (3, 5, 3) => Instruction::ExDEHL,
+        (3, 6, 3) => Instruction::Di,
+        (3, 7, 3) => Instruction::Ei,
+        (3, _, 4) => Instruction::Call(Some(CONDS[y]), rd.next16()),
+        (3, _, 5) if q == 0 => Instruction::Push(rp2(p)),
+        (3, 1, 5) => Instruction::Call(None, rd.next16()),
+        (3, _, 6) => Instruction::Alu(ALU_OPS[y], Operand::Imm(rd.next())),
+        (3, _, 7) => Instruction::Rst((y * 8) as RegT),
+        // prefixes are handled by decode()
+        _ => unreachable!(),
+    }
+}
+
+#[inline(always)]
+fn decode_cb(op: RegT, mem: Operand, indexed: bool) -> Instruction {
+    let x = op >> 6;
+    let y = ((op >> 3) & 7) as usize;
+    let z = (op & 7) as usize;
+    // the DD CB and FD CB instructions always work on (IX+d) and (IY+d),
+    // and also store the result in a register (undocumented)
+    let (operand, copy) = match (z, indexed) {
+        (6, _) => (mem, None),
+        (_, true) => (mem, Some(REGS[z])),
+        (_, false) => (Operand::Reg(REGS[z]), None),
+    };
+    match x {
+        0 => Instruction::Rot(ROT_OPS[y], operand, copy),
+        1 => Instruction::Bit(y as RegT, operand),
+        2 => Instruction::Res(y as RegT, operand, copy),
+        _ => Instruction::Set(y as RegT, operand, copy),
+    }
+}
+
+#[inline(always)]
+fn decode_ed(rd: &mut Reader) -> Instruction {
+    let op = rd.next();
+    let x = op >> 6;
+    let y = ((op >> 3) & 7) as usize;
+    let z = (op & 7) as usize;
+    let p = y >> 1;
+    let q = y & 1;
+    let a = Operand::Reg(Reg8::A);
+    match (x, y, z) {
+        (2, 4..=7, 0..=3) => {
+            [Instruction::Ldi, Instruction::Cpi, Instruction::Ini, Instruction::Outi,
+             Instruction::Ldd, Instruction::Cpd, Instruction::Ind, Instruction::Outd,
+             Instruction::Ldir, Instruction::Cpir, Instruction::Inir, Instruction::Otir,
+             Instruction::Lddr, Instruction::Cpdr, Instruction::Indr, Instruction::Otdr]
+                [(y - 4) * 4 + z]
+        }
+        (1, 6, 0) => Instruction::In(None),
+        (1, _, 0) => Instruction::In(Some(REGS[y])),
+        (1, 6, 1) => Instruction::Out(None),
+        (1, _, 1) => Instruction::Out(Some(REGS[y])),
+        (1, _, 2) if q == 0 => Instruction::Sbc16(RP[p]),
+        (1, _, 2) => Instruction::Adc16(RP[p]),
+        (1, _, 3) if q == 0 => Instruction::EdLdToMem16(rd.next16(), RP[p]),
+        (1, _, 3) => Instruction::EdLdFromMem16(RP[p], rd.next16()),
+        (1, _, 4) => Instruction::Neg,
+        (1, 1, 5) => Instruction::Reti,
+        (1, _, 5) => Instruction::Retn,
+        (1, _, 6) => Instruction::Im([Im::Zero, Im::Zero, Im::One, Im::Two][y & 3]),
+        (1, 0, 7) => Instruction::Ld(Operand::Reg(Reg8::I), a),
+        (1, 1, 7) => Instruction::Ld(Operand::Reg(Reg8::R), a),
+        (1, 2, 7) => Instruction::Ld(a, Operand::Reg(Reg8::I)),
+        (1, 3, 7) => Instruction::Ld(a, Operand::Reg(Reg8::R)),
+        (1, 4, 7) => Instruction::Rrd,
+        (1, 5, 7) => Instruction::Rld,
+        (1, _, 7) => Instruction::EdNop(op),
+        _ => Instruction::Invalid(op),
+    }
+}
+
+// ------------------------------------------------------------------------------
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Reg(r) => write!(f, "{:?}", r),
+            Operand::Imm(n) => write!(f, "{:02X}h", n),
+            Operand::Ind(r) => write!(f, "({:?})", r),
+            Operand::Idx(r, d) if d < 0 => write!(f, "({:?}-{:02X}h)", r, -d),
+            Operand::Idx(r, d) => write!(f, "({:?}+{:02X}h)", r, d),
+            Operand::Abs(nn) => write!(f, "({:04X}h)", nn),
+        }
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// the mnemonic, with the condition of conditional instructions
+fn cond_mnemonic(name: &str, cc: Option<Cond>) -> String {
+    match cc {
+        Some(cc) => format!("{} {},", name, cc),
+        None => format!("{} ", name),
+    }
+}
+
+// relative jump target as offset to the instruction address
+fn rel_target(d: RegT) -> String {
+    let offset = d + 2;
+    if offset < 0 {
+        format!("$-{:02X}h", -offset)
+    } else {
+        format!("$+{:02X}h", offset)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Nop => write!(f, "NOP"),
+            Instruction::Prefix(p) => write!(f, "DB {:02X}h", p),
+            Instruction::Halt => write!(f, "HALT"),
+            Instruction::Ld(dst, src) => write!(f, "LD {},{}", dst, src),
+            Instruction::Ld16(r, nn) => write!(f, "LD {:?},{:04X}h", r, nn),
+            Instruction::LdToMem16(nn, r) |
+            Instruction::EdLdToMem16(nn, r) => write!(f, "LD ({:04X}h),{:?}", nn, r),
+            Instruction::LdFromMem16(r, nn) |
+            Instruction::EdLdFromMem16(r, nn) => write!(f, "LD {:?},({:04X}h)", r, nn),
+            Instruction::LdSP(r) => write!(f, "LD SP,{:?}", r),
+            Instruction::Push(r) => write!(f, "PUSH {:?}", r),
+            Instruction::Pop(r) => write!(f, "POP {:?}", r),
+            Instruction::ExAF => write!(f, "EX AF,AF'"),
+            Instruction::Exx => write!(f, "EXX"),
+            Instruction::ExDEHL => write!(f, "EX DE,HL"),
+            Instruction::ExSP(r) => write!(f, "EX (SP),{:?}", r),
+            Instruction::Alu(op, s) => {
+                let name = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+                write!(f, "{}{}", name[op as usize], s)
+            }
+            Instruction::Inc(s) => write!(f, "INC {}", s),
+            Instruction::Dec(s) => write!(f, "DEC {}", s),
+            Instruction::Inc16(r) => write!(f, "INC {:?}", r),
+            Instruction::Dec16(r) => write!(f, "DEC {:?}", r),
+            Instruction::Add16(r, s) => write!(f, "ADD {:?},{:?}", r, s),
+            Instruction::Adc16(r) => write!(f, "ADC HL,{:?}", r),
+            Instruction::Sbc16(r) => write!(f, "SBC HL,{:?}", r),
+            Instruction::Rlca => write!(f, "RLCA"),
+            Instruction::Rrca => write!(f, "RRCA"),
+            Instruction::Rla => write!(f, "RLA"),
+            Instruction::Rra => write!(f, "RRA"),
+            Instruction::Daa => write!(f, "DAA"),
+            Instruction::Cpl => write!(f, "CPL"),
+            Instruction::Scf => write!(f, "SCF"),
+            Instruction::Ccf => write!(f, "CCF"),
+            Instruction::Neg => write!(f, "NEG"),
+            Instruction::Rot(op, s, copy) => {
+                let name = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+                write!(f, "{} {}", name[op as usize], s)?;
+                match copy {
+                    Some(r) => write!(f, ",{:?}", r),
+                    None => Ok(()),
+                }
+            }
+            Instruction::Bit(b, s) => write!(f, "BIT {},{}", b, s),
+            Instruction::Res(b, s, copy) | Instruction::Set(b, s, copy) => {
+                let name = if let Instruction::Res(..) = *self { "RES" } else { "SET" };
+                write!(f, "{} {},{}", name, b, s)?;
+                match copy {
+                    Some(r) => write!(f, ",{:?}", r),
+                    None => Ok(()),
+                }
+            }
+            Instruction::Rld => write!(f, "RLD"),
+            Instruction::Rrd => write!(f, "RRD"),
+            Instruction::Jp(cc, nn) => write!(f, "{}{:04X}h", cond_mnemonic("JP", cc), nn),
+            Instruction::JpInd(r) => write!(f, "JP ({:?})", r),
+            Instruction::Jr(cc, d) => write!(f, "{}{}", cond_mnemonic("JR", cc), rel_target(d)),
+            Instruction::Djnz(d) => write!(f, "DJNZ {}", rel_target(d)),
+            Instruction::Call(cc, nn) => write!(f, "{}{:04X}h", cond_mnemonic("CALL", cc), nn),
+            Instruction::Ret(Some(cc)) => write!(f, "RET {}", cc),
+            Instruction::Ret(None) => write!(f, "RET"),
+            Instruction::Reti => write!(f, "RETI"),
+            Instruction::Retn => write!(f, "RETN"),
+            Instruction::Rst(p) => write!(f, "RST {:02X}h", p),
+            Instruction::Di => write!(f, "DI"),
+            Instruction::Ei => write!(f, "EI"),
+            Instruction::Im(mode) => write!(f, "IM {}", mode),
+            Instruction::InA(n) => write!(f, "IN A,({:02X}h)", n),
+            Instruction::OutA(n) => write!(f, "OUT ({:02X}h),A", n),
+            Instruction::In(Some(r)) => write!(f, "IN {:?},(C)", r),
+            Instruction::In(None) => write!(f, "IN (C)"),
+            Instruction::Out(Some(r)) => write!(f, "OUT (C),{:?}", r),
+            Instruction::Out(None) => write!(f, "OUT (C),0"),
+            Instruction::Ldi => write!(f, "LDI"),
+            Instruction::Ldd => write!(f, "LDD"),
+            Instruction::Ldir => write!(f, "LDIR"),
+            Instruction::Lddr => write!(f, "LDDR"),
+            Instruction::Cpi => write!(f, "CPI"),
+            Instruction::Cpd => write!(f, "CPD"),
+            Instruction::Cpir => write!(f, "CPIR"),
+            Instruction::Cpdr => write!(f, "CPDR"),
+            Instruction::Ini => write!(f, "INI"),
+            Instruction::Ind => write!(f, "IND"),
+            Instruction::Inir => write!(f, "INIR"),
+            Instruction::Indr => write!(f, "INDR"),
+            Instruction::Outi => write!(f, "OUTI"),
+            Instruction::Outd => write!(f, "OUTD"),
+            Instruction::Otir => write!(f, "OTIR"),
+            Instruction::Otdr => write!(f, "OTDR"),
+            Instruction::EdNop(op) | Instruction::Invalid(op) => write!(f, "DB EDh,{:02X}h", op),
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dis(bytes: &[u8]) -> (String, RegT) {
+        let (inst, len) = decode(bytes);
+        (inst.to_string(), len)
+    }
+
+    #[test]
+    fn main() {
+        assert_eq!(dis(&[0x00]), ("NOP".to_string(), 1));
+        assert_eq!(dis(&[0x01, 0x34, 0x12]), ("LD BC,1234h".to_string(), 3));
+        assert_eq!(dis(&[0x36, 0x12]), ("LD (HL),12h".to_string(), 2));
+        assert_eq!(dis(&[0x3A, 0x00, 0x80]), ("LD A,(8000h)".to_string(), 3));
+        assert_eq!(dis(&[0x12]), ("LD (DE),A".to_string(), 1));
+        assert_eq!(dis(&[0x18, 0xFE]), ("JR $+00h".to_string(), 2));
+        assert_eq!(dis(&[0x20, 0xF0]), ("JR NZ,$-0Eh".to_string(), 2));
+        assert_eq!(dis(&[0xCA, 0x00, 0x01]), ("JP Z,0100h".to_string(), 3));
+        assert_eq!(dis(&[0xF5]), ("PUSH AF".to_string(), 1));
+        assert_eq!(dis(&[0x9E]), ("SBC A,(HL)".to_string(), 1));
+        assert_eq!(dis(&[0xFE, 0x0D]), ("CP 0Dh".to_string(), 2));
+        assert_eq!(dis(&[0x08]), ("EX AF,AF'".to_string(), 1));
+        assert_eq!(dis(&[0xFF]), ("RST 38h".to_string(), 1));
+        // missing bytes are zeros
+        assert_eq!(dis(&[0xC3]), ("JP 0000h".to_string(), 3));
+    }
+
+    #[test]
+    fn prefixed() {
+        assert_eq!(dis(&[0xCB, 0x06]), ("RLC (HL)".to_string(), 2));
+        assert_eq!(dis(&[0xCB, 0x7F]), ("BIT 7,A".to_string(), 2));
+        assert_eq!(dis(&[0xED, 0xB0]), ("LDIR".to_string(), 2));
+        assert_eq!(dis(&[0xED, 0x43, 0x00, 0x40]), ("LD (4000h),BC".to_string(), 4));
+        assert_eq!(dis(&[0xED, 0x70]), ("IN (C)".to_string(), 2));
+        assert_eq!(dis(&[0xED, 0x5E]), ("IM 2".to_string(), 2));
+        assert_eq!(dis(&[0xED, 0x57]), ("LD A,I".to_string(), 2));
+        assert_eq!(decode(&[0xED, 0x00]), (Instruction::Invalid(0x00), 2));
+        assert_eq!(dis(&[0xDD, 0x21, 0x00, 0x80]), ("LD IX,8000h".to_string(), 4));
+        assert_eq!(dis(&[0xFD, 0x36, 0xFE, 0x55]), ("LD (IY-02h),55h".to_string(), 4));
+        assert_eq!(dis(&[0xDD, 0x66, 0x01]), ("LD H,(IX+01h)".to_string(), 3));
+        assert_eq!(dis(&[0xDD, 0x65]), ("LD IXH,IXL".to_string(), 2));
+        assert_eq!(dis(&[0xFD, 0xE9]), ("JP (IY)".to_string(), 2));
+        assert_eq!(dis(&[0xDD, 0x29]), ("ADD IX,IX".to_string(), 2));
+        assert_eq!(dis(&[0xDD, 0xCB, 0x03, 0x46]), ("BIT 0,(IX+03h)".to_string(), 4));
+        assert_eq!(dis(&[0xFD, 0xCB, 0x03, 0xC7]), ("SET 0,(IY+03h),A".to_string(), 4));
+        // prefixes without effect
+        assert_eq!(decode(&[0xDD, 0x47]), (Instruction::Prefix(0xDD), 1));
+        assert_eq!(decode(&[0xDD, 0xEB]), (Instruction::Prefix(0xDD), 1));
+        assert_eq!(decode(&[0xFD, 0xDD, 0x21]), (Instruction::Prefix(0xFD), 1));
+        assert!(!Instruction::Prefix(0xDD).is_indexed());
+    }
+
+    #[test]
+    fn lengths() {
+        // every opcode decodes, and is at most 4 bytes long
+        for prefix in &[None, Some(0xCB), Some(0xDD), Some(0xED), Some(0xFD)] {
+            for op in 0..256 {
+                let bytes: Vec<u8> = prefix.iter().cloned().chain(Some(op as u8)).collect();
+                let (_, len) = decode(&bytes);
+                assert!((1..=4).contains(&len));
+            }
+        }
+    }
+}
@@ -0,0 +1,173 @@
+/// the kind of a chip registered in a DeviceMap
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum DeviceKind {
+    /// Z80 PIO, 2 interrupt sources (channel A and B)
+    Pio,
+    /// Z80 CTC, 4 interrupt sources (channel 0..3)
+    Ctc,
+    /// Z80 SIO, 2 interrupt sources (channel A and B)
+    Sio,
+    /// any other chip with a number of interrupt sources
+    Other(usize),
+}
+
+impl DeviceKind {
+    /// the number of daisychain controllers (interrupt sources) of the chip
+    pub fn irq_channels(&self) -> usize {
+        match *self {
+            DeviceKind::Pio | DeviceKind::Sio => 2,
+            DeviceKind::Ctc => 4,
+            DeviceKind::Other(num) => num,
+        }
+    }
+}
+
+/// a chip registered in a DeviceMap
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Device {
+    pub kind: DeviceKind,
+    /// the id the chip was created with (e.g. PIO::new(id)), passed to the Bus callbacks
+    pub id: usize,
+    pub name: String,
+    /// the daisychain controller index of the first interrupt source
+    pub first_ctrl: usize,
+}
+
+/// a registry of the system's chips and their interrupt daisychain positions
+///
+/// Systems with several chips of the same type (like the two PIOs of
+/// the KC85/4) register each chip with the id it was created with, in
+/// daisychain priority order. Each interrupt source (chip channel) gets
+/// the next daisychain controller index. If Bus::devices() returns the
+/// map, the default implementations of **Bus::pio_irq()**,
+/// **Bus::ctc_irq()** and **Bus::sio_irq()** look up the controller
+/// index and call **Bus::irq()**, which usually forwards the request
+/// to the Daisychain.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::RefCell;
+/// use rz80::{Bus, Daisychain, DeviceMap, DeviceKind, RegT};
+///
+/// struct System {
+///     devices: DeviceMap,
+///     daisy: RefCell<Daisychain>,
+/// }
+///
+/// impl Bus for System {
+///     fn devices(&self) -> Option<&DeviceMap> {
+///         Some(&self.devices)
+///     }
+///     fn irq(&self, ctrl_id: usize, vec: u8) {
+///         self.daisy.borrow_mut().irq(self, ctrl_id, vec);
+///     }
+///     fn irq_ack(&self) -> RegT {
+///         self.daisy.borrow_mut().irq_ack()
+///     }
+/// }
+///
+/// let mut devices = DeviceMap::new();
+/// devices.add(DeviceKind::Ctc, 0, "CTC");
+/// devices.add(DeviceKind::Pio, 0, "PIO1");
+/// devices.add(DeviceKind::Pio, 1, "PIO2");
+/// assert_eq!(devices.num_controllers(), 8);
+/// let sys = System {
+///     daisy: RefCell::new(Daisychain::new(devices.num_controllers())),
+///     devices,
+/// };
+///
+/// // an interrupt from channel B of the second PIO
+/// sys.pio_irq(1, 1, 0xE4);
+/// assert!(sys.daisy.borrow().ctrl[7].int_requested);
+/// assert_eq!(sys.irq_ack(), 0xE4);
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct DeviceMap {
+    devices: Vec<Device>,
+    num_ctrl: usize,
+}
+
+impl DeviceMap {
+    /// create an empty device map
+    pub fn new() -> DeviceMap {
+        DeviceMap {
+            devices: Vec::new(),
+            num_ctrl: 0,
+        }
+    }
+
+    /// register the next chip in daisychain order, return its index in the map
+    pub fn add(&mut self, kind: DeviceKind, id: usize, name: &str) -> usize {
+        assert!(self.find(kind, id).is_none(), "device registered twice");
+        self.devices.push(Device {
+            kind,
+            id,
+            name: name.to_string(),
+            first_ctrl: self.num_ctrl,
+        });
+        self.num_ctrl += kind.irq_channels();
+        self.devices.len() - 1
+    }
+
+    /// the number of daisychain controllers of all registered chips
+    pub fn num_controllers(&self) -> usize {
+        self.num_ctrl
+    }
+
+    /// find a chip by kind and id
+    pub fn find(&self, kind: DeviceKind, id: usize) -> Option<&Device> {
+        self.devices.iter().find(|d| d.kind == kind && d.id == id)
+    }
+
+    /// find a chip by name
+    pub fn find_name(&self, name: &str) -> Option<&Device> {
+        self.devices.iter().find(|d| d.name == name)
+    }
+
+    /// the daisychain controller index of a chip channel
+    pub fn irq_ctrl(&self, kind: DeviceKind, id: usize, chn: usize) -> Option<usize> {
+        self.find(kind, id)
+            .filter(|d| chn < d.kind.irq_channels())
+            .map(|d| d.first_ctrl + chn)
+    }
+
+    /// the chip and channel of a daisychain controller index
+    pub fn ctrl_device(&self, ctrl_id: usize) -> Option<(&Device, usize)> {
+        self.devices
+            .iter()
+            .find(|d| ctrl_id >= d.first_ctrl && ctrl_id < d.first_ctrl + d.kind.irq_channels())
+            .map(|d| (d, ctrl_id - d.first_ctrl))
+    }
+
+    /// iterate over the chips in daisychain order
+    pub fn iter(&self) -> impl Iterator<Item = &Device> {
+        self.devices.iter()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let mut devices = DeviceMap::new();
+        assert_eq!(devices.add(DeviceKind::Pio, 0, "PIO1"), 0);
+        assert_eq!(devices.add(DeviceKind::Other(1), 0, "VIDEO"), 1);
+        assert_eq!(devices.add(DeviceKind::Pio, 1, "PIO2"), 2);
+        assert_eq!(devices.add(DeviceKind::Ctc, 0, "CTC"), 3);
+        assert_eq!(devices.add(DeviceKind::Other(0), 1, "FDC"), 4);
+        assert_eq!(devices.num_controllers(), 9);
+        assert_eq!(devices.irq_ctrl(DeviceKind::Pio, 1, 1), Some(4));
+        assert_eq!(devices.irq_ctrl(DeviceKind::Ctc, 0, 3), Some(8));
+        assert_eq!(devices.irq_ctrl(DeviceKind::Ctc, 0, 4), None);
+        assert_eq!(devices.irq_ctrl(DeviceKind::Sio, 0, 0), None);
+        let (dev, chn) = devices.ctrl_device(2).unwrap();
+        assert_eq!((dev.name.as_str(), chn), ("VIDEO", 0));
+        assert_eq!(devices.ctrl_device(9), None);
+        assert_eq!(devices.find_name("CTC").map(|d| d.first_ctrl), Some(5));
+        assert_eq!(devices.iter().map(|d| d.id).collect::<Vec<_>>(), [0, 0, 1, 0, 1]);
+    }
+}
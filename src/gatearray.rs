@@ -0,0 +1,412 @@
+use RegT;
+use bus::Bus;
+
+/// the 27 colors of the CPC hardware palette (indexed by hardware color number, ARGB)
+const HW_COLORS: [u32; 32] = [
+    0xFF808080, // 0x00: white
+    0xFF808080, // 0x01: white
+    0xFF00FF80, // 0x02: sea green
+    0xFFFFFF80, // 0x03: pastel yellow
+    0xFF000080, // 0x04: blue
+    0xFFFF0080, // 0x05: purple
+    0xFF008080, // 0x06: cyan
+    0xFFFF8080, // 0x07: pink
+    0xFFFF0080, // 0x08: purple
+    0xFFFFFF80, // 0x09: pastel yellow
+    0xFFFFFF00, // 0x0A: bright yellow
+    0xFFFFFFFF, // 0x0B: bright white
+    0xFFFF0000, // 0x0C: bright red
+    0xFFFF00FF, // 0x0D: bright magenta
+    0xFFFF8000, // 0x0E: orange
+    0xFFFF80FF, // 0x0F: pastel magenta
+    0xFF000080, // 0x10: blue
+    0xFF00FF80, // 0x11: sea green
+    0xFF00FF00, // 0x12: bright green
+    0xFF00FFFF, // 0x13: bright cyan
+    0xFF000000, // 0x14: black
+    0xFF0000FF, // 0x15: bright blue
+    0xFF008000, // 0x16: green
+    0xFF0080FF, // 0x17: sky blue
+    0xFF800080, // 0x18: magenta
+    0xFF80FF80, // 0x19: pastel green
+    0xFF80FF00, // 0x1A: lime
+    0xFF80FFFF, // 0x1B: pastel cyan
+    0xFF800000, // 0x1C: red
+    0xFF8000FF, // 0x1D: mauve
+    0xFF808000, // 0x1E: yellow
+    0xFF8080FF, // 0x1F: pastel blue
+];
+
+/// index of the border color in the palette
+pub const GA_BORDER: usize = 16;
+const NUM_PENS: usize = 17;
+
+/// Amstrad CPC gate array emulation
+///
+/// The gate array is the CPC's video and memory controller, it is
+/// written through port 0x7Fxx (the gate array is selected when address
+/// bit A15 is 0 and A14 is 1). The upper 2 bits of the written value
+/// select one of 4 functions:
+///
+/// - **00xxxxxx**: select pen (bit 4 set selects the border)
+/// - **01xxxxxx**: set the hardware color of the selected pen
+/// - **10xxxxxx**: select the screen mode (bits 0..1), disable the lower
+///   (bit 2) and upper ROM (bit 3), and reset the interrupt counter (bit 4)
+/// - **11xxxxxx**: RAM bank configuration (only on machines with 128 KBytes)
+///
+/// Whenever the ROM or RAM configuration changes, Bus::ga_memory_config()
+/// is called, this is where the system maps the right ROMs and RAM banks
+/// into the CPU address space.
+///
+/// The gate array also generates the 300 Hz interrupt by counting the
+/// HSYNCs of the CRTC, the system must forward the CRTC sync signals
+/// to the **hsync()** and **vsync()** methods, and interrupt requests
+/// are forwarded to Bus::ga_irq().
+///
+/// The pixel data of a video memory byte is decoded with **decode_byte()**,
+/// the video memory address for a CRTC memory and row address is
+/// computed with **video_addr()**.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Bus, GateArray};
+///
+/// struct DummyBus;
+/// impl Bus for DummyBus { };
+///
+/// let mut ga = GateArray::new();
+/// // select mode 1, and set pen 1 to bright yellow
+/// ga.write(&DummyBus, 0x81);
+/// ga.write(&DummyBus, 0x01);
+/// ga.write(&DummyBus, 0x40 | 0x0A);
+/// assert_eq!(ga.mode(), 1);
+/// assert_eq!(ga.pen_color(1), 0xFFFFFF00);
+///
+/// // in mode 1, a byte contains 4 pixels, each 2 output pixels wide
+/// let mut pixels = [0u32; 8];
+/// ga.decode_byte(0x80, &mut pixels);
+/// assert_eq!(pixels[0], 0xFFFFFF00);
+/// assert_eq!(pixels[1], 0xFFFFFF00);
+/// assert_eq!(pixels[2], ga.pen_color(0));
+/// ```
+pub struct GateArray {
+    pen: usize,
+    palette: [u8; NUM_PENS],
+    mode: u8,
+    next_mode: u8,
+    lower_rom: bool,
+    upper_rom: bool,
+    ram_config: u8,
+    irq_counter: u8,
+    vsync_delay: u8,
+}
+
+impl GateArray {
+    /// initialize a new gate array object
+    pub fn new() -> GateArray {
+        GateArray {
+            pen: 0,
+            palette: [0x14; NUM_PENS],
+            mode: 1,
+            next_mode: 1,
+            lower_rom: true,
+            upper_rom: true,
+            ram_config: 0,
+            irq_counter: 0,
+            vsync_delay: 0,
+        }
+    }
+
+    /// reset the gate array
+    pub fn reset(&mut self, bus: &dyn Bus) {
+        self.pen = 0;
+        self.palette = [0x14; NUM_PENS];
+        self.mode = 1;
+        self.next_mode = 1;
+        self.lower_rom = true;
+        self.upper_rom = true;
+        self.ram_config = 0;
+        self.irq_counter = 0;
+        self.vsync_delay = 0;
+        bus.ga_memory_config(self);
+    }
+
+    /// write to the gate array (port 0x7Fxx)
+    pub fn write(&mut self, bus: &dyn Bus, val: RegT) {
+        let val = val as u8;
+        match val >> 6 {
+            0 => {
+                self.pen = if (val & 0x10) != 0 {
+                    GA_BORDER
+                } else {
+                    (val & 0x0F) as usize
+                };
+            }
+            1 => {
+                self.palette[self.pen] = val & 0x1F;
+            }
+            2 => {
+                // the new mode takes effect at the next hsync
+                self.next_mode = val & 3;
+                self.lower_rom = (val & 0x04) == 0;
+                self.upper_rom = (val & 0x08) == 0;
+                if (val & 0x10) != 0 {
+                    self.irq_counter = 0;
+                }
+                bus.ga_memory_config(self);
+            }
+            _ => {
+                self.ram_config = val & 0x3F;
+                bus.ga_memory_config(self);
+            }
+        }
+    }
+
+    /// current screen mode (0: 160x200 16 colors, 1: 320x200 4 colors, 2: 640x200 2 colors)
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    /// true if the lower ROM (OS) is enabled at 0x0000
+    pub fn lower_rom_enabled(&self) -> bool {
+        self.lower_rom
+    }
+
+    /// true if the upper ROM (BASIC) is enabled at 0xC000
+    pub fn upper_rom_enabled(&self) -> bool {
+        self.upper_rom
+    }
+
+    /// current RAM bank configuration (0..7 on a 128 KByte machine)
+    pub fn ram_config(&self) -> u8 {
+        self.ram_config & 7
+    }
+
+    /// get the hardware color number of a pen (GA_BORDER for the border)
+    pub fn pen(&self, pen: usize) -> u8 {
+        self.palette[pen]
+    }
+
+    /// get the ARGB color of a pen (GA_BORDER for the border)
+    #[inline(always)]
+    pub fn pen_color(&self, pen: usize) -> u32 {
+        HW_COLORS[self.palette[pen] as usize]
+    }
+
+    /// get the ARGB color of a hardware color number
+    pub fn hw_color(hw: u8) -> u32 {
+        HW_COLORS[(hw & 0x1F) as usize]
+    }
+
+    /// compute the video memory address from CRTC memory and row address
+    #[inline(always)]
+    pub fn video_addr(ma: RegT, ra: RegT) -> RegT {
+        ((ma & 0x3000) << 2) | ((ra & 7) << 11) | ((ma & 0x03FF) << 1)
+    }
+
+    /// decode a video memory byte into 8 ARGB pixels (at mode 2 resolution)
+    #[inline(always)]
+    pub fn decode_byte(&self, byte: u8, out: &mut [u32]) {
+        let b = byte as usize;
+        match self.mode {
+            0 => {
+                // 2 pixels, 4 bits per pixel
+                let p0 = (b >> 7 & 1) | (b >> 2 & 2) | (b >> 3 & 4) | (b << 2 & 8);
+                let p1 = (b >> 6 & 1) | (b >> 1 & 2) | (b >> 2 & 4) | (b << 3 & 8);
+                let c0 = self.pen_color(p0);
+                let c1 = self.pen_color(p1);
+                for p in &mut out[0..4] {
+                    *p = c0;
+                }
+                for p in &mut out[4..8] {
+                    *p = c1;
+                }
+            }
+            1 => {
+                // 4 pixels, 2 bits per pixel
+                for i in 0..4 {
+                    let p = (b >> (7 - i) & 1) | ((b >> (3 - i) & 1) << 1);
+                    let c = self.pen_color(p);
+                    out[i * 2] = c;
+                    out[i * 2 + 1] = c;
+                }
+            }
+            _ => {
+                // 8 pixels, 1 bit per pixel
+                for (i, p) in out[0..8].iter_mut().enumerate() {
+                    *p = self.pen_color(b >> (7 - i) & 1);
+                }
+            }
+        }
+    }
+
+    /// CRTC HSYNC output has changed, updates the interrupt counter
+    pub fn hsync(&mut self, bus: &dyn Bus, active: bool) {
+        if active {
+            self.mode = self.next_mode;
+            return;
+        }
+        // the interrupt counter is bumped at the end of each HSYNC
+        self.irq_counter += 1;
+        if self.vsync_delay > 0 {
+            // 2 HSYNCs after the start of VSYNC the counter is reset
+            self.vsync_delay -= 1;
+            if self.vsync_delay == 0 {
+                if self.irq_counter >= 32 {
+                    bus.ga_irq();
+                }
+                self.irq_counter = 0;
+            }
+        }
+        if self.irq_counter == 52 {
+            self.irq_counter = 0;
+            bus.ga_irq();
+        }
+    }
+
+    /// CRTC VSYNC output has changed
+    pub fn vsync(&mut self, active: bool) {
+        if active {
+            self.vsync_delay = 2;
+        }
+    }
+
+    /// the CPU has acknowledged the interrupt
+    pub fn irq_ack(&mut self) {
+        self.irq_counter &= 0x1F;
+    }
+}
+
+impl Default for GateArray {
+    fn default() -> GateArray {
+        GateArray::new()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use Bus;
+
+    struct TestBus {
+        irqs: Cell<usize>,
+        mem_configs: Cell<usize>,
+    }
+    impl Bus for TestBus {
+        fn ga_irq(&self) {
+            self.irqs.set(self.irqs.get() + 1);
+        }
+        fn ga_memory_config(&self, _: &GateArray) {
+            self.mem_configs.set(self.mem_configs.get() + 1);
+        }
+    }
+
+    fn test_bus() -> TestBus {
+        TestBus {
+            irqs: Cell::new(0),
+            mem_configs: Cell::new(0),
+        }
+    }
+
+    #[test]
+    fn write() {
+        let bus = test_bus();
+        let mut ga = GateArray::new();
+        ga.write(&bus, 0x10);
+        ga.write(&bus, 0x40 | 0x0C);
+        assert_eq!(ga.pen(GA_BORDER), 0x0C);
+        assert_eq!(ga.pen_color(GA_BORDER), 0xFFFF0000);
+        ga.write(&bus, 0x03);
+        ga.write(&bus, 0x40 | 0x0B);
+        assert_eq!(ga.pen(3), 0x0B);
+
+        // ROM config, the mode changes at the next HSYNC
+        assert_eq!(bus.mem_configs.get(), 0);
+        ga.write(&bus, 0x80 | 0x0C | 0x02);
+        assert_eq!(bus.mem_configs.get(), 1);
+        assert!(!ga.lower_rom_enabled());
+        assert!(!ga.upper_rom_enabled());
+        assert_eq!(ga.mode(), 1);
+        ga.hsync(&bus, true);
+        assert_eq!(ga.mode(), 2);
+        ga.write(&bus, 0x80);
+        assert!(ga.lower_rom_enabled());
+        assert!(ga.upper_rom_enabled());
+
+        // RAM config
+        ga.write(&bus, 0xC0 | 0x04);
+        assert_eq!(ga.ram_config(), 4);
+        assert_eq!(bus.mem_configs.get(), 3);
+    }
+
+    #[test]
+    fn decode() {
+        let bus = test_bus();
+        let mut ga = GateArray::new();
+        for pen in 0..16 {
+            ga.write(&bus, pen);
+            ga.write(&bus, 0x40 | pen);
+        }
+        let mut px = [0u32; 8];
+        // mode 0: pixel 0 = pen 0b1001 (bits 7 and 1), pixel 1 = pen 0b0110 (bits 2 and 4)
+        ga.write(&bus, 0x80);
+        ga.hsync(&bus, true);
+        ga.decode_byte(0x96, &mut px);
+        assert_eq!(px[0], GateArray::hw_color(9));
+        assert_eq!(px[3], GateArray::hw_color(9));
+        assert_eq!(px[4], GateArray::hw_color(6));
+        // mode 1: pixels 3, 2, 0, 1
+        ga.write(&bus, 0x81);
+        ga.hsync(&bus, true);
+        ga.decode_byte(0x8D, &mut px);
+        assert_eq!(px[0], GateArray::hw_color(3));
+        assert_eq!(px[1], GateArray::hw_color(3));
+        assert_eq!(px[2], GateArray::hw_color(2));
+        assert_eq!(px[4], GateArray::hw_color(0));
+        assert_eq!(px[6], GateArray::hw_color(2));
+        // mode 2
+        ga.write(&bus, 0x82);
+        ga.hsync(&bus, true);
+        ga.decode_byte(0xA0, &mut px);
+        assert_eq!(px[0], GateArray::hw_color(1));
+        assert_eq!(px[1], GateArray::hw_color(0));
+        assert_eq!(px[2], GateArray::hw_color(1));
+        assert_eq!(px[3], GateArray::hw_color(0));
+    }
+
+    #[test]
+    fn interrupts() {
+        let bus = test_bus();
+        let mut ga = GateArray::new();
+        for _ in 0..52 {
+            ga.hsync(&bus, true);
+            ga.hsync(&bus, false);
+        }
+        assert_eq!(bus.irqs.get(), 1);
+
+        // VSYNC resets the counter after 2 HSYNCs
+        for _ in 0..40 {
+            ga.hsync(&bus, false);
+        }
+        ga.vsync(true);
+        ga.hsync(&bus, false);
+        ga.hsync(&bus, false);
+        assert_eq!(bus.irqs.get(), 2);
+        for _ in 0..51 {
+            ga.hsync(&bus, false);
+        }
+        assert_eq!(bus.irqs.get(), 2);
+        ga.hsync(&bus, false);
+        assert_eq!(bus.irqs.get(), 3);
+    }
+
+    #[test]
+    fn video_addr() {
+        assert_eq!(GateArray::video_addr(0x3000, 0), 0xC000);
+        assert_eq!(GateArray::video_addr(0x3001, 1), 0xC802);
+        assert_eq!(GateArray::video_addr(0x3028, 7), 0xF850);
+    }
+}
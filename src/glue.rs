@@ -0,0 +1,419 @@
+use RegT;
+use bus::{Bus, Reset, ResetKind};
+use iomap::PortDecoder;
+
+/// 8-bit output latch (74LS273 / 74LS374 style)
+///
+/// Many systems have simple write-only registers made of a latch
+/// chip at a partially decoded I/O port, for instance a border color
+/// register or a memory bank select. The Latch stores the value of
+/// each write to a matching port and calls **Bus::latch_outp()** with
+/// the latch id, so the system can react to the new value (e.g. switch
+/// memory banks). A system reset clears the latch (like a 74LS273 with
+/// its CLR input on the RESET line).
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::{Cell, RefCell};
+/// use rz80::{CPU, Bus, Latch, PortDecoder, RegT};
+///
+/// struct System {
+///     border: RefCell<Latch>,
+///     border_color: Cell<RegT>,
+/// }
+///
+/// impl Bus for System {
+///     fn cpu_outp(&self, port: RegT, val: RegT) {
+///         self.border.borrow_mut().outp(self, port, val);
+///     }
+///     fn latch_outp(&self, _latch: usize, val: RegT) {
+///         self.border_color.set(val & 7);
+///     }
+/// }
+///
+/// let sys = System {
+///     border: RefCell::new(Latch::new(0, PortDecoder::low_byte(0xFE))),
+///     border_color: Cell::new(0),
+/// };
+/// let mut cpu = CPU::new_64k();
+/// // LD A,0x0D; OUT (0xFE),A
+/// cpu.mem.write(0x0000, &[0x3E, 0x0D, 0xD3, 0xFE]);
+/// cpu.step(&sys);
+/// cpu.step(&sys);
+/// assert_eq!(sys.border.borrow().value(), 0x0D);
+/// assert_eq!(sys.border_color.get(), 5);
+/// ```
+#[derive(Clone,Debug)]
+pub struct Latch {
+    id: usize,
+    port: PortDecoder,
+    value: u8,
+}
+
+impl Latch {
+    /// create a cleared latch with an id (for Bus::latch_outp()) at a port
+    pub fn new(id: usize, port: PortDecoder) -> Latch {
+        Latch { id, port, value: 0 }
+    }
+
+    /// the latch id
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// the latched value
+    pub fn value(&self) -> RegT {
+        self.value as RegT
+    }
+
+    /// latch a value, and call Bus::latch_outp()
+    pub fn write(&mut self, bus: &dyn Bus, val: RegT) {
+        self.value = val as u8;
+        bus.latch_outp(self.id, self.value as RegT);
+    }
+
+    /// latch a value if the port matches, return true if it matched
+    pub fn outp(&mut self, bus: &dyn Bus, port: RegT, val: RegT) -> bool {
+        if self.port.matches(port) {
+            self.write(bus, val);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Reset for Latch {
+    fn system_reset(&mut self, _kind: ResetKind) {
+        self.value = 0;
+    }
+}
+
+/// set/reset or D flip-flop (74LS74 style)
+///
+/// A single bit of state, for instance the flip-flop which disables
+/// the boot ROM after the first access to an I/O port. The flip-flop
+/// is set or cleared by an access (read or write) to the ports added
+/// with **set_port()** and **clear_port()**, or directly with **set()**,
+/// **clear()**, **toggle()** and **clock()**. When the output changes,
+/// **Bus::flipflop_changed()** is called with the flip-flop id. A system
+/// reset clears the flip-flop.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{FlipFlop, PortDecoder, NullBus};
+///
+/// // any access to port 0x?0 disables the boot ROM, 0x?1 enables it again
+/// let mut rom_off = FlipFlop::new(0)
+///     .set_port(PortDecoder::new(0x0F, 0x00))
+///     .clear_port(PortDecoder::new(0x0F, 0x01));
+/// assert!(!rom_off.q());
+/// assert!(rom_off.access(&NullBus, 0x1230));
+/// assert!(rom_off.q());
+/// assert!(!rom_off.access(&NullBus, 0x0002));
+/// rom_off.access(&NullBus, 0x0011);
+/// assert!(!rom_off.q());
+/// // D flip-flop
+/// rom_off.clock(&NullBus, true);
+/// assert!(rom_off.q());
+/// ```
+#[derive(Clone,Debug)]
+pub struct FlipFlop {
+    id: usize,
+    set_port: Option<PortDecoder>,
+    clear_port: Option<PortDecoder>,
+    q: bool,
+}
+
+impl FlipFlop {
+    /// create a cleared flip-flop with an id (for Bus::flipflop_changed())
+    pub fn new(id: usize) -> FlipFlop {
+        FlipFlop {
+            id,
+            set_port: None,
+            clear_port: None,
+            q: false,
+        }
+    }
+
+    /// set the flip-flop on access to a port
+    pub fn set_port(mut self, port: PortDecoder) -> FlipFlop {
+        self.set_port = Some(port);
+        self
+    }
+
+    /// clear the flip-flop on access to a port
+    pub fn clear_port(mut self, port: PortDecoder) -> FlipFlop {
+        self.clear_port = Some(port);
+        self
+    }
+
+    /// the flip-flop id
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// the Q output
+    pub fn q(&self) -> bool {
+        self.q
+    }
+
+    /// set the Q output to a level (the D input on a clock edge)
+    pub fn clock(&mut self, bus: &dyn Bus, d: bool) {
+        if d != self.q {
+            self.q = d;
+            bus.flipflop_changed(self.id, d);
+        }
+    }
+
+    /// set the Q output
+    pub fn set(&mut self, bus: &dyn Bus) {
+        self.clock(bus, true);
+    }
+
+    /// clear the Q output
+    pub fn clear(&mut self, bus: &dyn Bus) {
+        self.clock(bus, false);
+    }
+
+    /// invert the Q output (a D flip-flop with D wired to /Q)
+    pub fn toggle(&mut self, bus: &dyn Bus) {
+        let q = !self.q;
+        self.clock(bus, q);
+    }
+
+    /// set or clear on a port access (read or write), return true if a port matched
+    pub fn access(&mut self, bus: &dyn Bus, port: RegT) -> bool {
+        if self.set_port.is_some_and(|p| p.matches(port)) {
+            self.set(bus);
+            true
+        } else if self.clear_port.is_some_and(|p| p.matches(port)) {
+            self.clear(bus);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Reset for FlipFlop {
+    fn system_reset(&mut self, _kind: ResetKind) {
+        self.q = false;
+    }
+}
+
+/// serial-in/parallel-out and parallel-in/serial-out shift register (74LS164 / 74LS165 style)
+///
+/// A shift register with 1 to 32 bits. **shift()** shifts a bit in at
+/// bit 0, and returns the bit shifted out at the top, **load()** loads
+/// all bits in parallel. After each shift, **Bus::shiftreg_outp()** is
+/// called with the shift register id and the new parallel output. With
+/// a port added by **port()**, a write to the port shifts in bit 0 of
+/// the written value, and a read returns the top bit in bit 0, which
+/// is a common way to connect serial devices (e.g. a keyboard or an
+/// EEPROM) to a bit-banging CPU.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{ShiftRegister, PortDecoder, NullBus};
+///
+/// let mut sr = ShiftRegister::new(0, 8).port(PortDecoder::low_byte(0x40));
+/// for bit in &[1, 0, 1, 1] {
+///     sr.outp(&NullBus, 0x0040, *bit);
+/// }
+/// assert_eq!(sr.value(), 0b1011);
+///
+/// // parallel load and serial read
+/// sr.load(0x80);
+/// assert_eq!(sr.inp(0x0040), Some(1));
+/// assert_eq!(sr.shift(&NullBus, false), true);
+/// assert_eq!(sr.value(), 0x00);
+/// assert_eq!(sr.inp(0x0041), None);
+/// ```
+#[derive(Clone,Debug)]
+pub struct ShiftRegister {
+    id: usize,
+    bits: u32,
+    port: Option<PortDecoder>,
+    value: u32,
+}
+
+impl ShiftRegister {
+    /// create a cleared shift register with an id (for Bus::shiftreg_outp()) and 1..32 bits
+    pub fn new(id: usize, bits: u32) -> ShiftRegister {
+        assert!(bits > 0 && bits <= 32);
+        ShiftRegister {
+            id,
+            bits,
+            port: None,
+            value: 0,
+        }
+    }
+
+    /// shift in bit 0 of values written to a port, read the top bit from the port
+    pub fn port(mut self, port: PortDecoder) -> ShiftRegister {
+        self.port = Some(port);
+        self
+    }
+
+    /// the shift register id
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// number of bits
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// the parallel output
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    /// the serial output (the top bit)
+    pub fn serial_out(&self) -> bool {
+        (self.value >> (self.bits - 1)) & 1 != 0
+    }
+
+    /// parallel load, the bits above the register size are ignored
+    pub fn load(&mut self, val: u32) {
+        self.value = val & self.mask();
+    }
+
+    /// shift a bit in at bit 0, return the bit shifted out at the top
+    pub fn shift(&mut self, bus: &dyn Bus, bit: bool) -> bool {
+        let out = self.serial_out();
+        self.value = ((self.value << 1) | bit as u32) & self.mask();
+        bus.shiftreg_outp(self.id, self.value);
+        out
+    }
+
+    /// shift in bit 0 of a value if the port matches, return true if it matched
+    pub fn outp(&mut self, bus: &dyn Bus, port: RegT, val: RegT) -> bool {
+        if self.port.is_some_and(|p| p.matches(port)) {
+            self.shift(bus, val & 1 != 0);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// read the serial output in bit 0 if the port matches
+    pub fn inp(&self, port: RegT) -> Option<RegT> {
+        if self.port.is_some_and(|p| p.matches(port)) {
+            Some(self.serial_out() as RegT)
+        } else {
+            None
+        }
+    }
+
+    fn mask(&self) -> u32 {
+        (((1u64 << self.bits) - 1) & 0xFFFF_FFFF) as u32
+    }
+}
+
+impl Reset for ShiftRegister {
+    fn system_reset(&mut self, _kind: ResetKind) {
+        self.value = 0;
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use {CPU, NullBus, RecordingBus, BusEvent, reset_all};
+
+    struct System {
+        bank: RefCell<Latch>,
+        rom_off: RefCell<FlipFlop>,
+        serial: RefCell<ShiftRegister>,
+        hooks: RefCell<Vec<(char, usize, RegT)>>,
+        io: RecordingBus,
+    }
+
+    impl Bus for System {
+        fn cpu_inp(&self, port: RegT) -> RegT {
+            self.rom_off.borrow_mut().access(self, port);
+            self.serial.borrow().inp(port).unwrap_or(0xFF)
+        }
+        fn cpu_outp(&self, port: RegT, val: RegT) {
+            let handled = self.bank.borrow_mut().outp(self, port, val) |
+                          self.rom_off.borrow_mut().access(self, port) |
+                          self.serial.borrow_mut().outp(self, port, val);
+            if !handled {
+                self.io.cpu_outp(port, val);
+            }
+        }
+        fn latch_outp(&self, latch: usize, val: RegT) {
+            self.hooks.borrow_mut().push(('L', latch, val));
+        }
+        fn flipflop_changed(&self, ff: usize, q: bool) {
+            self.hooks.borrow_mut().push(('F', ff, q as RegT));
+        }
+        fn shiftreg_outp(&self, sr: usize, val: u32) {
+            self.hooks.borrow_mut().push(('S', sr, val as RegT));
+        }
+    }
+
+    #[test]
+    fn system() {
+        let sys = System {
+            bank: RefCell::new(Latch::new(1, PortDecoder::parse("xxxx xxxx 0111 xxxx").unwrap())),
+            rom_off: RefCell::new(FlipFlop::new(2).set_port(PortDecoder::low_byte(0x80))),
+            serial: RefCell::new(ShiftRegister::new(3, 4).port(PortDecoder::low_byte(0x90))),
+            hooks: RefCell::new(Vec::new()),
+            io: RecordingBus::new(),
+        };
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0000, &[
+            0x3E, 0x05,     // LD A,0x05
+            0xD3, 0x7A,     // OUT (0x7A),A
+            0xD3, 0x90,     // OUT (0x90),A
+            0xD3, 0x90,     // OUT (0x90),A
+            0xD3, 0x60,     // OUT (0x60),A
+            0xDB, 0x80,     // IN A,(0x80)
+            0xDB, 0x80,     // IN A,(0x80)
+            0xDB, 0x90,     // IN A,(0x90)
+        ]);
+        for _ in 0..7 {
+            cpu.step(&sys);
+        }
+        assert_eq!(sys.bank.borrow().value(), 0x05);
+        assert!(sys.rom_off.borrow().q());
+        assert_eq!(sys.serial.borrow().value(), 0b11);
+        // the flip-flop only reports changes
+        assert_eq!(*sys.hooks.borrow(), vec![('L', 1, 0x05), ('S', 3, 0b01), ('S', 3, 0b11),
+                                             ('F', 2, 1)]);
+        assert_eq!(sys.io.take_events(), vec![(0, BusEvent::CpuOutp { port: 0x0560, val: 0x05 })]);
+        // bit 3 of the shift register is 0
+        cpu.step(&sys);
+        assert_eq!(cpu.reg.a(), 0);
+
+        reset_all(ResetKind::Warm, &mut [&mut *sys.bank.borrow_mut(),
+                                         &mut *sys.rom_off.borrow_mut(),
+                                         &mut *sys.serial.borrow_mut()]);
+        assert_eq!(sys.bank.borrow().value(), 0);
+        assert!(!sys.rom_off.borrow().q());
+        assert_eq!(sys.serial.borrow().value(), 0);
+    }
+
+    #[test]
+    fn shift_register() {
+        let mut sr = ShiftRegister::new(0, 32);
+        sr.load(0x8000_0001);
+        assert!(sr.shift(&NullBus, true));
+        assert_eq!(sr.value(), 0x0000_0003);
+        let mut sr = ShiftRegister::new(0, 1);
+        assert!(!sr.shift(&NullBus, true));
+        assert!(sr.shift(&NullBus, false));
+        sr.load(0xFF);
+        assert_eq!(sr.value(), 1);
+        assert_eq!(sr.inp(0x0000), None);
+    }
+}
@@ -0,0 +1,243 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+use bus::ResetKind;
+use input::InputEvent;
+use machine::Machine;
+
+/// check the host time after this many cycles
+const DEFAULT_SLICE_CYCLES: i64 = 1000;
+
+/// a command for a MachineHandle, see MachineHandle::sender()
+#[derive(Clone,Debug,PartialEq)]
+pub enum Command {
+    Pause,
+    Resume,
+    AdvanceFrame,
+    AdvanceInstruction,
+    /// push a key event into the input queue
+    Input(InputEvent),
+    /// type a text at a number of characters per second
+    TypeText(String, i64),
+    /// request a reset from the host, see MachineHandle::take_reset()
+    Reset(ResetKind),
+}
+
+/// the state of a MachineHandle after run_for() or run_cycles()
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum RunState {
+    /// a frame (or a single-stepped instruction) is complete
+    Frame,
+    /// the budget ran out in the middle of a frame, the next call continues it
+    Yield,
+    /// paused, nothing has been executed
+    Paused,
+}
+
+/// the result of MachineHandle::run_for() and run_cycles()
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct RunResult {
+    pub state: RunState,
+    /// the number of executed cycles
+    pub cycles: i64,
+}
+
+/// a non-blocking front-end for a Machine
+///
+/// Machine::run() always executes a whole frame, which can block an
+/// async runtime or a browser event loop for too long (for instance in
+/// turbo mode, or on slow hosts). The MachineHandle instead executes a
+/// frame in slices: **run_for()** returns after a host time budget
+/// (checked every 1000 cycles by default), and the next call continues
+/// where the last one stopped, until the frame is complete. Hosts
+/// without a usable clock can use **run_cycles()** with a budget in
+/// cycles.
+///
+/// Control and input commands are sent through a channel, the Sender
+/// from **sender()** can be moved to other threads (like a UI or
+/// network thread), and the commands are applied at the start of each
+/// run_for() or run_cycles() call.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, Machine, MachineHandle, Command, RunState, NullBus, ResetKind};
+///
+/// let mut cpu = CPU::new_64k();
+/// // 100 cycles per frame, NOPs take 4 cycles
+/// let mut handle = MachineHandle::new(Machine::new(100));
+///
+/// let res = handle.run_cycles(40, || cpu.step(&NullBus), |_| ());
+/// assert_eq!((res.state, res.cycles), (RunState::Yield, 40));
+/// let res = handle.run_cycles(100, || cpu.step(&NullBus), |_| ());
+/// assert_eq!((res.state, res.cycles), (RunState::Frame, 60));
+///
+/// let sender = handle.sender();
+/// std::thread::spawn(move || {
+///     sender.send(Command::Pause).unwrap();
+///     sender.send(Command::Reset(ResetKind::Warm)).unwrap();
+/// }).join().unwrap();
+/// let res = handle.run_cycles(100, || cpu.step(&NullBus), |_| ());
+/// assert_eq!(res.state, RunState::Paused);
+/// assert_eq!(handle.take_reset(), Some(ResetKind::Warm));
+/// ```
+pub struct MachineHandle {
+    machine: Machine,
+    sender: Sender<Command>,
+    receiver: Receiver<Command>,
+    slice_cycles: i64,
+    reset: Option<ResetKind>,
+}
+
+impl MachineHandle {
+    /// create a handle which runs a Machine
+    pub fn new(machine: Machine) -> MachineHandle {
+        let (sender, receiver) = channel();
+        MachineHandle {
+            machine,
+            sender,
+            receiver,
+            slice_cycles: DEFAULT_SLICE_CYCLES,
+            reset: None,
+        }
+    }
+
+    /// the Machine
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// the Machine, for direct control
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    /// a Sender for commands, this can be cloned and moved to other threads
+    pub fn sender(&self) -> Sender<Command> {
+        self.sender.clone()
+    }
+
+    /// set the number of cycles after which run_for() checks the host time
+    pub fn set_slice_cycles(&mut self, cycles: i64) {
+        assert!(cycles > 0);
+        self.slice_cycles = cycles;
+    }
+
+    /// return a reset requested with Command::Reset and clear the request
+    pub fn take_reset(&mut self) -> Option<ResetKind> {
+        self.reset.take()
+    }
+
+    /// apply the commands received since the last call
+    fn apply_commands(&mut self) {
+        while let Ok(cmd) = self.receiver.try_recv() {
+            match cmd {
+                Command::Pause => self.machine.pause(),
+                Command::Resume => self.machine.resume(),
+                Command::AdvanceFrame => self.machine.advance_frame(),
+                Command::AdvanceInstruction => self.machine.advance_instruction(),
+                Command::Input(event) => {
+                    self.machine.input_mut().push(event);
+                }
+                Command::TypeText(text, chars_per_second) => {
+                    self.machine.type_text(&text, chars_per_second)
+                }
+                Command::Reset(kind) => self.reset = Some(kind),
+            }
+        }
+    }
+
+    /// execute until the frame is complete, or at most for a duration of host time
+    ///
+    /// The step and input closures are the same as for Machine::run_input(),
+    /// the budget may be exceeded by the time of one slice of cycles.
+    pub fn run_for<F, I>(&mut self, budget: Duration, mut step: F, mut input: I) -> RunResult
+        where F: FnMut() -> i64,
+              I: FnMut(InputEvent)
+    {
+        self.apply_commands();
+        let start = Instant::now();
+        let mut cycles = 0;
+        loop {
+            let (c, done) = self.machine.run_partial(self.slice_cycles, &mut step, &mut input);
+            cycles += c;
+            if done {
+                return RunResult { state: RunState::Frame, cycles };
+            }
+            if c == 0 {
+                return RunResult { state: RunState::Paused, cycles };
+            }
+            if start.elapsed() >= budget {
+                return RunResult { state: RunState::Yield, cycles };
+            }
+        }
+    }
+
+    /// execute until the frame is complete, or at most max_cycles
+    /// (plus the overshoot of the last instruction)
+    pub fn run_cycles<F, I>(&mut self, max_cycles: i64, step: F, input: I) -> RunResult
+        where F: FnMut() -> i64,
+              I: FnMut(InputEvent)
+    {
+        self.apply_commands();
+        let (cycles, done) = self.machine.run_partial(max_cycles, step, input);
+        let state = if done {
+            RunState::Frame
+        } else if cycles == 0 {
+            RunState::Paused
+        } else {
+            RunState::Yield
+        };
+        RunResult { state, cycles }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CPU;
+    use bus::NullBus;
+    use machine::Clock;
+
+    #[test]
+    fn run_for() {
+        let mut cpu = CPU::new_64k();
+        // a long frame, the budget runs out first
+        let mut handle = MachineHandle::new(Machine::new(1 << 40));
+        let res = handle.run_for(Duration::from_millis(1), || cpu.step(&NullBus), |_| ());
+        assert_eq!(res.state, RunState::Yield);
+        assert!(res.cycles >= 1000);
+
+        // a short frame completes within the budget
+        let mut handle = MachineHandle::new(Machine::new(10_000));
+        let res = handle.run_for(Duration::from_secs(10), || cpu.step(&NullBus), |_| ());
+        assert_eq!((res.state, res.cycles), (RunState::Frame, 10_000));
+    }
+
+    #[test]
+    fn commands() {
+        let mut cpu = CPU::new_64k();
+        let mut handle = MachineHandle::new(Machine::with_clock(Clock::new(1_000_000), 50));
+        let sender = handle.sender();
+        sender.send(Command::Pause).unwrap();
+        assert_eq!(handle.run_cycles(100, || cpu.step(&NullBus), |_| ()).state, RunState::Paused);
+
+        // a requested frame continues across calls
+        sender.send(Command::AdvanceFrame).unwrap();
+        assert_eq!(handle.run_cycles(10_000, || cpu.step(&NullBus), |_| ()).state, RunState::Yield);
+        assert_eq!(handle.run_cycles(10_000, || cpu.step(&NullBus), |_| ()).state, RunState::Frame);
+        assert_eq!(handle.run_cycles(10_000, || cpu.step(&NullBus), |_| ()).state, RunState::Paused);
+        sender.send(Command::AdvanceInstruction).unwrap();
+        let res = handle.run_cycles(10_000, || cpu.step(&NullBus), |_| ());
+        assert_eq!((res.state, res.cycles), (RunState::Frame, 4));
+
+        // key events are forwarded between instructions
+        sender.send(Command::Resume).unwrap();
+        sender.send(Command::Input(InputEvent::KeyDown(b'A'))).unwrap();
+        sender.send(Command::TypeText("B".to_string(), 100)).unwrap();
+        let mut events = Vec::new();
+        handle.run_cycles(100_000, || cpu.step(&NullBus), |e| events.push(e));
+        assert_eq!(events, [InputEvent::KeyDown(b'A'), InputEvent::KeyDown(b'B'), InputEvent::KeyUp(b'B')]);
+        assert_eq!(handle.take_reset(), None);
+    }
+}
@@ -0,0 +1,604 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use RegT;
+use bus::{Reset, ResetKind};
+
+/// data register (the task file registers are at index 0..7)
+pub const IDE_DATA: usize = 0;
+/// error register (read), features register (write)
+pub const IDE_ERROR: usize = 1;
+/// sector count register
+pub const IDE_SECTOR_COUNT: usize = 2;
+/// sector number, LBA bits 0..7
+pub const IDE_LBA0: usize = 3;
+/// cylinder low, LBA bits 8..15
+pub const IDE_LBA1: usize = 4;
+/// cylinder high, LBA bits 16..23
+pub const IDE_LBA2: usize = 5;
+/// drive/head, LBA bits 24..27
+pub const IDE_DRIVE_HEAD: usize = 6;
+/// status register (read), command register (write)
+pub const IDE_STATUS: usize = 7;
+
+/// status bit: the drive is busy
+pub const IDE_STATUS_BSY: u8 = 1 << 7;
+/// status bit: the drive is ready for commands
+pub const IDE_STATUS_DRDY: u8 = 1 << 6;
+/// status bit: seek complete
+pub const IDE_STATUS_DSC: u8 = 1 << 4;
+/// status bit: data transfer request
+pub const IDE_STATUS_DRQ: u8 = 1 << 3;
+/// status bit: the last command failed (see the error register)
+pub const IDE_STATUS_ERR: u8 = 1 << 0;
+
+// error register bits
+const ERROR_ABRT: u8 = 1 << 2;
+const ERROR_IDNF: u8 = 1 << 4;
+const ERROR_UNC: u8 = 1 << 6;
+
+const SECTOR_SIZE: usize = 512;
+const HEADS: u64 = 16;
+const SECTORS_PER_TRACK: u64 = 63;
+
+// the host side of a disk image
+trait DiskImage: Read + Write + Seek {}
+impl<T: Read + Write + Seek> DiskImage for T {}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum Transfer {
+    None,
+    Read,
+    Write,
+    Identify,
+}
+
+/// ATA hard disk or CompactFlash card on a simple 8-bit IDE interface
+///
+/// Emulates the task file registers and the PIO data transfer of an
+/// IDE drive (or a CompactFlash card in True IDE mode) which is backed
+/// by a disk image on the host, for instance a CP/M disk image of an
+/// RC2014 CF card. The image is a plain array of 512-byte sectors,
+/// written sectors are written through to the image immediately.
+///
+/// Simple 8-bit interfaces like the RC2014 CF module connect the
+/// drive's 8 register lines directly to I/O ports, usually the
+/// register index is the lower 3 bits of the port address. Because
+/// only the lower half of the 16-bit data bus is connected, the
+/// software enables the 8-bit transfer mode (SET FEATURES command
+/// with feature 0x01) before transferring data. Interfaces based on
+/// an 8255 PPI drive the register lines and the full 16-bit data bus
+/// through the PPI ports, and transfer data with **read_data16()**
+/// and **write_data16()**.
+///
+/// Supported commands are READ SECTORS, WRITE SECTORS, READ VERIFY,
+/// IDENTIFY DEVICE, SET FEATURES (8-bit mode), and the power management
+/// and cache commands (which do nothing), other commands are aborted.
+/// Commands complete instantly, the drive is never busy.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{IdeDrive, IDE_STATUS, IDE_ERROR, IDE_SECTOR_COUNT, IDE_LBA0, IDE_LBA1,
+///            IDE_LBA2, IDE_DRIVE_HEAD, IDE_DATA, IDE_STATUS_DRQ};
+///
+/// // a 1 MByte image with the sector number in the first byte of each sector
+/// let mut image = vec![0u8; 1024 * 1024];
+/// for (i, sector) in image.chunks_mut(512).enumerate() {
+///     sector[0] = i as u8;
+/// }
+/// let mut cf = IdeDrive::from_bytes(image);
+/// assert_eq!(cf.num_sectors(), 2048);
+///
+/// // enable the 8-bit mode
+/// cf.write(IDE_ERROR, 0x01);
+/// cf.write(IDE_STATUS, 0xEF);
+/// // read sector 0x123 in LBA mode
+/// cf.write(IDE_SECTOR_COUNT, 1);
+/// cf.write(IDE_LBA0, 0x23);
+/// cf.write(IDE_LBA1, 0x01);
+/// cf.write(IDE_LBA2, 0x00);
+/// cf.write(IDE_DRIVE_HEAD, 0xE0);
+/// cf.write(IDE_STATUS, 0x20);
+/// assert!(cf.read(IDE_STATUS) as u8 & IDE_STATUS_DRQ != 0);
+/// assert_eq!(cf.read(IDE_DATA), 0x23);
+/// for _ in 1..512 {
+///     cf.read(IDE_DATA);
+/// }
+/// assert_eq!(cf.read(IDE_STATUS) as u8 & IDE_STATUS_DRQ, 0);
+/// ```
+pub struct IdeDrive {
+    image: Box<dyn DiskImage>,
+    num_sectors: u64,
+    model: String,
+    error: u8,
+    features: u8,
+    sector_count: u8,
+    lba: [u8; 3],
+    drive_head: u8,
+    status: u8,
+    eight_bit: bool,
+    transfer: Transfer,
+    /// sectors left in the current transfer
+    remaining: u32,
+    /// the current sector of the transfer
+    sector: u64,
+    buffer: [u8; SECTOR_SIZE],
+    pos: usize,
+}
+
+impl IdeDrive {
+    /// create a drive backed by a host disk image
+    pub fn new<T: Read + Write + Seek + 'static>(mut image: T) -> io::Result<IdeDrive> {
+        let size = image.seek(SeekFrom::End(0))?;
+        Ok(IdeDrive {
+            image: Box::new(image),
+            num_sectors: size / SECTOR_SIZE as u64,
+            model: String::from("rz80 IDE drive"),
+            error: 0,
+            features: 0,
+            sector_count: 1,
+            lba: [1, 0, 0],
+            drive_head: 0,
+            status: IDE_STATUS_DRDY | IDE_STATUS_DSC,
+            eight_bit: false,
+            transfer: Transfer::None,
+            remaining: 0,
+            sector: 0,
+            buffer: [0; SECTOR_SIZE],
+            pos: 0,
+        })
+    }
+
+    /// open a disk image file for reading and writing
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<IdeDrive> {
+        let file: File = OpenOptions::new().read(true).write(true).open(path)?;
+        IdeDrive::new(file)
+    }
+
+    /// create a drive with an in-memory disk image
+    pub fn from_bytes(image: Vec<u8>) -> IdeDrive {
+        IdeDrive::new(Cursor::new(image)).unwrap()
+    }
+
+    /// set the model name returned by IDENTIFY DEVICE
+    pub fn set_model(&mut self, model: &str) {
+        self.model = model.to_string();
+    }
+
+    /// number of 512-byte sectors of the disk image
+    pub fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    /// true if the 8-bit transfer mode is enabled
+    pub fn eight_bit(&self) -> bool {
+        self.eight_bit
+    }
+
+    /// read a task file register (0..7)
+    pub fn read(&mut self, reg: usize) -> RegT {
+        if !self.selected() {
+            return 0x00;
+        }
+        let val = match reg & 7 {
+            IDE_DATA => {
+                if self.eight_bit {
+                    self.read_byte()
+                } else {
+                    // only the low byte is visible on an 8-bit interface
+                    self.read_data16() as u8
+                }
+            }
+            IDE_ERROR => self.error,
+            IDE_SECTOR_COUNT => self.sector_count,
+            IDE_LBA0 => self.lba[0],
+            IDE_LBA1 => self.lba[1],
+            IDE_LBA2 => self.lba[2],
+            IDE_DRIVE_HEAD => self.drive_head,
+            _ => self.status,
+        };
+        val as RegT
+    }
+
+    /// write a task file register (0..7)
+    pub fn write(&mut self, reg: usize, val: RegT) {
+        let val = val as u8;
+        match reg & 7 {
+            IDE_DATA => {
+                if self.eight_bit {
+                    self.write_byte(val);
+                } else {
+                    self.write_data16(val as u16);
+                }
+            }
+            IDE_ERROR => self.features = val,
+            IDE_SECTOR_COUNT => self.sector_count = val,
+            IDE_LBA0 => self.lba[0] = val,
+            IDE_LBA1 => self.lba[1] = val,
+            IDE_LBA2 => self.lba[2] = val,
+            IDE_DRIVE_HEAD => self.drive_head = val,
+            _ => {
+                if self.selected() {
+                    self.command(val);
+                }
+            }
+        }
+    }
+
+    /// read a 16-bit word from the data register
+    pub fn read_data16(&mut self) -> u16 {
+        let l = self.read_byte() as u16;
+        let h = self.read_byte() as u16;
+        h << 8 | l
+    }
+
+    /// write a 16-bit word to the data register
+    pub fn write_data16(&mut self, val: u16) {
+        self.write_byte(val as u8);
+        self.write_byte((val >> 8) as u8);
+    }
+
+    /// flush written sectors to the host disk image
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.image.flush()
+    }
+
+    // only the master drive is emulated
+    fn selected(&self) -> bool {
+        self.drive_head & 0x10 == 0
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        if self.transfer != Transfer::Read && self.transfer != Transfer::Identify {
+            return 0xFF;
+        }
+        let val = self.buffer[self.pos];
+        self.pos += 1;
+        if self.pos == SECTOR_SIZE {
+            if self.transfer == Transfer::Read {
+                self.update_address();
+            }
+            self.remaining -= 1;
+            if self.remaining > 0 {
+                self.sector += 1;
+                self.load_sector();
+            } else {
+                self.end_transfer();
+            }
+        }
+        val
+    }
+
+    fn write_byte(&mut self, val: u8) {
+        if self.transfer != Transfer::Write {
+            return;
+        }
+        self.buffer[self.pos] = val;
+        self.pos += 1;
+        if self.pos == SECTOR_SIZE && self.store_sector() {
+            self.remaining -= 1;
+            if self.remaining > 0 {
+                self.sector += 1;
+                self.pos = 0;
+            } else {
+                self.end_transfer();
+            }
+        }
+    }
+
+    fn command(&mut self, cmd: u8) {
+        self.end_transfer();
+        self.error = 0;
+        self.status = IDE_STATUS_DRDY | IDE_STATUS_DSC;
+        match cmd {
+            // READ SECTORS (with and without retries)
+            0x20 | 0x21 => {
+                if self.start_transfer(Transfer::Read) {
+                    self.load_sector();
+                }
+            }
+            // WRITE SECTORS
+            0x30 | 0x31 => {
+                self.start_transfer(Transfer::Write);
+            }
+            // READ VERIFY SECTORS
+            0x40 | 0x41 => {
+                let sector = self.start_sector();
+                if sector + self.count() as u64 > self.num_sectors {
+                    self.abort(ERROR_IDNF);
+                }
+            }
+            // IDENTIFY DEVICE
+            0xEC => {
+                self.identify();
+                self.transfer = Transfer::Identify;
+                self.remaining = 1;
+                self.pos = 0;
+                self.status |= IDE_STATUS_DRQ;
+            }
+            // SET FEATURES
+            0xEF => match self.features {
+                0x01 => self.eight_bit = true,
+                0x81 => self.eight_bit = false,
+                // write cache and transfer mode
+                0x02 | 0x82 | 0x03 => {}
+                _ => self.abort(ERROR_ABRT),
+            },
+            // CHECK POWER MODE, the drive is always active
+            0xE5 | 0x98 => self.sector_count = 0xFF,
+            // EXECUTE DEVICE DIAGNOSTIC, no error
+            0x90 => self.error = 0x01,
+            // power management, cache, RECALIBRATE, SEEK, INITIALIZE DEVICE PARAMETERS
+            0xE0..=0xE3 | 0xE6 | 0xE7 | 0xEA | 0x94..=0x97 | 0x99 | 0x10..=0x1F |
+            0x70..=0x7F | 0x91 => {}
+            _ => self.abort(ERROR_ABRT),
+        }
+    }
+
+    fn abort(&mut self, error: u8) {
+        self.end_transfer();
+        self.error = error;
+        self.status = IDE_STATUS_DRDY | IDE_STATUS_DSC | IDE_STATUS_ERR;
+    }
+
+    // a sector count of 0 means 256 sectors
+    fn count(&self) -> u32 {
+        if self.sector_count == 0 { 256 } else { self.sector_count as u32 }
+    }
+
+    fn start_sector(&self) -> u64 {
+        if self.drive_head & 0x40 != 0 {
+            ((self.drive_head as u64 & 0x0F) << 24) | ((self.lba[2] as u64) << 16) |
+            ((self.lba[1] as u64) << 8) | self.lba[0] as u64
+        } else {
+            let cylinder = ((self.lba[2] as u64) << 8) | self.lba[1] as u64;
+            let head = self.drive_head as u64 & 0x0F;
+            let sector = self.lba[0] as u64;
+            if sector == 0 {
+                // sector numbers start at 1
+                u64::MAX
+            } else {
+                (cylinder * HEADS + head) * SECTORS_PER_TRACK + sector - 1
+            }
+        }
+    }
+
+    fn start_transfer(&mut self, transfer: Transfer) -> bool {
+        self.sector = self.start_sector();
+        self.remaining = self.count();
+        if self.sector >= self.num_sectors {
+            self.abort(ERROR_IDNF);
+            false
+        } else {
+            self.transfer = transfer;
+            self.pos = 0;
+            self.status |= IDE_STATUS_DRQ;
+            true
+        }
+    }
+
+    fn end_transfer(&mut self) {
+        self.transfer = Transfer::None;
+        self.remaining = 0;
+        self.status &= !IDE_STATUS_DRQ;
+    }
+
+    // update the task file to the sector after the transfer
+    fn update_address(&mut self) {
+        let next = self.sector + 1;
+        if self.drive_head & 0x40 != 0 {
+            self.lba = [next as u8, (next >> 8) as u8, (next >> 16) as u8];
+            self.drive_head = (self.drive_head & 0xF0) | ((next >> 24) as u8 & 0x0F);
+        }
+        self.sector_count = self.remaining.wrapping_sub(1) as u8;
+    }
+
+    fn load_sector(&mut self) {
+        self.pos = 0;
+        if self.sector >= self.num_sectors {
+            self.abort(ERROR_IDNF);
+            return;
+        }
+        let res = self.image.seek(SeekFrom::Start(self.sector * SECTOR_SIZE as u64))
+            .and_then(|_| self.image.read_exact(&mut self.buffer));
+        if res.is_err() {
+            self.abort(ERROR_UNC);
+        }
+    }
+
+    fn store_sector(&mut self) -> bool {
+        if self.sector >= self.num_sectors {
+            self.abort(ERROR_IDNF);
+            return false;
+        }
+        let res = self.image.seek(SeekFrom::Start(self.sector * SECTOR_SIZE as u64))
+            .and_then(|_| self.image.write_all(&self.buffer));
+        if res.is_err() {
+            self.abort(ERROR_UNC);
+            return false;
+        }
+        self.update_address();
+        true
+    }
+
+    // the IDENTIFY DEVICE data, 256 little-endian words
+    fn identify(&mut self) {
+        let mut words = [0u16; 256];
+        let cylinders = (self.num_sectors / (HEADS * SECTORS_PER_TRACK)).min(16383);
+        // removable CompactFlash-compatible device
+        words[0] = 0x848A;
+        words[1] = cylinders as u16;
+        words[3] = HEADS as u16;
+        words[6] = SECTORS_PER_TRACK as u16;
+        words[7] = (self.num_sectors >> 16) as u16;
+        words[8] = self.num_sectors as u16;
+        ata_string(&mut words[10..20], "00000001");
+        ata_string(&mut words[23..27], "1.0");
+        ata_string(&mut words[27..47], &self.model);
+        words[47] = 0x0001;
+        // LBA supported
+        words[49] = 0x0200;
+        words[53] = 0x0001;
+        words[54] = cylinders as u16;
+        words[55] = HEADS as u16;
+        words[56] = SECTORS_PER_TRACK as u16;
+        let chs = cylinders * HEADS * SECTORS_PER_TRACK;
+        words[57] = chs as u16;
+        words[58] = (chs >> 16) as u16;
+        let lba = self.num_sectors.min(0x0FFF_FFFF);
+        words[60] = lba as u16;
+        words[61] = (lba >> 16) as u16;
+        for (i, w) in words.iter().enumerate() {
+            self.buffer[i * 2] = *w as u8;
+            self.buffer[i * 2 + 1] = (*w >> 8) as u8;
+        }
+    }
+}
+
+// an ATA string, space padded, with the first character in the high byte of each word
+fn ata_string(words: &mut [u16], s: &str) {
+    let mut bytes = s.bytes().chain(::std::iter::repeat(b' '));
+    for w in words.iter_mut() {
+        let h = bytes.next().unwrap_or(b' ') as u16;
+        let l = bytes.next().unwrap_or(b' ') as u16;
+        *w = h << 8 | l;
+    }
+}
+
+impl Reset for IdeDrive {
+    fn system_reset(&mut self, _kind: ResetKind) {
+        self.end_transfer();
+        self.error = 0x01;
+        self.features = 0;
+        self.sector_count = 1;
+        self.lba = [1, 0, 0];
+        self.drive_head = 0;
+        self.status = IDE_STATUS_DRDY | IDE_STATUS_DSC;
+        self.eight_bit = false;
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drive() -> IdeDrive {
+        let mut image = vec![0u8; 64 * SECTOR_SIZE];
+        for (i, sector) in image.chunks_mut(SECTOR_SIZE).enumerate() {
+            for (j, b) in sector.iter_mut().enumerate() {
+                *b = (i + j) as u8;
+            }
+        }
+        IdeDrive::from_bytes(image)
+    }
+
+    fn setup(ide: &mut IdeDrive, cmd: u8, count: u8, lba: u8) {
+        ide.write(IDE_SECTOR_COUNT, count as RegT);
+        ide.write(IDE_LBA0, lba as RegT);
+        ide.write(IDE_LBA1, 0);
+        ide.write(IDE_LBA2, 0);
+        ide.write(IDE_DRIVE_HEAD, 0xE0);
+        ide.write(IDE_STATUS, cmd as RegT);
+    }
+
+    #[test]
+    fn read_write() {
+        let mut ide = drive();
+        ide.write(IDE_ERROR, 0x01);
+        ide.write(IDE_STATUS, 0xEF);
+        assert!(ide.eight_bit());
+
+        // write 2 sectors at LBA 10
+        setup(&mut ide, 0x30, 2, 10);
+        assert_eq!(ide.read(IDE_STATUS), 0x58);
+        for i in 0..2 * SECTOR_SIZE {
+            ide.write(IDE_DATA, (i / 2) as RegT);
+        }
+        assert_eq!(ide.read(IDE_STATUS), 0x50);
+        assert_eq!(ide.read(IDE_LBA0), 12);
+        assert_eq!(ide.read(IDE_SECTOR_COUNT), 0);
+
+        // read back 3 sectors from LBA 9
+        setup(&mut ide, 0x20, 3, 9);
+        let data: Vec<RegT> = (0..3 * SECTOR_SIZE).map(|_| ide.read(IDE_DATA)).collect();
+        assert_eq!(data[0], 9);
+        assert_eq!(data[511], (9 + 511) & 0xFF);
+        assert_eq!(data[512], 0);
+        assert_eq!(data[1023], 255);
+        assert_eq!(data[1024], 0);
+        assert_eq!(data[1026], 1);
+        assert_eq!(data[1535], 0xFF);
+        assert_eq!(ide.read(IDE_STATUS), 0x50);
+        assert_eq!(ide.read(IDE_LBA0), 12);
+        // no more data
+        assert_eq!(ide.read(IDE_DATA), 0xFF);
+
+        // 16-bit mode
+        ide.write(IDE_ERROR, 0x81);
+        ide.write(IDE_STATUS, 0xEF);
+        setup(&mut ide, 0x20, 1, 10);
+        assert_eq!(ide.read_data16(), 0x0000);
+        assert_eq!(ide.read_data16(), 0x0101);
+        // the high byte is lost on an 8-bit interface
+        assert_eq!(ide.read(IDE_DATA), 0x02);
+    }
+
+    #[test]
+    fn errors() {
+        let mut ide = drive();
+        // beyond the end of the image
+        setup(&mut ide, 0x20, 1, 64);
+        assert_eq!(ide.read(IDE_STATUS), 0x51);
+        assert_eq!(ide.read(IDE_ERROR), ERROR_IDNF as RegT);
+        // the transfer stops at the end of the image
+        setup(&mut ide, 0x20, 2, 63);
+        assert_eq!(ide.read(IDE_STATUS), 0x58);
+        for _ in 0..SECTOR_SIZE {
+            ide.read(IDE_DATA);
+        }
+        assert_eq!(ide.read(IDE_STATUS), 0x51);
+        // unknown command
+        ide.write(IDE_STATUS, 0xC8);
+        assert_eq!(ide.read(IDE_STATUS), 0x51);
+        assert_eq!(ide.read(IDE_ERROR), ERROR_ABRT as RegT);
+        ide.write(IDE_STATUS, 0xE7);
+        assert_eq!(ide.read(IDE_STATUS), 0x50);
+        // slave drive isn't present
+        ide.write(IDE_DRIVE_HEAD, 0xF0);
+        assert_eq!(ide.read(IDE_STATUS), 0x00);
+        ide.write(IDE_STATUS, 0x20);
+        ide.write(IDE_DRIVE_HEAD, 0xE0);
+        assert_eq!(ide.read(IDE_STATUS), 0x50);
+    }
+
+    #[test]
+    fn chs_and_identify() {
+        let mut ide = drive();
+        ide.set_model("TEST CF");
+        // CHS: cylinder 0, head 0, sector 5 is LBA 4
+        ide.write(IDE_SECTOR_COUNT, 1);
+        ide.write(IDE_LBA0, 5);
+        ide.write(IDE_LBA1, 0);
+        ide.write(IDE_LBA2, 0);
+        ide.write(IDE_DRIVE_HEAD, 0xA0);
+        ide.write(IDE_STATUS, 0x20);
+        assert_eq!(ide.read_data16(), 0x0504);
+
+        ide.write(IDE_STATUS, 0xEC);
+        let words: Vec<u16> = (0..256).map(|_| ide.read_data16()).collect();
+        assert_eq!(words[0], 0x848A);
+        assert_eq!(words[49], 0x0200);
+        assert_eq!((words[60], words[61]), (64, 0));
+        assert_eq!(words[27], u16::from_be_bytes(*b"TE"));
+        assert_eq!(words[30], u16::from_be_bytes(*b"F "));
+        assert_eq!(ide.read(IDE_STATUS), 0x50);
+
+        ide.write(IDE_ERROR, 0x01);
+        ide.write(IDE_STATUS, 0xEF);
+        ide.system_reset(ResetKind::Warm);
+        assert!(!ide.eight_bit());
+        assert_eq!(ide.read(IDE_ERROR), 0x01);
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::VecDeque;
+
+/// a keyboard input event
+///
+/// Keys are identified by a system-specific key code, usually the
+/// ASCII code the key produces.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum InputEvent {
+    KeyDown(u8),
+    KeyUp(u8),
+}
+
+/// cycle-stamped queue of keyboard input events
+///
+/// Host front-ends usually poll the keyboard once per frame, and
+/// overwriting a single 'current key' with the polled state drops
+/// keys when several keys are pressed within the same frame, and
+/// can hand the same key twice to the emulated system when it isn't
+/// scanning the keyboard at the right moment. Instead, the front-end
+/// pushes key-down and key-up transitions into the InputQueue,
+/// which timestamps each event so that it's held for at least
+/// **hold_cycles()** before the next event is delivered. The run loop
+/// advances the queue by the executed CPU cycles and forwards
+/// due events to the keyboard matrix (see **KeyMatrix::apply()**).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{InputQueue, InputEvent};
+///
+/// // each key state is held for at least 1000 cycles
+/// let mut queue = InputQueue::new(1000);
+/// assert_eq!(queue.push(InputEvent::KeyDown(b'A')), 0);
+/// assert_eq!(queue.push(InputEvent::KeyUp(b'A')), 1000);
+/// assert_eq!(queue.push(InputEvent::KeyDown(b'B')), 2000);
+///
+/// queue.advance(1500);
+/// assert_eq!(queue.pop(), Some(InputEvent::KeyDown(b'A')));
+/// assert_eq!(queue.pop(), Some(InputEvent::KeyUp(b'A')));
+/// assert_eq!(queue.pop(), None);
+/// assert_eq!(queue.cycles_to_next(), Some(500));
+/// ```
+#[derive(Clone,Debug)]
+pub struct InputQueue {
+    now: i64,
+    hold: i64,
+    last: Option<i64>,
+    events: VecDeque<(i64, InputEvent)>,
+}
+
+impl InputQueue {
+    /// create an empty input queue with the minimal hold time between events in CPU cycles
+    pub fn new(hold_cycles: i64) -> InputQueue {
+        assert!(hold_cycles >= 0);
+        InputQueue {
+            now: 0,
+            hold: hold_cycles,
+            last: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// the minimal hold time between events in CPU cycles
+    pub fn hold_cycles(&self) -> i64 {
+        self.hold
+    }
+
+    /// get the current time in cycles
+    pub fn now(&self) -> i64 {
+        self.now
+    }
+
+    /// advance the current time by a number of cycles
+    #[inline(always)]
+    pub fn advance(&mut self, cycles: i64) {
+        self.now += cycles;
+    }
+
+    /// queue an event as early as possible, returns the delivery time
+    pub fn push(&mut self, event: InputEvent) -> i64 {
+        let time = self.now;
+        self.push_at(time, event)
+    }
+
+    /// queue an event at an absolute time, returns the delivery time
+    ///
+    /// The event is delayed if it would be delivered less than
+    /// hold_cycles() after the previous event.
+    pub fn push_at(&mut self, time: i64, event: InputEvent) -> i64 {
+        let time = match self.last {
+            Some(last) if time < last + self.hold => last + self.hold,
+            _ => time,
+        };
+        self.last = Some(time);
+        self.events.push_back((time, event));
+        time
+    }
+
+    /// queue key-down and key-up events to type a text, returns the time after the last character
+    ///
+    /// Each character is held down for the first half of cycles_per_char,
+    /// so that repeated characters are seen as separate key presses.
+    /// Newlines are typed as Enter (0x0D), non-ASCII characters are skipped.
+    pub fn type_text(&mut self, text: &str, cycles_per_char: i64) -> i64 {
+        assert!(cycles_per_char >= 2);
+        let mut time = self.now;
+        for c in text.chars().filter(|c| c.is_ascii()) {
+            let key = if c == '\n' { 0x0D } else { c as u8 };
+            time = self.push_at(time, InputEvent::KeyDown(key));
+            self.push_at(time + cycles_per_char / 2, InputEvent::KeyUp(key));
+            time += cycles_per_char;
+        }
+        time
+    }
+
+    /// pop the next due event
+    #[inline(always)]
+    pub fn pop(&mut self) -> Option<InputEvent> {
+        let due = match self.events.front() {
+            Some(&(time, _)) => time <= self.now,
+            None => false,
+        };
+        if due {
+            self.events.pop_front().map(|(_, event)| event)
+        } else {
+            None
+        }
+    }
+
+    /// get the number of cycles until the next event (0 if already due)
+    pub fn cycles_to_next(&self) -> Option<i64> {
+        self.events.front().map(|&(t, _)| if t > self.now { t - self.now } else { 0 })
+    }
+
+    /// the number of queued events
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// true if no events are queued
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// remove all queued events
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.last = None;
+    }
+}
+
+/// a keyboard matrix of up to 8 columns with 8 lines each
+///
+/// Each key code is mapped to the matrix bits it closes (usually
+/// one bit, plus the bit of a modifier key like shift). The state
+/// of the whole matrix is a 64-bit mask with 8 bits per column,
+/// the key in line 'l' of column 'c' is bit c*8+l (see **bit()**).
+/// The matrix bits are 'active high', emulated systems that read
+/// an 'active low' matrix need to invert the result.
+///
+/// Any number of keys can be held down at the same time (e.g. the
+/// direction keys and fire button of a game), each matrix position
+/// stays closed until all keys closing it are released, so releasing
+/// one shifted key doesn't release the shift bit of another held
+/// shifted key. Matrix positions can also be pressed directly with
+/// **press()** and **release()**.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{KeyMatrix, InputEvent};
+///
+/// let mut kbd = KeyMatrix::new();
+/// kbd.map(b'A', KeyMatrix::bit(1, 2));
+/// kbd.map(b'a', KeyMatrix::bit(1, 2) | KeyMatrix::bit(7, 6));
+/// kbd.map(b'B', KeyMatrix::bit(2, 2));
+///
+/// kbd.apply(InputEvent::KeyDown(b'a'));
+/// assert_eq!(kbd.column(1), 1<<2);
+/// assert_eq!(kbd.column(7), 1<<6);
+/// assert_eq!(kbd.lines(0x82), (1<<2) | (1<<6));
+///
+/// // chorded keys
+/// kbd.apply(InputEvent::KeyDown(b'B'));
+/// kbd.apply(InputEvent::KeyUp(b'a'));
+/// assert_eq!(kbd.state(), KeyMatrix::bit(2, 2));
+/// kbd.apply(InputEvent::KeyUp(b'B'));
+/// assert_eq!(kbd.state(), 0);
+/// ```
+#[derive(Clone,Debug)]
+pub struct KeyMatrix {
+    masks: Vec<u64>,
+    keys_down: Vec<bool>,
+    counts: [u8; 64],
+    state: u64,
+}
+
+impl KeyMatrix {
+    /// create a keyboard matrix without mapped keys
+    pub fn new() -> KeyMatrix {
+        KeyMatrix {
+            masks: vec![0; 256],
+            keys_down: vec![false; 256],
+            counts: [0; 64],
+            state: 0,
+        }
+    }
+
+    /// the matrix bit of the key at a column and line
+    pub fn bit(col: usize, line: usize) -> u64 {
+        assert!(col < 8 && line < 8);
+        (1u64 << line) << (col * 8)
+    }
+
+    /// map a key code to the matrix bits it closes
+    pub fn map(&mut self, key: u8, mask: u64) {
+        self.masks[key as usize] = mask;
+    }
+
+    /// the matrix bits of a key code
+    pub fn mask(&self, key: u8) -> u64 {
+        self.masks[key as usize]
+    }
+
+    /// press a key, pressing a key that's already down is ignored
+    pub fn key_down(&mut self, key: u8) {
+        if !self.keys_down[key as usize] {
+            self.keys_down[key as usize] = true;
+            let mask = self.masks[key as usize];
+            self.close(mask);
+        }
+    }
+
+    /// release a key, releasing a key that's not down is ignored
+    pub fn key_up(&mut self, key: u8) {
+        if self.keys_down[key as usize] {
+            self.keys_down[key as usize] = false;
+            let mask = self.masks[key as usize];
+            self.open(mask);
+        }
+    }
+
+    /// true if a key is held down
+    pub fn is_key_down(&self, key: u8) -> bool {
+        self.keys_down[key as usize]
+    }
+
+    /// press the key at a matrix position
+    pub fn press(&mut self, col: usize, line: usize) {
+        self.close(KeyMatrix::bit(col, line));
+    }
+
+    /// release the key at a matrix position pressed with press()
+    pub fn release(&mut self, col: usize, line: usize) {
+        self.open(KeyMatrix::bit(col, line));
+    }
+
+    /// release all keys and matrix positions
+    pub fn release_all(&mut self) {
+        for down in self.keys_down.iter_mut() {
+            *down = false;
+        }
+        self.counts = [0; 64];
+        self.state = 0;
+    }
+
+    /// forward an input event
+    pub fn apply(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::KeyDown(key) => self.key_down(key),
+            InputEvent::KeyUp(key) => self.key_up(key),
+        }
+    }
+
+    /// the state of the whole matrix, 8 bits per column
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    /// the closed lines of a column
+    pub fn column(&self, col: usize) -> u8 {
+        (self.state >> ((col & 7) * 8)) as u8
+    }
+
+    /// the closed lines of all columns selected by a column bit mask
+    pub fn lines(&self, column_mask: u8) -> u8 {
+        (0..8)
+            .filter(|col| (column_mask & (1 << col)) != 0)
+            .fold(0, |lines, col| lines | self.column(col))
+    }
+
+    // count a closing contact for each matrix bit
+    fn close(&mut self, mask: u64) {
+        for bit in 0..64 {
+            if (mask & (1 << bit)) != 0 {
+                self.counts[bit] = self.counts[bit].saturating_add(1);
+                self.state |= 1 << bit;
+            }
+        }
+    }
+
+    // uncount a closing contact for each matrix bit, the bit opens
+    // when no key closes it anymore
+    fn open(&mut self, mask: u64) {
+        for bit in 0..64 {
+            if (mask & (1 << bit)) != 0 && self.counts[bit] > 0 {
+                self.counts[bit] -= 1;
+                if self.counts[bit] == 0 {
+                    self.state &= !(1 << bit);
+                }
+            }
+        }
+    }
+}
+
+impl Default for KeyMatrix {
+    fn default() -> KeyMatrix {
+        KeyMatrix::new()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hold_time() {
+        let mut queue = InputQueue::new(100);
+        queue.advance(1000);
+        assert_eq!(queue.push(InputEvent::KeyDown(1)), 1000);
+        assert_eq!(queue.push_at(1050, InputEvent::KeyUp(1)), 1100);
+        assert_eq!(queue.push_at(1500, InputEvent::KeyDown(2)), 1500);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop(), Some(InputEvent::KeyDown(1)));
+        assert_eq!(queue.pop(), None);
+        queue.advance(500);
+        assert_eq!(queue.pop(), Some(InputEvent::KeyUp(1)));
+        assert_eq!(queue.pop(), Some(InputEvent::KeyDown(2)));
+        assert!(queue.is_empty());
+
+        // the hold time also applies to events pushed after the queue ran empty
+        assert_eq!(queue.push(InputEvent::KeyUp(2)), 1600);
+        queue.clear();
+        assert_eq!(queue.push(InputEvent::KeyUp(2)), 1500);
+    }
+
+    #[test]
+    fn type_text() {
+        let mut queue = InputQueue::new(0);
+        queue.advance(100);
+        assert_eq!(queue.type_text("a\u{e4}\n", 10), 120);
+        let mut events = Vec::new();
+        for _ in 0..20 {
+            while let Some(event) = queue.pop() {
+                events.push((queue.now(), event));
+            }
+            queue.advance(1);
+        }
+        assert_eq!(events, [(100, InputEvent::KeyDown(b'a')), (105, InputEvent::KeyUp(b'a')),
+                            (110, InputEvent::KeyDown(0x0D)), (115, InputEvent::KeyUp(0x0D))]);
+
+        // a long hold time slows down typing
+        let mut queue = InputQueue::new(8);
+        assert_eq!(queue.type_text("xy", 10), 26);
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.cycles_to_next(), Some(0));
+    }
+
+    #[test]
+    fn rollover() {
+        let shift = KeyMatrix::bit(7, 6);
+        let mut kbd = KeyMatrix::new();
+        kbd.map(1, KeyMatrix::bit(0, 0));
+        kbd.map(2, KeyMatrix::bit(7, 7));
+        kbd.map(3, KeyMatrix::bit(0, 1) | shift);
+        kbd.map(4, KeyMatrix::bit(0, 2) | shift);
+        kbd.key_down(1);
+        kbd.key_down(2);
+        assert_eq!(kbd.state(), 1 | (1 << 63));
+        kbd.key_up(1);
+        assert_eq!(kbd.state(), 1 << 63);
+        assert!(kbd.is_key_down(2) && !kbd.is_key_down(1));
+
+        // repeated key-down and key-up events are ignored
+        kbd.key_down(2);
+        kbd.key_up(2);
+        kbd.key_up(2);
+        assert_eq!(kbd.state(), 0);
+
+        // a shared shift bit stays closed until both shifted keys are released
+        kbd.key_down(3);
+        kbd.key_down(4);
+        kbd.key_up(3);
+        assert_eq!(kbd.state(), KeyMatrix::bit(0, 2) | shift);
+        kbd.press(7, 6);
+        kbd.key_up(4);
+        assert_eq!(kbd.state(), shift);
+        kbd.release(7, 6);
+        assert_eq!(kbd.state(), 0);
+
+        kbd.key_down(1);
+        kbd.press(3, 3);
+        kbd.release_all();
+        assert_eq!(kbd.lines(0xFF), 0);
+        assert!(!kbd.is_key_down(1));
+    }
+}
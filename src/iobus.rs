@@ -0,0 +1,228 @@
+use std::cell::RefCell;
+use RegT;
+use bus::Bus;
+
+/// CPU-side system trait with mutable access
+///
+/// The IoBus gets the CPU's port I/O and interrupt acknowledge cycles.
+/// It is passed as mutable reference to **CPU::step_io()**,
+/// **CPU::run_block_io()** and **CPU::step_until_io()**, so it can update
+/// its own state without having to wrap it in Cell or RefCell. Closures
+/// can be used as IoBus through **IoFn**.
+///
+/// New systems should implement IoBus for the struct which owns the
+/// chips (with the CPU in a separate field), and collect the chip
+/// callbacks with a **RecordingBus**. Implement the Bus trait directly
+/// only if the CPU's other callbacks are needed (e.g. m1() tracing,
+/// io_wait() or the IM0 instruction bytes).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, IoBus, RegT};
+///
+/// struct Latch {
+///     val: RegT,
+/// }
+///
+/// impl IoBus for Latch {
+///     fn inp(&mut self, _port: RegT) -> RegT {
+///         self.val
+///     }
+///     fn outp(&mut self, _port: RegT, val: RegT) {
+///         self.val = val;
+///     }
+/// }
+///
+/// let mut cpu = CPU::new_64k();
+/// let mut latch = Latch { val: 0 };
+/// // LD A,0x23; OUT (0x10),A
+/// cpu.mem.write(0x0000, &[0x3E, 0x23, 0xD3, 0x10]);
+/// cpu.step_io(&mut latch);
+/// cpu.step_io(&mut latch);
+/// assert_eq!(latch.val, 0x23);
+/// ```
+#[allow(unused_variables)]
+pub trait IoBus {
+    /// CPU reads from I/O port
+    fn inp(&mut self, port: RegT) -> RegT {
+        0
+    }
+    /// CPU writes to I/O port
+    fn outp(&mut self, port: RegT, val: RegT) {}
+    /// interrupt request acknowledge, return the interrupt vector (in IM2)
+    /// or the opcode byte to execute (in IM0)
+    fn irq_ack(&mut self) -> RegT {
+        0
+    }
+    /// the CPU executed a RETI
+    fn irq_reti(&mut self) {}
+}
+
+/// IoBus implementation from a port read and a port write closure
+///
+/// ```
+/// use rz80::{CPU, IoFn};
+///
+/// let mut cpu = CPU::new_64k();
+/// let mut written = Vec::new();
+/// {
+///     let mut io = IoFn::new(|port| port & 0xFF, |port, val| written.push((port, val)));
+///     // IN A,(0x42); OUT (0x43),A
+///     cpu.mem.write(0x0000, &[0xDB, 0x42, 0xD3, 0x43]);
+///     cpu.step_io(&mut io);
+///     cpu.step_io(&mut io);
+/// }
+/// assert_eq!(written, vec![(0x4243, 0x42)]);
+/// ```
+pub struct IoFn<I, O> {
+    inp: I,
+    outp: O,
+}
+
+impl<I, O> IoFn<I, O>
+    where I: FnMut(RegT) -> RegT,
+          O: FnMut(RegT, RegT)
+{
+    /// create an IoBus from a read and write closure
+    pub fn new(inp: I, outp: O) -> IoFn<I, O> {
+        IoFn { inp, outp }
+    }
+}
+
+impl<I, O> IoBus for IoFn<I, O>
+    where I: FnMut(RegT) -> RegT,
+          O: FnMut(RegT, RegT)
+{
+    fn inp(&mut self, port: RegT) -> RegT {
+        (self.inp)(port)
+    }
+    fn outp(&mut self, port: RegT, val: RegT) {
+        (self.outp)(port, val)
+    }
+}
+
+/// adapter to use an IoBus where a Bus is expected
+///
+/// All Bus methods except cpu_inp(), cpu_outp(), irq_ack() and
+/// irq_reti() keep their default implementations.
+pub struct IoBusAdapter<'a> {
+    io: RefCell<&'a mut dyn IoBus>,
+}
+
+impl<'a> IoBusAdapter<'a> {
+    /// wrap a mutable IoBus reference
+    pub fn new(io: &'a mut dyn IoBus) -> IoBusAdapter<'a> {
+        IoBusAdapter { io: RefCell::new(io) }
+    }
+}
+
+impl<'a> Bus for IoBusAdapter<'a> {
+    fn cpu_inp(&self, port: RegT) -> RegT {
+        self.io.borrow_mut().inp(port)
+    }
+    fn cpu_outp(&self, port: RegT, val: RegT) {
+        self.io.borrow_mut().outp(port, val);
+    }
+    fn irq_ack(&self) -> RegT {
+        self.io.borrow_mut().irq_ack()
+    }
+    fn irq_reti(&self) {
+        self.io.borrow_mut().irq_reti();
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::{CPU, StopReason};
+    use registers::Im;
+
+    struct Ports {
+        data: [RegT; 256],
+    }
+    impl IoBus for Ports {
+        fn inp(&mut self, port: RegT) -> RegT {
+            self.data[(port & 0xFF) as usize]
+        }
+        fn outp(&mut self, port: RegT, val: RegT) {
+            self.data[(port & 0xFF) as usize] = val;
+        }
+    }
+
+    #[test]
+    fn adapter() {
+        let mut ports = Ports { data: [0; 256] };
+        {
+            let bus = IoBusAdapter::new(&mut ports);
+            bus.cpu_outp(0x1234, 0x56);
+            assert_eq!(bus.cpu_inp(0x0034), 0x56);
+            // other Bus methods use the defaults
+            assert_eq!(bus.irq_ack(), 0);
+        }
+        assert_eq!(ports.data[0x34], 0x56);
+    }
+
+    #[test]
+    fn closures() {
+        let mut reads = 0;
+        let mut last = (0, 0);
+        {
+            let mut io = IoFn::new(|port| {
+                                       reads += 1;
+                                       port >> 8
+                                   },
+                                   |port, val| last = (port, val));
+            assert_eq!(io.inp(0x1234), 0x12);
+            io.outp(0x5678, 0x9A);
+        }
+        assert_eq!(reads, 1);
+        assert_eq!(last, (0x5678, 0x9A));
+    }
+
+    struct System {
+        out: Vec<RegT>,
+        acks: usize,
+        retis: usize,
+    }
+    impl IoBus for System {
+        fn outp(&mut self, _port: RegT, val: RegT) {
+            self.out.push(val);
+        }
+        fn irq_ack(&mut self) -> RegT {
+            self.acks += 1;
+            0x10
+        }
+        fn irq_reti(&mut self) {
+            self.retis += 1;
+        }
+    }
+
+    #[test]
+    fn stepping() {
+        let mut sys = System { out: Vec::new(), acks: 0, retis: 0 };
+        let mut cpu = CPU::new_64k();
+        // LD A,1; OUT (0),A; INC A; OUT (0),A; HALT
+        cpu.mem.write(0x0000, &[0x3E, 0x01, 0xD3, 0x00, 0x3C, 0xD3, 0x00, 0x76]);
+        // IM2 vector 0x10 points to: OUT (0),A; RETI
+        cpu.mem.w16(0x0010, 0x0100);
+        cpu.mem.write(0x0100, &[0xD3, 0x00, 0xED, 0x4D]);
+        cpu.reg.set_sp(0x8000);
+        assert_eq!(cpu.step_io(&mut sys), 7);
+        assert_eq!(cpu.run_block_io(&mut sys), 11);
+        assert_eq!(cpu.step_until_io(&mut sys, 1000, |_| false), (4 + 11 + 4, StopReason::Halt));
+        assert_eq!(sys.out, [1, 2]);
+
+        // the interrupt vector comes from IoBus::irq_ack()
+        cpu.set_interrupt_mode(Im::Two);
+        cpu.set_iff(true, true);
+        cpu.irq();
+        cpu.step_io(&mut sys);
+        assert_eq!(cpu.reg.pc(), 0x0100);
+        cpu.step_io(&mut sys);
+        cpu.step_io(&mut sys);
+        assert_eq!(cpu.reg.pc(), 0x0008);
+        assert_eq!((sys.out.len(), sys.acks, sys.retis), (3, 1, 1));
+    }
+}
@@ -0,0 +1,325 @@
+use RegT;
+
+/// I/O port read handler, called with the system object and the full 16-bit port
+pub type InpFn<T> = fn(&T, RegT) -> RegT;
+/// I/O port write handler, called with the system object, full 16-bit port and value
+pub type OutpFn<T> = fn(&T, RegT, RegT);
+
+struct Entry<T> {
+    mask: RegT,
+    value: RegT,
+    inp: Option<InpFn<T>>,
+    outp: Option<OutpFn<T>>,
+}
+
+/// port-mask aware I/O dispatch
+///
+/// Most Z80 systems only decode some of the 16 address bits when
+/// selecting an I/O device, so that a device shows up at several
+/// port addresses. Instead of handling all aliases in a big match
+/// statement in Bus::cpu_inp() and Bus::cpu_outp(), devices are
+/// registered in an IoMap with a (mask, value) pattern,
+/// a port matches if (port & mask) == value. The first registered
+/// device which matches a port wins.
+///
+/// The IoMap is generic over the system type which implements the
+/// Bus trait, the handler functions are called with a reference to
+/// the system object.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::Cell;
+/// use rz80::{Bus, IoMap, RegT};
+///
+/// struct System {
+///     io: IoMap<System>,
+///     latch: Cell<RegT>,
+/// }
+///
+/// impl System {
+///     fn new() -> System {
+///         let mut io = IoMap::new();
+///         // a latch which only decodes address bits A0..A3 (ports 0x?8)
+///         io.map(0x0F, 0x08,
+///                |sys: &System, _| sys.latch.get(),
+///                |sys: &System, _, val| sys.latch.set(val));
+///         System { io: io, latch: Cell::new(0) }
+///     }
+/// }
+///
+/// impl Bus for System {
+///     fn cpu_inp(&self, port: RegT) -> RegT {
+///         self.io.inp(self, port)
+///     }
+///     fn cpu_outp(&self, port: RegT, val: RegT) {
+///         self.io.outp(self, port, val);
+///     }
+/// }
+///
+/// let sys = System::new();
+/// sys.cpu_outp(0x1238, 0x23);
+/// assert_eq!(sys.cpu_inp(0x0018), 0x23);
+/// // unmapped ports read as 0xFF
+/// assert_eq!(sys.cpu_inp(0x0009), 0xFF);
+/// ```
+pub struct IoMap<T> {
+    entries: Vec<Entry<T>>,
+}
+
+impl<T> IoMap<T> {
+    /// create a new, empty I/O map
+    pub fn new() -> IoMap<T> {
+        IoMap { entries: Vec::new() }
+    }
+
+    /// register a device with read and write handler
+    pub fn map(&mut self, mask: RegT, value: RegT, inp: InpFn<T>, outp: OutpFn<T>) {
+        self.add(mask, value, Some(inp), Some(outp));
+    }
+
+    /// register a read-only device
+    pub fn map_inp(&mut self, mask: RegT, value: RegT, inp: InpFn<T>) {
+        self.add(mask, value, Some(inp), None);
+    }
+
+    /// register a write-only device
+    pub fn map_outp(&mut self, mask: RegT, value: RegT, outp: OutpFn<T>) {
+        self.add(mask, value, None, Some(outp));
+    }
+
+    /// remove all registered devices
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn add(&mut self, mask: RegT, value: RegT, inp: Option<InpFn<T>>, outp: Option<OutpFn<T>>) {
+        assert_eq!(value & !mask, 0);
+        self.entries.push(Entry {
+            mask: mask & 0xFFFF,
+            value,
+            inp,
+            outp,
+        });
+    }
+
+    /// find the read handler for a port
+    pub fn find_inp(&self, port: RegT) -> Option<InpFn<T>> {
+        self.entries
+            .iter()
+            .find(|e| e.inp.is_some() && (port & e.mask) == e.value)
+            .and_then(|e| e.inp)
+    }
+
+    /// find the write handler for a port
+    pub fn find_outp(&self, port: RegT) -> Option<OutpFn<T>> {
+        self.entries
+            .iter()
+            .find(|e| e.outp.is_some() && (port & e.mask) == e.value)
+            .and_then(|e| e.outp)
+    }
+
+    /// dispatch a port read, returns 0xFF for unmapped ports
+    pub fn inp(&self, sys: &T, port: RegT) -> RegT {
+        match self.find_inp(port) {
+            Some(inp) => inp(sys, port),
+            None => 0xFF,
+        }
+    }
+
+    /// dispatch a port write, returns false for unmapped ports
+    pub fn outp(&self, sys: &T, port: RegT, val: RegT) -> bool {
+        match self.find_outp(port) {
+            Some(outp) => {
+                outp(sys, port, val);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<T> Default for IoMap<T> {
+    fn default() -> IoMap<T> {
+        IoMap::new()
+    }
+}
+
+/// a (mask, value) pattern on the full 16-bit port address
+///
+/// Helps to describe partially decoded ports in terms of address lines,
+/// for instance the Spectrum 128 paging port is selected by A15 and A1
+/// being low (so it is usually called 0x7FFD), the +3's second paging
+/// port (0x1FFD) additionally needs A12 high and A13, A14 low.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::PortDecoder;
+///
+/// let p128 = PortDecoder::any().low(15).low(1);
+/// assert_eq!(p128, PortDecoder::parse("0xxx xxxx xxxx xx0x").unwrap());
+/// assert!(p128.matches(0x7FFD));
+/// assert!(p128.matches(0x3FFD));
+/// assert!(!p128.matches(0xFFFD));
+/// let p3 = PortDecoder::parse("0001 xxxx xxxx xx0x").unwrap();
+/// assert_eq!((p3.mask, p3.value), (0xF002, 0x1000));
+/// assert!(p3.matches(0x1FFD));
+/// assert!(!p3.matches(0x7FFD));
+/// ```
+///
+/// The mask and value can be used directly to register a device in an IoMap:
+/// `io.map(p128.mask, p128.value, inp, outp)`.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct PortDecoder {
+    pub mask: RegT,
+    pub value: RegT,
+}
+
+impl PortDecoder {
+    /// create a port decoder from mask and value
+    pub fn new(mask: RegT, value: RegT) -> PortDecoder {
+        assert_eq!(value & !mask, 0);
+        PortDecoder {
+            mask: mask & 0xFFFF,
+            value,
+        }
+    }
+
+    /// a port decoder which matches all ports
+    pub fn any() -> PortDecoder {
+        PortDecoder::new(0, 0)
+    }
+
+    /// a port decoder which matches the lower 8 address bits (A0..A7)
+    pub fn low_byte(port: RegT) -> PortDecoder {
+        PortDecoder::new(0x00FF, port & 0xFF)
+    }
+
+    /// parse a pattern of 16 '0', '1' or 'x' chars (A15 first)
+    ///
+    /// Spaces and underscores can be used as separators.
+    pub fn parse(pattern: &str) -> Option<PortDecoder> {
+        let mut mask = 0;
+        let mut value = 0;
+        let mut bits = 0;
+        for c in pattern.chars().filter(|&c| c != ' ' && c != '_') {
+            let (m, v) = match c {
+                '0' => (1, 0),
+                '1' => (1, 1),
+                'x' | 'X' => (0, 0),
+                _ => return None,
+            };
+            mask = (mask << 1) | m;
+            value = (value << 1) | v;
+            bits += 1;
+        }
+        if bits == 16 {
+            Some(PortDecoder::new(mask, value))
+        } else {
+            None
+        }
+    }
+
+    /// additionally require an address line (0..15) to be low
+    pub fn low(self, line: u32) -> PortDecoder {
+        assert!(line < 16);
+        PortDecoder::new(self.mask | (1 << line), self.value & !(1 << line))
+    }
+
+    /// additionally require an address line (0..15) to be high
+    pub fn high(self, line: u32) -> PortDecoder {
+        assert!(line < 16);
+        PortDecoder::new(self.mask | (1 << line), self.value | (1 << line))
+    }
+
+    /// true if a 16-bit port address is selected
+    #[inline(always)]
+    pub fn matches(&self, port: RegT) -> bool {
+        (port & self.mask) == self.value
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct State {
+        last_port: Cell<RegT>,
+        last_val: Cell<RegT>,
+    }
+
+    fn state_inp(s: &State, port: RegT) -> RegT {
+        s.last_port.set(port);
+        port & 0x0F
+    }
+    fn state_outp(s: &State, port: RegT, val: RegT) {
+        s.last_port.set(port);
+        s.last_val.set(val);
+    }
+    fn state_inp_42(_: &State, _: RegT) -> RegT {
+        0x42
+    }
+
+    #[test]
+    fn dispatch() {
+        let state = State {
+            last_port: Cell::new(0),
+            last_val: Cell::new(0),
+        };
+        let mut io = IoMap::new();
+        io.map(0xF8, 0x80, state_inp, state_outp);
+        io.map_inp(0x00FF, 0x10, state_inp_42);
+        io.map_outp(0x8000, 0x0000, state_outp);
+
+        // ports 0x80..0x87, upper 8 bits ignored
+        assert_eq!(io.inp(&state, 0x1283), 0x03);
+        assert_eq!(state.last_port.get(), 0x1283);
+        assert!(io.outp(&state, 0xFF87, 0x33));
+        assert_eq!(state.last_val.get(), 0x33);
+
+        // read-only port, the write falls through to the 0x8000 entry
+        assert_eq!(io.inp(&state, 0x2210), 0x42);
+        assert!(io.outp(&state, 0x2210, 0x44));
+        assert_eq!(state.last_port.get(), 0x2210);
+        assert_eq!(state.last_val.get(), 0x44);
+
+        // unmapped ports
+        assert_eq!(io.inp(&state, 0x8011), 0xFF);
+        assert!(!io.outp(&state, 0x8011, 0x55));
+        assert_eq!(state.last_val.get(), 0x44);
+    }
+
+    #[test]
+    fn port_decoder() {
+        let ula = PortDecoder::any().low(0);
+        assert!(ula.matches(0x7FFE) && ula.matches(0x00FE) && !ula.matches(0x00FF));
+        let kempston = PortDecoder::low_byte(0x1F);
+        assert_eq!(kempston, PortDecoder::parse("xxxxxxxx_00011111").unwrap());
+        assert!(kempston.matches(0xAB1F));
+        assert_eq!(PortDecoder::any().high(15).high(14).low(1),
+                   PortDecoder::new(0xC002, 0xC000));
+        // a later low()/high() for the same line overrides
+        assert_eq!(PortDecoder::any().high(3).low(3), PortDecoder::new(0x0008, 0x0000));
+        assert_eq!(PortDecoder::parse("xxxx"), None);
+        assert_eq!(PortDecoder::parse("0xxx xxxx xxxx xx0x 1"), None);
+        assert_eq!(PortDecoder::parse("0xxx xxxx xxxx xx2x"), None);
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let state = State {
+            last_port: Cell::new(0),
+            last_val: Cell::new(0),
+        };
+        let mut io = IoMap::new();
+        io.map_inp(0xFF, 0x10, state_inp_42);
+        io.map_inp(0xF0, 0x10, state_inp);
+        assert_eq!(io.inp(&state, 0x10), 0x42);
+        assert_eq!(io.inp(&state, 0x11), 0x01);
+        io.clear();
+        assert_eq!(io.inp(&state, 0x10), 0xFF);
+    }
+}
@@ -0,0 +1,267 @@
+use std::fmt;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use RegT;
+
+/// direction of a traced I/O access
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum IoDir {
+    In,
+    Out,
+}
+
+/// a traced I/O access
+#[derive(Clone,Debug,PartialEq)]
+pub struct IoAccess {
+    /// the time of the access in cycles
+    pub cycles: u64,
+    pub dir: IoDir,
+    /// the full 16-bit port address
+    pub port: RegT,
+    pub val: RegT,
+    /// 'device: description' from the matching decoder
+    pub info: Option<String>,
+}
+
+impl fmt::Display for IoAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let dir = match self.dir {
+            IoDir::In => "IN ",
+            IoDir::Out => "OUT",
+        };
+        write!(f, "{:10} {} {:04X} {:02X}", self.cycles, dir, self.port, self.val)?;
+        if let Some(ref info) = self.info {
+            write!(f, "  {}", info)?;
+        }
+        Ok(())
+    }
+}
+
+/// I/O access decoder function, called with direction, port and value
+pub type IoDecoderFn = Box<dyn FnMut(IoDir, RegT, RegT) -> Option<String>>;
+
+struct Decoder {
+    mask: RegT,
+    value: RegT,
+    name: String,
+    decode: IoDecoderFn,
+}
+
+/// a log of I/O port accesses with device-specific decoding
+///
+/// The system's Bus implementation calls **inp()** and **outp()** for
+/// each port access, the IoTrace stamps them with the time set by
+/// **set_cycles()** and keeps the most recent accesses (65536 by
+/// default). Decoders registered for a port (mask, value) pattern like
+/// in an IoMap turn the raw values into readable descriptions, the
+/// device modules provide decoders for their control registers
+/// (see ctc_decoder() and pio_control_decoder()). Decoding happens
+/// when an access is recorded, so that decoders can track device state
+/// like 'time constant follows'.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{IoTrace, ctc_decoder};
+///
+/// let mut trace = IoTrace::new();
+/// trace.add_decoder(0xFC, 0x80, "CTC", ctc_decoder());
+/// trace.add_label(0xFF, 0x08, "KBD");
+/// trace.set_cycles(100);
+/// trace.outp(0x80, 0x85);
+/// trace.outp(0x80, 0x40);
+/// trace.outp(0x08, 0x01);
+/// let lines: Vec<String> = trace.dump().lines().map(|l| l.to_string()).collect();
+/// assert_eq!(lines, [
+///     "       100 OUT 0080 85  CTC0: control 85h = timer, prescale 16, int on, constant follows",
+///     "       100 OUT 0080 40  CTC0: time constant 40h",
+///     "       100 OUT 0008 01  KBD",
+/// ]);
+/// ```
+pub struct IoTrace {
+    enabled: Cell<bool>,
+    cycles: Cell<u64>,
+    capacity: usize,
+    log: RefCell<VecDeque<IoAccess>>,
+    decoders: RefCell<Vec<Decoder>>,
+}
+
+impl IoTrace {
+    /// create an enabled IoTrace without decoders
+    pub fn new() -> IoTrace {
+        IoTrace {
+            enabled: Cell::new(true),
+            cycles: Cell::new(0),
+            capacity: 1 << 16,
+            log: RefCell::new(VecDeque::new()),
+            decoders: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// register a decoder for ports where (port & mask) == value
+    ///
+    /// The first matching decoder is used, its description is prefixed
+    /// with the device name and the port's lowest 2 bits (the channel
+    /// number of most Z80 chips) if the mask doesn't include them.
+    pub fn add_decoder<F>(&mut self, mask: RegT, value: RegT, name: &str, decode: F)
+        where F: FnMut(IoDir, RegT, RegT) -> Option<String> + 'static
+    {
+        self.decoders.borrow_mut().push(Decoder {
+            mask,
+            value,
+            name: name.to_string(),
+            decode: Box::new(decode),
+        });
+    }
+
+    /// register a device name for ports without decoding the values
+    pub fn add_label(&mut self, mask: RegT, value: RegT, name: &str) {
+        self.add_decoder(mask, value, name, |_, _, _| None);
+    }
+
+    /// set the max number of recorded accesses, older accesses are dropped
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        let mut log = self.log.borrow_mut();
+        while log.len() > capacity {
+            log.pop_front();
+        }
+    }
+
+    /// enable or disable recording
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.set(enabled);
+    }
+
+    /// true if recording is enabled
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    /// set the current time for the recorded accesses
+    pub fn set_cycles(&self, cycles: u64) {
+        self.cycles.set(cycles);
+    }
+
+    /// record a port read
+    pub fn inp(&self, port: RegT, val: RegT) {
+        self.record(IoDir::In, port, val);
+    }
+
+    /// record a port write
+    pub fn outp(&self, port: RegT, val: RegT) {
+        self.record(IoDir::Out, port, val);
+    }
+
+    fn record(&self, dir: IoDir, port: RegT, val: RegT) {
+        if !self.enabled.get() || self.capacity == 0 {
+            return;
+        }
+        let info = {
+            let mut decoders = self.decoders.borrow_mut();
+            decoders.iter_mut().find(|d| (port & d.mask) == d.value).map(|d| {
+                let name = if (d.mask & 3) == 0 {
+                    format!("{}{}", d.name, port & 3)
+                } else {
+                    d.name.clone()
+                };
+                match (d.decode)(dir, port, val) {
+                    Some(text) => format!("{}: {}", name, text),
+                    None => name,
+                }
+            })
+        };
+        let mut log = self.log.borrow_mut();
+        if log.len() >= self.capacity {
+            log.pop_front();
+        }
+        log.push_back(IoAccess {
+            cycles: self.cycles.get(),
+            dir,
+            port,
+            val,
+            info,
+        });
+    }
+
+    /// number of recorded accesses
+    pub fn len(&self) -> usize {
+        self.log.borrow().len()
+    }
+
+    /// true if no accesses are recorded
+    pub fn is_empty(&self) -> bool {
+        self.log.borrow().is_empty()
+    }
+
+    /// get a copy of the recorded accesses
+    pub fn entries(&self) -> Vec<IoAccess> {
+        self.log.borrow().iter().cloned().collect()
+    }
+
+    /// remove and return the recorded accesses
+    pub fn take(&self) -> Vec<IoAccess> {
+        self.log.borrow_mut().drain(..).collect()
+    }
+
+    /// remove the recorded accesses
+    pub fn clear(&self) {
+        self.log.borrow_mut().clear();
+    }
+
+    /// the recorded accesses as text, one line per access
+    pub fn dump(&self) -> String {
+        let mut s = String::new();
+        for access in self.log.borrow().iter() {
+            s += &format!("{}\n", access);
+        }
+        s
+    }
+}
+
+impl Default for IoTrace {
+    fn default() -> IoTrace {
+        IoTrace::new()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let mut trace = IoTrace::new();
+        trace.add_decoder(0xFF, 0x10, "LATCH", |dir, _, val| {
+            if dir == IoDir::Out { Some(format!("bits {:08b}", val)) } else { None }
+        });
+        trace.add_label(0xF0, 0x10, "NEVER");
+        trace.add_label(0xF0, 0x20, "DEV");
+        trace.set_capacity(3);
+        trace.outp(0x10, 0x81);
+        trace.set_cycles(5);
+        trace.inp(0x10, 0xFF);
+        trace.set_enabled(false);
+        trace.outp(0x11, 0x00);
+        trace.set_enabled(true);
+        trace.outp(0x1234, 0x55);
+        trace.inp(0x23, 0x12);
+        assert_eq!(trace.len(), 3);
+        let entries = trace.entries();
+        assert_eq!(entries[0].info, Some("LATCH".to_string()));
+        assert_eq!(entries[1].info, None);
+        assert_eq!(entries[2], IoAccess {
+            cycles: 5,
+            dir: IoDir::In,
+            port: 0x23,
+            val: 0x12,
+            info: Some("DEV3".to_string()),
+        });
+        assert_eq!(format!("{}", entries[2]), "         5 IN  0023 12  DEV3");
+        assert_eq!(trace.take().len(), 3);
+        assert!(trace.is_empty());
+        trace.outp(0x10, 0x81);
+        assert_eq!(trace.dump(), "         5 OUT 0010 81  LATCH: bits 10000001\n");
+    }
+}
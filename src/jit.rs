@@ -0,0 +1,1156 @@
+use std::mem;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, SigRef, Type, Value};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+use RegT;
+use cpu::CPU;
+use memory::Memory;
+use blocks::{decode_block, Inst};
+use decoder::{Instruction, Operand};
+use registers::{Reg8, Reg16};
+use registers::CF;
+use registers::NF;
+use registers::VF;
+use registers::PF;
+use registers::HF;
+use registers::ZF;
+use registers::SF;
+use registers::XYF;
+
+// register slots in the state array handed to compiled blocks
+const B: usize = 0;
+const C: usize = 1;
+const D: usize = 2;
+const E: usize = 3;
+const H: usize = 4;
+const L: usize = 5;
+const A: usize = 6;
+const F: usize = 7;
+const SP: usize = 8;
+const WZ: usize = 9;
+const NUM_VARS: usize = 10;
+const PC: usize = 10;
+const M1: usize = 11;
+const NUM_SLOTS: usize = 12;
+
+// map an 8-bit register of a decoded instruction to a state slot
+fn slot8(r: Reg8) -> usize {
+    match r {
+        Reg8::B => B,
+        Reg8::C => C,
+        Reg8::D => D,
+        Reg8::E => E,
+        Reg8::H => H,
+        Reg8::L => L,
+        Reg8::A => A,
+        Reg8::F => F,
+        _ => unreachable!(),
+    }
+}
+
+// number of times a block must be executed before it is compiled
+const HOT_THRESHOLD: u32 = 16;
+// number of compiled blocks before all code is thrown away
+const MAX_BLOCKS: usize = 8192;
+
+type BlockFn = unsafe extern "C" fn(*mut u32, *mut Memory) -> i64;
+
+extern "C" fn mem_read(mem: *mut Memory, addr: u32) -> u32 {
+    let mem = unsafe { &*mem };
+    mem.r8(addr as RegT) as u32
+}
+
+// write a byte, returns 1 if the write might have modified the running block
+extern "C" fn mem_write(mem: *mut Memory, addr: u32, val: u32, code_mask: u64) -> u32 {
+    let mem = unsafe { &mut *mem };
+    let map_gen = mem.map_generation();
+    mem.w8(addr as RegT, val as RegT);
+    let page = (addr & 0xFFFF) >> 10;
+    (((code_mask >> page) & 1) != 0 || mem.map_generation() != map_gen) as u32
+}
+
+/// compiled code for a block, or None if the block can't be compiled
+struct Code {
+    func: Option<BlockFn>,
+    pages: [RegT; 2],
+    gens: [u32; 2],
+}
+
+#[derive(Default)]
+struct Entry {
+    hits: u32,
+    recompiles: u32,
+    code: Option<Box<Code>>,
+}
+
+/// IR generation state for one block
+struct Compiler<'a> {
+    b: FunctionBuilder<'a>,
+    vars: [Variable; NUM_VARS],
+    state: Value,
+    mem: Value,
+    ptr_type: Type,
+    read_sig: SigRef,
+    write_sig: SigRef,
+    code_mask: u64,
+    cycles: i64,
+    m1: i64,
+}
+
+impl<'a> Compiler<'a> {
+    fn get(&mut self, slot: usize) -> Value {
+        self.b.use_var(self.vars[slot])
+    }
+
+    fn set(&mut self, slot: usize, val: Value) {
+        self.b.def_var(self.vars[slot], val);
+    }
+
+    fn set8(&mut self, slot: usize, val: Value) {
+        let v = self.b.ins().band_imm(val, 0xFF);
+        self.set(slot, v);
+    }
+
+    fn iconst(&mut self, val: RegT) -> Value {
+        self.b.ins().iconst(types::I32, val as i64)
+    }
+
+    fn get16(&mut self, hi: usize, lo: usize) -> Value {
+        let h = self.get(hi);
+        let l = self.get(lo);
+        let h = self.b.ins().ishl_imm(h, 8);
+        self.b.ins().bor(h, l)
+    }
+
+    fn set16(&mut self, hi: usize, lo: usize, val: Value) {
+        let h = self.b.ins().ushr_imm(val, 8);
+        self.set8(hi, h);
+        self.set8(lo, val);
+    }
+
+    // the 16-bit registers BC, DE, HL, AF and SP
+    fn get_reg16(&mut self, r: Reg16) -> Value {
+        match r {
+            Reg16::BC => self.get16(B, C),
+            Reg16::DE => self.get16(D, E),
+            Reg16::HL => self.get16(H, L),
+            Reg16::AF => self.get16(A, F),
+            Reg16::SP => self.get(SP),
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_reg16(&mut self, r: Reg16, val: Value) {
+        match r {
+            Reg16::BC => self.set16(B, C, val),
+            Reg16::DE => self.set16(D, E, val),
+            Reg16::HL => self.set16(H, L, val),
+            Reg16::AF => self.set16(A, F, val),
+            Reg16::SP => {
+                let v = self.b.ins().band_imm(val, 0xFFFF);
+                self.set(SP, v)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn read8(&mut self, addr: Value) -> Value {
+        let callee = self.b.ins().iconst(self.ptr_type, mem_read as *const () as usize as i64);
+        let addr = self.b.ins().band_imm(addr, 0xFFFF);
+        let call = self.b.ins().call_indirect(self.read_sig, callee, &[self.mem, addr]);
+        self.b.inst_results(call)[0]
+    }
+
+    fn read16(&mut self, addr: Value) -> Value {
+        let l = self.read8(addr);
+        let addr1 = self.b.ins().iadd_imm(addr, 1);
+        let h = self.read8(addr1);
+        let h = self.b.ins().ishl_imm(h, 8);
+        self.b.ins().bor(h, l)
+    }
+
+    // write a byte, returns non-zero if the block must be left
+    fn write8(&mut self, addr: Value, val: Value) -> Value {
+        let callee = self.b.ins().iconst(self.ptr_type, mem_write as *const () as usize as i64);
+        let addr = self.b.ins().band_imm(addr, 0xFFFF);
+        let val = self.b.ins().band_imm(val, 0xFF);
+        let mask = self.b.ins().iconst(types::I64, self.code_mask as i64);
+        let call = self.b.ins().call_indirect(self.write_sig, callee, &[self.mem, addr, val, mask]);
+        self.b.inst_results(call)[0]
+    }
+
+    fn write16(&mut self, addr: Value, val: Value) -> Value {
+        let r0 = self.write8(addr, val);
+        let addr1 = self.b.ins().iadd_imm(addr, 1);
+        let h = self.b.ins().ushr_imm(val, 8);
+        let r1 = self.write8(addr1, h);
+        self.b.ins().bor(r0, r1)
+    }
+
+    // store the registers back into the state array and return
+    fn exit(&mut self, pc: Value, cycles: i64, m1: i64) {
+        for slot in 0..NUM_VARS {
+            let v = self.get(slot);
+            self.b.ins().store(MemFlags::trusted(), v, self.state, (slot * 4) as i32);
+        }
+        let pc = self.b.ins().band_imm(pc, 0xFFFF);
+        self.b.ins().store(MemFlags::trusted(), pc, self.state, (PC * 4) as i32);
+        let m1 = self.b.ins().iconst(types::I32, m1);
+        self.b.ins().store(MemFlags::trusted(), m1, self.state, (M1 * 4) as i32);
+        let cycles = self.b.ins().iconst(types::I64, cycles);
+        self.b.ins().return_(&[cycles]);
+    }
+
+    fn exit_const(&mut self, pc: RegT, cycles: i64, m1: i64) {
+        let pc = self.iconst(pc);
+        self.exit(pc, cycles, m1);
+    }
+
+    // leave the block if cond is non-zero, otherwise continue in a new IR block
+    fn exit_if(&mut self, cond: Value, pc: RegT, cycles: i64, m1: i64) {
+        let exit_block = self.b.create_block();
+        let cont_block = self.b.create_block();
+        self.b.ins().brif(cond, exit_block, &[], cont_block, &[]);
+        self.b.switch_to_block(exit_block);
+        self.exit_const(pc, cycles, m1);
+        self.b.switch_to_block(cont_block);
+    }
+
+    // two exits selected by cond (e.g. taken and not-taken branch)
+    fn exit_branch(&mut self, cond: Value, taken: (Value, i64), not_taken: (Value, i64), m1: i64) {
+        let taken_block = self.b.create_block();
+        let not_taken_block = self.b.create_block();
+        self.b.ins().brif(cond, taken_block, &[], not_taken_block, &[]);
+        self.b.switch_to_block(taken_block);
+        self.exit(taken.0, taken.1, m1);
+        self.b.switch_to_block(not_taken_block);
+        self.exit(not_taken.0, not_taken.1, m1);
+    }
+
+    // evaluate a condition code on the F register
+    fn cc(&mut self, y: RegT) -> Value {
+        let mask = [ZF, ZF, CF, CF, PF, PF, SF, SF][y as usize];
+        let f = self.get(F);
+        let bit = self.b.ins().band_imm(f, mask as i64);
+        if (y & 1) == 0 {
+            self.b.ins().icmp_imm(IntCC::Equal, bit, 0)
+        } else {
+            self.b.ins().icmp_imm(IntCC::NotEqual, bit, 0)
+        }
+    }
+
+    // Z and S flags
+    fn flags_sz(&mut self, res: Value) -> Value {
+        let res8 = self.b.ins().band_imm(res, 0xFF);
+        let is_zero = self.b.ins().icmp_imm(IntCC::Equal, res8, 0);
+        let zf = self.iconst(ZF);
+        let sf = self.b.ins().band_imm(res, SF as i64);
+        self.b.ins().select(is_zero, zf, sf)
+    }
+
+    fn flags_szp(&mut self, val: Value) -> Value {
+        let v = self.b.ins().band_imm(val, 0xFF);
+        let sz = self.flags_sz(v);
+        let xy = self.b.ins().band_imm(v, XYF as i64);
+        let bits = self.b.ins().popcnt(v);
+        let odd = self.b.ins().band_imm(bits, 1);
+        let even = self.b.ins().bxor_imm(odd, 1);
+        let pf = self.b.ins().ishl_imm(even, 2);
+        let f = self.b.ins().bor(sz, xy);
+        self.b.ins().bor(f, pf)
+    }
+
+    // the flags of add, sub and cp, xy is the source of the X and Y flags
+    fn flags_arith(&mut self, acc: Value, val: Value, res: Value, sub: bool, xy: Value) -> Value {
+        let sz = self.flags_sz(res);
+        let xy = self.b.ins().band_imm(xy, XYF as i64);
+        let c = self.b.ins().sshr_imm(res, 8);
+        let cf = self.b.ins().band_imm(c, CF as i64);
+        let h = self.b.ins().bxor(acc, val);
+        let h = self.b.ins().bxor(h, res);
+        let hf = self.b.ins().band_imm(h, HF as i64);
+        let v = if sub {
+            let v0 = self.b.ins().bxor(acc, val);
+            let v1 = self.b.ins().bxor(res, acc);
+            self.b.ins().band(v0, v1)
+        } else {
+            let v0 = self.b.ins().bxor(acc, val);
+            let v0 = self.b.ins().bxor_imm(v0, 0x80);
+            let v1 = self.b.ins().bxor(val, res);
+            self.b.ins().band(v0, v1)
+        };
+        let v = self.b.ins().sshr_imm(v, 5);
+        let vf = self.b.ins().band_imm(v, VF as i64);
+        let f = self.b.ins().bor(sz, xy);
+        let f = self.b.ins().bor(f, cf);
+        let f = self.b.ins().bor(f, hf);
+        let f = self.b.ins().bor(f, vf);
+        if sub { self.b.ins().bor_imm(f, NF as i64) } else { f }
+    }
+
+    fn carry(&mut self) -> Value {
+        let f = self.get(F);
+        self.b.ins().band_imm(f, CF as i64)
+    }
+
+    fn alu8(&mut self, alu: RegT, val: Value) {
+        let acc = self.get(A);
+        match alu {
+            // ADD, ADC
+            0 | 1 => {
+                let mut res = self.b.ins().iadd(acc, val);
+                if alu == 1 {
+                    let c = self.carry();
+                    res = self.b.ins().iadd(res, c);
+                }
+                let f = self.flags_arith(acc, val, res, false, res);
+                self.set(F, f);
+                self.set8(A, res);
+            }
+            // SUB, SBC, CP
+            2 | 3 | 7 => {
+                let mut res = self.b.ins().isub(acc, val);
+                if alu == 3 {
+                    let c = self.carry();
+                    res = self.b.ins().isub(res, c);
+                }
+                let xy = if alu == 7 { val } else { res };
+                let f = self.flags_arith(acc, val, res, true, xy);
+                self.set(F, f);
+                if alu != 7 {
+                    self.set8(A, res);
+                }
+            }
+            // AND, XOR, OR
+            _ => {
+                let res = match alu {
+                    4 => self.b.ins().band(acc, val),
+                    5 => self.b.ins().bxor(acc, val),
+                    _ => self.b.ins().bor(acc, val),
+                };
+                let mut f = self.flags_szp(res);
+                if alu == 4 {
+                    f = self.b.ins().bor_imm(f, HF as i64);
+                }
+                self.set(F, f);
+                self.set(A, res);
+            }
+        }
+    }
+
+    fn inc_dec8(&mut self, val: Value, dec: bool) -> Value {
+        let res = if dec {
+            self.b.ins().iadd_imm(val, -1)
+        } else {
+            self.b.ins().iadd_imm(val, 1)
+        };
+        let res = self.b.ins().band_imm(res, 0xFF);
+        let sz = self.flags_sz(res);
+        let xy = self.b.ins().band_imm(res, XYF as i64);
+        let h = self.b.ins().bxor(res, val);
+        let hf = self.b.ins().band_imm(h, HF as i64);
+        let is_v = self.b.ins().icmp_imm(IntCC::Equal, res, if dec { 0x7F } else { 0x80 });
+        let vf = self.iconst(VF);
+        let zero = self.iconst(0);
+        let vf = self.b.ins().select(is_v, vf, zero);
+        let cf = self.carry();
+        let f = self.b.ins().bor(sz, xy);
+        let f = self.b.ins().bor(f, hf);
+        let f = self.b.ins().bor(f, vf);
+        let f = self.b.ins().bor(f, cf);
+        let f = if dec { self.b.ins().bor_imm(f, NF as i64) } else { f };
+        self.set(F, f);
+        res
+    }
+
+    // RLCA, RRCA, RLA, RRA
+    fn rot_a(&mut self, y: RegT) {
+        let acc = self.get(A);
+        let f = self.get(F);
+        let (res, cf) = match y {
+            0 => {
+                let l = self.b.ins().ishl_imm(acc, 1);
+                let r = self.b.ins().ushr_imm(acc, 7);
+                (self.b.ins().bor(l, r), r)
+            }
+            1 => {
+                let r = self.b.ins().ushr_imm(acc, 1);
+                let l = self.b.ins().ishl_imm(acc, 7);
+                (self.b.ins().bor(l, r), self.b.ins().band_imm(acc, CF as i64))
+            }
+            2 => {
+                let l = self.b.ins().ishl_imm(acc, 1);
+                let c = self.b.ins().band_imm(f, CF as i64);
+                (self.b.ins().bor(l, c), self.b.ins().ushr_imm(acc, 7))
+            }
+            _ => {
+                let r = self.b.ins().ushr_imm(acc, 1);
+                let c = self.b.ins().band_imm(f, CF as i64);
+                let c = self.b.ins().ishl_imm(c, 7);
+                (self.b.ins().bor(r, c), self.b.ins().band_imm(acc, CF as i64))
+            }
+        };
+        let res = self.b.ins().band_imm(res, 0xFF);
+        let xy = self.b.ins().band_imm(res, XYF as i64);
+        let keep = self.b.ins().band_imm(f, (SF | ZF | PF) as i64);
+        let nf = self.b.ins().bor(cf, xy);
+        let nf = self.b.ins().bor(nf, keep);
+        self.set(F, nf);
+        self.set(A, res);
+    }
+
+    fn add16(&mut self, val: Value) {
+        let acc = self.get16(H, L);
+        let wz = self.b.ins().iadd_imm(acc, 1);
+        let wz = self.b.ins().band_imm(wz, 0xFFFF);
+        self.set(WZ, wz);
+        let res = self.b.ins().iadd(acc, val);
+        let f = self.get(F);
+        let keep = self.b.ins().band_imm(f, (SF | ZF | VF) as i64);
+        let h = self.b.ins().bxor(acc, res);
+        let h = self.b.ins().bxor(h, val);
+        let h = self.b.ins().ushr_imm(h, 8);
+        let hf = self.b.ins().band_imm(h, HF as i64);
+        let c = self.b.ins().ushr_imm(res, 16);
+        let cf = self.b.ins().band_imm(c, CF as i64);
+        let xy = self.b.ins().ushr_imm(res, 8);
+        let xy = self.b.ins().band_imm(xy, XYF as i64);
+        let f = self.b.ins().bor(keep, hf);
+        let f = self.b.ins().bor(f, cf);
+        let f = self.b.ins().bor(f, xy);
+        self.set(F, f);
+        self.set16(H, L, res);
+    }
+
+    // push a value and leave the block to pc if the write hit the block's code
+    fn push(&mut self, val: Value) -> Value {
+        let sp = self.get(SP);
+        let sp = self.b.ins().iadd_imm(sp, -2);
+        let sp = self.b.ins().band_imm(sp, 0xFFFF);
+        self.set(SP, sp);
+        self.write16(sp, val)
+    }
+
+    fn pop(&mut self) -> Value {
+        let sp = self.get(SP);
+        let val = self.read16(sp);
+        let sp = self.b.ins().iadd_imm(sp, 2);
+        let sp = self.b.ins().band_imm(sp, 0xFFFF);
+        self.set(SP, sp);
+        val
+    }
+
+    /// emit the IR of one instruction, returns false if the block has ended
+    fn inst(&mut self, inst: &Inst) -> bool {
+        let next = (inst.addr + inst.len) & 0xFFFF;
+        self.m1 += 1;
+        let m1 = self.m1;
+        let cyc = self.cycles;
+        match inst.inst {
+            // LD (HL),r; LD (HL),n
+            Instruction::Ld(Operand::Ind(Reg16::HL), src) => {
+                let v = match src {
+                    Operand::Reg(r) => {
+                        self.cycles += 7;
+                        self.get(slot8(r))
+                    }
+                    Operand::Imm(n) => {
+                        self.cycles += 10;
+                        self.iconst(n)
+                    }
+                    _ => unreachable!(),
+                };
+                let addr = self.get16(H, L);
+                let r = self.write8(addr, v);
+                let c = self.cycles;
+                self.exit_if(r, next, c, m1);
+            }
+            // LD (BC),A; LD (DE),A
+            Instruction::Ld(Operand::Ind(rr), _) => {
+                self.cycles += 7;
+                let addr = self.get_reg16(rr);
+                let a = self.get(A);
+                let lo = self.b.ins().iadd_imm(addr, 1);
+                let lo = self.b.ins().band_imm(lo, 0xFF);
+                let hi = self.b.ins().ishl_imm(a, 8);
+                let wz = self.b.ins().bor(hi, lo);
+                self.set(WZ, wz);
+                let r = self.write8(addr, a);
+                let c = self.cycles;
+                self.exit_if(r, next, c, m1);
+            }
+            // LD (nn),A
+            Instruction::Ld(Operand::Abs(nn), _) => {
+                self.cycles += 13;
+                let addr = self.iconst(nn);
+                let a = self.get(A);
+                let wz = self.iconst((nn + 1) & 0xFFFF);
+                self.set(WZ, wz);
+                let r = self.write8(addr, a);
+                let c = self.cycles;
+                self.exit_if(r, next, c, m1);
+            }
+            // LD r,(HL)
+            Instruction::Ld(Operand::Reg(r), Operand::Ind(Reg16::HL)) => {
+                self.cycles += 7;
+                let addr = self.get16(H, L);
+                let v = self.read8(addr);
+                self.set(slot8(r), v);
+            }
+            // LD A,(BC); LD A,(DE)
+            Instruction::Ld(_, Operand::Ind(rr)) => {
+                self.cycles += 7;
+                let addr = self.get_reg16(rr);
+                let v = self.read8(addr);
+                self.set(A, v);
+                let wz = self.b.ins().iadd_imm(addr, 1);
+                let wz = self.b.ins().band_imm(wz, 0xFFFF);
+                self.set(WZ, wz);
+            }
+            // LD A,(nn)
+            Instruction::Ld(_, Operand::Abs(nn)) => {
+                self.cycles += 13;
+                let addr = self.iconst(nn);
+                let v = self.read8(addr);
+                self.set(A, v);
+                let wz = self.iconst((nn + 1) & 0xFFFF);
+                self.set(WZ, wz);
+            }
+            // LD r,s
+            Instruction::Ld(Operand::Reg(d), Operand::Reg(r)) => {
+                self.cycles += 4;
+                let v = self.get(slot8(r));
+                self.set(slot8(d), v);
+            }
+            // LD r,n
+            Instruction::Ld(Operand::Reg(d), Operand::Imm(n)) => {
+                self.cycles += 7;
+                let v = self.iconst(n);
+                self.set(slot8(d), v);
+            }
+            // ALU r; ALU (HL); ALU n
+            Instruction::Alu(op, src) => {
+                let v = match src {
+                    Operand::Reg(r) => {
+                        self.cycles += 4;
+                        self.get(slot8(r))
+                    }
+                    Operand::Ind(_) => {
+                        self.cycles += 7;
+                        let addr = self.get16(H, L);
+                        self.read8(addr)
+                    }
+                    Operand::Imm(n) => {
+                        self.cycles += 7;
+                        self.iconst(n)
+                    }
+                    _ => unreachable!(),
+                };
+                self.alu8(op as RegT, v);
+            }
+            Instruction::Nop => {
+                self.cycles += 4;
+            }
+            Instruction::Djnz(d) => {
+                let b = self.get(B);
+                let b = self.b.ins().iadd_imm(b, -1);
+                self.set8(B, b);
+                let b = self.get(B);
+                let cond = self.b.ins().icmp_imm(IntCC::NotEqual, b, 0);
+                let target = (next + d) & 0xFFFF;
+                self.branch(cond, target, next, cyc + 13, cyc + 8, m1);
+                return false;
+            }
+            // JR d
+            Instruction::Jr(None, d) => {
+                let target = (next + d) & 0xFFFF;
+                let wz = self.iconst(target);
+                self.set(WZ, wz);
+                self.exit_const(target, cyc + 12, m1);
+                return false;
+            }
+            // JR cc,d
+            Instruction::Jr(Some(cc), d) => {
+                let cond = self.cc(cc as RegT);
+                let target = (next + d) & 0xFFFF;
+                self.branch(cond, target, next, cyc + 12, cyc + 7, m1);
+                return false;
+            }
+            // LD rr,nn
+            Instruction::Ld16(rr, nn) => {
+                self.cycles += 10;
+                let v = self.iconst(nn);
+                self.set_reg16(rr, v);
+            }
+            // ADD HL,rr
+            Instruction::Add16(_, rr) => {
+                self.cycles += 11;
+                let v = self.get_reg16(rr);
+                self.add16(v);
+            }
+            // LD (nn),HL
+            Instruction::LdToMem16(nn, _) => {
+                self.cycles += 16;
+                let addr = self.iconst(nn);
+                let v = self.get16(H, L);
+                let wz = self.iconst((nn + 1) & 0xFFFF);
+                self.set(WZ, wz);
+                let r = self.write16(addr, v);
+                let c = self.cycles;
+                self.exit_if(r, next, c, m1);
+            }
+            // LD HL,(nn)
+            Instruction::LdFromMem16(_, nn) => {
+                self.cycles += 16;
+                let addr = self.iconst(nn);
+                let v = self.read16(addr);
+                self.set16(H, L, v);
+                let wz = self.iconst((nn + 1) & 0xFFFF);
+                self.set(WZ, wz);
+            }
+            // INC rr; DEC rr
+            Instruction::Inc16(rr) | Instruction::Dec16(rr) => {
+                self.cycles += 6;
+                let v = self.get_reg16(rr);
+                let inc = if let Instruction::Inc16(_) = inst.inst { 1 } else { -1 };
+                let v = self.b.ins().iadd_imm(v, inc);
+                self.set_reg16(rr, v);
+            }
+            // INC r; DEC r
+            Instruction::Inc(Operand::Reg(r)) | Instruction::Dec(Operand::Reg(r)) => {
+                self.cycles += 4;
+                let slot = slot8(r);
+                let v = self.get(slot);
+                let dec = matches!(inst.inst, Instruction::Dec(_));
+                let w = self.inc_dec8(v, dec);
+                self.set(slot, w);
+            }
+            // INC (HL); DEC (HL)
+            Instruction::Inc(_) | Instruction::Dec(_) => {
+                self.cycles += 11;
+                let addr = self.get16(H, L);
+                let v = self.read8(addr);
+                let dec = matches!(inst.inst, Instruction::Dec(_));
+                let w = self.inc_dec8(v, dec);
+                let r = self.write8(addr, w);
+                let c = self.cycles;
+                self.exit_if(r, next, c, m1);
+            }
+            Instruction::Cpl => {
+                self.cycles += 4;
+                let a = self.get(A);
+                let a = self.b.ins().bxor_imm(a, 0xFF);
+                let f = self.get(F);
+                let keep = self.b.ins().band_imm(f, (SF | ZF | PF | CF) as i64);
+                let xy = self.b.ins().band_imm(a, XYF as i64);
+                let f = self.b.ins().bor(keep, xy);
+                let f = self.b.ins().bor_imm(f, (HF | NF) as i64);
+                self.set(F, f);
+                self.set(A, a);
+            }
+            Instruction::Rlca | Instruction::Rrca | Instruction::Rla | Instruction::Rra => {
+                self.cycles += 4;
+                let y = match inst.inst {
+                    Instruction::Rlca => 0,
+                    Instruction::Rrca => 1,
+                    Instruction::Rla => 2,
+                    _ => 3,
+                };
+                self.rot_a(y);
+            }
+            // RET cc
+            Instruction::Ret(Some(cc)) => {
+                let cond = self.cc(cc as RegT);
+                let taken_block = self.b.create_block();
+                let not_taken_block = self.b.create_block();
+                self.b.ins().brif(cond, taken_block, &[], not_taken_block, &[]);
+                self.b.switch_to_block(taken_block);
+                let pc = self.pop();
+                self.set(WZ, pc);
+                self.exit(pc, cyc + 11, m1);
+                self.b.switch_to_block(not_taken_block);
+                self.exit_const(next, cyc + 5, m1);
+                return false;
+            }
+            Instruction::Ret(None) => {
+                let pc = self.pop();
+                self.set(WZ, pc);
+                self.exit(pc, cyc + 10, m1);
+                return false;
+            }
+            // POP BC,DE,HL,AF
+            Instruction::Pop(rr) => {
+                self.cycles += 10;
+                let v = self.pop();
+                self.set_reg16(rr, v);
+            }
+            // JP (HL)
+            Instruction::JpInd(_) => {
+                let pc = self.get16(H, L);
+                self.exit(pc, cyc + 4, m1);
+                return false;
+            }
+            // LD SP,HL
+            Instruction::LdSP(_) => {
+                self.cycles += 6;
+                let v = self.get16(H, L);
+                self.set(SP, v);
+            }
+            // JP cc,nn
+            Instruction::Jp(Some(cc), nn) => {
+                let wz = self.iconst(nn);
+                self.set(WZ, wz);
+                let cond = self.cc(cc as RegT);
+                self.branch(cond, nn, next, cyc + 10, cyc + 10, m1);
+                return false;
+            }
+            // JP nn
+            Instruction::Jp(None, nn) => {
+                let wz = self.iconst(nn);
+                self.set(WZ, wz);
+                self.exit_const(nn, cyc + 10, m1);
+                return false;
+            }
+            Instruction::ExDEHL => {
+                self.cycles += 4;
+                let (d, e, h, l) = (self.get(D), self.get(E), self.get(H), self.get(L));
+                self.set(D, h);
+                self.set(E, l);
+                self.set(H, d);
+                self.set(L, e);
+            }
+            // CALL cc,nn
+            Instruction::Call(Some(cc), nn) => {
+                let wz = self.iconst(nn);
+                self.set(WZ, wz);
+                let cond = self.cc(cc as RegT);
+                let taken_block = self.b.create_block();
+                let not_taken_block = self.b.create_block();
+                self.b.ins().brif(cond, taken_block, &[], not_taken_block, &[]);
+                self.b.switch_to_block(taken_block);
+                let ret = self.iconst(next);
+                self.push(ret);
+                self.exit_const(nn, cyc + 17, m1);
+                self.b.switch_to_block(not_taken_block);
+                self.exit_const(next, cyc + 10, m1);
+                return false;
+            }
+            // PUSH BC,DE,HL,AF
+            Instruction::Push(rr) => {
+                self.cycles += 11;
+                let v = self.get_reg16(rr);
+                let r = self.push(v);
+                let c = self.cycles;
+                self.exit_if(r, next, c, m1);
+            }
+            // CALL nn
+            Instruction::Call(None, nn) => {
+                let wz = self.iconst(nn);
+                self.set(WZ, wz);
+                let ret = self.iconst(next);
+                self.push(ret);
+                self.exit_const(nn, cyc + 17, m1);
+                return false;
+            }
+            Instruction::Rst(target) => {
+                let wz = self.iconst(target);
+                self.set(WZ, wz);
+                let ret = self.iconst(next);
+                self.push(ret);
+                self.exit_const(target, cyc + 11, m1);
+                return false;
+            }
+            // blocks::inst_info() only lets the above instructions into a block
+            _ => unreachable!(),
+        }
+        true
+    }
+
+    fn branch(&mut self, cond: Value, target: RegT, next: RegT, taken_cyc: i64, not_taken_cyc: i64, m1: i64) {
+        let t = self.iconst(target);
+        let n = self.iconst(next);
+        self.exit_branch(cond, (t, taken_cyc), (n, not_taken_cyc), m1);
+    }
+}
+
+/// the JIT compiler and code cache
+pub struct Jit {
+    module: JITModule,
+    func_ctx: FunctionBuilderContext,
+    cache: Vec<Entry>,
+    num_blocks: usize,
+}
+
+fn new_module() -> JITModule {
+    let mut flag_builder = settings::builder();
+    flag_builder.set("use_colocated_libcalls", "false").unwrap();
+    flag_builder.set("is_pic", "false").unwrap();
+    flag_builder.set("opt_level", "speed").unwrap();
+    let isa = cranelift_native::builder()
+        .expect("host machine not supported by the JIT")
+        .finish(settings::Flags::new(flag_builder))
+        .unwrap();
+    JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()))
+}
+
+impl Jit {
+    /// create a new JIT with an empty code cache
+    pub fn new() -> Jit {
+        Jit {
+            module: new_module(),
+            func_ctx: FunctionBuilderContext::new(),
+            cache: (0..(1 << 16)).map(|_| Entry::default()).collect(),
+            num_blocks: 0,
+        }
+    }
+
+    /// number of currently compiled blocks
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    /// throw away all compiled code
+    pub fn flush(&mut self) {
+        let old = mem::replace(&mut self.module, new_module());
+        // the old code can't be called anymore once all cache entries are gone
+        for entry in self.cache.iter_mut() {
+            *entry = Entry::default();
+        }
+        unsafe { old.free_memory() };
+        self.num_blocks = 0;
+    }
+
+    /// run the compiled block at the CPU's PC, or return None if there is none
+    pub fn run(&mut self, cpu: &mut CPU) -> Option<i64> {
+        let pc = cpu.reg.pc();
+        let func = self.lookup(&cpu.mem, pc)?;
+        let mut s = [0u32; NUM_SLOTS];
+        s[B] = cpu.reg.b() as u32;
+        s[C] = cpu.reg.c() as u32;
+        s[D] = cpu.reg.d() as u32;
+        s[E] = cpu.reg.e() as u32;
+        s[H] = cpu.reg.h() as u32;
+        s[L] = cpu.reg.l() as u32;
+        s[A] = cpu.reg.a() as u32;
+        s[F] = cpu.reg.f() as u32;
+        s[SP] = cpu.reg.sp() as u32;
+        s[WZ] = cpu.reg.wz() as u32;
+        let cycles = unsafe { func(s.as_mut_ptr(), &mut cpu.mem) };
+        cpu.reg.set_b(s[B] as RegT);
+        cpu.reg.set_c(s[C] as RegT);
+        cpu.reg.set_d(s[D] as RegT);
+        cpu.reg.set_e(s[E] as RegT);
+        cpu.reg.set_h(s[H] as RegT);
+        cpu.reg.set_l(s[L] as RegT);
+        cpu.reg.set_a(s[A] as RegT);
+        cpu.reg.set_f(s[F] as RegT);
+        cpu.reg.set_sp(s[SP] as RegT);
+        cpu.reg.set_wz(s[WZ] as RegT);
+        cpu.reg.set_pc(s[PC] as RegT);
+        let r = cpu.reg.r;
+        cpu.reg.r = (r & 0x80) | ((r + s[M1] as RegT) & 0x7F);
+        Some(cycles)
+    }
+
+    fn lookup(&mut self, mem: &Memory, pc: RegT) -> Option<BlockFn> {
+        let index = pc as usize;
+        let valid = match self.cache[index].code {
+            Some(ref code) => {
+                mem.page_generation(code.pages[0]) == code.gens[0] &&
+                mem.page_generation(code.pages[1]) == code.gens[1]
+            }
+            None => true,
+        };
+        if !valid {
+            let entry = &mut self.cache[index];
+            if entry.code.as_ref().unwrap().func.is_some() {
+                self.num_blocks -= 1;
+            }
+            entry.code = None;
+            entry.hits = 0;
+            entry.recompiles += 1;
+        }
+        if let Some(ref code) = self.cache[index].code {
+            return code.func;
+        }
+        // compile hot blocks, blocks which are invalidated often need to get hotter
+        let threshold = HOT_THRESHOLD << self.cache[index].recompiles.min(16);
+        self.cache[index].hits += 1;
+        if self.cache[index].hits < threshold {
+            return None;
+        }
+        if self.num_blocks >= MAX_BLOCKS {
+            self.flush();
+        }
+        let code = self.compile(mem, pc);
+        if code.func.is_some() {
+            self.num_blocks += 1;
+        }
+        let func = code.func;
+        self.cache[index].code = Some(Box::new(code));
+        func
+    }
+
+    fn compile(&mut self, mem: &Memory, pc: RegT) -> Code {
+        let insts = decode_block(mem, pc);
+        let last = match insts.last() {
+            Some(inst) => (inst.addr + inst.len - 1) & 0xFFFF,
+            None => pc,
+        };
+        let pages = [pc, last];
+        let gens = [mem.page_generation(pc), mem.page_generation(last)];
+        if insts.is_empty() {
+            return Code { func: None, pages, gens };
+        }
+        let mut code_mask = 0u64;
+        for inst in &insts {
+            for i in 0..inst.len {
+                code_mask |= 1 << (((inst.addr + i) & 0xFFFF) >> 10);
+            }
+        }
+
+        let ptr_type = self.module.target_config().pointer_type();
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(ptr_type));
+        sig.params.push(AbiParam::new(ptr_type));
+        sig.returns.push(AbiParam::new(types::I64));
+        let mut read_sig = self.module.make_signature();
+        read_sig.params.push(AbiParam::new(ptr_type));
+        read_sig.params.push(AbiParam::new(types::I32));
+        read_sig.returns.push(AbiParam::new(types::I32));
+        let mut write_sig = self.module.make_signature();
+        write_sig.params.push(AbiParam::new(ptr_type));
+        write_sig.params.push(AbiParam::new(types::I32));
+        write_sig.params.push(AbiParam::new(types::I32));
+        write_sig.params.push(AbiParam::new(types::I64));
+        write_sig.returns.push(AbiParam::new(types::I32));
+
+        let mut ctx = self.module.make_context();
+        ctx.func.signature = sig.clone();
+        {
+            let mut b = FunctionBuilder::new(&mut ctx.func, &mut self.func_ctx);
+            let entry_block = b.create_block();
+            b.append_block_params_for_function_params(entry_block);
+            b.switch_to_block(entry_block);
+            let state = b.block_params(entry_block)[0];
+            let mem_ptr = b.block_params(entry_block)[1];
+            let mut vars = [Variable::from_u32(0); NUM_VARS];
+            for (slot, var) in vars.iter_mut().enumerate() {
+                *var = Variable::from_u32(slot as u32);
+                b.declare_var(*var, types::I32);
+                let v = b.ins().load(types::I32, MemFlags::trusted(), state, (slot * 4) as i32);
+                b.def_var(*var, v);
+            }
+            let read_sig = b.import_signature(read_sig);
+            let write_sig = b.import_signature(write_sig);
+            let mut c = Compiler {
+                b,
+                vars,
+                state,
+                mem: mem_ptr,
+                ptr_type,
+                read_sig,
+                write_sig,
+                code_mask,
+                cycles: 0,
+                m1: 0,
+            };
+            let mut open = true;
+            for inst in &insts {
+                open = c.inst(inst);
+            }
+            if open {
+                // the block ended before an unsupported instruction
+                let next = (last + 1) & 0xFFFF;
+                let (cycles, m1) = (c.cycles, c.m1);
+                c.exit_const(next, cycles, m1);
+            }
+            c.b.seal_all_blocks();
+            c.b.finalize();
+        }
+        let id = self.module.declare_anonymous_function(&sig).unwrap();
+        self.module.define_function(id, &mut ctx).unwrap();
+        self.module.clear_context(&mut ctx);
+        self.module.finalize_definitions().unwrap();
+        let ptr = self.module.get_finalized_function(id);
+        let func = unsafe { mem::transmute::<*const u8, BlockFn>(ptr) };
+        Code { func: Some(func), pages, gens }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use Bus;
+    use blocks::inst_info;
+    use decoder::decode;
+    use registers::Im;
+
+    struct TestBus;
+    impl Bus for TestBus {}
+
+    struct M1Bus {
+        m1: Cell<usize>,
+    }
+    impl Bus for M1Bus {
+        fn m1(&self, _pc: RegT, _op: RegT) {
+            self.m1.set(self.m1.get() + 1);
+        }
+    }
+
+    // run until PC has been at 0x0100 num times, return cycles
+    fn run(cpu: &mut CPU, num: usize) -> i64 {
+        let bus = TestBus;
+        let mut cycles = 0;
+        let mut visits = 0;
+        while visits < num {
+            cycles += cpu.run_block(&bus);
+            if cpu.reg.pc() == 0x0100 {
+                visits += 1;
+            }
+        }
+        cycles
+    }
+
+    fn check_same(a: &CPU, b: &CPU) {
+        assert_eq!(a.reg.af(), b.reg.af());
+        assert_eq!(a.reg.bc(), b.reg.bc());
+        assert_eq!(a.reg.de(), b.reg.de());
+        assert_eq!(a.reg.hl(), b.reg.hl());
+        assert_eq!(a.reg.sp(), b.reg.sp());
+        assert_eq!(a.reg.wz(), b.reg.wz());
+        assert_eq!(a.reg.pc(), b.reg.pc());
+        assert_eq!(a.reg.r, b.reg.r);
+        assert!(a.mem.diff(&b.mem).is_empty());
+    }
+
+    #[test]
+    fn random_blocks() {
+        // random blocks of supported instructions in ROM, run by the
+        // interpreter and the JIT, must produce the same results
+        let mut seed: u32 = 0x12345678;
+        let mut rnd = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for _ in 0..32 {
+            let mut rom = vec![0u8; 0x400];
+            let mut addr = 0x100;
+            while addr < 0x1F0 {
+                let bytes: Vec<u8> = (0..4).map(|_| rnd() as u8).collect();
+                let (inst, len) = decode(&bytes);
+                if let Some(false) = inst_info(&inst) {
+                    let len = len as usize;
+                    rom[addr..addr + len].copy_from_slice(&bytes[..len]);
+                    addr += len;
+                }
+            }
+            // JP 0x0100
+            rom[addr..addr + 3].copy_from_slice(&[0xC3, 0x00, 0x01]);
+
+            let mut cpus = [CPU::new(), CPU::new()];
+            let regs: Vec<RegT> = (0..5).map(|_| (rnd() & 0xFFFF) as RegT).collect();
+            for cpu in cpus.iter_mut() {
+                cpu.mem.map_bytes(0, 0x00000, 0x0000, false, &rom);
+                cpu.mem.map(0, 0x00400, 0x0400, true, 0xFC00);
+                cpu.reg.set_af(regs[0]);
+                cpu.reg.set_bc(regs[1]);
+                cpu.reg.set_de(regs[2]);
+                cpu.reg.set_hl(regs[3]);
+                cpu.reg.set_sp(regs[4]);
+                cpu.reg.set_pc(0x0100);
+            }
+            cpus[1].set_jit(true);
+            let cycles0 = run(&mut cpus[0], 40);
+            let cycles1 = run(&mut cpus[1], 40);
+            assert!(cpus[1].jit_blocks() > 0);
+            assert_eq!(cycles0, cycles1);
+            check_same(&cpus[0], &cpus[1]);
+        }
+    }
+
+    #[test]
+    fn branches() {
+        // LD B,10; loop: INC A; DJNZ loop; LD C,A; CALL sub; JR NZ,+0; JP 0x0100
+        // sub: DEC C; RET NZ; INC D; RET
+        let prog = [0x06, 0x0A, 0x3C, 0x10, 0xFD, 0x4F, 0xCD, 0x10, 0x01, 0x20, 0x00,
+                    0xC3, 0x00, 0x01, 0x00, 0x00, 0x0D, 0xC0, 0x14, 0xC9];
+        let mut cpus = [CPU::new_64k(), CPU::new_64k()];
+        for cpu in cpus.iter_mut() {
+            cpu.mem.write(0x0100, &prog);
+            cpu.reg.set_sp(0x8000);
+            cpu.reg.set_pc(0x0100);
+        }
+        cpus[1].set_jit(true);
+        let cycles0 = run(&mut cpus[0], 100);
+        let cycles1 = run(&mut cpus[1], 100);
+        assert!(cpus[1].jit_blocks() > 0);
+        assert_eq!(cycles0, cycles1);
+        check_same(&cpus[0], &cpus[1]);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // LD HL,0x010A; INC (HL); NOP; NOP; NOP; NOP; NOP; ADD A,n; JP 0x0100
+        // (INC (HL) increments the immediate operand of ADD A,n)
+        let prog = [0x21, 0x0A, 0x01, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0xC6, 0x00,
+                    0xC3, 0x00, 0x01];
+        let mut cpus = [CPU::new_64k(), CPU::new_64k()];
+        for cpu in cpus.iter_mut() {
+            cpu.mem.write(0x0100, &prog);
+            cpu.reg.set_pc(0x0100);
+        }
+        cpus[1].set_jit(true);
+        let cycles0 = run(&mut cpus[0], 100);
+        let cycles1 = run(&mut cpus[1], 100);
+        assert_eq!(cpus[0].reg.a(), (5050 & 0xFF));
+        assert_eq!(cycles0, cycles1);
+        check_same(&cpus[0], &cpus[1]);
+
+        // disabling the JIT throws away all compiled code
+        cpus[1].set_jit(false);
+        assert_eq!(cpus[1].jit_blocks(), 0);
+    }
+
+    #[test]
+    fn m1_and_interrupts() {
+        // LD A,1; LD B,2; LD C,3; JP 0x0100
+        let prog = [0x3E, 0x01, 0x06, 0x02, 0x0E, 0x03, 0xC3, 0x00, 0x01];
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0100, &prog);
+        cpu.reg.set_pc(0x0100);
+        cpu.set_jit(true);
+        let bus = M1Bus { m1: Cell::new(0) };
+        while cpu.jit_blocks() == 0 {
+            cpu.run_block(&bus);
+        }
+
+        // compiled blocks don't call Bus::m1()
+        bus.m1.set(0);
+        assert_eq!(cpu.run_block(&bus), 3 * 7 + 10);
+        assert_eq!(cpu.reg.pc(), 0x0100);
+        assert_eq!(bus.m1.get(), 0);
+
+        // step() never runs compiled code
+        assert_eq!(cpu.step(&bus), 7);
+        assert_eq!(cpu.reg.pc(), 0x0102);
+        assert_eq!(bus.m1.get(), 1);
+
+        // with an interrupt pending, run_block() only executes one instruction
+        cpu.reg.set_pc(0x0100);
+        cpu.set_iff(true, true);
+        cpu.set_interrupt_mode(Im::One);
+        cpu.reg.set_sp(0x8000);
+        cpu.irq();
+        assert_eq!(cpu.run_block(&bus), 7 + 13);
+        assert_eq!(cpu.reg.pc(), 0x0038);
+        assert_eq!(cpu.mem.r16(0x7FFE), 0x0102);
+    }
+}
@@ -4,23 +4,37 @@
 //! # Overview
 //!
 //! The rz80 library provides chip emulators for the Z80 **CPU**, **PIO** (parallel in/out), **CTC**
-//! (counter/timer channels) and a **Bus** trait which defines how the chips are wired together
-//! in a specific emulated system.
+//! (counter/timer channels), **SIO** (serial in/out) and a **Bus** trait which defines how the chips are wired together
+//! in a specific emulated system. Video chips for specific systems like the MC6845 **CRTC** and
+//! the Amstrad CPC **GateArray**, the Sega Master System **VDP**, the **TMS9918** and the
+//! **SN76489** sound chip are also included.
 //!
 //! Writing a home computer emulator usually involves the following steps
 //!
-//! - import the required chips
+//! - import the required chips (the **prelude** module has the most common ones:
+//!   `use rz80::prelude::*;`)
 //! - import ROM dumps using the include_bytes! macro
 //! - define a **State** struct which holds emulator state required in addition to the chip state
-//! - define a **System** struct which embeds all chips and the State struct wrapped in RefCells
+//! - define a **System** struct which embeds the CPU, the other chips and the State struct
 //! - write a **System::poweron()** function which initializes the embedded chips and state objects,
 //!   initializes the memory map and sets the CPU PC register to the ROM dump start address
-//! - write a **video-decoder** function which generates a linear RGBA8 framebuffer each frame
-//! - implement the **Bus trait** on the System struct, this usually involves:
+//! - write a **video-decoder** function which renders into a **Framebuffer** each frame
+//! - implement the **IoBus trait** (or the **Bus trait**) to wire the chips together,
+//!   this usually involves:
 //!     - the keyboard emulation
 //!     - memory bank switching
 //!     - forward interrupt requests between the various hardware components
 //!     - sound generation
+//!
+//! New systems should implement the **IoBus trait**, which gets the CPU's port I/O and
+//! interrupt acknowledge cycles through a mutable reference: keep the CPU and the other
+//! chips in separate fields, and step the CPU with **CPU::step_io()**, **CPU::run_block_io()**
+//! or **CPU::step_until_io()** (`self.cpu.step_io(&mut self.board)` borrows the two fields
+//! separately). Chip callbacks can be collected with a **RecordingBus**, and applied after
+//! the chip call has returned. The z1013 and kc87 examples are written this way. The Bus
+//! trait functions only get a shared reference, so a System which implements Bus needs to
+//! wrap its chips in RefCells, this is only needed for the CPU's other callbacks (e.g.
+//! m1() tracing or io_wait()).
 //! - implement the **main loop** which creates a window, forwards keyboard input,
 //!   and steps the chips emulators forward
 //!
@@ -36,21 +50,137 @@
 //! ```
 //!
 
+#[cfg(feature = "jit")]
+extern crate cranelift_codegen;
+#[cfg(feature = "jit")]
+extern crate cranelift_frontend;
+#[cfg(feature = "jit")]
+extern crate cranelift_jit;
+#[cfg(feature = "jit")]
+extern crate cranelift_module;
+#[cfg(feature = "jit")]
+extern crate cranelift_native;
+
 /// generic integer type for 8- and 16-bit values
 pub type RegT = i32;
 
 mod registers;
 mod memory;
 mod bus;
+mod iobus;
 mod cpu;
+#[macro_use]
+mod cpustate;
+mod decoder;
+mod mcycles;
+mod opstats;
 mod pio;
 mod ctc;
+mod sio;
 mod daisychain;
+mod devicemap;
+mod rom;
+mod savestate;
+mod modules;
+mod iomap;
+mod iotrace;
+mod glue;
+mod scheduler;
+mod machine;
+mod handle;
+mod multimachine;
+mod audio;
+mod serial;
+mod printer;
+mod ide;
+mod tape;
+mod basic;
+mod video;
+mod input;
+mod testkit;
+mod symbols;
+mod breakpoints;
+mod watches;
+mod watchdog;
+mod cheats;
+mod chrometrace;
+mod crtc;
+mod gatearray;
+mod mapper;
+mod vdp;
+mod tms9918;
+mod sn76489;
+mod psgdump;
+mod blocks;
+mod zx128;
+mod contention;
+mod z9001;
+#[cfg(feature = "jit")]
+mod jit;
+pub mod prelude;
 
-pub use registers::{Registers, CF, NF, VF, PF, XF, HF, YF, ZF, SF};
-pub use memory::Memory;
-pub use cpu::CPU;
-pub use bus::Bus;
-pub use pio::{PIO, PIO_A, PIO_B};
-pub use ctc::{CTC, CTC_0, CTC_1, CTC_2, CTC_3};
+pub use registers::{Registers, Reg8, Reg16, Im, Flags, CF, NF, VF, PF, XF, HF, YF, ZF, SF};
+pub use memory::{Memory, MemFault, MemAccess, MEM_READ, MEM_WRITE, MEM_EXEC};
+pub use cpu::{CPU, CpuVariant, InvalidOpPolicy, StackFault, StopReason, StepRecord};
+pub use cpustate::CpuState;
+pub use decoder::{decode, Instruction, Operand, Cond, AluOp, RotOp};
+pub use mcycles::{MCycle, MCycleKind};
+pub use opstats::{OpcodeStats, OpCount, OpTable};
+pub use bus::{Bus, NullBus, RecordingBus, CompositeBus, BusEvent, ResetKind, Reset, reset_all};
+pub use iobus::{IoBus, IoFn, IoBusAdapter};
+pub use pio::{PIO, PIO_A, PIO_B, PioError, Mode as PioMode, pio_control_decoder, INTCTRL_ENABLE_INT,
+              INTCTRL_MASK_FOLLOWS, INTCTRL_AND_OR, INTCTRL_HIGH_LOW};
+pub use ctc::{CTC, ctc_decoder, CTC_0, CTC_1, CTC_2, CTC_3, CTC_INTERRUPT_BIT, CTC_MODE_BIT,
+              CTC_PRESCALER_BIT, CTC_EDGE_BIT, CTC_TRIGGER_BIT, CTC_CONSTANT_FOLLOWS,
+              CTC_RESET, CTC_CONTROL_BIT};
+pub use sio::{SIO, SIO_A, SIO_B, SIO_RR0_RX_AVAILABLE, SIO_RR0_INT_PENDING, SIO_RR0_TX_EMPTY,
+              SIO_RR0_DCD, SIO_RR0_CTS, SIO_RR1_ALL_SENT, SIO_RR1_RX_OVERRUN};
 pub use daisychain::Daisychain;
+pub use devicemap::{DeviceMap, DeviceKind, Device};
+pub use rom::{RomRegistry, RomInfo, RomError, Crc32, crc32};
+pub use modules::{RomModule, ModuleSlot};
+pub use savestate::{SaveState, StateWriter, StateReader, ChunkWriter, ChunkReader, StateError,
+                    SAVESTATE_MAGIC, SAVESTATE_VERSION};
+pub use iomap::{IoMap, InpFn, OutpFn, PortDecoder};
+pub use glue::{Latch, FlipFlop, ShiftRegister};
+pub use iotrace::{IoTrace, IoAccess, IoDir, IoDecoderFn};
+pub use scheduler::Scheduler;
+pub use machine::{Machine, Clock, Speed, FrameTimer, FrameTiming, MachineProfile, PAL_LINES,
+                  NTSC_LINES, PROFILE_Z1013, PROFILE_Z9001, PROFILE_KC85_4, PROFILE_SMS, PROFILE_CPC,
+                  PROFILE_ZX128};
+pub use handle::{MachineHandle, Command, RunState, RunResult};
+pub use multimachine::{MultiMachine, Mailbox, SharedRam};
+pub use audio::{AudioBuffer, Beeper};
+pub use serial::{SerialTransport, MemorySerial, Loopback, TcpSerial};
+pub use printer::{Printer, escp_to_text};
+pub use ide::{IdeDrive, IDE_DATA, IDE_ERROR, IDE_SECTOR_COUNT, IDE_LBA0, IDE_LBA1, IDE_LBA2,
+              IDE_DRIVE_HEAD, IDE_STATUS, IDE_STATUS_BSY, IDE_STATUS_DRDY, IDE_STATUS_DSC,
+              IDE_STATUS_DRQ, IDE_STATUS_ERR};
+pub use tape::{Tape, TapeFile, TapeError, ProgramFormat, read_tap, write_tap, z1013_encode,
+               z1013_decode, load_program};
+pub use basic::{BasicError, BASIC_KEYWORDS, basic_tokenize, basic_detokenize};
+pub use video::{Framebuffer, Rect, TextMode};
+pub use input::{InputQueue, InputEvent, KeyMatrix};
+pub use testkit::{TestKit, TestSystem};
+pub use symbols::{SymbolTable, SymbolError};
+pub use breakpoints::{Breakpoint, Breakpoints, Condition, ConditionError};
+pub use watches::{Watch, Watches, WatchMode};
+pub use watchdog::Watchdog;
+pub use cheats::{CheatEngine, Cheat, Search};
+pub use chrometrace::{ChromeTrace, TRACK_CPU, TRACK_IRQ, TRACK_IO};
+pub use crtc::{CRTC, CRTC_H_TOTAL, CRTC_H_DISPLAYED, CRTC_H_SYNC_POS, CRTC_SYNC_WIDTHS,
+               CRTC_V_TOTAL, CRTC_V_TOTAL_ADJUST, CRTC_V_DISPLAYED, CRTC_V_SYNC_POS,
+               CRTC_INTERLACE_MODE, CRTC_MAX_SCANLINE_ADDR, CRTC_CURSOR_START, CRTC_CURSOR_END,
+               CRTC_START_ADDR_HI, CRTC_START_ADDR_LO, CRTC_CURSOR_HI, CRTC_CURSOR_LO,
+               CRTC_LIGHTPEN_HI, CRTC_LIGHTPEN_LO};
+pub use gatearray::{GateArray, GA_BORDER};
+pub use mapper::{Mapper, SegaMapper, CodemastersMapper};
+pub use vdp::{VDP, VDP_WIDTH, VDP_HEIGHT, VDP_LINES, VDP_CYCLES_PER_LINE};
+pub use tms9918::{TMS9918, TMS9918_WIDTH, TMS9918_HEIGHT, TMS9918_LINES};
+pub use sn76489::SN76489;
+pub use psgdump::{PsgDump, PsgDumpError, PsgPlayer, PsgChip, PsgType, PsgWrite, VgmLog,
+                  PSG_DUMP_RATE};
+pub use zx128::{ZX128Paging, ZX128Model};
+pub use contention::{ContendedMemory, ContentionModel};
+pub use z9001::{Z9001Video, Z9001Config, Z9001Model, Z9001_COLUMNS, Z9001_ROWS, Z9001_BORDER,
+                Z9001_WIDTH, Z9001_HEIGHT, Z9001_VIDEO_RAM, Z9001_COLOR_RAM};
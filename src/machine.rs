@@ -0,0 +1,897 @@
+use std::time::{Duration, Instant};
+use input::{InputQueue, InputEvent};
+use scheduler::Scheduler;
+
+/// at most this many frames are caught up after the host stalled
+const MAX_CATCHUP_FRAMES: f64 = 4.0;
+/// a host frame which takes longer than 5/4 of the emulated frame is late
+const LATE_FRAME_NUM: i64 = 5;
+const LATE_FRAME_DEN: i64 = 4;
+/// the maximum number of video frames skipped in a row
+const MAX_SKIPPED_FRAMES: u32 = 4;
+
+/// CPU clock frequency and conversions between cycles and time
+///
+/// Use one Clock as single source of truth for everything which
+/// depends on the CPU frequency (frame length, timer periods, tape pulse
+/// lengths, audio sample generation).
+///
+/// ```
+/// use rz80::Clock;
+///
+/// const CLOCK: Clock = Clock::new(2_000_000);
+/// assert_eq!(CLOCK.cycles_from_micros(1000), 2000);
+/// assert_eq!(CLOCK.micros_from_cycles(500), 250);
+/// assert_eq!(CLOCK.cycles_per_frame(50), 40000);
+/// assert_eq!(CLOCK.frequency(400), 5000.0);
+/// ```
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Clock {
+    hz: i64,
+}
+
+impl Clock {
+    /// create a Clock with the CPU frequency in Hz
+    pub const fn new(hz: i64) -> Clock {
+        Clock { hz }
+    }
+
+    /// CPU frequency in Hz
+    pub const fn hz(&self) -> i64 {
+        self.hz
+    }
+
+    /// convert a duration in microseconds to CPU cycles
+    pub fn cycles_from_micros(&self, micros: i64) -> i64 {
+        self.hz * micros / 1_000_000
+    }
+
+    /// convert CPU cycles to a duration in microseconds
+    pub fn micros_from_cycles(&self, cycles: i64) -> i64 {
+        cycles * 1_000_000 / self.hz
+    }
+
+    /// number of CPU cycles per video frame at a frame rate in Hz
+    pub fn cycles_per_frame(&self, frame_rate: i64) -> i64 {
+        self.hz / frame_rate
+    }
+
+    /// frequency in Hz of a signal with a period in CPU cycles (e.g. CTC::timer_period())
+    pub fn frequency(&self, period: i64) -> f64 {
+        self.hz as f64 / period as f64
+    }
+}
+
+/// number of scanlines per frame of the PAL video standard (50 Hz)
+pub const PAL_LINES: i64 = 312;
+/// number of scanlines per frame of the NTSC video standard (60 Hz)
+pub const NTSC_LINES: i64 = 262;
+
+/// video frame timing as scanlines and CPU cycles per scanline
+///
+/// The vertical blank starts at the beginning of vblank_line (0 by
+/// default, the start of the frame). A Scheduler with a frame timing
+/// calls Bus::vblank() at this point of each frame (see
+/// Scheduler::set_frame_timing()).
+///
+/// ```
+/// use rz80::FrameTiming;
+///
+/// // the Sega Master System, the vertical blank starts after 192 lines
+/// let timing = FrameTiming::ntsc(228).with_vblank_line(192);
+/// assert_eq!(timing.cycles_per_frame(), 262 * 228);
+/// assert_eq!(timing.vblank_pos(), 192 * 228);
+/// ```
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct FrameTiming {
+    /// number of scanlines per video frame
+    pub lines_per_frame: i64,
+    /// number of CPU cycles per scanline
+    pub cycles_per_line: i64,
+    /// the scanline at which the vertical blank starts
+    pub vblank_line: i64,
+}
+
+impl FrameTiming {
+    /// create a frame timing, the vertical blank starts with the frame
+    pub const fn new(lines_per_frame: i64, cycles_per_line: i64) -> FrameTiming {
+        FrameTiming {
+            lines_per_frame,
+            cycles_per_line,
+            vblank_line: 0,
+        }
+    }
+
+    /// PAL frame timing (312 lines)
+    pub const fn pal(cycles_per_line: i64) -> FrameTiming {
+        FrameTiming::new(PAL_LINES, cycles_per_line)
+    }
+
+    /// NTSC frame timing (262 lines)
+    pub const fn ntsc(cycles_per_line: i64) -> FrameTiming {
+        FrameTiming::new(NTSC_LINES, cycles_per_line)
+    }
+
+    /// set the scanline at which the vertical blank starts
+    pub const fn with_vblank_line(self, vblank_line: i64) -> FrameTiming {
+        FrameTiming {
+            lines_per_frame: self.lines_per_frame,
+            cycles_per_line: self.cycles_per_line,
+            vblank_line,
+        }
+    }
+
+    /// number of CPU cycles per video frame
+    pub const fn cycles_per_frame(&self) -> i64 {
+        self.lines_per_frame * self.cycles_per_line
+    }
+
+    /// the cycle position of the vertical blank in the frame
+    pub const fn vblank_pos(&self) -> i64 {
+        self.vblank_line * self.cycles_per_line
+    }
+}
+
+/// CPU and video timing of an emulated machine
+///
+/// A profile is the single source of truth for the timing numbers of
+/// a machine: the CPU clock, and the video timing as number of
+/// scanlines per frame and CPU cycles per scanline. The frame length
+/// is always an exact number of cycles, and everything derived from
+/// it (like the frame rate and the audio sample step) is computed
+/// with integer fixed-point math, so timing is deterministic across
+/// hosts. Machines without a scanline-based video emulation have a
+/// single 'scanline' per frame (see **frame_based()**).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Machine, PROFILE_KC85_4};
+///
+/// assert_eq!(PROFILE_KC85_4.cycles_per_frame(), 312 * 113);
+/// // 50.302 Hz
+/// assert_eq!(PROFILE_KC85_4.frame_rate_milli(), 50302);
+/// // 40.21 cycles per sample at 44.1 kHz in 16.16 fixed-point
+/// assert_eq!(PROFILE_KC85_4.cycles_per_sample_fp(44100) >> 16, 40);
+///
+/// let machine = Machine::with_profile(PROFILE_KC85_4);
+/// assert_eq!(machine.frame_cycles(), 312 * 113);
+/// ```
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct MachineProfile {
+    pub name: &'static str,
+    pub clock: Clock,
+    /// number of scanlines per video frame
+    pub lines_per_frame: i64,
+    /// number of CPU cycles per scanline
+    pub cycles_per_line: i64,
+}
+
+impl MachineProfile {
+    /// create a profile with scanline timing
+    pub const fn new(name: &'static str, hz: i64, lines_per_frame: i64, cycles_per_line: i64)
+        -> MachineProfile
+    {
+        MachineProfile {
+            name,
+            clock: Clock::new(hz),
+            lines_per_frame,
+            cycles_per_line,
+        }
+    }
+
+    /// create a profile from a frame timing
+    pub const fn with_timing(name: &'static str, hz: i64, timing: FrameTiming) -> MachineProfile {
+        MachineProfile::new(name, hz, timing.lines_per_frame, timing.cycles_per_line)
+    }
+
+    /// create a profile without scanline timing from the frame rate in Hz
+    pub const fn frame_based(name: &'static str, hz: i64, frame_rate: i64) -> MachineProfile {
+        MachineProfile::new(name, hz, 1, hz / frame_rate)
+    }
+
+    /// number of CPU cycles per video frame
+    pub const fn cycles_per_frame(&self) -> i64 {
+        self.lines_per_frame * self.cycles_per_line
+    }
+
+    /// the frame timing, with the vertical blank at the start of the frame
+    pub const fn frame_timing(&self) -> FrameTiming {
+        FrameTiming::new(self.lines_per_frame, self.cycles_per_line)
+    }
+
+    /// the video frame rate in 1/1000 Hz (rounded)
+    pub fn frame_rate_milli(&self) -> i64 {
+        let frame = self.cycles_per_frame();
+        (self.clock.hz() * 1000 + frame / 2) / frame
+    }
+
+    /// the duration of a video frame in microseconds (rounded)
+    pub fn frame_micros(&self) -> i64 {
+        (self.cycles_per_frame() * 1_000_000 + self.clock.hz() / 2) / self.clock.hz()
+    }
+
+    /// number of CPU cycles per audio sample at a sample rate in Hz, as 16.16 fixed-point number
+    pub fn cycles_per_sample_fp(&self, sample_rate: i64) -> i64 {
+        (self.clock.hz() << 16) / sample_rate
+    }
+
+    /// the scanline and the cycle in the scanline of a cycle position in the frame
+    pub fn line_pos(&self, frame_pos: i64) -> (i64, i64) {
+        let pos = frame_pos.rem_euclid(self.cycles_per_frame());
+        (pos / self.cycles_per_line, pos % self.cycles_per_line)
+    }
+}
+
+/// the Z1013 (2 MHz, 50 Hz frame rate, no scanline timing)
+pub const PROFILE_Z1013: MachineProfile = MachineProfile::frame_based("Z1013", 2_000_000, 50);
+/// the Z9001, KC85/1 and KC87 (2.4576 MHz, 50 Hz frame rate, no scanline timing)
+pub const PROFILE_Z9001: MachineProfile = MachineProfile::frame_based("Z9001", 2_457_600, 50);
+/// the KC85/4 (1.77 MHz, 312 lines with 113 cycles)
+pub const PROFILE_KC85_4: MachineProfile =
+    MachineProfile::with_timing("KC85/4", 1_773_447, FrameTiming::pal(113));
+/// the Sega Master System, NTSC (3.58 MHz, 262 lines with 228 cycles)
+pub const PROFILE_SMS: MachineProfile =
+    MachineProfile::with_timing("SMS", 3_579_545, FrameTiming::ntsc(228));
+/// the Amstrad CPC (4 MHz, 312 lines with 256 cycles)
+pub const PROFILE_CPC: MachineProfile =
+    MachineProfile::with_timing("CPC", 4_000_000, FrameTiming::pal(256));
+/// the ZX Spectrum 128 (3.5469 MHz, 311 lines with 228 cycles)
+pub const PROFILE_ZX128: MachineProfile = MachineProfile::new("ZX128", 3_546_900, 311, 228);
+
+/// how Machine::run_speed() paces the emulation against the host
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Speed {
+    /// one emulated frame per call, for hosts which call run_speed() on each vsync
+    /// of a display with the emulated frame rate
+    Vsync,
+    /// emulated time runs at a multiple of the elapsed host time (1.0 is real time)
+    Factor(f64),
+    /// turbo mode, run as many frames as fit into the elapsed host time
+    Unlimited,
+    /// keep the host audio buffer filled up to a number of samples
+    Audio {
+        /// the sample rate of the audio buffer in Hz
+        sample_rate: i64,
+        /// the number of queued samples to keep in the audio buffer
+        target: usize,
+    },
+}
+
+/// what a paused Machine executes on the next call to run()
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum Advance {
+    None,
+    Frame,
+    Instruction,
+}
+
+/// the events in the Machine's scheduler
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum Event {
+    /// the end of the current video frame
+    FrameEnd,
+    /// the next key event in the input queue is due (only in run_input())
+    Input,
+}
+
+/// run control for an emulated system: frame timing, pause, resume and single-stepping
+///
+/// The Machine doesn't own the CPU or the system bus, instead **run()**
+/// is called once per host frame with a closure which executes a single
+/// instruction of the emulated system (including updating the chips) and
+/// returns the number of cycles it took. The Machine executes the closure
+/// until one video frame worth of cycles has passed (the frame end and
+/// due key events are events in an internal Scheduler), while paused it
+/// doesn't execute anything unless a single frame or instruction has been
+/// requested with **advance_frame()** or **advance_instruction()**.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, Machine, NullBus};
+///
+/// let mut cpu = CPU::new_64k();
+/// // 100 cycles per frame
+/// let mut machine = Machine::new(100);
+///
+/// // the memory is filled with NOPs (4 cycles each)
+/// assert_eq!(machine.run(|| cpu.step(&NullBus)), 100);
+/// assert_eq!(machine.frame_count(), 1);
+///
+/// machine.pause();
+/// assert_eq!(machine.run(|| cpu.step(&NullBus)), 0);
+/// machine.advance_instruction();
+/// assert_eq!(machine.run(|| cpu.step(&NullBus)), 4);
+/// assert_eq!(machine.run(|| cpu.step(&NullBus)), 0);
+/// machine.advance_frame();
+/// assert_eq!(machine.run(|| cpu.step(&NullBus)), 96);
+/// assert_eq!(machine.frame_count(), 2);
+/// ```
+///
+/// The Machine also has an InputQueue for keyboard input, **type_text()**
+/// types a text at a number of characters per second (e.g. to paste a
+/// BASIC listing, or in automated tests), and **run_input()** forwards
+/// due key events to the emulated keyboard between instructions:
+///
+/// ```
+/// use rz80::{CPU, Machine, Clock, NullBus, KeyMatrix};
+///
+/// let mut cpu = CPU::new_64k();
+/// let mut kbd = KeyMatrix::new();
+/// kbd.map(b'A', KeyMatrix::bit(0, 0));
+/// // 1 MHz CPU, 50 frames per second
+/// let mut machine = Machine::with_clock(Clock::new(1_000_000), 50);
+///
+/// // 10 characters per second, each key is held for 2.5 frames
+/// machine.type_text("A", 10);
+/// machine.run_input(|| cpu.step(&NullBus), |event| kbd.apply(event));
+/// assert_eq!(kbd.state(), 1);
+/// for _ in 0..2 {
+///     machine.run_input(|| cpu.step(&NullBus), |event| kbd.apply(event));
+/// }
+/// assert_eq!(kbd.state(), 0);
+/// assert!(machine.input().is_empty());
+/// ```
+///
+/// Instead of executing exactly one frame per call, **run_speed()**
+/// executes the cycles which are due after a duration of host time,
+/// according to the Speed policy set with **set_speed()**:
+///
+/// ```
+/// use rz80::{CPU, Machine, Clock, NullBus, Speed};
+///
+/// let mut cpu = CPU::new_64k();
+/// // 1 MHz CPU, 50 frames per second
+/// let mut machine = Machine::with_clock(Clock::new(1_000_000), 50);
+///
+/// // real time: 1 ms of host time executes 1000 cycles
+/// machine.set_speed(Speed::Factor(1.0));
+/// assert_eq!(machine.run_speed(1000, 0, || cpu.step(&NullBus)), 1000);
+/// // double speed
+/// machine.set_speed(Speed::Factor(2.0));
+/// assert_eq!(machine.run_speed(1000, 0, || cpu.step(&NullBus)), 2000);
+/// // fill up the audio buffer to 100 samples at 10 kHz (100 cycles per sample)
+/// machine.set_speed(Speed::Audio { sample_rate: 10_000, target: 100 });
+/// assert_eq!(machine.run_speed(1000, 90, || cpu.step(&NullBus)), 1000);
+/// assert_eq!(machine.run_speed(1000, 100, || cpu.step(&NullBus)), 0);
+/// ```
+#[derive(Clone,Debug)]
+pub struct Machine {
+    frame_cycles: i64,
+    frame_start: i64,
+    frame_count: u64,
+    sched: Scheduler<Event>,
+    input_time: i64,
+    paused: bool,
+    advance: Advance,
+    clock: Option<Clock>,
+    speed: Speed,
+    /// cycles executed ahead of the due cycles (the last instruction overshoot)
+    speed_debt: i64,
+    /// fractional due cycles carried over to the next run_speed()
+    speed_frac: f64,
+    input: InputQueue,
+}
+
+impl Machine {
+    /// create a running Machine with the number of CPU cycles per video frame
+    pub fn new(frame_cycles: i64) -> Machine {
+        assert!(frame_cycles > 0);
+        let mut sched = Scheduler::new();
+        sched.schedule(frame_cycles, Event::FrameEnd);
+        Machine {
+            frame_cycles,
+            frame_start: 0,
+            frame_count: 0,
+            sched,
+            input_time: 0,
+            paused: false,
+            advance: Advance::None,
+            clock: None,
+            speed: Speed::Vsync,
+            speed_debt: 0,
+            speed_frac: 0.0,
+            input: InputQueue::new(0),
+        }
+    }
+
+    /// create a running Machine from the CPU clock and the video frame rate in Hz
+    pub fn with_clock(clock: Clock, frame_rate: i64) -> Machine {
+        let mut machine = Machine::new(clock.cycles_per_frame(frame_rate));
+        machine.clock = Some(clock);
+        machine
+    }
+
+    /// create a running Machine with the clock and exact frame length of a profile
+    pub fn with_profile(profile: MachineProfile) -> Machine {
+        let mut machine = Machine::new(profile.cycles_per_frame());
+        machine.clock = Some(profile.clock);
+        machine
+    }
+
+    /// the CPU clock if the Machine was created with with_clock() or with_profile()
+    pub fn clock(&self) -> Option<Clock> {
+        self.clock
+    }
+
+    /// number of CPU cycles per video frame
+    pub fn frame_cycles(&self) -> i64 {
+        self.frame_cycles
+    }
+
+    /// number of cycles executed in the current frame
+    pub fn frame_pos(&self) -> i64 {
+        self.sched.now() - self.frame_start
+    }
+
+    /// number of completed frames
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// set the speed policy of run_speed() (default is Speed::Vsync)
+    ///
+    /// Speed::Factor and Speed::Audio need the CPU clock, so the Machine
+    /// must have been created with with_clock().
+    pub fn set_speed(&mut self, speed: Speed) {
+        if let Speed::Factor(_) | Speed::Audio { .. } = speed {
+            assert!(self.clock.is_some(), "the speed policy needs Machine::with_clock()");
+        }
+        self.speed = speed;
+        self.speed_debt = 0;
+        self.speed_frac = 0.0;
+    }
+
+    /// the speed policy of run_speed()
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// the keyboard input queue
+    pub fn input(&self) -> &InputQueue {
+        &self.input
+    }
+
+    /// the keyboard input queue for host key events
+    pub fn input_mut(&mut self) -> &mut InputQueue {
+        &mut self.input
+    }
+
+    /// type a text at a number of characters per second (see InputQueue::type_text())
+    ///
+    /// The key events are delivered by run_input(), this needs the CPU
+    /// clock, so the Machine must have been created with with_clock().
+    pub fn type_text(&mut self, text: &str, chars_per_second: i64) {
+        assert!(chars_per_second > 0);
+        let hz = self.clock.expect("type_text() needs Machine::with_clock()").hz();
+        self.input.type_text(text, hz / chars_per_second);
+    }
+
+    /// pause execution, run() doesn't execute anything until resumed
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.advance = Advance::None;
+    }
+
+    /// resume execution after pause
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.advance = Advance::None;
+    }
+
+    /// true if execution is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// while paused, execute the rest of the current frame on the next run()
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advance = Advance::Frame;
+        }
+    }
+
+    /// while paused, execute a single instruction on the next run()
+    pub fn advance_instruction(&mut self) {
+        if self.paused {
+            self.advance = Advance::Instruction;
+        }
+    }
+
+    /// execute one frame (or one instruction while single-stepping), return executed cycles
+    ///
+    /// Cycles executed past the end of a frame are subtracted from
+    /// the next frame, so the average frame length is exact.
+    pub fn run<F>(&mut self, step: F) -> i64
+        where F: FnMut() -> i64
+    {
+        self.run_frame(step, None::<fn(InputEvent)>, i64::MAX).0
+    }
+
+    /// like run(), and forward due key events of the input queue after each instruction
+    pub fn run_input<F, I>(&mut self, step: F, input: I) -> i64
+        where F: FnMut() -> i64,
+              I: FnMut(InputEvent)
+    {
+        self.run_frame(step, Some(input), i64::MAX).0
+    }
+
+    /// like run_input(), but stop after max_cycles (plus the overshoot of the
+    /// last instruction), return executed cycles and whether the frame (or
+    /// single-stepped instruction) is complete, see MachineHandle
+    pub(crate) fn run_partial<F, I>(&mut self, max_cycles: i64, step: F, input: I) -> (i64, bool)
+        where F: FnMut() -> i64,
+              I: FnMut(InputEvent)
+    {
+        self.run_frame(step, Some(input), max_cycles)
+    }
+
+    fn run_frame<F, I>(&mut self, mut step: F, mut input: Option<I>, max_cycles: i64) -> (i64, bool)
+        where F: FnMut() -> i64,
+              I: FnMut(InputEvent)
+    {
+        let advance = self.advance;
+        self.advance = Advance::None;
+        if self.paused && advance == Advance::None {
+            return (0, false);
+        }
+        if input.is_some() {
+            // key events are only delivered in run_input(), the input
+            // queue doesn't see the cycles executed by run()
+            self.input_time = self.sched.now();
+            self.schedule_input();
+        }
+        let mut cycles = 0;
+        let done = loop {
+            let c = step();
+            cycles += c;
+            self.sched.advance(c);
+            let mut frame_end = false;
+            while let Some((time, event)) = self.sched.pop() {
+                match event {
+                    Event::FrameEnd => {
+                        self.end_frame(time);
+                        frame_end = true;
+                    }
+                    Event::Input => {
+                        if let Some(ref mut input) = input {
+                            self.sync_input();
+                            while let Some(event) = self.input.pop() {
+                                input(event);
+                            }
+                            self.schedule_input();
+                        }
+                    }
+                }
+            }
+            if frame_end || advance == Advance::Instruction {
+                break true;
+            }
+            if cycles >= max_cycles {
+                // a requested frame continues on the next call
+                self.advance = advance;
+                break false;
+            }
+        };
+        if input.is_some() {
+            self.sync_input();
+            self.sched.cancel(|e| *e == Event::Input);
+        }
+        (cycles, done)
+    }
+
+    // the frame which ended at a point in time, the overshoot of
+    // the last instruction belongs to the next frame
+    fn end_frame(&mut self, time: i64) {
+        self.frame_start = time;
+        self.frame_count += 1;
+        self.sched.schedule_at(time + self.frame_cycles, Event::FrameEnd);
+    }
+
+    // bring the input queue up to the scheduler's current time
+    fn sync_input(&mut self) {
+        let now = self.sched.now();
+        self.input.advance(now - self.input_time);
+        self.input_time = now;
+    }
+
+    // schedule the next due key event of the input queue
+    fn schedule_input(&mut self) {
+        self.sched.cancel(|e| *e == Event::Input);
+        if let Some(next) = self.input.cycles_to_next() {
+            self.sched.schedule(next, Event::Input);
+        }
+    }
+
+    /// execute the cycles which are due according to the speed policy, return executed cycles
+    ///
+    /// The host calls this once per host frame with the host time in
+    /// microseconds since the last call, and (for Speed::Audio) the
+    /// number of samples waiting in the host audio buffer. Fractional
+    /// cycles and the overshoot of the last instruction are carried over
+    /// to the next call, so the emulation doesn't drift. After a host
+    /// stall, at most 4 frames are caught up. While paused, this behaves
+    /// like run().
+    pub fn run_speed<F>(&mut self, elapsed_micros: i64, audio_queued: usize, mut step: F) -> i64
+        where F: FnMut() -> i64
+    {
+        if self.paused {
+            return self.run(step);
+        }
+        let max_due = MAX_CATCHUP_FRAMES * self.frame_cycles as f64;
+        let due = match self.speed {
+            Speed::Vsync => return self.run(step),
+            Speed::Unlimited => {
+                let start = Instant::now();
+                let budget = Duration::from_micros(elapsed_micros.max(0) as u64);
+                let mut cycles = 0;
+                loop {
+                    cycles += self.run(&mut step);
+                    if start.elapsed() >= budget {
+                        return cycles;
+                    }
+                }
+            }
+            Speed::Factor(factor) => {
+                let hz = self.clock.map_or(0, |c| c.hz()) as f64;
+                let due = hz * elapsed_micros.max(0) as f64 / 1_000_000.0 * factor + self.speed_frac;
+                due.min(max_due * factor.max(1.0))
+            }
+            Speed::Audio { sample_rate, target } => {
+                let hz = self.clock.map_or(0, |c| c.hz()) as f64;
+                let missing = target.saturating_sub(audio_queued) as f64;
+                (missing * hz / sample_rate as f64 + self.speed_frac).min(max_due)
+            }
+        };
+        self.speed_frac = due.fract();
+        let due = due as i64 - self.speed_debt;
+        if due <= 0 {
+            self.speed_debt = -due;
+            return 0;
+        }
+        let mut cycles = 0;
+        while cycles < due {
+            let c = step();
+            cycles += c;
+            self.sched.advance(c);
+            while let Some((time, _)) = self.sched.pop() {
+                self.end_frame(time);
+            }
+        }
+        self.speed_debt = cycles - due;
+        cycles
+    }
+}
+
+/// host frame time measurement for the emulator main loop
+///
+/// **tick()** is called once at the start of each host frame and returns
+/// the host time in microseconds since the last call, which is passed to
+/// the emulator (e.g. to **Machine::run_speed()** or a system's step
+/// function). After a host stall (e.g. while the window is dragged) at
+/// most 4 emulated frames worth of time is returned.
+///
+/// If the host is too slow to run the emulation and render each frame
+/// (a host frame took more than 5/4 of the emulated frame duration),
+/// **skip_frame()** recommends to skip the rendering of the current
+/// frame, but never more than 4 frames in a row.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::FrameTimer;
+/// use std::time::{Duration, Instant};
+///
+/// // 50 Hz emulated frame rate
+/// let mut timer = FrameTimer::new(50);
+/// let start = Instant::now();
+/// // the first frame has the emulated frame duration
+/// assert_eq!(timer.tick_at(start), 20000);
+/// assert_eq!(timer.tick_at(start + Duration::from_millis(16)), 16000);
+/// assert!(!timer.skip_frame());
+/// // a late frame
+/// assert_eq!(timer.tick_at(start + Duration::from_millis(46)), 30000);
+/// assert!(timer.skip_frame());
+/// assert_eq!(timer.skipped_frames(), 1);
+/// ```
+#[derive(Clone,Debug)]
+pub struct FrameTimer {
+    frame_micros: i64,
+    last: Option<Instant>,
+    skip: bool,
+    skipped_in_row: u32,
+    skipped: u64,
+}
+
+impl FrameTimer {
+    /// create a frame timer for the emulated video frame rate in Hz
+    pub fn new(frame_rate: i64) -> FrameTimer {
+        assert!(frame_rate > 0);
+        FrameTimer {
+            frame_micros: 1_000_000 / frame_rate,
+            last: None,
+            skip: false,
+            skipped_in_row: 0,
+            skipped: 0,
+        }
+    }
+
+    /// the emulated frame duration in microseconds
+    pub fn frame_micros(&self) -> i64 {
+        self.frame_micros
+    }
+
+    /// start a new host frame, return the microseconds since the last tick
+    pub fn tick(&mut self) -> i64 {
+        self.tick_at(Instant::now())
+    }
+
+    /// start a new host frame at a point in time
+    pub fn tick_at(&mut self, now: Instant) -> i64 {
+        let elapsed = match self.last {
+            Some(last) => now.saturating_duration_since(last).as_micros() as i64,
+            None => self.frame_micros,
+        };
+        self.last = Some(now);
+        let late = elapsed * LATE_FRAME_DEN > self.frame_micros * LATE_FRAME_NUM;
+        if late && self.skipped_in_row < MAX_SKIPPED_FRAMES {
+            self.skip = true;
+            self.skipped_in_row += 1;
+            self.skipped += 1;
+        } else {
+            self.skip = false;
+            self.skipped_in_row = 0;
+        }
+        elapsed.min(self.frame_micros * MAX_CATCHUP_FRAMES as i64)
+    }
+
+    /// true if the rendering of the current frame should be skipped
+    pub fn skip_frame(&self) -> bool {
+        self.skip
+    }
+
+    /// total number of skipped frames
+    pub fn skipped_frames(&self) -> u64 {
+        self.skipped
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_overshoot() {
+        let mut machine = Machine::new(10);
+        // 3 cycles per instruction: 4 instructions fill the
+        // first frame, the next frame is 2 cycles shorter
+        assert_eq!(machine.run(|| 3), 12);
+        assert_eq!(machine.frame_pos(), 2);
+        assert_eq!(machine.run(|| 3), 9);
+        assert_eq!(machine.frame_pos(), 1);
+        assert_eq!(machine.frame_count(), 2);
+    }
+
+    #[test]
+    fn pause_resume() {
+        let mut machine = Machine::new(10);
+        let mut steps = 0;
+        // advance requests are ignored while running
+        machine.advance_instruction();
+        assert_eq!(machine.run(|| {
+                                   steps += 1;
+                                   5
+                               }),
+                   10);
+        assert_eq!(steps, 2);
+
+        machine.pause();
+        assert!(machine.is_paused());
+        machine.advance_instruction();
+        assert_eq!(machine.run(|| 5), 5);
+        assert_eq!(machine.run(|| 5), 0);
+        assert_eq!(machine.frame_count(), 1);
+        // the last instruction of a frame completes the frame
+        machine.advance_instruction();
+        assert_eq!(machine.run(|| 5), 5);
+        assert_eq!(machine.frame_count(), 2);
+        // a pending advance request is dropped on resume
+        machine.advance_frame();
+        machine.resume();
+        assert!(!machine.is_paused());
+        assert_eq!(machine.run(|| 5), 10);
+        assert_eq!(machine.frame_count(), 3);
+    }
+
+    #[test]
+    fn speed_factor() {
+        let mut machine = Machine::with_clock(Clock::new(1_000_000), 100);
+        machine.set_speed(Speed::Factor(1.0));
+        // 3 cycles per instruction: the 2 cycles overshoot are
+        // subtracted from the next call
+        assert_eq!(machine.run_speed(1000, 0, || 3), 1002);
+        assert_eq!(machine.run_speed(1000, 0, || 3), 999);
+        assert_eq!(machine.frame_count(), 0);
+        // fractional cycles are carried over
+        machine.set_speed(Speed::Factor(0.5));
+        assert_eq!(machine.run_speed(3, 0, || 1), 1);
+        assert_eq!(machine.run_speed(3, 0, || 1), 2);
+        // a host stall only catches up 4 frames
+        machine.set_speed(Speed::Factor(1.0));
+        assert_eq!(machine.run_speed(1_000_000, 0, || 10), 40000);
+        assert_eq!(machine.frame_count(), 4);
+        // paused is paused
+        machine.pause();
+        assert_eq!(machine.run_speed(1000, 0, || 10), 0);
+    }
+
+    #[test]
+    fn speed_audio() {
+        let mut machine = Machine::with_clock(Clock::new(1_000_000), 50);
+        machine.set_speed(Speed::Audio { sample_rate: 50_000, target: 1000 });
+        assert_eq!(machine.run_speed(0, 0, || 4), 20000);
+        assert_eq!(machine.run_speed(0, 1000, || 4), 0);
+        assert_eq!(machine.run_speed(0, 999, || 4), 20);
+        assert_eq!(machine.frame_count(), 1);
+        // Vsync and Unlimited run whole frames
+        machine.set_speed(Speed::Vsync);
+        assert_eq!(machine.run_speed(0, 0, || 4), 19980);
+        machine.set_speed(Speed::Unlimited);
+        assert_eq!(machine.run_speed(0, 0, || 4), 20000);
+        assert_eq!(machine.frame_count(), 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn speed_needs_clock() {
+        Machine::new(100).set_speed(Speed::Factor(1.0));
+    }
+
+    #[test]
+    fn frame_skip() {
+        let mut timer = FrameTimer::new(50);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(timer.tick_at(at(0)), 20000);
+        // a host stall only returns 4 frames
+        assert_eq!(timer.tick_at(at(1000)), 80000);
+        // at most 4 frames are skipped in a row
+        for i in 0..6 {
+            timer.tick_at(at(1030 + i * 30));
+            assert_eq!(timer.skip_frame(), i != 3);
+        }
+        assert_eq!(timer.skipped_frames(), 6);
+        timer.tick_at(at(1200));
+        assert!(!timer.skip_frame());
+    }
+
+    #[test]
+    fn with_clock() {
+        let clock = Clock::new(3_546_900);
+        let machine = Machine::with_clock(clock, 50);
+        assert_eq!(machine.frame_cycles(), 70938);
+        assert_eq!(machine.clock(), Some(clock));
+        assert_eq!(Machine::new(100).clock(), None);
+        // round trip
+        assert_eq!(clock.micros_from_cycles(clock.cycles_from_micros(20000)), 20000);
+    }
+
+    #[test]
+    fn profiles() {
+        assert_eq!(PROFILE_Z1013.cycles_per_frame(), 40000);
+        assert_eq!(PROFILE_Z9001.frame_rate_milli(), 50000);
+        assert_eq!(PROFILE_Z9001.frame_micros(), 20000);
+        assert_eq!(PROFILE_SMS.frame_rate_milli(), 59923);
+        assert_eq!(PROFILE_SMS.frame_timing(), FrameTiming::ntsc(228));
+        assert_eq!(PROFILE_CPC.frame_timing().lines_per_frame, PAL_LINES);
+        assert_eq!(PROFILE_CPC.frame_micros(), 19968);
+        assert_eq!(PROFILE_ZX128.cycles_per_frame(), 70908);
+        assert_eq!(PROFILE_CPC.cycles_per_sample_fp(1_000_000), 4 << 16);
+        assert_eq!(PROFILE_SMS.line_pos(228 * 10 + 5), (10, 5));
+        assert_eq!(PROFILE_SMS.line_pos(-1), (261, 227));
+        let machine = Machine::with_profile(PROFILE_ZX128);
+        assert_eq!(machine.clock(), Some(Clock::new(3_546_900)));
+    }
+}
@@ -0,0 +1,254 @@
+use RegT;
+use memory::Memory;
+
+const BANK_SIZE: usize = 0x4000;
+
+/// cartridge mapper trait
+///
+/// A mapper implements the bank switching logic of a cartridge. Most
+/// cartridge mappers are controlled by CPU writes to specific memory
+/// addresses, so a mapper attached to a Memory object with
+/// **Memory::set_mapper()** tells the Memory object which 1 KByte pages
+/// to watch (the **trap_mask()**), and the Memory object calls the
+/// mapper's **write()** method for each write to a watched page. The
+/// mapper then updates the memory mapping of its memory layer.
+pub trait Mapper {
+    /// called when the mapper is attached to a Memory object, map the initial banks
+    fn init(&mut self, mem: &mut Memory);
+    /// bit mask of the 64 1-KByte pages where writes must be forwarded to write()
+    fn trap_mask(&self) -> u64;
+    /// called after the CPU has written to a trapped page
+    fn write(&mut self, mem: &mut Memory, addr: RegT, val: RegT);
+}
+
+fn rom_bank(rom: &'static [u8], bank: usize) -> &'static [u8] {
+    let num_banks = rom.len() / BANK_SIZE;
+    let start = (bank % num_banks) * BANK_SIZE;
+    &rom[start..start + BANK_SIZE]
+}
+
+/// the standard Sega Master System / Game Gear cartridge mapper
+///
+/// Maps 3 16 KByte ROM banks to 0x0000, 0x4000 and 0x8000, selected by
+/// writing to the mapper registers at 0xFFFD, 0xFFFE and 0xFFFF (the
+/// first KByte at 0x0000 always maps ROM bank 0). Bit 3 of the control
+/// register at 0xFFFC maps 16 KByte of the (optional) 32 KByte cartridge
+/// RAM to 0x8000, bit 2 selects the RAM bank. The mapper registers are
+/// write-only and overlay the system RAM, so the written values also
+/// end up in RAM.
+///
+/// The ROM image must be a multiple of 16 KBytes, and is mapped
+/// without copying (see Memory::map_slice()), so it must be 'static
+/// (for instance embedded with include_bytes!, or a loaded ROM file
+/// turned into a static slice with Box::leak()).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Memory, SegaMapper};
+/// static ROM: [u8; 0x10000] = [0xAA; 0x10000];
+///
+/// let mut mem = Memory::new();
+/// // 8 KByte system RAM at 0xC000, mirrored at 0xE000
+/// mem.map(1, 0x00000, 0xC000, true, 0x2000);
+/// mem.map(1, 0x00000, 0xE000, true, 0x2000);
+/// // ROM banks are mapped on layer 0, cartridge RAM at heap offset 0x10000
+/// mem.set_mapper(Box::new(SegaMapper::new(&ROM, 0, 0x10000)));
+/// assert_eq!(mem.r8(0x0000), 0xAA);
+///
+/// // enable cartridge RAM at 0x8000
+/// mem.w8(0xFFFC, 0x08);
+/// mem.w8(0x8000, 0x55);
+/// assert_eq!(mem.r8(0x8000), 0x55);
+/// ```
+pub struct SegaMapper {
+    rom: &'static [u8],
+    layer: usize,
+    ram_offset: usize,
+    regs: [u8; 4],
+}
+
+impl SegaMapper {
+    /// create a Sega mapper for a ROM image, on a memory layer, with cartridge RAM at a heap offset
+    pub fn new(rom: &'static [u8], layer: usize, ram_offset: usize) -> SegaMapper {
+        assert!(!rom.is_empty() && (rom.len() & (BANK_SIZE - 1)) == 0);
+        SegaMapper {
+            rom,
+            layer,
+            ram_offset,
+            regs: [0, 0, 1, 2],
+        }
+    }
+
+    /// get the mapper register values (0xFFFC..0xFFFF)
+    pub fn regs(&self) -> [u8; 4] {
+        self.regs
+    }
+
+    fn update(&self, mem: &mut Memory) {
+        let rom = self.rom;
+        mem.map_slice(self.layer, 0x0000, &rom[0..0x0400]);
+        mem.map_slice(self.layer, 0x0400, &rom_bank(rom, self.regs[1] as usize)[0x0400..]);
+        mem.map_slice(self.layer, 0x4000, rom_bank(rom, self.regs[2] as usize));
+        if (self.regs[0] & 0x08) != 0 {
+            let offset = self.ram_offset + if (self.regs[0] & 0x04) != 0 { BANK_SIZE } else { 0 };
+            mem.map(self.layer, offset, 0x8000, true, BANK_SIZE);
+        } else {
+            mem.map_slice(self.layer, 0x8000, rom_bank(rom, self.regs[3] as usize));
+        }
+    }
+}
+
+impl Mapper for SegaMapper {
+    fn init(&mut self, mem: &mut Memory) {
+        self.regs = [0, 0, 1, 2];
+        self.update(mem);
+    }
+
+    fn trap_mask(&self) -> u64 {
+        // the page 0xFC00..0xFFFF
+        1 << 63
+    }
+
+    fn write(&mut self, mem: &mut Memory, addr: RegT, val: RegT) {
+        if addr >= 0xFFFC {
+            self.regs[(addr - 0xFFFC) as usize] = val as u8;
+            self.update(mem);
+        }
+    }
+}
+
+/// the Codemasters cartridge mapper
+///
+/// Maps 3 16 KByte ROM banks to 0x0000, 0x4000 and 0x8000, selected
+/// by writing the bank number to 0x0000, 0x4000 and 0x8000. Like the
+/// SegaMapper, the ROM image must be a multiple of 16 KBytes and 'static.
+pub struct CodemastersMapper {
+    rom: &'static [u8],
+    layer: usize,
+    regs: [u8; 3],
+}
+
+impl CodemastersMapper {
+    /// create a Codemasters mapper for a ROM image on a memory layer
+    pub fn new(rom: &'static [u8], layer: usize) -> CodemastersMapper {
+        assert!(!rom.is_empty() && (rom.len() & (BANK_SIZE - 1)) == 0);
+        CodemastersMapper {
+            rom,
+            layer,
+            regs: [0, 1, 0],
+        }
+    }
+
+    /// get the bank numbers mapped to 0x0000, 0x4000 and 0x8000
+    pub fn regs(&self) -> [u8; 3] {
+        self.regs
+    }
+
+    fn update(&self, mem: &mut Memory) {
+        for slot in 0..3 {
+            let bank = rom_bank(self.rom, self.regs[slot] as usize);
+            mem.map_slice(self.layer, slot * BANK_SIZE, bank);
+        }
+    }
+}
+
+impl Mapper for CodemastersMapper {
+    fn init(&mut self, mem: &mut Memory) {
+        self.regs = [0, 1, 0];
+        self.update(mem);
+    }
+
+    fn trap_mask(&self) -> u64 {
+        // the pages at 0x0000, 0x4000 and 0x8000
+        1 | (1 << 16) | (1 << 32)
+    }
+
+    fn write(&mut self, mem: &mut Memory, addr: RegT, val: RegT) {
+        match addr {
+            0x0000 | 0x4000 | 0x8000 => {
+                self.regs[(addr >> 14) as usize] = val as u8;
+                self.update(mem);
+            }
+            _ => (),
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+
+    // a ROM image where each byte contains its bank number
+    fn make_rom(num_banks: usize) -> &'static [u8] {
+        let mut rom = Vec::new();
+        for bank in 0..num_banks {
+            rom.extend(vec![bank as u8; BANK_SIZE]);
+        }
+        Box::leak(rom.into_boxed_slice())
+    }
+
+    #[test]
+    fn sega_mapper() {
+        let rom = make_rom(8);
+        let mut mem = Memory::new();
+        mem.map(1, 0x00000, 0xC000, true, 0x2000);
+        mem.map(1, 0x00000, 0xE000, true, 0x2000);
+        mem.set_mapper(Box::new(SegaMapper::new(rom, 0, 0x10000)));
+        assert_eq!(mem.r8(0x0000), 0);
+        assert_eq!(mem.r8(0x4000), 1);
+        assert_eq!(mem.r8(0x8000), 2);
+
+        // bank switching, the first KByte is always bank 0
+        mem.w8(0xFFFD, 5);
+        mem.w8(0xFFFE, 6);
+        mem.w8(0xFFFF, 7 + 8);
+        assert_eq!(mem.r8(0x0000), 0);
+        assert_eq!(mem.r8(0x03FF), 0);
+        assert_eq!(mem.r8(0x0400), 5);
+        assert_eq!(mem.r8(0x7FFF), 6);
+        assert_eq!(mem.r8(0x8000), 7);
+        // the mapper registers overlay the RAM
+        assert_eq!(mem.r8(0xDFFF), 7 + 8);
+
+        // cartridge RAM
+        mem.w8(0xFFFC, 0x08);
+        mem.w8(0x8000, 0x11);
+        mem.w8(0xFFFC, 0x0C);
+        mem.w8(0x8000, 0x22);
+        assert_eq!(mem.r8(0x8000), 0x22);
+        mem.w8(0xFFFC, 0x08);
+        assert_eq!(mem.r8(0x8000), 0x11);
+        mem.w8(0xFFFC, 0x00);
+        assert_eq!(mem.r8(0x8000), 7);
+
+        // writes to other pages are not forwarded
+        mem.w8(0xC000, 1);
+        assert_eq!(mem.r8(0x4000), 6);
+    }
+
+    #[test]
+    fn codemasters_mapper() {
+        let rom = make_rom(4);
+        let mut mem = Memory::new();
+        mem.map(1, 0x00000, 0xC000, true, 0x4000);
+        mem.set_mapper(Box::new(CodemastersMapper::new(rom, 0)));
+        assert_eq!(mem.r8(0x0000), 0);
+        assert_eq!(mem.r8(0x4000), 1);
+        assert_eq!(mem.r8(0x8000), 0);
+        mem.w8(0x8000, 3);
+        mem.w8(0x4000, 2);
+        mem.w8(0x4001, 0);
+        assert_eq!(mem.r8(0x4000), 2);
+        assert_eq!(mem.r8(0x8000), 3);
+        mem.w8(0x0000, 1);
+        assert_eq!(mem.r8(0x0000), 1);
+
+        // removing the mapper keeps the current mapping, but stops bank switching
+        assert!(mem.remove_mapper().is_some());
+        mem.w8(0x0000, 2);
+        assert_eq!(mem.r8(0x0000), 1);
+    }
+}
@@ -0,0 +1,455 @@
+use std::fmt;
+use RegT;
+use cpu::CPU;
+use decoder::{decode, Instruction, Operand, Cond};
+use registers::{Reg8, Im};
+
+/// kind of a machine cycle
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum MCycleKind {
+    /// opcode fetch (M1), also for the prefix bytes
+    Fetch,
+    /// memory read, also for the operand bytes (n, nn and d)
+    Read,
+    /// memory write
+    Write,
+    /// I/O port read
+    In,
+    /// I/O port write
+    Out,
+    /// internal operation without bus access
+    Internal,
+    /// interrupt acknowledge
+    IntAck,
+}
+
+/// a machine cycle recorded by the machine cycle trace
+///
+/// See CPU::set_mcycle_trace(). Internal cycles have address and data 0.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct MCycle {
+    pub kind: MCycleKind,
+    /// memory address, or the 16-bit port address of I/O cycles
+    pub addr: RegT,
+    /// the byte on the data bus
+    pub data: RegT,
+    /// length in clock cycles (T states), including wait states
+    pub tstates: i64,
+}
+
+impl fmt::Display for MCycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.kind {
+            MCycleKind::Fetch => "M1",
+            MCycleKind::Read => "MR",
+            MCycleKind::Write => "MW",
+            MCycleKind::In => "IOR",
+            MCycleKind::Out => "IOW",
+            MCycleKind::Internal => "--",
+            MCycleKind::IntAck => "IACK",
+        };
+        if self.kind == MCycleKind::Internal {
+            write!(f, "{:4} ---- -- {}T", name, self.tstates)
+        } else {
+            write!(f, "{:4} {:04X} {:02X} {}T", name, self.addr, self.data, self.tstates)
+        }
+    }
+}
+
+// the machine cycles of the current step(), and the last value on the
+// data bus of an I/O access or interrupt acknowledge
+pub struct MCycleTrace {
+    pub cycles: Vec<MCycle>,
+    pub data: RegT,
+}
+
+impl MCycleTrace {
+    pub fn new() -> MCycleTrace {
+        MCycleTrace {
+            cycles: Vec::new(),
+            data: 0,
+        }
+    }
+}
+
+// builds the machine cycles of an instruction from the CPU state before execution
+struct Builder<'a> {
+    cpu: &'a CPU,
+    cycles: Vec<MCycle>,
+}
+
+impl<'a> Builder<'a> {
+    fn push(&mut self, kind: MCycleKind, addr: RegT, data: RegT, tstates: i64) {
+        self.cycles.push(MCycle { kind, addr: addr & 0xFFFF, data, tstates });
+    }
+
+    fn fetch(&mut self, addr: RegT, tstates: i64) {
+        let data = self.cpu.mem.peek8(addr);
+        self.push(MCycleKind::Fetch, addr, data, tstates);
+    }
+
+    fn read(&mut self, addr: RegT, tstates: i64) {
+        let data = self.cpu.mem.peek8(addr);
+        self.push(MCycleKind::Read, addr, data, tstates);
+    }
+
+    // the data of writes and I/O accesses is filled in after execution
+    fn write(&mut self, addr: RegT, tstates: i64) {
+        self.push(MCycleKind::Write, addr, 0, tstates);
+    }
+
+    fn inp(&mut self, port: RegT) {
+        self.push(MCycleKind::In, port, 0, 4);
+    }
+
+    fn outp(&mut self, port: RegT) {
+        self.push(MCycleKind::Out, port, 0, 4);
+    }
+
+    fn internal(&mut self, tstates: i64) {
+        self.push(MCycleKind::Internal, 0, 0, tstates);
+    }
+
+    // add wait/internal states to the last opcode fetch
+    fn extend_m1(&mut self, tstates: i64) {
+        if let Some(c) = self.cycles.iter_mut().rev().find(|c| c.kind == MCycleKind::Fetch) {
+            c.tstates += tstates;
+        }
+    }
+
+    // add internal states to the last machine cycle
+    fn extend_last(&mut self, tstates: i64) {
+        if let Some(c) = self.cycles.last_mut() {
+            c.tstates += tstates;
+        }
+    }
+
+    // address of a memory operand
+    fn addr(&self, o: Operand) -> Option<RegT> {
+        match o {
+            Operand::Ind(r) => Some(self.cpu.reg.get(r)),
+            Operand::Idx(r, d) => Some((self.cpu.reg.get(r) + d) & 0xFFFF),
+            Operand::Abs(nn) => Some(nn),
+            Operand::Reg(_) | Operand::Imm(_) => None,
+        }
+    }
+
+    fn taken(&self, cc: Option<Cond>) -> bool {
+        cc.is_none_or(|cc| self.cpu.cc(cc as usize))
+    }
+
+    fn push16(&mut self, sp: RegT) {
+        self.write(sp - 1, 3);
+        self.write(sp - 2, 3);
+    }
+
+    fn pop16(&mut self, sp: RegT) {
+        self.read(sp, 3);
+        self.read(sp + 1, 3);
+    }
+}
+
+/// the machine cycles of the instruction at PC, before it is executed
+pub fn plan(cpu: &CPU) -> Vec<MCycle> {
+    let pc = cpu.reg.pc();
+    let bytes = cpu.mem.peek_inst(pc);
+    let (inst, len) = decode(&bytes);
+    let mut b = Builder { cpu, cycles: Vec::new() };
+
+    // opcode fetches and operand bytes, the DD CB d op and FD CB d op
+    // instructions fetch the op byte in a memory read with internal states
+    b.fetch(pc, 4);
+    let num_m1 = match (bytes[0], bytes[1]) {
+        (0xCB, _) | (0xED, _) => 2,
+        (0xDD, _) | (0xFD, _) if len > 1 => 2,
+        _ => 1,
+    };
+    if num_m1 > 1 {
+        b.fetch(pc + 1, 4);
+    }
+    for i in num_m1..len {
+        b.read(pc + i, 3);
+    }
+    if let (0xDD, 0xCB) | (0xFD, 0xCB) = (bytes[0], bytes[1]) {
+        b.extend_last(2);
+    }
+
+    let reg = &cpu.reg;
+    let sp = reg.sp();
+    match inst {
+        // LD A,I; LD A,R; LD I,A; LD R,A
+        Instruction::Ld(Operand::Reg(Reg8::I), _) |
+        Instruction::Ld(Operand::Reg(Reg8::R), _) |
+        Instruction::Ld(_, Operand::Reg(Reg8::I)) |
+        Instruction::Ld(_, Operand::Reg(Reg8::R)) => b.extend_m1(1),
+        Instruction::Ld(dst, src) => {
+            match (dst, src) {
+                // LD (IX+d),n reads n during the address calculation
+                (Operand::Idx(..), Operand::Imm(_)) => b.extend_last(2),
+                (Operand::Idx(..), _) | (_, Operand::Idx(..)) => b.internal(5),
+                _ => (),
+            }
+            if let Some(addr) = b.addr(src) {
+                b.read(addr, 3);
+            }
+            if let Some(addr) = b.addr(dst) {
+                b.write(addr, 3);
+            }
+        }
+        Instruction::LdToMem16(nn, _) | Instruction::EdLdToMem16(nn, _) => {
+            b.write(nn, 3);
+            b.write(nn + 1, 3);
+        }
+        Instruction::LdFromMem16(_, nn) | Instruction::EdLdFromMem16(_, nn) => {
+            b.read(nn, 3);
+            b.read(nn + 1, 3);
+        }
+        Instruction::LdSP(_) | Instruction::Inc16(_) | Instruction::Dec16(_) => b.extend_m1(2),
+        Instruction::Push(_) => {
+            b.extend_m1(1);
+            b.push16(sp);
+        }
+        Instruction::Pop(_) | Instruction::Ret(None) | Instruction::Reti | Instruction::Retn => {
+            b.pop16(sp);
+        }
+        Instruction::ExSP(_) => {
+            b.read(sp, 3);
+            b.read(sp + 1, 4);
+            b.write(sp + 1, 3);
+            b.write(sp, 5);
+        }
+        Instruction::Alu(_, o) | Instruction::Inc(o) | Instruction::Dec(o) |
+        Instruction::Rot(_, o, _) | Instruction::Bit(_, o) |
+        Instruction::Res(_, o, _) | Instruction::Set(_, o, _) => {
+            if let Some(addr) = b.addr(o) {
+                // the DD CB and FD CB instructions have read d and op already
+                if let (Operand::Idx(..), Instruction::Alu(..)) |
+                       (Operand::Idx(..), Instruction::Inc(_)) |
+                       (Operand::Idx(..), Instruction::Dec(_)) = (o, inst) {
+                    b.internal(5);
+                }
+                match inst {
+                    Instruction::Alu(..) => b.read(addr, 3),
+                    Instruction::Bit(..) => b.read(addr, 4),
+                    _ => {
+                        b.read(addr, 4);
+                        b.write(addr, 3);
+                    }
+                }
+            }
+        }
+        Instruction::Add16(..) | Instruction::Adc16(_) | Instruction::Sbc16(_) => {
+            b.internal(4);
+            b.internal(3);
+        }
+        Instruction::Rld | Instruction::Rrd => {
+            let hl = reg.hl();
+            b.read(hl, 3);
+            b.internal(4);
+            b.write(hl, 3);
+        }
+        // taken relative jump
+        Instruction::Jr(cc, _) if b.taken(cc) => b.internal(5),
+        Instruction::Djnz(_) => {
+            b.extend_m1(1);
+            if reg.b() != 1 {
+                b.internal(5);
+            }
+        }
+        // taken call
+        Instruction::Call(cc, _) if b.taken(cc) => {
+            b.extend_last(1);
+            b.push16(sp);
+        }
+        Instruction::Ret(cc) => {
+            b.extend_m1(1);
+            if b.taken(cc) {
+                b.pop16(sp);
+            }
+        }
+        Instruction::Rst(_) => {
+            b.extend_m1(1);
+            b.push16(sp);
+        }
+        Instruction::InA(n) => b.inp(reg.a() << 8 | n),
+        Instruction::OutA(n) => b.outp(reg.a() << 8 | n),
+        Instruction::In(_) => b.inp(reg.bc()),
+        Instruction::Out(_) => b.outp(reg.bc()),
+        Instruction::Ldi | Instruction::Ldd | Instruction::Ldir | Instruction::Lddr => {
+            b.read(reg.hl(), 3);
+            b.write(reg.de(), 5);
+        }
+        Instruction::Cpi | Instruction::Cpd | Instruction::Cpir | Instruction::Cpdr => {
+            b.read(reg.hl(), 3);
+            b.internal(5);
+        }
+        Instruction::Ini | Instruction::Ind | Instruction::Inir | Instruction::Indr => {
+            b.extend_m1(1);
+            b.inp(reg.bc());
+            b.write(reg.hl(), 3);
+        }
+        Instruction::Outi | Instruction::Outd | Instruction::Otir | Instruction::Otdr => {
+            // B is decremented before the port is written
+            b.extend_m1(1);
+            b.read(reg.hl(), 3);
+            b.outp(((reg.b() - 1) & 0xFF) << 8 | reg.c());
+        }
+        _ => (),
+    }
+    b.cycles
+}
+
+/// fill in the data of writes and I/O accesses and the extra I/O wait states
+/// after the instruction has executed, the repeat of a block instruction is
+/// an internal cycle
+pub fn complete(cpu: &CPU, trace: &mut MCycleTrace, mut cycles: Vec<MCycle>, tstates: i64,
+                io_wait: i64) {
+    for c in &mut cycles {
+        match c.kind {
+            MCycleKind::Write => c.data = cpu.mem.peek8(c.addr),
+            MCycleKind::In | MCycleKind::Out => {
+                c.data = trace.data;
+                c.tstates += io_wait;
+            }
+            _ => (),
+        }
+    }
+    let total: i64 = cycles.iter().map(|c| c.tstates).sum();
+    if total < tstates {
+        cycles.push(MCycle {
+            kind: MCycleKind::Internal,
+            addr: 0,
+            data: 0,
+            tstates: tstates - total,
+        });
+    }
+    trace.cycles.extend(cycles);
+}
+
+/// the machine cycles of an interrupt acknowledge, after handling the interrupt
+///
+/// PC and SP are the values before the interrupt, IM0 interrupts are
+/// recorded as a single interrupt acknowledge cycle.
+pub fn interrupt(cpu: &CPU, trace: &mut MCycleTrace, pc: RegT, sp: RegT, tstates: i64) {
+    let vec = trace.data;
+    let mut b = Builder { cpu, cycles: Vec::new() };
+    b.push(MCycleKind::IntAck, pc, vec, 0);
+    match cpu.reg.im() {
+        Im::One => b.push16(sp),
+        Im::Two => {
+            b.push16(sp);
+            let addr = (cpu.reg.i << 8 | vec) & 0xFFFE;
+            b.pop16(addr);
+        }
+        _ => (),
+    }
+    // the acknowledge cycle takes the remaining T states
+    let rest: i64 = b.cycles.iter().map(|c| c.tstates).sum();
+    b.cycles[0].tstates = tstates - rest;
+    let cycles = b.cycles;
+    complete(cpu, trace, cycles, tstates, 0);
+}
+
+//------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {CPU, NullBus};
+
+    fn trace(prog: &[u8]) -> (i64, Vec<String>) {
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0100, prog);
+        cpu.reg.set_pc(0x0100);
+        cpu.reg.set_sp(0x8000);
+        cpu.reg.set_hl(0x4000);
+        cpu.reg.set_ix(0x5000);
+        cpu.mem.w8(0x4000, 0x11);
+        cpu.mem.w8(0x5002, 0x22);
+        cpu.set_mcycle_trace(true);
+        let cycles = cpu.step(&NullBus);
+        (cycles, cpu.mcycles().iter().map(|c| c.to_string()).collect())
+    }
+
+    #[test]
+    fn instructions() {
+        // LD A,(HL)
+        assert_eq!(trace(&[0x7E]), (7, vec![
+            "M1   0100 7E 4T".to_string(),
+            "MR   4000 11 3T".to_string(),
+        ]));
+        // INC (IX+2)
+        assert_eq!(trace(&[0xDD, 0x34, 0x02]), (23, vec![
+            "M1   0100 DD 4T".to_string(),
+            "M1   0101 34 4T".to_string(),
+            "MR   0102 02 3T".to_string(),
+            "--   ---- -- 5T".to_string(),
+            "MR   5002 22 4T".to_string(),
+            "MW   5002 23 3T".to_string(),
+        ]));
+        // CALL 1234h
+        assert_eq!(trace(&[0xCD, 0x34, 0x12]), (17, vec![
+            "M1   0100 CD 4T".to_string(),
+            "MR   0101 34 3T".to_string(),
+            "MR   0102 12 4T".to_string(),
+            "MW   7FFF 01 3T".to_string(),
+            "MW   7FFE 03 3T".to_string(),
+        ]));
+        // SET 0,(IX+2)
+        assert_eq!(trace(&[0xDD, 0xCB, 0x02, 0xC6]), (23, vec![
+            "M1   0100 DD 4T".to_string(),
+            "M1   0101 CB 4T".to_string(),
+            "MR   0102 02 3T".to_string(),
+            "MR   0103 C6 5T".to_string(),
+            "MR   5002 22 4T".to_string(),
+            "MW   5002 23 3T".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn tstates_match_cycles() {
+        // the machine cycles must add up to the instruction's cycles
+        let mut prefixes: Vec<Vec<u8>> = vec![vec![], vec![0xCB], vec![0xED]];
+        for &p in &[0xDDu8, 0xFD] {
+            prefixes.push(vec![p]);
+            prefixes.push(vec![p, 0xCB, 0x01]);
+        }
+        for prefix in &prefixes {
+            for op in 0..256 {
+                for &flags in &[0x00, 0xFF] {
+                    let mut cpu = CPU::new_64k();
+                    let mut prog = prefix.clone();
+                    prog.extend_from_slice(&[op as u8, 0x01, 0x02]);
+                    cpu.mem.write(0x0100, &prog);
+                    cpu.reg.set_pc(0x0100);
+                    cpu.reg.set_sp(0x8000);
+                    cpu.reg.set_bc(0x0102);
+                    cpu.reg.set_f(flags);
+                    cpu.set_mcycle_trace(true);
+                    let cycles = cpu.step(&NullBus);
+                    let tstates: i64 = cpu.mcycles().iter().map(|c| c.tstates).sum();
+                    assert_eq!(tstates, cycles, "{:02X?} {:02X}", prog, flags);
+                    assert!(cpu.mcycles().iter().all(|c| c.tstates >= 3 || c.kind == MCycleKind::Internal),
+                            "{:02X?} {:02X}", prog, flags);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut cpu = CPU::new_64k();
+        cpu.reg.set_pc(0x0100);
+        cpu.reg.set_sp(0x8000);
+        cpu.set_interrupt_mode(Im::One);
+        cpu.set_iff(true, false);
+        cpu.set_mcycle_trace(true);
+        cpu.irq();
+        let cycles = cpu.step(&NullBus);
+        let tstates: i64 = cpu.mcycles().iter().map(|c| c.tstates).sum();
+        assert_eq!(tstates, cycles);
+        let kinds: Vec<MCycleKind> = cpu.mcycles().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, [MCycleKind::Fetch, MCycleKind::IntAck, MCycleKind::Write, MCycleKind::Write]);
+        assert_eq!(cpu.mcycles()[2].addr, 0x7FFF);
+        assert_eq!(cpu.mcycles()[2].data, 0x01);
+    }
+}
@@ -1,18 +1,50 @@
 use std::mem;
 use RegT;
+use rom::Crc32;
+use mapper::Mapper;
+use savestate::{SaveState, ChunkWriter, ChunkReader, StateError};
 
 const PAGE_SHIFT: usize = 10;   // 1 kByte page size = (1<<10)
 const PAGE_SIZE: usize = (1 << PAGE_SHIFT);
 const PAGE_MASK: usize = PAGE_SIZE - 1;
 const HEAP_SIZE: usize = 128 * PAGE_SIZE;
 const NUM_PAGES: usize = (1 << 16) / PAGE_SIZE;
-const NUM_LAYERS: usize = 4;
+const DEFAULT_LAYERS: usize = 4;
+
+/// page permission bit: reading doesn't fault
+pub const MEM_READ: u8 = 1 << 0;
+/// page permission bit: writes are executed
+pub const MEM_WRITE: u8 = 1 << 1;
+/// page permission bit: instruction fetches don't fault
+pub const MEM_EXEC: u8 = 1 << 2;
+
+/// the kind of memory access which caused a MemFault
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum MemAccess {
+    Read,
+    Write,
+    Exec,
+}
+
+/// a memory access which violated the page permissions
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct MemFault {
+    /// the 16-bit address
+    pub addr: RegT,
+    /// the kind of access
+    pub access: MemAccess,
+    /// false if no memory is mapped at the address
+    pub mapped: bool,
+}
 
 #[derive(Clone,Copy)]
 struct Page {
-    pub offset: usize, // offset into heap
+    pub offset: usize, // offset into heap (or into external data)
     pub writable: bool, // true if the page is writable
+    pub readable: bool, // false if reads should fault
+    pub executable: bool, // false if instruction fetches should fault
     pub mapped: bool, // true if currently mapped
+    pub ext: Option<&'static [u8]>, // external read-only data, None if mapped to heap
 }
 
 impl Page {
@@ -21,20 +53,38 @@ impl Page {
         Page {
             offset: 0,
             writable: false,
+            readable: false,
+            executable: false,
             mapped: false,
+            ext: None,
         }
     }
     /// map page to chunk of heap memory
     pub fn map(&mut self, offset: usize, writable: bool) {
         self.offset = offset;
         self.writable = writable;
+        self.readable = true;
+        self.executable = true;
+        self.mapped = true;
+        self.ext = None;
+    }
+    /// map page as read-only to chunk of external memory
+    pub fn map_ext(&mut self, data: &'static [u8], offset: usize) {
+        self.offset = offset;
+        self.writable = false;
+        self.readable = true;
+        self.executable = true;
         self.mapped = true;
+        self.ext = Some(data);
     }
     /// unmap page
     pub fn unmap(&mut self) {
         self.offset = 0;
         self.writable = false;
+        self.readable = false;
+        self.executable = false;
         self.mapped = false;
+        self.ext = None;
     }
 }
 
@@ -49,11 +99,12 @@ impl Page {
 ///
 /// ## Memory Layers
 ///
-/// Mapped memory is assigned to 1 out of (currently) 4 layers. If
-/// 2 memory chunks are mapped to the same CPU address range on
-/// different layers, only the memory assigned to the higher-priority
-/// layer is visible to the CPU (layer number 0 has the highest
-/// priority and layer number 3 the lowest).
+/// Mapped memory is assigned to 1 out of 4 layers (or more, see
+/// **with_layers()**). If 2 memory chunks are mapped to the same CPU
+/// address range on different layers, only the memory assigned to the
+/// higher-priority layer is visible to the CPU (by default layer number 0
+/// has the highest priority and layer number 3 the lowest, this can be
+/// changed at runtime with **set_layer_priority()**).
 ///
 /// The layer concept is easier to visualize than to describe:
 ///
@@ -74,15 +125,16 @@ impl Page {
 ///
 /// ## The Heap
 ///
-/// The Memory class will never keep references to external memory, instead it
-/// comes with it's own few hundred KBytes of embedded memory which is used
-/// as 'heap'. A single memory page maps 1 KByte of memory from the Z80
-/// address range to 1 KByte of memory somewhere on the embedded heap.
+/// The Memory class comes with it's own few hundred KBytes of embedded
+/// memory which is used as 'heap'. A single memory page maps 1 KByte of
+/// memory from the Z80 address range to 1 KByte of memory somewhere on
+/// the embedded heap. The only exception are read-only pages mapped
+/// with **map_slice()** which reference static data directly (see below).
 ///
 /// ## Mapping Memory
 ///
 /// This 'maps' a chunk of memory in Z80 address range to a chunk of memory
-/// of the same size in the embedded heap on one of the four memory layers.
+/// of the same size in the embedded heap on one of the memory layers.
 ///
 /// The simple form performs the memory mapping but does not copy
 /// any data into the mapped memory region:
@@ -113,6 +165,33 @@ impl Page {
 /// mem.map_bytes(0, 0x00000, 0xF000, false, &rom);
 /// ```
 ///
+/// ROM dumps which are embedded with the include_bytes! macro can also be mapped
+/// without copying them into the heap with **map_slice()**. This saves heap space and
+/// also works for ROMs which are bigger than the heap, for instance by mapping a
+/// different 16 KByte slice of a big ROM image into a CPU address window for each
+/// bank switch:
+///
+/// ```
+/// use rz80::Memory;
+/// static ROM: [u8; 0x10000] = [0xAA; 0x10000];
+/// let mut mem = Memory::new();
+///
+/// // map the 3rd 16 KByte bank of the ROM image as read-only to CPU addr 0x8000
+/// mem.map_slice(0, 0x8000, &ROM[0x8000..0xC000]);
+/// assert_eq!(mem.r8(0x8000), 0xAA);
+///
+/// // slice-mapped memory is never writable, not even with w8f()
+/// mem.w8f(0x8000, 0x55);
+/// assert_eq!(mem.r8(0x8000), 0xAA);
+/// ```
+///
+/// ## Cartridge Mappers
+///
+/// A cartridge **Mapper** can be attached with **set_mapper()**, the
+/// Memory object then forwards CPU writes to the mapper's trapped pages,
+/// and the mapper switches ROM and RAM banks on its own memory layer
+/// (see SegaMapper and CodemastersMapper).
+///
 /// ## Reading and Writing Memory
 ///
 /// The most common operations are reading and writing 8- and 16-bit unsigned values:
@@ -186,22 +265,70 @@ impl Page {
 ///
 /// ```
 ///
+/// For bulk copies which behave like the CPU's memory accesses (for
+/// instance DMA or snapshots), use read_slice() and write_slice().
+///
 pub struct Memory {
     /// currently CPU-visible pages
     pages: [Page; NUM_PAGES],
     /// currently mapped layers
-    layers: [[Page; NUM_PAGES]; NUM_LAYERS],
+    layers: Vec<[Page; NUM_PAGES]>,
+    /// layer indices from highest to lowest priority
+    priority: Vec<usize>,
     /// 'host' memory
     pub heap: [u8; HEAP_SIZE],
+    /// pages where writes are forwarded to the mapper
+    trap_mask: u64,
+    /// optional cartridge mapper
+    mapper: Option<Box<dyn Mapper>>,
+    /// bit mask of the 1-KByte pages written since the last clear_dirty_pages()
+    dirty_pages: u64,
+    /// per-page write counters
+    page_gen: [u32; NUM_PAGES],
+    /// memory mapping change counter
+    map_gen: u32,
+    /// called on page permission violations
+    fault_handler: Option<Box<dyn Fn(MemFault)>>,
+    /// true if a fault handler is set, checked first on the hot path
+    has_fault_handler: bool,
 }
 
 impl Memory {
     /// return new, unmapped memory object
     pub fn new() -> Memory {
+        Memory::with_layers(DEFAULT_LAYERS)
+    }
+
+    /// return new, unmapped memory object with a number of layers
+    ///
+    /// The layer count is a runtime value instead of a const generic
+    /// parameter, so that memory with any number of layers has the same
+    /// type and can be plugged into the CPU:
+    ///
+    /// ```
+    /// use rz80::{CPU, Memory};
+    ///
+    /// let mut cpu = CPU::new();
+    /// cpu.mem = Memory::with_layers(8);
+    /// cpu.mem.map(7, 0x0000, 0x0000, true, 0x10000);
+    /// cpu.mem.w8(0x1234, 0x56);
+    /// assert_eq!(cpu.mem.num_layers(), 8);
+    /// assert_eq!(cpu.mem.r8(0x1234), 0x56);
+    /// ```
+    pub fn with_layers(num_layers: usize) -> Memory {
+        assert!(num_layers > 0);
         Memory {
             pages: [Page::new(); NUM_PAGES],
-            layers: [[Page::new(); NUM_PAGES]; NUM_LAYERS],
+            layers: vec![[Page::new(); NUM_PAGES]; num_layers],
+            priority: (0..num_layers).collect(),
             heap: [0; HEAP_SIZE],
+            trap_mask: 0,
+            mapper: None,
+            dirty_pages: 0,
+            page_gen: [0; NUM_PAGES],
+            map_gen: 0,
+            fault_handler: None,
+            has_fault_handler: false,
         }
     }
 
@@ -246,6 +373,123 @@ impl Memory {
         dst.clone_from_slice(content);
     }
 
+    /// map external read-only data directly without copying it into the heap
+    pub fn map_slice(&mut self, layer: usize, addr: usize, data: &'static [u8]) {
+        assert_eq!((addr & PAGE_MASK), 0);
+        let size = data.len();
+        assert_eq!((size & PAGE_MASK), 0);
+        let num = size >> PAGE_SHIFT;
+        for i in 0..num {
+            let map_offset = i * PAGE_SIZE;
+            let page_index = ((addr + map_offset) & 0xFFFF) >> PAGE_SHIFT;
+            let page = &mut self.layers[layer][page_index];
+            page.map_ext(data, map_offset);
+        }
+        self.update_mapping();
+    }
+
+    /// set the read/write/execute permissions of mapped pages on a layer
+    ///
+    /// The permissions are a combination of MEM_READ, MEM_WRITE and
+    /// MEM_EXEC, mapping memory resets them (to all, or to read and
+    /// execute for read-only memory). A missing MEM_WRITE makes writes
+    /// ignored like for read-only memory (slice-mapped memory is never
+    /// writable), a missing MEM_READ or MEM_EXEC only calls the fault
+    /// handler, the CPU still sees the memory content.
+    pub fn set_permissions(&mut self, layer: usize, addr: usize, size: usize, perms: u8) {
+        assert_eq!((size & PAGE_MASK), 0);
+        assert_eq!((addr & PAGE_MASK), 0);
+        let num = size >> PAGE_SHIFT;
+        for i in 0..num {
+            let page_index = ((addr + i * PAGE_SIZE) & 0xFFFF) >> PAGE_SHIFT;
+            let page = &mut self.layers[layer][page_index];
+            if page.mapped {
+                page.readable = (perms & MEM_READ) != 0;
+                page.writable = (perms & MEM_WRITE) != 0 && page.ext.is_none();
+                page.executable = (perms & MEM_EXEC) != 0;
+            }
+        }
+        self.update_mapping();
+    }
+
+    /// get the permissions of the CPU-visible page at an address (0 if unmapped)
+    pub fn permissions(&self, addr: RegT) -> u8 {
+        let page = &self.pages[(addr as usize & 0xFFFF) >> PAGE_SHIFT];
+        (if page.readable { MEM_READ } else { 0 }) |
+        (if page.writable { MEM_WRITE } else { 0 }) |
+        (if page.executable { MEM_EXEC } else { 0 })
+    }
+
+    /// set a function which is called on memory accesses violating the page permissions
+    ///
+    /// Faults are reads from unmapped or non-readable memory, writes to
+    /// unmapped or read-only memory (except writes to pages trapped by a
+    /// cartridge mapper), and instruction fetches by the CPU from unmapped or
+    /// non-executable memory. Forced writes with w8f() and write() never
+    /// fault. This is meant for debugging guest software, e.g. to catch
+    /// writes to ROM or jumps into unmapped memory. Without a fault handler
+    /// the read and execute permissions aren't checked at all.
+    ///
+    /// ```
+    /// use std::rc::Rc;
+    /// use std::cell::RefCell;
+    /// use rz80::{Memory, MemFault, MemAccess, MEM_READ};
+    ///
+    /// let mut mem = Memory::new();
+    /// mem.map(0, 0x0000, 0x0000, false, 0x4000);  // ROM
+    /// mem.map(0, 0x4000, 0x4000, true, 0x4000);   // RAM
+    /// mem.set_permissions(0, 0x4000, 0x4000, MEM_READ);
+    ///
+    /// let faults = Rc::new(RefCell::new(Vec::new()));
+    /// let log = faults.clone();
+    /// mem.set_fault_handler(move |fault| log.borrow_mut().push(fault));
+    /// mem.w8(0x0100, 0x11);
+    /// mem.r8(0x8000);
+    /// mem.check_exec(0x4000);
+    /// assert_eq!(*faults.borrow(), [
+    ///     MemFault { addr: 0x0100, access: MemAccess::Write, mapped: true },
+    ///     MemFault { addr: 0x8000, access: MemAccess::Read, mapped: false },
+    ///     MemFault { addr: 0x4000, access: MemAccess::Exec, mapped: true },
+    /// ]);
+    /// ```
+    pub fn set_fault_handler<F>(&mut self, handler: F)
+        where F: Fn(MemFault) + 'static
+    {
+        self.fault_handler = Some(Box::new(handler));
+        self.has_fault_handler = true;
+    }
+
+    /// remove the fault handler
+    pub fn clear_fault_handler(&mut self) {
+        self.fault_handler = None;
+        self.has_fault_handler = false;
+    }
+
+    /// call the fault handler
+    #[cold]
+    #[inline(never)]
+    fn fault(&self, uaddr: usize, access: MemAccess) {
+        if let Some(ref handler) = self.fault_handler {
+            handler(MemFault {
+                addr: uaddr as RegT,
+                access,
+                mapped: self.pages[uaddr >> PAGE_SHIFT].mapped,
+            });
+        }
+    }
+
+    /// check an instruction fetch against the page permissions
+    ///
+    /// The CPU calls this for each opcode byte (except in JIT compiled
+    /// blocks), instruction bytes are read without checking MEM_READ.
+    #[inline(always)]
+    pub fn check_exec(&self, addr: RegT) {
+        let uaddr = (addr & 0xFFFF) as usize;
+        if self.has_fault_handler && !self.pages[uaddr >> PAGE_SHIFT].executable {
+            self.fault(uaddr, MemAccess::Exec);
+        }
+    }
+
     /// unmap a chunk heap memory
     pub fn unmap(&mut self, layer: usize, size: usize, addr: usize) {
         assert_eq!((size & PAGE_MASK), 0);
@@ -278,6 +522,31 @@ impl Memory {
         self.update_mapping();
     }
 
+    /// number of memory layers
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// set the layer priorities, a permutation of all layer indices from highest to lowest priority
+    ///
+    /// This allows to switch overlays like a shadow ROM or a video RAM
+    /// window on and off without remapping their pages.
+    pub fn set_layer_priority(&mut self, order: &[usize]) {
+        assert_eq!(order.len(), self.layers.len());
+        let mut seen = vec![false; order.len()];
+        for &layer in order {
+            assert!(layer < seen.len() && !seen[layer], "layer priority must be a permutation");
+            seen[layer] = true;
+        }
+        self.priority = order.to_vec();
+        self.update_mapping();
+    }
+
+    /// get the layer indices from highest to lowest priority
+    pub fn layer_priority(&self) -> &[usize] {
+        &self.priority
+    }
+
     /// private method to update internal CPU-visible mapping from mapped layers
     fn update_mapping(&mut self) {
         // for each cpu-visible page, find the highest-priority layer
@@ -285,7 +554,7 @@ impl Memory {
         // cpu-visible page
         for page_index in 0..NUM_PAGES {
             let mut layer_page: Option<&Page> = None;
-            for layer_index in 0..NUM_LAYERS {
+            for &layer_index in &self.priority {
                 if self.layers[layer_index][page_index].mapped {
                     layer_page = Some(&self.layers[layer_index][page_index]);
                     break;
@@ -296,6 +565,17 @@ impl Memory {
                 None => self.pages[page_index].unmap(),
             }
         }
+        self.map_gen = self.map_gen.wrapping_add(1);
+    }
+
+    /// private method to read a byte from a mapped page
+    #[inline(always)]
+    fn page_byte(&self, page: &Page, uaddr: usize) -> u8 {
+        let offset = page.offset + (uaddr & PAGE_MASK);
+        match page.ext {
+            Some(data) => data[offset],
+            None => self.heap[offset],
+        }
     }
 
     /// read unsigned byte from 16-bit address
@@ -303,22 +583,59 @@ impl Memory {
     pub fn r8(&self, addr: RegT) -> RegT {
         let uaddr = (addr & 0xFFFF) as usize;
         let page = &self.pages[uaddr >> PAGE_SHIFT];
+        if self.has_fault_handler && !page.readable {
+            self.fault(uaddr, MemAccess::Read);
+        }
         if page.mapped {
-            let heap_offset = page.offset + (uaddr & PAGE_MASK);
-            self.heap[heap_offset] as RegT
+            self.page_byte(page, uaddr) as RegT
+        } else {
+            0xFF
+        }
+    }
+
+    /// read unsigned byte without checking the read permission (for instruction fetches)
+    #[inline(always)]
+    pub(crate) fn peek8(&self, addr: RegT) -> RegT {
+        let uaddr = (addr & 0xFFFF) as usize;
+        let page = &self.pages[uaddr >> PAGE_SHIFT];
+        if page.mapped {
+            self.page_byte(page, uaddr) as RegT
         } else {
             0xFF
         }
     }
 
+    /// read the 4 bytes of the longest instruction without checking the read permission
+    #[inline(always)]
+    pub(crate) fn peek_inst(&self, addr: RegT) -> [u8; 4] {
+        let uaddr = (addr & 0xFFFF) as usize;
+        let page = &self.pages[uaddr >> PAGE_SHIFT];
+        if page.mapped && (uaddr & PAGE_MASK) <= PAGE_SIZE - 4 {
+            // fast path, all bytes are in the same page
+            let offset = page.offset + (uaddr & PAGE_MASK);
+            let bytes = match page.ext {
+                Some(data) => &data[offset..offset + 4],
+                None => &self.heap[offset..offset + 4],
+            };
+            [bytes[0], bytes[1], bytes[2], bytes[3]]
+        } else {
+            [self.peek8(addr) as u8,
+             self.peek8(addr + 1) as u8,
+             self.peek8(addr + 2) as u8,
+             self.peek8(addr + 3) as u8]
+        }
+    }
+
     /// read signed byte from 16-bit address
     #[inline(always)]
     pub fn rs8(&self, addr: RegT) -> RegT {
         let uaddr = (addr & 0xFFFF) as usize;
         let page = &self.pages[uaddr >> PAGE_SHIFT];
+        if self.has_fault_handler && !page.readable {
+            self.fault(uaddr, MemAccess::Read);
+        }
         if page.mapped {
-            let heap_offset = page.offset + (uaddr & PAGE_MASK);
-            self.heap[heap_offset] as i8 as RegT
+            self.page_byte(page, uaddr) as i8 as RegT
         } else {
             0xFF
         }
@@ -328,20 +645,52 @@ impl Memory {
     #[inline(always)]
     pub fn w8(&mut self, addr: RegT, val: RegT) {
         let uaddr = (addr & 0xFFFF) as usize;
-        let page = &self.pages[uaddr >> PAGE_SHIFT];
+        let page_index = uaddr >> PAGE_SHIFT;
+        let page = &self.pages[page_index];
         if page.mapped && page.writable {
             let heap_offset = page.offset + (uaddr & PAGE_MASK);
             self.heap[heap_offset] = val as u8;
+            self.dirty_pages |= 1 << page_index;
+            self.page_gen[page_index] = self.page_gen[page_index].wrapping_add(1);
+        } else if self.has_fault_handler && (self.trap_mask >> page_index) & 1 == 0 {
+            self.fault(uaddr, MemAccess::Write);
+        }
+        if (self.trap_mask >> page_index) & 1 != 0 {
+            self.trap_write(uaddr as RegT, val);
+        }
+    }
+
+    /// forward a write to a trapped page to the mapper
+    fn trap_write(&mut self, addr: RegT, val: RegT) {
+        if let Some(mut mapper) = self.mapper.take() {
+            mapper.write(self, addr, val);
+            self.mapper = Some(mapper);
         }
     }
 
+    /// attach a cartridge mapper, this also maps the mapper's initial banks
+    pub fn set_mapper(&mut self, mut mapper: Box<dyn Mapper>) {
+        mapper.init(self);
+        self.trap_mask = mapper.trap_mask();
+        self.mapper = Some(mapper);
+    }
+
+    /// detach the cartridge mapper (the current memory mapping is not changed)
+    pub fn remove_mapper(&mut self) -> Option<Box<dyn Mapper>> {
+        self.trap_mask = 0;
+        self.mapper.take()
+    }
+
     /// write unsigned byte, ignore write-protection flag
     pub fn w8f(&mut self, addr: RegT, val: RegT) {
         let uaddr = (addr & 0xFFFF) as usize;
-        let page = &self.pages[uaddr >> PAGE_SHIFT];
-        if page.mapped {
+        let page_index = uaddr >> PAGE_SHIFT;
+        let page = &self.pages[page_index];
+        if page.mapped && page.ext.is_none() {
             let heap_offset = page.offset + (uaddr & PAGE_MASK);
             self.heap[heap_offset] = val as u8;
+            self.dirty_pages |= 1 << page_index;
+            self.page_gen[page_index] = self.page_gen[page_index].wrapping_add(1);
         }
     }
 
@@ -363,13 +712,268 @@ impl Memory {
     }
 
     /// write a whole chunk of memory, ignore write-protection
+    ///
+    /// Pages mapped to external read-only data and unmapped pages are
+    /// skipped, the address wraps around at 64k.
     pub fn write(&mut self, addr: RegT, data: &[u8]) {
-        let mut offset = 0;
-        for b in data {
-            self.w8f(addr + offset, *b as RegT);
-            offset += 1;
+        let mut uaddr = (addr & 0xFFFF) as usize;
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = chunk_len(uaddr, data.len() - pos);
+            let page_index = uaddr >> PAGE_SHIFT;
+            let page = self.pages[page_index];
+            if page.mapped && page.ext.is_none() {
+                self.write_chunk(page_index, uaddr, &data[pos..pos + len]);
+            }
+            pos += len;
+            uaddr = (uaddr + len) & 0xFFFF;
         }
     }
+
+    /// copy a chunk of CPU-visible memory into a buffer
+    ///
+    /// This walks the page table instead of reading byte by byte,
+    /// unmapped memory reads as 0xFF, the address wraps around at 64k.
+    /// Read permissions are not checked, so this is also useful
+    /// for debuggers and snapshots.
+    pub fn read_slice(&self, addr: RegT, buf: &mut [u8]) {
+        let mut uaddr = (addr & 0xFFFF) as usize;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let len = chunk_len(uaddr, buf.len() - pos);
+            let page = &self.pages[uaddr >> PAGE_SHIFT];
+            let dst = &mut buf[pos..pos + len];
+            if page.mapped {
+                let offset = page.offset + (uaddr & PAGE_MASK);
+                match page.ext {
+                    Some(data) => dst.copy_from_slice(&data[offset..offset + len]),
+                    None => dst.copy_from_slice(&self.heap[offset..offset + len]),
+                }
+            } else {
+                for b in dst.iter_mut() {
+                    *b = 0xFF;
+                }
+            }
+            pos += len;
+            uaddr = (uaddr + len) & 0xFFFF;
+        }
+    }
+
+    /// write a chunk of memory, honoring write-protection
+    ///
+    /// This behaves like calling w8() for each byte (writes to read-only
+    /// pages are ignored or reported to the fault handler, writes to
+    /// pages trapped by a mapper are forwarded to the mapper), but
+    /// copies whole pages where possible. The address wraps around at 64k.
+    pub fn write_slice(&mut self, addr: RegT, data: &[u8]) {
+        let mut uaddr = (addr & 0xFFFF) as usize;
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = chunk_len(uaddr, data.len() - pos);
+            let page_index = uaddr >> PAGE_SHIFT;
+            let page = self.pages[page_index];
+            let trapped = (self.trap_mask >> page_index) & 1 != 0;
+            if page.mapped && page.writable && !trapped {
+                self.write_chunk(page_index, uaddr, &data[pos..pos + len]);
+            } else {
+                for (i, b) in data[pos..pos + len].iter().enumerate() {
+                    self.w8((uaddr + i) as RegT, *b as RegT);
+                }
+            }
+            pos += len;
+            uaddr = (uaddr + len) & 0xFFFF;
+        }
+    }
+
+    /// fill a chunk of memory with a byte value
+    ///
+    /// With force, write-protection is ignored like in write(), otherwise
+    /// it is honored like in write_slice(). The address wraps around at 64k.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rz80::Memory;
+    ///
+    /// let mut mem = Memory::new_64k();
+    /// mem.fill(0x4000, 0x1000, 0xFF, false);
+    /// assert_eq!(mem.r8(0x3FFF), 0x00);
+    /// assert_eq!(mem.r8(0x4000), 0xFF);
+    /// assert_eq!(mem.r8(0x4FFF), 0xFF);
+    /// assert_eq!(mem.r8(0x5000), 0x00);
+    /// ```
+    pub fn fill(&mut self, addr: RegT, len: usize, val: u8, force: bool) {
+        self.fill_pattern(addr, len, &[val], force);
+    }
+
+    /// fill a chunk of memory with a repeated byte pattern
+    ///
+    /// The pattern starts at addr, so the byte at addr+i is
+    /// pattern[i % pattern.len()]. This is useful for the power-on
+    /// content of RAM chips (for instance alternating blocks of 00h
+    /// and FFh). Write-protection is handled like in fill().
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rz80::Memory;
+    ///
+    /// let mut mem = Memory::new_64k();
+    /// // 64 bytes 00h, 64 bytes FFh, ...
+    /// let mut pattern = vec![0x00; 64];
+    /// pattern.extend_from_slice(&[0xFF; 64]);
+    /// mem.fill_pattern(0x0000, 0x10000, &pattern, false);
+    /// assert_eq!(mem.r8(0x003F), 0x00);
+    /// assert_eq!(mem.r8(0x0040), 0xFF);
+    /// assert_eq!(mem.r8(0xFFFF), 0xFF);
+    /// ```
+    pub fn fill_pattern(&mut self, addr: RegT, len: usize, pattern: &[u8], force: bool) {
+        if pattern.is_empty() {
+            return;
+        }
+        let mut uaddr = (addr & 0xFFFF) as usize;
+        let mut pos = 0;
+        while pos < len {
+            let chunk = chunk_len(uaddr, len - pos);
+            let page_index = uaddr >> PAGE_SHIFT;
+            let page = self.pages[page_index];
+            let trapped = (self.trap_mask >> page_index) & 1 != 0;
+            if page.mapped && page.ext.is_none() && (force || (page.writable && !trapped)) {
+                self.fill_chunk(page_index, uaddr, chunk, pattern, pos);
+            } else if !force {
+                for i in 0..chunk {
+                    self.w8((uaddr + i) as RegT, pattern[(pos + i) % pattern.len()] as RegT);
+                }
+            }
+            pos += chunk;
+            uaddr = (uaddr + chunk) & 0xFFFF;
+        }
+    }
+
+    /// private method to fill a chunk of a heap-mapped page with a pattern
+    fn fill_chunk(&mut self, page_index: usize, uaddr: usize, len: usize, pattern: &[u8], phase: usize) {
+        let offset = self.pages[page_index].offset + (uaddr & PAGE_MASK);
+        for (i, b) in self.heap[offset..offset + len].iter_mut().enumerate() {
+            *b = pattern[(phase + i) % pattern.len()];
+        }
+        self.dirty_pages |= 1 << page_index;
+        self.page_gen[page_index] = self.page_gen[page_index].wrapping_add(1);
+    }
+
+    /// private method to copy data into a heap-mapped page
+    fn write_chunk(&mut self, page_index: usize, uaddr: usize, data: &[u8]) {
+        let offset = self.pages[page_index].offset + (uaddr & PAGE_MASK);
+        self.heap[offset..offset + data.len()].copy_from_slice(data);
+        self.dirty_pages |= 1 << page_index;
+        self.page_gen[page_index] = self.page_gen[page_index].wrapping_add(1);
+    }
+
+    /// bit mask of the 1-KByte pages written since the last clear_dirty_pages()
+    ///
+    /// Bit n is set after a write to the CPU-visible address range
+    /// n*1024..(n+1)*1024 through w8(), w8f(), w16(), write(),
+    /// write_slice() or fill(). Writes
+    /// which are ignored because of write protection don't set the bit.
+    pub fn dirty_pages(&self) -> u64 {
+        self.dirty_pages
+    }
+
+    /// clear the dirty-page bit mask
+    pub fn clear_dirty_pages(&mut self) {
+        self.dirty_pages = 0;
+    }
+
+    /// return the dirty-page bit mask and clear it
+    ///
+    /// Video decoders call this once per frame and only decode the
+    /// video memory if one of its pages has been written since the last
+    /// frame (use page_mask() to build the bit mask of the video memory).
+    pub fn take_dirty_pages(&mut self) -> u64 {
+        let dirty = self.dirty_pages;
+        self.dirty_pages = 0;
+        dirty
+    }
+
+    /// return the page bit mask of an address range (wraps around at 64k)
+    pub fn page_mask(addr: usize, size: usize) -> u64 {
+        let mut mask = 0;
+        if size > 0 {
+            let first = (addr & 0xFFFF) >> PAGE_SHIFT;
+            let num = ((addr & PAGE_MASK) + size + PAGE_MASK) >> PAGE_SHIFT;
+            for i in 0..num.min(NUM_PAGES) {
+                mask |= 1 << ((first + i) & (NUM_PAGES - 1));
+            }
+        }
+        mask
+    }
+
+    /// return the generation counter of the 1-KByte page containing addr
+    ///
+    /// The generation changes whenever the page is written through w8(),
+    /// w8f(), w16(), write() or fill(), or the memory mapping changes, so code
+    /// caches can check whether decoded instructions are still valid.
+    pub fn page_generation(&self, addr: RegT) -> u32 {
+        let page_index = ((addr & 0xFFFF) as usize) >> PAGE_SHIFT;
+        self.page_gen[page_index].wrapping_add(self.map_gen)
+    }
+
+    /// return the generation counter of the memory mapping
+    pub fn map_generation(&self) -> u32 {
+        self.map_gen
+    }
+
+    /// compare the CPU-visible memory with another Memory object
+    ///
+    /// Returns the address, the value in other, and the value in self
+    /// for each byte that differs, so other is usually an older snapshot.
+    pub fn diff(&self, other: &Memory) -> Vec<(RegT, RegT, RegT)> {
+        let mut res = Vec::new();
+        for addr in 0..(1 << 16) {
+            let old = other.peek8(addr);
+            let new = self.peek8(addr);
+            if old != new {
+                res.push((addr, old, new));
+            }
+        }
+        res
+    }
+
+    /// compute CRC32 checksum of a CPU-visible memory range (wraps around at 64k)
+    pub fn crc32(&self, addr: RegT, len: usize) -> u32 {
+        let mut crc = Crc32::new();
+        for i in 0..len {
+            crc.update(self.peek8(addr + i as RegT) as u8);
+        }
+        crc.finish()
+    }
+}
+
+impl SaveState for Memory {
+    const STATE_VERSION: u16 = 1;
+
+    /// the heap content, not the memory mapping
+    fn save_state(&self, w: &mut ChunkWriter) {
+        w.bytes(&self.heap);
+    }
+
+    fn load_state(&mut self, r: &mut ChunkReader) -> Result<(), StateError> {
+        let heap = r.bytes()?;
+        if heap.len() != HEAP_SIZE {
+            return Err(r.invalid());
+        }
+        self.heap.copy_from_slice(heap);
+        // all pages have changed
+        self.dirty_pages = !0;
+        for gen in self.page_gen.iter_mut() {
+            *gen = gen.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+/// number of bytes from uaddr to the end of its page, at most len
+fn chunk_len(uaddr: usize, len: usize) -> usize {
+    (PAGE_SIZE - (uaddr & PAGE_MASK)).min(len)
 }
 
 #[cfg(test)]
@@ -456,4 +1060,269 @@ mod tests {
         assert_eq!(mem.r8(0x8000), 0x33);
         assert_eq!(mem.r8(0xC000), 0x33);
     }
+
+    #[test]
+    fn mem_map_slice() {
+        static ROM: [u8; 0x2000] = [0x11; 0x2000];
+        let mut mem = Memory::new();
+        mem.map(1, 0x00000, 0x0000, true, 0x10000);
+        mem.w8(0x4000, 0x22);
+        mem.map_slice(0, 0x3C00, &ROM[0x1000..0x1800]);
+        assert_eq!(mem.r8(0x3BFF), 0x00);
+        assert_eq!(mem.r8(0x3C00), 0x11);
+        assert_eq!(mem.r8(0x43FF), 0x11);
+        assert_eq!(mem.r8(0x4400), 0x00);
+        assert_eq!(mem.rs8(0x4000), 0x11);
+        mem.w8(0x4000, 0x33);
+        mem.w8f(0x4001, 0x33);
+        assert_eq!(mem.r8(0x4000), 0x11);
+        assert_eq!(mem.r8(0x4001), 0x11);
+
+        // underlying heap memory is visible again after unmapping
+        mem.unmap(0, 0x0800, 0x3C00);
+        assert_eq!(mem.r8(0x4000), 0x22);
+    }
+
+    #[test]
+    fn mem_dirty_pages() {
+        let mut mem = Memory::new();
+        let rom = [0x11u8; 0x400];
+        mem.map(0, 0x0000, 0x0000, true, 0x4000);
+        mem.map_bytes(0, 0x4000, 0x4000, false, &rom);
+        assert_eq!(mem.dirty_pages(), 0);
+        mem.w8(0x0000, 1);
+        mem.w16(0x07FF, 0x1234);
+        mem.write(0x3C00, &[1, 2, 3]);
+        assert_eq!(mem.dirty_pages(), (1 << 0) | (1 << 1) | (1 << 2) | (1 << 15));
+        // writes to read-only memory are ignored, unless forced
+        mem.clear_dirty_pages();
+        mem.w8(0x4000, 0x33);
+        assert_eq!(mem.dirty_pages(), 0);
+        mem.w8f(0x4000, 0x33);
+        assert_eq!(mem.dirty_pages(), 1 << 16);
+        assert_eq!(mem.take_dirty_pages(), 1 << 16);
+        assert_eq!(mem.dirty_pages(), 0);
+    }
+
+    #[test]
+    fn mem_page_mask() {
+        assert_eq!(Memory::page_mask(0x0000, 0), 0);
+        assert_eq!(Memory::page_mask(0x0000, 1), 1);
+        assert_eq!(Memory::page_mask(0xEC00, 0x400), 1 << 59);
+        assert_eq!(Memory::page_mask(0xE800, 0x800), (1 << 58) | (1 << 59));
+        assert_eq!(Memory::page_mask(0x03FF, 2), 3);
+        assert_eq!(Memory::page_mask(0xFC00, 0x800), (1 << 63) | 1);
+        assert_eq!(Memory::page_mask(0x1234, 0x10000), !0);
+
+        // only decode video memory when it has changed
+        let mut mem = Memory::new_64k();
+        let vid_mask = Memory::page_mask(0xEC00, 0x400);
+        mem.w8(0x1000, 1);
+        assert_eq!(mem.take_dirty_pages() & vid_mask, 0);
+        mem.w8(0xEFFF, 1);
+        assert_ne!(mem.take_dirty_pages() & vid_mask, 0);
+        assert_eq!(mem.take_dirty_pages() & vid_mask, 0);
+    }
+
+    #[test]
+    fn mem_page_generation() {
+        let mut mem = Memory::new_64k();
+        let gen0 = mem.page_generation(0x0000);
+        let gen1 = mem.page_generation(0x0400);
+        mem.w8(0x03FF, 1);
+        assert_ne!(mem.page_generation(0x0000), gen0);
+        assert_eq!(mem.page_generation(0x0400), gen1);
+        // remapping changes the generation of all pages
+        let map_gen = mem.map_generation();
+        mem.map(1, 0x10000, 0x8000, true, 0x0400);
+        assert_ne!(mem.map_generation(), map_gen);
+        assert_ne!(mem.page_generation(0x0400), gen1);
+    }
+
+    #[test]
+    fn mem_diff() {
+        let mut old = Memory::new_64k();
+        let mut new = Memory::new_64k();
+        assert!(new.diff(&old).is_empty());
+        old.w8(0x1234, 0x56);
+        new.w8(0x1234, 0x56);
+        new.w8(0x0100, 0x11);
+        new.w16(0xFFFF, 0x2233);
+        assert_eq!(new.diff(&old),
+                   vec![(0x0000, 0x00, 0x22), (0x0100, 0x00, 0x11), (0xFFFF, 0x00, 0x33)]);
+        assert_eq!(old.diff(&new),
+                   vec![(0x0000, 0x22, 0x00), (0x0100, 0x11, 0x00), (0xFFFF, 0x33, 0x00)]);
+    }
+
+    #[test]
+    fn mem_permissions() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        let mut mem = Memory::new();
+        mem.map(1, 0x0000, 0x0000, false, 0x2000);
+        mem.map(1, 0x2000, 0x2000, true, 0x2000);
+        mem.set_permissions(1, 0x0000, 0x2000, MEM_EXEC);
+        mem.set_permissions(1, 0x2000, 0x0400, MEM_READ | MEM_EXEC);
+        assert_eq!(mem.permissions(0x0000), MEM_EXEC);
+        assert_eq!(mem.permissions(0x2000), MEM_READ | MEM_EXEC);
+        assert_eq!(mem.permissions(0x2400), MEM_READ | MEM_WRITE | MEM_EXEC);
+        assert_eq!(mem.permissions(0x4000), 0);
+        // without a fault handler, only the write permission has an effect
+        mem.w8(0x2000, 0x11);
+        assert_eq!(mem.r8(0x2000), 0x00);
+
+        let faults = Rc::new(RefCell::new(Vec::new()));
+        let log = faults.clone();
+        mem.set_fault_handler(move |fault| log.borrow_mut().push((fault.addr, fault.access)));
+        assert_eq!(mem.r8(0x0010), 0x00);
+        mem.w8(0x2400, 0x22);
+        mem.check_exec(0x2400);
+        mem.w8f(0x0010, 0x33);
+        assert_eq!(mem.peek8(0x0010), 0x33);
+        mem.w16(0x23FF, 0x4455);
+        mem.check_exec(0x4000);
+        assert_eq!(*faults.borrow(),
+                   [(0x0010, MemAccess::Read), (0x23FF, MemAccess::Write),
+                    (0x4000, MemAccess::Exec)]);
+        assert_eq!(mem.r16(0x23FF), 0x4400);
+
+        // a higher-priority layer has its own permissions, remapping resets them
+        faults.borrow_mut().clear();
+        mem.map(0, 0x8000, 0x0000, true, 0x0400);
+        mem.r8(0x0000);
+        mem.w8(0x0000, 0x11);
+        mem.unmap_layer(0);
+        mem.map(1, 0x0000, 0x0000, false, 0x0400);
+        assert_eq!(mem.permissions(0x0000), MEM_READ | MEM_EXEC);
+        mem.clear_fault_handler();
+        mem.r8(0x4000);
+        assert!(faults.borrow().is_empty());
+    }
+
+    #[test]
+    fn mem_layer_priority() {
+        let mut mem = Memory::with_layers(6);
+        assert_eq!(mem.num_layers(), 6);
+        assert_eq!(mem.layer_priority(), &[0, 1, 2, 3, 4, 5]);
+        // RAM on the lowest layer, a ROM and a video RAM window above
+        mem.map(5, 0x00000, 0x0000, true, 0x10000);
+        mem.map(4, 0x10000, 0x0000, false, 0x0400);
+        mem.map(2, 0x10400, 0x4000, true, 0x0400);
+        mem.heap[0x10000] = 0x11;
+        mem.w8(0x4000, 0x22);
+        assert_eq!(mem.r8(0x0000), 0x11);
+        assert_eq!(mem.heap[0x10400], 0x22);
+        // move the RAM layer above the ROM (shadow RAM)
+        mem.set_layer_priority(&[0, 1, 2, 3, 5, 4]);
+        assert_eq!(mem.r8(0x0000), 0x00);
+        mem.w8(0x0000, 0x33);
+        assert_eq!(mem.r8(0x0000), 0x33);
+        assert_eq!(mem.r8(0x4000), 0x22);
+        // and to the top, this hides the video RAM window
+        mem.set_layer_priority(&[5, 0, 1, 2, 3, 4]);
+        assert_eq!(mem.r8(0x4000), 0x00);
+        assert_eq!(mem.heap[0x10000], 0x11);
+    }
+
+    #[test]
+    #[should_panic]
+    fn mem_layer_priority_permutation() {
+        let mut mem = Memory::new();
+        mem.set_layer_priority(&[0, 1, 1, 3]);
+    }
+
+    #[test]
+    fn mem_slices() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        static ROM: [u8; 0x400] = [0xAA; 0x400];
+        let mut mem = Memory::new();
+        mem.map_slice(0, 0x0000, &ROM);
+        mem.map(0, 0x0000, 0x0400, false, 0x0400);
+        mem.map(0, 0x0400, 0x0800, true, 0x0400);
+        mem.map(0, 0x0C00, 0xFC00, true, 0x0400);
+
+        // write() ignores write protection, but can't write external data
+        let data: Vec<u8> = (0..0x0C00).map(|i| i as u8).collect();
+        mem.write(0x0300, &data[..0x0600]);
+        assert_eq!(mem.r8(0x03FF), 0xAA);
+        assert_eq!(mem.r8(0x0400), 0x00);
+        assert_eq!(mem.r8(0x07FF), 0xFF);
+        assert_eq!(mem.r8(0x0800), 0x00);
+        assert_eq!(mem.dirty_pages(), 0b0110);
+
+        // write_slice() honors write protection and wraps around
+        let faults = Rc::new(RefCell::new(Vec::new()));
+        let log = faults.clone();
+        mem.set_fault_handler(move |fault| log.borrow_mut().push(fault.addr));
+        mem.clear_dirty_pages();
+        mem.write_slice(0xFFFE, &[1, 2, 3]);
+        mem.write_slice(0x0BFF, &[4, 5]);
+        assert_eq!(mem.r16(0xFFFE), 0x0201);
+        assert_eq!(mem.r8(0x0000), 0xAA);
+        assert_eq!(mem.r8(0x0BFF), 4);
+        assert_eq!(*faults.borrow(), [0x0000, 0x0C00]);
+        assert_eq!(mem.dirty_pages(), (1 << 63) | (1 << 2));
+
+        // read_slice() reads unmapped memory as 0xFF and doesn't fault
+        faults.borrow_mut().clear();
+        let mut buf = [0u8; 0x1000];
+        mem.read_slice(0xFFFF, &mut buf);
+        assert_eq!(buf[0], 2);
+        assert!(buf[1..0x401].iter().all(|&b| b == 0xAA));
+        assert_eq!(buf[0x401], 0x00);
+        assert_eq!(buf[0x0C00], 4);
+        assert_eq!(buf[0x0C01], 0xFF);
+        assert!(faults.borrow().is_empty());
+        let mut bytes = [0u8; 0x0C00];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = mem.r8(0xFFFF + i as RegT) as u8;
+        }
+        assert_eq!(&buf[..0x0C00], &bytes[..]);
+    }
+
+    #[test]
+    fn mem_fill() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        static ROM: [u8; 0x400] = [0xAA; 0x400];
+        let mut mem = Memory::new();
+        mem.map_slice(0, 0x0000, &ROM);
+        mem.map(0, 0x0000, 0x0400, false, 0x0400);
+        mem.map(0, 0x0400, 0x0800, true, 0x0400);
+        mem.map(0, 0x0C00, 0xFC00, true, 0x0400);
+        let faults = Rc::new(RefCell::new(Vec::new()));
+        let log = faults.clone();
+        mem.set_fault_handler(move |fault| log.borrow_mut().push(fault.addr));
+
+        // force ignores write protection, but can't write external data
+        mem.fill(0x03FE, 0x0404, 0x11, true);
+        assert_eq!(mem.r8(0x03FF), 0xAA);
+        assert_eq!(mem.r8(0x0400), 0x11);
+        assert_eq!(mem.r8(0x0801), 0x11);
+        assert_eq!(mem.r8(0x0802), 0x00);
+        assert_eq!(mem.dirty_pages(), 0b0110);
+        assert!(faults.borrow().is_empty());
+
+        // without force, protected writes fault like w8()
+        mem.clear_dirty_pages();
+        mem.fill(0x07FE, 4, 0x22, false);
+        assert_eq!(mem.r16(0x07FE), 0x1111);
+        assert_eq!(mem.r16(0x0800), 0x2222);
+        assert_eq!(*faults.borrow(), [0x07FE, 0x07FF]);
+        assert_eq!(mem.dirty_pages(), 0b0100);
+
+        // the pattern continues across pages and wraps around at 64k
+        faults.borrow_mut().clear();
+        mem.fill_pattern(0xFFFE, 0x0C04, &[1, 2, 3], false);
+        assert_eq!(mem.r16(0xFFFE), 0x0201);
+        assert_eq!(mem.r8(0x0000), 0xAA);
+        assert_eq!(mem.r8(0x0400), 0x11);
+        assert_eq!(faults.borrow().len(), 0x0802);
+        let mut buf = [0u8; 0x0400];
+        mem.read_slice(0x0800, &mut buf);
+        for (i, &b) in buf.iter().enumerate() {
+            assert_eq!(b, [1, 2, 3][(i + 0x0802) % 3]);
+        }
+    }
 }
@@ -0,0 +1,236 @@
+use memory::Memory;
+use bus::ResetKind;
+
+/// a pluggable ROM module (or cartridge)
+///
+/// The ROM is mapped without copying (see Memory::map_slice()), so
+/// it must be 'static, and its size a multiple of 1 KByte.
+#[derive(Clone,Debug)]
+pub struct RomModule {
+    pub name: String,
+    pub data: &'static [u8],
+    /// the start address in the CPU address space
+    pub addr: usize,
+}
+
+impl RomModule {
+    /// create a ROM module which maps at addr
+    pub fn new(name: &str, data: &'static [u8], addr: usize) -> RomModule {
+        RomModule {
+            name: name.to_string(),
+            data,
+            addr,
+        }
+    }
+}
+
+/// a module slot which allows to insert and remove ROM modules at runtime
+///
+/// The slot maps the ROM of the inserted module on its own memory
+/// layer, so that removing the module uncovers the memory below it
+/// again. The page table is updated immediately, the block cache and
+/// JIT notice the changed mapping through the memory generation
+/// counters, so a module can be swapped between two steps.
+///
+/// A module can also be switched off without removing it (like the
+/// control byte of a KC85 module), and moved to another address.
+///
+/// Real machines differ in what happens when a module is inserted or
+/// removed while running, so the slot only records a reset request
+/// (see set_reset()), which the front-end forwards to the system
+/// with CPU::system_reset().
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Memory, ModuleSlot, RomModule, ResetKind};
+/// static BASIC: [u8; 0x2000] = [0xAA; 0x2000];
+///
+/// let mut mem = Memory::new();
+/// mem.map(1, 0x00000, 0x0000, true, 0x10000);
+/// let mut slot = ModuleSlot::new(0);
+/// slot.set_reset(Some(ResetKind::Warm));
+///
+/// assert!(slot.insert(&mut mem, RomModule::new("BASIC", &BASIC, 0xC000)).is_none());
+/// assert_eq!(mem.r8(0xC000), 0xAA);
+/// assert_eq!(slot.take_reset(), Some(ResetKind::Warm));
+/// assert_eq!(slot.take_reset(), None);
+///
+/// // switching the module off uncovers the RAM
+/// slot.set_active(&mut mem, false);
+/// assert_eq!(mem.r8(0xC000), 0x00);
+/// slot.set_active(&mut mem, true);
+/// assert_eq!(mem.r8(0xC000), 0xAA);
+///
+/// let module = slot.remove(&mut mem).unwrap();
+/// assert_eq!(module.name, "BASIC");
+/// assert_eq!(mem.r8(0xC000), 0x00);
+/// ```
+pub struct ModuleSlot {
+    layer: usize,
+    module: Option<RomModule>,
+    active: bool,
+    reset: Option<ResetKind>,
+    reset_pending: Option<ResetKind>,
+}
+
+impl ModuleSlot {
+    /// create an empty slot which maps modules on a memory layer
+    pub fn new(layer: usize) -> ModuleSlot {
+        ModuleSlot {
+            layer,
+            module: None,
+            active: true,
+            reset: None,
+            reset_pending: None,
+        }
+    }
+
+    /// the memory layer of the slot
+    pub fn layer(&self) -> usize {
+        self.layer
+    }
+
+    /// the inserted module, if any
+    pub fn module(&self) -> Option<&RomModule> {
+        self.module.as_ref()
+    }
+
+    /// true if the inserted module is switched on (also true for an empty slot)
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// set the reset which is requested when a module is inserted or removed
+    pub fn set_reset(&mut self, reset: Option<ResetKind>) {
+        self.reset = reset;
+    }
+
+    /// return the requested reset and clear the request
+    pub fn take_reset(&mut self) -> Option<ResetKind> {
+        self.reset_pending.take()
+    }
+
+    /// insert a module, replacing and returning the inserted one
+    pub fn insert(&mut self, mem: &mut Memory, module: RomModule) -> Option<RomModule> {
+        let old = self.unmap(mem);
+        self.module = Some(module);
+        self.map(mem);
+        self.reset_pending = self.reset;
+        old
+    }
+
+    /// remove and return the inserted module
+    pub fn remove(&mut self, mem: &mut Memory) -> Option<RomModule> {
+        let old = self.unmap(mem);
+        if old.is_some() {
+            self.reset_pending = self.reset;
+        }
+        old
+    }
+
+    /// switch the inserted module on or off without removing it
+    pub fn set_active(&mut self, mem: &mut Memory, active: bool) {
+        if active != self.active {
+            self.active = active;
+            if active {
+                self.map(mem);
+            } else if let Some(ref module) = self.module {
+                mem.unmap(self.layer, module.data.len(), module.addr);
+            }
+        }
+    }
+
+    /// move the inserted module to another address
+    pub fn set_addr(&mut self, mem: &mut Memory, addr: usize) {
+        if let Some(mut module) = self.unmap(mem) {
+            module.addr = addr;
+            self.module = Some(module);
+            self.map(mem);
+        }
+    }
+
+    /// private method to map the inserted module if it is active
+    fn map(&self, mem: &mut Memory) {
+        if let Some(ref module) = self.module {
+            if self.active {
+                mem.map_slice(self.layer, module.addr, module.data);
+            }
+        }
+    }
+
+    /// private method to unmap and take the inserted module
+    fn unmap(&mut self, mem: &mut Memory) -> Option<RomModule> {
+        let module = self.module.take();
+        if let Some(ref module) = module {
+            if self.active {
+                mem.unmap(self.layer, module.data.len(), module.addr);
+            }
+        }
+        module
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CPU;
+    use bus::NullBus;
+
+    static ROM_A: [u8; 0x0400] = [0x3C; 0x0400];     // INC A
+    static ROM_B: [u8; 0x0400] = [0x3D; 0x0400];     // DEC A
+
+    #[test]
+    fn hot_swap() {
+        let mut cpu = CPU::new();
+        cpu.mem.map(1, 0x00000, 0x0000, true, 0x10000);
+        cpu.set_block_cache(true);
+        let mut slot = ModuleSlot::new(0);
+        slot.insert(&mut cpu.mem, RomModule::new("A", &ROM_A, 0x0000));
+        cpu.run_block(&NullBus);
+        assert!(cpu.reg.a() > 0);
+        assert_eq!(slot.take_reset(), None);
+
+        // the swapped module is executed on the next step
+        let old = slot.insert(&mut cpu.mem, RomModule::new("B", &ROM_B, 0x0000)).unwrap();
+        assert_eq!(old.name, "A");
+        cpu.reg.set_a(0);
+        cpu.reg.set_pc(0x0000);
+        cpu.run_block(&NullBus);
+        assert!(cpu.reg.a() > 0x80);
+
+        // the RAM below the module is uncovered on removal
+        assert_eq!(slot.remove(&mut cpu.mem).unwrap().name, "B");
+        assert!(slot.remove(&mut cpu.mem).is_none());
+        cpu.reg.set_a(0);
+        cpu.reg.set_pc(0x0000);
+        cpu.run_block(&NullBus);
+        assert_eq!(cpu.reg.a(), 0);
+    }
+
+    #[test]
+    fn relocate() {
+        let mut mem = Memory::new();
+        let mut slot = ModuleSlot::new(0);
+        slot.set_reset(Some(ResetKind::Cold));
+        // moving or switching an empty slot does nothing
+        slot.set_addr(&mut mem, 0x4000);
+        slot.set_active(&mut mem, false);
+        assert_eq!(slot.take_reset(), None);
+
+        // an inactive module isn't mapped until switched on
+        slot.insert(&mut mem, RomModule::new("A", &ROM_A, 0x8000));
+        assert_eq!(slot.take_reset(), Some(ResetKind::Cold));
+        assert_eq!(mem.r8(0x8000), 0xFF);
+        slot.set_addr(&mut mem, 0x4000);
+        slot.set_active(&mut mem, true);
+        assert_eq!(mem.r8(0x8000), 0xFF);
+        assert_eq!(mem.r8(0x4000), 0x3C);
+        assert_eq!(slot.module().unwrap().addr, 0x4000);
+        slot.set_addr(&mut mem, 0xC000);
+        assert_eq!(mem.r8(0x4000), 0xFF);
+        assert_eq!(mem.r8(0xC000), 0x3C);
+        assert_eq!(slot.take_reset(), None);
+    }
+}
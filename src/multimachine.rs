@@ -0,0 +1,494 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use RegT;
+use memory::Memory;
+use mapper::Mapper;
+use machine::{Machine, Clock};
+
+/// a secondary CPU driven by a MultiMachine
+#[derive(Clone,Debug)]
+struct SubCpu {
+    hz: i64,
+    /// due time in units of 1/(main hz * sub hz) seconds, positive if the CPU is behind
+    credit: i64,
+    running: bool,
+    cycles: u64,
+}
+
+/// run control for several CPUs in one emulated system
+///
+/// Many arcade boards (and some computers) have more than one CPU,
+/// for instance a Z80 main CPU plus a Z80 sound CPU with its own clock.
+/// The MultiMachine wraps the **Machine** of the main CPU (CPU 0, which
+/// defines the frame timing, pause and single-stepping), and runs any
+/// number of secondary CPUs added with **add_cpu()** in lockstep, each
+/// at its own clock frequency.
+///
+/// Like Machine::run(), **run()** is called once per host frame, but
+/// the closure gets the index of the CPU which executes the next
+/// instruction. After each **quantum** of main CPU cycles (by default
+/// after each instruction) the secondary CPUs execute instructions
+/// until they have caught up with the main CPU according to the clock
+/// ratio, and at the end of run() all CPUs are in sync. A secondary
+/// CPU is never behind the main CPU, and ahead by at most one of its
+/// own instructions. The cycle ratio is computed with integer math, so
+/// the interleaving is deterministic and doesn't drift.
+///
+/// The CPUs usually communicate through a **Mailbox** (a latch written
+/// by one CPU and read by the other, e.g. the 'sound command' port), or
+/// through RAM which is visible to both CPUs (see **SharedRam**).
+///
+/// All emulator state lives in the chip and system objects, there's no
+/// global state, so any number of CPUs and systems can be emulated side
+/// by side in one process.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, Machine, MultiMachine, Clock, NullBus};
+///
+/// let mut main_cpu = CPU::new_64k();
+/// let mut sound_cpu = CPU::new_64k();
+/// // 4 MHz main CPU with 80000 cycles per frame (50 Hz)
+/// let mut mm = MultiMachine::new(Machine::with_clock(Clock::new(4_000_000), 50));
+/// // 2 MHz sound CPU
+/// let sound = mm.add_cpu(Clock::new(2_000_000));
+///
+/// // the memory is filled with NOPs (4 cycles each)
+/// let cycles = mm.run(|cpu| if cpu == sound {
+///     sound_cpu.step(&NullBus)
+/// } else {
+///     main_cpu.step(&NullBus)
+/// });
+/// assert_eq!(cycles, 80000);
+/// assert_eq!(mm.cycles(0), 80000);
+/// assert_eq!(mm.cycles(sound), 40000);
+/// ```
+#[derive(Clone,Debug)]
+pub struct MultiMachine {
+    machine: Machine,
+    hz: i64,
+    quantum: i64,
+    /// main CPU cycles executed since the last sync
+    pending: i64,
+    cycles: u64,
+    cpus: Vec<SubCpu>,
+}
+
+impl MultiMachine {
+    /// create a MultiMachine with the Machine of the main CPU (created with a clock)
+    pub fn new(machine: Machine) -> MultiMachine {
+        let hz = machine.clock().expect("MultiMachine needs Machine::with_clock()").hz();
+        MultiMachine {
+            machine,
+            hz,
+            quantum: 1,
+            pending: 0,
+            cycles: 0,
+            cpus: Vec::new(),
+        }
+    }
+
+    /// add a secondary CPU with its clock, return its CPU index (starting at 1)
+    pub fn add_cpu(&mut self, clock: Clock) -> usize {
+        assert!(clock.hz() > 0);
+        self.cpus.push(SubCpu {
+            hz: clock.hz(),
+            credit: 0,
+            running: true,
+            cycles: 0,
+        });
+        self.cpus.len()
+    }
+
+    /// number of CPUs, including the main CPU
+    pub fn num_cpus(&self) -> usize {
+        self.cpus.len() + 1
+    }
+
+    /// the Machine of the main CPU (frame timing, pause and single-stepping)
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// the Machine of the main CPU, for pause(), resume() and advance_*()
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    /// set the number of main CPU cycles between syncs of the secondary CPUs
+    ///
+    /// The default of 1 syncs after every instruction of the main CPU,
+    /// a bigger quantum is faster, but the CPUs see each other's writes
+    /// later.
+    pub fn set_quantum(&mut self, cycles: i64) {
+        assert!(cycles > 0);
+        self.quantum = cycles;
+    }
+
+    /// number of main CPU cycles between syncs of the secondary CPUs
+    pub fn quantum(&self) -> i64 {
+        self.quantum
+    }
+
+    /// start or stop a secondary CPU (e.g. while its RESET or BUSREQ line is held)
+    ///
+    /// A stopped CPU doesn't execute instructions, and doesn't catch up
+    /// on the time it was stopped.
+    pub fn set_running(&mut self, cpu: usize, running: bool) {
+        assert!(cpu > 0, "the main CPU is paused with machine_mut().pause()");
+        let sub = &mut self.cpus[cpu - 1];
+        sub.running = running;
+        sub.credit = 0;
+    }
+
+    /// true if a CPU executes instructions
+    pub fn is_running(&self, cpu: usize) -> bool {
+        if cpu == 0 {
+            !self.machine.is_paused()
+        } else {
+            self.cpus[cpu - 1].running
+        }
+    }
+
+    /// number of cycles executed by a CPU
+    pub fn cycles(&self, cpu: usize) -> u64 {
+        if cpu == 0 {
+            self.cycles
+        } else {
+            self.cpus[cpu - 1].cycles
+        }
+    }
+
+    /// execute one frame of the main CPU (see Machine::run()), return the main CPU cycles
+    ///
+    /// The closure executes one instruction of the CPU with the index
+    /// passed in (0 is the main CPU) and returns the number of cycles
+    /// it took.
+    pub fn run<F>(&mut self, mut step: F) -> i64
+        where F: FnMut(usize) -> i64
+    {
+        let hz = self.hz;
+        let quantum = self.quantum;
+        let cpus = &mut self.cpus;
+        let pending = &mut self.pending;
+        let main_cycles = &mut self.cycles;
+        let cycles = self.machine.run(|| {
+            let c = step(0);
+            *main_cycles += c as u64;
+            *pending += c;
+            if *pending >= quantum {
+                sync(cpus, hz, pending, &mut step);
+            }
+            c
+        });
+        sync(&mut self.cpus, hz, &mut self.pending, &mut step);
+        cycles
+    }
+}
+
+// let the secondary CPUs catch up with the main CPU
+fn sync<F>(cpus: &mut [SubCpu], hz: i64, pending: &mut i64, step: &mut F)
+    where F: FnMut(usize) -> i64
+{
+    for (i, cpu) in cpus.iter_mut().enumerate() {
+        if !cpu.running {
+            continue;
+        }
+        cpu.credit += *pending * cpu.hz;
+        while cpu.credit > 0 {
+            let c = step(i + 1);
+            if c <= 0 {
+                break;
+            }
+            cpu.credit -= c * hz;
+            cpu.cycles += c as u64;
+        }
+    }
+    *pending = 0;
+}
+
+/// a byte latch for communication between two CPUs
+///
+/// One CPU writes a byte (usually with an OUT instruction, or a write
+/// to a memory-mapped register), which sets the **full** flag until
+/// the other CPU reads the byte. On most arcade boards the full flag
+/// is wired to the interrupt input of the receiving CPU. The Mailbox
+/// works through a shared reference, so it can be shared between the
+/// Bus implementations of both CPUs (e.g. in an Rc).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::Mailbox;
+///
+/// let latch = Mailbox::new();
+/// latch.write(0x42);
+/// assert!(latch.is_full());
+/// assert_eq!(latch.read(), 0x42);
+/// assert!(!latch.is_full());
+/// // reading again returns the same value
+/// assert_eq!(latch.read(), 0x42);
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct Mailbox {
+    data: Cell<u8>,
+    full: Cell<bool>,
+}
+
+impl Mailbox {
+    /// create an empty Mailbox
+    pub fn new() -> Mailbox {
+        Mailbox::default()
+    }
+
+    /// write a byte and set the full flag
+    pub fn write(&self, val: RegT) {
+        self.data.set(val as u8);
+        self.full.set(true);
+    }
+
+    /// read the byte and clear the full flag
+    pub fn read(&self) -> RegT {
+        self.full.set(false);
+        self.data.get() as RegT
+    }
+
+    /// read the byte without clearing the full flag
+    pub fn peek(&self) -> RegT {
+        self.data.get() as RegT
+    }
+
+    /// true if a byte was written and not read yet
+    pub fn is_full(&self) -> bool {
+        self.full.get()
+    }
+
+    /// clear the full flag (e.g. on reset)
+    pub fn clear(&self) {
+        self.full.set(false);
+    }
+}
+
+/// one CPU's view of a SharedRam
+#[derive(Debug)]
+struct Port {
+    addr: usize,
+    pending: VecDeque<(usize, u8)>,
+}
+
+/// RAM which is visible to several CPUs (dual-port RAM)
+///
+/// Each CPU has its own Memory object, so the shared RAM window must
+/// be mapped as RAM in each of them (at possibly different addresses).
+/// **attach()** installs a Mapper which forwards the CPU's writes to
+/// the window to the other CPUs, and **sync()** applies the writes of
+/// the other CPUs to a CPU's Memory object. Calling sync() before each
+/// instruction of a CPU (e.g. in the MultiMachine::run() closure) makes
+/// the writes visible in the same order they happen in emulated time.
+///
+/// A Memory object can only have one Mapper, so the shared RAM can't
+/// be combined with a cartridge mapper in the same Memory object.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Memory, SharedRam};
+///
+/// let mut main_mem = Memory::new();
+/// let mut sound_mem = Memory::new();
+/// main_mem.map(0, 0x0000, 0x8000, true, 0x0400);
+/// sound_mem.map(0, 0x0000, 0x4000, true, 0x0400);
+///
+/// // 1 KByte shared at 0x8000 (main CPU) and 0x4000 (sound CPU)
+/// let shared = SharedRam::new(0x0400);
+/// let main_port = SharedRam::attach(&shared, &mut main_mem, 0x8000);
+/// let sound_port = SharedRam::attach(&shared, &mut sound_mem, 0x4000);
+///
+/// main_mem.w8(0x8010, 0x55);
+/// shared.sync(sound_port, &mut sound_mem);
+/// assert_eq!(sound_mem.r8(0x4010), 0x55);
+/// sound_mem.w8(0x43FF, 0xAA);
+/// shared.sync(main_port, &mut main_mem);
+/// assert_eq!(main_mem.r8(0x83FF), 0xAA);
+/// ```
+#[derive(Debug)]
+pub struct SharedRam {
+    size: usize,
+    ports: RefCell<Vec<Port>>,
+}
+
+impl SharedRam {
+    /// create a shared RAM window with a size in bytes
+    pub fn new(size: usize) -> Rc<SharedRam> {
+        assert!(size > 0 && size <= 0x10000);
+        Rc::new(SharedRam {
+            size,
+            ports: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// size of the shared RAM window in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// attach a CPU's Memory object with the shared window at addr, return the port index
+    pub fn attach(shared: &Rc<SharedRam>, mem: &mut Memory, addr: usize) -> usize {
+        let port = {
+            let mut ports = shared.ports.borrow_mut();
+            ports.push(Port {
+                addr: addr & 0xFFFF,
+                pending: VecDeque::new(),
+            });
+            ports.len() - 1
+        };
+        mem.set_mapper(Box::new(SharedRamMapper {
+            shared: shared.clone(),
+            port,
+            addr: addr & 0xFFFF,
+        }));
+        port
+    }
+
+    /// apply the writes of the other CPUs to the Memory object of a port
+    pub fn sync(&self, port: usize, mem: &mut Memory) {
+        let mut ports = self.ports.borrow_mut();
+        let port = &mut ports[port];
+        while let Some((offset, val)) = port.pending.pop_front() {
+            mem.w8f((port.addr + offset) as RegT, val as RegT);
+        }
+    }
+
+    /// true if the port has writes of other CPUs which are not applied yet
+    pub fn is_pending(&self, port: usize) -> bool {
+        !self.ports.borrow()[port].pending.is_empty()
+    }
+
+    fn write(&self, from: usize, offset: usize, val: u8) {
+        for (i, port) in self.ports.borrow_mut().iter_mut().enumerate() {
+            if i != from {
+                port.pending.push_back((offset, val));
+            }
+        }
+    }
+}
+
+struct SharedRamMapper {
+    shared: Rc<SharedRam>,
+    port: usize,
+    addr: usize,
+}
+
+impl Mapper for SharedRamMapper {
+    fn init(&mut self, _mem: &mut Memory) {}
+
+    fn trap_mask(&self) -> u64 {
+        Memory::page_mask(self.addr, self.shared.size)
+    }
+
+    fn write(&mut self, _mem: &mut Memory, addr: RegT, val: RegT) {
+        // the trapped pages may be bigger than the shared window
+        let offset = (addr as usize).wrapping_sub(self.addr) & 0xFFFF;
+        if offset < self.shared.size {
+            self.shared.write(self.port, offset, val as u8);
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_ratio() {
+        // 3 MHz main CPU, 2 MHz secondary CPU with 7-cycle instructions
+        let mut mm = MultiMachine::new(Machine::with_clock(Clock::new(3_000_000), 50));
+        let sub = mm.add_cpu(Clock::new(2_000_000));
+        let mut order = Vec::new();
+        for _ in 0..10 {
+            mm.run(|cpu| {
+                order.push(cpu);
+                if cpu == 0 { 4 } else { 7 }
+            });
+            // in sync at the end of each frame, ahead by less than an instruction
+            let due = mm.cycles(0) * 2 / 3;
+            assert!(mm.cycles(sub) >= due && mm.cycles(sub) < due + 7);
+        }
+        assert_eq!(mm.cycles(0), 600_000);
+        assert_eq!(order[..4], [0, 1, 0, 0]);
+
+        // a stopped CPU doesn't execute and doesn't catch up
+        let before = mm.cycles(sub);
+        mm.set_running(sub, false);
+        mm.run(|cpu| if cpu == 0 { 4 } else { 7 });
+        assert_eq!(mm.cycles(sub), before);
+        mm.set_running(sub, true);
+        mm.run(|cpu| if cpu == 0 { 4 } else { 7 });
+        assert!(mm.cycles(sub) - before >= 40_000);
+    }
+
+    #[test]
+    fn quantum_and_pause() {
+        let mut mm = MultiMachine::new(Machine::with_clock(Clock::new(1_000_000), 50));
+        let sub = mm.add_cpu(Clock::new(1_000_000));
+        mm.set_quantum(100);
+        let mut order = Vec::new();
+        mm.run(|cpu| {
+            order.push(cpu);
+            10
+        });
+        // 10 main CPU instructions, then 10 secondary CPU instructions
+        assert_eq!(order[..11], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(mm.cycles(sub), 20_000);
+
+        mm.machine_mut().pause();
+        assert!(!mm.is_running(0));
+        assert_eq!(mm.run(|_| 10), 0);
+        mm.machine_mut().advance_instruction();
+        assert_eq!(mm.run(|_| 10), 10);
+        assert_eq!(mm.cycles(sub), 20_010);
+    }
+
+    #[test]
+    fn shared_ram() {
+        let mut mem0 = Memory::new();
+        let mut mem1 = Memory::new();
+        let mut mem2 = Memory::new();
+        mem0.map(0, 0x0000, 0x0000, true, 0x10000);
+        mem1.map(0, 0x0000, 0x0000, true, 0x10000);
+        mem2.map(0, 0x0000, 0x0000, true, 0x10000);
+        // not page aligned
+        let shared = SharedRam::new(0x100);
+        let p0 = SharedRam::attach(&shared, &mut mem0, 0x1080);
+        let p1 = SharedRam::attach(&shared, &mut mem1, 0x2000);
+        let p2 = SharedRam::attach(&shared, &mut mem2, 0xFF80);
+
+        mem0.w8(0x107F, 0x11);
+        mem0.w8(0x1080, 0x22);
+        mem0.w8(0x117F, 0x33);
+        mem0.w8(0x1180, 0x44);
+        assert!(!shared.is_pending(p0));
+        assert!(shared.is_pending(p1));
+        shared.sync(p1, &mut mem1);
+        shared.sync(p2, &mut mem2);
+        assert!(!shared.is_pending(p1));
+        assert_eq!(mem1.r8(0x1FFF), 0x00);
+        assert_eq!(mem1.r8(0x2000), 0x22);
+        assert_eq!(mem1.r8(0x20FF), 0x33);
+        assert_eq!(mem1.r8(0x2100), 0x00);
+        // the window wraps around at 64k
+        assert_eq!(mem2.r8(0xFF80), 0x22);
+        assert_eq!(mem2.r8(0x007F), 0x33);
+
+        // applied writes are not forwarded again
+        mem2.w16(0x0000, 0x5566);
+        shared.sync(p0, &mut mem0);
+        shared.sync(p1, &mut mem1);
+        assert!(!shared.is_pending(p2));
+        assert_eq!(mem0.r16(0x1100), 0x5566);
+        assert_eq!(mem1.r16(0x2080), 0x5566);
+    }
+}
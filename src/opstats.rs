@@ -0,0 +1,217 @@
+use std::fmt;
+use std::cmp::Reverse;
+use decoder::decode;
+
+/// the opcode tables of the Z80 instruction set
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum OpTable {
+    Main,
+    CB,
+    ED,
+    DD,
+    FD,
+    DDCB,
+    FDCB,
+}
+
+const OP_TABLES: [OpTable; 7] = [OpTable::Main, OpTable::CB, OpTable::ED, OpTable::DD,
+                                 OpTable::FD, OpTable::DDCB, OpTable::FDCB];
+
+impl OpTable {
+    /// the prefix bytes of the opcode table
+    pub fn prefix(&self) -> &'static [u8] {
+        match *self {
+            OpTable::Main => &[],
+            OpTable::CB => &[0xCB],
+            OpTable::ED => &[0xED],
+            OpTable::DD => &[0xDD],
+            OpTable::FD => &[0xFD],
+            OpTable::DDCB => &[0xDD, 0xCB],
+            OpTable::FDCB => &[0xFD, 0xCB],
+        }
+    }
+}
+
+/// an entry of the opcode statistics
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct OpCount {
+    pub table: OpTable,
+    pub op: u8,
+    pub count: u64,
+}
+
+impl OpCount {
+    /// the instruction bytes, with zero displacement and immediate operands
+    pub fn bytes(&self) -> [u8; 4] {
+        let mut bytes = [0u8; 4];
+        let prefix = self.table.prefix();
+        bytes[..prefix.len()].copy_from_slice(prefix);
+        match self.table {
+            // DD CB d op
+            OpTable::DDCB | OpTable::FDCB => bytes[3] = self.op,
+            _ => bytes[prefix.len()] = self.op,
+        }
+        bytes
+    }
+}
+
+impl fmt::Display for OpCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut ops = String::new();
+        for b in self.table.prefix() {
+            ops += &format!("{:02X} ", b);
+        }
+        ops += &format!("{:02X}", self.op);
+        let (inst, _) = decode(&self.bytes());
+        write!(f, "{:12} {:8}  {}", self.count, ops, inst)
+    }
+}
+
+/// per-opcode execution counts, see CPU::set_opcode_stats()
+///
+/// Each executed instruction is counted once in the table of its
+/// prefix (a DD or FD prefix which doesn't start an instruction
+/// counts as DD or FD in the main table).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, NullBus, OpTable};
+///
+/// let mut cpu = CPU::new_64k();
+/// // LD B,3; LOOP: DJNZ LOOP; LD IX,0000h
+/// cpu.mem.write(0x0000, &[0x06, 0x03, 0x10, 0xFE, 0xDD, 0x21, 0x00, 0x00]);
+/// cpu.set_opcode_stats(true);
+/// for _ in 0..5 {
+///     cpu.step(&NullBus);
+/// }
+/// let stats = cpu.opcode_stats().unwrap();
+/// assert_eq!(stats.count(OpTable::Main, 0x10), 3);
+/// assert_eq!(stats.count(OpTable::DD, 0x21), 1);
+/// assert_eq!(stats.total(), 5);
+/// let top = stats.top(1);
+/// assert_eq!(top[0].to_string().trim(), "3 10        DJNZ $+02h");
+/// ```
+#[derive(Clone)]
+pub struct OpcodeStats {
+    counts: Vec<u64>,
+}
+
+impl OpcodeStats {
+    /// create empty statistics
+    pub fn new() -> OpcodeStats {
+        OpcodeStats { counts: vec![0; OP_TABLES.len() * 256] }
+    }
+
+    /// count the instruction starting with bytes, len is its decoded length
+    pub fn record(&mut self, bytes: &[u8; 4], len: i32) {
+        let (table, op) = match (bytes[0], bytes[1]) {
+            (0xDD, 0xCB) => (OpTable::DDCB, bytes[3]),
+            (0xFD, 0xCB) => (OpTable::FDCB, bytes[3]),
+            (0xCB, op) => (OpTable::CB, op),
+            (0xED, op) => (OpTable::ED, op),
+            (0xDD, op) if len > 1 => (OpTable::DD, op),
+            (0xFD, op) if len > 1 => (OpTable::FD, op),
+            (op, _) => (OpTable::Main, op),
+        };
+        self.counts[table as usize * 256 + op as usize] += 1;
+    }
+
+    /// the number of executions of an opcode
+    pub fn count(&self, table: OpTable, op: u8) -> u64 {
+        self.counts[table as usize * 256 + op as usize]
+    }
+
+    /// the number of executions of all opcodes
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// reset all counts to zero
+    pub fn clear(&mut self) {
+        for c in self.counts.iter_mut() {
+            *c = 0;
+        }
+    }
+
+    /// the executed opcodes, most frequent first
+    pub fn sorted(&self) -> Vec<OpCount> {
+        let mut res: Vec<OpCount> = self.counts.iter().enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(i, &count)| OpCount {
+                table: OP_TABLES[i / 256],
+                op: (i & 0xFF) as u8,
+                count,
+            })
+            .collect();
+        // stable sort, equal counts stay in table and opcode order
+        res.sort_by_key(|entry| Reverse(entry.count));
+        res
+    }
+
+    /// the n most frequent opcodes
+    pub fn top(&self, n: usize) -> Vec<OpCount> {
+        let mut res = self.sorted();
+        res.truncate(n);
+        res
+    }
+
+    /// the n most frequent opcodes as text, one line per opcode
+    /// with count, share of the total, opcode bytes and mnemonic
+    pub fn report(&self, n: usize) -> String {
+        let total = self.total().max(1) as f64;
+        let mut s = String::new();
+        for entry in self.top(n) {
+            s += &format!("{:6.2}% {}\n", entry.count as f64 * 100.0 / total, entry);
+        }
+        s
+    }
+}
+
+impl Default for OpcodeStats {
+    fn default() -> OpcodeStats {
+        OpcodeStats::new()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let mut stats = OpcodeStats::new();
+        stats.record(&[0x00, 0x00, 0x00, 0x00], 1);
+        stats.record(&[0xCB, 0x11, 0x00, 0x00], 2);
+        stats.record(&[0xED, 0xB0, 0x00, 0x00], 2);
+        stats.record(&[0xDD, 0x21, 0x34, 0x12], 4);
+        stats.record(&[0xFD, 0xCB, 0x05, 0x46], 4);
+        stats.record(&[0xDD, 0xCB, 0x05, 0x46], 4);
+        stats.record(&[0xDD, 0xCB, 0x05, 0x46], 4);
+        // a DD prefix followed by another prefix
+        stats.record(&[0xDD, 0xFD, 0x21, 0x00], 1);
+        assert_eq!(stats.count(OpTable::Main, 0x00), 1);
+        assert_eq!(stats.count(OpTable::CB, 0x11), 1);
+        assert_eq!(stats.count(OpTable::ED, 0xB0), 1);
+        assert_eq!(stats.count(OpTable::DD, 0x21), 1);
+        assert_eq!(stats.count(OpTable::FDCB, 0x46), 1);
+        assert_eq!(stats.count(OpTable::DDCB, 0x46), 2);
+        assert_eq!(stats.count(OpTable::Main, 0xDD), 1);
+        assert_eq!(stats.total(), 8);
+
+        let top = stats.top(2);
+        assert_eq!((top[0].table, top[0].op, top[0].count), (OpTable::DDCB, 0x46, 2));
+        assert_eq!((top[1].table, top[1].op, top[1].count), (OpTable::Main, 0x00, 1));
+        assert_eq!(top[0].bytes(), [0xDD, 0xCB, 0x00, 0x46]);
+        let report = stats.report(2);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(" 25.00%"));
+        assert!(lines[0].ends_with("DD CB 46  BIT 0,(IX+00h)"), "{}", lines[0]);
+
+        stats.clear();
+        assert_eq!(stats.total(), 0);
+        assert!(stats.sorted().is_empty());
+    }
+}
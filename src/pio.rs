@@ -1,5 +1,9 @@
+use std::fmt;
 use RegT;
-use bus::Bus;
+use std::collections::HashMap;
+use bus::{Bus, Reset, ResetKind};
+use iotrace::IoDir;
+use savestate::{SaveState, ChunkWriter, ChunkReader, StateError};
 
 /// PIO channel A
 pub const PIO_A: usize = 0;
@@ -7,14 +11,15 @@ pub const PIO_A: usize = 0;
 pub const PIO_B: usize = 1;
 const NUM_CHANNELS: usize = 2;
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 enum Expect {
     Any,
     IOSelect,
     IntMask,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+/// PIO channel operation mode
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Mode {
     Output,
     Input,
@@ -24,12 +29,10 @@ pub enum Mode {
 
 pub const INTCTRL_ENABLE_INT: u8 = (1 << 7);
 pub const INTCTRL_MASK_FOLLOWS: u8 = (1 << 4);
-#[allow(unused)]
-pub const INTCTRL_AND_OR: u8 = (1 << 6);
-#[allow(unused)]
-pub const INTCTRL_HIGH_LOW: u8 = (1 << 5);
+pub const INTCTRL_AND_OR: u8 = 1 << 6;
+pub const INTCTRL_HIGH_LOW: u8 = 1 << 5;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 struct Channel {
     pub expect: Expect, // next expected control byte type
     pub mode: Mode, // current operation mode
@@ -40,11 +43,33 @@ struct Channel {
     pub int_vector: u8,
     pub int_control: u8,
     pub bctrl_match: bool,
+    pub int_pending: bool, // interrupt requested and not serviced yet
     pub rdy: bool,
-    pub stb: bool,
+    pub stb: bool, // strobe input is active (low)
+}
+
+/// reasons why PIO::write_control() has rejected a control word
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum PioError {
+    /// the control word isn't valid
+    InvalidControlWord(u8),
+    /// only channel A can be switched into bidirectional mode
+    BidirectionalChannelB,
+}
+
+impl fmt::Display for PioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PioError::InvalidControlWord(val) => write!(f, "invalid PIO control word {:02X}", val),
+            PioError::BidirectionalChannelB => {
+                write!(f, "bidirectional mode on PIO channel B not allowed")
+            }
+        }
+    }
 }
 
 /// Z80 PIO emulation
+#[derive(Debug)]
 pub struct PIO {
     id: usize, // id of PIO (needed for systems with multiple ids)
     chn: [Channel; NUM_CHANNELS],
@@ -65,6 +90,7 @@ impl PIO {
                 int_vector: 0,
                 int_control: 0,
                 bctrl_match: false,
+                int_pending: false,
                 rdy: false,
                 stb: false,
             }; NUM_CHANNELS],
@@ -81,13 +107,17 @@ impl PIO {
             chn.int_mask = 0xFF;
             chn.int_control &= !INTCTRL_ENABLE_INT;
             chn.bctrl_match = false;
+            chn.int_pending = false;
             chn.rdy = false;
             chn.stb = false;
         }
     }
 
     /// write to control register
-    pub fn write_control(&mut self, chn: usize, val: RegT) {
+    ///
+    /// Invalid control words are ignored (the PIO state doesn't change)
+    /// and reported as error, the caller may just ignore the error.
+    pub fn write_control(&mut self, chn: usize, val: RegT) -> Result<(), PioError> {
         let c = &mut self.chn[chn];
         match c.expect {
             Expect::IOSelect => {
@@ -109,7 +139,7 @@ impl PIO {
                             _ => Mode::Bitcontrol,
                         };
                         if (chn == PIO_B) && mode == Mode::Bidirectional {
-                            panic!("Bidirectional mode on PIO channel B not allowed!");
+                            return Err(PioError::BidirectionalChannelB);
                         } else {
                             c.mode = mode;
                             if mode == Mode::Bitcontrol {
@@ -125,27 +155,129 @@ impl PIO {
                             c.expect = Expect::IntMask;
                             c.bctrl_match = false;
                         }
+                        if (c.int_control & INTCTRL_ENABLE_INT) == 0 {
+                            c.int_pending = false;
+                        }
                     }
                     // set/clear interrupt enable bit
                     0x3 => {
                         c.int_control = ((val as u8) & INTCTRL_ENABLE_INT) |
                                         (c.int_control & !INTCTRL_ENABLE_INT);
+                        if (c.int_control & INTCTRL_ENABLE_INT) == 0 {
+                            c.int_pending = false;
+                        }
                     }
                     // set interrupt vector
                     _ if (val & 1) == 0 => {
                         c.int_vector = val as u8;
                     }
-                    _ => panic!("Invalid PIO control word!"),
+                    _ => return Err(PioError::InvalidControlWord(val as u8)),
                 }
             }
         }
+        Ok(())
     }
 
     /// read control register
+    ///
+    /// The PIO control registers are write-only, but reading a control
+    /// port returns the interrupt control bits of both channels (this
+    /// is the same for both ports): bit 7 is the interrupt enable bit
+    /// of channel A and bit 6 the AND/OR bit, bits 3..0 are bits 7..4 of
+    /// channel B's interrupt control word (interrupt enable, AND/OR,
+    /// HIGH/LOW, mask follows), bits 5 and 4 are 0.
     pub fn read_control(&self) -> RegT {
         ((self.chn[PIO_A].int_control & 0xC0) | (self.chn[PIO_B].int_control >> 4)) as RegT
     }
 
+    /// current operation mode of a channel
+    pub fn mode(&self, chn: usize) -> Mode {
+        self.chn[chn].mode
+    }
+
+    /// I/O select mask of a channel in bit-control mode (1: input, 0: output)
+    pub fn io_select(&self, chn: usize) -> u8 {
+        self.chn[chn].io_select
+    }
+
+    /// interrupt mask of a channel in bit-control mode
+    pub fn int_mask(&self, chn: usize) -> u8 {
+        self.chn[chn].int_mask
+    }
+
+    /// interrupt vector of a channel
+    pub fn int_vector(&self, chn: usize) -> u8 {
+        self.chn[chn].int_vector
+    }
+
+    /// interrupt control bits of a channel (INTCTRL_*)
+    pub fn int_control(&self, chn: usize) -> u8 {
+        self.chn[chn].int_control
+    }
+
+    /// true if interrupts are enabled on a channel
+    pub fn int_enabled(&self, chn: usize) -> bool {
+        (self.chn[chn].int_control & INTCTRL_ENABLE_INT) != 0
+    }
+
+    /// true if a channel has requested an interrupt which hasn't been serviced yet
+    ///
+    /// The request is serviced when the CPU reads or writes the data
+    /// register of the channel (in bit-control mode, when the interrupt
+    /// condition isn't true anymore), or when interrupts are disabled.
+    /// In bidirectional mode, both directions request interrupts on
+    /// channel A.
+    pub fn int_pending(&self, chn: usize) -> bool {
+        self.chn[chn].int_pending
+    }
+
+    /// the channel with a pending interrupt request and its interrupt vector
+    ///
+    /// If both channels have a pending request, channel A is returned
+    /// (it has the higher priority in the PIO's internal daisychain).
+    ///
+    /// ```
+    /// use rz80::{PIO, PIO_B, NullBus};
+    ///
+    /// let mut pio = PIO::new(0);
+    /// // channel B: vector 0xE2, bit control mode with bit 0 as input,
+    /// // interrupt when the input bit is high
+    /// for &val in &[0xE2, 0xCF, 0x01, 0xB7, 0xFE] {
+    ///     pio.write_control(PIO_B, val).unwrap();
+    /// }
+    /// assert_eq!(pio.pending_irq(), None);
+    /// pio.write(&NullBus, PIO_B, 0x01);
+    /// assert_eq!(pio.pending_irq(), Some((PIO_B, 0xE2)));
+    /// pio.write(&NullBus, PIO_B, 0x00);
+    /// assert_eq!(pio.pending_irq(), None);
+    /// ```
+    pub fn pending_irq(&self) -> Option<(usize, u8)> {
+        self.chn
+            .iter()
+            .position(|c| c.int_pending)
+            .map(|chn| (chn, self.chn[chn].int_vector))
+    }
+
+    /// output register value of a channel
+    pub fn output(&self, chn: usize) -> u8 {
+        self.chn[chn].output
+    }
+
+    /// input register value of a channel
+    pub fn input(&self, chn: usize) -> u8 {
+        self.chn[chn].input
+    }
+
+    /// state of a channel's RDY output line
+    pub fn rdy(&self, chn: usize) -> bool {
+        self.chn[chn].rdy
+    }
+
+    /// true if a channel's STB input is active
+    pub fn stb(&self, chn: usize) -> bool {
+        self.chn[chn].stb
+    }
+
     /// set rdy flag on channel, and call pio_rdy callback on bus if changed
     fn set_rdy(&mut self, bus: &dyn Bus, chn: usize, rdy: bool) {
         let c = &mut self.chn[chn];
@@ -157,6 +289,9 @@ impl PIO {
 
     /// write data to PIO channel
     pub fn write_data(&mut self, bus: &dyn Bus, chn: usize, data: RegT) {
+        if self.chn[chn].mode != Mode::Bitcontrol {
+            self.chn[chn].int_pending = false;
+        }
         match self.chn[chn].mode {
             Mode::Output => {
                 self.set_rdy(bus, chn, false);
@@ -168,9 +303,10 @@ impl PIO {
                 self.chn[chn].output = data as u8;  // not a bug
             }
             Mode::Bidirectional => {
-                self.set_rdy(bus, chn, false);
+                // ARDY signals that output data is available, the
+                // data is only put on the port while ASTB is active
                 self.chn[chn].output = data as u8;
-                if !self.chn[chn].stb {
+                if self.chn[chn].stb {
                     bus.pio_outp(self.id, chn, data);
                 }
                 self.set_rdy(bus, chn, true);
@@ -184,6 +320,9 @@ impl PIO {
 
     /// read data from PIO channel
     pub fn read_data(&mut self, bus: &dyn Bus, chn: usize) -> RegT {
+        if self.chn[chn].mode != Mode::Bitcontrol {
+            self.chn[chn].int_pending = false;
+        }
         match self.chn[chn].mode {
             Mode::Output => self.chn[chn].output as RegT,
             Mode::Input => {
@@ -195,8 +334,9 @@ impl PIO {
                 self.chn[chn].input as RegT
             }
             Mode::Bidirectional => {
-                self.set_rdy(bus, chn, false);
-                self.set_rdy(bus, chn, true);
+                // input register has been emptied, BRDY signals that
+                // the PIO is ready for the next input byte
+                self.set_rdy(bus, PIO_B, true);
                 self.chn[chn].input as RegT
             }
             Mode::Bitcontrol => {
@@ -207,9 +347,44 @@ impl PIO {
         }
     }
 
+    /// strobe input from peripheral device (false: strobe active)
+    ///
+    /// Implements the handshake of the bidirectional mode (mode 2) on
+    /// channel A, where ASTB (chn == PIO_A) controls the output and BSTB
+    /// (chn == PIO_B) the input direction. While ASTB is active, the
+    /// output register is put on the port, and on the rising edge ARDY
+    /// goes low. When BSTB becomes active the port data is latched into
+    /// the input register, and on the rising edge BRDY goes low. Both
+    /// rising edges request an interrupt with channel A's interrupt
+    /// vector if channel A interrupts are enabled.
+    pub fn strobe(&mut self, bus: &dyn Bus, chn: usize, level: bool) {
+        let active = !level;
+        if self.chn[chn].stb == active {
+            return;
+        }
+        self.chn[chn].stb = active;
+        if self.chn[PIO_A].mode != Mode::Bidirectional {
+            return;
+        }
+        if active {
+            if chn == PIO_A {
+                bus.pio_outp(self.id, PIO_A, self.chn[PIO_A].output as RegT);
+            } else {
+                self.chn[PIO_A].input = bus.pio_inp(self.id, PIO_A) as u8;
+            }
+        } else {
+            self.set_rdy(bus, chn, false);
+            let c = &mut self.chn[PIO_A];
+            if 0 != (c.int_control & INTCTRL_ENABLE_INT) {
+                c.int_pending = true;
+                bus.pio_irq(self.id, PIO_A, c.int_vector as RegT);
+            }
+        }
+    }
+
     /// write data from peripheral device into PIO
     pub fn write(&mut self, bus: &dyn Bus, chn: usize, data: RegT) {
-        let mut c = self.chn[chn];
+        let c = &mut self.chn[chn];
         if c.mode == Mode::Bitcontrol {
             c.input = data as u8;
             let mask = !c.int_mask;
@@ -221,13 +396,130 @@ impl PIO {
                          ((ictrl == 0x60) && (val == mask));
 
             if !c.bctrl_match && bmatch && (0 != (c.int_control & INTCTRL_ENABLE_INT)) {
+                c.int_pending = true;
                 bus.pio_irq(self.id, chn, c.int_vector as RegT);
+            } else if !bmatch {
+                c.int_pending = false;
             }
             c.bctrl_match = bmatch;
         }
     }
 }
 
+/// create an IoTrace decoder for PIO control ports
+///
+/// The decoder tracks which control word follows (I/O select or
+/// interrupt mask) per port, so it must see all control writes.
+pub fn pio_control_decoder() -> impl FnMut(IoDir, RegT, RegT) -> Option<String> {
+    let mut expect: HashMap<RegT, Expect> = HashMap::new();
+    move |dir, port, val| {
+        if dir == IoDir::In {
+            return Some("read control".to_string());
+        }
+        let v = val as u8;
+        let text = match expect.remove(&port).unwrap_or(Expect::Any) {
+            Expect::IOSelect => format!("io select {:02X}h", v),
+            Expect::IntMask => format!("int mask {:02X}h", v),
+            Expect::Any => {
+                match v & 0xF {
+                    0xF => {
+                        let mode = match v >> 6 {
+                            0 => "output",
+                            1 => "input",
+                            2 => "bidirectional",
+                            _ => {
+                                expect.insert(port, Expect::IOSelect);
+                                "bitcontrol, io select follows"
+                            }
+                        };
+                        format!("mode {}", mode)
+                    }
+                    0x7 => {
+                        let mut parts = vec![
+                            if (v & INTCTRL_ENABLE_INT) != 0 { "int on" } else { "int off" },
+                            if (v & INTCTRL_AND_OR) != 0 { "AND" } else { "OR" },
+                            if (v & INTCTRL_HIGH_LOW) != 0 { "high" } else { "low" },
+                        ];
+                        if (v & INTCTRL_MASK_FOLLOWS) != 0 {
+                            expect.insert(port, Expect::IntMask);
+                            parts.push("mask follows");
+                        }
+                        format!("int control {:02X}h = {}", v, parts.join(", "))
+                    }
+                    0x3 => {
+                        format!("int {}", if (v & INTCTRL_ENABLE_INT) != 0 { "on" } else { "off" })
+                    }
+                    _ if (v & 1) == 0 => format!("int vector {:02X}h", v),
+                    _ => format!("invalid control word {:02X}h", v),
+                }
+            }
+        };
+        Some(text)
+    }
+}
+
+impl Reset for PIO {
+    /// a cold reset also clears the input registers and interrupt vectors
+    fn system_reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            *self = PIO::new(self.id);
+        }
+        self.reset();
+    }
+}
+
+impl SaveState for PIO {
+    const STATE_VERSION: u16 = 1;
+
+    fn save_state(&self, w: &mut ChunkWriter) {
+        for chn in &self.chn {
+            w.u8(chn.expect as u8);
+            w.u8(chn.mode as u8);
+            w.u8(chn.output);
+            w.u8(chn.input);
+            w.u8(chn.io_select);
+            w.u8(chn.int_mask);
+            w.u8(chn.int_vector);
+            w.u8(chn.int_control);
+            w.bool(chn.bctrl_match);
+            w.bool(chn.int_pending);
+            w.bool(chn.rdy);
+            w.bool(chn.stb);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut ChunkReader) -> Result<(), StateError> {
+        let mut chns = self.chn;
+        for chn in &mut chns {
+            chn.expect = match r.u8()? {
+                0 => Expect::Any,
+                1 => Expect::IOSelect,
+                2 => Expect::IntMask,
+                _ => return Err(r.invalid()),
+            };
+            chn.mode = match r.u8()? {
+                0 => Mode::Output,
+                1 => Mode::Input,
+                2 => Mode::Bidirectional,
+                3 => Mode::Bitcontrol,
+                _ => return Err(r.invalid()),
+            };
+            chn.output = r.u8()?;
+            chn.input = r.u8()?;
+            chn.io_select = r.u8()?;
+            chn.int_mask = r.u8()?;
+            chn.int_vector = r.u8()?;
+            chn.int_control = r.u8()?;
+            chn.bctrl_match = r.bool()?;
+            chn.int_pending = r.bool()?;
+            chn.rdy = r.bool()?;
+            chn.stb = r.bool()?;
+        }
+        self.chn = chns;
+        Ok(())
+    }
+}
+
 // ------------------------------------------------------------------------------
 #[cfg(test)]
 mod tests {
@@ -290,8 +582,8 @@ mod tests {
         let mut pio = PIO::new(0);
 
         // load interrupt vector (bit 0 == 0)
-        pio.write_control(PIO_A, 0xE0);
-        pio.write_control(PIO_B, 0xE2);
+        pio.write_control(PIO_A, 0xE0).unwrap();
+        pio.write_control(PIO_B, 0xE2).unwrap();
         assert!(0xE0 == pio.chn[PIO_A].int_vector);
         assert!(0xE2 == pio.chn[PIO_B].int_vector);
 
@@ -299,16 +591,16 @@ mod tests {
         // is the mode (00:output, 01:input, 10:bidirectional, 11:bitcontrol)
         // xx is ignored
         // bidirectional requires the bit control word to be written next
-        pio.write_control(PIO_A, 0b00101111);   // output
+        pio.write_control(PIO_A, 0b00101111).unwrap();   // output
         assert!(Mode::Output == pio.chn[PIO_A].mode);
-        pio.write_control(PIO_A, 0b01011111);   // input
+        pio.write_control(PIO_A, 0b01011111).unwrap();   // input
         assert!(Mode::Input == pio.chn[PIO_A].mode);
-        pio.write_control(PIO_A, 0b10111111);   // bidirectional
+        pio.write_control(PIO_A, 0b10111111).unwrap();   // bidirectional
         assert!(Mode::Bidirectional == pio.chn[PIO_A].mode);
-        pio.write_control(PIO_A, 0b11001111);   // bitcontrol
+        pio.write_control(PIO_A, 0b11001111).unwrap();   // bitcontrol
         assert!(Mode::Bitcontrol == pio.chn[PIO_A].mode);
         assert!(Expect::IOSelect == pio.chn[PIO_A].expect);
-        pio.write_control(PIO_A, 0b10101010);   // write bitcontrol IO mask
+        pio.write_control(PIO_A, 0b10101010).unwrap();   // write bitcontrol IO mask
         assert!(0b10101010 == pio.chn[PIO_A].io_select);
         assert!(Expect::Any == pio.chn[PIO_A].expect);
 
@@ -318,26 +610,174 @@ mod tests {
         // bit 5: high/low (bitcontrol mode)
         // bit 4: mask follows (bitcontrol mode)
         // bit 3..0: 0111
-        pio.write_control(PIO_A, 0b10100111);
+        pio.write_control(PIO_A, 0b10100111).unwrap();
         assert!(0b10100000 == pio.chn[PIO_A].int_control);
         assert!(Expect::Any == pio.chn[PIO_A].expect);
         assert!(INTCTRL_ENABLE_INT | INTCTRL_HIGH_LOW ==
                 INTCTRL_ENABLE_INT | INTCTRL_HIGH_LOW & pio.chn[PIO_A].int_control);
-        pio.write_control(PIO_A, 0b00010111);
+        pio.write_control(PIO_A, 0b00010111).unwrap();
         assert!(0b00010000 == pio.chn[PIO_A].int_control);
         assert!(INTCTRL_MASK_FOLLOWS == pio.chn[PIO_A].int_control & INTCTRL_MASK_FOLLOWS);
         assert!(Expect::IntMask == pio.chn[PIO_A].expect);
-        pio.write_control(PIO_A, 0b01010101);
+        pio.write_control(PIO_A, 0b01010101).unwrap();
         assert!(0b01010101 == pio.chn[PIO_A].int_mask);
         assert!(Expect::Any == pio.chn[PIO_A].expect);
 
         // set interrupt enable bit individually
-        pio.write_control(PIO_A, 0b11100111);
+        pio.write_control(PIO_A, 0b11100111).unwrap();
         assert!(0b11100000 == pio.chn[PIO_A].int_control);
-        pio.write_control(PIO_A, 0b00000011);
+        pio.write_control(PIO_A, 0b00000011).unwrap();
         assert!(0b01100000 == pio.chn[PIO_A].int_control);
-        pio.write_control(PIO_A, 0b10110011);
+        pio.write_control(PIO_A, 0b10110011).unwrap();
         assert!(0b11100000 == pio.chn[PIO_A].int_control);
         assert!(Expect::Any == pio.chn[PIO_A].expect);
     }
+
+    #[test]
+    fn invalid_control() {
+        let mut pio = PIO::new(0);
+        assert_eq!(pio.write_control(PIO_B, 0b10001111), Err(PioError::BidirectionalChannelB));
+        assert!(Mode::Output == pio.chn[PIO_B].mode);
+        assert_eq!(pio.write_control(PIO_A, 0b00000101), Err(PioError::InvalidControlWord(0x05)));
+        assert!(Expect::Any == pio.chn[PIO_A].expect);
+        // the PIO still works afterwards
+        pio.write_control(PIO_A, 0b01001111).unwrap();
+        assert!(Mode::Input == pio.chn[PIO_A].mode);
+    }
+
+    #[test]
+    fn bidirectional() {
+        use {RecordingBus, BusEvent};
+        let mut pio = PIO::new(0);
+        let bus = RecordingBus::new();
+        let ev = |chn, rdy| BusEvent::PioRdy { pio: 0, chn, rdy };
+        let irq = BusEvent::PioIrq { pio: 0, chn: PIO_A, int_vector: 0xE0 };
+        pio.write_control(PIO_A, 0xE0).unwrap();
+        pio.write_control(PIO_A, 0b10001111).unwrap();
+        pio.write_control(PIO_A, 0b10000111).unwrap();
+
+        // output: CPU writes data, ARDY goes high, the data appears on
+        // the port while ASTB is low, ASTB rising edge requests interrupt
+        pio.write_data(&bus, PIO_A, 0x12);
+        pio.strobe(&bus, PIO_A, false);
+        pio.strobe(&bus, PIO_A, true);
+        let events: Vec<BusEvent> = bus.take_events().into_iter().map(|e| e.1).collect();
+        assert_eq!(events,
+                   vec![ev(PIO_A, true),
+                        BusEvent::PioOutp { pio: 0, chn: PIO_A, data: 0x12 },
+                        ev(PIO_A, false),
+                        irq]);
+
+        // input: BSTB low latches the port data, BSTB rising edge
+        // requests interrupt, the CPU read sets BRDY again
+        bus.set_inp_value(0x34);
+        pio.strobe(&bus, PIO_B, false);
+        pio.strobe(&bus, PIO_B, true);
+        assert_eq!(pio.read_data(&bus, PIO_A), 0x34);
+        let events: Vec<BusEvent> = bus.take_events().into_iter().map(|e| e.1).collect();
+        assert_eq!(events,
+                   vec![BusEvent::PioInp { pio: 0, chn: PIO_A }, irq, ev(PIO_B, true)]);
+        pio.strobe(&bus, PIO_B, false);
+        pio.strobe(&bus, PIO_B, true);
+        let events: Vec<BusEvent> = bus.take_events().into_iter().map(|e| e.1).collect();
+        assert_eq!(events,
+                   vec![BusEvent::PioInp { pio: 0, chn: PIO_A }, ev(PIO_B, false), irq]);
+
+        // no interrupts when disabled, unchanged strobe level is ignored
+        pio.write_control(PIO_A, 0b00000011).unwrap();
+        pio.write_data(&bus, PIO_A, 0x56);
+        pio.strobe(&bus, PIO_A, true);
+        pio.strobe(&bus, PIO_A, false);
+        pio.strobe(&bus, PIO_A, false);
+        pio.strobe(&bus, PIO_A, true);
+        let events: Vec<BusEvent> = bus.take_events().into_iter().map(|e| e.1).collect();
+        assert_eq!(events,
+                   vec![ev(PIO_A, true),
+                        BusEvent::PioOutp { pio: 0, chn: PIO_A, data: 0x56 },
+                        ev(PIO_A, false)]);
+    }
+
+    #[test]
+    fn introspection() {
+        let mut pio = PIO::new(0);
+        pio.write_control(PIO_B, 0xE2).unwrap();
+        pio.write_control(PIO_B, 0b11001111).unwrap();
+        pio.write_control(PIO_B, 0x0F).unwrap();
+        pio.write_control(PIO_B, 0b10110111).unwrap();
+        pio.write_control(PIO_B, 0xF0).unwrap();
+        assert_eq!(pio.mode(PIO_A), Mode::Output);
+        assert_eq!(pio.mode(PIO_B), Mode::Bitcontrol);
+        assert_eq!(pio.io_select(PIO_B), 0x0F);
+        assert_eq!(pio.int_mask(PIO_B), 0xF0);
+        assert_eq!(pio.int_vector(PIO_B), 0xE2);
+        assert_eq!(pio.int_control(PIO_B), INTCTRL_ENABLE_INT | INTCTRL_HIGH_LOW | INTCTRL_MASK_FOLLOWS);
+        assert_eq!(pio.output(PIO_B), 0);
+        assert_eq!(pio.input(PIO_B), 0);
+        assert!(!pio.rdy(PIO_A));
+        assert!(!pio.stb(PIO_A));
+    }
+
+    #[test]
+    fn int_status() {
+        use {RecordingBus, BusEvent};
+        let mut pio = PIO::new(0);
+        let bus = RecordingBus::new();
+        // A: bidirectional with interrupts, B: bit control, bits 0..1
+        // are inputs, interrupt when both are low
+        pio.write_control(PIO_A, 0xE0).unwrap();
+        pio.write_control(PIO_A, 0b10001111).unwrap();
+        pio.write_control(PIO_A, 0b10000111).unwrap();
+        pio.write_control(PIO_B, 0xE2).unwrap();
+        pio.write_control(PIO_B, 0b11001111).unwrap();
+        pio.write_control(PIO_B, 0x03).unwrap();
+        pio.write_control(PIO_B, 0b11010111).unwrap();
+        pio.write_control(PIO_B, 0xFC).unwrap();
+        assert!(pio.int_enabled(PIO_A) && pio.int_enabled(PIO_B));
+        assert_eq!(pio.read_control(), 0x80 | 0x0D);
+
+        // the bit control interrupt is edge triggered
+        pio.write(&bus, PIO_B, 0x00);
+        pio.write(&bus, PIO_B, 0x00);
+        let irq_b = BusEvent::PioIrq { pio: 0, chn: PIO_B, int_vector: 0xE2 };
+        let events: Vec<BusEvent> = bus.take_events().into_iter().map(|e| e.1).collect();
+        assert_eq!(events, vec![irq_b]);
+        assert_eq!(pio.pending_irq(), Some((PIO_B, 0xE2)));
+
+        // channel A has priority, and is serviced by the data read
+        bus.set_inp_value(0x34);
+        pio.strobe(&bus, PIO_B, false);
+        pio.strobe(&bus, PIO_B, true);
+        assert!(pio.int_pending(PIO_A));
+        assert_eq!(pio.pending_irq(), Some((PIO_A, 0xE0)));
+        assert_eq!(pio.read_data(&bus, PIO_A), 0x34);
+        assert_eq!(pio.pending_irq(), Some((PIO_B, 0xE2)));
+
+        // disabling interrupts drops the request
+        pio.write_control(PIO_B, 0x03).unwrap();
+        assert!(!pio.int_enabled(PIO_B));
+        assert_eq!(pio.pending_irq(), None);
+        assert_eq!(pio.read_control(), 0x80 | 0x05);
+
+        pio.write_control(PIO_B, 0x83).unwrap();
+        pio.write(&bus, PIO_B, 0x01);
+        pio.write(&bus, PIO_B, 0x00);
+        assert!(pio.int_pending(PIO_B));
+        pio.reset();
+        assert_eq!(pio.pending_irq(), None);
+    }
+
+    #[test]
+    fn decoder() {
+        let mut decode = pio_control_decoder();
+        let mut out = |port, val| decode(IoDir::Out, port, val).unwrap();
+        assert_eq!(out(0x02, 0xE2), "int vector E2h");
+        assert_eq!(out(0x02, 0xCF), "mode bitcontrol, io select follows");
+        assert_eq!(out(0x03, 0x4F), "mode input");
+        assert_eq!(out(0x02, 0x0F), "io select 0Fh");
+        assert_eq!(out(0x02, 0xB7), "int control B7h = int on, OR, high, mask follows");
+        assert_eq!(out(0x02, 0xF0), "int mask F0h");
+        assert_eq!(out(0x03, 0x03), "int off");
+        assert_eq!(out(0x03, 0x01), "invalid control word 01h");
+        assert_eq!(decode(IoDir::In, 0x03, 0x00).unwrap(), "read control");
+    }
 }
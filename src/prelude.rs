@@ -0,0 +1,33 @@
+//! the commonly used rz80 types in one import
+//!
+//! ```
+//! use rz80::prelude::*;
+//!
+//! let mut cpu = CPU::new_64k();
+//! cpu.mem.write(0x0000, &[0xAF]);     // XOR A
+//! cpu.step(&NullBus);
+//! assert_eq!(cpu.reg.f(), ZF | PF);
+//! ```
+//!
+//! This covers the chips, the Bus traits, the register and flag
+//! definitions and the machine and debugging helpers, the more
+//! specialized types (like the video and sound chips, the chip
+//! register constants or the test helpers) are imported from the
+//! crate root.
+
+pub use RegT;
+pub use registers::{Registers, Reg8, Reg16, Im, Flags, CF, NF, VF, PF, XF, HF, YF, ZF, SF};
+pub use memory::Memory;
+pub use cpu::{CPU, CpuVariant, StopReason};
+pub use bus::{Bus, NullBus, ResetKind};
+pub use iobus::IoBus;
+pub use pio::{PIO, PIO_A, PIO_B};
+pub use ctc::{CTC, CTC_0, CTC_1, CTC_2, CTC_3};
+pub use sio::{SIO, SIO_A, SIO_B};
+pub use daisychain::Daisychain;
+pub use machine::{Machine, Clock, FrameTimer, FrameTiming};
+pub use handle::MachineHandle;
+pub use savestate::SaveState;
+pub use symbols::SymbolTable;
+pub use breakpoints::{Breakpoint, Breakpoints};
+pub use watches::{Watch, Watches};
@@ -0,0 +1,355 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use RegT;
+
+const ESC: u8 = 0x1B;
+const TAB_WIDTH: usize = 8;
+
+/// Centronics printer which captures the printed bytes
+///
+/// The printer is connected to the emulated system through the 8
+/// data lines, the /STROBE input and the BUSY output, either on the
+/// data ports of a PIO (see Bus::pio_outp() and Bus::pio_inp()), or
+/// on dedicated printer ports. The system puts a byte on the data lines
+/// with **set_data()**, and pulses /STROBE low with **set_strobe()**, the
+/// printer takes the byte on the falling edge of /STROBE unless it is
+/// busy. After each byte, the printer is busy for the number of cycles
+/// set with **set_busy_cycles()** (default 0), the time is advanced
+/// with **tick()**.
+///
+/// The captured bytes are available with **output()**, or can be saved
+/// to a file. Most printer drivers send ESC/P (Epson) control codes,
+/// **text()** converts the output to plain text (see **escp_to_text()**).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::Printer;
+///
+/// let mut prn = Printer::new();
+/// prn.set_busy_cycles(100);
+/// for &b in b"\x1bEHello\x1bF\r\n" {
+///     // wait until the printer isn't busy
+///     while prn.busy() {
+///         prn.tick(10);
+///     }
+///     prn.set_data(b as i32);
+///     prn.set_strobe(false);
+///     prn.set_strobe(true);
+/// }
+/// assert_eq!(prn.output(), b"\x1bEHello\x1bF\r\n");
+/// assert_eq!(prn.text(), "Hello\n");
+/// ```
+#[derive(Clone,Debug)]
+pub struct Printer {
+    data: u8,
+    strobe: bool,
+    busy_cycles: i64,
+    busy: i64,
+    output: Vec<u8>,
+}
+
+impl Printer {
+    /// create a printer with an empty output buffer
+    pub fn new() -> Printer {
+        Printer {
+            data: 0,
+            strobe: true,
+            busy_cycles: 0,
+            busy: 0,
+            output: Vec::new(),
+        }
+    }
+
+    /// set the number of cycles the printer is busy after each byte
+    pub fn set_busy_cycles(&mut self, cycles: i64) {
+        self.busy_cycles = cycles.max(0);
+    }
+
+    /// set the data lines
+    pub fn set_data(&mut self, val: RegT) {
+        self.data = val as u8;
+    }
+
+    /// set the level of the (active low) /STROBE line, the byte is taken on the falling edge
+    pub fn set_strobe(&mut self, level: bool) {
+        if self.strobe && !level && self.busy == 0 {
+            self.print(self.data);
+        }
+        self.strobe = level;
+    }
+
+    /// the BUSY line, true while the printer doesn't accept a byte
+    pub fn busy(&self) -> bool {
+        self.busy > 0
+    }
+
+    /// advance the time by a number of cycles
+    pub fn tick(&mut self, cycles: i64) {
+        self.busy = (self.busy - cycles).max(0);
+    }
+
+    /// print a byte, bypassing the handshake (e.g. for a dedicated printer port)
+    pub fn print(&mut self, byte: u8) {
+        self.output.push(byte);
+        self.busy = self.busy_cycles;
+    }
+
+    /// the captured bytes
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// remove and return the captured bytes
+    pub fn take_output(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.output)
+    }
+
+    /// the captured bytes converted to plain text
+    pub fn text(&self) -> String {
+        escp_to_text(&self.output)
+    }
+
+    /// write the captured bytes
+    pub fn write(&self, out: &mut dyn io::Write) -> io::Result<()> {
+        out.write_all(&self.output)
+    }
+
+    /// save the captured bytes to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, &self.output)
+    }
+
+    /// save the captured bytes as plain text file
+    pub fn save_text<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.text())
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Printer {
+        Printer::new()
+    }
+}
+
+/// convert printer output with ESC/P control codes to plain text
+///
+/// Escape sequences (including bit image graphics) are skipped, CR
+/// returns to the start of the line and overprints the following
+/// characters (spaces don't erase, and underscores keep the underlined
+/// characters), LF starts a new line, BS and TAB move the print
+/// position, FF is kept as form feed character, and CAN clears the
+/// current line. Characters with bit 7 set are printed
+/// like the same character without bit 7 (italic on Epson printers).
+///
+/// ```
+/// use rz80::escp_to_text;
+///
+/// assert_eq!(escp_to_text(b"\x1b@\x1b-\x01A\x1b-\x00\tB\r\n"), "A       B\n");
+/// // underline by overprinting
+/// assert_eq!(escp_to_text(b"Hello World\r      _____\n"), "Hello World\n");
+/// ```
+pub fn escp_to_text(data: &[u8]) -> String {
+    let mut text = String::new();
+    let mut line: Vec<u8> = Vec::new();
+    let mut col: usize = 0;
+    let mut pos = 0;
+    while pos < data.len() {
+        let c = data[pos] & 0x7F;
+        pos += 1;
+        match c {
+            ESC => pos += escape_len(&data[pos..]),
+            b'\r' => col = 0,
+            b'\n' => {
+                flush_line(&mut text, &mut line);
+                text.push('\n');
+                col = 0;
+            }
+            0x0C => {
+                flush_line(&mut text, &mut line);
+                text.push('\x0C');
+                col = 0;
+            }
+            0x08 => col = col.saturating_sub(1),
+            b'\t' => col = (col / TAB_WIDTH + 1) * TAB_WIDTH,
+            0x18 => {
+                line.clear();
+                col = 0;
+            }
+            0x20..=0x7E => {
+                if line.len() <= col {
+                    line.resize(col + 1, b' ');
+                }
+                if c != b' ' && (c != b'_' || line[col] == b' ') {
+                    line[col] = c;
+                }
+                col += 1;
+            }
+            // other control codes (SO, SI, DC2, DC4, BEL, ...) don't print
+            _ => {}
+        }
+    }
+    flush_line(&mut text, &mut line);
+    text
+}
+
+fn flush_line(text: &mut String, line: &mut Vec<u8>) {
+    text.push_str(String::from_utf8_lossy(line).trim_end());
+    line.clear();
+}
+
+/// number of bytes of an ESC/P sequence after the ESC byte
+fn escape_len(seq: &[u8]) -> usize {
+    let byte = |i: usize| seq.get(i).map_or(0, |&b| b as usize);
+    let len = match seq.first().map(|&b| b & 0x7F) {
+        None => 0,
+        Some(cmd) => match cmd {
+            // bit image graphics with mode, column count and data
+            b'*' => {
+                let bytes_per_column = match byte(1) {
+                    32..=40 => 3,
+                    71..=73 => 6,
+                    _ => 1,
+                };
+                4 + (byte(2) + 256 * byte(3)) * bytes_per_column
+            }
+            b'K' | b'L' | b'Y' | b'Z' => 3 + byte(1) + 256 * byte(2),
+            // tab stops, terminated by NUL
+            b'D' | b'B' => match seq[1..].iter().position(|&b| b == 0) {
+                Some(end) => end + 2,
+                None => seq.len(),
+            },
+            b'$' | b'\\' => 3,
+            // one parameter byte
+            b'-' | b'W' | b'x' | b'k' | b'3' | b'A' | b'J' | b'Q' | b'l' | b'!' | b'S' |
+            b'R' | b't' | b'U' | b'p' | b'w' | b'a' | b'N' | b'+' | b'e' => 2,
+            // page length in lines, or in inches with a NUL byte
+            b'C' => if byte(1) == 0 { 3 } else { 2 },
+            _ => 1,
+        },
+    };
+    len.min(seq.len())
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use {CPU, Bus, PIO, PIO_A, PIO_B};
+
+    // printer data on PIO port A, /STROBE on port B bit 0, BUSY on port B bit 7
+    struct System {
+        pio: RefCell<PIO>,
+        printer: RefCell<Printer>,
+    }
+
+    impl Bus for System {
+        fn cpu_outp(&self, port: RegT, val: RegT) {
+            match port & 0xFF {
+                0x00 => self.pio.borrow_mut().write_data(self, PIO_A, val),
+                0x01 => self.pio.borrow_mut().write_data(self, PIO_B, val),
+                0x02 => self.pio.borrow_mut().write_control(PIO_A, val).unwrap(),
+                0x03 => self.pio.borrow_mut().write_control(PIO_B, val).unwrap(),
+                _ => {}
+            }
+        }
+        fn cpu_inp(&self, port: RegT) -> RegT {
+            match port & 0xFF {
+                0x01 => self.pio.borrow_mut().read_data(self, PIO_B),
+                _ => 0xFF,
+            }
+        }
+        fn pio_outp(&self, _pio: usize, chn: usize, data: RegT) {
+            let mut printer = self.printer.borrow_mut();
+            if chn == PIO_A {
+                printer.set_data(data);
+            } else {
+                printer.set_strobe(data & 1 != 0);
+            }
+        }
+        fn pio_inp(&self, _pio: usize, chn: usize) -> RegT {
+            if chn == PIO_B && self.printer.borrow().busy() {
+                0x80
+            } else {
+                0x00
+            }
+        }
+    }
+
+    #[test]
+    fn pio_handshake() {
+        let sys = System {
+            pio: RefCell::new(PIO::new(0)),
+            printer: RefCell::new(Printer::new()),
+        };
+        sys.printer.borrow_mut().set_busy_cycles(200);
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0000, &[
+            0x3E, 0x0F, 0xD3, 0x02,     // LD A,0x0F; OUT (2),A  (port A: output)
+            0x3E, 0xCF, 0xD3, 0x03,     // LD A,0xCF; OUT (3),A  (port B: bit control)
+            0x3E, 0x80, 0xD3, 0x03,     // LD A,0x80; OUT (3),A  (bit 7 input)
+            0x3E, 0x01, 0xD3, 0x01,     // LD A,0x01; OUT (1),A  (/STROBE high)
+            0x21, 0x40, 0x00,           // LD HL,0x0040
+            // loop:
+            0xDB, 0x01,                 // IN A,(1)
+            0xE6, 0x80,                 // AND 0x80
+            0x20, 0xFA,                 // JR NZ,loop
+            0x7E, 0xD3, 0x00,           // LD A,(HL); OUT (0),A
+            0x3E, 0x00, 0xD3, 0x01,     // LD A,0; OUT (1),A  (/STROBE low)
+            0x3C, 0xD3, 0x01,           // INC A; OUT (1),A   (/STROBE high)
+            0x23,                       // INC HL
+            0x7E, 0xB7,                 // LD A,(HL); OR A
+            0x20, 0xEB,                 // JR NZ,loop
+            0x76,                       // HALT
+        ]);
+        cpu.mem.write(0x0040, b"AB\x1b!\x20CD\r\n\x00");
+        while !cpu.is_halted() {
+            let cycles = cpu.step(&sys);
+            sys.printer.borrow_mut().tick(cycles);
+        }
+        let printer = sys.printer.borrow();
+        assert_eq!(printer.output(), b"AB\x1b!\x20CD\r\n");
+        assert_eq!(printer.text(), "ABCD\n");
+    }
+
+    #[test]
+    fn busy() {
+        let mut prn = Printer::new();
+        prn.set_busy_cycles(50);
+        prn.set_data(0x41);
+        prn.set_strobe(false);
+        assert!(prn.busy());
+        // bytes strobed while busy are lost
+        prn.set_strobe(true);
+        prn.set_data(0x42);
+        prn.set_strobe(false);
+        prn.set_strobe(true);
+        prn.tick(49);
+        assert!(prn.busy());
+        prn.tick(1);
+        assert!(!prn.busy());
+        // only the falling edge prints
+        prn.set_strobe(false);
+        prn.set_strobe(false);
+        assert_eq!(prn.take_output(), b"AB");
+        assert!(prn.output().is_empty());
+    }
+
+    #[test]
+    fn escp() {
+        // bit image graphics, 2 columns in 24-pin mode
+        assert_eq!(escp_to_text(b"A\x1b*\x27\x02\x00\x0D\x0A\x0C\x1B\x1B\x1BB\n"), "AB\n");
+        assert_eq!(escp_to_text(b"A\x1bK\x03\x00\n\n\nB"), "AB");
+        // tab stops and page length
+        assert_eq!(escp_to_text(b"\x1bD\x08\x10\x00\x1bC\x00\x0bX"), "X");
+        assert_eq!(escp_to_text(b"\x1bC\x42X"), "X");
+        // BS, CAN, FF and italic characters
+        assert_eq!(escp_to_text(b"AB\x08C\r\nXY\x18Z\x0CE\xC6"), "AC\nZ\x0CEF");
+        // incomplete sequence at the end
+        assert_eq!(escp_to_text(b"A\x1bK\x10"), "A");
+        // overprinting
+        assert_eq!(escp_to_text(b"A_C\r_  \rXB\n"), "XBC\n");
+    }
+}
@@ -0,0 +1,722 @@
+use std::fmt;
+use RegT;
+use sn76489::SN76489;
+
+/// time base of register dumps (VGM uses 44.1 kHz sample ticks)
+pub const PSG_DUMP_RATE: i64 = 44100;
+
+/// errors when parsing a register dump
+#[derive(Clone,Debug,PartialEq)]
+pub enum PsgDumpError {
+    /// the data is shorter than its header or a command
+    TooShort,
+    /// the data doesn't start with a VGM or YM magic
+    BadMagic,
+    /// the file is LHA compressed (most YM files are), unpack it first
+    Compressed,
+    /// the header of the dump is inconsistent
+    BadHeader,
+    /// an unknown VGM command (command byte and offset)
+    BadCommand(u8, usize),
+}
+
+impl fmt::Display for PsgDumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PsgDumpError::TooShort => write!(f, "register dump is too short"),
+            PsgDumpError::BadMagic => write!(f, "not a VGM or YM file (bad magic)"),
+            PsgDumpError::Compressed => write!(f, "YM file is LHA compressed"),
+            PsgDumpError::BadHeader => write!(f, "register dump has a bad header"),
+            PsgDumpError::BadCommand(cmd, offset) => {
+                write!(f, "unknown VGM command {:02X}h at offset {}", cmd, offset)
+            }
+        }
+    }
+}
+
+/// the sound chip a register dump was recorded from
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum PsgType {
+    SN76489,
+    AY8910,
+}
+
+/// a register write in a register dump
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct PsgWrite {
+    /// the time of the write in 44.1 kHz ticks
+    pub time: u32,
+    /// the register number (always 0 for the SN76489)
+    pub reg: u8,
+    pub val: u8,
+}
+
+/// a sound chip which can be driven by a PsgPlayer
+pub trait PsgChip {
+    /// write a register (the SN76489 ignores the register number)
+    fn write_reg(&mut self, reg: RegT, val: RegT);
+    /// run the chip for a number of clock cycles
+    fn step(&mut self, cycles: i64);
+}
+
+impl PsgChip for SN76489 {
+    fn write_reg(&mut self, _reg: RegT, val: RegT) {
+        self.write(val);
+    }
+    fn step(&mut self, cycles: i64) {
+        SN76489::step(self, cycles);
+    }
+}
+
+/// a sound chip register dump loaded from a .VGM or .YM file
+///
+/// VGM files can contain writes for many chips, only the SN76489 (or,
+/// if the file has no SN76489, the AY-3-8910) writes are kept. YM files
+/// (YM2!, YM3!, YM3b, YM5! and YM6!) are AY-3-8910 register frames, most
+/// YM files are LHA compressed and must be unpacked first, digidrums
+/// and other special effects are ignored.
+#[derive(Clone,Debug)]
+pub struct PsgDump {
+    pub chip: PsgType,
+    /// the chip clock in Hz
+    pub clock_hz: i64,
+    /// the register writes, ordered by time
+    pub writes: Vec<PsgWrite>,
+    /// the length of the dump in 44.1 kHz ticks
+    pub length: u32,
+    /// the loop start in 44.1 kHz ticks
+    pub loop_start: Option<u32>,
+}
+
+fn le32(data: &[u8], offset: usize) -> Result<u32, PsgDumpError> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from(b[0]) | u32::from(b[1]) << 8 | u32::from(b[2]) << 16 | u32::from(b[3]) << 24)
+        .ok_or(PsgDumpError::TooShort)
+}
+
+fn be32(data: &[u8], offset: usize) -> Result<u32, PsgDumpError> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from(b[3]) | u32::from(b[2]) << 8 | u32::from(b[1]) << 16 | u32::from(b[0]) << 24)
+        .ok_or(PsgDumpError::TooShort)
+}
+
+fn be16(data: &[u8], offset: usize) -> Result<u32, PsgDumpError> {
+    data.get(offset..offset + 2)
+        .map(|b| u32::from(b[1]) | u32::from(b[0]) << 8)
+        .ok_or(PsgDumpError::TooShort)
+}
+
+fn byte(data: &[u8], offset: usize) -> Result<u8, PsgDumpError> {
+    data.get(offset).cloned().ok_or(PsgDumpError::TooShort)
+}
+
+impl PsgDump {
+    /// parse a VGM or YM file
+    pub fn parse(data: &[u8]) -> Result<PsgDump, PsgDumpError> {
+        if data.starts_with(b"Vgm ") {
+            PsgDump::parse_vgm(data)
+        } else {
+            PsgDump::parse_ym(data)
+        }
+    }
+
+    /// parse a .VGM file
+    pub fn parse_vgm(data: &[u8]) -> Result<PsgDump, PsgDumpError> {
+        if data.len() < 0x40 {
+            return Err(PsgDumpError::TooShort);
+        }
+        if !data.starts_with(b"Vgm ") {
+            return Err(PsgDumpError::BadMagic);
+        }
+        let version = le32(data, 0x08)?;
+        let sn_clock = le32(data, 0x0C)? & 0x3FFF_FFFF;
+        let loop_offset = le32(data, 0x1C)? as usize;
+        let data_offset = if version >= 0x150 && le32(data, 0x34)? != 0 {
+            0x34 + le32(data, 0x34)? as usize
+        } else {
+            0x40
+        };
+        let ay_clock = if version >= 0x151 && data_offset >= 0x78 {
+            le32(data, 0x74)? & 0x3FFF_FFFF
+        } else {
+            0
+        };
+        let (chip, clock) = if sn_clock != 0 {
+            (PsgType::SN76489, sn_clock)
+        } else if ay_clock != 0 {
+            (PsgType::AY8910, ay_clock)
+        } else {
+            return Err(PsgDumpError::BadHeader);
+        };
+        let loop_pos = if loop_offset != 0 { Some(0x1C + loop_offset) } else { None };
+
+        let mut writes = Vec::new();
+        let mut loop_start = None;
+        let mut time: u32 = 0;
+        let mut pos = data_offset;
+        loop {
+            if Some(pos) == loop_pos {
+                loop_start = Some(time);
+            }
+            let cmd = byte(data, pos)?;
+            let len = match cmd {
+                0x50 => {
+                    if chip == PsgType::SN76489 {
+                        writes.push(PsgWrite { time, reg: 0, val: byte(data, pos + 1)? });
+                    }
+                    2
+                }
+                0xA0 => {
+                    // bit 7 of the register number selects the second chip
+                    let reg = byte(data, pos + 1)?;
+                    if chip == PsgType::AY8910 && reg < 0x80 {
+                        writes.push(PsgWrite { time, reg, val: byte(data, pos + 2)? });
+                    }
+                    3
+                }
+                0x61 => {
+                    time += u32::from(byte(data, pos + 1)?) | u32::from(byte(data, pos + 2)?) << 8;
+                    3
+                }
+                0x62 => {
+                    time += 735;
+                    1
+                }
+                0x63 => {
+                    time += 882;
+                    1
+                }
+                0x66 => break,
+                0x67 => {
+                    // data block: 0x67 0x66 type size32 data
+                    7 + le32(data, pos + 3)? as usize
+                }
+                0x70..=0x7F => {
+                    time += u32::from(cmd & 0x0F) + 1;
+                    1
+                }
+                0x80..=0x8F => {
+                    // YM2612 DAC write and wait
+                    time += u32::from(cmd & 0x0F);
+                    1
+                }
+                // writes to other chips
+                0x30..=0x3F | 0x4F => 2,
+                0x40..=0x4E | 0x51..=0x5F | 0xA1..=0xBF => 3,
+                0xC0..=0xDF => 4,
+                0xE0..=0xFF => 5,
+                _ => return Err(PsgDumpError::BadCommand(cmd, pos)),
+            };
+            pos += len;
+        }
+        Ok(PsgDump {
+            chip,
+            clock_hz: i64::from(clock),
+            writes,
+            length: time,
+            loop_start,
+        })
+    }
+
+    /// parse an uncompressed .YM file
+    pub fn parse_ym(data: &[u8]) -> Result<PsgDump, PsgDumpError> {
+        if data.len() > 7 && &data[2..7] == b"-lh5-" {
+            return Err(PsgDumpError::Compressed);
+        }
+        let magic = data.get(0..4).ok_or(PsgDumpError::TooShort)?;
+        let (frames, num_frames, interleaved, num_regs, clock, rate, loop_frame) = match magic {
+            b"YM2!" | b"YM3!" | b"YM3b" => {
+                let mut len = data.len() - 4;
+                let mut loop_frame = 0;
+                if magic == b"YM3b" {
+                    len = len.checked_sub(4).ok_or(PsgDumpError::TooShort)?;
+                    loop_frame = le32(data, data.len() - 4)?;
+                }
+                (&data[4..4 + len], len / 14, true, 14, 2_000_000, 50, loop_frame)
+            }
+            b"YM5!" | b"YM6!" => {
+                if data.get(4..12) != Some(&b"LeOnArD!"[..]) {
+                    return Err(PsgDumpError::BadMagic);
+                }
+                let num_frames = be32(data, 12)? as usize;
+                let attrs = be32(data, 16)?;
+                let num_drums = be16(data, 20)?;
+                let clock = be32(data, 22)?;
+                let rate = be16(data, 26)?;
+                let loop_frame = be32(data, 28)?;
+                let extra = be16(data, 32)? as usize;
+                let mut pos = 34 + extra;
+                for _ in 0..num_drums {
+                    pos += 4 + be32(data, pos)? as usize;
+                }
+                // song name, author and comment
+                for _ in 0..3 {
+                    let len = data.get(pos..).and_then(|d| d.iter().position(|&b| b == 0));
+                    pos += len.ok_or(PsgDumpError::TooShort)? + 1;
+                }
+                let frames = data.get(pos..pos + num_frames * 16).ok_or(PsgDumpError::TooShort)?;
+                (frames, num_frames, (attrs & 1) != 0, 16, clock, rate, loop_frame)
+            }
+            _ => return Err(PsgDumpError::BadMagic),
+        };
+        if rate == 0 || clock == 0 || (num_frames > 0 && loop_frame as usize >= num_frames) {
+            return Err(PsgDumpError::BadHeader);
+        }
+        let frame_time = |frame: usize| (frame as u64 * PSG_DUMP_RATE as u64 / u64::from(rate)) as u32;
+        let mut writes = Vec::new();
+        for frame in 0..num_frames {
+            let time = frame_time(frame);
+            for reg in 0..14 {
+                let val = if interleaved {
+                    frames[reg * num_frames + frame]
+                } else {
+                    frames[frame * num_regs + reg]
+                };
+                // 0xFF in the envelope shape register means 'don't restart the envelope'
+                if reg == 13 && val == 0xFF {
+                    continue;
+                }
+                writes.push(PsgWrite { time, reg: reg as u8, val });
+            }
+        }
+        Ok(PsgDump {
+            chip: PsgType::AY8910,
+            clock_hz: i64::from(clock),
+            writes,
+            length: frame_time(num_frames),
+            loop_start: Some(frame_time(loop_frame as usize)),
+        })
+    }
+
+    /// write the register dump as .VGM file (version 1.51)
+    pub fn to_vgm(&self) -> Vec<u8> {
+        const DATA_OFFSET: usize = 0x80;
+        let mut data = vec![0u8; DATA_OFFSET];
+        let put32 = |data: &mut Vec<u8>, offset: usize, val: u32| {
+            data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+        };
+        data[0..4].copy_from_slice(b"Vgm ");
+        put32(&mut data, 0x08, 0x151);
+        match self.chip {
+            PsgType::SN76489 => {
+                put32(&mut data, 0x0C, self.clock_hz as u32);
+                // the Sega variant: feedback pattern and 16-bit shift register
+                data[0x28] = 0x09;
+                data[0x2A] = 16;
+            }
+            PsgType::AY8910 => put32(&mut data, 0x74, self.clock_hz as u32),
+        }
+        put32(&mut data, 0x18, self.length);
+        put32(&mut data, 0x34, (DATA_OFFSET - 0x34) as u32);
+
+        let mut time = 0;
+        let mut loop_pos = None;
+        let loop_start = self.loop_start.filter(|&start| start <= self.length);
+        for write in &self.writes {
+            if let Some(start) = loop_start.filter(|&start| loop_pos.is_none() && start <= write.time) {
+                vgm_wait(&mut data, start - time);
+                time = start;
+                loop_pos = Some(data.len());
+            }
+            vgm_wait(&mut data, write.time - time);
+            time = write.time;
+            match self.chip {
+                PsgType::SN76489 => data.extend_from_slice(&[0x50, write.val]),
+                PsgType::AY8910 => data.extend_from_slice(&[0xA0, write.reg, write.val]),
+            }
+        }
+        if let Some(start) = loop_start.filter(|_| loop_pos.is_none()) {
+            vgm_wait(&mut data, start - time);
+            time = start;
+            loop_pos = Some(data.len());
+        }
+        vgm_wait(&mut data, self.length.saturating_sub(time));
+        data.push(0x66);
+        if let Some(pos) = loop_pos {
+            put32(&mut data, 0x1C, (pos - 0x1C) as u32);
+            put32(&mut data, 0x20, self.length - loop_start.unwrap_or(0));
+        }
+        let eof = (data.len() - 4) as u32;
+        put32(&mut data, 0x04, eof);
+        data
+    }
+}
+
+/// append VGM wait commands for a number of 44.1 kHz ticks
+fn vgm_wait(data: &mut Vec<u8>, mut ticks: u32) {
+    while ticks > 0 {
+        let n = ticks.min(0xFFFF);
+        match n {
+            735 => data.push(0x62),
+            882 => data.push(0x63),
+            1..=16 => data.push(0x70 | (n - 1) as u8),
+            _ => data.extend_from_slice(&[0x61, n as u8, (n >> 8) as u8]),
+        }
+        ticks -= n;
+    }
+}
+
+/// records sound chip register writes into a PsgDump
+///
+/// The sound chip modules own a VgmLog while logging is active (see
+/// SN76489::start_log()), the log converts the chip's cycle counter
+/// into VGM time. Use PsgDump::to_vgm() to save the recorded dump.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{PsgDump, SN76489};
+///
+/// let mut psg = SN76489::new(3579545, 44100);
+/// psg.start_log();
+/// psg.write(0x90);
+/// psg.step(3579545 / 60);
+/// psg.write(0x9F);
+/// let dump = psg.stop_log().unwrap().finish(psg.cycles());
+/// assert_eq!(dump.writes.len(), 2);
+/// assert_eq!(dump.writes[1].time, 735);
+///
+/// // save as .VGM file and load it again
+/// let vgm = dump.to_vgm();
+/// assert_eq!(PsgDump::parse(&vgm).unwrap().writes, dump.writes);
+/// ```
+#[derive(Clone,Debug)]
+pub struct VgmLog {
+    dump: PsgDump,
+    start: i64,
+}
+
+impl VgmLog {
+    /// start a log for a chip running at clock_hz, at the chip's current cycle count
+    pub fn new(chip: PsgType, clock_hz: i64, start_cycles: i64) -> VgmLog {
+        VgmLog {
+            dump: PsgDump {
+                chip,
+                clock_hz,
+                writes: Vec::new(),
+                length: 0,
+                loop_start: None,
+            },
+            start: start_cycles,
+        }
+    }
+
+    fn time(&self, cycles: i64) -> u32 {
+        let cycles = (cycles - self.start).max(0);
+        ((cycles * PSG_DUMP_RATE + self.dump.clock_hz / 2) / self.dump.clock_hz) as u32
+    }
+
+    /// record a register write at the chip's cycle count
+    pub fn write(&mut self, cycles: i64, reg: RegT, val: RegT) {
+        let time = self.time(cycles);
+        self.dump.writes.push(PsgWrite { time, reg: reg as u8, val: val as u8 });
+    }
+
+    /// the number of recorded writes
+    pub fn len(&self) -> usize {
+        self.dump.writes.len()
+    }
+
+    /// true if no writes are recorded
+    pub fn is_empty(&self) -> bool {
+        self.dump.writes.is_empty()
+    }
+
+    /// end the log at the chip's cycle count and return the recorded dump
+    pub fn finish(mut self, cycles: i64) -> PsgDump {
+        let last = self.dump.writes.last().map_or(0, |w| w.time);
+        self.dump.length = self.time(cycles).max(last);
+        self.dump
+    }
+}
+
+/// plays a register dump on a sound chip with correct timing
+///
+/// The player is advanced in chip clock cycles (see PsgDump::clock_hz),
+/// it steps the chip and writes the registers at the right times, so
+/// the generated samples can be fetched from the chip as usual.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{PsgDump, PsgPlayer, SN76489};
+///
+/// // a minimal VGM file: SN76489 at 3.58 MHz, a note for 1/60 second
+/// let mut vgm = vec![0u8; 0x40];
+/// vgm[0..4].copy_from_slice(b"Vgm ");
+/// vgm[0x08] = 0x50;
+/// vgm[0x09] = 0x01;
+/// vgm[0x0C..0x10].copy_from_slice(&3579545u32.to_le_bytes());
+/// vgm.extend_from_slice(&[0x50, 0x8E, 0x50, 0x0F, 0x50, 0x90, 0x62, 0x50, 0x9F, 0x66]);
+///
+/// let dump = PsgDump::parse(&vgm).unwrap();
+/// let mut psg = SN76489::new(dump.clock_hz, 44100);
+/// let mut player = PsgPlayer::new(dump);
+/// player.play(&mut psg, 1000);
+/// assert_eq!(psg.tone_period(0), 0x0FE);
+/// assert_eq!(psg.attenuation(0), 0);
+/// player.play(&mut psg, 3579545 / 60);
+/// assert_eq!(psg.attenuation(0), 15);
+/// assert!(player.is_finished());
+/// ```
+pub struct PsgPlayer {
+    dump: PsgDump,
+    looping: bool,
+    pos: usize,
+    /// dump time at the start of the current loop iteration
+    base: u64,
+    /// chip cycles played
+    cycles: i64,
+}
+
+impl PsgPlayer {
+    /// create a player for a register dump
+    pub fn new(dump: PsgDump) -> PsgPlayer {
+        PsgPlayer {
+            dump,
+            looping: false,
+            pos: 0,
+            base: 0,
+            cycles: 0,
+        }
+    }
+
+    /// get the played register dump
+    pub fn dump(&self) -> &PsgDump {
+        &self.dump
+    }
+
+    /// restart at the loop start when the end is reached (default is off)
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    /// restart the dump from the beginning
+    pub fn rewind(&mut self) {
+        self.pos = 0;
+        self.base = 0;
+        self.cycles = 0;
+    }
+
+    /// the number of chip cycles played
+    pub fn cycles(&self) -> i64 {
+        self.cycles
+    }
+
+    /// true if the end of the dump has been played (never while looping)
+    pub fn is_finished(&self) -> bool {
+        !self.can_loop()
+            && self.pos == self.dump.writes.len()
+            && self.cycles >= self.to_cycles(self.base + u64::from(self.dump.length))
+    }
+
+    fn can_loop(&self) -> bool {
+        self.looping && self.dump.loop_start.is_some_and(|start| start < self.dump.length)
+    }
+
+    fn to_cycles(&self, time: u64) -> i64 {
+        (time as i64) * self.dump.clock_hz / PSG_DUMP_RATE
+    }
+
+    /// run the chip for a number of clock cycles and apply the register writes
+    pub fn play(&mut self, chip: &mut dyn PsgChip, cycles: i64) {
+        let end = self.cycles + cycles;
+        loop {
+            if self.pos == self.dump.writes.len() {
+                let song_end = self.base + u64::from(self.dump.length);
+                if !self.can_loop() || self.to_cycles(song_end) > end {
+                    break;
+                }
+                // jump back to the loop start
+                let loop_start = self.dump.loop_start.unwrap_or(0);
+                self.base = song_end - u64::from(loop_start);
+                self.pos = self.dump.writes.partition_point(|w| w.time < loop_start);
+                continue;
+            }
+            let write = self.dump.writes[self.pos];
+            let t = self.to_cycles(self.base + u64::from(write.time));
+            if t > end {
+                break;
+            }
+            if t > self.cycles {
+                chip.step(t - self.cycles);
+                self.cycles = t;
+            }
+            chip.write_reg(RegT::from(write.reg), RegT::from(write.val));
+            self.pos += 1;
+        }
+        if end > self.cycles {
+            chip.step(end - self.cycles);
+            self.cycles = end;
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Recorder {
+        cycles: i64,
+        writes: Vec<(i64, RegT, RegT)>,
+    }
+
+    impl PsgChip for Recorder {
+        fn write_reg(&mut self, reg: RegT, val: RegT) {
+            self.writes.push((self.cycles, reg, val));
+        }
+        fn step(&mut self, cycles: i64) {
+            self.cycles += cycles;
+        }
+    }
+
+    fn vgm(version: u32, commands: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 0x40];
+        data[0..4].copy_from_slice(b"Vgm ");
+        data[0x08..0x0C].copy_from_slice(&version.to_le_bytes());
+        data[0x0C..0x10].copy_from_slice(&44100u32.to_le_bytes());
+        data.extend_from_slice(commands);
+        data
+    }
+
+    #[test]
+    fn parse_vgm() {
+        let data = vgm(0x150, &[
+            0x50, 0x81,             // SN write
+            0x61, 0x10, 0x00,       // wait 16
+            0x4F, 0xFF,             // GG stereo (ignored)
+            0xA0, 0x07, 0x38,       // AY write (ignored)
+            0x67, 0x66, 0x00, 0x02, 0x00, 0x00, 0x00, 0xAA, 0xBB,  // data block
+            0x50, 0x82,             // loop start
+            0x73,                   // wait 4
+            0x50, 0x83,
+            0x62,                   // wait 735
+            0x66,
+        ]);
+        let mut data = data;
+        data[0x1C..0x20].copy_from_slice(&(0x40u32 + 19 - 0x1C).to_le_bytes());
+        let dump = PsgDump::parse(&data).unwrap();
+        assert_eq!(dump.chip, PsgType::SN76489);
+        assert_eq!(dump.clock_hz, 44100);
+        assert_eq!(dump.writes, [
+            PsgWrite { time: 0, reg: 0, val: 0x81 },
+            PsgWrite { time: 16, reg: 0, val: 0x82 },
+            PsgWrite { time: 20, reg: 0, val: 0x83 },
+        ]);
+        assert_eq!(dump.length, 755);
+        assert_eq!(dump.loop_start, Some(16));
+
+        assert_eq!(PsgDump::parse(&data[..0x20]).unwrap_err(), PsgDumpError::TooShort);
+        assert_eq!(PsgDump::parse(&data[..0x45]).unwrap_err(), PsgDumpError::TooShort);
+        assert_eq!(PsgDump::parse(&vgm(0x150, &[0x90, 0x66])).unwrap_err(),
+                   PsgDumpError::BadCommand(0x90, 0x40));
+    }
+
+    #[test]
+    fn parse_ym() {
+        // YM5, 2 frames, not interleaved, 1 digidrum, loop at frame 1
+        let mut data = Vec::new();
+        data.extend_from_slice(b"YM5!LeOnArD!");
+        data.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 0, 0, 1]);
+        data.extend_from_slice(&[0x00, 0x1E, 0x84, 0x80, 0, 50, 0, 0, 0, 1, 0, 0]);
+        data.extend_from_slice(&[0, 0, 0, 2, 0xAA, 0xBB]);
+        data.extend_from_slice(b"song\0author\0\0");
+        for frame in 0..2u8 {
+            for reg in 0..16u8 {
+                data.push(if reg == 13 && frame == 1 { 0xFF } else { frame * 16 + reg });
+            }
+        }
+        data.extend_from_slice(b"End!");
+        let dump = PsgDump::parse(&data).unwrap();
+        assert_eq!(dump.chip, PsgType::AY8910);
+        assert_eq!(dump.clock_hz, 2_000_000);
+        assert_eq!(dump.writes.len(), 27);
+        assert_eq!(dump.writes[13], PsgWrite { time: 0, reg: 13, val: 13 });
+        assert_eq!(dump.writes[14], PsgWrite { time: 882, reg: 0, val: 16 });
+        assert_eq!(dump.writes[26], PsgWrite { time: 882, reg: 12, val: 28 });
+        assert_eq!(dump.length, 1764);
+        assert_eq!(dump.loop_start, Some(882));
+
+        // YM3, interleaved register data
+        let mut data = b"YM3!".to_vec();
+        for reg in 0..14u8 {
+            data.extend_from_slice(&[reg, 0x80 | reg]);
+        }
+        let dump = PsgDump::parse(&data).unwrap();
+        assert_eq!(dump.writes[1], PsgWrite { time: 0, reg: 1, val: 1 });
+        assert_eq!(dump.writes[15], PsgWrite { time: 882, reg: 1, val: 0x81 });
+
+        let mut lha = vec![0u8; 32];
+        lha[2..7].copy_from_slice(b"-lh5-");
+        assert_eq!(PsgDump::parse(&lha).unwrap_err(), PsgDumpError::Compressed);
+        assert_eq!(PsgDump::parse(b"XYZ!1234").unwrap_err(), PsgDumpError::BadMagic);
+    }
+
+    #[test]
+    fn write_vgm() {
+        let dump = PsgDump {
+            chip: PsgType::AY8910,
+            clock_hz: 1_773_400,
+            writes: vec![
+                PsgWrite { time: 0, reg: 7, val: 0x38 },
+                PsgWrite { time: 5, reg: 8, val: 0x0F },
+                PsgWrite { time: 887, reg: 8, val: 0x00 },
+                PsgWrite { time: 70_000, reg: 0, val: 0x10 },
+            ],
+            length: 70_100,
+            loop_start: Some(5),
+        };
+        let vgm = dump.to_vgm();
+        assert_eq!(&vgm[0x80..], &[
+            0xA0, 0x07, 0x38,
+            0x74,                   // wait 5, loop start
+            0xA0, 0x08, 0x0F,
+            0x63,                   // wait 882
+            0xA0, 0x08, 0x00,
+            0x61, 0xFF, 0xFF,       // wait 65535 + 3578
+            0x61, 0xFA, 0x0D,
+            0xA0, 0x00, 0x10,
+            0x61, 0x64, 0x00,       // wait 100
+            0x66][..]);
+        let parsed = PsgDump::parse(&vgm).unwrap();
+        assert_eq!(parsed.chip, PsgType::AY8910);
+        assert_eq!(parsed.clock_hz, 1_773_400);
+        assert_eq!(parsed.writes, dump.writes);
+        assert_eq!(parsed.length, dump.length);
+        assert_eq!(parsed.loop_start, Some(5));
+    }
+
+    #[test]
+    fn play() {
+        // a 44.1 kHz clock, so that chip cycles are dump ticks
+        let dump = PsgDump {
+            chip: PsgType::SN76489,
+            clock_hz: 44100,
+            writes: vec![
+                PsgWrite { time: 0, reg: 0, val: 1 },
+                PsgWrite { time: 10, reg: 0, val: 2 },
+                PsgWrite { time: 30, reg: 0, val: 3 },
+            ],
+            length: 40,
+            loop_start: Some(10),
+        };
+        let mut chip = Recorder { cycles: 0, writes: Vec::new() };
+        let mut player = PsgPlayer::new(dump);
+        player.play(&mut chip, 25);
+        assert_eq!(chip.writes, [(0, 0, 1), (10, 0, 2)]);
+        assert_eq!(chip.cycles, 25);
+        player.play(&mut chip, 100);
+        assert_eq!(chip.writes.len(), 3);
+        assert!(player.is_finished());
+
+        player.rewind();
+        player.set_looping(true);
+        chip = Recorder { cycles: 0, writes: Vec::new() };
+        player.play(&mut chip, 100);
+        assert_eq!(chip.writes, [(0, 0, 1), (10, 0, 2), (30, 0, 3),
+                                 (40, 0, 2), (60, 0, 3), (70, 0, 2), (90, 0, 3), (100, 0, 2)]);
+        assert!(!player.is_finished());
+        assert_eq!(player.cycles(), 100);
+    }
+}
@@ -1,3 +1,4 @@
+use std::fmt;
 use RegT;
 
 /// CPU carry flag
@@ -19,6 +20,91 @@ pub const ZF: RegT = 1 << 6;
 /// CPU sign flag
 pub const SF: RegT = 1 << 7;
 
+// the undocumented flags computed by the CPU, none with the 'fast' feature
+#[cfg(not(feature = "fast"))]
+pub(crate) const XYF: RegT = XF | YF;
+#[cfg(feature = "fast")]
+pub(crate) const XYF: RegT = 0;
+
+/// the CPU status flags as individual bits
+///
+/// Converts from and to the F register value, and displays the flags
+/// as "SZ5H3PNC" string with '-' for cleared flags (5 and 3 are the
+/// undocumented Y and X flags).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, Flags, RegT, ZF, CF};
+///
+/// let mut cpu = CPU::new();
+/// cpu.reg.set_f(ZF | CF);
+/// let flags = cpu.reg.flags();
+/// assert!(flags.z && flags.c && !flags.s);
+/// assert_eq!(flags.to_string(), "-Z-----C");
+///
+/// let flags = Flags { h: true, pv: true, ..Flags::default() };
+/// assert_eq!(RegT::from(flags), 0x14);
+/// cpu.reg.set_flags(flags);
+/// assert_eq!(cpu.reg.f(), 0x14);
+/// assert_eq!(Flags::from(0xFF).to_string(), "SZ5H3PNC");
+/// ```
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct Flags {
+    /// sign flag
+    pub s: bool,
+    /// zero flag
+    pub z: bool,
+    /// undocumented 'Y' flag (bit 5)
+    pub y: bool,
+    /// half carry flag
+    pub h: bool,
+    /// undocumented 'X' flag (bit 3)
+    pub x: bool,
+    /// parity/overflow flag
+    pub pv: bool,
+    /// add/subtract flag
+    pub n: bool,
+    /// carry flag
+    pub c: bool,
+}
+
+impl From<RegT> for Flags {
+    fn from(f: RegT) -> Flags {
+        Flags {
+            s: (f & SF) != 0,
+            z: (f & ZF) != 0,
+            y: (f & YF) != 0,
+            h: (f & HF) != 0,
+            x: (f & XF) != 0,
+            pv: (f & PF) != 0,
+            n: (f & NF) != 0,
+            c: (f & CF) != 0,
+        }
+    }
+}
+
+impl From<Flags> for RegT {
+    fn from(flags: Flags) -> RegT {
+        [(flags.s, SF), (flags.z, ZF), (flags.y, YF), (flags.h, HF),
+         (flags.x, XF), (flags.pv, PF), (flags.n, NF), (flags.c, CF)]
+            .iter()
+            .filter(|&&(set, _)| set)
+            .fold(0, |f, &(_, bit)| f | bit)
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bits = RegT::from(*self);
+        let s: String = "SZ5H3PNC".chars()
+            .enumerate()
+            .map(|(i, c)| if (bits & (0x80 >> i)) != 0 { c } else { '-' })
+            .collect();
+        f.write_str(&s)
+    }
+}
+
 const B: usize = 0;
 const C: usize = 1;
 const D: usize = 2;
@@ -47,19 +133,65 @@ const WZH_: usize = 24;
 const WZL_: usize = 25;
 const NUM_REGS: usize = 26;
 
-pub const BC: usize = 0;
-pub const DE: usize = 2;
-pub const HL: usize = 4;
-pub const AF: usize = 6;
-pub const IX: usize = 8;
-pub const IY: usize = 10;
-pub const SP: usize = 12;
-pub const WZ: usize = 14;
-pub const BC_: usize = 16;
-pub const DE_: usize = 18;
-pub const HL_: usize = 20;
-pub const AF_: usize = 22;
-pub const WZ_: usize = 24;
+pub(crate) const BC: usize = 0;
+pub(crate) const DE: usize = 2;
+pub(crate) const HL: usize = 4;
+pub(crate) const AF: usize = 6;
+pub(crate) const IX: usize = 8;
+pub(crate) const IY: usize = 10;
+pub(crate) const SP: usize = 12;
+pub(crate) const WZ: usize = 14;
+pub(crate) const BC_: usize = 16;
+pub(crate) const DE_: usize = 18;
+pub(crate) const HL_: usize = 20;
+pub(crate) const AF_: usize = 22;
+pub(crate) const WZ_: usize = 24;
+
+// register indices for Reg8 and Reg16 in enum order (I, R and PC are not in the register array)
+const MAP_REG8: [usize; 12] = [A, F, B, C, D, E, H, L, IXH, IXL, IYH, IYL];
+const MAP_REG16: [usize; 14] = [AF, BC, DE, HL, IX, IY, SP, 0, WZ, AF_, BC_, DE_, HL_, WZ_];
+
+/// 8-bit registers for Registers::get8() and Registers::set8()
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Reg8 {
+    A, F, B, C, D, E, H, L,
+    IXH, IXL, IYH, IYL,
+    I, R,
+}
+
+/// 16-bit registers for Registers::get() and Registers::set()
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Reg16 {
+    AF, BC, DE, HL,
+    IX, IY, SP, PC, WZ,
+    AF_, BC_, DE_, HL_, WZ_,
+}
+
+/// the interrupt mode set with the IM instruction
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Im {
+    Zero = 0,
+    One = 1,
+    Two = 2,
+}
+
+impl Im {
+    /// the interrupt mode for a number, None if not 0, 1 or 2
+    pub fn from_bits(val: RegT) -> Option<Im> {
+        match val {
+            0 => Some(Im::Zero),
+            1 => Some(Im::One),
+            2 => Some(Im::Two),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Im {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", *self as RegT)
+    }
+}
 
 /// CPU register access
 ///
@@ -103,13 +235,38 @@ pub const WZ_: usize = 24;
 /// cpu.reg.set_hl(hl);
 /// assert_eq!(cpu.reg.hl(), 0xFFFF);
 /// ```
+///
+/// access registers through the Reg8 and Reg16 enums (e.g. in debuggers):
+///
+/// ```
+/// use rz80::{CPU, Reg8, Reg16};
+///
+/// let mut cpu = CPU::new();
+/// cpu.reg.set(Reg16::HL, 0x1234);
+/// assert_eq!(cpu.reg.get8(Reg8::H), 0x12);
+/// cpu.reg.set8(Reg8::L, 0x56);
+/// assert_eq!(cpu.reg.get(Reg16::HL), 0x1256);
+/// ```
+///
+/// print a register dump:
+///
+/// ```
+/// use rz80::CPU;
+///
+/// let mut cpu = CPU::new();
+/// cpu.reg.set_af(0x12C1);
+/// println!("{}", cpu.reg);
+/// // AF=12C1 BC=0000 DE=0000 HL=0000 IX=0000 IY=0000 SP=0000 PC=0000 [SZ-----C]
+/// // AF'=0000 BC'=0000 DE'=0000 HL'=0000 WZ=0000 I=00 R=00 IM=0
+/// ```
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
 pub struct Registers {
     reg: [u8; NUM_REGS],
     r_pc: u16,
 
     pub i: RegT,
     pub r: RegT,
-    pub im: RegT,
+    im: Im,
 
     m_r: [usize; 8],
     m_r2: [usize; 8],
@@ -125,7 +282,7 @@ impl Registers {
             r_pc: 0,
             i: 0,
             r: 0,
-            im: 0,
+            im: Im::Zero,
             m_r: [B, C, D, E, H, L, F, A],
             m_r2: [B, C, D, E, H, L, F, A],
             m_sp: [BC, DE, HL, SP],
@@ -137,7 +294,7 @@ impl Registers {
     pub fn reset(&mut self) {
         self.r_pc = 0;
         self.set_wz(0);
-        self.im = 0;
+        self.im = Im::Zero;
         self.i = 0;
         self.r = 0;
     }
@@ -198,6 +355,14 @@ impl Registers {
     pub fn set_f(&mut self, v: RegT) {
         self.reg[F] = v as u8;
     }
+    /// get the status flags of the F register
+    pub fn flags(&self) -> Flags {
+        Flags::from(self.f())
+    }
+    /// set the F register from status flags
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.set_f(RegT::from(flags));
+    }
     /// set content of B register
     #[inline(always)]
     pub fn set_b(&mut self, v: RegT) {
@@ -264,6 +429,14 @@ impl Registers {
     pub fn sp(&self) -> RegT {
         (self.reg[SPH] as RegT) << 8 | self.reg[SPL] as RegT
     }
+    /// get the interrupt mode
+    pub fn im(&self) -> Im {
+        self.im
+    }
+    /// set the interrupt mode
+    pub fn set_im(&mut self, im: Im) {
+        self.im = im;
+    }
     /// get content of undocumented WZ register
     #[inline(always)]
     pub fn wz(&self) -> RegT {
@@ -342,11 +515,13 @@ impl Registers {
         self.reg[SPH] = (v >> 8) as u8;
         self.reg[SPL] = v as u8;
     }
-    /// set content of undocumented WZ register
+    /// set content of undocumented WZ register (ignored with the 'fast' feature)
     #[inline(always)]
     pub fn set_wz(&mut self, v: RegT) {
-        self.reg[WZH] = (v >> 8) as u8;
-        self.reg[WZL] = v as u8;
+        if cfg!(not(feature = "fast")) {
+            self.reg[WZH] = (v >> 8) as u8;
+            self.reg[WZL] = v as u8;
+        }
     }
     /// set content of AF' register
     #[inline(always)]
@@ -396,73 +571,118 @@ impl Registers {
         self.r_pc = self.r_pc.wrapping_sub(dec);
     }
 
+    /// get content of an 8-bit register
+    #[inline(always)]
+    pub fn get8(&self, r: Reg8) -> RegT {
+        match r {
+            Reg8::I => self.i,
+            Reg8::R => self.r,
+            _ => self.reg[MAP_REG8[r as usize]] as RegT,
+        }
+    }
+
+    /// set content of an 8-bit register
+    #[inline(always)]
+    pub fn set8(&mut self, r: Reg8, v: RegT) {
+        match r {
+            Reg8::I => self.i = v & 0xFF,
+            Reg8::R => self.r = v & 0xFF,
+            _ => self.reg[MAP_REG8[r as usize]] = v as u8,
+        }
+    }
+
+    /// get content of a 16-bit register
+    #[inline(always)]
+    pub fn get(&self, r: Reg16) -> RegT {
+        match r {
+            Reg16::PC => self.pc(),
+            _ => self.r16i(Registers::index16(r)),
+        }
+    }
+
+    /// set content of a 16-bit register
+    #[inline(always)]
+    pub fn set(&mut self, r: Reg16, v: RegT) {
+        match r {
+            Reg16::PC => self.set_pc(v),
+            _ => self.set_r16i(Registers::index16(r), v),
+        }
+    }
+
+    /// direct index of a 16-bit register (except PC)
+    #[inline(always)]
+    fn index16(r: Reg16) -> usize {
+        debug_assert!(r != Reg16::PC);
+        MAP_REG16[r as usize]
+    }
+
     /// get 8-bit register by index (where index is 3-bit register id from Z80 instruction)
     #[inline(always)]
-    pub fn r8(&self, r: usize) -> RegT {
+    pub(crate) fn r8(&self, r: usize) -> RegT {
         self.reg[self.m_r[r]] as RegT
     }
 
     /// set 8-bit register by index (where index is 3-bit register id from Z80 instruction)
     #[inline(always)]
-    pub fn set_r8(&mut self, r: usize, v: RegT) {
+    pub(crate) fn set_r8(&mut self, r: usize, v: RegT) {
         self.reg[self.m_r[r]] = v as u8;
     }
 
     /// get 8-bit register by index, H,L never patched to IXH,IXL,IYH,IYL
     #[inline(always)]
-    pub fn r8i(&self, r: usize) -> RegT {
+    pub(crate) fn r8i(&self, r: usize) -> RegT {
         self.reg[self.m_r2[r]] as RegT
     }
 
     /// set 8-bit register by index, H,L never patched to IXH,IXL,IYH,IYL
     #[inline(always)]
-    pub fn set_r8i(&mut self, r: usize, v: RegT) {
+    pub(crate) fn set_r8i(&mut self, r: usize, v: RegT) {
         self.reg[self.m_r2[r]] = v as u8;
     }
 
     /// get 16-bit register by direct index (AF, BC, DE, HL, etc)
     #[inline(always)]
-    pub fn r16i(&self, i: usize) -> RegT {
+    pub(crate) fn r16i(&self, i: usize) -> RegT {
         (self.reg[i] as RegT) << 8 | self.reg[i + 1] as RegT
     }
 
     /// set 16-bit register by direct index (AF, BC, DE, ...)
     #[inline(always)]
-    pub fn set_r16i(&mut self, i: usize, v: RegT) {
+    pub(crate) fn set_r16i(&mut self, i: usize, v: RegT) {
         self.reg[i] = (v >> 8) as u8;
         self.reg[i + 1] = v as u8;
     }
 
     /// get 16-bit register by 2-bit index with mapping through SP-table
     #[inline(always)]
-    pub fn r16sp(&self, r: usize) -> RegT {
+    pub(crate) fn r16sp(&self, r: usize) -> RegT {
         let i = self.m_sp[r];
         self.r16i(i)
     }
 
     /// set 16-bit register by 2-bit index with mapping through SP-table
     #[inline(always)]
-    pub fn set_r16sp(&mut self, r: usize, v: RegT) {
+    pub(crate) fn set_r16sp(&mut self, r: usize, v: RegT) {
         let i = self.m_sp[r];
         self.set_r16i(i, v);
     }
 
     /// get 16-bit register by 2-bit index with mapping through AF-table
     #[inline(always)]
-    pub fn r16af(&self, r: usize) -> RegT {
+    pub(crate) fn r16af(&self, r: usize) -> RegT {
         let i = self.m_af[r];
         self.r16i(i)
     }
 
     /// set 16-bit register by 2-bit index with mapping through AF-table
     #[inline(always)]
-    pub fn set_r16af(&mut self, r: usize, v: RegT) {
+    pub(crate) fn set_r16af(&mut self, r: usize, v: RegT) {
         let i = self.m_af[r];
         self.set_r16i(i, v);
     }
 
     /// swap 2 16-bit registers by direct index (HL, BC, DE, ...)
-    pub fn swap(&mut self, i: usize, i_: usize) {
+    pub(crate) fn swap(&mut self, i: usize, i_: usize) {
         let v = self.r16i(i);
         let v_ = self.r16i(i_);
         self.set_r16i(i, v_);
@@ -470,7 +690,7 @@ impl Registers {
     }
 
     /// patch register mapping tables for use of IX instead of HL
-    pub fn patch_ix(&mut self) {
+    pub(crate) fn patch_ix(&mut self) {
         self.m_r[H] = IXH;
         self.m_r[L] = IXL;
         self.m_sp[2] = IX;
@@ -478,7 +698,7 @@ impl Registers {
     }
 
     /// patch register mapping tables for use of IY instead of HL
-    pub fn patch_iy(&mut self) {
+    pub(crate) fn patch_iy(&mut self) {
         self.m_r[H] = IYH;
         self.m_r[L] = IYL;
         self.m_sp[2] = IY;
@@ -486,7 +706,7 @@ impl Registers {
     }
 
     /// unpatch register mapping tables to use HL instead of IX/IY
-    pub fn unpatch(&mut self) {
+    pub(crate) fn unpatch(&mut self) {
         self.m_r[H] = H;
         self.m_r[L] = L;
         self.m_sp[2] = HL;
@@ -494,6 +714,32 @@ impl Registers {
     }
 }
 
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "AF={:04X} BC={:04X} DE={:04X} HL={:04X} IX={:04X} IY={:04X} SP={:04X} PC={:04X} [{}]",
+                 self.af(),
+                 self.bc(),
+                 self.de(),
+                 self.hl(),
+                 self.ix(),
+                 self.iy(),
+                 self.sp(),
+                 self.pc(),
+                 self.flags())?;
+        write!(f,
+               "AF'={:04X} BC'={:04X} DE'={:04X} HL'={:04X} WZ={:04X} I={:02X} R={:02X} IM={}",
+               self.af_(),
+               self.bc_(),
+               self.de_(),
+               self.hl_(),
+               self.wz(),
+               self.i,
+               self.r,
+               self.im)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -525,7 +771,7 @@ mod tests {
         assert_eq!(reg.sp(), 0);
         assert_eq!(reg.r, 0);
         assert_eq!(reg.i, 0);
-        assert_eq!(reg.im, 0);
+        assert_eq!(reg.im(), Im::Zero);
     }
 
     #[test]
@@ -564,4 +810,62 @@ mod tests {
         reg.set_sp(0x3344);
         assert_eq!(reg.sp(), 0x3344);
     }
+
+    #[test]
+    fn get_set_enum() {
+        let mut reg = Registers::new();
+        reg.set(Reg16::AF, 0x1234);
+        reg.set(Reg16::BC, 0x5678);
+        reg.set(Reg16::IX, 0x9ABC);
+        reg.set(Reg16::PC, 0x12345);
+        reg.set(Reg16::HL_, 0xDEF0);
+        assert_eq!(reg.a(), 0x12);
+        assert_eq!(reg.f(), 0x34);
+        assert_eq!(reg.bc(), 0x5678);
+        assert_eq!(reg.ix(), 0x9ABC);
+        assert_eq!(reg.pc(), 0x2345);
+        assert_eq!(reg.hl_(), 0xDEF0);
+        assert_eq!(reg.get(Reg16::PC), 0x2345);
+        assert_eq!(reg.get8(Reg8::C), 0x78);
+        assert_eq!(reg.get8(Reg8::IXH), 0x9A);
+        reg.set8(Reg8::IYL, 0x1FF);
+        reg.set8(Reg8::R, 0x80);
+        assert_eq!(reg.iy(), 0x00FF);
+        assert_eq!(reg.r, 0x80);
+        // the enum API is never affected by IX/IY patching
+        reg.patch_ix();
+        assert_eq!(reg.get(Reg16::HL), 0x0000);
+        assert_eq!(reg.get8(Reg8::H), 0x00);
+        reg.unpatch();
+    }
+
+    #[test]
+    fn display() {
+        let mut reg = Registers::new();
+        reg.set_af(0x12D5);
+        reg.set_hl(0xABCD);
+        reg.set_pc(0x0100);
+        reg.set_bc_(0x4711);
+        reg.i = 0x3F;
+        reg.set_im(Im::Two);
+        assert_eq!(reg.flags().to_string(), "SZ-H-P-C");
+        assert_eq!(format!("{}", reg),
+                   "AF=12D5 BC=0000 DE=0000 HL=ABCD IX=0000 IY=0000 SP=0000 PC=0100 [SZ-H-P-C]\n\
+                    AF'=0000 BC'=4711 DE'=0000 HL'=0000 WZ=0000 I=3F R=00 IM=2");
+    }
+
+    #[test]
+    fn flags() {
+        for f in 0..256 {
+            let flags = Flags::from(f);
+            assert_eq!(RegT::from(flags), f);
+        }
+        let flags = Flags::from(SF | YF | XF | NF);
+        assert_eq!(flags, Flags { s: true, y: true, x: true, n: true, ..Flags::default() });
+        assert_eq!(flags.to_string(), "S-5-3-N-");
+        let mut reg = Registers::new();
+        reg.set_af(0x1200);
+        reg.set_flags(flags);
+        assert_eq!(reg.af(), 0x12AA);
+    }
 }
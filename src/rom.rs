@@ -0,0 +1,271 @@
+use std::fmt;
+use memory::Memory;
+use RegT;
+
+/// incremental CRC32 checksum (IEEE 802.3 polynomial, as used by zip and png)
+#[derive(Clone,Copy)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    /// start a new checksum
+    pub fn new() -> Crc32 {
+        Crc32 { crc: 0xFFFFFFFF }
+    }
+
+    /// feed a single byte into the checksum
+    #[inline(always)]
+    pub fn update(&mut self, byte: u8) {
+        let mut crc = self.crc ^ byte as u32;
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+        self.crc = crc;
+    }
+
+    /// feed a range of bytes into the checksum
+    pub fn update_bytes(&mut self, data: &[u8]) {
+        for b in data {
+            self.update(*b);
+        }
+    }
+
+    /// get the final checksum value
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+/// compute the CRC32 checksum of a byte slice
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update_bytes(data);
+    crc.finish()
+}
+
+/// description of a known-good ROM dump
+#[derive(Clone,Debug)]
+pub struct RomInfo {
+    pub name: String,
+    pub size: usize,
+    pub crc32: u32,
+}
+
+/// reasons why a ROM dump didn't pass verification
+#[derive(Clone,Debug,PartialEq)]
+pub enum RomError {
+    /// no ROM with this name has been registered
+    Unknown(String),
+    /// the ROM dump has the wrong size
+    SizeMismatch {
+        name: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// the ROM dump has the right size, but the wrong content
+    ChecksumMismatch {
+        name: String,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::Unknown(ref name) => write!(f, "unknown ROM '{}'", name),
+            RomError::SizeMismatch { ref name, expected, actual } => {
+                write!(f,
+                       "ROM '{}' has wrong size (expected {} bytes, got {} bytes)",
+                       name,
+                       expected,
+                       actual)
+            }
+            RomError::ChecksumMismatch { ref name, expected, actual } => {
+                write!(f,
+                       "ROM '{}' has wrong checksum (expected {:08X}, got {:08X}), bad dump?",
+                       name,
+                       expected,
+                       actual)
+            }
+        }
+    }
+}
+
+/// ROM dump registry
+///
+/// Holds a list of known-good ROM dumps (name, size and CRC32 checksum)
+/// and verifies ROM data against them, either directly from a byte slice
+/// or after the ROM has been mapped into the CPU address space. This
+/// helps to diagnose 'my emulator shows garbage' problems which are
+/// caused by bad or mismatched ROM dumps.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Memory, RomRegistry, RomError, crc32};
+///
+/// let rom = [0x11u8; 1024];
+/// let mut roms = RomRegistry::new();
+/// roms.register("os", rom.len(), crc32(&rom));
+///
+/// // verify the ROM data before mapping it
+/// assert!(roms.verify("os", &rom).is_ok());
+///
+/// // ...or verify the ROM after it has been mapped into memory
+/// let mut mem = Memory::new();
+/// mem.map_bytes(0, 0x00000, 0xF000, false, &rom);
+/// assert!(roms.verify_mapped("os", &mem, 0xF000).is_ok());
+///
+/// // a corrupted dump is reported as checksum mismatch
+/// let bad = [0x12u8; 1024];
+/// match roms.verify("os", &bad) {
+///     Err(RomError::ChecksumMismatch { .. }) => (),
+///     _ => panic!("bad dump not detected!"),
+/// }
+/// ```
+pub struct RomRegistry {
+    roms: Vec<RomInfo>,
+}
+
+impl RomRegistry {
+    /// create a new, empty ROM registry
+    pub fn new() -> RomRegistry {
+        RomRegistry { roms: Vec::new() }
+    }
+
+    /// register a known-good ROM dump by name, size and CRC32
+    pub fn register(&mut self, name: &str, size: usize, crc32: u32) {
+        self.roms.retain(|rom| rom.name != name);
+        self.roms.push(RomInfo {
+            name: name.to_string(),
+            size,
+            crc32,
+        });
+    }
+
+    /// lookup a registered ROM by name
+    pub fn get(&self, name: &str) -> Option<&RomInfo> {
+        self.roms.iter().find(|rom| rom.name == name)
+    }
+
+    /// find a registered ROM by its content
+    pub fn identify(&self, data: &[u8]) -> Option<&RomInfo> {
+        let crc = crc32(data);
+        self.roms.iter().find(|rom| rom.size == data.len() && rom.crc32 == crc)
+    }
+
+    /// verify a ROM dump against the registered size and checksum
+    pub fn verify(&self, name: &str, data: &[u8]) -> Result<(), RomError> {
+        let rom = self.lookup(name)?;
+        if rom.size != data.len() {
+            return Err(RomError::SizeMismatch {
+                name: rom.name.clone(),
+                expected: rom.size,
+                actual: data.len(),
+            });
+        }
+        self.check(rom, crc32(data))
+    }
+
+    /// verify a ROM which has been mapped to a CPU address
+    pub fn verify_mapped(&self, name: &str, mem: &Memory, addr: RegT) -> Result<(), RomError> {
+        let rom = self.lookup(name)?;
+        self.check(rom, mem.crc32(addr, rom.size))
+    }
+
+    fn lookup(&self, name: &str) -> Result<&RomInfo, RomError> {
+        match self.get(name) {
+            Some(rom) => Ok(rom),
+            None => Err(RomError::Unknown(name.to_string())),
+        }
+    }
+
+    fn check(&self, rom: &RomInfo, actual: u32) -> Result<(), RomError> {
+        if rom.crc32 == actual {
+            Ok(())
+        } else {
+            Err(RomError::ChecksumMismatch {
+                name: rom.name.clone(),
+                expected: rom.crc32,
+                actual,
+            })
+        }
+    }
+}
+
+impl Default for RomRegistry {
+    fn default() -> RomRegistry {
+        RomRegistry::new()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+
+    #[test]
+    fn crc32_check_value() {
+        // the standard CRC32 check value
+        assert_eq!(0xCBF43926, crc32(b"123456789"));
+        assert_eq!(0, crc32(&[]));
+    }
+
+    #[test]
+    fn mem_crc32() {
+        let mut mem = Memory::new_64k();
+        mem.write(0xFFFB, b"123456789");
+        assert_eq!(0xCBF43926, mem.crc32(0xFFFB, 9));
+        assert_eq!(mem.crc32(0x0000, 4), crc32(b"6789"));
+    }
+
+    #[test]
+    fn verify() {
+        let rom = [0x55u8; 2048];
+        let mut roms = RomRegistry::new();
+        roms.register("os", rom.len(), crc32(&rom));
+        assert!(roms.verify("os", &rom).is_ok());
+        assert_eq!(roms.verify("basic", &rom),
+                   Err(RomError::Unknown("basic".to_string())));
+        assert_eq!(roms.verify("os", &rom[..1024]),
+                   Err(RomError::SizeMismatch {
+                       name: "os".to_string(),
+                       expected: 2048,
+                       actual: 1024,
+                   }));
+        let mut bad = rom;
+        bad[100] = 0x56;
+        assert_eq!(roms.verify("os", &bad),
+                   Err(RomError::ChecksumMismatch {
+                       name: "os".to_string(),
+                       expected: crc32(&rom),
+                       actual: crc32(&bad),
+                   }));
+        assert_eq!("os", roms.identify(&rom).unwrap().name);
+        assert!(roms.identify(&bad).is_none());
+    }
+
+    #[test]
+    fn verify_mapped() {
+        let rom = [0x77u8; 1024];
+        let mut roms = RomRegistry::new();
+        roms.register("os", rom.len(), crc32(&rom));
+        let mut mem = Memory::new();
+        assert!(roms.verify_mapped("os", &mem, 0xF000).is_err());
+        mem.map_bytes(0, 0x00000, 0xF000, false, &rom);
+        assert!(roms.verify_mapped("os", &mem, 0xF000).is_ok());
+    }
+}
@@ -0,0 +1,389 @@
+use std::fmt;
+use rom::crc32;
+
+/// the magic bytes at the start of a savestate
+pub const SAVESTATE_MAGIC: &[u8; 8] = b"RZ80SAVE";
+/// the version of the savestate container format
+pub const SAVESTATE_VERSION: u16 = 1;
+
+/// reasons why a savestate couldn't be loaded
+#[derive(Clone,Debug,PartialEq)]
+pub enum StateError {
+    /// the data doesn't start with SAVESTATE_MAGIC
+    BadMagic,
+    /// the container format is newer than this crate version
+    UnsupportedFormat(u16),
+    /// the data ends in the middle of the header, a chunk or a value
+    Truncated,
+    /// the chunk content doesn't match its checksum
+    BadChecksum([u8; 4]),
+    /// the savestate doesn't contain a chunk
+    MissingChunk([u8; 4]),
+    /// the chunk was written by a newer version of the chip
+    UnsupportedChunk {
+        tag: [u8; 4],
+        version: u16,
+    },
+    /// the chunk contains a value which is out of range
+    InvalidValue([u8; 4]),
+}
+
+fn tag_str(tag: &[u8; 4]) -> String {
+    String::from_utf8_lossy(tag).trim_end().to_string()
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "not a savestate"),
+            StateError::UnsupportedFormat(version) => {
+                write!(f, "unsupported savestate format version {}", version)
+            }
+            StateError::Truncated => write!(f, "savestate is truncated"),
+            StateError::BadChecksum(ref tag) => {
+                write!(f, "savestate chunk '{}' has wrong checksum", tag_str(tag))
+            }
+            StateError::MissingChunk(ref tag) => {
+                write!(f, "savestate chunk '{}' is missing", tag_str(tag))
+            }
+            StateError::UnsupportedChunk { ref tag, version } => {
+                write!(f, "unsupported version {} of savestate chunk '{}'", version, tag_str(tag))
+            }
+            StateError::InvalidValue(ref tag) => {
+                write!(f, "savestate chunk '{}' contains an invalid value", tag_str(tag))
+            }
+        }
+    }
+}
+
+/// an object which can be saved into and loaded from a savestate chunk
+///
+/// save_state() writes the current chunk version, load_state() must
+/// be able to read all versions up to STATE_VERSION (see
+/// ChunkReader::version()). Increase STATE_VERSION whenever the
+/// chunk layout changes, and only append new values, so that older
+/// savestates remain loadable.
+pub trait SaveState {
+    /// the chunk version written by save_state()
+    const STATE_VERSION: u16;
+    /// write the state into a chunk
+    fn save_state(&self, w: &mut ChunkWriter);
+    /// read the state from a chunk
+    fn load_state(&mut self, r: &mut ChunkReader) -> Result<(), StateError>;
+}
+
+/// writes the values of a savestate chunk in little-endian byte order
+pub struct ChunkWriter {
+    data: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new() -> ChunkWriter {
+        ChunkWriter { data: Vec::new() }
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// write a bool as 0 or 1
+    pub fn bool(&mut self, val: bool) {
+        self.data.push(val as u8);
+    }
+
+    /// write a byte array with its length
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.data.extend_from_slice(data);
+    }
+}
+
+/// reads the values of a savestate chunk
+pub struct ChunkReader<'a> {
+    tag: [u8; 4],
+    version: u16,
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ChunkReader<'a> {
+    /// the version of the chunk
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// an InvalidValue error for this chunk
+    pub fn invalid(&self) -> StateError {
+        StateError::InvalidValue(self.tag)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.pos + len > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+        let res = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(res)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let mut buf = [0u8; 2];
+        buf.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// read a bool, values other than 0 and 1 are invalid
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(self.invalid()),
+        }
+    }
+
+    /// read a byte array written with ChunkWriter::bytes()
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// builds a savestate from chunks
+///
+/// The savestate layout is (all values little-endian):
+///
+/// ```text
+/// header: magic "RZ80SAVE" (8 bytes), format version (u16), number of chunks (u16)
+/// chunk:  tag (4 bytes), chunk version (u16), length (u32), content, CRC32 of the content (u32)
+/// ```
+///
+/// Each chip is saved into its own chunk, identified by a tag chosen by
+/// the emulated system (for instance "PIO1" and "PIO2" for two PIOs).
+/// The memory mapping is part of the system configuration and not
+/// saved (since ROMs may be mapped from external data), so a savestate
+/// is loaded into a system which has been set up like the saved one.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, PIO, StateWriter, StateReader};
+///
+/// let mut cpu = CPU::new_64k();
+/// let mut pio = PIO::new(0);
+/// cpu.reg.set_hl(0x1234);
+/// cpu.mem.w8(0x4000, 0x55);
+///
+/// let mut w = StateWriter::new();
+/// w.add(b"CPU ", &cpu);
+/// w.add(b"MEM ", &cpu.mem);
+/// w.add(b"PIO ", &pio);
+/// let data = w.finish();
+///
+/// let mut cpu2 = CPU::new_64k();
+/// let r = StateReader::new(&data).unwrap();
+/// r.load(b"CPU ", &mut cpu2).unwrap();
+/// r.load(b"MEM ", &mut cpu2.mem).unwrap();
+/// r.load(b"PIO ", &mut pio).unwrap();
+/// assert_eq!(cpu2.reg.hl(), 0x1234);
+/// assert_eq!(cpu2.mem.r8(0x4000), 0x55);
+/// ```
+pub struct StateWriter {
+    chunks: Vec<([u8; 4], u16, Vec<u8>)>,
+}
+
+impl StateWriter {
+    /// create an empty savestate
+    pub fn new() -> StateWriter {
+        StateWriter { chunks: Vec::new() }
+    }
+
+    /// save an object into a chunk
+    pub fn add<T: SaveState>(&mut self, tag: &[u8; 4], obj: &T) {
+        let mut w = ChunkWriter::new();
+        obj.save_state(&mut w);
+        self.chunks.push((*tag, T::STATE_VERSION, w.data));
+    }
+
+    /// return the savestate data
+    pub fn finish(self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(SAVESTATE_MAGIC);
+        data.extend_from_slice(&SAVESTATE_VERSION.to_le_bytes());
+        data.extend_from_slice(&(self.chunks.len() as u16).to_le_bytes());
+        for (tag, version, content) in self.chunks {
+            data.extend_from_slice(&tag);
+            data.extend_from_slice(&version.to_le_bytes());
+            data.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            data.extend_from_slice(&content);
+            data.extend_from_slice(&crc32(&content).to_le_bytes());
+        }
+        data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> StateWriter {
+        StateWriter::new()
+    }
+}
+
+/// loads objects from a savestate, see StateWriter
+pub struct StateReader<'a> {
+    chunks: Vec<([u8; 4], u16, &'a [u8])>,
+}
+
+impl<'a> StateReader<'a> {
+    /// parse a savestate and verify the chunk checksums
+    pub fn new(data: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        let mut r = ChunkReader {
+            tag: *b"    ",
+            version: 0,
+            data,
+            pos: 0,
+        };
+        if r.take(8).ok() != Some(&SAVESTATE_MAGIC[..]) {
+            return Err(StateError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version > SAVESTATE_VERSION {
+            return Err(StateError::UnsupportedFormat(version));
+        }
+        let num_chunks = r.u16()?;
+        let mut chunks = Vec::new();
+        for _ in 0..num_chunks {
+            let mut tag = [0u8; 4];
+            tag.copy_from_slice(r.take(4)?);
+            let version = r.u16()?;
+            let len = r.u32()? as usize;
+            let content = r.take(len)?;
+            if r.u32()? != crc32(content) {
+                return Err(StateError::BadChecksum(tag));
+            }
+            chunks.push((tag, version, content));
+        }
+        Ok(StateReader { chunks })
+    }
+
+    /// the tags and versions of the chunks in the savestate
+    pub fn chunks(&self) -> Vec<([u8; 4], u16)> {
+        self.chunks.iter().map(|&(tag, version, _)| (tag, version)).collect()
+    }
+
+    /// true if the savestate contains a chunk
+    pub fn has_chunk(&self, tag: &[u8; 4]) -> bool {
+        self.chunks.iter().any(|c| c.0 == *tag)
+    }
+
+    /// load an object from a chunk
+    pub fn load<T: SaveState>(&self, tag: &[u8; 4], obj: &mut T) -> Result<(), StateError> {
+        let &(tag, version, data) = self.chunks.iter()
+            .find(|c| c.0 == *tag)
+            .ok_or(StateError::MissingChunk(*tag))?;
+        if version > T::STATE_VERSION {
+            return Err(StateError::UnsupportedChunk { tag, version });
+        }
+        obj.load_state(&mut ChunkReader { tag, version, data, pos: 0 })
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Thing {
+        a: u8,
+        b: u16,
+        c: bool,
+    }
+
+    impl SaveState for Thing {
+        const STATE_VERSION: u16 = 2;
+        fn save_state(&self, w: &mut ChunkWriter) {
+            w.u8(self.a);
+            w.u16(self.b);
+        }
+        fn load_state(&mut self, r: &mut ChunkReader) -> Result<(), StateError> {
+            self.a = r.u8()?;
+            self.b = r.u16()?;
+            // version 2 added c
+            if r.version() >= 2 {
+                self.c = r.bool()?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let mut w = StateWriter::new();
+        w.add(b"THNG", &Thing { a: 1, b: 0x0302, c: false });
+        let data = w.finish();
+        assert_eq!(&data[..12], b"RZ80SAVE\x01\x00\x01\x00");
+        assert_eq!(&data[12..25], b"THNG\x02\x00\x03\x00\x00\x00\x01\x02\x03");
+        assert_eq!(&data[25..], &crc32(&[1, 2, 3]).to_le_bytes());
+
+        // the saved chunk lacks c, so loading fails
+        let r = StateReader::new(&data).unwrap();
+        assert_eq!(r.chunks(), [(*b"THNG", 2)]);
+        let mut thing = Thing { a: 0, b: 0, c: false };
+        assert_eq!(r.load(b"THNG", &mut thing), Err(StateError::Truncated));
+        assert_eq!(r.load(b"NONE", &mut thing), Err(StateError::MissingChunk(*b"NONE")));
+    }
+
+    #[test]
+    fn versions() {
+        // a version 1 chunk without c
+        let mut data = b"RZ80SAVE\x01\x00\x01\x00THNG\x01\x00\x03\x00\x00\x00\x07\x06\x05".to_vec();
+        data.extend_from_slice(&crc32(&[7, 6, 5]).to_le_bytes());
+        let mut thing = Thing { a: 0, b: 0, c: true };
+        StateReader::new(&data).unwrap().load(b"THNG", &mut thing).unwrap();
+        assert_eq!((thing.a, thing.b, thing.c), (7, 0x0506, true));
+
+        // a newer chunk version is rejected
+        data[16] = 3;
+        assert_eq!(StateReader::new(&data).unwrap().load(b"THNG", &mut thing),
+                   Err(StateError::UnsupportedChunk { tag: *b"THNG", version: 3 }));
+
+        // corrupted content, a newer format, and garbage
+        data[16] = 1;
+        data[22] = 8;
+        assert_eq!(StateReader::new(&data).err(), Some(StateError::BadChecksum(*b"THNG")));
+        data[8] = 2;
+        assert_eq!(StateReader::new(&data).err(), Some(StateError::UnsupportedFormat(2)));
+        assert_eq!(StateReader::new(&data[..20]).err(), Some(StateError::UnsupportedFormat(2)));
+        data[8] = 1;
+        assert_eq!(StateReader::new(&data[..20]).err(), Some(StateError::Truncated));
+        assert_eq!(StateReader::new(b"RZ80").err(), Some(StateError::BadMagic));
+        assert_eq!(StateError::BadChecksum(*b"CPU ").to_string(),
+                   "savestate chunk 'CPU' has wrong checksum");
+    }
+}
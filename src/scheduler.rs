@@ -0,0 +1,304 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use bus::Bus;
+use machine::FrameTiming;
+
+#[derive(Clone,Debug)]
+struct Entry<E> {
+    time: i64,
+    seq: u64,
+    event: E,
+}
+
+// the BinaryHeap is a max-heap, so the ordering is reversed to
+// get the earliest event first, events scheduled for the same
+// time are ordered by insertion
+impl<E> Ord for Entry<E> {
+    fn cmp(&self, other: &Entry<E>) -> Ordering {
+        other.time.cmp(&self.time).then(other.seq.cmp(&self.seq))
+    }
+}
+impl<E> PartialOrd for Entry<E> {
+    fn partial_cmp(&self, other: &Entry<E>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<E> PartialEq for Entry<E> {
+    fn eq(&self, other: &Entry<E>) -> bool {
+        self.time == other.time && self.seq == other.seq
+    }
+}
+impl<E> Eq for Entry<E> {}
+
+/// cycle-stamped event queue
+///
+/// The Scheduler keeps track of the current time (in CPU cycles) and
+/// a queue of future events. Instead of polling a chip after each
+/// CPU instruction (e.g. calling CTC::update_timers()), the run loop
+/// schedules an event for the point in time when something interesting
+/// happens (e.g. a CTC timer reaching zero, see CTC::next_event()),
+/// and only needs to check for due events after each instruction.
+///
+/// Chips which don't raise interrupts (like the Tape or the Beeper)
+/// don't need events, they are brought up to the current time when the
+/// CPU accesses them (see the Z1013 example). The KC87, KC85/4, RC2014
+/// and Z1013 examples run their CPU loop this way, and Machine uses a
+/// Scheduler for the end of each frame and the due key events.
+///
+/// The event type E is defined by the emulated system, usually an
+/// enum which identifies the chip or action the event is for.
+///
+/// With a frame timing (see **set_frame_timing()**), the scheduler
+/// also raises the vertical blank of each frame: **update_vblank()**
+/// calls Bus::vblank() when the vertical blank has started, so that
+/// the machine can request its frame interrupt, and the front-end
+/// can present the frame.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::Scheduler;
+///
+/// #[derive(Clone,Copy,PartialEq,Debug)]
+/// enum Event {
+///     Timer,
+///     Raster(i32),
+/// }
+///
+/// let mut sched = Scheduler::new();
+/// sched.schedule(100, Event::Timer);
+/// sched.schedule(50, Event::Raster(1));
+/// assert_eq!(sched.cycles_to_next(), Some(50));
+///
+/// // advance time (e.g. by the cycles returned from CPU::step())
+/// sched.advance(60);
+/// assert_eq!(sched.pop(), Some((50, Event::Raster(1))));
+/// assert_eq!(sched.pop(), None);
+/// assert_eq!(sched.cycles_to_next(), Some(40));
+/// ```
+#[derive(Clone,Debug)]
+pub struct Scheduler<E> {
+    now: i64,
+    seq: u64,
+    events: BinaryHeap<Entry<E>>,
+    frame_timing: Option<FrameTiming>,
+    next_vblank: i64,
+}
+
+impl<E> Scheduler<E> {
+    /// create a new, empty scheduler at time 0
+    pub fn new() -> Scheduler<E> {
+        Scheduler {
+            now: 0,
+            seq: 0,
+            events: BinaryHeap::new(),
+            frame_timing: None,
+            next_vblank: 0,
+        }
+    }
+
+    /// get the current time in cycles
+    pub fn now(&self) -> i64 {
+        self.now
+    }
+
+    /// advance the current time by a number of cycles
+    #[inline(always)]
+    pub fn advance(&mut self, cycles: i64) {
+        self.now += cycles;
+    }
+
+    /// schedule an event in a number of cycles from now
+    pub fn schedule(&mut self, delay: i64, event: E) {
+        let time = self.now + delay;
+        self.schedule_at(time, event);
+    }
+
+    /// schedule an event at an absolute time
+    pub fn schedule_at(&mut self, time: i64, event: E) {
+        self.seq += 1;
+        self.events.push(Entry {
+            time,
+            seq: self.seq,
+            event,
+        });
+    }
+
+    /// remove all events for which the filter function returns true
+    pub fn cancel<F>(&mut self, mut filter: F)
+        where F: FnMut(&E) -> bool
+    {
+        let mut events = BinaryHeap::new();
+        ::std::mem::swap(&mut events, &mut self.events);
+        self.events = events.into_iter().filter(|e| !filter(&e.event)).collect();
+    }
+
+    /// remove all events
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    /// get the time of the next event (or of the next vertical blank)
+    pub fn next_time(&self) -> Option<i64> {
+        let next = self.events.peek().map(|e| e.time);
+        match self.frame_timing {
+            Some(_) => Some(next.map_or(self.next_vblank, |t| t.min(self.next_vblank))),
+            None => next,
+        }
+    }
+
+    /// get the number of cycles until the next event (0 if already due)
+    pub fn cycles_to_next(&self) -> Option<i64> {
+        self.next_time().map(|t| if t > self.now { t - self.now } else { 0 })
+    }
+
+    /// pop the next due event, returns the time the event was scheduled for
+    #[inline(always)]
+    pub fn pop(&mut self) -> Option<(i64, E)> {
+        let due = match self.events.peek() {
+            Some(e) => e.time <= self.now,
+            None => false,
+        };
+        if due {
+            self.events.pop().map(|e| (e.time, e.event))
+        } else {
+            None
+        }
+    }
+}
+
+impl<E> Scheduler<E> {
+    /// set the frame timing for raising the vertical blank, the frame starts now
+    pub fn set_frame_timing(&mut self, timing: Option<FrameTiming>) {
+        self.frame_timing = timing;
+        if let Some(timing) = timing {
+            self.next_vblank = self.now + timing.vblank_pos();
+        }
+    }
+
+    /// the frame timing
+    pub fn frame_timing(&self) -> Option<FrameTiming> {
+        self.frame_timing
+    }
+
+    /// the time of the next vertical blank
+    pub fn next_vblank(&self) -> Option<i64> {
+        self.frame_timing.map(|_| self.next_vblank)
+    }
+
+    /// the cycle position in the current frame
+    pub fn frame_pos(&self) -> Option<i64> {
+        self.frame_timing.map(|timing| {
+            let frame = timing.cycles_per_frame();
+            (self.now - self.next_vblank + timing.vblank_pos()).rem_euclid(frame)
+        })
+    }
+
+    /// call Bus::vblank() if the vertical blank has started, returns true if it has
+    ///
+    /// This is called after advance(), if more than one frame has passed
+    /// since the last call, the vertical blank is only raised once.
+    pub fn update_vblank(&mut self, bus: &dyn Bus) -> bool {
+        match self.frame_timing {
+            Some(timing) if self.now >= self.next_vblank => {
+                let frame = timing.cycles_per_frame();
+                let frames = (self.now - self.next_vblank) / frame + 1;
+                self.next_vblank += frames * frame;
+                bus.vblank();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<E> Default for Scheduler<E> {
+    fn default() -> Scheduler<E> {
+        Scheduler::new()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn ordering() {
+        let mut sched = Scheduler::new();
+        assert_eq!(sched.next_time(), None);
+        sched.schedule(30, 'c');
+        sched.schedule(10, 'a');
+        sched.schedule(20, 'b');
+        sched.schedule(10, 'x');
+        assert_eq!(sched.next_time(), Some(10));
+        assert_eq!(sched.pop(), None);
+        sched.advance(25);
+        assert_eq!(sched.now(), 25);
+        assert_eq!(sched.pop(), Some((10, 'a')));
+        assert_eq!(sched.pop(), Some((10, 'x')));
+        assert_eq!(sched.pop(), Some((20, 'b')));
+        assert_eq!(sched.pop(), None);
+        assert_eq!(sched.cycles_to_next(), Some(5));
+        sched.advance(10);
+        assert_eq!(sched.cycles_to_next(), Some(0));
+        assert_eq!(sched.pop(), Some((30, 'c')));
+        assert_eq!(sched.cycles_to_next(), None);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut sched = Scheduler::new();
+        sched.schedule_at(10, 1);
+        sched.schedule_at(20, 2);
+        sched.schedule_at(30, 1);
+        sched.cancel(|e| *e == 1);
+        assert_eq!(sched.next_time(), Some(20));
+        sched.advance(100);
+        assert_eq!(sched.pop(), Some((20, 2)));
+        assert_eq!(sched.pop(), None);
+        sched.schedule(5, 3);
+        sched.clear();
+        assert_eq!(sched.next_time(), None);
+    }
+
+    struct VblankBus {
+        count: Cell<usize>,
+    }
+    impl Bus for VblankBus {
+        fn vblank(&self) {
+            self.count.set(self.count.get() + 1);
+        }
+    }
+
+    #[test]
+    fn vblank() {
+        let bus = VblankBus { count: Cell::new(0) };
+        let mut sched: Scheduler<()> = Scheduler::new();
+        sched.advance(100);
+        assert!(!sched.update_vblank(&bus));
+        assert_eq!(sched.frame_pos(), None);
+
+        // 10 lines with 20 cycles, the vertical blank starts at line 8
+        sched.set_frame_timing(Some(FrameTiming::new(10, 20).with_vblank_line(8)));
+        assert_eq!(sched.next_vblank(), Some(260));
+        assert_eq!(sched.frame_pos(), Some(0));
+        sched.schedule(300, ());
+        assert_eq!(sched.cycles_to_next(), Some(160));
+        sched.advance(159);
+        assert_eq!(sched.frame_pos(), Some(159));
+        assert!(!sched.update_vblank(&bus));
+        sched.advance(1);
+        assert!(sched.update_vblank(&bus));
+        assert!(!sched.update_vblank(&bus));
+        assert_eq!((bus.count.get(), sched.next_vblank()), (1, Some(460)));
+        assert_eq!(sched.cycles_to_next(), Some(140));
+
+        // a vertical blank is only raised once after a long stall
+        sched.advance(1000);
+        assert!(sched.update_vblank(&bus));
+        assert_eq!((bus.count.get(), sched.next_vblank()), (2, Some(1460)));
+        assert_eq!(sched.frame_pos(), Some(160));
+    }
+}
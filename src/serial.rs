@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// host side of an emulated serial port
+///
+/// A serial chip emulation forwards the bytes transmitted by the
+/// emulated system to **send()**, and polls **recv()** for received
+/// bytes (for instance once per character time at the emulated baud
+/// rate). Both functions must not block the emulation.
+pub trait SerialTransport {
+    /// transmit a byte from the emulated system to the host side
+    fn send(&mut self, byte: u8);
+    /// receive a byte from the host side, None if no byte is available
+    fn recv(&mut self) -> Option<u8>;
+}
+
+/// in-memory serial transport with an input queue and output buffer
+///
+/// Useful for tests and scripted input: bytes queued with **type_bytes()**
+/// are received by the emulated system, and the bytes it transmits are
+/// collected in **output**.
+///
+/// ```
+/// use rz80::{SerialTransport, MemorySerial};
+///
+/// let mut serial = MemorySerial::new();
+/// serial.type_bytes(b"DIR\r");
+/// assert_eq!(serial.recv(), Some(b'D'));
+/// serial.send(b'A');
+/// assert_eq!(serial.output, b"A");
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct MemorySerial {
+    /// bytes waiting to be received by the emulated system
+    pub input: VecDeque<u8>,
+    /// bytes transmitted by the emulated system
+    pub output: Vec<u8>,
+}
+
+impl MemorySerial {
+    /// create an empty in-memory transport
+    pub fn new() -> MemorySerial {
+        MemorySerial::default()
+    }
+
+    /// queue bytes for the emulated system
+    pub fn type_bytes(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    /// the transmitted bytes as (lossy) UTF-8 string
+    pub fn output_str(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+impl SerialTransport for MemorySerial {
+    fn send(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+    fn recv(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+}
+
+/// serial transport which sends every transmitted byte back to the emulated system
+#[derive(Clone,Debug,Default)]
+pub struct Loopback {
+    buf: VecDeque<u8>,
+}
+
+impl Loopback {
+    /// create a loopback transport
+    pub fn new() -> Loopback {
+        Loopback::default()
+    }
+}
+
+impl SerialTransport for Loopback {
+    fn send(&mut self, byte: u8) {
+        self.buf.push_back(byte);
+    }
+    fn recv(&mut self) -> Option<u8> {
+        self.buf.pop_front()
+    }
+}
+
+/// serial transport over a non-blocking TCP connection
+///
+/// Connect a terminal program with e.g. `telnet localhost 2323` or
+/// `nc localhost 2323` to the emulated serial port. Transmitted bytes
+/// which can't be written without blocking are buffered, received
+/// bytes are only returned while the connection is open.
+#[derive(Debug)]
+pub struct TcpSerial {
+    stream: TcpStream,
+    pending: VecDeque<u8>,
+    connected: bool,
+}
+
+impl TcpSerial {
+    /// connect to a TCP server
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpSerial> {
+        TcpSerial::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// wait for a single incoming connection (blocks until a client connects)
+    pub fn accept<A: ToSocketAddrs>(addr: A) -> io::Result<TcpSerial> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        TcpSerial::from_stream(stream)
+    }
+
+    /// use an already connected stream (switched to non-blocking mode)
+    pub fn from_stream(stream: TcpStream) -> io::Result<TcpSerial> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(TcpSerial {
+            stream,
+            pending: VecDeque::new(),
+            connected: true,
+        })
+    }
+
+    /// false after the remote side has closed the connection or an I/O error occurred
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// try to write the buffered transmitted bytes
+    fn flush_pending(&mut self) {
+        while self.connected && !self.pending.is_empty() {
+            let res = {
+                let (front, _) = self.pending.as_slices();
+                self.stream.write(front)
+            };
+            match res {
+                Ok(0) => self.connected = false,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => self.connected = false,
+            }
+        }
+    }
+}
+
+impl SerialTransport for TcpSerial {
+    fn send(&mut self, byte: u8) {
+        if self.connected {
+            self.pending.push_back(byte);
+            self.flush_pending();
+        }
+    }
+    fn recv(&mut self) -> Option<u8> {
+        self.flush_pending();
+        if !self.connected {
+            return None;
+        }
+        let mut byte = [0u8; 1];
+        match self.stream.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            Ok(_) => {
+                self.connected = false;
+                None
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                          e.kind() == io::ErrorKind::Interrupted => None,
+            Err(_) => {
+                self.connected = false;
+                None
+            }
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn loopback() {
+        let mut serial = Loopback::new();
+        assert_eq!(serial.recv(), None);
+        serial.send(1);
+        serial.send(2);
+        assert_eq!(serial.recv(), Some(1));
+        assert_eq!(serial.recv(), Some(2));
+        assert_eq!(serial.recv(), None);
+    }
+
+    #[test]
+    fn tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"HI").unwrap();
+            let mut buf = [0u8; 3];
+            stream.read_exact(&mut buf).unwrap();
+            buf
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut serial = TcpSerial::from_stream(stream).unwrap();
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            match serial.recv() {
+                Some(b) => received.push(b),
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        assert_eq!(received, b"HI");
+        for &b in b"OK!" {
+            serial.send(b);
+        }
+        assert_eq!(&client.join().unwrap(), b"OK!");
+        assert!(serial.is_connected());
+    }
+}
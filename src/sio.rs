@@ -0,0 +1,397 @@
+use std::collections::VecDeque;
+use RegT;
+use bus::{Bus, Reset, ResetKind};
+
+/// SIO channel A
+pub const SIO_A: usize = 0;
+/// SIO channel B
+pub const SIO_B: usize = 1;
+const NUM_CHANNELS: usize = 2;
+const RX_FIFO_SIZE: usize = 3;
+
+/// RR0: a received character is available
+pub const SIO_RR0_RX_AVAILABLE: u8 = 1 << 0;
+/// RR0: an interrupt is pending (only channel A)
+pub const SIO_RR0_INT_PENDING: u8 = 1 << 1;
+/// RR0: the transmit buffer is empty
+pub const SIO_RR0_TX_EMPTY: u8 = 1 << 2;
+/// RR0: state of the DCD input
+pub const SIO_RR0_DCD: u8 = 1 << 3;
+/// RR0: state of the CTS input
+pub const SIO_RR0_CTS: u8 = 1 << 5;
+/// RR1: all characters have been sent
+pub const SIO_RR1_ALL_SENT: u8 = 1 << 0;
+/// RR1: a received character has been lost because the receive FIFO was full
+pub const SIO_RR1_RX_OVERRUN: u8 = 1 << 5;
+
+const WR1_TX_INT_ENABLE: u8 = 1 << 1;
+const WR1_STATUS_AFFECTS_VECTOR: u8 = 1 << 2;
+const WR1_RX_INT_MASK: u8 = 3 << 3;
+const WR1_RX_INT_FIRST: u8 = 1 << 3;
+const WR3_RX_ENABLE: u8 = 1 << 0;
+const WR5_TX_ENABLE: u8 = 1 << 3;
+
+// interrupt conditions, used for the 'status affects vector' mode
+const COND_TX: u8 = 0;
+const COND_RX: u8 = 2;
+const COND_NONE: u8 = 3;
+
+#[derive(Clone, Debug)]
+struct Channel {
+    pub wr: [u8; 8], // write registers
+    pub ptr: usize, // register pointer for the next control access
+    pub rx_fifo: VecDeque<u8>,
+    pub rx_overrun: bool,
+    pub rx_int_armed: bool, // 'interrupt on first character' is armed
+    pub rx_int_pending: bool,
+    pub tx_int_pending: bool,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            wr: [0; 8],
+            ptr: 0,
+            rx_fifo: VecDeque::with_capacity(RX_FIFO_SIZE),
+            rx_overrun: false,
+            rx_int_armed: true,
+            rx_int_pending: false,
+            tx_int_pending: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        let wr2 = self.wr[2];
+        *self = Channel::new();
+        self.wr[2] = wr2;
+    }
+}
+
+/// Z80 SIO emulation (asynchronous mode)
+///
+/// Emulates the 2 serial channels of the Z80 SIO in asynchronous mode
+/// with a 3-byte receive FIFO and transmit, receive and 'status affects
+/// vector' interrupts. The synchronous modes, the external/status
+/// interrupts and the modem control lines are not emulated (CTS and DCD
+/// always read as active). Characters are transferred instantly, the
+/// system decides how fast characters arrive by calling **receive()**,
+/// usually paced by a CTC channel acting as baud rate generator.
+///
+/// Transmitted characters are forwarded to **Bus::sio_outp()**, and
+/// interrupt requests to **Bus::sio_irq()**.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::RefCell;
+/// use rz80::{Bus, SIO, SIO_A, RegT};
+///
+/// struct System {
+///     out: RefCell<Vec<u8>>,
+/// }
+/// impl Bus for System {
+///     fn sio_outp(&self, _sio: usize, _chn: usize, data: RegT) {
+///         self.out.borrow_mut().push(data as u8);
+///     }
+/// }
+///
+/// let sys = System { out: RefCell::new(Vec::new()) };
+/// let mut sio = SIO::new(0);
+/// // WR3: receiver enable, 8 bits; WR5: transmitter enable, 8 bits
+/// for &val in &[0x03, 0xC1, 0x05, 0x68] {
+///     sio.write_control(SIO_A, val);
+/// }
+/// sio.write_data(&sys, SIO_A, 'A' as RegT);
+/// assert!(sio.receive(&sys, SIO_A, 'B' as u8));
+/// assert_eq!(sio.read_data(SIO_A), 'B' as RegT);
+/// assert_eq!(*sys.out.borrow(), b"A");
+/// ```
+#[derive(Debug)]
+pub struct SIO {
+    id: usize,
+    chn: [Channel; NUM_CHANNELS],
+}
+
+impl SIO {
+    /// initialize new SIO object
+    pub fn new(id: usize) -> SIO {
+        SIO {
+            id,
+            chn: [Channel::new(), Channel::new()],
+        }
+    }
+
+    /// reset the SIO (the interrupt vector is preserved)
+    pub fn reset(&mut self) {
+        for chn in &mut self.chn {
+            chn.reset();
+        }
+    }
+
+    /// write to control register (WR0, or the register selected by WR0)
+    pub fn write_control(&mut self, chn: usize, val: RegT) {
+        let val = val as u8;
+        let ptr = self.chn[chn].ptr;
+        if ptr != 0 {
+            self.chn[chn].wr[ptr] = val;
+            self.chn[chn].ptr = 0;
+            return;
+        }
+        let c = &mut self.chn[chn];
+        c.wr[0] = val;
+        c.ptr = (val & 7) as usize;
+        match (val >> 3) & 7 {
+            // channel reset
+            3 => c.reset(),
+            // enable interrupt on next received character
+            4 => c.rx_int_armed = true,
+            // reset transmitter interrupt pending
+            5 => c.tx_int_pending = false,
+            // error reset
+            6 => c.rx_overrun = false,
+            // return from interrupt: the daisychain takes care of this,
+            // null command, send abort and reset ext/status interrupts
+            _ => {}
+        }
+        self.update_int_flags();
+    }
+
+    /// read control register (RR0, or the register selected by WR0)
+    pub fn read_control(&mut self, chn: usize) -> RegT {
+        let ptr = self.chn[chn].ptr;
+        self.chn[chn].ptr = 0;
+        let c = &self.chn[chn];
+        match ptr {
+            0 => {
+                let mut rr0 = SIO_RR0_TX_EMPTY | SIO_RR0_DCD | SIO_RR0_CTS;
+                if !c.rx_fifo.is_empty() {
+                    rr0 |= SIO_RR0_RX_AVAILABLE;
+                }
+                if chn == SIO_A && self.int_pending() {
+                    rr0 |= SIO_RR0_INT_PENDING;
+                }
+                rr0 as RegT
+            }
+            1 => {
+                let mut rr1 = SIO_RR1_ALL_SENT;
+                if c.rx_overrun {
+                    rr1 |= SIO_RR1_RX_OVERRUN;
+                }
+                rr1 as RegT
+            }
+            2 if chn == SIO_B => self.pending_vector(),
+            _ => 0,
+        }
+    }
+
+    /// write data to transmit, the character is forwarded to Bus::sio_outp()
+    pub fn write_data(&mut self, bus: &dyn Bus, chn: usize, data: RegT) {
+        if (self.chn[chn].wr[5] & WR5_TX_ENABLE) == 0 {
+            return;
+        }
+        self.chn[chn].tx_int_pending = false;
+        bus.sio_outp(self.id, chn, data & 0xFF);
+        // the transmit buffer is empty again
+        if (self.chn[chn].wr[1] & WR1_TX_INT_ENABLE) != 0 {
+            self.chn[chn].tx_int_pending = true;
+            let vec = self.int_vector(chn, COND_TX);
+            bus.sio_irq(self.id, chn, vec);
+        }
+    }
+
+    /// read the next received character (0xFF if the receive FIFO is empty)
+    pub fn read_data(&mut self, chn: usize) -> RegT {
+        let c = &mut self.chn[chn];
+        let data = c.rx_fifo.pop_front().map_or(0xFF, |b| b as RegT);
+        if c.rx_fifo.is_empty() {
+            c.rx_int_pending = false;
+        }
+        data
+    }
+
+    /// true if the receiver is enabled and there's room in the receive FIFO
+    pub fn rx_ready(&self, chn: usize) -> bool {
+        let c = &self.chn[chn];
+        (c.wr[3] & WR3_RX_ENABLE) != 0 && c.rx_fifo.len() < RX_FIFO_SIZE
+    }
+
+    /// a character arrives from the serial line, return false if the receiver is disabled
+    ///
+    /// If the receive FIFO is full, the last character is overwritten
+    /// and the overrun flag in RR1 is set.
+    pub fn receive(&mut self, bus: &dyn Bus, chn: usize, data: u8) -> bool {
+        let irq = {
+            let c = &mut self.chn[chn];
+            if (c.wr[3] & WR3_RX_ENABLE) == 0 {
+                return false;
+            }
+            if c.rx_fifo.len() == RX_FIFO_SIZE {
+                c.rx_fifo.pop_back();
+                c.rx_overrun = true;
+            }
+            c.rx_fifo.push_back(data);
+            match c.wr[1] & WR1_RX_INT_MASK {
+                0 => false,
+                WR1_RX_INT_FIRST => {
+                    let armed = c.rx_int_armed;
+                    c.rx_int_armed = false;
+                    armed
+                }
+                _ => true,
+            }
+        };
+        if irq {
+            self.chn[chn].rx_int_pending = true;
+            let vec = self.int_vector(chn, COND_RX);
+            bus.sio_irq(self.id, chn, vec);
+        }
+        true
+    }
+
+    /// true if any interrupt is pending
+    pub fn int_pending(&self) -> bool {
+        self.chn.iter().any(|c| c.rx_int_pending || c.tx_int_pending)
+    }
+
+    /// the interrupt vector (written to WR2 of channel B)
+    pub fn vector(&self) -> u8 {
+        self.chn[SIO_B].wr[2]
+    }
+
+    /// compute the interrupt vector for a channel and condition
+    fn int_vector(&self, chn: usize, cond: u8) -> RegT {
+        let base = self.chn[SIO_B].wr[2];
+        if (self.chn[SIO_B].wr[1] & WR1_STATUS_AFFECTS_VECTOR) != 0 {
+            let code = if chn == SIO_A { 4 | cond } else { cond };
+            ((base & 0xF1) | (code << 1)) as RegT
+        } else {
+            base as RegT
+        }
+    }
+
+    /// RR2: the vector of the highest-priority pending interrupt
+    fn pending_vector(&self) -> RegT {
+        for &chn in &[SIO_A, SIO_B] {
+            let c = &self.chn[chn];
+            if c.rx_int_pending {
+                return self.int_vector(chn, COND_RX);
+            }
+            if c.tx_int_pending {
+                return self.int_vector(chn, COND_TX);
+            }
+        }
+        self.int_vector(SIO_B, COND_NONE)
+    }
+
+    /// drop pending interrupts which have been disabled
+    fn update_int_flags(&mut self) {
+        for c in &mut self.chn {
+            if (c.wr[1] & WR1_TX_INT_ENABLE) == 0 {
+                c.tx_int_pending = false;
+            }
+            if (c.wr[1] & WR1_RX_INT_MASK) == 0 {
+                c.rx_int_pending = false;
+            }
+        }
+    }
+}
+
+impl Reset for SIO {
+    /// a cold reset also clears the interrupt vector
+    fn system_reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            *self = SIO::new(self.id);
+        }
+        self.reset();
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {RecordingBus, BusEvent};
+
+    fn init(sio: &mut SIO, chn: usize, wr1: u8) {
+        for &val in &[0x18, 0x03, 0xC1, 0x05, 0x68, 0x01, wr1 as RegT] {
+            sio.write_control(chn, val);
+        }
+    }
+
+    #[test]
+    fn transmit() {
+        let bus = RecordingBus::new();
+        let mut sio = SIO::new(0);
+        // transmitter disabled
+        sio.write_data(&bus, SIO_A, 0x41);
+        assert!(bus.events().is_empty());
+        init(&mut sio, SIO_A, 0x00);
+        assert_eq!(sio.read_control(SIO_A) as u8 & SIO_RR0_TX_EMPTY, SIO_RR0_TX_EMPTY);
+        sio.write_data(&bus, SIO_A, 0x41);
+        assert_eq!(bus.take_events(),
+                   vec![(0, BusEvent::SioOutp { sio: 0, chn: SIO_A, data: 0x41 })]);
+
+        // transmit interrupt, status affects vector
+        sio.write_control(SIO_B, 0x02);
+        sio.write_control(SIO_B, 0x40);
+        sio.write_control(SIO_B, 0x01);
+        sio.write_control(SIO_B, 0x04);
+        sio.write_control(SIO_A, 0x01);
+        sio.write_control(SIO_A, 0x02);
+        sio.write_data(&bus, SIO_A, 0x42);
+        assert_eq!(bus.take_events(),
+                   vec![(0, BusEvent::SioOutp { sio: 0, chn: SIO_A, data: 0x42 }),
+                        (0, BusEvent::SioIrq { sio: 0, chn: SIO_A, int_vector: 0x48 })]);
+        assert!(sio.int_pending());
+        sio.write_control(SIO_B, 0x02);
+        assert_eq!(sio.read_control(SIO_B), 0x48);
+        // reset transmitter interrupt pending
+        sio.write_control(SIO_A, 0x28);
+        assert!(!sio.int_pending());
+        sio.write_control(SIO_B, 0x02);
+        assert_eq!(sio.read_control(SIO_B), 0x46);
+    }
+
+    #[test]
+    fn receive() {
+        let bus = RecordingBus::new();
+        let mut sio = SIO::new(0);
+        assert!(!sio.rx_ready(SIO_B));
+        assert!(!sio.receive(&bus, SIO_B, 0x11));
+        // interrupt on all received characters
+        init(&mut sio, SIO_B, 0x18);
+        sio.write_control(SIO_B, 0x02);
+        sio.write_control(SIO_B, 0xE0);
+        for i in 0..3 {
+            assert!(sio.rx_ready(SIO_B));
+            assert!(sio.receive(&bus, SIO_B, 0x11 * (i + 1)));
+        }
+        assert!(!sio.rx_ready(SIO_B));
+        assert_eq!(bus.take_events().len(), 3);
+        assert_eq!(sio.read_control(SIO_B) as u8 & SIO_RR0_RX_AVAILABLE, SIO_RR0_RX_AVAILABLE);
+        // overrun replaces the last character
+        sio.receive(&bus, SIO_B, 0x44);
+        sio.write_control(SIO_B, 0x01);
+        assert_eq!(sio.read_control(SIO_B) as u8 & SIO_RR1_RX_OVERRUN, SIO_RR1_RX_OVERRUN);
+        sio.write_control(SIO_B, 0x30);
+        sio.write_control(SIO_B, 0x01);
+        assert_eq!(sio.read_control(SIO_B) as u8 & SIO_RR1_RX_OVERRUN, 0);
+        assert_eq!(sio.read_data(SIO_B), 0x11);
+        assert_eq!(sio.read_data(SIO_B), 0x22);
+        assert!(sio.int_pending());
+        assert_eq!(sio.read_data(SIO_B), 0x44);
+        assert!(!sio.int_pending());
+        assert_eq!(sio.read_data(SIO_B), 0xFF);
+
+        // interrupt on first character only, until re-armed
+        init(&mut sio, SIO_B, 0x08);
+        bus.take_events();
+        sio.receive(&bus, SIO_B, 1);
+        sio.receive(&bus, SIO_B, 2);
+        assert_eq!(bus.take_events(),
+                   vec![(0, BusEvent::SioIrq { sio: 0, chn: SIO_B, int_vector: 0xE0 })]);
+        sio.write_control(SIO_B, 0x20);
+        sio.receive(&bus, SIO_B, 3);
+        assert_eq!(bus.take_events().len(), 1);
+    }
+}
@@ -0,0 +1,328 @@
+use RegT;
+use psgdump::{VgmLog, PsgType};
+
+const NUM_CHANNELS: usize = 4;
+const NOISE: usize = 3;
+// the SMS variant uses a 16-bit noise shift register with taps at bit 0 and 3
+const NOISE_RESET: u16 = 0x8000;
+const NOISE_TAPS: u16 = 0x0009;
+
+// output level for the 16 attenuation values (2 dB steps, 15 is off)
+const VOLUME: [f32; 16] = [1.0, 0.794, 0.631, 0.501, 0.398, 0.316, 0.251, 0.200,
+                           0.158, 0.126, 0.100, 0.079, 0.063, 0.050, 0.040, 0.0];
+
+/// SN76489 sound chip emulation
+///
+/// The SN76489 has 3 square wave tone channels and 1 noise channel
+/// (with periodic and white noise modes), each with a 4-bit attenuation.
+/// All registers are written through a single write-only port with
+/// **write()**, on the SMS and Game Gear this is port 0x7F, on the
+/// ColecoVision port 0xFF.
+///
+/// The sound chip runs at the CPU clock (internally divided by 16), so
+/// the system calls **step()** with the number of CPU cycles executed,
+/// and the chip generates audio samples at the sample rate given in
+/// **new()**. The generated samples (mono, -1.0..1.0) are fetched with
+/// **take_samples()** and can be pushed to the host's audio API.
+///
+/// All register writes can be recorded into a VGM file between
+/// **start_log()** and **stop_log()**.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::SN76489;
+///
+/// // 3.58 MHz CPU clock, 44.1 kHz sample rate
+/// let mut psg = SN76489::new(3579545, 44100);
+///
+/// // channel 0: tone period 0x0FE (~440 Hz), full volume
+/// psg.write(0x80 | 0x0E);
+/// psg.write(0x0F);
+/// psg.write(0x90 | 0x00);
+/// assert_eq!(psg.tone_period(0), 0x0FE);
+///
+/// // run for one 60 Hz frame
+/// psg.step(3579545 / 60);
+/// let mut samples = Vec::new();
+/// psg.take_samples(&mut samples);
+/// assert!(samples.len() >= 734 && samples.len() <= 735);
+/// ```
+pub struct SN76489 {
+    period: [u16; NUM_CHANNELS],
+    counter: [u16; NUM_CHANNELS],
+    output: [bool; NUM_CHANNELS],
+    attenuation: [u8; NUM_CHANNELS],
+    latch: usize,
+    latch_volume: bool,
+    noise_shift: u16,
+    clock_acc: i64,
+    clock_hz: i64,
+    cycles: i64,
+    tick_rate: i64,
+    sample_rate: i64,
+    sample_acc: i64,
+    sample_sum: f32,
+    sample_ticks: u32,
+    samples: Vec<f32>,
+    log: Option<VgmLog>,
+}
+
+impl SN76489 {
+    /// initialize a new SN76489 with the chip clock and audio sample rate in Hz
+    pub fn new(clock_hz: i64, sample_rate: i64) -> SN76489 {
+        SN76489 {
+            period: [0; NUM_CHANNELS],
+            counter: [0; NUM_CHANNELS],
+            output: [true; NUM_CHANNELS],
+            attenuation: [15; NUM_CHANNELS],
+            latch: 0,
+            latch_volume: false,
+            noise_shift: NOISE_RESET,
+            clock_acc: 0,
+            clock_hz,
+            cycles: 0,
+            tick_rate: clock_hz / 16,
+            sample_rate,
+            sample_acc: 0,
+            sample_sum: 0.0,
+            sample_ticks: 0,
+            samples: Vec::new(),
+            log: None,
+        }
+    }
+
+    /// reset the SN76489 (silences all channels)
+    pub fn reset(&mut self) {
+        self.period = [0; NUM_CHANNELS];
+        self.counter = [0; NUM_CHANNELS];
+        self.output = [true; NUM_CHANNELS];
+        self.attenuation = [15; NUM_CHANNELS];
+        self.latch = 0;
+        self.latch_volume = false;
+        self.noise_shift = NOISE_RESET;
+        self.clock_acc = 0;
+        self.sample_acc = 0;
+        self.sample_sum = 0.0;
+        self.sample_ticks = 0;
+        self.samples.clear();
+    }
+
+    /// write a latch/data byte
+    pub fn write(&mut self, val: RegT) {
+        if let Some(ref mut log) = self.log {
+            log.write(self.cycles, 0, val);
+        }
+        let val = val as u8;
+        if (val & 0x80) != 0 {
+            // latch byte: channel, register type, and lower 4 data bits
+            self.latch = ((val >> 5) & 3) as usize;
+            self.latch_volume = (val & 0x10) != 0;
+            let data = (val & 0x0F) as u16;
+            if self.latch_volume {
+                self.attenuation[self.latch] = data as u8;
+            } else if self.latch == NOISE {
+                self.write_noise(data);
+            } else {
+                self.period[self.latch] = (self.period[self.latch] & 0x3F0) | data;
+            }
+        } else {
+            // data byte: upper 6 bits of a tone period, or volume/noise data
+            let data = (val & 0x3F) as u16;
+            if self.latch_volume {
+                self.attenuation[self.latch] = (data & 0x0F) as u8;
+            } else if self.latch == NOISE {
+                self.write_noise(data & 0x0F);
+            } else {
+                self.period[self.latch] = (self.period[self.latch] & 0x00F) | (data << 4);
+            }
+        }
+    }
+
+    fn write_noise(&mut self, data: u16) {
+        self.period[NOISE] = data & 7;
+        self.noise_shift = NOISE_RESET;
+    }
+
+    /// get the 10-bit tone period of a tone channel (0..2)
+    pub fn tone_period(&self, chn: usize) -> u16 {
+        self.period[chn]
+    }
+
+    /// get the 4-bit attenuation of a channel (0..3), 15 means off
+    pub fn attenuation(&self, chn: usize) -> u8 {
+        self.attenuation[chn]
+    }
+
+    /// advance the chip by a number of CPU cycles, generating audio samples
+    pub fn step(&mut self, cycles: i64) {
+        self.cycles += cycles;
+        self.clock_acc += cycles;
+        while self.clock_acc >= 16 {
+            self.clock_acc -= 16;
+            self.tick();
+            self.sample_sum += self.output_level();
+            self.sample_ticks += 1;
+            self.sample_acc += self.sample_rate;
+            if self.sample_acc >= self.tick_rate {
+                self.sample_acc -= self.tick_rate;
+                self.samples.push(self.sample_sum / self.sample_ticks as f32);
+                self.sample_sum = 0.0;
+                self.sample_ticks = 0;
+            }
+        }
+    }
+
+    /// the number of CPU cycles the chip has been running
+    pub fn cycles(&self) -> i64 {
+        self.cycles
+    }
+
+    /// start recording the register writes (restarts a running log)
+    pub fn start_log(&mut self) {
+        self.log = Some(VgmLog::new(PsgType::SN76489, self.clock_hz, self.cycles));
+    }
+
+    /// stop recording, finish the log with VgmLog::finish(self.cycles())
+    pub fn stop_log(&mut self) -> Option<VgmLog> {
+        self.log.take()
+    }
+
+    /// move the generated audio samples to the end of dst
+    pub fn take_samples(&mut self, dst: &mut Vec<f32>) {
+        dst.append(&mut self.samples);
+    }
+
+    /// advance the tone and noise counters by one tick (clock / 16)
+    fn tick(&mut self) {
+        for chn in 0..NOISE {
+            if self.counter[chn] > 0 {
+                self.counter[chn] -= 1;
+            }
+            if self.counter[chn] == 0 {
+                self.counter[chn] = self.period[chn];
+                // periods 0 and 1 output a constant level
+                self.output[chn] = self.period[chn] <= 1 || !self.output[chn];
+            }
+        }
+
+        if self.counter[NOISE] > 0 {
+            self.counter[NOISE] -= 1;
+        }
+        if self.counter[NOISE] == 0 {
+            let rate = self.period[NOISE] & 3;
+            self.counter[NOISE] = if rate == 3 {
+                self.period[2].max(1)
+            } else {
+                0x10 << rate
+            };
+            self.output[NOISE] = !self.output[NOISE];
+            // the shift register is clocked on each positive edge
+            if self.output[NOISE] {
+                let fb = if (self.period[NOISE] & 4) != 0 {
+                    // white noise
+                    ((self.noise_shift & NOISE_TAPS).count_ones() & 1) as u16
+                } else {
+                    // periodic noise
+                    self.noise_shift & 1
+                };
+                self.noise_shift = (self.noise_shift >> 1) | (fb << 15);
+            }
+        }
+    }
+
+    /// current mixed output level of all channels
+    fn output_level(&self) -> f32 {
+        let mut level = 0.0;
+        for chn in 0..NOISE {
+            let vol = VOLUME[self.attenuation[chn] as usize];
+            level += if self.output[chn] { vol } else { -vol };
+        }
+        let vol = VOLUME[self.attenuation[NOISE] as usize];
+        level += if (self.noise_shift & 1) != 0 { vol } else { -vol };
+        level / NUM_CHANNELS as f32
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers() {
+        let mut psg = SN76489::new(3579545, 44100);
+        // channel 1 tone, latch + data byte
+        psg.write(0xA0 | 0x05);
+        psg.write(0x3F);
+        assert_eq!(psg.tone_period(1), 0x3F5);
+        // a latch byte only replaces the lower 4 bits
+        psg.write(0xA0 | 0x0A);
+        assert_eq!(psg.tone_period(1), 0x3FA);
+        // volume of channel 2, a data byte after a volume latch updates the volume
+        psg.write(0xD0 | 0x03);
+        assert_eq!(psg.attenuation(2), 3);
+        psg.write(0x07);
+        assert_eq!(psg.attenuation(2), 7);
+        // noise control resets the shift register
+        psg.noise_shift = 0x1234;
+        psg.write(0xE0 | 0x05);
+        assert_eq!(psg.period[NOISE], 5);
+        assert_eq!(psg.noise_shift, NOISE_RESET);
+    }
+
+    #[test]
+    fn tone() {
+        let mut psg = SN76489::new(3579545, 44100);
+        psg.write(0x80 | 0x04);
+        psg.write(0x00);
+        psg.write(0x90);
+        // the output flips every 4 ticks (64 CPU cycles)
+        psg.step(16);
+        let out = psg.output[0];
+        psg.step(16 * 3);
+        assert_eq!(psg.output[0], out);
+        psg.step(16);
+        assert_eq!(psg.output[0], !out);
+        psg.step(16 * 4);
+        assert_eq!(psg.output[0], out);
+    }
+
+    #[test]
+    fn noise() {
+        let mut psg = SN76489::new(3579545, 44100);
+        // periodic noise: the reset bit takes 16 shifts to come around
+        psg.write(0xE0);
+        let mut seen = 0;
+        for _ in 0..(16 * 0x20) {
+            psg.step(16);
+            if (psg.noise_shift & 1) != 0 {
+                seen += 1;
+            }
+        }
+        assert!(seen > 0);
+        // white noise
+        psg.write(0xE0 | 0x04);
+        let mut values = Vec::new();
+        for _ in 0..64 {
+            psg.step(16 * 0x20);
+            values.push(psg.noise_shift);
+        }
+        values.sort();
+        values.dedup();
+        assert!(values.len() > 32);
+    }
+
+    #[test]
+    fn samples() {
+        let mut psg = SN76489::new(3579545, 44100);
+        let mut samples = Vec::new();
+        psg.step(3579545);
+        psg.take_samples(&mut samples);
+        assert!((samples.len() as i64 - 44100).abs() <= 1);
+        // all channels off, output is silent
+        assert!(samples.iter().all(|s| *s == 0.0));
+        psg.take_samples(&mut samples);
+        assert!((samples.len() as i64 - 44100).abs() <= 1);
+    }
+}
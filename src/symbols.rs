@@ -0,0 +1,254 @@
+use std::fmt;
+use std::collections::{BTreeMap, HashMap};
+use RegT;
+
+/// errors when parsing a symbol file
+#[derive(Clone,Debug,PartialEq)]
+pub enum SymbolError {
+    /// a line couldn't be parsed (1-based line number)
+    Syntax(usize),
+    /// the address of a symbol isn't a valid number (1-based line number)
+    BadAddress(usize),
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SymbolError::Syntax(line) => write!(f, "syntax error in symbol file line {}", line),
+            SymbolError::BadAddress(line) => {
+                write!(f, "invalid address in symbol file line {}", line)
+            }
+        }
+    }
+}
+
+/// maps label names to 16-bit addresses and back
+///
+/// Symbol files can be loaded in the following formats (the format is
+/// detected per line, comments start with ';'):
+///
+/// - simple `name=addr` lines
+/// - SjASMPlus .sym files: `name: EQU 0x00001234`
+/// - z88dk .map files: `name = $1234 ; addr, local, ...`
+///
+/// Addresses can be written as `0x1234`, `$1234`, `#1234`, `1234h` or
+/// as decimal numbers.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::SymbolTable;
+///
+/// let syms = SymbolTable::parse("start=0x0100\nloop: EQU 0x00000105\n").unwrap();
+/// assert_eq!(syms.addr("loop"), Some(0x0105));
+/// assert_eq!(syms.name(0x0100), Some("start"));
+/// assert_eq!(syms.format_addr(0x0100), "start");
+/// assert_eq!(syms.format_addr(0x0107), "loop+2");
+/// assert_eq!(syms.format_addr(0x8000), "8000h");
+/// ```
+#[derive(Clone,Debug)]
+pub struct SymbolTable {
+    by_name: HashMap<String, RegT>,
+    by_addr: BTreeMap<RegT, String>,
+    max_offset: RegT,
+}
+
+impl SymbolTable {
+    /// create an empty symbol table
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            by_name: HashMap::new(),
+            by_addr: BTreeMap::new(),
+            max_offset: 16,
+        }
+    }
+
+    /// parse the content of a symbol file into a new symbol table
+    pub fn parse(text: &str) -> Result<SymbolTable, SymbolError> {
+        let mut syms = SymbolTable::new();
+        syms.load(text)?;
+        Ok(syms)
+    }
+
+    /// add the symbols of a symbol file to the table
+    pub fn load(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (i, line) in text.lines().enumerate() {
+            let line_nr = i + 1;
+            let line = match line.find(';') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = split_symbol(line).ok_or(SymbolError::Syntax(line_nr))?;
+            let addr = parse_addr(value).ok_or(SymbolError::BadAddress(line_nr))?;
+            self.add(name, addr);
+        }
+        Ok(())
+    }
+
+    /// add a symbol, the first symbol added for an address is used in lookups
+    pub fn add(&mut self, name: &str, addr: RegT) {
+        let addr = addr & 0xFFFF;
+        self.by_name.insert(name.to_string(), addr);
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    /// number of symbols
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    /// true if the table has no symbols
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// max distance to a preceding label for 'label+offset' lookups (default is 16)
+    pub fn set_max_offset(&mut self, max_offset: RegT) {
+        self.max_offset = max_offset;
+    }
+
+    /// get the address of a symbol
+    pub fn addr(&self, name: &str) -> Option<RegT> {
+        self.by_name.get(name).cloned()
+    }
+
+    /// get the symbol name for an exact address
+    pub fn name(&self, addr: RegT) -> Option<&str> {
+        self.by_addr.get(&(addr & 0xFFFF)).map(|s| s.as_str())
+    }
+
+    /// find the closest symbol at or before an address, return name and offset
+    pub fn lookup(&self, addr: RegT) -> Option<(&str, RegT)> {
+        let addr = addr & 0xFFFF;
+        self.by_addr
+            .range(..=addr)
+            .next_back()
+            .map(|(a, name)| (name.as_str(), addr - a))
+            .filter(|&(_, offset)| offset <= self.max_offset)
+    }
+
+    /// format an address as 'label', 'label+offset' or hex number
+    pub fn format_addr(&self, addr: RegT) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("{:04X}h", addr & 0xFFFF),
+        }
+    }
+
+    /// iterate over the symbols ordered by address
+    pub fn iter(&self) -> impl Iterator<Item = (RegT, &str)> {
+        self.by_addr.iter().map(|(addr, name)| (*addr, name.as_str()))
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> SymbolTable {
+        SymbolTable::new()
+    }
+}
+
+/// split a symbol line into name and value
+fn split_symbol(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = if let Some(pos) = line.find('=') {
+        (&line[..pos], &line[pos + 1..])
+    } else {
+        // 'name: EQU value' or 'name EQU value'
+        let mut parts = line.split_whitespace();
+        let name = parts.next()?;
+        if !parts.next()?.eq_ignore_ascii_case("equ") {
+            return None;
+        }
+        let value = parts.next()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        (name, value)
+    };
+    let name = name.trim().trim_end_matches(':');
+    let value = value.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) || value.is_empty() {
+        None
+    } else {
+        Some((name, value))
+    }
+}
+
+/// parse an address in one of the common assembler notations
+pub(crate) fn parse_addr(value: &str) -> Option<RegT> {
+    let lower = value.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_prefix('$').or_else(|| lower.strip_prefix('#')) {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        (hex, 16)
+    } else {
+        (lower.as_str(), 10)
+    };
+    u32::from_str_radix(digits, radix).ok().map(|v| (v & 0xFFFF) as RegT)
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let text = "; simple\n\
+                    start=0x0100\n\
+                    data = 0C000h\n\
+                    \n\
+                    ; sjasmplus\n\
+                    main: EQU 0x00008000\n\
+                    main.loop: EQU 0x00008003\n\
+                    ; z88dk\n\
+                    _main                           = $8000 ; addr, public, , main, code_compiler\n\
+                    _count                          = $C010 ; addr, local, , main, bss_compiler\n";
+        let syms = SymbolTable::parse(text).unwrap();
+        assert_eq!(syms.len(), 6);
+        assert_eq!(syms.addr("start"), Some(0x0100));
+        assert_eq!(syms.addr("data"), Some(0xC000));
+        assert_eq!(syms.addr("main.loop"), Some(0x8003));
+        assert_eq!(syms.addr("_main"), Some(0x8000));
+        assert_eq!(syms.addr("_count"), Some(0xC010));
+        assert_eq!(syms.addr("missing"), None);
+        // first symbol for an address wins
+        assert_eq!(syms.name(0x8000), Some("main"));
+        assert_eq!(syms.iter().map(|(a, _)| a).collect::<Vec<_>>(),
+                   [0x0100, 0x8000, 0x8003, 0xC000, 0xC010]);
+    }
+
+    #[test]
+    fn lookup() {
+        let mut syms = SymbolTable::new();
+        syms.add("start", 0x0100);
+        syms.add("loop", 0x0110);
+        assert_eq!(syms.lookup(0x0100), Some(("start", 0)));
+        assert_eq!(syms.lookup(0x010F), Some(("start", 15)));
+        assert_eq!(syms.lookup(0x0120), Some(("loop", 16)));
+        assert_eq!(syms.lookup(0x0121), None);
+        assert_eq!(syms.lookup(0x00FF), None);
+        assert_eq!(syms.format_addr(0x0112), "loop+2");
+        syms.set_max_offset(0);
+        assert_eq!(syms.format_addr(0x0112), "0112h");
+        assert_eq!(syms.format_addr(0x0110), "loop");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(SymbolTable::parse("a=1\nfoo bar\n").unwrap_err(),
+                   SymbolError::Syntax(2));
+        assert_eq!(SymbolTable::parse("a=1\nb=xyz\n").unwrap_err(),
+                   SymbolError::BadAddress(2));
+        assert_eq!(SymbolTable::parse("a: EQU 12 34").unwrap_err(),
+                   SymbolError::Syntax(1));
+        assert_eq!(format!("{}", SymbolError::Syntax(3)),
+                   "syntax error in symbol file line 3");
+    }
+}
@@ -0,0 +1,608 @@
+use std::fmt;
+use std::mem;
+use machine::Clock;
+use memory::Memory;
+use RegT;
+
+/// reasons why a tape image couldn't be loaded
+#[derive(Clone,Debug,PartialEq)]
+pub enum TapeError {
+    /// the image is shorter than its header
+    TooShort,
+    /// the image doesn't start with the expected magic bytes
+    BadMagic,
+    /// the header of the image is inconsistent
+    BadHeader,
+    /// a tape block has a wrong checksum (block number)
+    Checksum(usize),
+}
+
+impl fmt::Display for TapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TapeError::TooShort => write!(f, "tape image is too short"),
+            TapeError::BadMagic => write!(f, "not a tape image (bad magic)"),
+            TapeError::BadHeader => write!(f, "tape image has a bad header"),
+            TapeError::Checksum(block) => write!(f, "checksum error in tape block {}", block),
+        }
+    }
+}
+
+/// a program file with a KC/Z1013 'headersave' header (.z80 files)
+///
+/// The 32 byte header contains the load, end and start address, a
+/// file type character and a 16 character name, followed by the
+/// program data which is loaded at the load address.
+///
+/// ```
+/// use rz80::TapeFile;
+///
+/// let file = TapeFile::new("HELLO", b'C', 0x0100, 0x0100, vec![0xC9; 64]);
+/// assert_eq!(file.end, 0x013F);
+///
+/// let bytes = file.to_z80();
+/// assert_eq!(bytes.len(), 32 + 64);
+/// assert_eq!(TapeFile::from_z80(&bytes), Ok(file));
+/// ```
+#[derive(Clone,Debug,PartialEq)]
+pub struct TapeFile {
+    /// file name (up to 16 characters)
+    pub name: String,
+    /// file type character (e.g. b'C' for machine code)
+    pub kind: u8,
+    /// load address
+    pub load: RegT,
+    /// end address (inclusive)
+    pub end: RegT,
+    /// start address
+    pub exec: RegT,
+    /// program data
+    pub data: Vec<u8>,
+}
+
+const Z80_HEADER_SIZE: usize = 32;
+const KCC_HEADER_SIZE: usize = 128;
+const KC_TAP_MAGIC: &[u8] = b"\xC3KC-TAPE by AF. ";
+const KC_TAP_BLOCK_SIZE: usize = 129;
+
+/// the file formats of programs which can be loaded into memory
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ProgramFormat {
+    /// Z1013 .z80 file with 32 byte 'headersave' header
+    Z80,
+    /// KC85 .KCC file with 128 byte header
+    Kcc,
+    /// KC85 .TAP file, a .KCC file split into numbered 128 byte tape blocks
+    KcTap,
+    /// headerless binary (like a CP/M .COM file) loaded and started at an address
+    Com(RegT),
+}
+
+impl TapeFile {
+    /// create a tape file, the end address is computed from the data size
+    pub fn new(name: &str, kind: u8, load: RegT, exec: RegT, data: Vec<u8>) -> TapeFile {
+        assert!(!data.is_empty());
+        TapeFile {
+            name: name.to_string(),
+            kind,
+            load,
+            end: (load + data.len() as RegT - 1) & 0xFFFF,
+            exec,
+            data,
+        }
+    }
+
+    /// parse a .z80 file
+    pub fn from_z80(bytes: &[u8]) -> Result<TapeFile, TapeError> {
+        if bytes.len() < Z80_HEADER_SIZE {
+            return Err(TapeError::TooShort);
+        }
+        if &bytes[13..16] != b"\xD3\xD3\xD3" {
+            return Err(TapeError::BadMagic);
+        }
+        let word = |i: usize| (bytes[i] as RegT) | ((bytes[i + 1] as RegT) << 8);
+        let (load, end, exec) = (word(0), word(2), word(4));
+        if end < load {
+            return Err(TapeError::BadHeader);
+        }
+        // the data may be padded to full tape blocks, or miss trailing bytes
+        let size = ((end - load + 1) as usize).min(bytes.len() - Z80_HEADER_SIZE);
+        let name = String::from_utf8_lossy(&bytes[16..32]).trim_end().to_string();
+        Ok(TapeFile {
+            name,
+            kind: bytes[12],
+            load,
+            end,
+            exec,
+            data: bytes[Z80_HEADER_SIZE..Z80_HEADER_SIZE + size].to_vec(),
+        })
+    }
+
+    /// parse a .KCC file
+    ///
+    /// Files without a start address get the load address as start address.
+    pub fn from_kcc(bytes: &[u8]) -> Result<TapeFile, TapeError> {
+        if bytes.len() <= KCC_HEADER_SIZE {
+            return Err(TapeError::TooShort);
+        }
+        let word = |i: usize| (bytes[i] as RegT) | ((bytes[i + 1] as RegT) << 8);
+        let num_addrs = bytes[16];
+        // the end address in the header is exclusive
+        let (load, end) = (word(17), word(19) - 1);
+        if !(2..=3).contains(&num_addrs) || end < load {
+            return Err(TapeError::BadHeader);
+        }
+        let exec = if num_addrs == 3 { word(21) } else { load };
+        let size = ((end - load + 1) as usize).min(bytes.len() - KCC_HEADER_SIZE);
+        let name = String::from_utf8_lossy(&bytes[0..8]).trim_end().to_string();
+        let ext = String::from_utf8_lossy(&bytes[8..11]).trim_end().to_string();
+        Ok(TapeFile {
+            name: if ext.is_empty() { name } else { format!("{}.{}", name, ext) },
+            kind: b'C',
+            load,
+            end,
+            exec,
+            data: bytes[KCC_HEADER_SIZE..KCC_HEADER_SIZE + size].to_vec(),
+        })
+    }
+
+    /// parse a KC85 .TAP file (not the pulse recordings of read_tap())
+    pub fn from_kc_tap(bytes: &[u8]) -> Result<TapeFile, TapeError> {
+        if !bytes.starts_with(KC_TAP_MAGIC) {
+            return Err(TapeError::BadMagic);
+        }
+        // strip the block numbers, which leaves a .KCC file
+        let kcc: Vec<u8> = bytes[KC_TAP_MAGIC.len()..]
+            .chunks(KC_TAP_BLOCK_SIZE)
+            .filter(|block| block.len() == KC_TAP_BLOCK_SIZE)
+            .flat_map(|block| block[1..].iter().cloned())
+            .collect();
+        TapeFile::from_kcc(&kcc)
+    }
+
+    /// create a tape file from a headerless binary which is loaded and started at org
+    pub fn from_com(bytes: &[u8], org: RegT) -> Result<TapeFile, TapeError> {
+        if bytes.is_empty() || org as usize + bytes.len() > 0x10000 {
+            return Err(TapeError::BadHeader);
+        }
+        Ok(TapeFile::new("", b'C', org, org, bytes.to_vec()))
+    }
+
+    /// parse a program file in one of the supported formats
+    pub fn parse(format: ProgramFormat, bytes: &[u8]) -> Result<TapeFile, TapeError> {
+        match format {
+            ProgramFormat::Z80 => TapeFile::from_z80(bytes),
+            ProgramFormat::Kcc => TapeFile::from_kcc(bytes),
+            ProgramFormat::KcTap => TapeFile::from_kc_tap(bytes),
+            ProgramFormat::Com(org) => TapeFile::from_com(bytes, org),
+        }
+    }
+
+    /// copy the program data to the load address, ignoring write-protection
+    pub fn write_to(&self, mem: &mut Memory) {
+        mem.write(self.load, &self.data);
+    }
+
+    /// convert to a .z80 file
+    pub fn to_z80(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; Z80_HEADER_SIZE];
+        for (i, &w) in [self.load, self.end, self.exec].iter().enumerate() {
+            bytes[i * 2] = w as u8;
+            bytes[i * 2 + 1] = (w >> 8) as u8;
+        }
+        bytes[12] = self.kind;
+        bytes[13..16].copy_from_slice(b"\xD3\xD3\xD3");
+        for (i, b) in bytes[16..32].iter_mut().enumerate() {
+            *b = *self.name.as_bytes().get(i).unwrap_or(&b' ');
+        }
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+/// cassette tape deck with cycle-accurate pulse playback and recording
+///
+/// A tape is a sequence of pulses, each pulse is the number of CPU cycles
+/// until the next level change of the tape signal. The emulator calls
+/// **update()** with the executed CPU cycles and feeds **level()** into
+/// the tape input bit, and calls **toggle()** whenever the emulated
+/// system flips the tape output bit while recording.
+///
+/// ```
+/// use rz80::Tape;
+///
+/// let mut tape = Tape::new();
+/// tape.insert(vec![100, 50]);
+/// tape.play();
+/// assert_eq!(tape.update(99), false);
+/// assert_eq!(tape.update(1), true);
+/// assert_eq!(tape.update(50), false);
+/// assert!(tape.at_end());
+///
+/// // instead of calling update() after each instruction, the tape can
+/// // be brought up to date when its level is read
+/// tape.rewind();
+/// tape.play();
+/// assert_eq!(tape.next_event(), Some(100));
+/// tape.update(120);
+/// assert_eq!(tape.next_event(), Some(30));
+///
+/// tape.record();
+/// tape.update(30);
+/// tape.toggle();
+/// tape.update(20);
+/// tape.toggle();
+/// assert_eq!(tape.take_recording(), [30, 20]);
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct Tape {
+    pulses: Vec<i64>,
+    pos: usize,
+    remaining: i64,
+    level: bool,
+    playing: bool,
+    recording: bool,
+    rec_pulses: Vec<i64>,
+    rec_cycles: i64,
+}
+
+impl Tape {
+    /// create an empty tape deck
+    pub fn new() -> Tape {
+        Tape::default()
+    }
+
+    /// insert a tape with pulse lengths in CPU cycles, and rewind it
+    pub fn insert(&mut self, pulses: Vec<i64>) {
+        self.stop();
+        self.pulses = pulses;
+        self.rewind();
+    }
+
+    /// rewind the tape to the start
+    pub fn rewind(&mut self) {
+        self.pos = 0;
+        self.remaining = self.pulses.first().cloned().unwrap_or(0);
+        self.level = false;
+    }
+
+    /// start playback
+    pub fn play(&mut self) {
+        self.recording = false;
+        self.playing = !self.at_end();
+    }
+
+    /// start recording, this discards the previous recording
+    pub fn record(&mut self) {
+        self.playing = false;
+        self.recording = true;
+        self.rec_pulses.clear();
+        self.rec_cycles = 0;
+    }
+
+    /// stop playback or recording
+    pub fn stop(&mut self) {
+        self.playing = false;
+        self.recording = false;
+    }
+
+    /// true while the tape is playing
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// true while the tape is recording
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// true if all pulses have been played
+    pub fn at_end(&self) -> bool {
+        self.pos >= self.pulses.len()
+    }
+
+    /// current level of the tape signal
+    pub fn level(&self) -> bool {
+        self.level
+    }
+
+    /// advance the tape by a number of CPU cycles, return the new level
+    pub fn update(&mut self, cycles: i64) -> bool {
+        if self.recording {
+            self.rec_cycles += cycles;
+        }
+        if self.playing {
+            self.remaining -= cycles;
+            while self.remaining <= 0 {
+                self.level = !self.level;
+                self.pos += 1;
+                if self.at_end() {
+                    self.playing = false;
+                    break;
+                }
+                self.remaining += self.pulses[self.pos];
+            }
+        }
+        self.level
+    }
+
+    /// number of cycles until the next level change of the tape signal
+    ///
+    /// Returns None if the tape isn't playing, this can be used to
+    /// schedule an event for the next tape edge in a Scheduler.
+    pub fn next_event(&self) -> Option<i64> {
+        if self.playing {
+            Some(self.remaining)
+        } else {
+            None
+        }
+    }
+
+    /// the tape output level of the emulated system has changed
+    pub fn toggle(&mut self) {
+        if self.recording {
+            self.rec_pulses.push(self.rec_cycles);
+            self.rec_cycles = 0;
+        }
+    }
+
+    /// return the recorded pulses and clear the recording
+    pub fn take_recording(&mut self) -> Vec<i64> {
+        mem::take(&mut self.rec_pulses)
+    }
+}
+
+/// parse a program file and copy it into memory at its load address
+///
+/// Returns the parsed file, the caller usually sets the PC to the
+/// start address **exec** to run the program.
+///
+/// ```
+/// use rz80::{Memory, ProgramFormat, load_program};
+///
+/// let mut mem = Memory::new_64k();
+/// let file = load_program(&mut mem, ProgramFormat::Com(0x0100), &[0x3E, 0x01, 0xC9]).unwrap();
+/// assert_eq!((file.load, file.end, file.exec), (0x0100, 0x0102, 0x0100));
+/// assert_eq!(mem.r8(0x0102), 0xC9);
+/// ```
+pub fn load_program(mem: &mut Memory, format: ProgramFormat, bytes: &[u8]) -> Result<TapeFile, TapeError> {
+    let file = TapeFile::parse(format, bytes)?;
+    file.write_to(mem);
+    Ok(file)
+}
+
+const TAP_MAGIC: &[u8] = b"Z80-TAPE-RAW";
+
+/// parse a .tap pulse file, and convert the pulses to a CPU clock
+///
+/// A .tap file starts with the 12 byte magic 'Z80-TAPE-RAW', followed
+/// by the recording clock frequency in Hz and the number of pulses, and
+/// the pulse lengths in cycles (all little endian 32-bit values).
+pub fn read_tap(bytes: &[u8], clock: Clock) -> Result<Vec<i64>, TapeError> {
+    let header_size = TAP_MAGIC.len() + 8;
+    if bytes.len() < header_size {
+        return Err(TapeError::TooShort);
+    }
+    if &bytes[..TAP_MAGIC.len()] != TAP_MAGIC {
+        return Err(TapeError::BadMagic);
+    }
+    let dword = |i: usize| {
+        (bytes[i] as i64) | ((bytes[i + 1] as i64) << 8) | ((bytes[i + 2] as i64) << 16) |
+        ((bytes[i + 3] as i64) << 24)
+    };
+    let hz = dword(TAP_MAGIC.len());
+    let num = dword(TAP_MAGIC.len() + 4) as usize;
+    if hz == 0 {
+        return Err(TapeError::BadHeader);
+    }
+    if bytes.len() < header_size + num * 4 {
+        return Err(TapeError::TooShort);
+    }
+    Ok((0..num)
+        .map(|i| dword(header_size + i * 4) * clock.hz() / hz)
+        .collect())
+}
+
+/// convert pulses recorded at a CPU clock into a .tap pulse file
+pub fn write_tap(pulses: &[i64], clock: Clock) -> Vec<u8> {
+    let mut bytes = TAP_MAGIC.to_vec();
+    for &v in [clock.hz(), pulses.len() as i64].iter().chain(pulses) {
+        for i in 0..4 {
+            bytes.push((v >> (i * 8)) as u8);
+        }
+    }
+    bytes
+}
+
+// Z1013 tape signal half-period lengths in cycles at 2 MHz, as
+// generated by the monitor's save routine
+const Z1013_HZ: i64 = 2_000_000;
+const Z1013_LEAD: i64 = 1540;
+const Z1013_SYNC: i64 = 771;
+const Z1013_BIT0: i64 = 380;
+const Z1013_BIT1: i64 = 759;
+// number of lead-in half-periods before the first and all other blocks
+const Z1013_FIRST_LEAD_COUNT: usize = 2000;
+const Z1013_LEAD_COUNT: usize = 14;
+// data words per block
+const Z1013_BLOCK_WORDS: usize = 16;
+
+/// encode a tape file into the Z1013 tape signal
+///
+/// The data is split into 32 byte blocks, each block has a lead-in
+/// tone, 2 sync half-periods, the block address, 16 data words and a
+/// checksum word (sum of all words including the block address). Each
+/// word is sent LSB first, a 0-bit is a full period, a 1-bit a half
+/// period of twice the length. The tape signal can be loaded with the
+/// monitor's **L** command.
+pub fn z1013_encode(file: &TapeFile, clock: Clock) -> Vec<i64> {
+    let scale = |cycles: i64| cycles * clock.hz() / Z1013_HZ;
+    let mut pulses = Vec::new();
+    let put_word = |pulses: &mut Vec<i64>, w: u16| for bit in 0..16 {
+        if w & (1 << bit) != 0 {
+            pulses.push(scale(Z1013_BIT1));
+        } else {
+            pulses.push(scale(Z1013_BIT0));
+            pulses.push(scale(Z1013_BIT0));
+        }
+    };
+    let block_size = Z1013_BLOCK_WORDS * 2;
+    for (i, chunk) in file.data.chunks(block_size).enumerate() {
+        let lead = if i == 0 { Z1013_FIRST_LEAD_COUNT } else { Z1013_LEAD_COUNT };
+        for _ in 0..lead {
+            pulses.push(scale(Z1013_LEAD));
+        }
+        pulses.push(scale(Z1013_SYNC));
+        pulses.push(scale(Z1013_SYNC));
+        let addr = (file.load as usize + i * block_size) as u16;
+        put_word(&mut pulses, addr);
+        let mut sum = addr;
+        for w in 0..Z1013_BLOCK_WORDS {
+            let lo = *chunk.get(w * 2).unwrap_or(&0) as u16;
+            let hi = *chunk.get(w * 2 + 1).unwrap_or(&0) as u16;
+            let word = lo | (hi << 8);
+            sum = sum.wrapping_add(word);
+            put_word(&mut pulses, word);
+        }
+        put_word(&mut pulses, sum);
+    }
+    // trailing half-period so the last bit is complete
+    pulses.push(scale(Z1013_LEAD));
+    pulses
+}
+
+/// decode a Z1013 tape signal into (block address, 32 data bytes) blocks
+///
+/// Tapes saved with the monitor's **S** command have 0 as block address.
+/// Incomplete blocks at the end of the signal are ignored.
+pub fn z1013_decode(pulses: &[i64], clock: Clock) -> Result<Vec<(RegT, Vec<u8>)>, TapeError> {
+    let scale = |cycles: i64| cycles * clock.hz() / Z1013_HZ;
+    let short_max = scale((Z1013_BIT0 + Z1013_BIT1) / 2);
+    let long_max = scale((Z1013_BIT1 + Z1013_LEAD) / 2);
+    let is_lead = |p: i64| p > long_max;
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    'blocks: loop {
+        // find a lead-in tone followed by 2 sync half-periods
+        let mut lead = 0;
+        while pos < pulses.len() && (lead < 8 || is_lead(pulses[pos])) {
+            lead = if is_lead(pulses[pos]) { lead + 1 } else { 0 };
+            pos += 1;
+        }
+        pos += 2;
+        let mut words = [0u16; Z1013_BLOCK_WORDS + 2];
+        for word in words.iter_mut() {
+            for bit in 0..16 {
+                match pulses.get(pos) {
+                    Some(&p) if p <= short_max => pos += 2,
+                    Some(&p) if p <= long_max => {
+                        *word |= 1 << bit;
+                        pos += 1;
+                    }
+                    _ => break 'blocks,
+                }
+            }
+        }
+        let sum = words[..Z1013_BLOCK_WORDS + 1].iter().fold(0u16, |s, &w| s.wrapping_add(w));
+        if sum != words[Z1013_BLOCK_WORDS + 1] {
+            return Err(TapeError::Checksum(blocks.len()));
+        }
+        let data = words[1..Z1013_BLOCK_WORDS + 1]
+            .iter()
+            .flat_map(|&w| vec![w as u8, (w >> 8) as u8])
+            .collect();
+        blocks.push((words[0] as RegT, data));
+    }
+    Ok(blocks)
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn z80_file() {
+        let mut bytes = vec![0x00, 0x01, 0x03, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0, b'C', 0xD3,
+                             0xD3, 0xD3];
+        bytes.extend_from_slice(b"TEST            ");
+        // padded to a full block
+        bytes.extend_from_slice(&[1, 2, 3, 4, 0, 0, 0, 0]);
+        let file = TapeFile::from_z80(&bytes).unwrap();
+        assert_eq!(file.name, "TEST");
+        assert_eq!(file.kind, b'C');
+        assert_eq!((file.load, file.end, file.exec), (0x0100, 0x0103, 0x0100));
+        assert_eq!(file.data, [1, 2, 3, 4]);
+        assert_eq!(file.to_z80(), &bytes[..36]);
+
+        assert_eq!(TapeFile::from_z80(&bytes[..20]), Err(TapeError::TooShort));
+        bytes[14] = 0;
+        assert_eq!(TapeFile::from_z80(&bytes), Err(TapeError::BadMagic));
+    }
+
+    #[test]
+    fn kcc_file() {
+        let mut kcc = vec![b' '; 16];
+        kcc[..8].copy_from_slice(b"GAME    ");
+        kcc[8..11].copy_from_slice(b"COM");
+        kcc.extend_from_slice(&[3, 0x00, 0x02, 0x04, 0x02, 0x02, 0x02]);
+        kcc.resize(128, 0);
+        kcc.extend_from_slice(&[1, 2, 3, 4]);
+        kcc.resize(256, 0);
+        let file = TapeFile::from_kcc(&kcc).unwrap();
+        assert_eq!(file.name, "GAME.COM");
+        assert_eq!((file.load, file.end, file.exec), (0x0200, 0x0203, 0x0202));
+        assert_eq!(file.data, [1, 2, 3, 4]);
+
+        // the same file on a KC85 tape, the last block is incomplete
+        let mut tap = b"\xC3KC-TAPE by AF. ".to_vec();
+        tap.push(1);
+        tap.extend_from_slice(&kcc[..128]);
+        tap.push(0xFF);
+        tap.extend_from_slice(&kcc[128..]);
+        tap.extend_from_slice(&[0xFF, 0]);
+        assert_eq!(TapeFile::from_kc_tap(&tap), Ok(file));
+        assert_eq!(TapeFile::from_kc_tap(&kcc), Err(TapeError::BadMagic));
+
+        // without start address
+        kcc[16] = 2;
+        assert_eq!(TapeFile::parse(ProgramFormat::Kcc, &kcc).map(|f| f.exec), Ok(0x0200));
+        kcc[16] = 4;
+        assert_eq!(TapeFile::from_kcc(&kcc), Err(TapeError::BadHeader));
+        assert_eq!(TapeFile::from_kcc(&kcc[..128]), Err(TapeError::TooShort));
+        assert_eq!(TapeFile::from_com(&[0; 16], 0xFFF8), Err(TapeError::BadHeader));
+    }
+
+    #[test]
+    fn tap_file() {
+        let pulses = vec![1540, 771, 380, 759];
+        let bytes = write_tap(&pulses, Clock::new(2_000_000));
+        assert_eq!(bytes.len(), 12 + 8 + 16);
+        assert_eq!(read_tap(&bytes, Clock::new(2_000_000)), Ok(pulses));
+        // played back at a different clock
+        assert_eq!(read_tap(&bytes, Clock::new(1_000_000)), Ok(vec![770, 385, 190, 379]));
+        assert_eq!(read_tap(&bytes[..30], Clock::new(2_000_000)), Err(TapeError::TooShort));
+        assert_eq!(read_tap(b"C64-TAPE-RAW\0\0\0\0\0\0\0\0", Clock::new(1)),
+                   Err(TapeError::BadMagic));
+    }
+
+    #[test]
+    fn z1013_roundtrip() {
+        let clock = Clock::new(2_000_000);
+        let data: Vec<u8> = (0..40).map(|i| (i * 7 + 3) as u8).collect();
+        let file = TapeFile::new("TEST", b'C', 0x0300, 0x0300, data.clone());
+        let mut pulses = z1013_encode(&file, clock);
+        let blocks = z1013_decode(&pulses, clock).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0], (0x0300, data[..32].to_vec()));
+        assert_eq!(blocks[1].0, 0x0320);
+        assert_eq!(&blocks[1].1[..8], &data[32..]);
+        assert_eq!(&blocks[1].1[8..], &[0; 24]);
+
+        // flip a bit in the first block: a 1-bit becomes a 0-bit
+        let i = pulses.iter().position(|&p| p == Z1013_BIT1).unwrap();
+        pulses[i] = Z1013_BIT0;
+        pulses.insert(i, Z1013_BIT0);
+        assert_eq!(z1013_decode(&pulses, clock), Err(TapeError::Checksum(0)));
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::VecDeque;
+use machine::Machine;
+use RegT;
+
+/// an emulated system which can be driven by a TestKit
+pub trait TestSystem {
+    /// execute one instruction (and update the other chips), return the executed cycles
+    fn step(&mut self) -> i64;
+    /// set the currently pressed key as ASCII code, 0 if no key is pressed
+    fn put_key(&mut self, ascii: u8);
+    /// read a byte from the memory seen by the CPU
+    fn read_mem(&self, addr: RegT) -> u8;
+    /// the current screen content as text (e.g. from TextMode::screen_text())
+    fn screen_text(&self) -> String;
+}
+
+/// headless test harness for complete emulated systems
+///
+/// The TestKit runs a TestSystem frame by frame through a Machine,
+/// injects scripted key presses (each key is held down for a number
+/// of frames and then released for a number of frames, so that the
+/// keyboard scanning code of the emulated system sees every key), and
+/// checks conditions on the memory and screen content. The assertion
+/// functions panic with a dump of the screen, which makes failing
+/// tests easy to diagnose.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, Machine, NullBus, RegT, TestKit, TestSystem};
+///
+/// // a 'system' which stores the pressed key at address 0x1000
+/// struct System {
+///     cpu: CPU,
+/// }
+/// impl TestSystem for System {
+///     fn step(&mut self) -> i64 {
+///         self.cpu.step(&NullBus)
+///     }
+///     fn put_key(&mut self, ascii: u8) {
+///         self.cpu.mem.w8(0x1000, ascii as i32);
+///     }
+///     fn read_mem(&self, addr: RegT) -> u8 {
+///         self.cpu.mem.r8(addr) as u8
+///     }
+///     fn screen_text(&self) -> String {
+///         String::new()
+///     }
+/// }
+///
+/// let mut kit = TestKit::new(System { cpu: CPU::new_64k() }, Machine::new(1000));
+/// kit.type_text("A");
+/// kit.run_frames(1);
+/// kit.assert_mem(0x1000, b"A");
+/// kit.run_until(10, |sys| sys.read_mem(0x1000) == 0);
+/// kit.type_and_run("B");
+/// assert!(!kit.keys_pending());
+/// kit.assert_mem(0x1000, &[0]);
+/// ```
+pub struct TestKit<S: TestSystem> {
+    /// the system under test
+    pub system: S,
+    machine: Machine,
+    keys: VecDeque<(u8, u32)>,
+    press_frames: u32,
+    release_frames: u32,
+    hold_frames: u32,
+}
+
+impl<S: TestSystem> TestKit<S> {
+    /// create a test harness for a system, the Machine defines the frame length
+    pub fn new(system: S, machine: Machine) -> TestKit<S> {
+        TestKit {
+            system,
+            machine,
+            keys: VecDeque::new(),
+            press_frames: 2,
+            release_frames: 2,
+            hold_frames: 0,
+        }
+    }
+
+    /// number of frames a key is held down and released (default is 2 and 2)
+    pub fn set_key_frames(&mut self, press: u32, release: u32) {
+        assert!(press > 0 && release > 0);
+        self.press_frames = press;
+        self.release_frames = release;
+    }
+
+    /// the Machine which runs the system
+    pub fn machine(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    /// number of completed frames
+    pub fn frame_count(&self) -> u64 {
+        self.machine.frame_count()
+    }
+
+    /// queue a key press and release
+    pub fn press_key(&mut self, ascii: u8) {
+        self.keys.push_back((ascii, self.press_frames));
+        self.keys.push_back((0, self.release_frames));
+    }
+
+    /// queue key presses for a string, newlines are sent as Enter (0x0D)
+    pub fn type_text(&mut self, text: &str) {
+        for b in text.bytes() {
+            self.press_key(if b == b'\n' { 0x0D } else { b });
+        }
+    }
+
+    /// true while queued key presses haven't been sent
+    pub fn keys_pending(&self) -> bool {
+        self.hold_frames > 0 || !self.keys.is_empty()
+    }
+
+    /// run one frame, and forward the queued key presses
+    pub fn run_frame(&mut self) {
+        if self.hold_frames > 0 {
+            self.hold_frames -= 1;
+        }
+        if self.hold_frames == 0 {
+            if let Some((ascii, frames)) = self.keys.pop_front() {
+                self.system.put_key(ascii);
+                self.hold_frames = frames;
+            }
+        }
+        let system = &mut self.system;
+        self.machine.run(|| system.step());
+    }
+
+    /// run a number of frames
+    pub fn run_frames(&mut self, num_frames: u32) {
+        for _ in 0..num_frames {
+            self.run_frame();
+        }
+    }
+
+    /// run frames until the condition is true, return the number of frames
+    ///
+    /// Panics with a screen dump if the condition isn't true after max_frames.
+    pub fn run_until<F>(&mut self, max_frames: u32, cond: F) -> u32
+        where F: Fn(&S) -> bool
+    {
+        for frame in 0..max_frames {
+            if cond(&self.system) {
+                return frame;
+            }
+            self.run_frame();
+        }
+        if !cond(&self.system) {
+            self.fail(&format!("condition not met after {} frames", max_frames));
+        }
+        max_frames
+    }
+
+    /// run frames until the screen contains a text, return the number of frames
+    pub fn run_until_text(&mut self, text: &str, max_frames: u32) -> u32 {
+        self.run_until(max_frames, |sys| sys.screen_text().contains(text))
+    }
+
+    /// type a text and run frames until all keys have been sent
+    pub fn type_and_run(&mut self, text: &str) {
+        self.type_text(text);
+        while self.keys_pending() {
+            self.run_frame();
+        }
+    }
+
+    /// the current screen content as text
+    pub fn screen_text(&self) -> String {
+        self.system.screen_text()
+    }
+
+    /// panic with a screen dump if the screen doesn't contain a text
+    pub fn assert_text(&self, text: &str) {
+        if !self.screen_text().contains(text) {
+            self.fail(&format!("screen doesn't contain '{}'", text));
+        }
+    }
+
+    /// panic if the memory at an address doesn't contain the expected bytes
+    pub fn assert_mem(&self, addr: RegT, expected: &[u8]) {
+        let actual: Vec<u8> = (0..expected.len())
+            .map(|i| self.system.read_mem((addr + i as RegT) & 0xFFFF))
+            .collect();
+        if actual != expected {
+            self.fail(&format!("memory at {:04X} is {:02X?}, expected {:02X?}",
+                               addr,
+                               actual,
+                               expected));
+        }
+    }
+
+    fn fail(&self, msg: &str) -> ! {
+        panic!("{} (frame {})\n---- screen ----\n{}\n----------------",
+               msg,
+               self.frame_count(),
+               self.screen_text())
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CPU;
+    use bus::NullBus;
+
+    // a system where the CPU runs NOPs, the pressed keys are recorded
+    struct KeySystem {
+        cpu: CPU,
+        keys: Vec<(u64, u8)>,
+        frame: u64,
+    }
+
+    impl TestSystem for KeySystem {
+        fn step(&mut self) -> i64 {
+            self.cpu.step(&NullBus)
+        }
+        fn put_key(&mut self, ascii: u8) {
+            self.keys.push((self.cpu.cycles, ascii));
+        }
+        fn read_mem(&self, addr: RegT) -> u8 {
+            self.cpu.mem.r8(addr) as u8
+        }
+        fn screen_text(&self) -> String {
+            format!("frame {}", self.frame)
+        }
+    }
+
+    fn kit() -> TestKit<KeySystem> {
+        let system = KeySystem {
+            cpu: CPU::new_64k(),
+            keys: Vec::new(),
+            frame: 0,
+        };
+        TestKit::new(system, Machine::new(100))
+    }
+
+    #[test]
+    fn key_script() {
+        let mut kit = kit();
+        kit.set_key_frames(2, 1);
+        kit.type_text("A\n");
+        kit.run_frames(8);
+        assert!(!kit.keys_pending());
+        assert_eq!(kit.system.keys,
+                   [(0, b'A'), (200, 0), (300, 0x0D), (500, 0)]);
+    }
+
+    #[test]
+    fn run_until() {
+        let mut kit = kit();
+        assert_eq!(kit.run_until(10, |sys| sys.cpu.cycles >= 500), 5);
+        assert_eq!(kit.frame_count(), 5);
+        kit.assert_mem(0x0000, &[0, 0]);
+        kit.system.frame = 5;
+        kit.assert_text("frame 5");
+    }
+
+    #[test]
+    #[should_panic(expected = "screen doesn't contain 'READY'")]
+    fn assert_text() {
+        kit().assert_text("READY");
+    }
+
+    #[test]
+    #[should_panic(expected = "condition not met after 3 frames (frame 3)")]
+    fn timeout() {
+        kit().run_until(3, |_| false);
+    }
+}
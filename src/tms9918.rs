@@ -0,0 +1,479 @@
+use RegT;
+use bus::Bus;
+use video::Framebuffer;
+
+/// width of the TMS9918 framebuffer in pixels
+pub const TMS9918_WIDTH: usize = 256;
+/// height of the TMS9918 framebuffer in pixels
+pub const TMS9918_HEIGHT: usize = 192;
+/// number of scanlines per frame (NTSC)
+pub const TMS9918_LINES: usize = 262;
+
+const VRAM_SIZE: usize = 0x4000;
+const NUM_REGS: usize = 8;
+
+const STATUS_FRAME_INT: u8 = 1 << 7;
+const STATUS_FIFTH_SPRITE: u8 = 1 << 6;
+const STATUS_COLLISION: u8 = 1 << 5;
+
+// the 16 TMS9918 colors (ARGB), color 0 is transparent
+const COLORS: [u32; 16] = [
+    0xFF000000, 0xFF000000, 0xFF21C842, 0xFF5EDC78,
+    0xFF5455ED, 0xFF7D76FC, 0xFFD4524D, 0xFF42EBF5,
+    0xFFFC5554, 0xFFFF7978, 0xFFD4C154, 0xFFE6CE80,
+    0xFF21B03B, 0xFFC95BBA, 0xFFCCCCCC, 0xFFFFFFFF,
+];
+
+/// TMS9918A video chip emulation
+///
+/// The video chip of the MSX1, ColecoVision and SG-1000, with 16 KBytes
+/// VRAM, the 4 display modes Graphics I, Graphics II, Multicolor and
+/// Text, and 32 sprites (4 per scanline) with the 5th-sprite and
+/// sprite collision flags in the status register.
+///
+/// The CPU side has 2 ports, the control port (**write_control()**,
+/// **read_status()**), and the data port (**write_data()**,
+/// **read_data()**). Where these ports live in the I/O address space
+/// depends on the system (for instance 0x98/0x99 on the MSX, 0xBE/0xBF
+/// on the ColecoVision and SG-1000), so the system maps them in its
+/// Bus::cpu_inp() and Bus::cpu_outp() implementation (for instance
+/// with an IoMap).
+///
+/// The system must call **step_line()** once per scanline (228 CPU cycles
+/// at 3.58 MHz), this renders the current scanline into an RGBA8
+/// Framebuffer of TMS9918_WIDTH * TMS9918_HEIGHT pixels and sets the
+/// frame interrupt flag at the end of the visible area. Changes of
+/// the interrupt output are forwarded to Bus::tms9918_irq(), the
+/// interrupt output stays active until the status register is read.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Bus, Framebuffer, TMS9918, TMS9918_WIDTH, TMS9918_HEIGHT, TMS9918_LINES};
+///
+/// struct DummyBus;
+/// impl Bus for DummyBus { };
+///
+/// let mut tms = TMS9918::new();
+/// let mut fb = Framebuffer::new(TMS9918_WIDTH, TMS9918_HEIGHT);
+///
+/// // enable the display (register 1) and set the backdrop color to white (register 7)
+/// tms.write_control(0x40);
+/// tms.write_control(0x81);
+/// tms.write_control(0x0F);
+/// tms.write_control(0x87);
+///
+/// for _ in 0..TMS9918_LINES {
+///     tms.step_line(&DummyBus, &mut fb);
+/// }
+/// assert_eq!(fb[0], 0xFFFFFFFF);
+/// ```
+pub struct TMS9918 {
+    /// 16 KByte video memory
+    pub vram: [u8; VRAM_SIZE],
+    reg: [u8; NUM_REGS],
+    status: u8,
+    addr: usize,
+    latch: Option<u8>,
+    read_buffer: u8,
+    line: usize,
+    int_line: bool,
+}
+
+impl TMS9918 {
+    /// initialize a new TMS9918 object
+    pub fn new() -> TMS9918 {
+        TMS9918 {
+            vram: [0; VRAM_SIZE],
+            reg: [0; NUM_REGS],
+            status: 0,
+            addr: 0,
+            latch: None,
+            read_buffer: 0,
+            line: 0,
+            int_line: false,
+        }
+    }
+
+    /// reset the TMS9918
+    pub fn reset(&mut self) {
+        self.reg = [0; NUM_REGS];
+        self.status = 0;
+        self.addr = 0;
+        self.latch = None;
+        self.read_buffer = 0;
+        self.line = 0;
+        self.int_line = false;
+    }
+
+    /// get a register value
+    pub fn reg(&self, r: usize) -> RegT {
+        self.reg[r] as RegT
+    }
+
+    /// get the current scanline (0..TMS9918_LINES)
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// get the state of the interrupt output
+    pub fn int_line(&self) -> bool {
+        self.int_line
+    }
+
+    /// write the control port
+    pub fn write_control(&mut self, val: RegT) {
+        let val = val as u8;
+        match self.latch.take() {
+            None => {
+                self.latch = Some(val);
+            }
+            Some(lo) => {
+                if (val & 0x80) != 0 {
+                    self.reg[(val & 7) as usize] = lo;
+                } else {
+                    self.addr = ((val as usize & 0x3F) << 8) | lo as usize;
+                    if (val & 0x40) == 0 {
+                        // read setup, pre-fill the read buffer
+                        self.read_buffer = self.vram[self.addr];
+                        self.addr = (self.addr + 1) & (VRAM_SIZE - 1);
+                    }
+                }
+            }
+        }
+    }
+
+    /// read the status register, this clears the status flags and interrupt output
+    pub fn read_status(&mut self, bus: &dyn Bus) -> RegT {
+        let status = self.status;
+        self.status &= !(STATUS_FRAME_INT | STATUS_FIFTH_SPRITE | STATUS_COLLISION);
+        self.latch = None;
+        self.update_irq(bus);
+        status as RegT
+    }
+
+    /// write the data port
+    pub fn write_data(&mut self, val: RegT) {
+        self.latch = None;
+        self.vram[self.addr] = val as u8;
+        self.read_buffer = val as u8;
+        self.addr = (self.addr + 1) & (VRAM_SIZE - 1);
+    }
+
+    /// read the data port
+    pub fn read_data(&mut self) -> RegT {
+        self.latch = None;
+        let val = self.read_buffer;
+        self.read_buffer = self.vram[self.addr];
+        self.addr = (self.addr + 1) & (VRAM_SIZE - 1);
+        val as RegT
+    }
+
+    /// get the ARGB value of one of the 16 colors
+    pub fn color(index: usize) -> u32 {
+        COLORS[index & 15]
+    }
+
+    /// render the current scanline, update interrupts and advance to the next scanline
+    pub fn step_line(&mut self, bus: &dyn Bus, fb: &mut Framebuffer) {
+        let line = self.line;
+        if line < TMS9918_HEIGHT {
+            self.render_line(line, &mut fb.line_mut(line)[..TMS9918_WIDTH]);
+        }
+        if line == TMS9918_HEIGHT {
+            self.status |= STATUS_FRAME_INT;
+            self.update_irq(bus);
+        }
+        self.line = (line + 1) % TMS9918_LINES;
+    }
+
+    fn update_irq(&mut self, bus: &dyn Bus) {
+        let active = (self.status & STATUS_FRAME_INT) != 0 && (self.reg[1] & 0x20) != 0;
+        if active != self.int_line {
+            self.int_line = active;
+            bus.tms9918_irq(active);
+        }
+    }
+
+    fn render_line(&mut self, line: usize, out: &mut [u32]) {
+        let backdrop = (self.reg[7] & 0x0F) as usize;
+        let mut pixels = [backdrop as u8; TMS9918_WIDTH];
+        if (self.reg[1] & 0x40) != 0 {
+            let m1 = (self.reg[1] & 0x10) != 0;
+            let m2 = (self.reg[1] & 0x08) != 0;
+            let m3 = (self.reg[0] & 0x02) != 0;
+            if m1 {
+                self.render_text(line, &mut pixels);
+            } else {
+                if m2 {
+                    self.render_multicolor(line, &mut pixels);
+                } else {
+                    self.render_graphics(line, m3, &mut pixels);
+                }
+                self.render_sprites(line, &mut pixels);
+            }
+        }
+        for (p, &c) in out.iter_mut().zip(pixels.iter()) {
+            *p = COLORS[if c == 0 { backdrop } else { c as usize }];
+        }
+    }
+
+    fn render_text(&self, line: usize, pixels: &mut [u8]) {
+        let nt = ((self.reg[2] & 0x0F) as usize) << 10;
+        let pg = ((self.reg[4] & 0x07) as usize) << 11;
+        let fg = self.reg[7] >> 4;
+        let bg = self.reg[7] & 0x0F;
+        for col in 0..40 {
+            let name = self.vram[nt + (line >> 3) * 40 + col] as usize;
+            let bits = self.vram[pg + name * 8 + (line & 7)];
+            for px in 0..6 {
+                pixels[8 + col * 6 + px] = if (bits & (0x80 >> px)) != 0 { fg } else { bg };
+            }
+        }
+    }
+
+    fn render_multicolor(&self, line: usize, pixels: &mut [u8]) {
+        let nt = ((self.reg[2] & 0x0F) as usize) << 10;
+        let pg = ((self.reg[4] & 0x07) as usize) << 11;
+        for col in 0..32 {
+            let name = self.vram[nt + (line >> 3) * 32 + col] as usize;
+            let colors = self.vram[pg + name * 8 + ((line >> 3) & 3) * 2 + ((line >> 2) & 1)];
+            for px in 0..8 {
+                pixels[col * 8 + px] = if px < 4 { colors >> 4 } else { colors & 0x0F };
+            }
+        }
+    }
+
+    fn render_graphics(&self, line: usize, mode2: bool, pixels: &mut [u8]) {
+        let nt = ((self.reg[2] & 0x0F) as usize) << 10;
+        let row = line >> 3;
+        for col in 0..32 {
+            let name = self.vram[nt + row * 32 + col] as usize;
+            let (bits, colors) = if mode2 {
+                // Graphics II: 3 pattern and color tables, one for each third of the screen
+                let index = ((row >> 3) << 8 | name) * 8 + (line & 7);
+                let pg = ((self.reg[4] & 0x04) as usize) << 11;
+                let pg_mask = ((self.reg[4] & 0x03) as usize) << 11 | 0x07FF;
+                let ct = ((self.reg[3] & 0x80) as usize) << 6;
+                let ct_mask = ((self.reg[3] & 0x7F) as usize) << 6 | 0x003F;
+                (self.vram[pg | (index & pg_mask)], self.vram[ct | (index & ct_mask)])
+            } else {
+                let pg = ((self.reg[4] & 0x07) as usize) << 11;
+                let ct = (self.reg[3] as usize) << 6;
+                (self.vram[pg + name * 8 + (line & 7)], self.vram[ct + (name >> 3)])
+            };
+            for px in 0..8 {
+                pixels[col * 8 + px] = if (bits & (0x80 >> px)) != 0 {
+                    colors >> 4
+                } else {
+                    colors & 0x0F
+                };
+            }
+        }
+    }
+
+    fn render_sprites(&mut self, line: usize, pixels: &mut [u8]) {
+        let sat = ((self.reg[5] & 0x7F) as usize) << 7;
+        let spg = ((self.reg[6] & 0x07) as usize) << 11;
+        let size = if (self.reg[1] & 0x02) != 0 { 16 } else { 8 };
+        let mag = if (self.reg[1] & 0x01) != 0 { 2 } else { 1 };
+        let mut drawn = [false; TMS9918_WIDTH];
+        let mut count = 0;
+        for i in 0..32 {
+            let attr = sat + i * 4;
+            let y = self.vram[attr];
+            if y == 0xD0 {
+                break;
+            }
+            // the sprite is displayed one line below its y coordinate,
+            // y coordinates above 0xE0 are partially offscreen at the top
+            let sy = if y > 0xE0 { y as isize - 255 } else { y as isize + 1 };
+            let row = line as isize - sy;
+            if row < 0 || row >= (size * mag) as isize {
+                continue;
+            }
+            count += 1;
+            if count > 4 {
+                if (self.status & STATUS_FIFTH_SPRITE) == 0 {
+                    self.status = (self.status & 0xE0) | STATUS_FIFTH_SPRITE | i as u8;
+                }
+                break;
+            }
+            let row = row as usize / mag;
+            let mut x = self.vram[attr + 1] as isize;
+            let mut name = self.vram[attr + 2] as usize;
+            let color = self.vram[attr + 3];
+            if (color & 0x80) != 0 {
+                // early clock bit
+                x -= 32;
+            }
+            if size == 16 {
+                name &= 0xFC;
+            }
+            for px in 0..(size * mag) {
+                let sx = x + px as isize;
+                if sx < 0 || sx >= TMS9918_WIDTH as isize {
+                    continue;
+                }
+                let sx = sx as usize;
+                let bx = px / mag;
+                // 16x16 sprites: the right half comes 16 bytes later
+                let addr = spg + name * 8 + row + if bx >= 8 { 16 } else { 0 };
+                if (self.vram[addr] & (0x80 >> (bx & 7))) == 0 {
+                    continue;
+                }
+                if drawn[sx] {
+                    self.status |= STATUS_COLLISION;
+                    continue;
+                }
+                drawn[sx] = true;
+                if (color & 0x0F) != 0 {
+                    pixels[sx] = color & 0x0F;
+                }
+            }
+        }
+    }
+}
+
+impl Default for TMS9918 {
+    fn default() -> TMS9918 {
+        TMS9918::new()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use Bus;
+
+    struct TestBus {
+        irq: Cell<bool>,
+    }
+    impl Bus for TestBus {
+        fn tms9918_irq(&self, active: bool) {
+            self.irq.set(active);
+        }
+    }
+
+    fn set_reg(tms: &mut TMS9918, r: RegT, val: RegT) {
+        tms.write_control(val);
+        tms.write_control(0x80 | r);
+    }
+
+    fn render(tms: &mut TMS9918, fb: &mut Framebuffer) {
+        let bus = TestBus { irq: Cell::new(false) };
+        for _ in 0..TMS9918_LINES {
+            tms.step_line(&bus, fb);
+        }
+    }
+
+    #[test]
+    fn ports() {
+        let mut tms = TMS9918::new();
+        set_reg(&mut tms, 7, 0xF4);
+        assert_eq!(tms.reg(7), 0xF4);
+        tms.write_control(0x00);
+        tms.write_control(0x40 | 0x38);
+        tms.write_data(0x11);
+        tms.write_data(0x22);
+        assert_eq!(tms.vram[0x3800], 0x11);
+        assert_eq!(tms.vram[0x3801], 0x22);
+        tms.write_control(0x00);
+        tms.write_control(0x38);
+        assert_eq!(tms.read_data(), 0x11);
+        assert_eq!(tms.read_data(), 0x22);
+    }
+
+    #[test]
+    fn interrupt() {
+        let bus = TestBus { irq: Cell::new(false) };
+        let mut tms = TMS9918::new();
+        let mut fb = Framebuffer::new(TMS9918_WIDTH, TMS9918_HEIGHT);
+        set_reg(&mut tms, 1, 0x20);
+        for _ in 0..(TMS9918_HEIGHT + 1) {
+            tms.step_line(&bus, &mut fb);
+        }
+        assert!(bus.irq.get());
+        assert_eq!(tms.read_status(&bus) as u8 & STATUS_FRAME_INT, STATUS_FRAME_INT);
+        assert!(!bus.irq.get());
+        assert_eq!(tms.read_status(&bus) as u8 & STATUS_FRAME_INT, 0);
+    }
+
+    #[test]
+    fn graphics1() {
+        let mut tms = TMS9918::new();
+        let mut fb = Framebuffer::new(TMS9918_WIDTH, TMS9918_HEIGHT);
+        // name table 0x1800, color table 0x2000, patterns 0x0000
+        set_reg(&mut tms, 1, 0x40);
+        set_reg(&mut tms, 2, 0x06);
+        set_reg(&mut tms, 3, 0x80);
+        set_reg(&mut tms, 4, 0x00);
+        set_reg(&mut tms, 7, 0x01);
+        // pattern 8 = left half set, colors: red on transparent
+        for row in 0..8 {
+            tms.vram[8 * 8 + row] = 0xF0;
+        }
+        tms.vram[0x2001] = 0x60;
+        tms.vram[0x1800 + 33] = 8;
+        render(&mut tms, &mut fb);
+        let y = 8 * TMS9918_WIDTH;
+        assert_eq!(fb[y + 7], TMS9918::color(1));
+        assert_eq!(fb[y + 8], TMS9918::color(6));
+        assert_eq!(fb[y + 11], TMS9918::color(6));
+        assert_eq!(fb[y + 12], TMS9918::color(1));
+    }
+
+    #[test]
+    fn text() {
+        let mut tms = TMS9918::new();
+        let mut fb = Framebuffer::new(TMS9918_WIDTH, TMS9918_HEIGHT);
+        set_reg(&mut tms, 1, 0x50);
+        set_reg(&mut tms, 2, 0x06);
+        set_reg(&mut tms, 4, 0x00);
+        set_reg(&mut tms, 7, 0xF4);
+        tms.vram[8] = 0x80;
+        tms.vram[0x1800 + 1] = 1;
+        render(&mut tms, &mut fb);
+        // the first column starts at x=8, 6 pixels per character
+        assert_eq!(fb[7], TMS9918::color(4));
+        assert_eq!(fb[8 + 6], TMS9918::color(15));
+        assert_eq!(fb[8 + 7], TMS9918::color(4));
+    }
+
+    #[test]
+    fn sprites() {
+        let bus = TestBus { irq: Cell::new(false) };
+        let mut tms = TMS9918::new();
+        let mut fb = Framebuffer::new(TMS9918_WIDTH, TMS9918_HEIGHT);
+        // sprite attributes at 0x1B00, sprite patterns at 0x3800
+        set_reg(&mut tms, 1, 0x40);
+        set_reg(&mut tms, 5, 0x36);
+        set_reg(&mut tms, 6, 0x07);
+        for row in 0..8 {
+            tms.vram[0x3800 + row] = 0xFF;
+        }
+        // 5 sprites on line 10, the first 2 overlap
+        for i in 0..5 {
+            let attr = 0x1B00 + i * 4;
+            tms.vram[attr] = 9;
+            tms.vram[attr + 1] = if i == 0 { 0 } else { (i * 16) as u8 - 12 };
+            tms.vram[attr + 2] = 0;
+            tms.vram[attr + 3] = 2 + i as u8;
+        }
+        tms.vram[0x1B00 + 20] = 0xD0;
+        render(&mut tms, &mut fb);
+        let y = 10 * TMS9918_WIDTH;
+        assert_eq!(fb[y], TMS9918::color(2));
+        assert_eq!(fb[y + 7], TMS9918::color(2));
+        assert_eq!(fb[y + 8], TMS9918::color(3));
+        assert_eq!(fb[y + 36], TMS9918::color(5));
+        // the 5th sprite isn't visible
+        assert_eq!(fb[y + 52], TMS9918::color(0));
+        let status = tms.read_status(&bus) as u8;
+        assert_eq!(status & STATUS_COLLISION, STATUS_COLLISION);
+        assert_eq!(status & STATUS_FIFTH_SPRITE, STATUS_FIFTH_SPRITE);
+        assert_eq!(status & 0x1F, 4);
+    }
+}
@@ -0,0 +1,527 @@
+use RegT;
+use bus::Bus;
+use video::Framebuffer;
+use machine::PROFILE_SMS;
+
+/// width of the VDP framebuffer in pixels
+pub const VDP_WIDTH: usize = 256;
+/// height of the VDP framebuffer in pixels
+pub const VDP_HEIGHT: usize = 192;
+/// number of scanlines per frame (NTSC)
+pub const VDP_LINES: usize = PROFILE_SMS.lines_per_frame as usize;
+/// number of CPU cycles per scanline
+pub const VDP_CYCLES_PER_LINE: i64 = PROFILE_SMS.cycles_per_line;
+
+const VRAM_SIZE: usize = 0x4000;
+const CRAM_SIZE: usize = 32;
+const NUM_REGS: usize = 11;
+
+const STATUS_FRAME_INT: u8 = 1 << 7;
+const STATUS_OVERFLOW: u8 = 1 << 6;
+const STATUS_COLLISION: u8 = 1 << 5;
+
+/// Sega Master System VDP (315-5124) emulation
+///
+/// Emulates the SMS video display processor in its native 'mode 4'
+/// (the legacy TMS9918 modes are not supported), with 16 KBytes VRAM,
+/// 32 bytes color RAM, scrollable background, 64 sprites (8 per
+/// scanline), and frame- and line-interrupts.
+///
+/// The CPU accesses the VDP through the control port (**write_control()**,
+/// **read_status()**) and the data port (**write_data()**, **read_data()**),
+/// and can read the current scanline with **read_vcounter()**, and the
+/// light gun position latched from Bus::lightpen() with **read_hcounter()**.
+/// On the SMS, the data port is at 0xBE and the control port at 0xBF
+/// (mirrored throughout 0x80..0xBF), the V counter is read from port
+/// 0x7E and the H counter from port 0x7F.
+///
+/// The system must call **step_line()** every VDP_CYCLES_PER_LINE
+/// CPU cycles, this renders the current scanline into an RGBA8
+/// Framebuffer of VDP_WIDTH * VDP_HEIGHT pixels, and updates the
+/// interrupt state. Changes of the VDP interrupt output are forwarded
+/// to Bus::vdp_irq(), the interrupt output stays active until the
+/// CPU reads the status register.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Bus, Framebuffer, VDP, VDP_WIDTH, VDP_HEIGHT, VDP_LINES};
+///
+/// struct DummyBus;
+/// impl Bus for DummyBus { };
+///
+/// let mut vdp = VDP::new();
+/// let mut fb = Framebuffer::new(VDP_WIDTH, VDP_HEIGHT);
+///
+/// // set the backdrop color to color RAM entry 16...
+/// vdp.write_control(0x00);
+/// vdp.write_control(0x87);
+/// // ...and set color RAM entry 16 to bright red
+/// vdp.write_control(0x10);
+/// vdp.write_control(0xC0);
+/// vdp.write_data(0x03);
+///
+/// // render a frame (with the display disabled, only the backdrop is visible)
+/// for _ in 0..VDP_LINES {
+///     vdp.step_line(&DummyBus, &mut fb);
+/// }
+/// assert_eq!(fb[0], 0xFFFF0000);
+/// ```
+pub struct VDP {
+    /// 16 KByte video memory
+    pub vram: [u8; VRAM_SIZE],
+    /// 32 bytes color memory (--BBGGRR)
+    pub cram: [u8; CRAM_SIZE],
+    reg: [u8; NUM_REGS],
+    status: u8,
+    addr: usize,
+    code: u8,
+    latch: Option<u8>,
+    read_buffer: u8,
+    line: usize,
+    line_counter: u8,
+    line_int: bool,
+    int_line: bool,
+    hcounter: u8,
+}
+
+impl VDP {
+    /// initialize a new VDP object
+    pub fn new() -> VDP {
+        VDP {
+            vram: [0; VRAM_SIZE],
+            cram: [0; CRAM_SIZE],
+            reg: [0; NUM_REGS],
+            status: 0,
+            addr: 0,
+            code: 0,
+            latch: None,
+            read_buffer: 0,
+            line: 0,
+            line_counter: 0xFF,
+            line_int: false,
+            int_line: false,
+            hcounter: 0,
+        }
+    }
+
+    /// reset the VDP
+    pub fn reset(&mut self) {
+        self.reg = [0; NUM_REGS];
+        self.status = 0;
+        self.addr = 0;
+        self.code = 0;
+        self.latch = None;
+        self.read_buffer = 0;
+        self.line = 0;
+        self.line_counter = 0xFF;
+        self.line_int = false;
+        self.int_line = false;
+        self.hcounter = 0;
+    }
+
+    /// get a register value
+    pub fn reg(&self, r: usize) -> RegT {
+        self.reg[r] as RegT
+    }
+
+    /// get the current scanline (0..VDP_LINES)
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// get the state of the interrupt output
+    pub fn int_line(&self) -> bool {
+        self.int_line
+    }
+
+    /// write the control port
+    pub fn write_control(&mut self, val: RegT) {
+        let val = val as u8;
+        match self.latch.take() {
+            None => {
+                self.latch = Some(val);
+                self.addr = (self.addr & 0x3F00) | val as usize;
+            }
+            Some(lo) => {
+                self.code = val >> 6;
+                self.addr = ((val as usize & 0x3F) << 8) | lo as usize;
+                match self.code {
+                    0 => {
+                        // VRAM read setup, pre-fill the read buffer
+                        self.read_buffer = self.vram[self.addr];
+                        self.addr = (self.addr + 1) & (VRAM_SIZE - 1);
+                    }
+                    2 => {
+                        let r = (val & 0x0F) as usize;
+                        if r < NUM_REGS {
+                            self.reg[r] = lo;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// read the status register, this clears the status flags and interrupt output
+    pub fn read_status(&mut self, bus: &dyn Bus) -> RegT {
+        let status = self.status;
+        self.status = 0;
+        self.line_int = false;
+        self.latch = None;
+        self.update_irq(bus);
+        status as RegT
+    }
+
+    /// write the data port (to VRAM or color RAM)
+    pub fn write_data(&mut self, val: RegT) {
+        self.latch = None;
+        if self.code == 3 {
+            self.cram[self.addr & (CRAM_SIZE - 1)] = val as u8;
+        } else {
+            self.vram[self.addr] = val as u8;
+        }
+        self.read_buffer = val as u8;
+        self.addr = (self.addr + 1) & (VRAM_SIZE - 1);
+    }
+
+    /// read the data port
+    pub fn read_data(&mut self) -> RegT {
+        self.latch = None;
+        let val = self.read_buffer;
+        self.read_buffer = self.vram[self.addr];
+        self.addr = (self.addr + 1) & (VRAM_SIZE - 1);
+        val as RegT
+    }
+
+    /// read the V counter
+    pub fn read_vcounter(&self) -> RegT {
+        // NTSC: 0x00..0xDA, then jumps back to 0xD5..0xFF
+        if self.line <= 0xDA {
+            self.line as RegT
+        } else {
+            (self.line - 6) as RegT
+        }
+    }
+
+    /// read the latched H counter
+    ///
+    /// The H counter is latched when the light gun sees the beam, it
+    /// counts in steps of 2 pixels from the left edge of the display.
+    pub fn read_hcounter(&self) -> RegT {
+        self.hcounter as RegT
+    }
+
+    /// convert a color RAM entry into an RGBA8 color
+    pub fn color(&self, index: usize) -> u32 {
+        let c = self.cram[index & (CRAM_SIZE - 1)] as u32;
+        let r = (c & 3) * 0x55;
+        let g = ((c >> 2) & 3) * 0x55;
+        let b = ((c >> 4) & 3) * 0x55;
+        0xFF000000 | (r << 16) | (g << 8) | b
+    }
+
+    /// render the current scanline, update interrupts and advance to the next scanline
+    pub fn step_line(&mut self, bus: &dyn Bus, fb: &mut Framebuffer) {
+        let line = self.line;
+        if line < VDP_HEIGHT {
+            self.render_line(line, &mut fb.line_mut(line)[..VDP_WIDTH]);
+            if let Some((x, y)) = bus.lightpen() {
+                if y as usize == line {
+                    self.hcounter = (x >> 1) as u8;
+                }
+            }
+        }
+
+        // the line counter is decremented on each active line (and the
+        // first line after), and reloaded on underflow and in the vblank
+        if line <= VDP_HEIGHT {
+            let (counter, underflow) = self.line_counter.overflowing_sub(1);
+            if underflow {
+                self.line_counter = self.reg[10];
+                self.line_int = true;
+            } else {
+                self.line_counter = counter;
+            }
+        } else {
+            self.line_counter = self.reg[10];
+        }
+        if line == VDP_HEIGHT {
+            self.status |= STATUS_FRAME_INT;
+        }
+        self.update_irq(bus);
+
+        self.line = (line + 1) % VDP_LINES;
+    }
+
+    fn update_irq(&mut self, bus: &dyn Bus) {
+        let active = ((self.status & STATUS_FRAME_INT) != 0 && (self.reg[1] & 0x20) != 0) ||
+                     (self.line_int && (self.reg[0] & 0x10) != 0);
+        if active != self.int_line {
+            self.int_line = active;
+            bus.vdp_irq(active);
+        }
+    }
+
+    fn render_line(&mut self, line: usize, out: &mut [u32]) {
+        let backdrop = self.color(16 + (self.reg[7] & 0x0F) as usize);
+        if (self.reg[1] & 0x40) == 0 {
+            // display disabled
+            for p in out.iter_mut() {
+                *p = backdrop;
+            }
+            return;
+        }
+
+        // background: palette index, and whether the pixel has priority over sprites
+        let mut bg = [(0u8, false); VDP_WIDTH];
+        let nt_base = ((self.reg[2] & 0x0E) as usize) << 10;
+        let scroll_x = if (self.reg[0] & 0x40) != 0 && line < 16 {
+            0
+        } else {
+            self.reg[8] as usize
+        };
+        for (x, pixel) in bg.iter_mut().enumerate() {
+            let scroll_y = if (self.reg[0] & 0x80) != 0 && x >= 192 {
+                0
+            } else {
+                self.reg[9] as usize
+            };
+            let bx = x.wrapping_sub(scroll_x) & 0xFF;
+            let by = (line + scroll_y) % 224;
+            let entry_addr = nt_base + ((by >> 3) * 32 + (bx >> 3)) * 2;
+            let entry = self.vram[entry_addr] as usize | (self.vram[entry_addr + 1] as usize) << 8;
+            let tile = entry & 0x1FF;
+            let px = if (entry & 0x200) != 0 { 7 - (bx & 7) } else { bx & 7 };
+            let py = if (entry & 0x400) != 0 { 7 - (by & 7) } else { by & 7 };
+            let index = self.tile_pixel(tile, py, px);
+            let pal = if (entry & 0x800) != 0 { 16 } else { 0 };
+            *pixel = (pal + index, index != 0 && (entry & 0x1000) != 0);
+        }
+        for (p, &(index, _)) in out.iter_mut().zip(bg.iter()) {
+            *p = self.color(index as usize);
+        }
+
+        // sprites, the first sprite in the attribute table has the highest priority
+        let mut drawn = [false; VDP_WIDTH];
+        let sat = ((self.reg[5] & 0x7E) as usize) << 7;
+        let height = if (self.reg[1] & 0x02) != 0 { 16 } else { 8 };
+        let mut count = 0;
+        for i in 0..64 {
+            let y = self.vram[sat + i] as usize;
+            if y == 0xD0 {
+                break;
+            }
+            let row = line.wrapping_sub(y + 1) & 0xFF;
+            if row >= height {
+                continue;
+            }
+            count += 1;
+            if count > 8 {
+                self.status |= STATUS_OVERFLOW;
+                break;
+            }
+            let mut x = self.vram[sat + 0x80 + i * 2] as isize;
+            if (self.reg[0] & 0x08) != 0 {
+                x -= 8;
+            }
+            let mut tile = self.vram[sat + 0x81 + i * 2] as usize;
+            if (self.reg[6] & 0x04) != 0 {
+                tile |= 0x100;
+            }
+            if height == 16 {
+                tile &= !1;
+            }
+            for px in 0..8 {
+                let sx = x + px as isize;
+                if sx < 0 || sx >= VDP_WIDTH as isize {
+                    continue;
+                }
+                let sx = sx as usize;
+                let index = self.tile_pixel(tile, row, px);
+                if index == 0 {
+                    continue;
+                }
+                if drawn[sx] {
+                    self.status |= STATUS_COLLISION;
+                    continue;
+                }
+                drawn[sx] = true;
+                if !bg[sx].1 {
+                    out[sx] = self.color(16 + index as usize);
+                }
+            }
+        }
+
+        // optionally blank the leftmost column
+        if (self.reg[0] & 0x20) != 0 {
+            for p in &mut out[0..8] {
+                *p = backdrop;
+            }
+        }
+    }
+
+    /// get the 4-bit color index of a pixel in a tile (rows 8..15 continue in the next tile)
+    #[inline(always)]
+    fn tile_pixel(&self, tile: usize, row: usize, px: usize) -> u8 {
+        let addr = (tile * 32 + row * 4) & (VRAM_SIZE - 1);
+        let bit = 7 - px;
+        ((self.vram[addr] >> bit) & 1) | (((self.vram[addr + 1] >> bit) & 1) << 1) |
+        (((self.vram[addr + 2] >> bit) & 1) << 2) | (((self.vram[addr + 3] >> bit) & 1) << 3)
+    }
+}
+
+impl Default for VDP {
+    fn default() -> VDP {
+        VDP::new()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use Bus;
+
+    struct TestBus {
+        irq: Cell<bool>,
+    }
+    impl Bus for TestBus {
+        fn vdp_irq(&self, active: bool) {
+            self.irq.set(active);
+        }
+    }
+
+    fn set_reg(vdp: &mut VDP, r: RegT, val: RegT) {
+        vdp.write_control(val);
+        vdp.write_control(0x80 | r);
+    }
+
+    fn set_addr(vdp: &mut VDP, code: RegT, addr: RegT) {
+        vdp.write_control(addr & 0xFF);
+        vdp.write_control((code << 6) | (addr >> 8));
+    }
+
+    #[test]
+    fn ports() {
+        let mut vdp = VDP::new();
+        set_reg(&mut vdp, 2, 0xFF);
+        assert_eq!(vdp.reg(2), 0xFF);
+        set_addr(&mut vdp, 1, 0x1234);
+        vdp.write_data(0x11);
+        vdp.write_data(0x22);
+        assert_eq!(vdp.vram[0x1234], 0x11);
+        assert_eq!(vdp.vram[0x1235], 0x22);
+        set_addr(&mut vdp, 0, 0x1234);
+        assert_eq!(vdp.read_data(), 0x11);
+        assert_eq!(vdp.read_data(), 0x22);
+        set_addr(&mut vdp, 3, 0x0021);
+        vdp.write_data(0x3F);
+        assert_eq!(vdp.cram[1], 0x3F);
+        assert_eq!(vdp.color(1), 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn interrupts() {
+        let bus = TestBus { irq: Cell::new(false) };
+        let mut vdp = VDP::new();
+        let mut fb = Framebuffer::new(VDP_WIDTH, VDP_HEIGHT);
+
+        // frame interrupt enabled
+        set_reg(&mut vdp, 1, 0x20);
+        for _ in 0..VDP_HEIGHT {
+            vdp.step_line(&bus, &mut fb);
+        }
+        assert!(!bus.irq.get());
+        vdp.step_line(&bus, &mut fb);
+        assert!(bus.irq.get());
+        assert_eq!(vdp.read_status(&bus) as u8 & STATUS_FRAME_INT, STATUS_FRAME_INT);
+        assert!(!bus.irq.get());
+        assert_eq!(vdp.read_status(&bus), 0);
+        while vdp.line() != 0 {
+            vdp.step_line(&bus, &mut fb);
+        }
+
+        // line interrupt every 10 lines
+        set_reg(&mut vdp, 1, 0x00);
+        set_reg(&mut vdp, 0, 0x10);
+        set_reg(&mut vdp, 10, 9);
+        vdp.line_counter = 9;
+        for _ in 0..10 {
+            assert!(!bus.irq.get());
+            vdp.step_line(&bus, &mut fb);
+        }
+        assert!(bus.irq.get());
+        vdp.read_status(&bus);
+        assert!(!bus.irq.get());
+    }
+
+    #[test]
+    fn render() {
+        let bus = TestBus { irq: Cell::new(false) };
+        let mut vdp = VDP::new();
+        let mut fb = Framebuffer::new(VDP_WIDTH, VDP_HEIGHT);
+
+        // name table at 0x3800, sprite table at 0x3F00, display on
+        set_reg(&mut vdp, 2, 0x0E);
+        set_reg(&mut vdp, 5, 0x7E);
+        set_reg(&mut vdp, 1, 0x40);
+        // palette: 1 = red, 17 = green
+        set_addr(&mut vdp, 3, 1);
+        vdp.write_data(0x03);
+        set_addr(&mut vdp, 3, 17);
+        vdp.write_data(0x0C);
+        // tile 1: first row all color 1
+        vdp.vram[32] = 0xFF;
+        // top-left background tile is tile 1
+        vdp.vram[0x3800] = 1;
+        // sprite 0 at (16,0) with tile 1, end of sprite list
+        vdp.vram[0x3F00] = 0xFF;
+        vdp.vram[0x3F01] = 0xD0;
+        vdp.vram[0x3F80] = 16;
+        vdp.vram[0x3F81] = 1;
+
+        vdp.step_line(&bus, &mut fb);
+        assert_eq!(fb[0], 0xFFFF0000);
+        assert_eq!(fb[7], 0xFFFF0000);
+        assert_eq!(fb[8], 0xFF000000);
+        assert_eq!(fb[16], 0xFF00FF00);
+        assert_eq!(fb[23], 0xFF00FF00);
+        assert_eq!(fb[24], 0xFF000000);
+
+        // horizontal scrolling
+        set_reg(&mut vdp, 8, 4);
+        while vdp.line() != 0 {
+            vdp.step_line(&bus, &mut fb);
+        }
+        vdp.step_line(&bus, &mut fb);
+        assert_eq!(fb[3], 0xFF000000);
+        assert_eq!(fb[4], 0xFFFF0000);
+        assert_eq!(fb[11], 0xFFFF0000);
+        assert_eq!(fb[12], 0xFF000000);
+    }
+
+    struct GunBus {
+        pos: (RegT, RegT),
+    }
+    impl Bus for GunBus {
+        fn lightpen(&self) -> Option<(RegT, RegT)> {
+            Some(self.pos)
+        }
+    }
+
+    #[test]
+    fn lightgun() {
+        let bus = GunBus { pos: (100, 50) };
+        let mut vdp = VDP::new();
+        let mut fb = Framebuffer::new(VDP_WIDTH, VDP_HEIGHT);
+        for _ in 0..50 {
+            vdp.step_line(&bus, &mut fb);
+        }
+        assert_eq!(vdp.read_hcounter(), 0);
+        vdp.step_line(&bus, &mut fb);
+        assert_eq!(vdp.read_hcounter(), 50);
+    }
+}
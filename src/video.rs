@@ -0,0 +1,471 @@
+use std::mem;
+use std::ops::Deref;
+#[cfg(feature = "png")]
+use std::{fs, io};
+#[cfg(feature = "png")]
+use std::path::Path;
+#[cfg(feature = "png")]
+use rom::Crc32;
+
+/// a rectangle in framebuffer pixel coordinates
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    /// create a rectangle from position and size
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect { x, y, width, height }
+    }
+}
+
+/// linear RGBA8 framebuffer with a color palette and a dirty rectangle list
+///
+/// Video decoders write into the framebuffer (either RGBA8 colors, or
+/// palette indices with **set_index()**), and mark the changed areas as
+/// dirty. The front-end blits the pixels (the framebuffer dereferences
+/// to a slice of RGBA8 pixels) and can skip the blit, or only blit the
+/// changed areas returned by **take_dirty()**.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Framebuffer, Rect};
+///
+/// let mut fb = Framebuffer::new(320, 200);
+/// fb.set_palette(&[0xFF000000, 0xFFFFFFFF]);
+/// fb.set_index(10, 20, 1);
+/// assert_eq!(fb.pixel(10, 20), 0xFFFFFFFF);
+/// assert_eq!(fb[20 * 320 + 10], 0xFFFFFFFF);
+///
+/// // the two rows are merged into one dirty rectangle
+/// fb.line_mut(0)[0] = 0xFF0000FF;
+/// fb.line_mut(1)[0] = 0xFF0000FF;
+/// assert_eq!(fb.take_dirty(), [Rect::new(10, 20, 1, 1), Rect::new(0, 0, 320, 2)]);
+/// assert!(!fb.is_dirty());
+/// ```
+#[derive(Clone,Debug)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>,
+    palette: Vec<u32>,
+    dirty: Vec<Rect>,
+}
+
+impl Framebuffer {
+    /// create a black framebuffer with an empty palette
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        assert!(width > 0 && height > 0);
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![0xFF000000; width * height],
+            palette: Vec::new(),
+            dirty: Vec::new(),
+        }
+    }
+
+    /// width in pixels
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// height in pixels
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// all pixels, row by row
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    /// mutable access to all pixels, marks the whole framebuffer as dirty
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        self.mark_all_dirty();
+        &mut self.pixels
+    }
+
+    /// mutable access to one pixel row, marks the row as dirty
+    pub fn line_mut(&mut self, y: usize) -> &mut [u32] {
+        let width = self.width;
+        self.mark_dirty(Rect::new(0, y, width, 1));
+        &mut self.pixels[y * width..(y + 1) * width]
+    }
+
+    /// get the RGBA8 color of a pixel
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.pixels[y * self.width + x]
+    }
+
+    /// set a pixel to an RGBA8 color
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: u32) {
+        self.pixels[y * self.width + x] = color;
+        self.mark_dirty(Rect::new(x, y, 1, 1));
+    }
+
+    /// set a pixel to a palette color
+    pub fn set_index(&mut self, x: usize, y: usize, index: usize) {
+        let color = self.color(index);
+        self.set_pixel(x, y, color);
+    }
+
+    /// fill the framebuffer with an RGBA8 color
+    pub fn clear(&mut self, color: u32) {
+        for p in self.pixels_mut() {
+            *p = color;
+        }
+    }
+
+    /// replace the palette
+    pub fn set_palette(&mut self, palette: &[u32]) {
+        self.palette = palette.to_vec();
+    }
+
+    /// the palette colors
+    pub fn palette(&self) -> &[u32] {
+        &self.palette
+    }
+
+    /// get the RGBA8 color of a palette entry (wraps around)
+    pub fn color(&self, index: usize) -> u32 {
+        assert!(!self.palette.is_empty());
+        self.palette[index % self.palette.len()]
+    }
+
+    /// add a changed area to the dirty list (clipped to the framebuffer)
+    ///
+    /// A rectangle directly below the previous rectangle with the same
+    /// horizontal extent is merged into it, so a video decoder which
+    /// marks each rendered scanline produces a single rectangle.
+    pub fn mark_dirty(&mut self, rect: Rect) {
+        if rect.x >= self.width || rect.y >= self.height {
+            return;
+        }
+        let rect = Rect::new(rect.x,
+                             rect.y,
+                             rect.width.min(self.width - rect.x),
+                             rect.height.min(self.height - rect.y));
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        if let Some(last) = self.dirty.last_mut() {
+            if last.x == rect.x && last.width == rect.width && last.y + last.height == rect.y {
+                last.height += rect.height;
+                return;
+            }
+        }
+        self.dirty.push(rect);
+    }
+
+    /// mark the whole framebuffer as dirty
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty.clear();
+        self.dirty.push(Rect::new(0, 0, self.width, self.height));
+    }
+
+    /// true if any area has changed since the last take_dirty()
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// return and clear the dirty rectangle list
+    pub fn take_dirty(&mut self) -> Vec<Rect> {
+        mem::take(&mut self.dirty)
+    }
+}
+
+#[cfg(feature = "png")]
+impl Framebuffer {
+    /// encode the framebuffer as (uncompressed) RGBA PNG image
+    ///
+    /// Only available with the **png** feature.
+    pub fn to_png(&self) -> Vec<u8> {
+        fn chunk(png: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            let start = png.len();
+            png.extend_from_slice(kind);
+            png.extend_from_slice(data);
+            let mut crc = Crc32::new();
+            crc.update_bytes(&png[start..]);
+            png.extend_from_slice(&crc.finish().to_be_bytes());
+        }
+
+        // raw image data: filter type 0 and RGBA bytes for each row
+        let mut raw = Vec::with_capacity((self.width * 4 + 1) * self.height);
+        for row in self.pixels.chunks(self.width) {
+            raw.push(0);
+            for &p in row {
+                raw.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, p as u8, (p >> 24) as u8]);
+            }
+        }
+        // zlib stream with stored deflate blocks
+        let mut zlib = vec![0x78, 0x01];
+        let num_blocks = raw.len().div_ceil(0xFFFF);
+        for (i, block) in raw.chunks(0xFFFF).enumerate() {
+            zlib.push(if i + 1 == num_blocks { 1 } else { 0 });
+            let len = block.len() as u16;
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(!len).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in &raw {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&(self.width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGBA, no interlacing
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1A\n".to_vec();
+        chunk(&mut png, b"IHDR", &ihdr);
+        chunk(&mut png, b"IDAT", &zlib);
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// write the framebuffer to a PNG file
+    ///
+    /// Only available with the **png** feature.
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_png())
+    }
+}
+
+impl Deref for Framebuffer {
+    type Target = [u32];
+    fn deref(&self) -> &[u32] {
+        &self.pixels
+    }
+}
+
+/// text-mode video decoder with an 8x8 pixel font
+///
+/// Decodes a character video memory (one byte per character, row by
+/// row) into the top-left **width()** x **height()** pixels of a
+/// Framebuffer, and marks this area as dirty. The font has 8 bytes per character, one byte per pixel row,
+/// with the leftmost pixel in bit 7.
+///
+/// Systems with a color attribute memory (one attribute byte per
+/// character) use **decode_attrs()** with a function which maps an
+/// attribute byte to the foreground and background color.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{TextMode, Framebuffer};
+///
+/// // a font where character 1 has the top-left pixel set
+/// let mut font = vec![0u8; 256 * 8];
+/// font[8] = 0x80;
+///
+/// let text = TextMode::new(2, 1, &font);
+/// let mut fb = Framebuffer::new(text.width(), text.height());
+/// text.decode(&[1, 0], &mut fb);
+/// assert_eq!(fb[0], 0xFFFFFFFF);
+/// assert_eq!(fb[1], 0xFF000000);
+///
+/// // color attributes: upper nibble foreground, lower nibble background
+/// text.decode_attrs(&[1, 0], &[0x10, 0x02], &mut fb, |attr| {
+///     let color = |c: u8| 0xFF000000 | c as u32;
+///     (color(attr >> 4), color(attr & 0xF))
+/// });
+/// assert_eq!(fb[0], 0xFF000001);
+/// assert_eq!(fb[1], 0xFF000000);
+/// assert_eq!(fb[8], 0xFF000002);
+/// ```
+#[derive(Clone,Debug)]
+pub struct TextMode {
+    columns: usize,
+    rows: usize,
+    font: Vec<u8>,
+    fg: u32,
+    bg: u32,
+}
+
+impl TextMode {
+    /// create a text mode with the number of columns and rows and the font data
+    pub fn new(columns: usize, rows: usize, font: &[u8]) -> TextMode {
+        assert!(columns > 0 && rows > 0 && font.len() >= 8);
+        TextMode {
+            columns,
+            rows,
+            font: font.to_vec(),
+            fg: 0xFFFFFFFF,
+            bg: 0xFF000000,
+        }
+    }
+
+    /// set the foreground and background color used by decode() (default is white on black)
+    pub fn set_colors(&mut self, fg: u32, bg: u32) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    /// number of character columns
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    /// number of character rows
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// framebuffer width in pixels
+    pub fn width(&self) -> usize {
+        self.columns * 8
+    }
+
+    /// framebuffer height in pixels
+    pub fn height(&self) -> usize {
+        self.rows * 8
+    }
+
+    /// decode the video memory with the foreground and background color
+    pub fn decode(&self, video_mem: &[u8], fb: &mut Framebuffer) {
+        let (fg, bg) = (self.fg, self.bg);
+        self.decode_chars(video_mem, fb, |_| (fg, bg));
+    }
+
+    /// decode the video memory with per-character colors from the attribute memory
+    pub fn decode_attrs<F>(&self, video_mem: &[u8], attr_mem: &[u8], fb: &mut Framebuffer, colors: F)
+        where F: Fn(u8) -> (u32, u32)
+    {
+        self.decode_chars(video_mem, fb, |i| colors(attr_mem[i]))
+    }
+
+    /// extract the characters of the video memory as text
+    ///
+    /// Character codes 0x20..0x7E are converted to ASCII characters,
+    /// all others to spaces. Rows are separated by newlines, trailing
+    /// spaces of each row are removed.
+    pub fn screen_text(&self, video_mem: &[u8]) -> String {
+        self.screen_text_with(video_mem, |c| if (0x20..0x7F).contains(&c) { c as char } else { ' ' })
+    }
+
+    /// extract the characters of the video memory as text with a custom character mapping
+    pub fn screen_text_with<F>(&self, video_mem: &[u8], to_char: F) -> String
+        where F: Fn(u8) -> char
+    {
+        assert!(video_mem.len() >= self.columns * self.rows);
+        let rows: Vec<String> = video_mem[..self.columns * self.rows]
+            .chunks(self.columns)
+            .map(|row| row.iter().map(|&c| to_char(c)).collect::<String>().trim_end().to_string())
+            .collect();
+        rows.join("\n")
+    }
+
+    fn decode_chars<F>(&self, video_mem: &[u8], fb: &mut Framebuffer, colors: F)
+        where F: Fn(usize) -> (u32, u32)
+    {
+        assert!(video_mem.len() >= self.columns * self.rows);
+        assert!(fb.width() >= self.width() && fb.height() >= self.height());
+        let num_chars = self.font.len() / 8;
+        let fb_width = fb.width();
+        for y in 0..self.rows {
+            for py in 0..8 {
+                let start = (y * 8 + py) * fb_width;
+                let mut fb_iter = fb.pixels[start..start + self.width()].iter_mut();
+                for x in 0..self.columns {
+                    let i = y * self.columns + x;
+                    let chr = video_mem[i] as usize % num_chars;
+                    let bits = self.font[(chr << 3) | py];
+                    let (fg, bg) = colors(i);
+                    for px in 0..8 {
+                        let pixel = if (bits & (0x80 >> px)) != 0 { fg } else { bg };
+                        *fb_iter.next().unwrap() = pixel;
+                    }
+                }
+            }
+        }
+        fb.mark_dirty(Rect::new(0, 0, self.width(), self.height()));
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        // character 0 is empty, character 1 a diagonal line
+        let mut font = vec![0u8; 16];
+        for i in 0..8 {
+            font[8 + i] = 0x80 >> i;
+        }
+        let mut text = TextMode::new(2, 2, &font);
+        text.set_colors(1, 0);
+        assert_eq!((text.width(), text.height()), (16, 16));
+        let mut fb = Framebuffer::new(24, 16);
+        text.decode(&[0, 1, 1, 0], &mut fb);
+        assert_eq!(fb.take_dirty(), [Rect::new(0, 0, 16, 16)]);
+        for y in 0..16 {
+            for x in 0..16 {
+                let expected = if (x < 8) != (y < 8) && x % 8 == y % 8 { 1 } else { 0 };
+                assert_eq!(fb.pixel(x, y), expected);
+            }
+            // pixels right of the text area are not touched
+            assert_eq!(fb.pixel(16, y), 0xFF000000);
+        }
+        // character codes wrap around for small fonts
+        text.decode(&[2, 3, 2, 2], &mut fb);
+        assert_eq!(fb.pixel(8, 0), 1);
+        assert_eq!(fb.pixel(0, 0), 0);
+    }
+
+    #[test]
+    fn framebuffer() {
+        let mut fb = Framebuffer::new(4, 4);
+        assert!(!fb.is_dirty());
+        fb.set_palette(&[1, 2, 3]);
+        fb.set_index(3, 3, 4);
+        assert_eq!(fb.pixel(3, 3), 2);
+        // clipped, and rectangles outside the framebuffer are ignored
+        fb.mark_dirty(Rect::new(2, 2, 8, 8));
+        fb.mark_dirty(Rect::new(4, 0, 1, 1));
+        assert_eq!(fb.take_dirty(), [Rect::new(3, 3, 1, 1), Rect::new(2, 2, 2, 2)]);
+        fb.set_pixel(0, 0, 5);
+        fb.clear(7);
+        assert_eq!(fb.take_dirty(), [Rect::new(0, 0, 4, 4)]);
+        assert!(fb.iter().all(|&p| p == 7));
+    }
+
+    #[test]
+    fn screen_text() {
+        let text = TextMode::new(4, 3, &[0; 8]);
+        let mut video_mem = b"AB  ".to_vec();
+        video_mem.extend_from_slice(&[0, 0x80, b'x', 0]);
+        video_mem.extend_from_slice(b" 123");
+        assert_eq!(text.screen_text(&video_mem), "AB\n  x\n 123");
+        let line = TextMode::new(4, 1, &[0; 8]);
+        assert_eq!(line.screen_text_with(&video_mem, |c| (c + 1) as char), "BC!!");
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn png() {
+        let mut fb = Framebuffer::new(3, 2);
+        fb.set_pixel(1, 0, 0x80112233);
+        let png = fb.to_png();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1A\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 3, 0, 0, 0, 2, 8, 6, 0, 0, 0]);
+        // IDAT: 2 rows of 1 filter byte + 3 RGBA pixels in a single stored block
+        let idat = &png[33 + 8..];
+        assert_eq!(&idat[..4], &[0x78, 0x01, 0x01, 26]);
+        assert_eq!(&idat[7..15], &[0, 0, 0, 0, 255, 0x11, 0x22, 0x33]);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+    }
+}
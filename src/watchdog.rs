@@ -0,0 +1,125 @@
+use RegT;
+
+/// a detector for runaway loops, see CPU::set_watchdog()
+///
+/// The watchdog trips when the PC has stayed within a small address
+/// range for a number of cycles without any I/O access or memory write,
+/// which usually means that the emulated system is stuck (for instance
+/// a ROM waiting for hardware which isn't emulated, or a failed boot).
+/// Leaving the range, I/O and memory writes restart the watch.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, NullBus, StopReason, Watchdog};
+///
+/// let mut cpu = CPU::new_64k();
+/// // LOOP: JR LOOP
+/// cpu.mem.write(0x0100, &[0x18, 0xFE]);
+/// cpu.reg.set_pc(0x0100);
+/// cpu.set_watchdog(Watchdog::new(16, 100_000));
+/// let (_, reason) = cpu.step_until(&NullBus, 1_000_000, |_| false);
+/// assert_eq!(reason, StopReason::Runaway);
+/// assert_eq!(cpu.watchdog().unwrap().range(), (0x0100, 0x0100));
+/// ```
+#[derive(Clone,Debug)]
+pub struct Watchdog {
+    size: RegT,
+    timeout: u64,
+    lo: RegT,
+    hi: RegT,
+    start: u64,
+    mem_gen: u32,
+    tripped: bool,
+}
+
+impl Watchdog {
+    /// create a watchdog for a PC range of size bytes and a timeout in cycles
+    pub fn new(size: RegT, timeout: u64) -> Watchdog {
+        Watchdog {
+            size,
+            timeout,
+            lo: 0,
+            hi: 0,
+            start: 0,
+            mem_gen: 0,
+            tripped: false,
+        }
+    }
+
+    /// the size of the PC range
+    pub fn size(&self) -> RegT {
+        self.size
+    }
+
+    /// the number of cycles without progress until the watchdog trips
+    pub fn timeout(&self) -> u64 {
+        self.timeout
+    }
+
+    /// the lowest and highest PC since the watch was last restarted
+    pub fn range(&self) -> (RegT, RegT) {
+        (self.lo, self.hi)
+    }
+
+    /// true if the watchdog has tripped and there was no progress since
+    pub fn is_tripped(&self) -> bool {
+        self.tripped
+    }
+
+    /// restart the watch at pc
+    pub fn restart(&mut self, pc: RegT, cycles: u64, mem_gen: u32) {
+        self.lo = pc;
+        self.hi = pc;
+        self.start = cycles;
+        self.mem_gen = mem_gen;
+        self.tripped = false;
+    }
+
+    /// update with the state after a step, true if the watchdog has just tripped
+    ///
+    /// mem_gen is a counter which changes on memory writes, io whether
+    /// the step has accessed an I/O port.
+    pub fn update(&mut self, pc: RegT, cycles: u64, mem_gen: u32, io: bool) -> bool {
+        let lo = self.lo.min(pc);
+        let hi = self.hi.max(pc);
+        if io || mem_gen != self.mem_gen || hi - lo >= self.size {
+            self.restart(pc, cycles, mem_gen);
+            return false;
+        }
+        self.lo = lo;
+        self.hi = hi;
+        if !self.tripped && cycles.saturating_sub(self.start) >= self.timeout {
+            self.tripped = true;
+            return true;
+        }
+        false
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update() {
+        let mut wd = Watchdog::new(4, 100);
+        wd.restart(0x1000, 0, 0);
+        assert!(!wd.update(0x1003, 50, 0, false));
+        assert!(wd.update(0x1000, 100, 0, false));
+        assert!(wd.is_tripped());
+        assert_eq!(wd.range(), (0x1000, 0x1003));
+        // only reported once
+        assert!(!wd.update(0x1001, 200, 0, false));
+
+        // leaving the range, I/O and memory writes restart the watch
+        assert!(!wd.update(0x1004, 210, 0, false));
+        assert!(!wd.is_tripped());
+        assert_eq!(wd.range(), (0x1004, 0x1004));
+        assert!(!wd.update(0x1004, 300, 0, true));
+        assert!(!wd.update(0x1004, 390, 1, false));
+        assert!(!wd.update(0x1004, 489, 1, false));
+        assert!(wd.update(0x1004, 490, 1, false));
+    }
+}
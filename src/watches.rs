@@ -0,0 +1,182 @@
+use RegT;
+use memory::Memory;
+
+/// when a watch delivers its sampled value
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum WatchMode {
+    /// on each sample
+    Always,
+    /// on the first sample, and when the value has changed since the last sample
+    OnChange,
+}
+
+/// a watched memory range
+#[derive(Clone,Debug)]
+pub struct Watch {
+    /// a name for displaying the watch (e.g. 'SCORE')
+    pub name: String,
+    /// start address of the watched range
+    pub addr: RegT,
+    /// number of watched bytes
+    pub len: usize,
+    pub mode: WatchMode,
+    /// disabled watches are not sampled
+    pub enabled: bool,
+    value: Option<Vec<u8>>,
+}
+
+impl Watch {
+    /// the last sampled value (empty before the first sample)
+    pub fn value(&self) -> &[u8] {
+        self.value.as_ref().map_or(&[], |v| v.as_slice())
+    }
+
+    /// the last sampled value as little endian number (of the first 4 bytes)
+    pub fn number(&self) -> u32 {
+        self.value().iter().take(4).rev().fold(0, |n, &b| (n << 8) | b as u32)
+    }
+
+    /// sample the memory range, true if the value should be delivered
+    pub fn sample(&mut self, mem: &Memory) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let mut value = vec![0u8; self.len];
+        mem.read_slice(self.addr, &mut value);
+        let changed = self.value.as_ref() != Some(&value);
+        self.value = Some(value);
+        changed || self.mode == WatchMode::Always
+    }
+}
+
+/// a list of watched memory ranges which are sampled once per frame
+///
+/// A lightweight alternative to watchpoints for displaying emulator
+/// state in the host (like the score of a game, or a variable
+/// inspector): the host calls **sample()** after each frame, which
+/// reads the watched memory ranges and calls a callback for each
+/// watch whose value should be delivered according to its WatchMode.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Memory, Watches, WatchMode};
+///
+/// let mut mem = Memory::new_64k();
+/// let mut watches = Watches::new();
+/// let score = watches.add("SCORE", 0x4000, 2, WatchMode::OnChange);
+/// let lives = watches.add("LIVES", 0x4002, 1, WatchMode::Always);
+///
+/// let mut delivered = Vec::new();
+/// watches.sample(&mem, |id, w| delivered.push((id, w.number())));
+/// assert_eq!(delivered, [(score, 0), (lives, 0)]);
+///
+/// // the score only shows up again when it has changed
+/// mem.w16(0x4000, 1234);
+/// delivered.clear();
+/// watches.sample(&mem, |id, w| delivered.push((id, w.number())));
+/// watches.sample(&mem, |id, w| delivered.push((id, w.number())));
+/// assert_eq!(delivered, [(score, 1234), (lives, 0), (lives, 0)]);
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct Watches {
+    watches: Vec<Option<Watch>>,
+}
+
+impl Watches {
+    /// create an empty watch list
+    pub fn new() -> Watches {
+        Watches { watches: Vec::new() }
+    }
+
+    /// add a watch on a memory range, return its id
+    pub fn add(&mut self, name: &str, addr: RegT, len: usize, mode: WatchMode) -> usize {
+        self.watches.push(Some(Watch {
+            name: name.to_string(),
+            addr,
+            len,
+            mode,
+            enabled: true,
+            value: None,
+        }));
+        self.watches.len() - 1
+    }
+
+    /// remove a watch, the ids of other watches are unchanged
+    pub fn remove(&mut self, id: usize) -> Option<Watch> {
+        self.watches.get_mut(id).and_then(|w| w.take())
+    }
+
+    /// remove all watches
+    pub fn clear(&mut self) {
+        self.watches.clear();
+    }
+
+    /// get a watch by id
+    pub fn get(&self, id: usize) -> Option<&Watch> {
+        self.watches.get(id).and_then(|w| w.as_ref())
+    }
+
+    /// get a mutable watch by id
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Watch> {
+        self.watches.get_mut(id).and_then(|w| w.as_mut())
+    }
+
+    /// iterate over the watches and their ids
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Watch)> {
+        self.watches.iter().enumerate().filter_map(|(id, w)| w.as_ref().map(|w| (id, w)))
+    }
+
+    /// sample all watches, and call the callback for each delivered watch
+    ///
+    /// Returns the number of delivered watches.
+    pub fn sample<F>(&mut self, mem: &Memory, mut callback: F) -> usize
+        where F: FnMut(usize, &Watch)
+    {
+        let mut num = 0;
+        for (id, w) in self.watches.iter_mut().enumerate() {
+            if let Some(ref mut w) = *w {
+                if w.sample(mem) {
+                    callback(id, w);
+                    num += 1;
+                }
+            }
+        }
+        num
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample() {
+        let mut mem = Memory::new_64k();
+        let mut watches = Watches::new();
+        let a = watches.add("A", 0xFFFE, 4, WatchMode::OnChange);
+        let b = watches.add("B", 0x1000, 1, WatchMode::OnChange);
+        assert_eq!(watches.get(a).unwrap().value(), []);
+        assert_eq!(watches.sample(&mem, |_, _| ()), 2);
+        assert_eq!(watches.sample(&mem, |_, _| ()), 0);
+
+        // the range wraps around at 64k
+        mem.w8(0x0001, 0x12);
+        let mut ids = Vec::new();
+        assert_eq!(watches.sample(&mem, |id, _| ids.push(id)), 1);
+        assert_eq!(ids, [a]);
+        assert_eq!(watches.get(a).unwrap().value(), [0, 0, 0, 0x12]);
+        assert_eq!(watches.get(a).unwrap().number(), 0x12000000);
+
+        // disabled and removed watches are not sampled
+        watches.get_mut(a).unwrap().enabled = false;
+        mem.w8(0x1000, 1);
+        mem.w8(0x0000, 1);
+        assert_eq!(watches.sample(&mem, |id, _| assert_eq!(id, b)), 1);
+        assert!(watches.remove(b).is_some());
+        assert!(watches.remove(b).is_none());
+        assert_eq!(watches.iter().map(|(id, w)| (id, w.name.as_str())).collect::<Vec<_>>(),
+                   [(a, "A")]);
+    }
+}
@@ -0,0 +1,301 @@
+use RegT;
+use memory::Memory;
+use machine::{Clock, MachineProfile, PROFILE_Z9001};
+use video::Framebuffer;
+
+/// the number of character columns
+pub const Z9001_COLUMNS: usize = 40;
+/// the number of character rows
+pub const Z9001_ROWS: usize = 24;
+/// the border width in pixels around the text area
+pub const Z9001_BORDER: usize = 8;
+/// the framebuffer width in pixels (text area and border)
+pub const Z9001_WIDTH: usize = Z9001_COLUMNS * 8 + 2 * Z9001_BORDER;
+/// the framebuffer height in pixels (text area and border)
+pub const Z9001_HEIGHT: usize = Z9001_ROWS * 8 + 2 * Z9001_BORDER;
+/// the address of the video RAM (one character code per character)
+pub const Z9001_VIDEO_RAM: RegT = 0xEC00;
+/// the address of the color RAM (one color attribute per character)
+pub const Z9001_COLOR_RAM: RegT = 0xE800;
+
+// the blink flip-flop toggles every 0.32 seconds
+const BLINK_PERIOD: i64 = PROFILE_Z9001.clock.hz() * 8 / 25;
+
+// the 8 colors of the color module: black, red, green, yellow, blue, purple, cyan, white
+const COLORS: [u32; 8] = [
+    0xFF000000, 0xFFFF0000, 0xFF00FF00, 0xFFFFFF00,
+    0xFF0000FF, 0xFFFF00FF, 0xFF00FFFF, 0xFFFFFFFF,
+];
+
+/// the models of the Z9001 family
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Z9001Model {
+    /// the original Z9001, OS ROM at 0xF000, BASIC loaded from tape or a ROM module
+    Z9001,
+    /// the renamed Z9001, identical hardware
+    KC85_1,
+    /// the KC87, with the BASIC ROM at 0xC000
+    KC87,
+}
+
+/// a Z9001 family configuration
+///
+/// All models have the same 2.4576 MHz CPU, a 40x24 text display with
+/// the video RAM at 0xEC00, and two PIOs and a CTC. The optional color
+/// module adds the color RAM at 0xE800 (one attribute byte per
+/// character), and a border color. The KC87 usually came with the color
+/// module, the Z9001 and KC85/1 without.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Z9001Config {
+    pub model: Z9001Model,
+    /// the color module is installed
+    pub color: bool,
+}
+
+impl Z9001Config {
+    /// the usual configuration of a model
+    pub fn new(model: Z9001Model) -> Z9001Config {
+        Z9001Config {
+            model,
+            color: model == Z9001Model::KC87,
+        }
+    }
+
+    /// the configuration with or without color module
+    pub fn with_color(self, color: bool) -> Z9001Config {
+        Z9001Config { color, ..self }
+    }
+
+    /// the name of the model
+    pub fn name(&self) -> &'static str {
+        match self.model {
+            Z9001Model::Z9001 => "Z9001",
+            Z9001Model::KC85_1 => "KC85/1",
+            Z9001Model::KC87 => "KC87",
+        }
+    }
+
+    /// true if the model has the BASIC ROM at 0xC000
+    pub fn has_basic_rom(&self) -> bool {
+        self.model == Z9001Model::KC87
+    }
+
+    /// the CPU clock
+    pub fn clock(&self) -> Clock {
+        PROFILE_Z9001.clock
+    }
+
+    /// the CPU and video timing
+    pub fn profile(&self) -> MachineProfile {
+        PROFILE_Z9001
+    }
+}
+
+/// Z9001 / KC85/1 / KC87 video output
+///
+/// Decodes the 40x24 video RAM with the 8x8 character font (which lives
+/// in a ROM that's not visible to the CPU) into a framebuffer of
+/// Z9001_WIDTH x Z9001_HEIGHT pixels, the text area is surrounded by
+/// a border of Z9001_BORDER pixels.
+///
+/// Without color module, the characters are white on black. With color
+/// module, each color attribute byte has the foreground color in bits
+/// 4..6 and the background color in bits 0..2, bit 7 makes the character
+/// blink (foreground and background are swapped while the blink
+/// flip-flop is set). The border color is written to bits 3..5 of PIO1
+/// channel A (**pio1_a()**).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Memory, Framebuffer, Z9001Video, Z9001Config, Z9001Model, Z9001_WIDTH,
+///            Z9001_HEIGHT, Z9001_BORDER};
+///
+/// // a font where character 1 has the top-left pixel set
+/// let mut font = vec![0u8; 256 * 8];
+/// font[8] = 0x80;
+///
+/// let mut mem = Memory::new_64k();
+/// mem.w8(0xEC00, 1);
+/// mem.w8(0xE800, 0x21);
+///
+/// let mut video = Z9001Video::new(Z9001Config::new(Z9001Model::KC87), &font);
+/// video.pio1_a(0x20);
+/// let mut fb = Framebuffer::new(Z9001_WIDTH, Z9001_HEIGHT);
+/// video.decode_mem(&mem, &mut fb);
+/// // green on red, blue border
+/// assert_eq!(fb.pixel(Z9001_BORDER, Z9001_BORDER), 0xFF00FF00);
+/// assert_eq!(fb.pixel(Z9001_BORDER + 1, Z9001_BORDER), 0xFFFF0000);
+/// assert_eq!(fb.pixel(0, 0), 0xFF0000FF);
+/// ```
+#[derive(Clone,Debug)]
+pub struct Z9001Video {
+    config: Z9001Config,
+    font: Vec<u8>,
+    border: u8,
+    blink: bool,
+    blink_counter: i64,
+}
+
+impl Z9001Video {
+    /// create the video output for a configuration with the font data
+    pub fn new(config: Z9001Config, font: &[u8]) -> Z9001Video {
+        assert!(font.len() >= 8);
+        Z9001Video {
+            config,
+            font: font.to_vec(),
+            border: 0,
+            blink: false,
+            blink_counter: BLINK_PERIOD,
+        }
+    }
+
+    /// the configuration
+    pub fn config(&self) -> Z9001Config {
+        self.config
+    }
+
+    /// output of PIO1 channel A, bits 3..5 are the border color
+    pub fn pio1_a(&mut self, data: RegT) {
+        self.border = ((data >> 3) & 7) as u8;
+    }
+
+    /// the border color as RGBA8 (always black without color module)
+    pub fn border_color(&self) -> u32 {
+        if self.config.color {
+            COLORS[self.border as usize]
+        } else {
+            COLORS[0]
+        }
+    }
+
+    /// the state of the blink flip-flop
+    pub fn blink(&self) -> bool {
+        self.blink
+    }
+
+    /// advance the blink flip-flop by a number of CPU cycles
+    pub fn update(&mut self, cycles: i64) {
+        self.blink_counter -= cycles;
+        while self.blink_counter <= 0 {
+            self.blink = !self.blink;
+            self.blink_counter += BLINK_PERIOD;
+        }
+    }
+
+    /// decode the video and color RAM into the framebuffer
+    ///
+    /// The color RAM is ignored without color module.
+    pub fn decode(&self, video_mem: &[u8], color_mem: &[u8], fb: &mut Framebuffer) {
+        let num_chars = Z9001_COLUMNS * Z9001_ROWS;
+        assert!(video_mem.len() >= num_chars);
+        assert!(!self.config.color || color_mem.len() >= num_chars);
+        assert!(fb.width() >= Z9001_WIDTH && fb.height() >= Z9001_HEIGHT);
+        let border = self.border_color();
+        let num_font_chars = self.font.len() / 8;
+        let fb_width = fb.width();
+        let pixels = fb.pixels_mut();
+        for y in 0..Z9001_HEIGHT {
+            let line = &mut pixels[y * fb_width..y * fb_width + Z9001_WIDTH];
+            let text_y = y.wrapping_sub(Z9001_BORDER);
+            if text_y >= Z9001_ROWS * 8 {
+                for p in line.iter_mut() {
+                    *p = border;
+                }
+                continue;
+            }
+            let (left, rest) = line.split_at_mut(Z9001_BORDER);
+            let (_, right) = rest.split_at_mut(Z9001_COLUMNS * 8);
+            for p in left.iter_mut().chain(right.iter_mut()) {
+                *p = border;
+            }
+            for x in 0..Z9001_COLUMNS {
+                let i = (text_y / 8) * Z9001_COLUMNS + x;
+                let chr = video_mem[i] as usize % num_font_chars;
+                let bits = self.font[(chr << 3) | (text_y & 7)];
+                let (fg, bg) = self.colors(if self.config.color { color_mem[i] } else { 0x70 });
+                let start = Z9001_BORDER + x * 8;
+                for (px, p) in line[start..start + 8].iter_mut().enumerate() {
+                    *p = if (bits & (0x80 >> px)) != 0 { fg } else { bg };
+                }
+            }
+        }
+    }
+
+    /// decode the video and color RAM of the CPU address space into the framebuffer
+    pub fn decode_mem(&self, mem: &Memory, fb: &mut Framebuffer) {
+        let mut video_mem = [0u8; Z9001_COLUMNS * Z9001_ROWS];
+        let mut color_mem = [0u8; Z9001_COLUMNS * Z9001_ROWS];
+        mem.read_slice(Z9001_VIDEO_RAM, &mut video_mem);
+        if self.config.color {
+            mem.read_slice(Z9001_COLOR_RAM, &mut color_mem);
+        }
+        self.decode(&video_mem, &color_mem, fb);
+    }
+
+    // foreground and background color of a color attribute byte
+    fn colors(&self, attr: u8) -> (u32, u32) {
+        let fg = COLORS[((attr >> 4) & 7) as usize];
+        let bg = COLORS[(attr & 7) as usize];
+        if (attr & 0x80) != 0 && self.blink {
+            (bg, fg)
+        } else {
+            (fg, bg)
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a font where character 1 is a filled block
+    fn font() -> Vec<u8> {
+        let mut font = vec![0u8; 16];
+        for i in 0..8 {
+            font[8 + i] = 0xFF;
+        }
+        font
+    }
+
+    #[test]
+    fn configs() {
+        let kc87 = Z9001Config::new(Z9001Model::KC87);
+        assert!(kc87.color && kc87.has_basic_rom());
+        let z9001 = Z9001Config::new(Z9001Model::Z9001);
+        assert!(!z9001.color && !z9001.has_basic_rom());
+        assert!(z9001.with_color(true).color);
+        assert_eq!(Z9001Config::new(Z9001Model::KC85_1).name(), "KC85/1");
+        assert_eq!(kc87.clock().hz(), 2_457_600);
+    }
+
+    #[test]
+    fn mono_and_blink() {
+        let video_mem = [1u8; Z9001_COLUMNS * Z9001_ROWS];
+        let color_mem = [0x92u8; Z9001_COLUMNS * Z9001_ROWS];
+        let mut fb = Framebuffer::new(Z9001_WIDTH, Z9001_HEIGHT);
+        let (x, y) = (Z9001_BORDER + 39 * 8 + 7, Z9001_BORDER + 23 * 8 + 7);
+
+        // without color module, the color RAM and border color are ignored
+        let mut mono = Z9001Video::new(Z9001Config::new(Z9001Model::Z9001), &font());
+        mono.pio1_a(0x38);
+        mono.decode(&video_mem, &[], &mut fb);
+        assert_eq!(fb.pixel(x, y), 0xFFFFFFFF);
+        assert_eq!(fb.pixel(x + 1, y), 0xFF000000);
+
+        // blinking red on green, white border
+        let mut color = Z9001Video::new(Z9001Config::new(Z9001Model::KC87), &font());
+        color.pio1_a(0x38);
+        color.decode(&video_mem, &color_mem, &mut fb);
+        assert_eq!(fb.pixel(x, y), 0xFFFF0000);
+        assert_eq!(fb.pixel(x + 1, y), 0xFFFFFFFF);
+        assert_eq!(fb.pixel(x, y + 1), 0xFFFFFFFF);
+        color.update(BLINK_PERIOD - 1);
+        assert!(!color.blink());
+        color.update(1);
+        assert!(color.blink());
+        color.decode(&video_mem, &color_mem, &mut fb);
+        assert_eq!(fb.pixel(x, y), 0xFF00FF00);
+    }
+}
@@ -0,0 +1,291 @@
+use RegT;
+use memory::Memory;
+use iomap::PortDecoder;
+
+const BANK_SIZE: usize = 0x4000;
+
+/// the paging hardware variants of the 128K Spectrums
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ZX128Model {
+    /// Spectrum 128 and +2: 2 ROMs, paging port 0x7FFD
+    Spectrum128,
+    /// Spectrum +2A and +3: 4 ROMs, paging ports 0x7FFD and 0x1FFD
+    Plus3,
+}
+
+/// Spectrum 128K / +2 / +3 memory paging
+///
+/// Maps the ROMs and the 8 16-KByte RAM banks into the CPU address space
+/// on one memory layer. RAM bank n lives at heap offset n * 0x4000
+/// (which uses the complete 128 KByte heap), the ROM image (32 KBytes for
+/// the 128, 64 KBytes for the +3) is mapped without copying, so it must
+/// be 'static.
+///
+/// The normal memory configuration is ROM at 0x0000, RAM bank 5 at
+/// 0x4000, bank 2 at 0x8000 and the bank selected with port 0x7FFD
+/// at 0xC000. The bits of port 0x7FFD are:
+///
+/// - **bits 0..2**: RAM bank at 0xC000
+/// - **bit 3**: display the screen in bank 7 instead of bank 5
+/// - **bit 4**: ROM select (low bit on the +3)
+/// - **bit 5**: lock paging until the next reset
+///
+/// The +3 port 0x1FFD has the high bit of the ROM select in bit 2, and
+/// bit 0 enables the special all-RAM configurations selected by bits 1..2
+/// (banks 0-1-2-3, 4-5-6-7, 4-5-6-3 and 4-7-6-3).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Memory, ZX128Paging, ZX128Model};
+/// static ROM: [u8; 0x8000] = [0xAA; 0x8000];
+///
+/// let mut mem = Memory::new();
+/// let mut paging = ZX128Paging::new(ZX128Model::Spectrum128, &ROM, 0);
+/// paging.init(&mut mem);
+/// mem.w8(0xC000, 0x11);
+/// // map RAM bank 1 to 0xC000, and display the screen in bank 7
+/// assert!(paging.outp(&mut mem, 0x7FFD, 0x09));
+/// assert_eq!(paging.ram_bank(), 1);
+/// assert_eq!(paging.screen_bank(), 7);
+/// assert_eq!(mem.r8(0xC000), 0x00);
+/// paging.outp(&mut mem, 0x7FFD, 0x00);
+/// assert_eq!(mem.r8(0xC000), 0x11);
+/// ```
+pub struct ZX128Paging {
+    model: ZX128Model,
+    rom: &'static [u8],
+    layer: usize,
+    port_7ffd: u8,
+    port_1ffd: u8,
+}
+
+impl ZX128Paging {
+    /// create the paging logic for a model with its ROM image, on a memory layer
+    pub fn new(model: ZX128Model, rom: &'static [u8], layer: usize) -> ZX128Paging {
+        let num_roms = match model {
+            ZX128Model::Spectrum128 => 2,
+            ZX128Model::Plus3 => 4,
+        };
+        assert_eq!(rom.len(), num_roms * BANK_SIZE);
+        ZX128Paging {
+            model,
+            rom,
+            layer,
+            port_7ffd: 0,
+            port_1ffd: 0,
+        }
+    }
+
+    /// map the power-on memory configuration
+    pub fn init(&mut self, mem: &mut Memory) {
+        self.reset(mem);
+    }
+
+    /// reset the paging registers and unlock paging
+    pub fn reset(&mut self, mem: &mut Memory) {
+        self.port_7ffd = 0;
+        self.port_1ffd = 0;
+        self.update(mem);
+    }
+
+    /// the port decoder of the main paging port (0x7FFD)
+    pub fn decoder_7ffd(&self) -> PortDecoder {
+        match self.model {
+            // A15 and A1 low
+            ZX128Model::Spectrum128 => PortDecoder::any().low(15).low(1),
+            // the +3 also decodes A14 to not collide with 0x1FFD
+            ZX128Model::Plus3 => PortDecoder::any().low(15).high(14).low(1),
+        }
+    }
+
+    /// the port decoder of the +3 paging port (0x1FFD)
+    pub fn decoder_1ffd(&self) -> Option<PortDecoder> {
+        match self.model {
+            ZX128Model::Spectrum128 => None,
+            ZX128Model::Plus3 => Some(PortDecoder::new(0xF002, 0x1000)),
+        }
+    }
+
+    /// handle a port write, returns false if the port isn't a paging port
+    pub fn outp(&mut self, mem: &mut Memory, port: RegT, val: RegT) -> bool {
+        if self.decoder_7ffd().matches(port) {
+            self.write_7ffd(mem, val);
+            true
+        } else if self.decoder_1ffd().is_some_and(|d| d.matches(port)) {
+            self.write_1ffd(mem, val);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// write the paging port 0x7FFD (ignored while paging is locked)
+    pub fn write_7ffd(&mut self, mem: &mut Memory, val: RegT) {
+        if !self.locked() {
+            self.port_7ffd = val as u8;
+            self.update(mem);
+        }
+    }
+
+    /// write the +3 paging port 0x1FFD (ignored while paging is locked)
+    pub fn write_1ffd(&mut self, mem: &mut Memory, val: RegT) {
+        if !self.locked() && self.model == ZX128Model::Plus3 {
+            self.port_1ffd = val as u8;
+            self.update(mem);
+        }
+    }
+
+    /// the last value written to port 0x7FFD
+    pub fn port_7ffd(&self) -> u8 {
+        self.port_7ffd
+    }
+
+    /// the last value written to port 0x1FFD
+    pub fn port_1ffd(&self) -> u8 {
+        self.port_1ffd
+    }
+
+    /// true if paging is locked until the next reset
+    pub fn locked(&self) -> bool {
+        (self.port_7ffd & 0x20) != 0
+    }
+
+    /// the RAM bank selected for 0xC000 in the normal configuration
+    pub fn ram_bank(&self) -> usize {
+        (self.port_7ffd & 7) as usize
+    }
+
+    /// the RAM bank which contains the displayed screen (5 or 7)
+    pub fn screen_bank(&self) -> usize {
+        if (self.port_7ffd & 0x08) != 0 { 7 } else { 5 }
+    }
+
+    /// the selected ROM (0..1 on the 128, 0..3 on the +3)
+    pub fn rom_index(&self) -> usize {
+        let lo = ((self.port_7ffd >> 4) & 1) as usize;
+        let hi = ((self.port_1ffd >> 2) & 1) as usize;
+        (hi << 1) | lo
+    }
+
+    /// the RAM banks mapped to 0x0000, 0x4000, 0x8000, 0xC000 in the
+    /// +3 special configuration, None if ROM is mapped to 0x0000
+    pub fn special_banks(&self) -> Option<[usize; 4]> {
+        if (self.port_1ffd & 1) == 0 {
+            return None;
+        }
+        Some(match (self.port_1ffd >> 1) & 3 {
+            0 => [0, 1, 2, 3],
+            1 => [4, 5, 6, 7],
+            2 => [4, 5, 6, 3],
+            _ => [4, 7, 6, 3],
+        })
+    }
+
+    fn update(&self, mem: &mut Memory) {
+        match self.special_banks() {
+            Some(banks) => {
+                for (i, bank) in banks.iter().enumerate() {
+                    mem.map(self.layer, bank * BANK_SIZE, i * BANK_SIZE, true, BANK_SIZE);
+                }
+            }
+            None => {
+                let rom = self.rom_index() * BANK_SIZE;
+                mem.map_slice(self.layer, 0x0000, &self.rom[rom..rom + BANK_SIZE]);
+                mem.map(self.layer, 5 * BANK_SIZE, 0x4000, true, BANK_SIZE);
+                mem.map(self.layer, 2 * BANK_SIZE, 0x8000, true, BANK_SIZE);
+                mem.map(self.layer, self.ram_bank() * BANK_SIZE, 0xC000, true, BANK_SIZE);
+            }
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a ROM image where each byte contains 0x80 + the ROM number
+    fn make_rom(num_roms: usize) -> &'static [u8] {
+        let mut rom = Vec::new();
+        for i in 0..num_roms {
+            rom.extend(vec![0x80 + i as u8; BANK_SIZE]);
+        }
+        Box::leak(rom.into_boxed_slice())
+    }
+
+    // write the bank number into each RAM bank
+    fn mark_banks(mem: &mut Memory, paging: &mut ZX128Paging) {
+        for bank in 0..8 {
+            paging.write_7ffd(mem, bank);
+            mem.w8(0xC000, bank);
+        }
+        paging.write_7ffd(mem, 0);
+    }
+
+    fn banks(mem: &Memory) -> [RegT; 4] {
+        [mem.r8(0x0000), mem.r8(0x4000), mem.r8(0x8000), mem.r8(0xC000)]
+    }
+
+    #[test]
+    fn lock() {
+        let mut mem = Memory::new();
+        let mut paging = ZX128Paging::new(ZX128Model::Spectrum128, make_rom(2), 0);
+        paging.init(&mut mem);
+        mark_banks(&mut mem, &mut paging);
+        assert_eq!(banks(&mem), [0x80, 5, 2, 0]);
+        // ports with A15 or A1 set don't select the paging port
+        assert!(!paging.outp(&mut mem, 0xFFFD, 0x13));
+        assert!(!paging.outp(&mut mem, 0x7FFF, 0x13));
+        assert!(paging.outp(&mut mem, 0x7FFD, 0x13));
+        assert_eq!(banks(&mem), [0x81, 5, 2, 3]);
+        // the 128 has no port 0x1FFD
+        paging.write_1ffd(&mut mem, 0x01);
+        assert_eq!(paging.port_1ffd(), 0);
+        // lock paging, further writes are ignored
+        paging.write_7ffd(&mut mem, 0x24);
+        assert!(paging.locked());
+        assert_eq!(banks(&mem), [0x80, 5, 2, 4]);
+        paging.write_7ffd(&mut mem, 0x01);
+        assert_eq!(paging.port_7ffd(), 0x24);
+        assert_eq!(banks(&mem), [0x80, 5, 2, 4]);
+        // until the next reset
+        paging.reset(&mut mem);
+        assert!(!paging.locked());
+        paging.write_7ffd(&mut mem, 0x01);
+        assert_eq!(banks(&mem), [0x80, 5, 2, 1]);
+    }
+
+    #[test]
+    fn plus3() {
+        let mut mem = Memory::new();
+        let mut paging = ZX128Paging::new(ZX128Model::Plus3, make_rom(4), 0);
+        paging.init(&mut mem);
+        mark_banks(&mut mem, &mut paging);
+        // ROM select with bit 4 of 0x7FFD and bit 2 of 0x1FFD
+        assert!(paging.outp(&mut mem, 0x7FFD, 0x10));
+        assert!(paging.outp(&mut mem, 0x1FFD, 0x04));
+        assert_eq!(paging.rom_index(), 3);
+        assert_eq!(banks(&mem), [0x83, 5, 2, 0]);
+        // special all-RAM configurations
+        let configs: [[usize; 4]; 4] = [[0, 1, 2, 3], [4, 5, 6, 7], [4, 5, 6, 3], [4, 7, 6, 3]];
+        for (i, config) in configs.iter().enumerate() {
+            paging.write_1ffd(&mut mem, 0x01 | (i as RegT) << 1);
+            assert_eq!(paging.special_banks(), Some(*config));
+            assert_eq!(banks(&mem), config.map(|bank| bank as RegT));
+        }
+        // the special configuration ignores the RAM bank of 0x7FFD
+        paging.write_7ffd(&mut mem, 0x02);
+        assert_eq!(banks(&mem), [4, 7, 6, 3]);
+        // ROM 0 is back when the special configuration is disabled
+        paging.write_1ffd(&mut mem, 0x00);
+        assert_eq!(banks(&mem), [0x80, 5, 2, 2]);
+        // the lock bit also locks port 0x1FFD
+        paging.write_7ffd(&mut mem, 0x20);
+        paging.write_1ffd(&mut mem, 0x01);
+        assert_eq!(paging.special_banks(), None);
+        // the ROM is read-only
+        mem.w8(0x0000, 0x00);
+        assert_eq!(mem.r8(0x0000), 0x80);
+    }
+}
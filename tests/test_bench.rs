@@ -0,0 +1,67 @@
+extern crate rz80;
+
+#[cfg(test)]
+mod test_bench {
+    use std::time::Instant;
+    use rz80;
+
+    static ZEXDOC: &[u8] = include_bytes!("zexdoc.com");
+
+    // the number of cycles to run, the first ZEXDOC tests cover
+    // the 8- and 16-bit ALU instructions, which compute the
+    // undocumented flags
+    const NUM_CYCLES: i64 = 500_000_000;
+
+    // run ZEXDOC for a fixed number of cycles, CP/M BDOS calls
+    // (the test output) return immediately, with the block cache
+    // the number of steps is the number of blocks and instructions
+    // outside of blocks
+    fn run_zexdoc(block_cache: bool) -> i64 {
+        let mut cpu = rz80::CPU::new_64k();
+        cpu.mem.write(0x0100, ZEXDOC);
+        cpu.reg.set_sp(0xF000);
+        cpu.reg.set_pc(0x0100);
+        cpu.set_block_cache(block_cache);
+        let mut num_steps = 0;
+        let mut num_cycles = 0;
+        while num_cycles < NUM_CYCLES {
+            num_steps += 1;
+            num_cycles += if block_cache {
+                cpu.run_block(&rz80::NullBus)
+            } else {
+                cpu.step(&rz80::NullBus)
+            };
+            if cpu.reg.pc() == 0x0005 {
+                cpu.ret();
+            }
+        }
+        num_steps
+    }
+
+    // compare the interpreter speed with and without the 'fast' feature:
+    //
+    // > cargo test --release --test test_bench -- --ignored --nocapture
+    // > cargo test --release --features fast --test test_bench -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_interpreter() {
+        let start = Instant::now();
+        let num_ops = run_zexdoc(false);
+        let ms = start.elapsed().as_millis().max(1) as i64;
+        println!("\n>>> interpreter ({}): ops: {}, cycles: {}, duration: {}ms, mips: {}, MHz: {}\n",
+            if cfg!(feature = "fast") { "fast" } else { "default" },
+            num_ops, NUM_CYCLES, ms, num_ops / ms / 1000, NUM_CYCLES / ms / 1000);
+    }
+
+    // the same with the block cache and CPU::run_block()
+    #[test]
+    #[ignore]
+    fn bench_block_cache() {
+        let start = Instant::now();
+        let num_steps = run_zexdoc(true);
+        let ms = start.elapsed().as_millis().max(1) as i64;
+        println!("\n>>> block cache ({}): steps: {}, cycles: {}, duration: {}ms, MHz: {}\n",
+            if cfg!(feature = "fast") { "fast" } else { "default" },
+            num_steps, NUM_CYCLES, ms, NUM_CYCLES / ms / 1000);
+    }
+}
@@ -0,0 +1,214 @@
+extern crate rz80;
+
+// expected cycle counts for every opcode, ported from the tables of the
+// C++ emulator, each instruction is executed in a controlled harness
+// (see run()) and the cycles returned by CPU::step() are checked
+#[cfg(test)]
+mod test_cycles {
+    use rz80;
+
+    // unprefixed instructions, 0 for prefix bytes (branches not taken)
+    const MAIN: [u8; 256] = [
+         4, 10,  7,  6,  4,  4,  7,  4,  4, 11,  7,  6,  4,  4,  7,  4,  // 00
+         8, 10,  7,  6,  4,  4,  7,  4, 12, 11,  7,  6,  4,  4,  7,  4,  // 10
+         7, 10, 16,  6,  4,  4,  7,  4,  7, 11, 16,  6,  4,  4,  7,  4,  // 20
+         7, 10, 13,  6, 11, 11, 10,  4,  7, 11, 13,  6,  4,  4,  7,  4,  // 30
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 40
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 50
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 60
+         7,  7,  7,  7,  7,  7,  4,  7,  4,  4,  4,  4,  4,  4,  7,  4,  // 70
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 80
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 90
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // A0
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // B0
+         5, 10, 10, 10, 10, 11,  7, 11,  5, 10, 10,  0, 10, 17,  7, 11,  // C0
+         5, 10, 10, 11, 10, 11,  7, 11,  5,  4, 10, 11, 10,  0,  7, 11,  // D0
+         5, 10, 10, 19, 10, 11,  7, 11,  5,  4, 10,  4, 10,  0,  7, 11,  // E0
+         5, 10, 10,  4, 10, 11,  7, 11,  5,  6, 10,  4, 10,  0,  7, 11,  // F0
+    ];
+    // conditional instructions when the branch is taken
+    const MAIN_TAKEN: &[(u8, u8)] = &[
+        (0x10, 13), (0x20, 12), (0x28, 12), (0x30, 12), (0x38, 12), (0xC0, 11), (0xC4, 17), (0xC8, 11),
+        (0xCC, 17), (0xD0, 11), (0xD4, 17), (0xD8, 11), (0xDC, 17), (0xE0, 11), (0xE4, 17), (0xE8, 11),
+        (0xEC, 17), (0xF0, 11), (0xF4, 17), (0xF8, 11), (0xFC, 17),
+    ];
+    // CB prefix
+    const CB: [u8; 256] = [
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // 00
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // 10
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // 20
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // 30
+         8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8,  // 40
+         8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8,  // 50
+         8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8,  // 60
+         8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8,  // 70
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // 80
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // 90
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // A0
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // B0
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // C0
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // D0
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // E0
+         8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8,  // F0
+    ];
+    // ED prefix (undefined instructions are 8-cycle NOPs, repeats not taken)
+    const ED: [u8; 256] = [
+         8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  // 00
+         8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  // 10
+         8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  // 20
+         8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  // 30
+        12, 12, 15, 20,  8, 14,  8,  9, 12, 12, 15, 20,  8, 14,  8,  9,  // 40
+        12, 12, 15, 20,  8, 14,  8,  9, 12, 12, 15, 20,  8, 14,  8,  9,  // 50
+        12, 12, 15, 20,  8, 14,  8, 18, 12, 12, 15, 20,  8, 14,  8, 18,  // 60
+        12, 12, 15, 20,  8, 14,  8,  8, 12, 12, 15, 20,  8, 14,  8,  8,  // 70
+         8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  // 80
+         8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  // 90
+        16, 16, 16, 16,  8,  8,  8,  8, 16, 16, 16, 16,  8,  8,  8,  8,  // A0
+        16, 16, 16, 16,  8,  8,  8,  8, 16, 16, 16, 16,  8,  8,  8,  8,  // B0
+         8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  // C0
+         8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  // D0
+         8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  // E0
+         8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  // F0
+    ];
+    // repeating block instructions when repeated
+    const ED_TAKEN: &[(u8, u8)] = &[
+        (0xB0, 21), (0xB1, 21), (0xB2, 21), (0xB3, 21), (0xB8, 21), (0xB9, 21), (0xBA, 21), (0xBB, 21),
+    ];
+    // DD and FD prefix (0 for prefix bytes)
+    const DD: [u8; 256] = [
+         8, 14, 11, 10,  8,  8, 11,  8,  8, 15, 11, 10,  8,  8, 11,  8,  // 00
+        12, 14, 11, 10,  8,  8, 11,  8, 16, 15, 11, 10,  8,  8, 11,  8,  // 10
+        11, 14, 20, 10,  8,  8, 11,  8, 11, 15, 20, 10,  8,  8, 11,  8,  // 20
+        11, 14, 17, 10, 23, 23, 19,  8, 11, 15, 17, 10,  8,  8, 11,  8,  // 30
+         8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8,  // 40
+         8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8,  // 50
+         8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8,  // 60
+        19, 19, 19, 19, 19, 19,  8, 19,  8,  8,  8,  8,  8,  8, 19,  8,  // 70
+         8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8,  // 80
+         8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8,  // 90
+         8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8,  // A0
+         8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8,  // B0
+         9, 14, 14, 14, 14, 15, 11, 15,  9, 14, 14,  0, 14, 21, 11, 15,  // C0
+         9, 14, 14, 15, 14, 15, 11, 15,  9,  8, 14, 15, 14,  0, 11, 15,  // D0
+         9, 14, 14, 23, 14, 15, 11, 15,  9,  8, 14,  8, 14,  0, 11, 15,  // E0
+         9, 14, 14,  8, 14, 15, 11, 15,  9, 10, 14,  8, 14,  0, 11, 15,  // F0
+    ];
+    // DD/FD prefixed conditional instructions when the branch is taken
+    const DD_TAKEN: &[(u8, u8)] = &[
+        (0x10, 17), (0x20, 16), (0x28, 16), (0x30, 16), (0x38, 16), (0xC0, 15), (0xC4, 21), (0xC8, 15),
+        (0xCC, 21), (0xD0, 15), (0xD4, 21), (0xD8, 15), (0xDC, 21), (0xE0, 15), (0xE4, 21), (0xE8, 15),
+        (0xEC, 21), (0xF0, 15), (0xF4, 21), (0xF8, 15), (0xFC, 21),
+    ];
+    // DD CB d op and FD CB d op
+    const DDCB: [u8; 256] = [
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // 00
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // 10
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // 20
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // 30
+        20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20,  // 40
+        20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20,  // 50
+        20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20,  // 60
+        20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20,  // 70
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // 80
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // 90
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // A0
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // B0
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // C0
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // D0
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // E0
+        23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23,  // F0
+    ];
+
+    struct NullBus;
+    impl rz80::Bus for NullBus { }
+
+    const DJNZ: u8 = 0x10;
+
+    // execute a single instruction and return the cycles, 'taken' sets up
+    // the registers so that conditional branches are taken and block
+    // instructions repeat
+    fn run(code: &[u8], taken: bool) -> i64 {
+        let mut cpu = rz80::CPU::new_64k();
+        let bus = &NullBus;
+        cpu.mem.write(0x0100, code);
+        cpu.reg.set_pc(0x0100);
+        cpu.reg.set_sp(0x8000);
+        cpu.reg.set_hl(0x4000);
+        cpu.reg.set_ix(0x4000);
+        cpu.reg.set_iy(0x4000);
+        cpu.reg.set_de(0x5000);
+        // A differs from (HL), so CPIR/CPDR don't stop on a match
+        cpu.reg.set_a(0x55);
+        let op = code[code.len() - 3];
+        let prefix = code[0];
+        if prefix == 0xED {
+            // LDxR/CPxR count down BC, INxR/OTxR count down B
+            let bc = if (op & 3) < 2 { 1 } else { 0x100 };
+            cpu.reg.set_bc(if taken { bc * 2 } else { bc });
+        } else if op == DJNZ {
+            cpu.reg.set_b(if taken { 2 } else { 1 });
+        } else {
+            // cc is true with cleared flags for NZ, NC, PO and P
+            let y = if op < 0x40 { (op >> 3).wrapping_sub(4) } else { (op >> 3) & 7 };
+            let cc_if_clear = (y & 1) == 0;
+            cpu.reg.set_f(if cc_if_clear == taken { 0x00 } else { 0xFF });
+        }
+        cpu.step(bus)
+    }
+
+    fn check(name: &str, prefix: &[u8], table: &[u8; 256], taken: &[(u8, u8)]) {
+        let mut errors = Vec::new();
+        for (op, &cycles) in table.iter().enumerate() {
+            if cycles == 0 {
+                continue;
+            }
+            let op = op as u8;
+            let mut code = prefix.to_vec();
+            code.extend_from_slice(&[op, 0x00, 0x00]);
+            let mut expected = vec![(false, cycles)];
+            if let Some(&(_, cycles)) = taken.iter().find(|&&(o, _)| o == op) {
+                expected.push((true, cycles));
+            }
+            for (is_taken, cycles) in expected {
+                let actual = run(&code, is_taken);
+                if actual != cycles as i64 {
+                    errors.push(format!("{} {:02X}{}: expected {}, got {}", name, op,
+                        if is_taken { " (taken)" } else { "" }, cycles, actual));
+                }
+            }
+        }
+        assert!(errors.is_empty(), "\n{}", errors.join("\n"));
+    }
+
+    #[test]
+    fn test_cycles_main() {
+        check("", &[], &MAIN, MAIN_TAKEN);
+    }
+
+    #[test]
+    fn test_cycles_cb() {
+        check("CB", &[0xCB], &CB, &[]);
+    }
+
+    #[test]
+    fn test_cycles_ed() {
+        check("ED", &[0xED], &ED, ED_TAKEN);
+    }
+
+    #[test]
+    fn test_cycles_dd() {
+        check("DD", &[0xDD], &DD, DD_TAKEN);
+    }
+
+    #[test]
+    fn test_cycles_fd() {
+        check("FD", &[0xFD], &DD, DD_TAKEN);
+    }
+
+    #[test]
+    fn test_cycles_ddcb() {
+        // the displacement comes before the opcode
+        check("DDCB", &[0xDD, 0xCB, 0x01], &DDCB, &[]);
+        check("FDCB", &[0xFD, 0xCB, 0x01], &DDCB, &[]);
+    }
+}
@@ -0,0 +1,547 @@
+extern crate rz80;
+
+// randomized differential test: random instruction sequences are run
+// on the rz80 CPU and on a small independent reference core, which
+// decodes the opcodes through their x/y/z bit fields instead of big
+// match tables, registers, flags (including the undocumented X and Y
+// flags), cycles and memory must be identical after each instruction
+//
+// the reference core implements the straight-line subset of the
+// unprefixed and CB-prefixed instructions (no branches, I/O,
+// interrupt control or HALT), run with:
+//
+// > cargo test --release --features difftest
+//
+#[cfg(all(test, feature = "difftest"))]
+mod test_diff {
+    use rz80::{CPU, Bus, RegT, CF, NF, VF, XF, HF, YF, ZF, SF};
+
+    // the code is placed into ROM, the rest of memory is random RAM
+    const ROM_SIZE: usize = 0x0400;
+    const CODE_START: usize = 0x0100;
+    const CODE_END: usize = 0x03F0;
+    const NUM_SEQUENCES: usize = 2000;
+
+    // register indices of the reference core, in opcode order,
+    // with F in the slot of (HL)
+    const B: usize = 0;
+    const D: usize = 2;
+    const H: usize = 4;
+    const F: usize = 6;
+    const A: usize = 7;
+
+    struct TestBus;
+    impl Bus for TestBus {}
+
+    struct Rng(u32);
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    struct RefCore {
+        regs: [u8; 8],
+        alt: [u16; 4],
+        sp: u16,
+        pc: u16,
+        r: u8,
+        mem: Vec<u8>,
+    }
+
+    // sign, zero, X/Y and parity flags of an 8-bit result
+    fn szp(val: u8) -> u8 {
+        let f = (val as RegT & (SF | XF | YF)) as u8 | if val == 0 { ZF as u8 } else { 0 };
+        if val.count_ones() & 1 == 0 { f | VF as u8 } else { f }
+    }
+
+    impl RefCore {
+        fn new(mem: Vec<u8>) -> RefCore {
+            RefCore {
+                regs: [0; 8],
+                alt: [0; 4],
+                sp: 0,
+                pc: 0,
+                r: 0,
+                mem,
+            }
+        }
+
+        fn flag(&self, mask: RegT) -> bool {
+            (self.regs[F] as RegT & mask) != 0
+        }
+
+        fn r8(&self, addr: u16) -> u8 {
+            self.mem[addr as usize]
+        }
+
+        fn w8(&mut self, addr: u16, val: u8) {
+            if addr as usize >= ROM_SIZE {
+                self.mem[addr as usize] = val;
+            }
+        }
+
+        fn r16(&self, addr: u16) -> u16 {
+            self.r8(addr) as u16 | (self.r8(addr.wrapping_add(1)) as u16) << 8
+        }
+
+        fn w16(&mut self, addr: u16, val: u16) {
+            self.w8(addr, val as u8);
+            self.w8(addr.wrapping_add(1), (val >> 8) as u8);
+        }
+
+        fn fetch(&mut self) -> u8 {
+            let val = self.r8(self.pc);
+            self.pc = self.pc.wrapping_add(1);
+            val
+        }
+
+        fn fetch16(&mut self) -> u16 {
+            let lo = self.fetch() as u16;
+            lo | (self.fetch() as u16) << 8
+        }
+
+        fn pair(&self, hi: usize) -> u16 {
+            (self.regs[hi] as u16) << 8 | self.regs[hi + 1] as u16
+        }
+
+        fn set_pair(&mut self, hi: usize, val: u16) {
+            self.regs[hi] = (val >> 8) as u8;
+            self.regs[hi + 1] = val as u8;
+        }
+
+        fn hl(&self) -> u16 {
+            self.pair(H)
+        }
+
+        fn af(&self) -> u16 {
+            (self.regs[A] as u16) << 8 | self.regs[F] as u16
+        }
+
+        fn set_af(&mut self, val: u16) {
+            self.regs[A] = (val >> 8) as u8;
+            self.regs[F] = val as u8;
+        }
+
+        // rp table: BC, DE, HL, SP
+        fn rp(&self, p: usize) -> u16 {
+            if p == 3 { self.sp } else { self.pair(p * 2) }
+        }
+
+        fn set_rp(&mut self, p: usize, val: u16) {
+            if p == 3 { self.sp = val } else { self.set_pair(p * 2, val) }
+        }
+
+        // rp2 table: BC, DE, HL, AF
+        fn rp2(&self, p: usize) -> u16 {
+            if p == 3 { self.af() } else { self.pair(p * 2) }
+        }
+
+        fn set_rp2(&mut self, p: usize, val: u16) {
+            if p == 3 { self.set_af(val) } else { self.set_pair(p * 2, val) }
+        }
+
+        // r table: B, C, D, E, H, L, (HL), A
+        fn get_r(&self, r: usize) -> u8 {
+            if r == 6 { self.r8(self.hl()) } else { self.regs[r] }
+        }
+
+        fn set_r(&mut self, r: usize, val: u8) {
+            if r == 6 { let hl = self.hl(); self.w8(hl, val) } else { self.regs[r] = val }
+        }
+
+        fn bump_r(&mut self) {
+            self.r = (self.r & 0x80) | (self.r.wrapping_add(1) & 0x7F);
+        }
+
+        fn alu(&mut self, op: usize, val: u8) {
+            let a = self.regs[A] as RegT;
+            let v = val as RegT;
+            let carry = self.flag(CF) as RegT;
+            let (res, f) = match op {
+                // ADD, ADC
+                0 | 1 => {
+                    let res = a + v + if op == 1 { carry } else { 0 };
+                    let f = ((a ^ v ^ res) & HF) | (((a ^ res) & (v ^ res) & 0x80) >> 5) |
+                            if res > 0xFF { CF } else { 0 };
+                    (res & 0xFF, f)
+                }
+                // SUB, SBC, CP
+                2 | 3 | 7 => {
+                    let res = a - v - if op == 3 { carry } else { 0 };
+                    let f = NF | ((a ^ v ^ res) & HF) | (((a ^ v) & (a ^ res) & 0x80) >> 5) |
+                            if res < 0 { CF } else { 0 };
+                    (res & 0xFF, f)
+                }
+                4 => (a & v, HF),
+                5 => (a ^ v, 0),
+                _ => (a | v, 0),
+            };
+            let f = match op {
+                0..=3 => f | (res & (SF | XF | YF)) | if res == 0 { ZF } else { 0 },
+                // CP takes the X/Y flags from the operand
+                7 => f | (res & SF) | (v & (XF | YF)) | if res == 0 { ZF } else { 0 },
+                _ => f | szp(res as u8) as RegT,
+            };
+            self.regs[F] = f as u8;
+            if op != 7 {
+                self.regs[A] = res as u8;
+            }
+        }
+
+        // RLC, RRC, RL, RR, SLA, SRA, SLL, SRL
+        fn rot(&mut self, op: usize, val: u8) -> u8 {
+            let carry = self.flag(CF) as u8;
+            let (res, c) = match op {
+                0 => (val.rotate_left(1), val >> 7),
+                1 => (val.rotate_right(1), val & 1),
+                2 => (val << 1 | carry, val >> 7),
+                3 => (val >> 1 | carry << 7, val & 1),
+                4 => (val << 1, val >> 7),
+                5 => (val >> 1 | (val & 0x80), val & 1),
+                6 => (val << 1 | 1, val >> 7),
+                _ => (val >> 1, val & 1),
+            };
+            self.regs[F] = szp(res) | c;
+            res
+        }
+
+        // RLCA, RRCA, RLA, RRA, DAA, CPL, SCF, CCF
+        fn acc_op(&mut self, op: usize) {
+            let a = self.regs[A];
+            let f = self.regs[F] as RegT;
+            let keep = f & (SF | ZF | VF);
+            match op {
+                0..=3 => {
+                    let res = self.rot(op, a);
+                    self.regs[F] = (keep | (self.regs[F] as RegT & (CF | XF | YF))) as u8;
+                    self.regs[A] = res;
+                }
+                4 => {
+                    let mut diff = 0;
+                    let mut carry = self.flag(CF);
+                    if self.flag(HF) || (a & 0xF) > 9 {
+                        diff |= 0x06;
+                    }
+                    if carry || a > 0x99 {
+                        diff |= 0x60;
+                        carry = true;
+                    }
+                    let sub = self.flag(NF);
+                    let res = if sub { a.wrapping_sub(diff) } else { a.wrapping_add(diff) };
+                    let half = if sub { self.flag(HF) && (a & 0xF) < 6 } else { (a & 0xF) > 9 };
+                    self.regs[F] = szp(res) | (f & NF) as u8 |
+                                   if half { HF as u8 } else { 0 } |
+                                   if carry { CF as u8 } else { 0 };
+                    self.regs[A] = res;
+                }
+                5 => {
+                    let res = !a;
+                    self.regs[F] = ((f & (SF | ZF | VF | CF)) | HF | NF |
+                                    (res as RegT & (XF | YF))) as u8;
+                    self.regs[A] = res;
+                }
+                _ => {
+                    // NMOS chips: X/Y from the previous flags or'ed with A
+                    let xy = (f | a as RegT) & (XF | YF);
+                    let c = f & CF;
+                    let f = if op == 6 {
+                        keep | xy | CF
+                    } else {
+                        keep | xy | (c << 4) | (c ^ CF)
+                    };
+                    self.regs[F] = f as u8;
+                }
+            }
+        }
+
+        // execute one instruction, return the number of cycles
+        fn step(&mut self) -> i64 {
+            self.bump_r();
+            let op = self.fetch() as usize;
+            let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+            let (p, q) = (y >> 1, y & 1);
+            match (x, z) {
+                (0, 0) => {
+                    if y == 1 {
+                        let af = self.af();
+                        self.set_af(self.alt[3]);
+                        self.alt[3] = af;
+                    }
+                    4
+                }
+                (0, 1) => {
+                    if q == 0 {
+                        let nn = self.fetch16();
+                        self.set_rp(p, nn);
+                        10
+                    } else {
+                        let hl = self.hl() as RegT;
+                        let v = self.rp(p) as RegT;
+                        let res = hl + v;
+                        let f = (self.regs[F] as RegT & (SF | ZF | VF)) |
+                                (((hl ^ v ^ res) >> 8) & HF) |
+                                ((res >> 8) & (XF | YF)) |
+                                if res > 0xFFFF { CF } else { 0 };
+                        self.regs[F] = f as u8;
+                        self.set_pair(H, res as u16);
+                        11
+                    }
+                }
+                (0, 2) => {
+                    match (p, q) {
+                        (0, 0) | (1, 0) => {
+                            let addr = self.rp(p);
+                            let a = self.regs[A];
+                            self.w8(addr, a);
+                            7
+                        }
+                        (0, 1) | (1, 1) => {
+                            self.regs[A] = self.r8(self.rp(p));
+                            7
+                        }
+                        (2, 0) => {
+                            let nn = self.fetch16();
+                            let hl = self.hl();
+                            self.w16(nn, hl);
+                            16
+                        }
+                        (2, 1) => {
+                            let nn = self.fetch16();
+                            let val = self.r16(nn);
+                            self.set_pair(H, val);
+                            16
+                        }
+                        (3, 0) => {
+                            let nn = self.fetch16();
+                            let a = self.regs[A];
+                            self.w8(nn, a);
+                            13
+                        }
+                        _ => {
+                            let nn = self.fetch16();
+                            self.regs[A] = self.r8(nn);
+                            13
+                        }
+                    }
+                }
+                (0, 3) => {
+                    let val = self.rp(p);
+                    self.set_rp(p, if q == 0 { val.wrapping_add(1) } else { val.wrapping_sub(1) });
+                    6
+                }
+                (0, 4) | (0, 5) => {
+                    let val = self.get_r(y);
+                    let (res, f) = if z == 4 {
+                        let res = val.wrapping_add(1);
+                        (res, if (val & 0xF) == 0xF { HF } else { 0 } |
+                              if val == 0x7F { VF } else { 0 })
+                    } else {
+                        let res = val.wrapping_sub(1);
+                        (res, NF | if (val & 0xF) == 0 { HF } else { 0 } |
+                              if val == 0x80 { VF } else { 0 })
+                    };
+                    let f = f | (self.regs[F] as RegT & CF) | (szp(res) as RegT & !VF);
+                    self.regs[F] = f as u8;
+                    self.set_r(y, res);
+                    if y == 6 { 11 } else { 4 }
+                }
+                (0, 6) => {
+                    let n = self.fetch();
+                    self.set_r(y, n);
+                    if y == 6 { 10 } else { 7 }
+                }
+                (0, _) => {
+                    self.acc_op(y);
+                    4
+                }
+                (1, _) => {
+                    let val = self.get_r(z);
+                    self.set_r(y, val);
+                    if y == 6 || z == 6 { 7 } else { 4 }
+                }
+                (2, _) => {
+                    let val = self.get_r(z);
+                    self.alu(y, val);
+                    if z == 6 { 7 } else { 4 }
+                }
+                (3, 1) => {
+                    match (q, p) {
+                        (0, _) => {
+                            let val = self.r16(self.sp);
+                            self.sp = self.sp.wrapping_add(2);
+                            self.set_rp2(p, val);
+                            10
+                        }
+                        (_, 1) => {
+                            for i in 0..3 {
+                                let val = self.pair(i * 2);
+                                self.set_pair(i * 2, self.alt[i]);
+                                self.alt[i] = val;
+                            }
+                            4
+                        }
+                        _ => {
+                            self.sp = self.hl();
+                            6
+                        }
+                    }
+                }
+                (3, 3) => {
+                    match y {
+                        1 => self.step_cb(),
+                        4 => {
+                            let sp = self.sp;
+                            let val = self.r16(sp);
+                            let hl = self.hl();
+                            self.w16(sp, hl);
+                            self.set_pair(H, val);
+                            19
+                        }
+                        _ => {
+                            let de = self.pair(D);
+                            let hl = self.hl();
+                            self.set_pair(D, hl);
+                            self.set_pair(H, de);
+                            4
+                        }
+                    }
+                }
+                (3, 5) => {
+                    self.sp = self.sp.wrapping_sub(2);
+                    let (sp, val) = (self.sp, self.rp2(p));
+                    self.w16(sp, val);
+                    11
+                }
+                _ => {
+                    let n = self.fetch();
+                    self.alu(y, n);
+                    7
+                }
+            }
+        }
+
+        fn step_cb(&mut self) -> i64 {
+            self.bump_r();
+            let op = self.fetch() as usize;
+            let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+            let val = self.get_r(z);
+            let res = match x {
+                0 => self.rot(y, val),
+                1 => {
+                    let bit = val & (1 << y);
+                    let f = (self.regs[F] as RegT & CF) | HF | (val as RegT & (XF | YF)) |
+                            (bit as RegT & SF) | if bit == 0 { ZF | VF } else { 0 };
+                    self.regs[F] = f as u8;
+                    return 8;
+                }
+                2 => val & !(1 << y),
+                _ => val | (1 << y),
+            };
+            self.set_r(z, res);
+            if z == 6 { 15 } else { 8 }
+        }
+    }
+
+    // length of a supported instruction, or None
+    fn inst_len(op: u8, cb_op: u8) -> Option<usize> {
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        match (x, z) {
+            (0, 0) => if y <= 1 { Some(1) } else { None },
+            (0, 1) => Some(if y & 1 == 0 { 3 } else { 1 }),
+            (0, 2) => Some(if y >= 4 { 3 } else { 1 }),
+            (0, 6) => Some(2),
+            (0, _) => Some(1),
+            (1, _) => if op == 0x76 { None } else { Some(1) },
+            (2, _) => Some(1),
+            (3, 1) => if y & 1 == 0 || y == 3 || y == 7 { Some(1) } else { None },
+            // BIT n,(HL) takes the X/Y flags from the internal WZ register
+            (3, 3) if y == 1 => if (cb_op & 0xC7) == 0x46 { None } else { Some(2) },
+            (3, 3) => if y == 4 || y == 5 { Some(1) } else { None },
+            (3, 5) => if y & 1 == 0 { Some(1) } else { None },
+            (3, 6) => Some(2),
+            _ => None,
+        }
+    }
+
+    // compare the CPU state after executing the instruction at pc
+    fn check_regs(cpu: &CPU, rc: &RefCore, seq: usize, pc: u16, cycles: (i64, i64)) {
+        let op: Vec<String> = (0..3).map(|i| format!("{:02X}", rc.r8(pc.wrapping_add(i)))).collect();
+        let ctx = format!("sequence {}, instruction at {:04X} ({})", seq, pc, op.join(" "));
+        let expected = [rc.af(), rc.pair(B), rc.pair(D), rc.hl(), rc.sp, rc.pc,
+                        rc.alt[3], rc.alt[0], rc.alt[1], rc.alt[2]];
+        let actual = [cpu.reg.af(), cpu.reg.bc(), cpu.reg.de(), cpu.reg.hl(), cpu.reg.sp(),
+                      cpu.reg.pc(), cpu.reg.af_(), cpu.reg.bc_(), cpu.reg.de_(), cpu.reg.hl_()];
+        let names = ["AF", "BC", "DE", "HL", "SP", "PC", "AF'", "BC'", "DE'", "HL'"];
+        for i in 0..names.len() {
+            assert_eq!(actual[i], expected[i] as RegT, "{}: {} differs", ctx, names[i]);
+        }
+        assert_eq!(cpu.reg.r, rc.r as RegT, "{}: R differs", ctx);
+        assert_eq!(cycles.0, cycles.1, "{}: cycles differ", ctx);
+    }
+
+    #[test]
+    fn random_sequences() {
+        let mut rng = Rng(0x2468ACE1);
+        for seq in 0..NUM_SEQUENCES {
+            // random straight-line code in ROM, followed by NOPs
+            let mut mem: Vec<u8> = (0..0x10000).map(|_| rng.next() as u8).collect();
+            let mut addr = CODE_START;
+            while addr < CODE_END {
+                let op = rng.next() as u8;
+                let cb_op = rng.next() as u8;
+                if let Some(len) = inst_len(op, cb_op) {
+                    mem[addr] = op;
+                    if len > 1 {
+                        mem[addr + 1] = cb_op;
+                    }
+                    if len > 2 {
+                        mem[addr + 2] = rng.next() as u8;
+                    }
+                    addr += len;
+                }
+            }
+            for byte in &mut mem[addr..ROM_SIZE] {
+                *byte = 0;
+            }
+
+            let mut cpu = CPU::new();
+            cpu.mem.map_bytes(0, 0x00000, 0x0000, false, &mem[..ROM_SIZE]);
+            cpu.mem.map_bytes(0, ROM_SIZE, ROM_SIZE, true, &mem[ROM_SIZE..]);
+            let mut rc = RefCore::new(mem);
+            let regs: Vec<u16> = (0..9).map(|_| rng.next() as u16).collect();
+            cpu.reg.set_af(regs[0] as RegT);
+            cpu.reg.set_bc(regs[1] as RegT);
+            cpu.reg.set_de(regs[2] as RegT);
+            cpu.reg.set_hl(regs[3] as RegT);
+            cpu.reg.set_sp(regs[4] as RegT);
+            cpu.reg.set_af_(regs[5] as RegT);
+            cpu.reg.set_bc_(regs[6] as RegT);
+            cpu.reg.set_de_(regs[7] as RegT);
+            cpu.reg.set_hl_(regs[8] as RegT);
+            cpu.reg.set_pc(CODE_START as RegT);
+            rc.set_af(regs[0]);
+            rc.set_pair(B, regs[1]);
+            rc.set_pair(D, regs[2]);
+            rc.set_pair(H, regs[3]);
+            rc.sp = regs[4];
+            rc.alt = [regs[6], regs[7], regs[8], regs[5]];
+            rc.pc = CODE_START as u16;
+            rc.r = cpu.reg.r as u8;
+
+            let bus = TestBus;
+            while (rc.pc as usize) < addr {
+                let pc = rc.pc;
+                let cycles = (cpu.step(&bus), rc.step());
+                check_regs(&cpu, &rc, seq, pc, cycles);
+            }
+            for i in ROM_SIZE..0x10000 {
+                assert_eq!(cpu.mem.r8(i as RegT), rc.mem[i] as RegT,
+                           "sequence {}: memory at {:04X} differs", seq, i);
+            }
+        }
+    }
+}
@@ -5,16 +5,7 @@ extern crate rz80;
 mod test_opcodes {
     use std::cell::Cell;
     use rz80;
-    use rz80::RegT as RegT;
-    use rz80::CF as CF;
-    use rz80::NF as NF;
-    use rz80::VF as VF;
-    use rz80::PF as PF;
-    use rz80::XF as XF;
-    use rz80::HF as HF;
-    use rz80::YF as YF;
-    use rz80::ZF as ZF;
-    use rz80::SF as SF;
+    use rz80::prelude::*;
 
     struct TestBus { 
         pub port: Cell<RegT>,
@@ -1259,6 +1250,61 @@ mod test_opcodes {
         assert_eq!(4, cpu.step(bus)); assert_eq!(0x90, cpu.reg.a()); assert!(flags(&cpu, SF|PF|NF|CF));
     }
 
+    // reference DAA from the correction/flag tables in 'The Undocumented Z80 Documented'
+    #[cfg(not(feature = "fast"))]
+    fn daa_reference(a: RegT, f: RegT) -> (RegT, RegT) {
+        let (cf, hf, nf) = (0 != (f & CF), 0 != (f & HF), 0 != (f & NF));
+        let hi = a >> 4;
+        let lo = a & 0xF;
+        let diff = match (cf, hi, hf, lo) {
+            (false, 0..=9, false, 0..=9) => 0x00,
+            (false, 0..=9, true, 0..=9) => 0x06,
+            (false, 0..=8, _, 0xA..=0xF) => 0x06,
+            (false, 0xA..=0xF, false, 0..=9) => 0x60,
+            (true, _, false, 0..=9) => 0x60,
+            (true, _, true, 0..=9) => 0x66,
+            (true, _, _, 0xA..=0xF) => 0x66,
+            (false, 0x9..=0xF, _, 0xA..=0xF) => 0x66,
+            (false, 0xA..=0xF, true, 0..=9) => 0x66,
+            _ => unreachable!(),
+        };
+        let c = !matches!((cf, hi, lo), (false, 0..=9, 0..=9) | (false, 0..=8, 0xA..=0xF));
+        let h = match (nf, hf, lo) {
+            (false, _, 0..=9) => false,
+            (false, _, _) => true,
+            (true, false, _) => false,
+            (true, true, 6..=0xF) => false,
+            (true, true, _) => true,
+        };
+        let r = if nf { (a - diff) & 0xFF } else { (a + diff) & 0xFF };
+        let mut rf = (r & (SF | YF | XF)) | (f & NF);
+        if r == 0 { rf |= ZF; }
+        if (r as u8).count_ones() & 1 == 0 { rf |= PF; }
+        if h { rf |= HF; }
+        if c { rf |= CF; }
+        (r, rf)
+    }
+
+    #[test]
+    #[cfg(not(feature = "fast"))]
+    fn test_daa_exhaustive() {
+        let mut cpu = rz80::CPU::new_64k();
+        let bus = &TestBus::new();
+        cpu.mem.w8(0x0000, 0x27);   // DAA
+        for a in 0..256 {
+            for f in 0..256 {
+                cpu.reg.set_pc(0x0000);
+                cpu.reg.set_a(a);
+                cpu.reg.set_f(f);
+                assert_eq!(4, cpu.step(bus));
+                let (ra, rf) = daa_reference(a, f);
+                assert!(cpu.reg.a() == ra && cpu.reg.f() == rf,
+                    "DAA A={:02X} F={:02X}: got A={:02X} F={:02X}, expected A={:02X} F={:02X}",
+                    a, f, cpu.reg.a(), cpu.reg.f(), ra, rf);
+            }
+        }
+    }
+
     #[test]
     fn test_cpl() {
         let mut cpu = rz80::CPU::new_64k();
@@ -1400,9 +1446,9 @@ mod test_opcodes {
             0x76,       // HALT
         ];
         cpu.mem.write(0x0000, &prog);
-        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0000, cpu.reg.pc()); assert!(cpu.halt);
-        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0000, cpu.reg.pc()); assert!(cpu.halt);
-        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0000, cpu.reg.pc()); assert!(cpu.halt);
+        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0000, cpu.reg.pc()); assert!(cpu.is_halted());
+        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0000, cpu.reg.pc()); assert!(cpu.is_halted());
+        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0000, cpu.reg.pc()); assert!(cpu.is_halted());
     }
 
     #[test]
@@ -1910,6 +1956,77 @@ mod test_opcodes {
         assert_eq!(15, cpu.step(bus)); assert_eq!(0x100C, cpu.reg.iy()); assert!(flags(&cpu, 0));
     }
 
+    // 16-bit operands around nibble, byte and sign boundaries
+    #[cfg(not(feature = "fast"))]
+    const OPS16: [RegT; 18] = [
+        0x0000, 0x0001, 0x00FF, 0x0100, 0x07FF, 0x0800, 0x0FFF, 0x1000, 0x1234,
+        0x7FFE, 0x7FFF, 0x8000, 0x8001, 0xA5A5, 0xEFFF, 0xF000, 0xFFFE, 0xFFFF,
+    ];
+    #[cfg(not(feature = "fast"))]
+    const FLAGS16: [RegT; 4] = [0x00, CF, 0xFF & !CF, 0xFF];
+
+    // reference 16-bit ADD/ADC/SBC, returns (result, flags)
+    #[cfg(not(feature = "fast"))]
+    fn alu16_reference(op: u8, acc: RegT, val: RegT, f: RegT) -> (RegT, RegT) {
+        let c = if op == 0 { 0 } else { f & CF };
+        let (res, h, carry, ovf) = if op == 2 {
+            let r = acc - val - c;
+            let sr = (acc as u16 as i16 as i32) - (val as u16 as i16 as i32) - c;
+            (r & 0xFFFF, (acc & 0xFFF) < (val & 0xFFF) + c, r < 0, !(-0x8000..=0x7FFF).contains(&sr))
+        } else {
+            let r = acc + val + c;
+            let sr = (acc as u16 as i16 as i32) + (val as u16 as i16 as i32) + c;
+            (r & 0xFFFF, (acc & 0xFFF) + (val & 0xFFF) + c > 0xFFF, r > 0xFFFF, !(-0x8000..=0x7FFF).contains(&sr))
+        };
+        let mut rf = (res >> 8) & (YF | XF);
+        if op == 0 {
+            // ADD leaves S, Z and P/V alone
+            rf |= f & (SF | ZF | VF);
+        } else {
+            rf |= (res >> 8) & SF;
+            if res == 0 { rf |= ZF; }
+            if ovf { rf |= VF; }
+        }
+        if op == 2 { rf |= NF; }
+        if h { rf |= HF; }
+        if carry { rf |= CF; }
+        (res, rf)
+    }
+
+    #[test]
+    #[cfg(not(feature = "fast"))]
+    fn test_add_adc_sbc_16_sweep() {
+        let mut cpu = rz80::CPU::new_64k();
+        let bus = &TestBus::new();
+        // (name, opcode bytes, reference op, uses IX instead of HL, cycles)
+        let ops: [(&str, &[u8], u8, bool, i64); 4] = [
+            ("ADD HL,BC", &[0x09], 0, false, 11),
+            ("ADC HL,BC", &[0xED, 0x4A], 1, false, 15),
+            ("SBC HL,BC", &[0xED, 0x42], 2, false, 15),
+            ("ADD IX,BC", &[0xDD, 0x09], 0, true, 15),
+        ];
+        for &(name, code, op, ix, cycles) in ops.iter() {
+            cpu.mem.write(0x0000, code);
+            for &acc in OPS16.iter() {
+                for &val in OPS16.iter() {
+                    for &f in FLAGS16.iter() {
+                        cpu.reg.set_pc(0x0000);
+                        if ix { cpu.reg.set_ix(acc); } else { cpu.reg.set_hl(acc); }
+                        cpu.reg.set_bc(val);
+                        cpu.reg.set_f(f);
+                        assert_eq!(cycles, cpu.step(bus));
+                        let res = if ix { cpu.reg.ix() } else { cpu.reg.hl() };
+                        let (ref_res, ref_f) = alu16_reference(op, acc, val, f);
+                        assert!(res == ref_res && cpu.reg.f() == ref_f,
+                            "{} {:04X},{:04X} F={:02X}: got {:04X} F={:02X}, expected {:04X} F={:02X}",
+                            name, acc, val, f, res, cpu.reg.f(), ref_res, ref_f);
+                        assert_eq!((acc + 1) & 0xFFFF, cpu.reg.wz(), "{} WZ", name);
+                    }
+                }
+            }
+        }
+    }
+
     #[test]
     fn ld_hlddixiy_inn() {
         let mut cpu = rz80::CPU::new_64k();
@@ -2006,8 +2123,7 @@ mod test_opcodes {
     fn test_ld_a_ir() {
         let mut cpu = rz80::CPU::new_64k();
         let bus = &TestBus::new();
-        cpu.iff1 = true;
-        cpu.iff2 = true;
+        cpu.set_iff(true, true);
         cpu.reg.r = 0x34;
         cpu.reg.i = 0x1;
         cpu.reg.set_f(CF);
@@ -2467,10 +2583,22 @@ mod test_opcodes {
         assert_eq!(12, cpu.step(bus)); assert_eq!(0x00, cpu.reg.c()); assert!(flags(&cpu, ZF|PF|CF));
     }
 
+    // records the last port write, passed as mutable IoBus to CPU::step_io()
+    struct OutBus {
+        port: RegT,
+        val: RegT,
+    }
+    impl rz80::IoBus for OutBus {
+        fn outp(&mut self, port: RegT, val: RegT) {
+            self.port = port;
+            self.val = val;
+        }
+    }
+
     #[test]
     fn test_out() {
         let mut cpu = rz80::CPU::new_64k();
-        let bus = &TestBus::new();
+        let bus = &mut OutBus { port: 0, val: 0 };
         let prog = [
             0x3E, 0x01,         // LD A,0x01
             0xD3, 0x01,         // OUT (0x01),A
@@ -2488,19 +2616,19 @@ mod test_opcodes {
         ];
         cpu.mem.write(0x0000, &prog);
 
-        assert_eq!(7, cpu.step(bus)); assert_eq!(0x01, cpu.reg.a());
-        assert_eq!(11, cpu.step(bus)); assert_eq!(0x0101, bus.port.get()); assert_eq!(0x01, bus.val.get());
-        assert_eq!(11, cpu.step(bus)); assert_eq!(0x0102, bus.port.get()); assert_eq!(0x01, bus.val.get());
-        assert_eq!(10, cpu.step(bus)); assert_eq!(0x1234, cpu.reg.bc());
-        assert_eq!(10, cpu.step(bus)); assert_eq!(0x5678, cpu.reg.de());
-        assert_eq!(10, cpu.step(bus)); assert_eq!(0xABCD, cpu.reg.hl());
-        assert_eq!(12, cpu.step(bus)); assert_eq!(0x1234, bus.port.get()); assert_eq!(0x01, bus.val.get());
-        assert_eq!(12, cpu.step(bus)); assert_eq!(0x1234, bus.port.get()); assert_eq!(0x12, bus.val.get());
-        assert_eq!(12, cpu.step(bus)); assert_eq!(0x1234, bus.port.get()); assert_eq!(0x34, bus.val.get());
-        assert_eq!(12, cpu.step(bus)); assert_eq!(0x1234, bus.port.get()); assert_eq!(0x56, bus.val.get());
-        assert_eq!(12, cpu.step(bus)); assert_eq!(0x1234, bus.port.get()); assert_eq!(0x78, bus.val.get());
-        assert_eq!(12, cpu.step(bus)); assert_eq!(0x1234, bus.port.get()); assert_eq!(0xAB, bus.val.get());
-        assert_eq!(12, cpu.step(bus)); assert_eq!(0x1234, bus.port.get()); assert_eq!(0xCD, bus.val.get());
+        assert_eq!(7, cpu.step_io(bus)); assert_eq!(0x01, cpu.reg.a());
+        assert_eq!(11, cpu.step_io(bus)); assert_eq!(0x0101, bus.port); assert_eq!(0x01, bus.val);
+        assert_eq!(11, cpu.step_io(bus)); assert_eq!(0x0102, bus.port); assert_eq!(0x01, bus.val);
+        assert_eq!(10, cpu.step_io(bus)); assert_eq!(0x1234, cpu.reg.bc());
+        assert_eq!(10, cpu.step_io(bus)); assert_eq!(0x5678, cpu.reg.de());
+        assert_eq!(10, cpu.step_io(bus)); assert_eq!(0xABCD, cpu.reg.hl());
+        assert_eq!(12, cpu.step_io(bus)); assert_eq!(0x1234, bus.port); assert_eq!(0x01, bus.val);
+        assert_eq!(12, cpu.step_io(bus)); assert_eq!(0x1234, bus.port); assert_eq!(0x12, bus.val);
+        assert_eq!(12, cpu.step_io(bus)); assert_eq!(0x1234, bus.port); assert_eq!(0x34, bus.val);
+        assert_eq!(12, cpu.step_io(bus)); assert_eq!(0x1234, bus.port); assert_eq!(0x56, bus.val);
+        assert_eq!(12, cpu.step_io(bus)); assert_eq!(0x1234, bus.port); assert_eq!(0x78, bus.val);
+        assert_eq!(12, cpu.step_io(bus)); assert_eq!(0x1234, bus.port); assert_eq!(0xAB, bus.val);
+        assert_eq!(12, cpu.step_io(bus)); assert_eq!(0x1234, bus.port); assert_eq!(0xCD, bus.val);
     }
 
     #[test]
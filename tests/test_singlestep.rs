@@ -0,0 +1,446 @@
+extern crate rz80;
+
+// runs the per-opcode JSON test vectors of the SingleStepTests/z80
+// suite: each test has an initial CPU state and RAM content, the
+// expected final state and RAM, the bus activity of each clock cycle,
+// and the values of port reads and writes. One instruction is executed
+// per test, the final state, the cycle count, the memory and I/O
+// accesses (from the machine cycle trace) and the port writes must match.
+//
+// the suite is too big to include here, check it out and run:
+//
+// > RZ80_SINGLESTEP_DIR=path/to/z80/v1 cargo test --release --test test_singlestep -- --ignored
+//
+// rz80 doesn't emulate the internal Q register, so the undocumented
+// X and Y flags of SCF and CCF are ignored when Q isn't 0 (which means
+// that the previous instruction has changed the flags)
+#[cfg(all(test, not(feature = "fast")))]
+mod test_singlestep {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::fs;
+    use rz80::{CPU, Bus, RegT, Reg8, Reg16, Im, MCycleKind, XF, YF};
+
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Null,
+        Bool(bool),
+        Num(i64),
+        Str(String),
+        Arr(Vec<Json>),
+        Obj(Vec<(String, Json)>),
+    }
+
+    impl Json {
+        fn get(&self, key: &str) -> &Json {
+            match *self {
+                Json::Obj(ref members) => {
+                    members.iter().find(|m| m.0 == key).map(|m| &m.1).unwrap_or(&Json::Null)
+                }
+                _ => &Json::Null,
+            }
+        }
+
+        fn num(&self) -> RegT {
+            match *self {
+                Json::Num(n) => n as RegT,
+                _ => panic!("expected a number, got {:?}", self),
+            }
+        }
+
+        fn opt_num(&self) -> Option<RegT> {
+            match *self {
+                Json::Num(n) => Some(n as RegT),
+                _ => None,
+            }
+        }
+
+        fn str(&self) -> &str {
+            match *self {
+                Json::Str(ref s) => s,
+                _ => panic!("expected a string, got {:?}", self),
+            }
+        }
+
+        fn arr(&self) -> &[Json] {
+            match *self {
+                Json::Arr(ref items) => items,
+                Json::Null => &[],
+                _ => panic!("expected an array, got {:?}", self),
+            }
+        }
+    }
+
+    // a minimal JSON parser, enough for the test vectors
+    struct Parser<'a> {
+        s: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> Parser<'a> {
+        fn parse(s: &'a str) -> Json {
+            let mut p = Parser { s: s.as_bytes(), pos: 0 };
+            let val = p.value();
+            p.ws();
+            assert_eq!(p.pos, p.s.len(), "trailing characters");
+            val
+        }
+
+        fn ws(&mut self) {
+            while self.pos < self.s.len() && (self.s[self.pos] as char).is_whitespace() {
+                self.pos += 1;
+            }
+        }
+
+        fn eat(&mut self, c: u8) -> bool {
+            self.ws();
+            if self.pos < self.s.len() && self.s[self.pos] == c {
+                self.pos += 1;
+                true
+            } else {
+                false
+            }
+        }
+
+        fn expect(&mut self, c: u8) {
+            assert!(self.eat(c), "expected '{}' at {}", c as char, self.pos);
+        }
+
+        fn literal(&mut self, word: &str, val: Json) -> Json {
+            assert!(self.s[self.pos..].starts_with(word.as_bytes()), "bad literal at {}", self.pos);
+            self.pos += word.len();
+            val
+        }
+
+        fn string(&mut self) -> String {
+            self.expect(b'"');
+            let mut res = String::new();
+            while self.s[self.pos] != b'"' {
+                if self.s[self.pos] == b'\\' {
+                    self.pos += 1;
+                }
+                res.push(self.s[self.pos] as char);
+                self.pos += 1;
+            }
+            self.pos += 1;
+            res
+        }
+
+        fn value(&mut self) -> Json {
+            self.ws();
+            match self.s[self.pos] {
+                b'n' => self.literal("null", Json::Null),
+                b't' => self.literal("true", Json::Bool(true)),
+                b'f' => self.literal("false", Json::Bool(false)),
+                b'"' => Json::Str(self.string()),
+                b'[' => {
+                    self.pos += 1;
+                    let mut items = Vec::new();
+                    if !self.eat(b']') {
+                        loop {
+                            items.push(self.value());
+                            if self.eat(b']') {
+                                break;
+                            }
+                            self.expect(b',');
+                        }
+                    }
+                    Json::Arr(items)
+                }
+                b'{' => {
+                    self.pos += 1;
+                    let mut members = Vec::new();
+                    if !self.eat(b'}') {
+                        loop {
+                            self.ws();
+                            let key = self.string();
+                            self.expect(b':');
+                            members.push((key, self.value()));
+                            if self.eat(b'}') {
+                                break;
+                            }
+                            self.expect(b',');
+                        }
+                    }
+                    Json::Obj(members)
+                }
+                _ => {
+                    let start = self.pos;
+                    while self.pos < self.s.len() && (self.s[self.pos] == b'-' || self.s[self.pos].is_ascii_digit()) {
+                        self.pos += 1;
+                    }
+                    let num = std::str::from_utf8(&self.s[start..self.pos]).unwrap();
+                    Json::Num(num.parse().unwrap_or_else(|_| panic!("bad number at {}", start)))
+                }
+            }
+        }
+    }
+
+    // port reads return the values from the test, port writes are recorded
+    struct TestBus {
+        inputs: RefCell<VecDeque<(RegT, RegT)>>,
+        outputs: RefCell<Vec<(RegT, RegT)>>,
+    }
+
+    impl Bus for TestBus {
+        fn cpu_inp(&self, port: RegT) -> RegT {
+            match self.inputs.borrow_mut().pop_front() {
+                Some((p, val)) if p == port => val,
+                _ => 0xFF,
+            }
+        }
+        fn cpu_outp(&self, port: RegT, val: RegT) {
+            self.outputs.borrow_mut().push((port, val));
+        }
+    }
+
+    // the 16-bit registers in the test vectors
+    const REGS16: [(&str, Reg16); 9] = [
+        ("pc", Reg16::PC), ("sp", Reg16::SP), ("wz", Reg16::WZ), ("ix", Reg16::IX), ("iy", Reg16::IY),
+        ("af_", Reg16::AF_), ("bc_", Reg16::BC_), ("de_", Reg16::DE_), ("hl_", Reg16::HL_),
+    ];
+    // the 8-bit registers in the test vectors
+    const REGS8: [(&str, Reg8); 10] = [
+        ("a", Reg8::A), ("f", Reg8::F), ("b", Reg8::B), ("c", Reg8::C), ("d", Reg8::D),
+        ("e", Reg8::E), ("h", Reg8::H), ("l", Reg8::L), ("i", Reg8::I), ("r", Reg8::R),
+    ];
+
+    fn set_state(cpu: &mut CPU, state: &Json) {
+        for &(name, r) in REGS16.iter() {
+            if let Some(val) = state.get(name).opt_num() {
+                cpu.reg.set(r, val);
+            }
+        }
+        for &(name, r) in REGS8.iter() {
+            cpu.reg.set8(r, state.get(name).num());
+        }
+        cpu.set_interrupt_mode(Im::from_bits(state.get("im").num()).expect("invalid interrupt mode"));
+        cpu.set_iff(state.get("iff1").num() != 0, state.get("iff2").num() != 0);
+        for entry in state.get("ram").arr() {
+            cpu.mem.w8(entry.arr()[0].num(), entry.arr()[1].num());
+        }
+    }
+
+    fn check_state(cpu: &CPU, state: &Json, f_mask: RegT, errors: &mut Vec<String>) {
+        for &(name, r) in REGS16.iter() {
+            if let Some(val) = state.get(name).opt_num() {
+                if cpu.reg.get(r) != val {
+                    errors.push(format!("{}: expected {:04X}, got {:04X}", name, val, cpu.reg.get(r)));
+                }
+            }
+        }
+        for &(name, r) in REGS8.iter() {
+            let mask = if r == Reg8::F { f_mask } else { 0xFF };
+            let val = state.get(name).num();
+            if cpu.reg.get8(r) & mask != val & mask {
+                errors.push(format!("{}: expected {:02X}, got {:02X}", name, val, cpu.reg.get8(r)));
+            }
+        }
+        let flags = [("im", cpu.interrupt_mode() as RegT), ("iff1", cpu.iff1() as RegT), ("iff2", cpu.iff2() as RegT)];
+        for &(name, val) in flags.iter() {
+            if state.get(name).num() != val {
+                errors.push(format!("{}: expected {}, got {}", name, state.get(name).num(), val));
+            }
+        }
+        for entry in state.get("ram").arr() {
+            let (addr, val) = (entry.arr()[0].num(), entry.arr()[1].num());
+            if cpu.mem.r8(addr) != val {
+                errors.push(format!("ram {:04X}: expected {:02X}, got {:02X}", addr, val, cpu.mem.r8(addr)));
+            }
+        }
+    }
+
+    // the memory and I/O accesses of the per-clock-cycle bus activity,
+    // consecutive cycles of the same access are merged
+    fn bus_accesses(cycles: &[Json]) -> Vec<(char, RegT, Option<RegT>)> {
+        let mut res: Vec<(char, RegT, Option<RegT>)> = Vec::new();
+        let mut last = None;
+        for cycle in cycles {
+            let items = cycle.arr();
+            let (addr, data, pins) = (items[0].num(), items[1].opt_num(), items[2].str());
+            let kind = match (pins.contains('r'), pins.contains('w'), pins.contains('m'), pins.contains('i')) {
+                (true, false, true, false) => Some('r'),
+                (false, true, true, false) => Some('w'),
+                (true, false, false, true) => Some('i'),
+                (false, true, false, true) => Some('o'),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                if last == Some((kind, addr)) {
+                    let prev = res.last_mut().unwrap();
+                    prev.2 = data.or(prev.2);
+                } else {
+                    res.push((kind, addr, data));
+                }
+            }
+            last = kind.map(|k| (k, addr));
+        }
+        res
+    }
+
+    // run a single test, return the mismatches
+    fn run_test(test: &Json) -> Vec<String> {
+        let initial = test.get("initial");
+        if initial.get("ei").opt_num().unwrap_or(0) != 0 {
+            return Vec::new();
+        }
+        let mut inputs = VecDeque::new();
+        let mut outputs = Vec::new();
+        for port in test.get("ports").arr() {
+            let p = port.arr();
+            let entry = (p[0].num(), p[1].num());
+            if p[2].str() == "r" { inputs.push_back(entry) } else { outputs.push(entry) }
+        }
+        let bus = TestBus { inputs: RefCell::new(inputs), outputs: RefCell::new(Vec::new()) };
+
+        let mut cpu = CPU::new_64k();
+        set_state(&mut cpu, initial);
+        let pc = cpu.reg.pc();
+        let op = cpu.mem.r8(pc);
+        let f_mask = if (op == 0x37 || op == 0x3F) && initial.get("q").opt_num().unwrap_or(0) != 0 {
+            !(XF | YF) & 0xFF
+        } else {
+            0xFF
+        };
+        cpu.set_mcycle_trace(true);
+        let cycles = cpu.step(&bus);
+
+        let mut errors = Vec::new();
+        check_state(&cpu, test.get("final"), f_mask, &mut errors);
+        let expected_cycles = test.get("cycles").arr();
+        if cycles != expected_cycles.len() as i64 {
+            errors.push(format!("cycles: expected {}, got {}", expected_cycles.len(), cycles));
+        }
+        let accesses: Vec<(char, RegT, Option<RegT>)> = cpu.mcycles().iter()
+            .filter_map(|c| {
+                let kind = match c.kind {
+                    MCycleKind::Fetch | MCycleKind::Read => 'r',
+                    MCycleKind::Write => 'w',
+                    MCycleKind::In => 'i',
+                    MCycleKind::Out => 'o',
+                    _ => return None,
+                };
+                Some((kind, c.addr, Some(c.data)))
+            })
+            .collect();
+        let expected = bus_accesses(expected_cycles);
+        let matches = accesses.len() == expected.len() &&
+            accesses.iter().zip(expected.iter()).all(|(a, e)| a.0 == e.0 && a.1 == e.1 && (e.2.is_none() || a.2 == e.2));
+        if !matches {
+            errors.push(format!("bus: expected {:?}, got {:?}", expected, accesses));
+        }
+        if *bus.outputs.borrow() != outputs {
+            errors.push(format!("ports: expected {:?}, got {:?}", outputs, bus.outputs.borrow()));
+        }
+        errors
+    }
+
+    // run all tests of a JSON file, return the number of tests and the failures
+    fn run_file(json: &str) -> (usize, Vec<String>) {
+        let tests = Parser::parse(json);
+        let mut failures = Vec::new();
+        for test in tests.arr() {
+            let errors = run_test(test);
+            if !errors.is_empty() {
+                failures.push(format!("{}: {}", test.get("name").str(), errors.join(", ")));
+            }
+        }
+        (tests.arr().len(), failures)
+    }
+
+    // a few hand-written tests in the format of the suite
+    static SAMPLE: &str = r#"[
+        {
+            "name": "00 0000",
+            "initial": { "pc": 4096, "sp": 0, "a": 1, "b": 2, "c": 3, "d": 4, "e": 5, "f": 6,
+                         "h": 7, "l": 8, "i": 9, "r": 127, "ei": 0, "wz": 0, "ix": 0, "iy": 0,
+                         "af_": 0, "bc_": 0, "de_": 0, "hl_": 0, "im": 1, "p": 0, "q": 0,
+                         "iff1": 1, "iff2": 1, "ram": [[4096, 0]] },
+            "final": { "pc": 4097, "sp": 0, "a": 1, "b": 2, "c": 3, "d": 4, "e": 5, "f": 6,
+                       "h": 7, "l": 8, "i": 9, "r": 0, "ei": 0, "wz": 0, "ix": 0, "iy": 0,
+                       "af_": 0, "bc_": 0, "de_": 0, "hl_": 0, "im": 1, "p": 0, "q": 0,
+                       "iff1": 1, "iff2": 1, "ram": [[4096, 0]] },
+            "cycles": [[4096, null, "----"], [4096, 0, "r-m-"], [2431, null, "--m-"], [2431, null, "----"]]
+        },
+        {
+            "name": "77 0000",
+            "initial": { "pc": 256, "sp": 0, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                         "h": 64, "l": 0, "i": 0, "r": 0, "ei": 0, "wz": 4660, "ix": 0, "iy": 0,
+                         "af_": 0, "bc_": 0, "de_": 0, "hl_": 0, "im": 0, "p": 0, "q": 0,
+                         "iff1": 0, "iff2": 0, "ram": [[256, 119], [16384, 0]] },
+            "final": { "pc": 257, "sp": 0, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                       "h": 64, "l": 0, "i": 0, "r": 1, "ei": 0, "wz": 4660, "ix": 0, "iy": 0,
+                       "af_": 0, "bc_": 0, "de_": 0, "hl_": 0, "im": 0, "p": 0, "q": 0,
+                       "iff1": 0, "iff2": 0, "ram": [[256, 119], [16384, 66]] },
+            "cycles": [[256, null, "----"], [256, 119, "r-m-"], [0, null, "--m-"], [0, null, "----"],
+                       [16384, null, "----"], [16384, 66, "-wm-"], [16384, 66, "-wm-"]]
+        },
+        {
+            "name": "d3 0000",
+            "initial": { "pc": 0, "sp": 0, "a": 18, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                         "h": 0, "l": 0, "i": 0, "r": 0, "ei": 0, "wz": 0, "ix": 0, "iy": 0,
+                         "af_": 0, "bc_": 0, "de_": 0, "hl_": 0, "im": 0, "p": 0, "q": 0,
+                         "iff1": 0, "iff2": 0, "ram": [[0, 211], [1, 52]] },
+            "final": { "pc": 2, "sp": 0, "a": 18, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                       "h": 0, "l": 0, "i": 0, "r": 1, "ei": 0, "wz": 4661, "ix": 0, "iy": 0,
+                       "af_": 0, "bc_": 0, "de_": 0, "hl_": 0, "im": 0, "p": 0, "q": 0,
+                       "iff1": 0, "iff2": 0, "ram": [[0, 211], [1, 52]] },
+            "cycles": [[0, null, "----"], [0, 211, "r-m-"], [0, null, "--m-"], [0, null, "----"],
+                       [1, null, "----"], [1, 52, "r-m-"], [1, 52, "r-m-"],
+                       [4660, null, "----"], [4660, 18, "-w-i"], [4660, 18, "-w-i"], [4660, 18, "-w-i"]],
+            "ports": [[4660, 18, "w"]]
+        },
+        {
+            "name": "db 0000",
+            "initial": { "pc": 0, "sp": 0, "a": 18, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                         "h": 0, "l": 0, "i": 0, "r": 0, "ei": 0, "wz": 0, "ix": 0, "iy": 0,
+                         "af_": 0, "bc_": 0, "de_": 0, "hl_": 0, "im": 0, "p": 0, "q": 0,
+                         "iff1": 0, "iff2": 0, "ram": [[0, 219], [1, 52]] },
+            "final": { "pc": 2, "sp": 0, "a": 171, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                       "h": 0, "l": 0, "i": 0, "r": 1, "ei": 0, "wz": 4661, "ix": 0, "iy": 0,
+                       "af_": 0, "bc_": 0, "de_": 0, "hl_": 0, "im": 0, "p": 0, "q": 0,
+                       "iff1": 0, "iff2": 0, "ram": [[0, 219], [1, 52]] },
+            "cycles": [[0, null, "----"], [0, 219, "r-m-"], [0, null, "--m-"], [0, null, "----"],
+                       [1, null, "----"], [1, 52, "r-m-"], [1, 52, "r-m-"],
+                       [4660, null, "----"], [4660, null, "r--i"], [4660, 171, "r--i"], [4660, 171, "r--i"]],
+            "ports": [[4660, 171, "r"]]
+        }
+    ]"#;
+
+    #[test]
+    fn singlestep_sample() {
+        let (num, failures) = run_file(SAMPLE);
+        assert_eq!(num, 4);
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+
+        // a wrong expectation is reported
+        let bad = SAMPLE.replacen("\"a\": 171", "\"a\": 170", 1);
+        let (_, failures) = run_file(&bad);
+        assert_eq!(failures, ["db 0000: a: expected AA, got AB"]);
+    }
+
+    #[test]
+    #[ignore]
+    fn singlestep_suite() {
+        let dir = std::env::var("RZ80_SINGLESTEP_DIR").expect("set RZ80_SINGLESTEP_DIR to the test vector directory");
+        let mut paths: Vec<_> = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+        let mut num_tests = 0;
+        let mut failures = Vec::new();
+        for path in &paths {
+            let json = fs::read_to_string(path).unwrap();
+            let (num, file_failures) = run_file(&json);
+            if !file_failures.is_empty() {
+                println!("{}: {} of {} tests failed", path.display(), file_failures.len(), num);
+            }
+            num_tests += num;
+            failures.extend(file_failures);
+        }
+        println!("{} files, {} tests, {} failed", paths.len(), num_tests, failures.len());
+        let first: Vec<&str> = failures.iter().take(20).map(|s| s.as_str()).collect();
+        assert!(failures.is_empty(), "first failures:\n{}", first.join("\n"));
+    }
+}
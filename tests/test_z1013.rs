@@ -0,0 +1,187 @@
+extern crate rz80;
+
+// end-to-end test of a headless Z1013 (see examples/z1013.rs for the
+// full emulator with comments on the keyboard matrix emulation)
+#[cfg(test)]
+mod test_z1013 {
+    use std::cell::{Cell, RefCell};
+    use rz80::{CPU, PIO, Bus, RegT, Clock, Machine, TextMode, TestKit, TestSystem,
+               PIO_A, PIO_B, ProgramFormat, basic_tokenize, basic_detokenize, load_program};
+
+    static OS: &[u8] = include_bytes!("../examples/dumps/z1013_mon_a2.bin");
+    static FONT: &[u8] = include_bytes!("../examples/dumps/z1013_font.bin");
+    static BASIC: &[u8] = include_bytes!("../examples/dumps/kc_basic.z80");
+
+    // the BASIC program area, and the pointers to the end of the program
+    // (start of the variables, arrays and free memory)
+    const PROG_START: RegT = 0x2C01;
+    const PROG_END_PTRS: [RegT; 3] = [0x2BD7, 0x2BD9, 0x2BDB];
+
+    static KEY_MATRIX: &[u8] =
+        b"13579-  QETUO@  ADGJL*  YCBM.^  24680[  WRZIP]  SFHK+\\  XVN,/_  \
+          !#%')=  qetuo`  adgjl:  ycbm>~  \"$&( {  wrzip}  sfhk;|  xvn<?   ";
+
+    struct Z1013 {
+        cpu: RefCell<CPU>,
+        pio: RefCell<PIO>,
+        key_map: [u64; 128],
+        next_kbd_matrix_bits: Cell<u64>,
+        kbd_matrix_bits: Cell<u64>,
+        kbd_column: Cell<usize>,
+        kbd_high_lines: Cell<bool>,
+        text: TextMode,
+    }
+
+    impl Z1013 {
+        fn new() -> Z1013 {
+            let key_bit = |col: usize, line: usize| (1u64 << line) << (col * 8);
+            let mut key_map = [0u64; 128];
+            for shift in 0..2 {
+                for line in 0..8 {
+                    for col in 0..8 {
+                        let c = KEY_MATRIX[shift * 64 + line * 8 + col] as usize;
+                        if c != 0x20 {
+                            key_map[c] = key_bit(col, line) |
+                                         if shift != 0 { key_bit(7, 6) } else { 0 };
+                        }
+                    }
+                }
+            }
+            key_map[0x20] = key_bit(6, 4);
+            key_map[0x0D] = key_bit(6, 1);
+
+            let mut cpu = CPU::new();
+            cpu.mem.map(1, 0x00000, 0x0000, true, 0x10000);
+            cpu.mem.map_bytes(0, 0x10000, 0xF000, false, OS);
+            load_program(&mut cpu.mem, ProgramFormat::Z80, BASIC).unwrap();
+            cpu.reg.set_pc(0xF000);
+            Z1013 {
+                cpu: RefCell::new(cpu),
+                pio: RefCell::new(PIO::new(0)),
+                key_map,
+                next_kbd_matrix_bits: Cell::new(0),
+                kbd_matrix_bits: Cell::new(0),
+                kbd_column: Cell::new(0),
+                kbd_high_lines: Cell::new(false),
+                text: TextMode::new(32, 32, FONT),
+            }
+        }
+    }
+
+    impl Bus for Z1013 {
+        fn cpu_outp(&self, port: RegT, val: RegT) {
+            match port & 0xFF {
+                0x00..=0x03 => {
+                    let chn = if (port & 2) == 0 { PIO_A } else { PIO_B };
+                    if (port & 1) == 0 {
+                        self.pio.borrow_mut().write_data(self, chn, val);
+                    } else {
+                        let _ = self.pio.borrow_mut().write_control(chn, val);
+                    }
+                }
+                0x08 => {
+                    if val == 0 {
+                        self.kbd_matrix_bits.set(self.next_kbd_matrix_bits.get());
+                    }
+                    self.kbd_column.set(val as usize);
+                }
+                _ => (),
+            }
+        }
+        fn cpu_inp(&self, port: RegT) -> RegT {
+            match port & 0xFF {
+                0x00..=0x03 => {
+                    let chn = if (port & 2) == 0 { PIO_A } else { PIO_B };
+                    if (port & 1) == 0 {
+                        self.pio.borrow_mut().read_data(self, chn)
+                    } else {
+                        self.pio.borrow().read_control()
+                    }
+                }
+                _ => 0xFF,
+            }
+        }
+        fn pio_outp(&self, _: usize, chn: usize, data: RegT) {
+            if chn == PIO_B {
+                self.kbd_high_lines.set((data & (1 << 4)) != 0);
+            }
+        }
+        fn pio_inp(&self, _: usize, chn: usize) -> RegT {
+            if chn == PIO_B {
+                let mut val = self.kbd_matrix_bits.get() >> ((self.kbd_column.get() & 7) * 8);
+                if self.kbd_high_lines.get() {
+                    val >>= 4;
+                }
+                (0xF & !(val & 0xF)) as RegT
+            } else {
+                0xFF
+            }
+        }
+    }
+
+    // the TestKit needs mutable access, while the chips are wired
+    // through the Bus trait with RefCells, so wrap the system
+    struct System(Z1013);
+
+    impl TestSystem for System {
+        fn step(&mut self) -> i64 {
+            let sys = &self.0;
+            sys.cpu.borrow_mut().step(sys)
+        }
+        fn put_key(&mut self, ascii: u8) {
+            let bits = if ascii == 0 { 0 } else { self.0.key_map[(ascii & 0x7F) as usize] };
+            self.0.next_kbd_matrix_bits.set(bits);
+        }
+        fn read_mem(&self, addr: RegT) -> u8 {
+            self.0.cpu.borrow().mem.r8(addr) as u8
+        }
+        fn screen_text(&self) -> String {
+            let cpu = self.0.cpu.borrow();
+            self.0.text.screen_text(&cpu.mem.heap[0xEC00..0xF000])
+        }
+    }
+
+    // boot into the BASIC interpreter
+    fn start_basic() -> TestKit<System> {
+        let machine = Machine::with_clock(Clock::new(2_000_000), 50);
+        let mut kit = TestKit::new(System(Z1013::new()), machine);
+        kit.run_until_text("robotron", 100);
+        kit.type_and_run("J 300\n");
+        kit.run_until_text("MEMORY SIZE?", 100);
+        kit.type_and_run("\n");
+        kit.run_until_text("OK", 200);
+        kit
+    }
+
+    #[test]
+    fn basic() {
+        let mut kit = start_basic();
+        kit.type_and_run("PRINT 6*7\n");
+        kit.run_until_text(" 42", 100);
+        // the BASIC interpreter is loaded at 0x0100
+        kit.assert_mem(0x0100, &BASIC[0x20..0x30]);
+    }
+
+    #[test]
+    fn basic_program() {
+        let mut kit = start_basic();
+        let listing = "10 FOR I=1 TO 3\n20 PRINT I*11;\n30 NEXT\n";
+        let prog = basic_tokenize(listing, PROG_START).unwrap();
+        {
+            let mut cpu = kit.system.0.cpu.borrow_mut();
+            cpu.mem.write(PROG_START, &prog);
+            for &ptr in &PROG_END_PTRS {
+                cpu.mem.w16(ptr, PROG_START + prog.len() as RegT);
+            }
+        }
+        kit.type_and_run("RUN\n");
+        kit.run_until_text(" 11  22  33", 100);
+
+        // a line typed into the interpreter shows up in the program
+        kit.type_and_run("40 PRINT\"OK\"\n");
+        kit.run_frames(10);
+        let mem: Vec<u8> = (0..256).map(|i| kit.system.read_mem(PROG_START + i)).collect();
+        assert_eq!(basic_detokenize(&mem, PROG_START).unwrap(),
+                   format!("{}40 PRINT\"OK\"\n", listing));
+    }
+}
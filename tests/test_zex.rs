@@ -1,9 +1,8 @@
 extern crate rz80;
-extern crate time;
 
 #[cfg(test)]
 mod test_zex {
-    use time::PreciseTime;
+    use std::time::Instant;
     use rz80;
     
     static ZEXDOC: &'static [u8] = include_bytes!("zexdoc.com");
@@ -44,13 +43,15 @@ mod test_zex {
         let mut num_ops = 0;
         let mut num_cycles = 0;
         let mut cpu = rz80::CPU::new_64k();
-        let mut bus = DummyBus { };
+        let bus = DummyBus { };
         cpu.mem.write(0x0100, prog);
         cpu.reg.set_sp(0xF000);
         cpu.reg.set_pc(0x0100);
+        #[cfg(feature = "jit")]
+        cpu.set_jit(true);
         loop {
             num_ops += 1;
-            num_cycles += cpu.step(&mut bus);
+            num_cycles += cpu.run_block(&bus);
             match cpu.reg.pc() {
                 0x0005 => { cpm_bdos(&mut cpu); },  // emulated CP/M BDOS call
                 0x0000 => { break; },
@@ -60,32 +61,37 @@ mod test_zex {
         (num_ops, num_cycles)
     }
 
+    // with the JIT, run_block() runs whole blocks, so the number of
+    // run_block() calls isn't the number of executed instructions
+    fn print_stats(num_ops: i64, num_cycles: i64, ms: i64) {
+        let mhz  = (num_cycles / ms)/1000;
+        if cfg!(feature = "jit") {
+            println!("\n\nblocks: {}, cycles: {}, duration: {}ms", num_ops, num_cycles, ms);
+            println!("MHz: {}", mhz);
+        } else {
+            let mips = (num_ops / ms)/1000;
+            println!("\n\nops: {}, cycles: {}, duration: {}ms", num_ops, num_cycles, ms);
+            println!("mips: {}, MHz: {}", mips, mhz);
+        }
+    }
+
     fn test_zexdoc() {
         println!(">>> RUNNING ZEXDOC");
 
-        let start = PreciseTime::now();
+        let start = Instant::now();
         let (num_ops, num_cycles) = run_test(&ZEXDOC);
-        let end = PreciseTime::now();
-        let ms = start.to(end).num_milliseconds();
-        let mips = (num_ops / ms)/1000;
-        let mhz  = (num_cycles / ms)/1000;
-        
-        println!("\n\nops: {}, cycles: {}, duration: {}ms", num_ops, num_cycles, ms);
-        println!("mips: {}, MHz: {}\n\n", mips, mhz);
+        let ms = start.elapsed().as_millis() as i64;
+        print_stats(num_ops, num_cycles, ms);
+        println!("\n");
     }
     
     fn test_zexall() {
         println!(">>> RUNNING ZEXALL");
 
-        let start = PreciseTime::now();
+        let start = Instant::now();
         let (num_ops, num_cycles) = run_test(&ZEXALL);
-        let end = PreciseTime::now();
-        let ms = start.to(end).num_milliseconds();
-        let mips = (num_ops / ms)/1000;
-        let mhz  = (num_cycles / ms)/1000;
-        
-        println!("\n\nops: {}, cycles: {}, duration: {}ms", num_ops, num_cycles, ms);
-        println!("mips: {}, MHz: {}", mips, mhz);
+        let ms = start.elapsed().as_millis() as i64;
+        print_stats(num_ops, num_cycles, ms);
     }
     
     #[test]